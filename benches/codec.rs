@@ -0,0 +1,116 @@
+//! Binary codec benchmarks on representative stanzas.
+//!
+//! Run with `cargo bench --bench codec`.
+
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use whatsmeow_rust::binary::{decode, encode, Encoder, Node};
+use whatsmeow_rust::JID;
+
+/// An incoming message with an encrypted payload, as in a burst of chat
+/// traffic.
+fn message(i: usize) -> Node {
+    let mut enc = Node::new("enc");
+    enc.set_attr("v", "2");
+    enc.set_attr("type", "msg");
+    enc.set_bytes(vec![0x5A; 180]);
+
+    let mut node = Node::new("message");
+    node.set_attr("from", JID::new("120363012345678901", "g.us"));
+    node.set_attr("participant", JID::new_ad(format!("4915112345{:03}", i % 1000), 0, 3));
+    node.set_attr("id", format!("3EB0{:016X}", i));
+    node.set_attr("type", "text");
+    node.set_attr("t", (1_700_000_000 + i as i64).to_string());
+    node.set_attr("notify", "Ana");
+    node.add_child(enc);
+    node
+}
+
+/// A large history sync payload delivered inline.
+fn history_sync() -> Node {
+    let mut enc = Node::new("enc");
+    enc.set_attr("v", "2");
+    enc.set_attr("type", "pkmsg");
+    enc.set_bytes((0..512 * 1024).map(|i| (i % 251) as u8).collect());
+
+    let mut node = message(0);
+    node.set_attr("category", "peer");
+    node.set_children(vec![enc]);
+    node
+}
+
+/// A group info result listing a thousand participants.
+fn group_info() -> Node {
+    let mut group = Node::new("group");
+    group.set_attr("id", "120363012345678901");
+    group.set_attr("subject", "Community announcements");
+    for i in 0..1000 {
+        let mut participant = Node::new("participant");
+        participant.set_attr("jid", JID::new(format!("4915112345{:03}", i), "s.whatsapp.net"));
+        if i % 50 == 0 {
+            participant.set_attr("type", "admin");
+        }
+        group.add_child(participant);
+    }
+
+    let mut iq = Node::new("iq");
+    iq.set_attr("from", JID::new("", "g.us"));
+    iq.set_attr("id", "1234.5678-9");
+    iq.set_attr("type", "result");
+    iq.add_child(group);
+    iq
+}
+
+fn stanzas() -> Vec<(&'static str, Vec<Node>)> {
+    vec![
+        ("message_burst", (0..100).map(message).collect()),
+        ("history_sync", vec![history_sync()]),
+        ("group_info", vec![group_info()]),
+    ]
+}
+
+fn bench_encode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("encode");
+    for (name, nodes) in stanzas() {
+        let size: usize = nodes.iter().map(|node| encode(node).len()).sum();
+        group.throughput(Throughput::Bytes(size as u64));
+
+        group.bench_with_input(BenchmarkId::new("fresh", name), &nodes, |b, nodes| {
+            b.iter(|| {
+                for node in nodes {
+                    black_box(encode(node));
+                }
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("reused", name), &nodes, |b, nodes| {
+            let mut encoder = Encoder::new();
+            b.iter(|| {
+                for node in nodes {
+                    black_box(encoder.encode_node(node));
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+fn bench_decode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("decode");
+    for (name, nodes) in stanzas() {
+        let encoded: Vec<Vec<u8>> = nodes.iter().map(encode).collect();
+        group.throughput(Throughput::Bytes(encoded.iter().map(Vec::len).sum::<usize>() as u64));
+
+        group.bench_with_input(BenchmarkId::from_parameter(name), &encoded, |b, encoded| {
+            b.iter(|| {
+                for data in encoded {
+                    black_box(decode(data).unwrap());
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_encode, bench_decode);
+criterion_main!(benches);
@@ -8,20 +8,16 @@
 //! 
 //! Run with: cargo run --example echo_bot
 
-use std::time::Duration;
-use tokio::time::sleep;
-
 use whatsmeow_rust::{
-    Client, ClientConfig, Device, MemoryStore,
-    JID, Node, encode, decode,
+    Device,
+    JID, Node, encode,
     protocol::{
-        QRPairing, QREvent,
+        QRPairing,
         build_text_message, build_presence, build_chat_state,
         generate_message_id, parse_message,
     },
-    types::{Event, MessageContent, servers},
+    types::{MessageContent, servers},
     crypto::KeyPair,
-    binary::Attrs,
 };
 
 #[tokio::main]
@@ -36,14 +32,16 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let mut device = Device::new();
     device.initialize();
     println!("   ✓ Device initialized");
-    println!("   ✓ Noise key generated: {:?}...", &hex::encode(&device.noise_key.as_ref().unwrap().public)[..16]);
-    println!("   ✓ Identity key generated: {:?}...", &hex::encode(&device.identity_key.as_ref().unwrap().public)[..16]);
+    println!("   ✓ Noise key generated: {:?}...", &hex::encode(device.noise_key.as_ref().unwrap().public)[..16]);
+    println!("   ✓ Identity key generated: {:?}...", &hex::encode(device.identity_key.as_ref().unwrap().public)[..16]);
     println!("   ✓ Registration ID: {}", device.registration_id);
     println!();
 
     // Generate QR code for pairing
     println!("📲 Generating QR code for pairing...");
-    let pairing = QRPairing::new(device.clone());
+    // The refs come from the server's pair-device query; a connected
+    // Client emits QRCode events for them instead
+    let pairing = QRPairing::new(&device, vec!["2@example-ref".to_string()]);
     
     if let Some(qr_data) = pairing.current_code() {
         println!("   QR Data: {}", &qr_data[..50.min(qr_data.len())]);
@@ -79,7 +77,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("   ✓ Presence built: type={}", presence.get_attr_str("type").unwrap_or("?"));
     
     // Demonstrate chat state (typing indicator)
-    let typing = build_chat_state(&test_jid, true);
+    let _typing = build_chat_state(&test_jid, true);
     println!("   ✓ Typing indicator built");
     println!();
 
@@ -104,7 +102,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     incoming.add_child(body);
     
     // Parse the simulated message
-    if let Some((info, content)) = parse_message(&incoming) {
+    if let Some((info, content)) = parse_message(&incoming, chrono::Utc::now().timestamp()) {
         println!("   ✓ Received message:");
         println!("     - ID: {}", info.id);
         println!("     - From: {}", info.sender);
@@ -134,8 +132,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let shared1 = kp1.dh(&kp2.public);
     let shared2 = kp2.dh(&kp1.public);
     println!("   ✓ Key exchange:");
-    println!("     - Alice public: {}...", &hex::encode(&kp1.public)[..16]);
-    println!("     - Bob public: {}...", &hex::encode(&kp2.public)[..16]);
+    println!("     - Alice public: {}...", &hex::encode(kp1.public)[..16]);
+    println!("     - Bob public: {}...", &hex::encode(kp2.public)[..16]);
     println!("     - Shared secret matches: {}", shared1 == shared2);
     
     // Summary
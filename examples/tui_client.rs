@@ -0,0 +1,262 @@
+//! Terminal WhatsApp Client
+//!
+//! A chat list, the messages of the selected chat, an input box and a
+//! connection status bar, driven only by the public client API:
+//! - `Client::receive` for the event stream
+//! - `ClientHandle::send_message` for sending
+//!
+//! Keys: Up/Down pick a chat, Enter sends, Esc quits. Typing
+//! `/to <jid>` opens a chat with someone new.
+//!
+//! Run with: cargo run --example tui_client
+
+use std::collections::HashMap;
+
+use ratatui::crossterm::event::{self, Event as TermEvent, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Borders, List, ListItem, ListState, Paragraph};
+use ratatui::Frame;
+use tokio::sync::mpsc;
+
+use whatsmeow_rust::protocol::{Client, QRPairing};
+use whatsmeow_rust::types::{Event, MessageContent};
+use whatsmeow_rust::JID;
+
+/// A message shown in the message pane.
+struct ChatLine {
+    from: String,
+    text: String,
+    from_me: bool,
+}
+
+/// Everything the screen shows.
+#[derive(Default)]
+struct App {
+    status: String,
+    connected: bool,
+    /// Chats, most recently active first
+    chats: Vec<JID>,
+    messages: HashMap<JID, Vec<ChatLine>>,
+    selected: ListState,
+    input: String,
+    qr: Option<String>,
+}
+
+impl App {
+    fn selected_chat(&self) -> Option<&JID> {
+        self.selected.selected().and_then(|i| self.chats.get(i))
+    }
+
+    /// Move a chat to the top of the list, keeping the selection on the
+    /// chat it was on.
+    fn touch(&mut self, chat: &JID) {
+        let selected = self.selected_chat().cloned();
+        self.chats.retain(|c| c != chat);
+        self.chats.insert(0, chat.clone());
+        let index = selected
+            .and_then(|s| self.chats.iter().position(|c| *c == s))
+            .unwrap_or(0);
+        self.selected.select(Some(index));
+    }
+
+    fn push(&mut self, chat: &JID, line: ChatLine) {
+        self.messages.entry(chat.clone()).or_default().push(line);
+        self.touch(chat);
+    }
+
+    fn handle_event(&mut self, event: Event) {
+        match event {
+            Event::QRCode(qr) => {
+                self.status = "scan the QR code with WhatsApp to link this device".to_string();
+                self.qr = Some(QRPairing::render_qr_ascii(&qr.code).unwrap_or(qr.code));
+            }
+            Event::Connected(_) => {
+                self.connected = true;
+                self.qr = None;
+                self.status = "connected".to_string();
+            }
+            Event::Disconnected(disconnected) => {
+                self.connected = false;
+                self.status = format!("disconnected: {:?}", disconnected.reason);
+            }
+            Event::LoggedOut(_) => {
+                self.connected = false;
+                self.status = "logged out".to_string();
+            }
+            Event::Message(msg) => {
+                let from = if msg.info.is_from_me {
+                    "me".to_string()
+                } else {
+                    msg.info.push_name.clone().unwrap_or_else(|| msg.info.sender.user.clone())
+                };
+                let line = ChatLine { from, text: describe(&msg.content), from_me: msg.info.is_from_me };
+                self.push(&msg.info.chat, line);
+            }
+            _ => {}
+        }
+    }
+
+    /// Act on a key press; returns false to quit.
+    fn handle_key(&mut self, key: KeyEvent, sends: &mpsc::UnboundedSender<(JID, String)>) -> bool {
+        match key.code {
+            KeyCode::Esc => return false,
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => return false,
+            KeyCode::Char(c) => self.input.push(c),
+            KeyCode::Backspace => {
+                self.input.pop();
+            }
+            KeyCode::Up => {
+                let index = self.selected.selected().unwrap_or(0).saturating_sub(1);
+                self.selected.select(Some(index));
+            }
+            KeyCode::Down if !self.chats.is_empty() => {
+                let index = self.selected.selected().map_or(0, |i| (i + 1).min(self.chats.len() - 1));
+                self.selected.select(Some(index));
+            }
+            KeyCode::Enter => self.submit(sends),
+            _ => {}
+        }
+        true
+    }
+
+    fn submit(&mut self, sends: &mpsc::UnboundedSender<(JID, String)>) {
+        let input = std::mem::take(&mut self.input);
+        let input = input.trim();
+        if input.is_empty() {
+            return;
+        }
+        if let Some(jid) = input.strip_prefix("/to ") {
+            match jid.trim().parse::<JID>() {
+                Ok(jid) => self.touch(&jid),
+                Err(err) => self.status = format!("invalid JID {jid}: {err}"),
+            }
+            return;
+        }
+        let Some(chat) = self.selected_chat().cloned() else {
+            self.status = "open a chat first with /to <jid>".to_string();
+            return;
+        };
+        let _ = sends.send((chat.clone(), input.to_string()));
+        self.push(&chat, ChatLine { from: "me".to_string(), text: input.to_string(), from_me: true });
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [main, input, status] = Layout::vertical([
+            Constraint::Min(1),
+            Constraint::Length(3),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+        let [chats, messages] = Layout::horizontal([Constraint::Length(32), Constraint::Min(1)]).areas(main);
+
+        let items: Vec<ListItem> = self.chats.iter().map(|chat| ListItem::new(chat.to_string())).collect();
+        let list = List::new(items)
+            .block(Block::default().borders(Borders::ALL).title("Chats"))
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+        frame.render_stateful_widget(list, chats, &mut self.selected);
+
+        let (title, lines) = match (&self.qr, self.selected_chat()) {
+            (Some(qr), _) => ("Link device".to_string(), qr.lines().map(|l| Line::raw(l.to_string())).collect()),
+            (None, Some(chat)) => {
+                let lines: Vec<Line> = self.messages.get(chat).into_iter().flatten().map(|m| {
+                    let color = if m.from_me { Color::Green } else { Color::Cyan };
+                    Line::from(vec![
+                        Span::styled(format!("{}: ", m.from), Style::default().fg(color)),
+                        Span::raw(m.text.clone()),
+                    ])
+                }).collect();
+                (chat.to_string(), lines)
+            }
+            (None, None) => ("Messages".to_string(), Vec::new()),
+        };
+        // Keep the newest messages in view
+        let height = messages.height.saturating_sub(2) as usize;
+        let scroll = lines.len().saturating_sub(height) as u16;
+        let pane = Paragraph::new(lines)
+            .block(Block::default().borders(Borders::ALL).title(title))
+            .scroll((scroll, 0));
+        frame.render_widget(pane, messages);
+
+        let input_box = Paragraph::new(self.input.as_str())
+            .block(Block::default().borders(Borders::ALL).title("Message (Enter sends, /to <jid>, Esc quits)"));
+        frame.render_widget(input_box, input);
+
+        let color = if self.connected { Color::Green } else { Color::Red };
+        let bar = Paragraph::new(self.status.as_str()).style(Style::default().fg(Color::Black).bg(color));
+        frame.render_widget(bar, status);
+    }
+}
+
+/// One-line description of a message.
+fn describe(content: &MessageContent) -> String {
+    match content {
+        MessageContent::Text(text) => text.clone(),
+        MessageContent::Image { caption, .. } => format!("<image> {}", caption.as_deref().unwrap_or("")),
+        MessageContent::Video { caption, .. } => format!("<video> {}", caption.as_deref().unwrap_or("")),
+        MessageContent::Audio { .. } => "<audio>".to_string(),
+        MessageContent::Document { filename, .. } => format!("<document> {filename}"),
+        MessageContent::Sticker { .. } => "<sticker>".to_string(),
+        MessageContent::Reaction { emoji, .. } => format!("<reaction> {emoji}"),
+        _ => "<unsupported message>".to_string(),
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut client = Client::new();
+    client.connect().await?;
+    let handle = client.handle().ok_or("no connection handle")?;
+
+    // Terminal input blocks, so it is read on its own thread
+    let (keys_tx, mut keys) = mpsc::unbounded_channel();
+    std::thread::spawn(move || {
+        while let Ok(event) = event::read() {
+            if let TermEvent::Key(key) = event {
+                if key.kind == KeyEventKind::Press && keys_tx.send(key).is_err() {
+                    break;
+                }
+            }
+        }
+    });
+
+    // Sends run in the background and report failures back to the status bar
+    let (sends_tx, mut sends) = mpsc::unbounded_channel::<(JID, String)>();
+    let (errors_tx, mut errors) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Some((to, text)) = sends.recv().await {
+            if let Err(err) = handle.send_message(to.clone(), &text).await {
+                let _ = errors_tx.send(format!("sending to {to} failed: {err}"));
+            }
+        }
+    });
+
+    let mut app = App { status: "connecting".to_string(), ..App::default() };
+    let mut receiving = true;
+    let mut terminal = ratatui::init();
+    loop {
+        terminal.draw(|frame| app.draw(frame))?;
+        tokio::select! {
+            event = client.receive(), if receiving => match event {
+                Ok(Some(event)) => app.handle_event(event),
+                Ok(None) => {}
+                Err(err) => {
+                    receiving = false;
+                    app.connected = false;
+                    app.status = format!("connection closed: {err}");
+                }
+            },
+            Some(key) = keys.recv() => {
+                if !app.handle_key(key, &sends_tx) {
+                    break;
+                }
+            }
+            Some(error) = errors.recv() => app.status = error,
+        }
+    }
+    ratatui::restore();
+
+    let _ = client.disconnect().await;
+    Ok(())
+}
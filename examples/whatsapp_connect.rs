@@ -17,8 +17,8 @@ use whatsmeow_rust::{
     Device, JID,
     protocol::{QRPairing, build_text_message},
     types::servers,
-    crypto::{KeyPair, NoiseHandshake, Cipher, Hkdf},
-    binary::{Node, encode, decode},
+    crypto::NoiseHandshake,
+    binary::encode,
 };
 
 /// WhatsApp WebSocket endpoint
@@ -42,8 +42,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let noise_key = device.noise_key.clone().expect("noise key");
     let identity_key = device.identity_key.clone().expect("identity key");
     
-    println!("   ✓ Noise key: {}...", &hex::encode(&noise_key.public)[..16]);
-    println!("   ✓ Identity key: {}...", &hex::encode(&identity_key.public)[..16]);
+    println!("   ✓ Noise key: {}...", &hex::encode(noise_key.public)[..16]);
+    println!("   ✓ Identity key: {}...", &hex::encode(identity_key.public)[..16]);
     println!();
 
     // Step 2: Connect to WhatsApp WebSocket
@@ -55,7 +55,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         connect_async(WA_ENDPOINT)
     ).await;
 
-    let (mut ws_stream, response) = match connect_result {
+    let (mut ws_stream, _response) = match connect_result {
         Ok(Ok((stream, resp))) => {
             println!("   ✓ Connected! Status: {}", resp.status());
             (stream, resp)
@@ -91,7 +91,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("   Sending handshake message 1 ({} bytes)...", handshake_frame.len());
     
     // Send handshake
-    ws_stream.send(Message::Binary(handshake_frame.into())).await?;
+    ws_stream.send(Message::Binary(handshake_frame)).await?;
     println!("   ✓ Handshake message 1 sent");
     
     // Wait for response
@@ -139,7 +139,9 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     // Step 4: Generate QR code for pairing (would be sent after handshake)
     println!("📲 Step 4: QR Code for pairing...");
-    let pairing = QRPairing::new(device.clone());
+    // The refs come from the server's pair-device query; a connected
+    // Client emits QRCode events for them instead
+    let pairing = QRPairing::new(&device, vec!["2@example-ref".to_string()]);
     
     if let Some(qr_data) = pairing.current_code() {
         println!("   QR Data: {}", &qr_data[..qr_data.len().min(60)]);
@@ -12,7 +12,7 @@ use tokio::time::timeout;
 
 use whatsmeow_rust::{
     Device,
-    socket::{do_handshake, WhatsAppConnection},
+    socket::do_handshake,
     protocol::QRPairing,
 };
 
@@ -35,7 +35,9 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     // Step 2: Display QR code for pairing  
     println!("📲 QR Code for pairing (scan with WhatsApp):");
-    let pairing = QRPairing::new(device.clone());
+    // The refs come from the server's pair-device query; a connected
+    // Client emits QRCode events for them instead
+    let pairing = QRPairing::new(&device, vec!["2@example-ref".to_string()]);
     
     if let Some(qr_data) = pairing.current_code() {
         match QRPairing::render_qr_ascii(qr_data) {
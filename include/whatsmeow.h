@@ -0,0 +1,181 @@
+#ifndef WHATSMEOW_H
+#define WHATSMEOW_H
+
+/* Generated by cbindgen from src/ffi.rs; do not edit. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+// Result of a call.
+typedef enum WmStatus {
+  WM_STATUS_OK = 0,
+  // A required pointer was null
+  WM_STATUS_NULL_ARGUMENT,
+  // A string was not valid UTF-8
+  WM_STATUS_INVALID_UTF8,
+  // A JID could not be parsed
+  WM_STATUS_INVALID_JID,
+  // Connecting or the handshake failed
+  WM_STATUS_CONNECT_FAILED,
+  // The client is not connected
+  WM_STATUS_NOT_CONNECTED,
+  // Sending failed
+  WM_STATUS_SEND_FAILED,
+  // Any other failure
+  WM_STATUS_FAILED,
+  // The library panicked; the client should be freed
+  WM_STATUS_PANICKED,
+} WmStatus;
+
+// Kind of a `WmEvent`.
+typedef enum WmEventKind {
+  WM_EVENT_KIND_CONNECTED = 0,
+  WM_EVENT_KIND_DISCONNECTED,
+  WM_EVENT_KIND_LOGGED_OUT,
+  // `text` is the code to show as a QR code
+  WM_EVENT_KIND_QR_CODE,
+  // `text` is the code to enter on the phone
+  WM_EVENT_KIND_PAIRING_CODE,
+  // `chat`, `sender`, `id` and `text` are set; `text` is null for
+  // messages without text
+  WM_EVENT_KIND_MESSAGE,
+  // `chat`, `sender` and `id` (the first receipted message) are set
+  WM_EVENT_KIND_RECEIPT,
+  // An event with no C representation yet
+  WM_EVENT_KIND_OTHER,
+} WmEventKind;
+
+// Opaque client handle.
+typedef struct WmClient WmClient;
+
+// An event. Unused fields are null.
+typedef struct WmEvent {
+  enum WmEventKind kind;
+  const char *chat;
+  const char *sender;
+  const char *id;
+  const char *text;
+} WmEvent;
+
+// Callback receiving events on a library thread.
+//
+// The event is only valid during the call. Calling back into the client
+// from the callback is not allowed, as it would block the thread events
+// are delivered on; hand the event to another thread instead.
+typedef void (*WmEventCallback)(void *user_data, const struct WmEvent *event);
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// Create a client. The paired device, its keys and the chat history are
+// kept in the SQLite database at `db_path`, so the client logs in again
+// after a restart; with a null `db_path` everything stays in memory and
+// the device has to pair every time.
+//
+// Returns null on failure.
+//
+// # Safety
+//
+// `db_path` must be null or a NUL-terminated string.
+struct WmClient *wm_client_new(const char *db_path);
+
+// Disconnect and free a client.
+//
+// # Safety
+//
+// `client` must be null or come from `wm_client_new`, and not be used
+// afterwards.
+void wm_client_free(struct WmClient *client);
+
+// Call `callback` with every event, replacing any previous callback. A
+// null `callback` removes it, and events are queued for
+// `wm_client_poll_event` again.
+//
+// # Safety
+//
+// `client` must come from `wm_client_new`. `user_data` is passed back
+// as is and must be usable from any thread.
+enum WmStatus wm_client_set_event_callback(struct WmClient *client,
+                                           WmEventCallback callback,
+                                           void *user_data);
+
+// Connect to WhatsApp.
+//
+// # Safety
+//
+// `client` must come from `wm_client_new`.
+enum WmStatus wm_client_connect(struct WmClient *client);
+
+// Disconnect from WhatsApp. The client can connect again afterwards.
+//
+// # Safety
+//
+// `client` must come from `wm_client_new`.
+enum WmStatus wm_client_disconnect(struct WmClient *client);
+
+// Get the QR code currently linking this device, to be freed with
+// `wm_string_free`.
+//
+// Returns null until the server starts pairing after `wm_client_connect`,
+// and once pairing has ended.
+//
+// # Safety
+//
+// `client` must come from `wm_client_new`.
+char *wm_client_pair_qr(struct WmClient *client);
+
+// Send a text message to `to`, a JID such as `123456789@s.whatsapp.net`.
+//
+// If `out_id` is not null, it receives the message ID, to be freed with
+// `wm_string_free`.
+//
+// # Safety
+//
+// `client` must come from `wm_client_new`; `to` and `text` must be
+// NUL-terminated strings.
+enum WmStatus wm_client_send_text(struct WmClient *client,
+                                  const char *to,
+                                  const char *text,
+                                  char **out_id);
+
+// Wait up to `timeout_ms` milliseconds for the next queued event. Events
+// are only queued while no callback is registered.
+//
+// On success `out_event` receives the event, to be freed with
+// `wm_event_free`, or null if none arrived in time.
+//
+// # Safety
+//
+// `client` must come from `wm_client_new` and `out_event` must be valid
+// for writes.
+enum WmStatus wm_client_poll_event(struct WmClient *client,
+                                   uint32_t timeout_ms,
+                                   struct WmEvent **out_event);
+
+// Free an event from `wm_client_poll_event`.
+//
+// # Safety
+//
+// `event` must be null or come from `wm_client_poll_event`.
+void wm_event_free(struct WmEvent *event);
+
+// Free a string returned by the library.
+//
+// # Safety
+//
+// `s` must be null or a string returned by the library.
+void wm_string_free(char *s);
+
+// Describe the last failure on this thread, or return null.
+//
+// The string stays valid until the next failing call on this thread.
+const char *wm_last_error(void);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* WHATSMEOW_H */
@@ -2,8 +2,13 @@
 //!
 //! Decodes WhatsApp's binary XML format into Node structures.
 
+use std::borrow::Cow;
+
 use super::node::{Node, NodeContent, AttrValue, Attrs};
-use super::token::get_token;
+use super::token::{
+    default_dictionary, TokenDictionary, AD_JID, BINARY_20, BINARY_32, BINARY_8, DICTIONARY_0, HEX_8,
+    JID_PAIR, LIST_16, LIST_8, LIST_EMPTY, NIBBLE_8,
+};
 use crate::types::JID;
 
 /// Error type for decoding
@@ -22,17 +27,28 @@ impl std::error::Error for DecodeError {}
 pub struct Decoder<'a> {
     data: &'a [u8],
     index: usize,
+    dict: &'static TokenDictionary,
 }
 
 impl<'a> Decoder<'a> {
     /// Create a new decoder
     pub fn new(data: &'a [u8]) -> Self {
-        Self { data, index: 0 }
+        Self::with_dictionary(data, default_dictionary())
+    }
+
+    /// Create a decoder using the given token dictionary
+    pub fn with_dictionary(data: &'a [u8], dict: &'static TokenDictionary) -> Self {
+        Self { data, index: 0, dict }
     }
 
     /// Decode the data into a node
     pub fn decode(data: &[u8]) -> Result<Node, DecodeError> {
-        let mut decoder = Decoder::new(data);
+        Self::decode_with(data, default_dictionary())
+    }
+
+    /// Decode the data into a node using the given token dictionary
+    pub fn decode_with(data: &[u8], dict: &'static TokenDictionary) -> Result<Node, DecodeError> {
+        let mut decoder = Decoder::with_dictionary(data, dict);
         let node = decoder.read_node()?;
         
         if decoder.index != decoder.data.len() {
@@ -45,11 +61,6 @@ impl<'a> Decoder<'a> {
         Ok(node)
     }
 
-    /// Check if there's more data
-    fn has_more(&self) -> bool {
-        self.index < self.data.len()
-    }
-
     /// Read a single byte
     fn read_byte(&mut self) -> Result<u8, DecodeError> {
         if self.index >= self.data.len() {
@@ -62,14 +73,27 @@ impl<'a> Decoder<'a> {
 
     /// Read multiple bytes
     fn read_bytes(&mut self, n: usize) -> Result<Vec<u8>, DecodeError> {
-        if self.index + n > self.data.len() {
+        self.read_slice(n).map(<[u8]>::to_vec)
+    }
+
+    /// Borrow the next `n` bytes
+    fn read_slice(&mut self, n: usize) -> Result<&'a [u8], DecodeError> {
+        if n > self.data.len() - self.index {
             return Err(DecodeError("unexpected end of data".to_string()));
         }
-        let bytes = self.data[self.index..self.index + n].to_vec();
+        let bytes = &self.data[self.index..self.index + n];
         self.index += n;
         Ok(bytes)
     }
 
+    /// Read a raw UTF-8 string of `len` bytes
+    fn read_raw_string(&mut self, len: usize) -> Result<Cow<'static, str>, DecodeError> {
+        let bytes = self.read_slice(len)?;
+        std::str::from_utf8(bytes)
+            .map(|s| Cow::Owned(s.to_string()))
+            .map_err(|e| DecodeError(format!("invalid utf8: {}", e)))
+    }
+
     /// Read an integer based on length marker
     fn read_int(&mut self, bytes: usize) -> Result<usize, DecodeError> {
         let mut result = 0usize;
@@ -79,48 +103,59 @@ impl<'a> Decoder<'a> {
         Ok(result)
     }
 
-    /// Read a string (possibly from token)
-    fn read_string(&mut self, tag: u8) -> Result<String, DecodeError> {
+    /// Read the length of binary data
+    fn read_binary_len(&mut self, tag: u8) -> Result<usize, DecodeError> {
         match tag {
-            0x00 => Ok(String::new()),
-            0xFC => {
-                // Short string
-                let len = self.read_byte()? as usize;
-                if len == 0 {
-                    return Ok(String::new());
-                }
-                let bytes = self.read_bytes(len)?;
-                String::from_utf8(bytes)
-                    .map_err(|e| DecodeError(format!("invalid utf8: {}", e)))
-            }
-            0xFD => {
-                // Medium string
-                let len = self.read_int(2)?;
-                let bytes = self.read_bytes(len)?;
-                String::from_utf8(bytes)
-                    .map_err(|e| DecodeError(format!("invalid utf8: {}", e)))
+            BINARY_8 => Ok(self.read_byte()? as usize),
+            BINARY_20 => Ok(self.read_int(3)? & 0x0F_FFFF),
+            BINARY_32 => self.read_int(4),
+            _ => Err(DecodeError(format!("expected binary tag, got 0x{:02x}", tag))),
+        }
+    }
+
+    /// Read a string packed two characters to a byte
+    fn read_packed(&mut self, tag: u8) -> Result<Cow<'static, str>, DecodeError> {
+        let start = self.read_byte()?;
+        let mut s = String::with_capacity(2 * (start & 0x7F) as usize);
+        for _ in 0..start & 0x7F {
+            let byte = self.read_byte()?;
+            for nibble in [byte >> 4, byte & 0x0F] {
+                s.push(unpack(tag, nibble)?);
             }
-            0xFE => {
-                // Long string
-                let len = self.read_int(3)?;
-                let bytes = self.read_bytes(len)?;
-                String::from_utf8(bytes)
-                    .map_err(|e| DecodeError(format!("invalid utf8: {}", e)))
+        }
+        // An odd length ends in a padding nibble
+        if start & 0x80 != 0 {
+            s.pop();
+        }
+        Ok(Cow::Owned(s))
+    }
+
+    /// Read a string (possibly from token)
+    ///
+    /// Tokens are borrowed from the dictionary rather than allocated.
+    fn read_string(&mut self, tag: u8) -> Result<Cow<'static, str>, DecodeError> {
+        match tag {
+            LIST_EMPTY => Ok(Cow::Borrowed("")),
+            BINARY_8 | BINARY_20 | BINARY_32 => {
+                let len = self.read_binary_len(tag)?;
+                self.read_raw_string(len)
             }
+            NIBBLE_8 | HEX_8 => self.read_packed(tag),
+            AD_JID | JID_PAIR => Ok(Cow::Owned(self.read_jid(tag)?.to_string())),
             // Dictionary tokens (double-byte)
             0xEC..=0xEF => {
-                let dict = tag - 0xEC;  // 0-3
+                let dict = tag - DICTIONARY_0;  // 0-3
                 let index = self.read_byte()?;
-                if let Some(token) = super::token::get_double_token(dict, index) {
-                    Ok(token.to_string())
+                if let Some(token) = self.dict.double_token(dict, index) {
+                    Ok(Cow::Borrowed(token))
                 } else {
                     Err(DecodeError(format!("unknown double token: dict={}, index={}", dict, index)))
                 }
             }
             _ => {
                 // Single-byte token
-                if let Some(token) = get_token(tag) {
-                    Ok(token.to_string())
+                if let Some(token) = self.dict.token(tag) {
+                    Ok(Cow::Borrowed(token))
                 } else {
                     Err(DecodeError(format!("unknown token: {}", tag)))
                 }
@@ -131,21 +166,19 @@ impl<'a> Decoder<'a> {
     /// Read a JID
     fn read_jid(&mut self, marker: u8) -> Result<JID, DecodeError> {
         match marker {
-            0xF9 => {
-                // Regular JID
+            JID_PAIR => {
                 let user_tag = self.read_byte()?;
                 let user = self.read_string(user_tag)?;
                 let server_tag = self.read_byte()?;
                 let server = self.read_string(server_tag)?;
-                Ok(JID::new(user, server))
+                Ok(JID::new(user.into_owned(), server.into_owned()))
             }
-            0xFA => {
-                // AD JID
+            AD_JID => {
                 let agent = self.read_byte()?;
                 let device = self.read_byte()?;
                 let user_tag = self.read_byte()?;
                 let user = self.read_string(user_tag)?;
-                Ok(JID::new_ad(user, agent, device))
+                Ok(JID::new_ad(user.into_owned(), agent, device))
             }
             _ => Err(DecodeError(format!("invalid JID marker: {}", marker))),
         }
@@ -155,23 +188,19 @@ impl<'a> Decoder<'a> {
     fn read_attr_value(&mut self) -> Result<AttrValue, DecodeError> {
         let tag = self.read_byte()?;
         match tag {
-            0x00 => Ok(AttrValue::None),
-            0xF9 | 0xFA => {
+            LIST_EMPTY => Ok(AttrValue::None),
+            AD_JID | JID_PAIR => {
                 let jid = self.read_jid(tag)?;
                 Ok(AttrValue::JID(jid))
             }
-            0xFF => {
-                // Bytes
-                let len_tag = self.read_byte()?;
-                let len = match len_tag {
-                    n if n < 0xFC => n as usize,
-                    0xFC => self.read_byte()? as usize,
-                    0xFD => self.read_int(2)?,
-                    0xFE => self.read_int(3)?,
-                    _ => return Err(DecodeError("invalid length marker".to_string())),
-                };
+            BINARY_8 | BINARY_20 | BINARY_32 => {
+                // Binary values are text unless they are not UTF-8
+                let len = self.read_binary_len(tag)?;
                 let bytes = self.read_bytes(len)?;
-                Ok(AttrValue::Bytes(bytes))
+                Ok(match String::from_utf8(bytes) {
+                    Ok(s) => AttrValue::String(Cow::Owned(s)),
+                    Err(err) => AttrValue::Bytes(err.into_bytes()),
+                })
             }
             _ => {
                 let s = self.read_string(tag)?;
@@ -183,9 +212,9 @@ impl<'a> Decoder<'a> {
     /// Read list size from token
     fn read_list_size(&mut self, token: u8) -> Result<usize, DecodeError> {
         match token {
-            0x00 => Ok(0),
-            0xF8 => Ok(self.read_byte()? as usize),
-            0xF9 => Ok(self.read_int(2)?),
+            LIST_EMPTY => Ok(0),
+            LIST_8 => Ok(self.read_byte()? as usize),
+            LIST_16 => Ok(self.read_int(2)?),
             _ => Err(DecodeError(format!("expected list token (f8/f9), got 0x{:02x}", token))),
         }
     }
@@ -204,7 +233,7 @@ impl<'a> Decoder<'a> {
         let tag_marker = self.read_byte()?;
         let tag = self.read_string(tag_marker)?;
 
-        let mut attrs = Attrs::new();
+        let mut attrs = Attrs::with_capacity((size - 1) / 2);
         
         // Number of attribute pairs = (size - 1) / 2
         let num_attr_pairs = (size - 1) / 2;
@@ -223,7 +252,7 @@ impl<'a> Decoder<'a> {
         let content = if has_content {
             let content_marker = self.read_byte()?;
             match content_marker {
-                0xF8 | 0xF9 => {
+                LIST_EMPTY | LIST_8 | LIST_16 => {
                     // List -> Children
                     let len = self.read_list_size(content_marker)?;
                     let mut children = Vec::with_capacity(len);
@@ -232,20 +261,14 @@ impl<'a> Decoder<'a> {
                     }
                     NodeContent::Children(children)
                 }
-                0xFF | 0xFC | 0xFD | 0xFE => {
-                    // Bytes
-                    let len = match content_marker {
-                        0xFC => self.read_byte()? as usize,
-                        0xFD => self.read_int(2)?,
-                        0xFE => self.read_int(3)?,
-                        _ => return Err(DecodeError("invalid bytes length".to_string())), // FF shouldn't happen alone?
-                    };
+                BINARY_8 | BINARY_20 | BINARY_32 => {
+                    let len = self.read_binary_len(content_marker)?;
                     NodeContent::Bytes(self.read_bytes(len)?)
                 }
                 _ => {
                     // String content - treat as bytes
                     let s = self.read_string(content_marker)?;
-                    NodeContent::Bytes(s.into_bytes())
+                    NodeContent::Bytes(s.into_owned().into_bytes())
                 }
             }
         } else {
@@ -256,6 +279,19 @@ impl<'a> Decoder<'a> {
     }
 }
 
+/// Character of a packed nibble.
+fn unpack(tag: u8, nibble: u8) -> Result<char, DecodeError> {
+    match (tag, nibble) {
+        (_, 0..=9) => Ok((b'0' + nibble) as char),
+        (NIBBLE_8, 10) => Ok('-'),
+        (NIBBLE_8, 11) => Ok('.'),
+        // Padding after an odd number of characters
+        (NIBBLE_8, 15) => Ok('\0'),
+        (HEX_8, 10..=15) => Ok((b'A' + nibble - 10) as char),
+        _ => Err(DecodeError(format!("invalid packed nibble: {}", nibble))),
+    }
+}
+
 /// Decode binary data into a node
 pub fn decode(data: &[u8]) -> Result<Node, DecodeError> {
     Decoder::decode(data)
@@ -273,7 +309,46 @@ mod tests {
         node.set_attr("type", "text");
         
         let encoded = encode(&node);
-        // Note: Full roundtrip testing requires consistent encoding
         assert!(!encoded.is_empty());
+
+        let decoded = decode(&encoded).unwrap();
+        assert_eq!(decoded.tag, "message");
+        assert_eq!(decoded.get_attr_str("id"), Some("test123"));
+        assert_eq!(decoded.get_attr_str("type"), Some("text"));
+    }
+
+    #[test]
+    fn test_roundtrip_content() {
+        let mut enc = Node::new("enc");
+        enc.set_attr("v", "2");
+        enc.set_bytes(vec![7; 300]);
+        let mut node = Node::new("message");
+        node.set_attr("to", JID::new("1234", "s.whatsapp.net"));
+        node.set_attr("id", "");
+        node.add_child(enc);
+        node.add_child(Node::new("meta"));
+
+        let decoded = decode(&encode(&node)).unwrap();
+        assert_eq!(decoded.get_attr_jid("to"), Some(&JID::new("1234", "s.whatsapp.net")));
+        assert_eq!(decoded.get_attr_str("id"), Some(""));
+        let children = decoded.get_children().unwrap();
+        assert_eq!(children[0].get_bytes(), Some(&[7; 300][..]));
+        assert_eq!(children[1].tag, "meta");
+
+        // Tokens are not copied
+        assert!(matches!(decoded.tag, Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn test_double_byte_tokens() {
+        let mut node = Node::new("call");
+        node.set_attr("type", "reject");
+        let encoded = encode(&node);
+        assert!(encoded.windows(2).any(|w| w == [DICTIONARY_0 + 1, 0]));
+
+        let mut decoder = Decoder::new(&[0]);
+        assert_eq!(decoder.read_string(DICTIONARY_0 + 1).unwrap(), "reject");
+        let mut decoder = Decoder::new(&[255]);
+        assert_eq!(decoder.read_string(DICTIONARY_0 + 3).unwrap(), "1961");
     }
 }
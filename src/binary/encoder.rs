@@ -2,27 +2,57 @@
 //!
 //! Encodes Node structures into WhatsApp's binary XML format.
 
-use super::node::{Node, NodeContent, AttrValue};
-use super::token::get_token_index;
+use std::io::Write as _;
 
-/// Binary encoder for WhatsApp XML nodes
+use super::node::{Node, NodeContent, AttrValue};
+use super::token::{
+    default_dictionary, Token, TokenDictionary, AD_JID, BINARY_20, BINARY_32, BINARY_8, DICTIONARY_0,
+    HEX_8, JID_PAIR, LIST_16, LIST_8, LIST_EMPTY, NIBBLE_8, PACKED_MAX,
+};
+
+/// Initial buffer size; most stanzas fit without growing.
+const INITIAL_CAPACITY: usize = 256;
+
+/// Binary encoder for WhatsApp XML nodes.
+///
+/// An encoder kept across calls to `encode_node` reuses its buffer, so a
+/// connection sending many stanzas does not allocate for each one.
 pub struct Encoder {
     data: Vec<u8>,
+    dict: &'static TokenDictionary,
 }
 
 impl Encoder {
     /// Create a new encoder
     pub fn new() -> Self {
-        Self { data: Vec::new() }
+        Self::with_dictionary(default_dictionary())
+    }
+
+    /// Create an encoder using the given token dictionary
+    pub fn with_dictionary(dict: &'static TokenDictionary) -> Self {
+        Self { data: Vec::with_capacity(INITIAL_CAPACITY), dict }
     }
 
     /// Encode a node and return the binary data
     pub fn encode(node: &Node) -> Vec<u8> {
-        let mut encoder = Self::new();
+        Self::encode_with(node, default_dictionary())
+    }
+
+    /// Encode a node using the given token dictionary
+    pub fn encode_with(node: &Node, dict: &'static TokenDictionary) -> Vec<u8> {
+        let mut encoder = Self::with_dictionary(dict);
         encoder.write_node(node);
         encoder.data
     }
 
+    /// Encode a node into the encoder's buffer, replacing what the previous
+    /// call wrote.
+    pub fn encode_node(&mut self, node: &Node) -> &[u8] {
+        self.data.clear();
+        self.write_node(node);
+        &self.data
+    }
+
     /// Write a byte
     fn write_byte(&mut self, b: u8) {
         self.data.push(b);
@@ -33,64 +63,84 @@ impl Encoder {
         self.data.extend_from_slice(bytes);
     }
 
-    /// Write an integer with variable byte encoding
-    fn write_int(&mut self, n: usize) {
-        if n < 256 {
-            self.write_byte(n as u8);
-        } else if n < 65536 {
-            self.write_byte(((n >> 8) & 0xFF) as u8);
-            self.write_byte((n & 0xFF) as u8);
-        } else {
-            self.write_byte(((n >> 16) & 0xFF) as u8);
-            self.write_byte(((n >> 8) & 0xFF) as u8);
-            self.write_byte((n & 0xFF) as u8);
+    /// Write an integer in `n` big-endian bytes
+    fn write_int_n(&mut self, value: usize, n: usize) {
+        for i in (0..n).rev() {
+            self.write_byte((value >> (i * 8)) as u8);
         }
     }
 
-    /// Write a string (possibly as token)
-    fn write_string(&mut self, s: &str) {
-        if s.is_empty() {
-            self.write_byte(0xFC); // Empty string marker
-            return;
-        }
-
-        // Try to use a token
-        if let Some(token) = get_token_index(s) {
-            self.write_byte(token);
-            return;
+    /// Write the header of a list of `size` items
+    fn write_list_start(&mut self, size: usize) {
+        if size == 0 {
+            self.write_byte(LIST_EMPTY);
+        } else if size < 256 {
+            self.write_byte(LIST_8);
+            self.write_byte(size as u8);
+        } else {
+            self.write_byte(LIST_16);
+            self.write_int_n(size, 2);
         }
+    }
 
-        // Write as raw string
-        let bytes = s.as_bytes();
+    /// Write raw bytes behind an 8, 20 or 32-bit length
+    fn write_binary(&mut self, bytes: &[u8]) {
         if bytes.len() < 256 {
-            self.write_byte(0xFC); // Short string marker
+            self.write_byte(BINARY_8);
             self.write_byte(bytes.len() as u8);
-        } else if bytes.len() < 65536 {
-            self.write_byte(0xFD); // Medium string marker
-            self.write_byte(((bytes.len() >> 8) & 0xFF) as u8);
-            self.write_byte((bytes.len() & 0xFF) as u8);
+        } else if bytes.len() < 1 << 20 {
+            self.write_byte(BINARY_20);
+            self.write_int_n(bytes.len(), 3);
         } else {
-            self.write_byte(0xFE); // Long string marker
-            self.write_byte(((bytes.len() >> 16) & 0xFF) as u8);
-            self.write_byte(((bytes.len() >> 8) & 0xFF) as u8);
-            self.write_byte((bytes.len() & 0xFF) as u8);
+            self.write_byte(BINARY_32);
+            self.write_int_n(bytes.len(), 4);
         }
         self.write_bytes(bytes);
     }
 
+    /// Write a string two characters to a byte
+    fn write_packed(&mut self, s: &str, tag: u8, pack: fn(u8) -> u8) {
+        let bytes = s.as_bytes();
+        self.write_byte(tag);
+        // The top bit marks an odd length, whose last nibble is padding
+        self.write_byte(bytes.len().div_ceil(2) as u8 | ((bytes.len() as u8 & 1) << 7));
+        for pair in bytes.chunks(2) {
+            let low = pair.get(1).map_or(0x0F, |&c| pack(c));
+            self.write_byte(pack(pair[0]) << 4 | low);
+        }
+    }
+
+    /// Write a string (possibly as token)
+    fn write_string(&mut self, s: &str) {
+        // Try to use a token
+        match self.dict.lookup(s) {
+            Some(Token::Single(index)) => self.write_byte(index),
+            Some(Token::Double { dict, index }) => {
+                self.write_byte(DICTIONARY_0 + dict);
+                self.write_byte(index);
+            }
+            None if is_nibble(s) => self.write_packed(s, NIBBLE_8, pack_nibble),
+            None if is_hex(s) => self.write_packed(s, HEX_8, pack_hex),
+            None => self.write_binary(s.as_bytes()),
+        }
+    }
+
     /// Write an attribute value
     fn write_attr_value(&mut self, value: &AttrValue) {
         match value {
-            AttrValue::None => self.write_byte(0x00),
+            AttrValue::None => self.write_byte(LIST_EMPTY),
             AttrValue::String(s) => self.write_string(s),
-            AttrValue::Bytes(b) => {
-                self.write_byte(0xFF); // Bytes marker
-                self.write_int(b.len());
-                self.write_bytes(b);
-            }
+            AttrValue::Bytes(b) => self.write_binary(b),
             AttrValue::Int(n) => {
-                // Write as string representation
-                self.write_string(&n.to_string());
+                // Write as string representation, formatted on the stack
+                let mut buf = [0u8; 20];
+                let len = {
+                    let mut cursor = std::io::Cursor::new(&mut buf[..]);
+                    let _ = write!(cursor, "{}", n);
+                    cursor.position() as usize
+                };
+                let digits = std::str::from_utf8(&buf[..len]).unwrap_or_default();
+                self.write_string(digits);
             }
             AttrValue::Bool(b) => {
                 self.write_string(if *b { "true" } else { "false" });
@@ -104,31 +154,27 @@ impl Encoder {
     /// Write a JID
     fn write_jid(&mut self, jid: &crate::types::JID) {
         if jid.raw_agent > 0 || jid.device > 0 {
-            // AD JID
-            self.write_byte(0xFA); // AD JID marker
-            self.write_byte(jid.raw_agent);
+            // The agent byte stands for the server
+            self.write_byte(AD_JID);
+            self.write_byte(jid.actual_agent());
             self.write_byte(jid.device as u8);
             self.write_string(&jid.user);
         } else {
-            // Regular JID - write as user@server
-            self.write_byte(0xF9); // JID marker
-            self.write_string(&jid.user);
+            self.write_byte(JID_PAIR);
+            if jid.user.is_empty() {
+                self.write_byte(LIST_EMPTY);
+            } else {
+                self.write_string(&jid.user);
+            }
             self.write_string(&jid.server);
         }
     }
 
     /// Write a node
     fn write_node(&mut self, node: &Node) {
-        // Determine number of attributes
-        let num_attrs = node.attrs.len();
-        
-        // Determine if there's content
+        // A node is a list of the tag, the attribute pairs and the content
         let has_content = !matches!(node.content, NodeContent::None);
-
-        // Write node header
-        // Format: 1 byte for number of attrs + has_content flag
-        let header = ((num_attrs << 1) | (if has_content { 1 } else { 0 })) as u8;
-        self.write_byte(header);
+        self.write_list_start(1 + 2 * node.attrs.len() + usize::from(has_content));
 
         // Write tag
         self.write_string(&node.tag);
@@ -144,17 +190,12 @@ impl Encoder {
             NodeContent::None => {}
             NodeContent::Children(children) => {
                 // Write list header
-                self.write_byte(0xF8); // List marker
-                self.write_int(children.len());
+                self.write_list_start(children.len());
                 for child in children {
                     self.write_node(child);
                 }
             }
-            NodeContent::Bytes(bytes) => {
-                self.write_byte(0xFF); // Bytes marker
-                self.write_int(bytes.len());
-                self.write_bytes(bytes);
-            }
+            NodeContent::Bytes(bytes) => self.write_binary(bytes),
         }
     }
 }
@@ -165,6 +206,28 @@ impl Default for Encoder {
     }
 }
 
+/// Whether a string is packed as nibbles: digits, `-` and `.`.
+fn is_nibble(s: &str) -> bool {
+    s.len() <= PACKED_MAX && s.bytes().all(|c| c.is_ascii_digit() || c == b'-' || c == b'.')
+}
+
+/// Whether a string is packed as uppercase hex.
+fn is_hex(s: &str) -> bool {
+    s.len() <= PACKED_MAX && s.bytes().all(|c| c.is_ascii_digit() || (b'A'..=b'F').contains(&c))
+}
+
+fn pack_nibble(c: u8) -> u8 {
+    match c {
+        b'-' => 10,
+        b'.' => 11,
+        _ => c - b'0',
+    }
+}
+
+fn pack_hex(c: u8) -> u8 {
+    if c.is_ascii_digit() { c - b'0' } else { c - b'A' + 10 }
+}
+
 /// Encode a node to binary format
 pub fn encode(node: &Node) -> Vec<u8> {
     Encoder::encode(node)
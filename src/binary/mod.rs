@@ -9,6 +9,9 @@ mod encoder;
 mod decoder;
 
 pub use node::*;
-pub use token::{get_token, get_token_index, SINGLE_BYTE_TOKENS};
+pub use token::{
+    get_token, get_token_index, get_double_token, dictionary, default_dictionary, Token,
+    TokenDictionary, DICTIONARY_V3, DICT_VERSION, SINGLE_BYTE_TOKENS, DOUBLE_BYTE_TOKENS,
+};
 pub use encoder::{encode, Encoder};
 pub use decoder::{decode, Decoder, DecodeError};
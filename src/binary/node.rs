@@ -3,17 +3,39 @@
 //! WhatsApp uses a custom binary XML format for message encoding.
 //! This module provides the Node type and serialization.
 
+use std::borrow::Cow;
 use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+
 use crate::types::JID;
 
-/// Attributes of an XML node
-pub type Attrs = HashMap<String, AttrValue>;
+/// Attributes of an XML node.
+///
+/// Keys are borrowed when they are dictionary tokens, as almost all are.
+pub type Attrs = HashMap<Cow<'static, str>, AttrValue>;
 
 /// Possible values for node attributes
+///
+/// The wire only knows strings, binary data and JIDs, so values are
+/// written and read back as:
+///
+/// | Written          | On the wire              | Read back as       |
+/// |------------------|--------------------------|--------------------|
+/// | `String`         | token, packed or binary  | `String`           |
+/// | `Int(n)`         | decimal digits of `n`    | `String`           |
+/// | `Bool(b)`        | `"true"` or `"false"`    | `String`           |
+/// | `Bytes`          | binary                   | `String` if UTF-8, else `Bytes` |
+/// | `JID`            | JID pair or device JID   | `JID`              |
+/// | `None`           | empty list               | `None`             |
+///
+/// Typed reads such as `as_int` and `as_bool` therefore accept both the
+/// typed value and its string form.
 #[derive(Debug, Clone, PartialEq)]
 pub enum AttrValue {
     None,
-    String(String),
+    /// Borrowed when decoded from a dictionary token
+    String(Cow<'static, str>),
     Bytes(Vec<u8>),
     Int(i64),
     Bool(bool),
@@ -22,13 +44,13 @@ pub enum AttrValue {
 
 impl From<&str> for AttrValue {
     fn from(s: &str) -> Self {
-        AttrValue::String(s.to_string())
+        AttrValue::String(Cow::Owned(s.to_string()))
     }
 }
 
 impl From<String> for AttrValue {
     fn from(s: String) -> Self {
-        AttrValue::String(s)
+        AttrValue::String(Cow::Owned(s))
     }
 }
 
@@ -59,10 +81,33 @@ impl AttrValue {
         }
     }
 
-    /// Get as i64 if possible
+    /// Get as i64, parsing decimal strings
     pub fn as_int(&self) -> Option<i64> {
         match self {
             AttrValue::Int(n) => Some(*n),
+            AttrValue::String(s) => s.parse().ok(),
+            _ => None,
+        }
+    }
+
+    /// Get as u64, parsing decimal strings
+    pub fn as_u64(&self) -> Option<u64> {
+        match self {
+            AttrValue::Int(n) => u64::try_from(*n).ok(),
+            AttrValue::String(s) => s.parse().ok(),
+            _ => None,
+        }
+    }
+
+    /// Get as bool, accepting the strings Go's `strconv.ParseBool` does
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            AttrValue::Bool(b) => Some(*b),
+            AttrValue::String(s) => match &**s {
+                "1" | "t" | "T" | "true" | "TRUE" | "True" => Some(true),
+                "0" | "f" | "F" | "false" | "FALSE" | "False" => Some(false),
+                _ => None,
+            },
             _ => None,
         }
     }
@@ -77,10 +122,10 @@ impl AttrValue {
 }
 
 /// Node represents a binary XML element in WhatsApp protocol.
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone, Default, PartialEq)]
 pub struct Node {
     /// The tag name of the element
-    pub tag: String,
+    pub tag: Cow<'static, str>,
     /// The attributes of the element
     pub attrs: Attrs,
     /// The content inside the element (nil, children, or bytes)
@@ -88,7 +133,7 @@ pub struct Node {
 }
 
 /// Content of a node
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone, Default, PartialEq)]
 pub enum NodeContent {
     #[default]
     None,
@@ -100,7 +145,7 @@ pub enum NodeContent {
 
 impl Node {
     /// Create a new node with the given tag
-    pub fn new(tag: impl Into<String>) -> Self {
+    pub fn new(tag: impl Into<Cow<'static, str>>) -> Self {
         Self {
             tag: tag.into(),
             attrs: Attrs::new(),
@@ -109,7 +154,7 @@ impl Node {
     }
 
     /// Create a new node with tag and attributes
-    pub fn with_attrs(tag: impl Into<String>, attrs: Attrs) -> Self {
+    pub fn with_attrs(tag: impl Into<Cow<'static, str>>, attrs: Attrs) -> Self {
         Self {
             tag: tag.into(),
             attrs,
@@ -118,7 +163,7 @@ impl Node {
     }
 
     /// Set an attribute on this node
-    pub fn set_attr(&mut self, key: impl Into<String>, value: impl Into<AttrValue>) {
+    pub fn set_attr(&mut self, key: impl Into<Cow<'static, str>>, value: impl Into<AttrValue>) {
         self.attrs.insert(key.into(), value.into());
     }
 
@@ -137,11 +182,39 @@ impl Node {
         self.attrs.get(key).and_then(|v| v.as_int())
     }
 
+    /// Get an attribute as u64
+    pub fn get_attr_u64(&self, key: &str) -> Option<u64> {
+        self.attrs.get(key).and_then(|v| v.as_u64())
+    }
+
+    /// Get an attribute as bool
+    pub fn get_attr_bool(&self, key: &str) -> Option<bool> {
+        self.attrs.get(key).and_then(|v| v.as_bool())
+    }
+
+    /// Get an attribute holding Unix seconds as a time. Zero means unset,
+    /// as it does for the server.
+    pub fn get_attr_unix_time(&self, key: &str) -> Option<DateTime<Utc>> {
+        match self.get_attr_int(key)? {
+            0 => None,
+            secs => DateTime::from_timestamp(secs, 0),
+        }
+    }
+
     /// Get an attribute as JID
     pub fn get_attr_jid(&self, key: &str) -> Option<&JID> {
         self.attrs.get(key).and_then(|v| v.as_jid())
     }
 
+    /// Get an attribute as JID, whether it was sent as a JID or as a
+    /// string. Decoded nodes carry JIDs, nodes built by hand often strings.
+    pub fn parse_attr_jid(&self, key: &str) -> Option<JID> {
+        match self.attrs.get(key)? {
+            AttrValue::JID(jid) => Some(jid.clone()),
+            value => value.as_str()?.parse().ok(),
+        }
+    }
+
     /// Set the content to child nodes
     pub fn set_children(&mut self, children: Vec<Node>) {
         self.content = NodeContent::Children(children);
@@ -216,6 +289,30 @@ mod tests {
         assert_eq!(node.get_attr_str("type"), Some("text"));
     }
 
+    #[test]
+    fn test_typed_attrs_survive_the_wire() {
+        let mut node = Node::new("receipt");
+        node.set_attr("t", 1_700_000_000i64);
+        node.set_attr("offline", true);
+        node.set_attr("count", -3i64);
+        node.set_attr("id", "3EB0");
+
+        let decoded = crate::binary::decode(&crate::binary::encode(&node)).unwrap();
+        // Typed values come back as their string form
+        assert_eq!(decoded.get_attr_str("t"), Some("1700000000"));
+        assert_eq!(decoded.get_attr_int("t"), node.get_attr_int("t"));
+        assert_eq!(decoded.get_attr_u64("t"), Some(1_700_000_000));
+        assert_eq!(decoded.get_attr_unix_time("t").unwrap().timestamp(), 1_700_000_000);
+        assert_eq!(decoded.get_attr_bool("offline"), Some(true));
+        assert_eq!(decoded.get_attr_int("count"), Some(-3));
+        assert_eq!(decoded.get_attr_u64("count"), None);
+        assert_eq!(decoded.get_attr_int("id"), None);
+        assert_eq!(decoded.get_attr_bool("id"), None);
+
+        node.set_attr("t", "0");
+        assert_eq!(node.get_attr_unix_time("t"), None);
+    }
+
     #[test]
     fn test_node_children() {
         let mut parent = Node::new("iq");
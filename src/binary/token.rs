@@ -2,9 +2,48 @@
 //!
 //! WhatsApp uses a dictionary of common strings to compress binary XML messages.
 //! Instead of sending the full string, a token byte is sent that maps to the string.
+//!
+//! The dictionary is versioned; the version in use is announced in the
+//! connection header, so tables are grouped per version in a
+//! `TokenDictionary` and selected with `dictionary`.
+
+use std::collections::HashMap;
+use std::hash::{BuildHasherDefault, Hasher};
+use std::sync::OnceLock;
 
-/// Single-byte tokens (0-235)
-pub static SINGLE_BYTE_TOKENS: &[&str] = &[
+/// Dictionary version announced in the connection header.
+pub const DICT_VERSION: u8 = 3;
+
+/// Tag byte selecting the first double-byte dictionary.
+///
+/// Dictionaries 1 to 3 follow at 0xED to 0xEF.
+pub const DICTIONARY_0: u8 = 0xEC;
+
+/// Tag of an empty list, or of a missing value.
+pub const LIST_EMPTY: u8 = 0x00;
+/// Tag of a device JID: agent, device, then the user.
+pub const AD_JID: u8 = 0xF7;
+/// Tag of a list with an 8-bit size.
+pub const LIST_8: u8 = 0xF8;
+/// Tag of a list with a 16-bit size.
+pub const LIST_16: u8 = 0xF9;
+/// Tag of a JID written as its user and server.
+pub const JID_PAIR: u8 = 0xFA;
+/// Tag of a string of uppercase hex digits packed two to a byte.
+pub const HEX_8: u8 = 0xFB;
+/// Tag of binary data with an 8-bit length.
+pub const BINARY_8: u8 = 0xFC;
+/// Tag of binary data with a 20-bit length.
+pub const BINARY_20: u8 = 0xFD;
+/// Tag of binary data with a 32-bit length.
+pub const BINARY_32: u8 = 0xFE;
+/// Tag of a string of digits, `-` and `.` packed two to a byte.
+pub const NIBBLE_8: u8 = 0xFF;
+/// Longest string that is packed.
+pub const PACKED_MAX: usize = 127;
+
+/// Single-byte tokens (0-235) of dictionary version 3
+pub const SINGLE_BYTE_TOKENS: &[&str] = &[
     "",                       // 0
     "xmlstreamstart",         // 1
     "xmlstreamend",           // 2
@@ -243,34 +282,10 @@ pub static SINGLE_BYTE_TOKENS: &[&str] = &[
     "screen_height",          // 235
 ];
 
-use std::collections::HashMap;
-use std::sync::OnceLock;
-
-/// Get the token index for a string (reverse lookup)
-pub fn get_token_index(s: &str) -> Option<u8> {
-    static TOKEN_MAP: OnceLock<HashMap<&'static str, u8>> = OnceLock::new();
-    
-    let map = TOKEN_MAP.get_or_init(|| {
-        let mut m = HashMap::new();
-        for (i, token) in SINGLE_BYTE_TOKENS.iter().enumerate() {
-            if !token.is_empty() {
-                m.insert(*token, i as u8);
-            }
-        }
-        m
-    });
-    
-    map.get(s).copied()
-}
-
-/// Get the string for a token index
-pub fn get_token(index: u8) -> Option<&'static str> {
-    SINGLE_BYTE_TOKENS.get(index as usize).copied()
-}
-
-/// Double-byte token dictionaries (indices 236-239 use these)
+/// Double-byte token dictionaries (indices 236-239 use these) of
+/// dictionary version 3
 /// Dictionary 0 = tag 236 (0xEC), Dictionary 1 = tag 237 (0xED), etc.
-pub static DOUBLE_BYTE_TOKENS: &[&[&str]] = &[
+pub const DOUBLE_BYTE_TOKENS: &[&[&str]] = &[
     // Dictionary 0 (tag 236 / 0xEC)
     &[
         "read-self", "active", "fbns", "protocol", "reaction", "screen_width", "heartbeat", "deviceid",
@@ -438,15 +453,125 @@ pub static DOUBLE_BYTE_TOKENS: &[&[&str]] = &[
         "modify", "spam_request", "p_121_aa_1101_test4", "866", "1427", "1502", "1638", "1744",
         "2153", "068", "382", "725", "1704", "1864", "1990", "2003", "Asia/Dubai", "508", "531",
         "1387", "1474", "1632", "2307", "2386", "819", "2014", "066", "387", "1468", "1706", "2186",
-        "2261", "471", "728", "1147", "1372", "1961", "stream:features",
+        "2261", "471", "728", "1147", "1372", "1961",
     ],
 ];
 
+/// Position of a string in a token dictionary.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Token {
+    /// Single-byte token
+    Single(u8),
+    /// Token in one of the double-byte dictionaries
+    Double { dict: u8, index: u8 },
+}
+
+/// FNV-1a, much cheaper than SipHash for the short keys looked up on
+/// every encoded string. The keys are fixed, so flooding is not a concern.
+#[derive(Default)]
+struct FnvHasher(u64);
+
+impl Hasher for FnvHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        let mut hash = if self.0 == 0 { 0xcbf2_9ce4_8422_2325 } else { self.0 };
+        for byte in bytes {
+            hash = (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3);
+        }
+        self.0 = hash;
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+type TokenIndex = HashMap<&'static str, Token, BuildHasherDefault<FnvHasher>>;
+
+/// Token tables of one dictionary version.
+pub struct TokenDictionary {
+    /// Version announced in the connection header
+    pub version: u8,
+    /// Single-byte tokens
+    pub single_byte: &'static [&'static str],
+    /// Double-byte dictionaries, selected by tags 0xEC to 0xEF
+    pub double_byte: &'static [&'static [&'static str]],
+    /// Reverse lookup, built on first use
+    index: OnceLock<TokenIndex>,
+}
+
+impl TokenDictionary {
+    const fn new(
+        version: u8,
+        single_byte: &'static [&'static str],
+        double_byte: &'static [&'static [&'static str]],
+    ) -> Self {
+        Self { version, single_byte, double_byte, index: OnceLock::new() }
+    }
+
+    /// Get a single-byte token.
+    pub fn token(&self, index: u8) -> Option<&'static str> {
+        self.single_byte.get(index as usize).copied()
+    }
+
+    /// Get a double-byte token.
+    pub fn double_token(&self, dict: u8, index: u8) -> Option<&'static str> {
+        self.double_byte.get(dict as usize)
+            .and_then(|tokens| tokens.get(index as usize))
+            .copied()
+    }
+
+    /// Find the token for a string, preferring single-byte tokens.
+    pub fn lookup(&self, s: &str) -> Option<Token> {
+        let index = self.index.get_or_init(|| {
+            let mut m = TokenIndex::default();
+            for (dict, tokens) in self.double_byte.iter().enumerate() {
+                for (i, token) in tokens.iter().enumerate() {
+                    m.entry(*token).or_insert(Token::Double { dict: dict as u8, index: i as u8 });
+                }
+            }
+            for (i, token) in self.single_byte.iter().enumerate() {
+                if !token.is_empty() {
+                    m.insert(*token, Token::Single(i as u8));
+                }
+            }
+            m
+        });
+        index.get(s).copied()
+    }
+}
+
+/// Dictionary version 3.
+pub static DICTIONARY_V3: TokenDictionary = TokenDictionary::new(3, SINGLE_BYTE_TOKENS, DOUBLE_BYTE_TOKENS);
+
+/// Get the tables of a dictionary version, if supported.
+pub fn dictionary(version: u8) -> Option<&'static TokenDictionary> {
+    match version {
+        3 => Some(&DICTIONARY_V3),
+        _ => None,
+    }
+}
+
+/// The dictionary of `DICT_VERSION`.
+pub fn default_dictionary() -> &'static TokenDictionary {
+    &DICTIONARY_V3
+}
+
+/// Get the token index for a string (reverse lookup)
+pub fn get_token_index(s: &str) -> Option<u8> {
+    match default_dictionary().lookup(s)? {
+        Token::Single(index) => Some(index),
+        Token::Double { .. } => None,
+    }
+}
+
+/// Get the string for a token index
+pub fn get_token(index: u8) -> Option<&'static str> {
+    default_dictionary().token(index)
+}
+
 /// Get a double-byte token
 pub fn get_double_token(dict: u8, index: u8) -> Option<&'static str> {
-    DOUBLE_BYTE_TOKENS.get(dict as usize)
-        .and_then(|tokens| tokens.get(index as usize))
-        .copied()
+    default_dictionary().double_token(dict, index)
 }
 
 #[cfg(test)]
@@ -475,6 +600,24 @@ mod tests {
         assert_eq!(get_double_token(1, 0), Some("reject"));
     }
 
+    #[test]
+    fn test_dictionary_v3_tables() {
+        let dict = dictionary(DICT_VERSION).unwrap();
+        assert_eq!(dict.single_byte.len(), 236);
+        assert_eq!(dict.double_byte.len(), 4);
+        assert!(dict.double_byte.iter().all(|tokens| tokens.len() == 256));
+        assert_eq!(dict.double_token(3, 255), Some("1961"));
+        assert!(dictionary(2).is_none());
+    }
+
+    #[test]
+    fn test_lookup_prefers_single_byte() {
+        let dict = default_dictionary();
+        assert_eq!(dict.lookup("message"), Some(Token::Single(19)));
+        assert_eq!(dict.lookup("reject"), Some(Token::Double { dict: 1, index: 0 }));
+        assert_eq!(dict.lookup("1961"), Some(Token::Double { dict: 3, index: 255 }));
+    }
+
     #[test]
     fn test_unknown_token() {
         assert_eq!(get_token_index("unknown_string_xyz"), None);
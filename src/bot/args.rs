@@ -0,0 +1,57 @@
+//! Command argument parsing.
+
+/// Split command arguments on whitespace.
+///
+/// Double or single quotes group words into one argument and a backslash
+/// escapes the next character. An unterminated quote runs to the end.
+pub fn parse_args(input: &str) -> Vec<String> {
+    let mut args = Vec::new();
+    let mut current = String::new();
+    let mut in_arg = false;
+    let mut quote = None;
+    let mut chars = input.chars();
+
+    while let Some(c) = chars.next() {
+        match (c, quote) {
+            ('\\', _) => {
+                if let Some(next) = chars.next() {
+                    current.push(next);
+                }
+                in_arg = true;
+            }
+            (c, Some(q)) if c == q => quote = None,
+            (c, Some(_)) => current.push(c),
+            ('"' | '\'', None) => {
+                quote = Some(c);
+                in_arg = true;
+            }
+            (c, None) if c.is_whitespace() => {
+                if in_arg {
+                    args.push(std::mem::take(&mut current));
+                    in_arg = false;
+                }
+            }
+            (c, None) => {
+                current.push(c);
+                in_arg = true;
+            }
+        }
+    }
+    if in_arg {
+        args.push(current);
+    }
+    args
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_args() {
+        assert_eq!(parse_args("  a  b\tc "), ["a", "b", "c"]);
+        assert_eq!(parse_args(r#"book "Room 4" 'at noon' x\ y"#), ["book", "Room 4", "at noon", "x y"]);
+        assert_eq!(parse_args(r#"say "" done"#), ["say", "", "done"]);
+        assert!(parse_args("   ").is_empty());
+    }
+}
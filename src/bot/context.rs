@@ -0,0 +1,53 @@
+//! Context passed to command handlers.
+
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+use crate::protocol::{ClientError, ClientHandle};
+use crate::types::{Message, JID};
+
+/// A matched command and what is needed to answer it.
+pub struct CommandContext<S = ()> {
+    /// Handle of the connection the message arrived on
+    pub handle: ClientHandle,
+    /// The message that triggered the command
+    pub message: Message,
+    /// Text of the message
+    pub text: String,
+    /// Command name, for command routes
+    pub command: Option<String>,
+    /// Parsed arguments after the command name, for command routes
+    pub args: Vec<String>,
+    /// Capture groups, for regex routes; index 0 is the whole match
+    pub captures: Vec<Option<String>>,
+    /// State shared by all commands in this chat
+    pub state: Arc<Mutex<S>>,
+}
+
+impl<S> CommandContext<S> {
+    /// Chat the command was sent in.
+    pub fn chat(&self) -> &JID {
+        &self.message.info.chat
+    }
+
+    /// User who sent the command.
+    pub fn sender(&self) -> &JID {
+        &self.message.info.sender
+    }
+
+    /// Parse the argument at `index`, if present and valid.
+    pub fn arg<T: FromStr>(&self, index: usize) -> Option<T> {
+        self.args.get(index)?.parse().ok()
+    }
+
+    /// Get the capture group at `index`, if it matched.
+    pub fn capture(&self, index: usize) -> Option<&str> {
+        self.captures.get(index)?.as_deref()
+    }
+
+    /// Send a text message to the chat the command came from.
+    pub async fn reply(&self, text: &str) -> Result<String, ClientError> {
+        self.handle.send_message(self.chat().clone(), text).await
+    }
+}
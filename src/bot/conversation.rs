@@ -0,0 +1,223 @@
+//! Multi-step dialogs.
+//!
+//! A `ConversationManager` remembers, per chat, which flow is running and
+//! which step waits for a reply. Replies are matched to the waiting step,
+//! cancellation keywords end the flow, and flows left unanswered for too
+//! long time out. State lives in a `ConversationStore`, so dialogs survive
+//! a restart when a persistent store is used.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::bot::router::message_text;
+use crate::protocol::{Clock, SystemClock};
+use crate::store::{ConversationState, ConversationStore, StoreResult};
+use crate::types::{Message, JID};
+
+/// What an incoming message means for the chat's conversation.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConversationInput {
+    /// No conversation is running in the chat
+    Idle,
+    /// A reply to the step the conversation waits on
+    Reply {
+        state: ConversationState,
+        text: String,
+    },
+    /// The user sent a cancellation keyword; the conversation was ended
+    Cancelled(ConversationState),
+    /// The conversation had gone unanswered too long and was ended; the
+    /// message is not part of it
+    TimedOut(ConversationState),
+}
+
+/// Tracks one conversation per chat.
+pub struct ConversationManager {
+    store: Arc<dyn ConversationStore>,
+    timeout: Duration,
+    cancel_keywords: Vec<String>,
+    clock: Arc<dyn Clock>,
+}
+
+impl ConversationManager {
+    /// Create a manager keeping state in `store`.
+    ///
+    /// Conversations time out after 10 minutes without a reply and are
+    /// cancelled by "cancel" or "stop".
+    pub fn new(store: Arc<dyn ConversationStore>) -> Self {
+        Self {
+            store,
+            timeout: Duration::from_secs(10 * 60),
+            cancel_keywords: vec!["cancel".to_string(), "stop".to_string()],
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Set how long a step waits for a reply.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Read the time from `clock` instead of the system clock, such as the
+    /// client's `ClientConfig::clock`.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Set the words that cancel a conversation. Matching ignores case
+    /// and surrounding whitespace.
+    pub fn with_cancel_keywords<I, K>(mut self, keywords: I) -> Self
+    where
+        I: IntoIterator<Item = K>,
+        K: Into<String>,
+    {
+        self.cancel_keywords = keywords.into_iter().map(|k| k.into().to_lowercase()).collect();
+        self
+    }
+
+    /// Start `flow` in a chat, waiting on `step`. Replaces any conversation
+    /// already running there.
+    pub fn start(&self, chat: &JID, flow: &str, step: &str) -> StoreResult<ConversationState> {
+        let state = ConversationState {
+            chat: chat.to_non_ad(),
+            flow: flow.to_string(),
+            step: step.to_string(),
+            data: Default::default(),
+            updated_at: self.now(),
+        };
+        self.store.put_conversation(&state)?;
+        Ok(state)
+    }
+
+    /// Get the conversation running in a chat, if it has not timed out.
+    pub fn get(&self, chat: &JID) -> StoreResult<Option<ConversationState>> {
+        let now = self.now();
+        Ok(self.store.get_conversation(&chat.to_non_ad())?.filter(|state| !self.expired(state, now)))
+    }
+
+    /// Move a conversation to its next step and save it, along with any
+    /// data set on it.
+    pub fn advance(&self, state: &mut ConversationState, step: &str) -> StoreResult<()> {
+        state.step = step.to_string();
+        self.save(state)
+    }
+
+    /// Save a conversation, restarting its timeout.
+    pub fn save(&self, state: &mut ConversationState) -> StoreResult<()> {
+        state.updated_at = self.now();
+        self.store.put_conversation(state)
+    }
+
+    /// End the conversation running in a chat.
+    pub fn finish(&self, chat: &JID) -> StoreResult<()> {
+        self.store.delete_conversation(&chat.to_non_ad())
+    }
+
+    /// Classify an incoming message against its chat's conversation.
+    ///
+    /// Cancelled and timed out conversations are removed from the store.
+    /// Our own messages and messages without text are `Idle`.
+    pub fn handle(&self, message: &Message) -> StoreResult<ConversationInput> {
+        if message.info.is_from_me {
+            return Ok(ConversationInput::Idle);
+        }
+        let Some(text) = message_text(message) else {
+            return Ok(ConversationInput::Idle);
+        };
+        let chat = message.info.chat.to_non_ad();
+        let Some(state) = self.store.get_conversation(&chat)? else {
+            return Ok(ConversationInput::Idle);
+        };
+
+        if self.expired(&state, self.now()) {
+            self.store.delete_conversation(&chat)?;
+            return Ok(ConversationInput::TimedOut(state));
+        }
+        let keyword = text.trim().to_lowercase();
+        if self.cancel_keywords.contains(&keyword) {
+            self.store.delete_conversation(&chat)?;
+            return Ok(ConversationInput::Cancelled(state));
+        }
+        Ok(ConversationInput::Reply { state, text })
+    }
+
+    fn now(&self) -> i64 {
+        self.clock.now().timestamp()
+    }
+
+    fn expired(&self, state: &ConversationState, now: i64) -> bool {
+        now - state.updated_at > self.timeout.as_secs() as i64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::ManualClock;
+    use crate::store::MemoryStore;
+    use crate::types::{MessageContent, MessageInfo};
+
+    fn message(text: &str) -> Message {
+        let chat: JID = "123@s.whatsapp.net".parse().unwrap();
+        Message {
+            info: MessageInfo {
+                id: "3EB0000000000001".to_string(),
+                sender: chat.clone(),
+                chat,
+                is_from_me: false,
+                is_group: false,
+                timestamp: 0,
+                push_name: None,
+                reply_to: None,
+            },
+            content: MessageContent::Text(text.to_string()),
+        }
+    }
+
+    #[test]
+    fn test_booking_flow() {
+        let manager = ConversationManager::new(Arc::new(MemoryStore::new()));
+        let chat: JID = "123@s.whatsapp.net".parse().unwrap();
+        assert_eq!(manager.handle(&message("hi")).unwrap(), ConversationInput::Idle);
+
+        manager.start(&chat, "booking", "room").unwrap();
+        let ConversationInput::Reply { mut state, text } = manager.handle(&message("Room 4")).unwrap() else {
+            panic!("expected a reply");
+        };
+        assert_eq!((state.flow.as_str(), state.step.as_str(), text.as_str()), ("booking", "room", "Room 4"));
+
+        state.data.insert("room".to_string(), text);
+        manager.advance(&mut state, "date").unwrap();
+        let stored = manager.get(&chat).unwrap().unwrap();
+        assert_eq!(stored.step, "date");
+        assert_eq!(stored.data["room"], "Room 4");
+
+        assert!(matches!(manager.handle(&message(" STOP ")).unwrap(), ConversationInput::Cancelled(_)));
+        assert_eq!(manager.get(&chat).unwrap(), None);
+    }
+
+    #[test]
+    fn test_timeout() {
+        let clock = Arc::new(ManualClock::new(chrono::DateTime::from_timestamp(1700000000, 0).unwrap()));
+        let manager = ConversationManager::new(Arc::new(MemoryStore::new()))
+            .with_timeout(Duration::from_secs(60))
+            .with_clock(clock.clone());
+        let chat: JID = "123@s.whatsapp.net".parse().unwrap();
+        let state = manager.start(&chat, "survey", "q1").unwrap();
+        assert_eq!(state.updated_at, 1700000000);
+
+        // Saving restarts the timeout
+        clock.advance(chrono::Duration::seconds(50));
+        let mut state = manager.get(&chat).unwrap().unwrap();
+        manager.save(&mut state).unwrap();
+        clock.advance(chrono::Duration::seconds(50));
+        assert!(manager.get(&chat).unwrap().is_some());
+
+        clock.advance(chrono::Duration::seconds(11));
+        assert_eq!(manager.get(&chat).unwrap(), None);
+        assert_eq!(manager.handle(&message("yes")).unwrap(), ConversationInput::TimedOut(state));
+        assert_eq!(manager.handle(&message("yes")).unwrap(), ConversationInput::Idle);
+    }
+}
@@ -0,0 +1,124 @@
+//! Checks run before command handlers.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::Mutex;
+
+use crate::bot::CommandContext;
+use crate::protocol::{RateLimit, RateLimiter};
+use crate::types::JID;
+
+/// A command was stopped by middleware.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rejected {
+    /// Message sent back to the chat, if any
+    pub reply: Option<String>,
+}
+
+impl Rejected {
+    /// Reject silently.
+    pub fn silent() -> Self {
+        Self { reply: None }
+    }
+
+    /// Reject and tell the sender why.
+    pub fn with_reply(reply: impl Into<String>) -> Self {
+        Self { reply: Some(reply.into()) }
+    }
+}
+
+impl fmt::Display for Rejected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.reply {
+            Some(reply) => write!(f, "rejected: {}", reply),
+            None => write!(f, "rejected"),
+        }
+    }
+}
+
+impl std::error::Error for Rejected {}
+
+/// Check run before a command handler.
+pub trait Middleware<S>: Send + Sync {
+    /// Let the command through, or reject it.
+    fn check(&self, ctx: &CommandContext<S>) -> Result<(), Rejected>;
+}
+
+impl<S, F> Middleware<S> for F
+where
+    F: Fn(&CommandContext<S>) -> Result<(), Rejected> + Send + Sync,
+{
+    fn check(&self, ctx: &CommandContext<S>) -> Result<(), Rejected> {
+        self(ctx)
+    }
+}
+
+/// Only lets commands from the given users through.
+pub struct AdminOnly {
+    admins: HashSet<JID>,
+    reply: Option<String>,
+}
+
+impl AdminOnly {
+    /// Allow only the given users. Devices of a user count as the user.
+    pub fn new<I: IntoIterator<Item = JID>>(admins: I) -> Self {
+        Self {
+            admins: admins.into_iter().map(|jid| jid.to_non_ad()).collect(),
+            reply: None,
+        }
+    }
+
+    /// Tell rejected senders why.
+    pub fn with_reply(mut self, reply: impl Into<String>) -> Self {
+        self.reply = Some(reply.into());
+        self
+    }
+}
+
+impl<S> Middleware<S> for AdminOnly {
+    fn check(&self, ctx: &CommandContext<S>) -> Result<(), Rejected> {
+        if self.admins.contains(&ctx.sender().to_non_ad()) {
+            Ok(())
+        } else {
+            Err(Rejected { reply: self.reply.clone() })
+        }
+    }
+}
+
+/// Limits how often each sender can run commands.
+pub struct RateLimitPerSender {
+    limit: RateLimit,
+    limiters: Mutex<HashMap<JID, RateLimiter>>,
+    reply: Option<String>,
+}
+
+impl RateLimitPerSender {
+    /// Give every sender their own token bucket with this limit.
+    pub fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            limiters: Mutex::new(HashMap::new()),
+            reply: None,
+        }
+    }
+
+    /// Tell rate-limited senders to slow down.
+    pub fn with_reply(mut self, reply: impl Into<String>) -> Self {
+        self.reply = Some(reply.into());
+        self
+    }
+}
+
+impl<S> Middleware<S> for RateLimitPerSender {
+    fn check(&self, ctx: &CommandContext<S>) -> Result<(), Rejected> {
+        let mut limiters = self.limiters.lock().unwrap();
+        let limiter = limiters
+            .entry(ctx.sender().to_non_ad())
+            .or_insert_with(|| RateLimiter::new(self.limit));
+        if limiter.try_acquire() {
+            Ok(())
+        } else {
+            Err(Rejected { reply: self.reply.clone() })
+        }
+    }
+}
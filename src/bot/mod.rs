@@ -0,0 +1,38 @@
+//! Command bots built on the client event stream.
+//!
+//! A `CommandRouter` matches incoming text messages against registered
+//! commands (`/name args...`) and regular expressions, runs middleware
+//! such as rate limiting or admin checks, and calls the handler with a
+//! `CommandContext` carrying the parsed arguments and per-chat state.
+//! `ConversationManager` tracks multi-step dialogs on top of that.
+//!
+//! ```no_run
+//! use whatsmeow_rust::bot::{CommandRouter, AdminOnly};
+//! use whatsmeow_rust::Client;
+//!
+//! # async fn example(mut client: Client, owner: whatsmeow_rust::JID) {
+//! let mut router: CommandRouter<u32> = CommandRouter::new("/");
+//! router.command("ping", |ctx| async move { ctx.reply("pong").await.map(|_| ()) });
+//! router.command("count", |ctx| async move {
+//!     let mut count = ctx.state.lock().await;
+//!     *count += 1;
+//!     ctx.reply(&count.to_string()).await.map(|_| ())
+//! });
+//! router.command("shutdown", |_ctx| async move { Ok(()) })
+//!     .with(AdminOnly::new([owner]));
+//!
+//! router.run(&mut client).await.ok();
+//! # }
+//! ```
+
+mod args;
+mod context;
+mod conversation;
+mod middleware;
+mod router;
+
+pub use args::parse_args;
+pub use context::CommandContext;
+pub use conversation::{ConversationManager, ConversationInput};
+pub use middleware::{Middleware, Rejected, AdminOnly, RateLimitPerSender};
+pub use router::{CommandRouter, Route};
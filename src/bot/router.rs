@@ -0,0 +1,309 @@
+//! Routing of text messages to command handlers.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+use futures::future::BoxFuture;
+use regex::Regex;
+
+use crate::bot::{parse_args, CommandContext, Middleware};
+use crate::protocol::{Client, ClientError, ClientHandle};
+use crate::types::{Event, Message, MessageContent, JID};
+
+type Handler<S> = Arc<dyn Fn(CommandContext<S>) -> BoxFuture<'static, Result<(), ClientError>> + Send + Sync>;
+
+/// What a route responds to.
+enum Matcher {
+    /// Prefix plus this name as the first word
+    Command(String),
+    /// Anywhere in the text
+    Regex(Regex),
+}
+
+/// A registered command or pattern.
+pub struct Route<S> {
+    matcher: Matcher,
+    handler: Handler<S>,
+    middleware: Vec<Arc<dyn Middleware<S>>>,
+    description: Option<String>,
+}
+
+impl<S> Route<S> {
+    /// Run `middleware` before this route's handler, after the router-wide
+    /// middleware.
+    pub fn with<M: Middleware<S> + 'static>(&mut self, middleware: M) -> &mut Self {
+        self.middleware.push(Arc::new(middleware));
+        self
+    }
+
+    /// Describe the route for `CommandRouter::help`.
+    pub fn describe(&mut self, description: impl Into<String>) -> &mut Self {
+        self.description = Some(description.into());
+        self
+    }
+}
+
+/// Dispatches incoming text messages to handlers.
+///
+/// Routes are tried in registration order and the first match wins.
+/// Messages sent by us are ignored. `S` is per-chat state, created with
+/// `Default` on the first command in a chat.
+pub struct CommandRouter<S = ()> {
+    prefix: String,
+    routes: Vec<Route<S>>,
+    middleware: Vec<Arc<dyn Middleware<S>>>,
+    states: std::sync::Mutex<HashMap<JID, Arc<tokio::sync::Mutex<S>>>>,
+}
+
+impl<S: Default + Send + 'static> CommandRouter<S> {
+    /// Create a router for commands starting with `prefix`, such as "/".
+    pub fn new(prefix: impl Into<String>) -> Self {
+        Self {
+            prefix: prefix.into(),
+            routes: Vec::new(),
+            middleware: Vec::new(),
+            states: std::sync::Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Handle `<prefix><name> args...`. Names match case-insensitively.
+    pub fn command<F, Fut>(&mut self, name: &str, handler: F) -> &mut Route<S>
+    where
+        F: Fn(CommandContext<S>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), ClientError>> + Send + 'static,
+    {
+        self.add_route(Matcher::Command(name.to_lowercase()), handler)
+    }
+
+    /// Handle messages matching `pattern`.
+    pub fn regex<F, Fut>(&mut self, pattern: Regex, handler: F) -> &mut Route<S>
+    where
+        F: Fn(CommandContext<S>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), ClientError>> + Send + 'static,
+    {
+        self.add_route(Matcher::Regex(pattern), handler)
+    }
+
+    /// Run `middleware` before every handler.
+    pub fn with<M: Middleware<S> + 'static>(&mut self, middleware: M) -> &mut Self {
+        self.middleware.push(Arc::new(middleware));
+        self
+    }
+
+    fn add_route<F, Fut>(&mut self, matcher: Matcher, handler: F) -> &mut Route<S>
+    where
+        F: Fn(CommandContext<S>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), ClientError>> + Send + 'static,
+    {
+        self.routes.push(Route {
+            matcher,
+            handler: Arc::new(move |ctx| Box::pin(handler(ctx))),
+            middleware: Vec::new(),
+            description: None,
+        });
+        self.routes.last_mut().unwrap()
+    }
+
+    /// List the registered commands with their descriptions.
+    pub fn help(&self) -> String {
+        self.routes.iter()
+            .filter_map(|route| match &route.matcher {
+                Matcher::Command(name) => Some(match &route.description {
+                    Some(description) => format!("{}{} - {}", self.prefix, name, description),
+                    None => format!("{}{}", self.prefix, name),
+                }),
+                Matcher::Regex(_) => None,
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Get the state of a chat, creating it if needed.
+    pub fn state(&self, chat: &JID) -> Arc<tokio::sync::Mutex<S>> {
+        self.states.lock().unwrap()
+            .entry(chat.to_non_ad())
+            .or_default()
+            .clone()
+    }
+
+    /// Route one message.
+    ///
+    /// Returns false if no route matched. Handler errors and rejection
+    /// replies that fail to send are logged.
+    pub async fn dispatch(&self, handle: &ClientHandle, message: Message) -> bool {
+        if message.info.is_from_me {
+            return false;
+        }
+        let Some(text) = message_text(&message) else {
+            return false;
+        };
+        let Some((route, ctx)) = self.route(handle, message, text) else {
+            return false;
+        };
+
+        for middleware in self.middleware.iter().chain(&route.middleware) {
+            if let Err(rejected) = middleware.check(&ctx) {
+                if let Some(reply) = &rejected.reply {
+                    if let Err(e) = ctx.reply(reply).await {
+                        log::warn!("failed to send rejection reply: {}", e);
+                    }
+                }
+                return true;
+            }
+        }
+
+        let name = ctx.command.clone().unwrap_or_else(|| "<regex>".to_string());
+        if let Err(e) = (route.handler)(ctx).await {
+            log::warn!("command {} failed: {}", name, e);
+        }
+        true
+    }
+
+    /// Find the first matching route and build its context.
+    fn route(&self, handle: &ClientHandle, message: Message, text: String) -> Option<(&Route<S>, CommandContext<S>)> {
+        let command = text.strip_prefix(self.prefix.as_str()).map(|rest| {
+            let rest = rest.trim_start();
+            let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            (rest[..end].to_lowercase(), &rest[end..])
+        });
+
+        for route in &self.routes {
+            let (name, args, captures) = match &route.matcher {
+                Matcher::Command(name) => match &command {
+                    Some((word, args)) if word == name => (Some(name.clone()), parse_args(args), Vec::new()),
+                    _ => continue,
+                },
+                Matcher::Regex(pattern) => match pattern.captures(&text) {
+                    Some(caps) => {
+                        let captures = caps.iter().map(|m| m.map(|m| m.as_str().to_string())).collect();
+                        (None, Vec::new(), captures)
+                    }
+                    None => continue,
+                },
+            };
+
+            let state = self.state(&message.info.chat);
+            return Some((route, CommandContext {
+                handle: handle.clone(),
+                message,
+                text,
+                command: name,
+                args,
+                captures,
+                state,
+            }));
+        }
+        None
+    }
+
+    /// Dispatch messages from the client's event stream until the
+    /// connection ends, returning the error that ended it.
+    ///
+    /// Each command is handled in its own task.
+    pub async fn run(self, client: &mut Client) -> Result<(), ClientError>
+    where
+        S: Sync,
+    {
+        let router = Arc::new(self);
+        loop {
+            let Some(Event::Message(message)) = client.receive().await? else {
+                continue;
+            };
+            let handle = client.handle().ok_or(ClientError::NotConnected)?;
+            let router = router.clone();
+            tokio::spawn(async move { router.dispatch(&handle, message).await });
+        }
+    }
+}
+
+/// Text of a message, or the caption of a media message.
+pub(crate) fn message_text(message: &Message) -> Option<String> {
+    match &message.content {
+        MessageContent::Text(text) => Some(text.clone()),
+        MessageContent::Image { caption, .. } | MessageContent::Video { caption, .. } => caption.clone(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use crate::bot::{AdminOnly, RateLimitPerSender};
+    use crate::protocol::RateLimit;
+    use crate::types::MessageInfo;
+
+    fn text_message(sender: &str, text: &str) -> Message {
+        let sender: JID = sender.parse().unwrap();
+        Message {
+            info: MessageInfo {
+                id: "3EB0000000000001".to_string(),
+                chat: sender.clone(),
+                sender,
+                is_from_me: false,
+                is_group: false,
+                timestamp: 0,
+                push_name: None,
+                reply_to: None,
+            },
+            content: MessageContent::Text(text.to_string()),
+        }
+    }
+
+    type Calls = Arc<Mutex<Vec<(Option<String>, Vec<String>, Vec<Option<String>>)>>>;
+
+    fn recording_router(calls: &Calls) -> CommandRouter<u32> {
+        let mut router = CommandRouter::new("/");
+        let record = |calls: &Calls| {
+            let calls = calls.clone();
+            move |ctx: CommandContext<u32>| {
+                let calls = calls.clone();
+                async move {
+                    *ctx.state.lock().await += 1;
+                    calls.lock().unwrap().push((ctx.command, ctx.args, ctx.captures));
+                    Ok(())
+                }
+            }
+        };
+        router.command("book", record(calls)).describe("book a room");
+        router.command("admin", record(calls)).with(AdminOnly::new(["1@s.whatsapp.net".parse().unwrap()]));
+        router.regex(Regex::new(r"(?i)order #(\d+)").unwrap(), record(calls));
+        router
+    }
+
+    #[tokio::test]
+    async fn test_command_routing() {
+        let calls = Calls::default();
+        let router = recording_router(&calls);
+        let handle = ClientHandle::disconnected();
+
+        assert!(router.dispatch(&handle, text_message("2@s.whatsapp.net", "/Book \"Room 4\" 3")).await);
+        assert!(router.dispatch(&handle, text_message("2@s.whatsapp.net", "where is Order #42?")).await);
+        assert!(!router.dispatch(&handle, text_message("2@s.whatsapp.net", "/unknown")).await);
+        assert!(!router.dispatch(&handle, text_message("2@s.whatsapp.net", "/bookx")).await);
+
+        let calls = calls.lock().unwrap();
+        assert_eq!(calls[0], (Some("book".to_string()), vec!["Room 4".to_string(), "3".to_string()], vec![]));
+        assert_eq!(calls[1].2, [Some("Order #42".to_string()), Some("42".to_string())]);
+        assert_eq!(router.help(), "/book - book a room\n/admin");
+    }
+
+    #[tokio::test]
+    async fn test_middleware_and_state() {
+        let calls = Calls::default();
+        let mut router = recording_router(&calls);
+        router.with(RateLimitPerSender::new(RateLimit { burst: 2, per_second: 0.001 }));
+        let handle = ClientHandle::disconnected();
+
+        // Not an admin: rejected, but the route still matched
+        assert!(router.dispatch(&handle, text_message("2@s.whatsapp.net", "/admin")).await);
+        assert!(router.dispatch(&handle, text_message("1:3@s.whatsapp.net", "/admin")).await);
+        // Sender 1 used one of its two tokens
+        assert!(router.dispatch(&handle, text_message("1@s.whatsapp.net", "/book")).await);
+        assert!(router.dispatch(&handle, text_message("1@s.whatsapp.net", "/book")).await);
+
+        assert_eq!(calls.lock().unwrap().len(), 2);
+        assert_eq!(*router.state(&"1@s.whatsapp.net".parse().unwrap()).lock().await, 2);
+        assert_eq!(*router.state(&"2@s.whatsapp.net".parse().unwrap()).lock().await, 0);
+    }
+}
@@ -10,7 +10,7 @@ use aes_gcm::{
 /// AES-256-GCM cipher for encrypting/decrypting messages.
 pub struct Cipher {
     key: [u8; 32],
-    nonce_counter: u64,
+    nonce_counter: u32,
 }
 
 impl Cipher {
@@ -24,7 +24,7 @@ impl Cipher {
 
     /// Encrypt data with optional associated data.
     pub fn encrypt(&mut self, plaintext: &[u8], ad: &[u8]) -> Result<Vec<u8>, CipherError> {
-        let nonce = self.next_nonce();
+        let nonce = self.next_nonce()?;
         self.encrypt_with_nonce(plaintext, &nonce, ad)
     }
 
@@ -48,7 +48,7 @@ impl Cipher {
 
     /// Decrypt data with optional associated data.
     pub fn decrypt(&mut self, ciphertext: &[u8], ad: &[u8]) -> Result<Vec<u8>, CipherError> {
-        let nonce = self.next_nonce();
+        let nonce = self.next_nonce()?;
         self.decrypt_with_nonce(ciphertext, &nonce, ad)
     }
 
@@ -70,12 +70,21 @@ impl Cipher {
     }
 
     /// Generate the next nonce (counter-based).
-    fn next_nonce(&mut self) -> [u8; 12] {
+    ///
+    /// WhatsApp puts the counter in the last 4 bytes of the nonce, so it
+    /// fails at `u32::MAX` instead of wrapping around, as reusing a nonce
+    /// with the same key breaks AES-GCM.
+    fn next_nonce(&mut self) -> Result<[u8; 12], CipherError> {
         let mut nonce = [0u8; 12];
-        // Put counter in last 8 bytes (big-endian)
-        nonce[4..12].copy_from_slice(&self.nonce_counter.to_be_bytes());
-        self.nonce_counter += 1;
-        nonce
+        // Put counter in last 4 bytes (big-endian)
+        nonce[8..12].copy_from_slice(&self.nonce_counter.to_be_bytes());
+        self.nonce_counter = self.nonce_counter.checked_add(1).ok_or(CipherError::NonceExhausted)?;
+        Ok(nonce)
+    }
+
+    /// Number of messages that can still be processed with this key.
+    pub fn remaining_nonces(&self) -> u32 {
+        u32::MAX - self.nonce_counter
     }
 
     /// Reset the nonce counter.
@@ -84,7 +93,7 @@ impl Cipher {
     }
 
     /// Set the nonce counter to a specific value.
-    pub fn set_nonce(&mut self, counter: u64) {
+    pub fn set_nonce(&mut self, counter: u32) {
         self.nonce_counter = counter;
     }
 }
@@ -95,6 +104,8 @@ pub enum CipherError {
     InvalidKey,
     EncryptionFailed,
     DecryptionFailed,
+    /// Every nonce for this key has been used; a new key is needed
+    NonceExhausted,
 }
 
 impl std::fmt::Display for CipherError {
@@ -103,6 +114,7 @@ impl std::fmt::Display for CipherError {
             CipherError::InvalidKey => write!(f, "invalid key"),
             CipherError::EncryptionFailed => write!(f, "encryption failed"),
             CipherError::DecryptionFailed => write!(f, "decryption failed"),
+            CipherError::NonceExhausted => write!(f, "nonce counter exhausted"),
         }
     }
 }
@@ -147,9 +159,25 @@ mod tests {
         let key = [0xab; 32];
         let mut cipher = Cipher::new(key);
         
-        let nonce1 = cipher.next_nonce();
-        let nonce2 = cipher.next_nonce();
+        let nonce1 = cipher.next_nonce().unwrap();
+        let nonce2 = cipher.next_nonce().unwrap();
         
         assert_ne!(nonce1, nonce2);
+        assert_eq!(nonce2, [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn test_nonce_exhaustion() {
+        let mut cipher = Cipher::new([0xab; 32]);
+        cipher.set_nonce(u32::MAX - 1);
+        assert_eq!(cipher.remaining_nonces(), 1);
+
+        let ciphertext = cipher.encrypt(b"last", &[]).unwrap();
+        assert_eq!(cipher.remaining_nonces(), 0);
+        assert_eq!(cipher.encrypt(b"again", &[]), Err(CipherError::NonceExhausted));
+        assert_eq!(cipher.decrypt(&ciphertext, &[]), Err(CipherError::NonceExhausted));
+
+        cipher.set_nonce(u32::MAX - 1);
+        assert_eq!(cipher.decrypt(&ciphertext, &[]).unwrap(), b"last");
     }
 }
@@ -2,7 +2,11 @@
 //!
 //! Provides Curve25519 key pair generation and management for Signal Protocol.
 
+use curve25519_dalek::constants::ED25519_BASEPOINT_TABLE;
+use curve25519_dalek::montgomery::MontgomeryPoint;
+use curve25519_dalek::scalar::Scalar;
 use rand::RngCore;
+use sha2::{Digest, Sha512};
 use x25519_dalek::{PublicKey, StaticSecret};
 
 /// A Curve25519 key pair for Signal Protocol operations.
@@ -100,22 +104,67 @@ impl PreKey {
 impl KeyPair {
     /// Sign another key pair's public key.
     pub fn sign(&self, key_to_sign: &KeyPair) -> [u8; 64] {
-        use ed25519_dalek::{SigningKey, Signer};
-        
-        // Create message to sign: 0x05 || public_key
+        // Public keys are signed with their DJB type prefix
         let mut message = [0u8; 33];
-        message[0] = 0x05; // DJB type
+        message[0] = 0x05;
         message[1..].copy_from_slice(&key_to_sign.public);
-        
-        // Convert Curve25519 private key to Ed25519 signing key
-        // Note: This is a simplified approach; real implementation needs proper conversion
-        let signing_key = SigningKey::from_bytes(&self.private);
-        let signature = signing_key.sign(&message);
-        
-        signature.to_bytes()
+        self.sign_message(&message)
+    }
+
+    /// Sign a message the way libsignal does: the Curve25519 private key
+    /// signs as an Ed25519 key, and the sign bit of the matching Edwards
+    /// public key is carried in the top bit of the signature.
+    pub fn sign_message(&self, message: &[u8]) -> [u8; 64] {
+        let mut private = self.private;
+        private[0] &= 248;
+        private[31] &= 127;
+        private[31] |= 64;
+        let a = Scalar::from_bytes_mod_order(private);
+        let public = (&a * ED25519_BASEPOINT_TABLE).compress().to_bytes();
+
+        let mut random = [0u8; 64];
+        rand::thread_rng().fill_bytes(&mut random);
+        let mut hash = Sha512::new();
+        hash.update([0xFE]);
+        hash.update([0xFF; 31]);
+        hash.update(private);
+        hash.update(message);
+        hash.update(random);
+        let r = Scalar::from_bytes_mod_order_wide(&hash.finalize().into());
+        let big_r = (&r * ED25519_BASEPOINT_TABLE).compress().to_bytes();
+
+        let mut hash = Sha512::new();
+        hash.update(big_r);
+        hash.update(public);
+        hash.update(message);
+        let h = Scalar::from_bytes_mod_order_wide(&hash.finalize().into());
+        let s = r + h * a;
+
+        let mut signature = [0u8; 64];
+        signature[..32].copy_from_slice(&big_r);
+        signature[32..].copy_from_slice(s.as_bytes());
+        signature[63] |= public[31] & 0x80;
+        signature
     }
 }
 
+/// Verify a signature made by the owner of a Curve25519 public key, as
+/// produced by `KeyPair::sign_message`.
+pub fn verify_signature(public_key: &[u8; 32], message: &[u8], signature: &[u8; 64]) -> bool {
+    use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+
+    let mut signature = *signature;
+    let sign_bit = signature[63] >> 7;
+    signature[63] &= 0x7F;
+    let Some(edwards) = MontgomeryPoint(*public_key).to_edwards(sign_bit) else {
+        return false;
+    };
+    let Ok(key) = VerifyingKey::from_bytes(&edwards.compress().to_bytes()) else {
+        return false;
+    };
+    key.verify(message, &Signature::from_bytes(&signature)).is_ok()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -144,4 +193,22 @@ mod tests {
         assert_eq!(pk.key_id, 1);
         assert!(pk.signature.is_none());
     }
+
+    #[test]
+    fn test_signatures_verify_with_the_curve_key() {
+        let identity = KeyPair::generate();
+        let pre_key = PreKey::new_signed(1, &identity);
+        let mut message = vec![0x05];
+        message.extend_from_slice(&pre_key.key_pair.public);
+
+        // Half of all keys have a negative Edwards form, so try several
+        for _ in 0..16 {
+            let key = KeyPair::generate();
+            let signature = key.sign_message(b"hello");
+            assert!(verify_signature(&key.public, b"hello", &signature));
+            assert!(!verify_signature(&key.public, b"hellp", &signature));
+        }
+        assert!(verify_signature(&identity.public, &message, &pre_key.signature.unwrap()));
+        assert!(!verify_signature(&KeyPair::generate().public, &message, &pre_key.signature.unwrap()));
+    }
 }
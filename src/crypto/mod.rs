@@ -9,7 +9,7 @@ mod hkdf;
 mod cipher;
 mod noise;
 
-pub use keypair::{KeyPair, PreKey};
+pub use keypair::{KeyPair, PreKey, verify_signature};
 pub use hkdf::{Hkdf, derive_noise_keys};
 pub use cipher::{Cipher, CipherError};
 pub use noise::{NoiseHandshake, HandshakeError, NOISE_PROTOCOL_NAME};
@@ -49,10 +49,8 @@ impl NoiseHandshake {
 
     /// Initialize the handshake state.
     fn initialize(&mut self) {
-        // h = SHA256(protocol_name)
-        let mut hasher = Sha256::new();
-        hasher.update(NOISE_PROTOCOL_NAME);
-        self.hash = hasher.finalize().into();
+        // The padded name is exactly 32 bytes, so it is used as h unhashed
+        self.hash.copy_from_slice(NOISE_PROTOCOL_NAME);
         
         // ck = h
         self.chaining_key = self.hash;
@@ -64,7 +62,7 @@ impl NoiseHandshake {
     /// Mix a value into the hash.
     fn mix_hash(&mut self, data: &[u8]) {
         let mut hasher = Sha256::new();
-        hasher.update(&self.hash);
+        hasher.update(self.hash);
         hasher.update(data);
         self.hash = hasher.finalize().into();
     }
@@ -184,6 +182,11 @@ impl NoiseHandshake {
         (Cipher::new(send_key), Cipher::new(recv_key))
     }
 
+    /// Get the handshake hash (h), which binds everything sent so far.
+    pub fn handshake_hash(&self) -> &[u8; 32] {
+        &self.hash
+    }
+
     /// Get the remote static public key after handshake.
     pub fn remote_static_key(&self) -> Option<&[u8; 32]> {
         self.remote_static.as_ref()
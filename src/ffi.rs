@@ -0,0 +1,631 @@
+//! C ABI for embedding the client in other languages.
+//!
+//! A `WmClient` is an opaque handle owning a tokio runtime and a task on
+//! it that owns the `Client` and keeps receiving from the connection, so
+//! events flow and the client reconnects without the caller polling. A
+//! client can be used from several threads at once; every call blocks the
+//! calling thread until the operation is done.
+//!
+//! Events are pushed to the callback registered with
+//! `wm_client_set_event_callback`. Without a callback they are queued, up
+//! to 1024 of them, for `wm_client_poll_event`.
+//!
+//! Strings are UTF-8 and NUL-terminated. Strings and events returned by the
+//! library are owned by the caller and freed with `wm_string_free` and
+//! `wm_event_free`. When a call fails, `wm_last_error` describes why. A
+//! panic inside the library is caught and reported as `WmStatus::Panicked`
+//! instead of unwinding into the caller.
+//!
+//! The library is built with
+//! `cargo rustc --release --lib --features ffi --crate-type cdylib` (or
+//! `staticlib`), and the C header is generated with `cbindgen` into
+//! `include/whatsmeow.h`.
+
+use std::cell::RefCell;
+use std::ffi::{c_char, c_void, CStr, CString};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::ptr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::{mpsc, oneshot};
+
+use crate::protocol::{Client, ClientConfig, ClientError, ClientHandle};
+use crate::store::{SqliteStore, StoredMessage};
+use crate::types::{Event, JID};
+
+/// Most events queued for `wm_client_poll_event`; later ones are dropped
+/// until the queue is polled.
+const EVENT_QUEUE: usize = 1024;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Result of a call.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WmStatus {
+    Ok = 0,
+    /// A required pointer was null
+    NullArgument,
+    /// A string was not valid UTF-8
+    InvalidUtf8,
+    /// A JID could not be parsed
+    InvalidJid,
+    /// Connecting or the handshake failed
+    ConnectFailed,
+    /// The client is not connected
+    NotConnected,
+    /// Sending failed
+    SendFailed,
+    /// Any other failure
+    Failed,
+    /// The library panicked; the client should be freed
+    Panicked,
+}
+
+/// Kind of a `WmEvent`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WmEventKind {
+    Connected = 0,
+    Disconnected,
+    LoggedOut,
+    /// `text` is the code to show as a QR code
+    QrCode,
+    /// `text` is the code to enter on the phone
+    PairingCode,
+    /// `chat`, `sender`, `id` and `text` are set; `text` is null for
+    /// messages without text
+    Message,
+    /// `chat`, `sender` and `id` (the first receipted message) are set
+    Receipt,
+    /// An event with no C representation yet
+    Other,
+}
+
+/// An event. Unused fields are null.
+#[repr(C)]
+pub struct WmEvent {
+    pub kind: WmEventKind,
+    pub chat: *const c_char,
+    pub sender: *const c_char,
+    pub id: *const c_char,
+    pub text: *const c_char,
+}
+
+/// Callback receiving events on a library thread.
+///
+/// The event is only valid during the call. Calling back into the client
+/// from the callback is not allowed, as it would block the thread events
+/// are delivered on; hand the event to another thread instead.
+pub type WmEventCallback = Option<extern "C" fn(user_data: *mut c_void, event: *const WmEvent)>;
+
+/// An event with the strings it points to. `event` comes first, so a
+/// pointer to it is a pointer to the whole allocation.
+#[repr(C)]
+struct OwnedEvent {
+    event: WmEvent,
+    _strings: [Option<CString>; 4],
+}
+
+impl OwnedEvent {
+    fn new(event: &Event) -> Box<Self> {
+        let string = |s: String| CString::new(s).ok();
+        let (kind, chat, sender, id, text) = match event {
+            Event::Connected(_) => (WmEventKind::Connected, None, None, None, None),
+            Event::Disconnected(_) => (WmEventKind::Disconnected, None, None, None, None),
+            Event::LoggedOut(_) => (WmEventKind::LoggedOut, None, None, None, None),
+            Event::QRCode(qr) => (WmEventKind::QrCode, None, None, None, string(qr.code.clone())),
+            Event::PairingCode(code) => (WmEventKind::PairingCode, None, None, None, string(code.code.clone())),
+            Event::Message(msg) => (
+                WmEventKind::Message,
+                string(msg.info.chat.to_string()),
+                string(msg.info.sender.to_string()),
+                string(msg.info.id.clone()),
+                StoredMessage::from(msg).text.and_then(string),
+            ),
+            Event::Receipt(receipt) => (
+                WmEventKind::Receipt,
+                string(receipt.chat.to_string()),
+                string(receipt.sender.to_string()),
+                receipt.message_ids.first().cloned().and_then(string),
+                None,
+            ),
+            _ => (WmEventKind::Other, None, None, None, None),
+        };
+
+        let as_ptr = |s: &Option<CString>| s.as_ref().map_or(ptr::null(), |s| s.as_ptr());
+        Box::new(Self {
+            event: WmEvent {
+                kind,
+                chat: as_ptr(&chat),
+                sender: as_ptr(&sender),
+                id: as_ptr(&id),
+                text: as_ptr(&text),
+            },
+            _strings: [chat, sender, id, text],
+        })
+    }
+}
+
+/// Registered callback and its user data.
+struct Callback {
+    callback: extern "C" fn(user_data: *mut c_void, event: *const WmEvent),
+    user_data: *mut c_void,
+}
+
+// The caller promises the user data can be used from library threads
+unsafe impl Send for Callback {}
+
+/// Calls run by the task owning the `Client`.
+enum Command {
+    Connect(oneshot::Sender<Result<(), ClientError>>),
+    Disconnect(oneshot::Sender<Result<(), ClientError>>),
+    PairingQr(oneshot::Sender<Option<String>>),
+    Shutdown(oneshot::Sender<Result<(), ClientError>>),
+}
+
+/// Opaque client handle.
+pub struct WmClient {
+    runtime: tokio::runtime::Runtime,
+    commands: mpsc::UnboundedSender<Command>,
+    /// Handle of the live connection, for sending without waiting on the
+    /// client task
+    handle: Arc<Mutex<Option<ClientHandle>>>,
+    callback: Arc<Mutex<Option<Callback>>>,
+    events: Mutex<mpsc::Receiver<Event>>,
+}
+
+impl WmClient {
+    /// Run a call on the client task and wait for its answer.
+    fn call<T>(&self, command: impl FnOnce(oneshot::Sender<T>) -> Command) -> Option<T> {
+        let (reply, answer) = oneshot::channel();
+        self.commands.send(command(reply)).ok()?;
+        self.runtime.block_on(answer).ok()
+    }
+}
+
+/// Own the client: run calls from C and keep receiving while connected,
+/// queueing events for polling when no callback takes them.
+async fn run_client(
+    mut client: Client,
+    mut commands: mpsc::UnboundedReceiver<Command>,
+    handle: Arc<Mutex<Option<ClientHandle>>>,
+    callback: Arc<Mutex<Option<Callback>>>,
+    events: mpsc::Sender<Event>,
+) {
+    let mut receiving = false;
+    loop {
+        // `receive` is cancel-safe, so a call can interrupt it
+        tokio::select! {
+            biased;
+            command = commands.recv() => match command {
+                Some(Command::Connect(reply)) => {
+                    let connected = client.connect().await;
+                    receiving |= connected.is_ok();
+                    let _ = reply.send(connected);
+                }
+                Some(Command::Disconnect(reply)) => {
+                    let _ = reply.send(client.disconnect().await);
+                }
+                Some(Command::PairingQr(reply)) => {
+                    let _ = reply.send(client.pairing_qr().await);
+                }
+                Some(Command::Shutdown(reply)) => {
+                    let _ = reply.send(client.shutdown().await);
+                    break;
+                }
+                None => break,
+            },
+            received = client.receive(), if receiving => match received {
+                Ok(Some(event)) => {
+                    if callback.lock().unwrap().is_none() && events.try_send(event).is_err() {
+                        log::warn!("event queue full, dropping event; poll with wm_client_poll_event");
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    log::debug!("stopped receiving: {}", e);
+                    receiving = false;
+                }
+            },
+        }
+        *handle.lock().unwrap() = client.handle();
+    }
+    *handle.lock().unwrap() = None;
+}
+
+fn set_last_error(message: impl ToString) {
+    let message = CString::new(message.to_string()).ok();
+    LAST_ERROR.with(|last| *last.borrow_mut() = message);
+}
+
+fn fail(status: WmStatus, message: impl ToString) -> WmStatus {
+    set_last_error(message);
+    status
+}
+
+/// Run the body of a call, turning a panic into `on_panic` instead of
+/// unwinding into C.
+fn guard<T>(on_panic: T, body: impl FnOnce() -> T) -> T {
+    catch_unwind(AssertUnwindSafe(body)).unwrap_or_else(|panic| {
+        let message = panic.downcast_ref::<&str>().copied()
+            .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("unknown panic");
+        set_last_error(format!("panic: {}", message));
+        on_panic
+    })
+}
+
+/// Borrow a C string argument.
+unsafe fn str_arg<'a>(s: *const c_char) -> Result<&'a str, WmStatus> {
+    if s.is_null() {
+        return Err(fail(WmStatus::NullArgument, "null string argument"));
+    }
+    CStr::from_ptr(s).to_str().map_err(|e| fail(WmStatus::InvalidUtf8, e))
+}
+
+/// Create a client. The paired device, its keys and the chat history are
+/// kept in the SQLite database at `db_path`, so the client logs in again
+/// after a restart; with a null `db_path` everything stays in memory and
+/// the device has to pair every time.
+///
+/// Returns null on failure.
+///
+/// # Safety
+///
+/// `db_path` must be null or a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn wm_client_new(db_path: *const c_char) -> *mut WmClient {
+    guard(ptr::null_mut(), || {
+        let runtime = match tokio::runtime::Runtime::new() {
+            Ok(runtime) => runtime,
+            Err(e) => {
+                set_last_error(e);
+                return ptr::null_mut();
+            }
+        };
+
+        let mut client = if db_path.is_null() {
+            Client::new()
+        } else {
+            let Ok(path) = str_arg(db_path) else {
+                return ptr::null_mut();
+            };
+            let opened = SqliteStore::open(path).and_then(|store| Ok((store, SqliteStore::open(path)?)));
+            match opened {
+                Ok((store, chat_store)) => {
+                    let mut client = Client::with_store(ClientConfig::default(), store);
+                    client.set_chat_store(chat_store);
+                    client
+                }
+                Err(e) => {
+                    set_last_error(e);
+                    return ptr::null_mut();
+                }
+            }
+        };
+
+        let callback: Arc<Mutex<Option<Callback>>> = Arc::new(Mutex::new(None));
+        let registered = callback.clone();
+        client.add_event_handler(move |event| {
+            if let Some(cb) = registered.lock().unwrap().as_ref() {
+                let event = OwnedEvent::new(&event);
+                (cb.callback)(cb.user_data, &event.event);
+            }
+        });
+
+        let (commands, command_rx) = mpsc::unbounded_channel();
+        let (event_tx, events) = mpsc::channel(EVENT_QUEUE);
+        let handle = Arc::new(Mutex::new(None));
+        runtime.spawn(run_client(client, command_rx, handle.clone(), callback.clone(), event_tx));
+
+        Box::into_raw(Box::new(WmClient {
+            runtime,
+            commands,
+            handle,
+            callback,
+            events: Mutex::new(events),
+        }))
+    })
+}
+
+/// Disconnect and free a client.
+///
+/// # Safety
+///
+/// `client` must be null or come from `wm_client_new`, and not be used
+/// afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn wm_client_free(client: *mut WmClient) {
+    if client.is_null() {
+        return;
+    }
+    guard((), || {
+        let client = Box::from_raw(client);
+        if let Some(Err(e)) = client.call(Command::Shutdown) {
+            log::warn!("failed to shut down client: {}", e);
+        }
+    })
+}
+
+/// Call `callback` with every event, replacing any previous callback. A
+/// null `callback` removes it, and events are queued for
+/// `wm_client_poll_event` again.
+///
+/// # Safety
+///
+/// `client` must come from `wm_client_new`. `user_data` is passed back
+/// as is and must be usable from any thread.
+#[no_mangle]
+pub unsafe extern "C" fn wm_client_set_event_callback(
+    client: *mut WmClient,
+    callback: WmEventCallback,
+    user_data: *mut c_void,
+) -> WmStatus {
+    guard(WmStatus::Panicked, || {
+        let Some(client) = client.as_ref() else {
+            return fail(WmStatus::NullArgument, "null client");
+        };
+        *client.callback.lock().unwrap() = callback.map(|callback| Callback { callback, user_data });
+        WmStatus::Ok
+    })
+}
+
+/// Connect to WhatsApp.
+///
+/// # Safety
+///
+/// `client` must come from `wm_client_new`.
+#[no_mangle]
+pub unsafe extern "C" fn wm_client_connect(client: *mut WmClient) -> WmStatus {
+    guard(WmStatus::Panicked, || {
+        let Some(client) = client.as_ref() else {
+            return fail(WmStatus::NullArgument, "null client");
+        };
+        match client.call(Command::Connect) {
+            Some(Ok(())) => WmStatus::Ok,
+            Some(Err(e)) => fail(WmStatus::ConnectFailed, e),
+            None => fail(WmStatus::Failed, "client stopped"),
+        }
+    })
+}
+
+/// Disconnect from WhatsApp. The client can connect again afterwards.
+///
+/// # Safety
+///
+/// `client` must come from `wm_client_new`.
+#[no_mangle]
+pub unsafe extern "C" fn wm_client_disconnect(client: *mut WmClient) -> WmStatus {
+    guard(WmStatus::Panicked, || {
+        let Some(client) = client.as_ref() else {
+            return fail(WmStatus::NullArgument, "null client");
+        };
+        match client.call(Command::Disconnect) {
+            Some(Ok(())) => WmStatus::Ok,
+            Some(Err(e)) => fail(WmStatus::Failed, e),
+            None => fail(WmStatus::Failed, "client stopped"),
+        }
+    })
+}
+
+/// Get the QR code currently linking this device, to be freed with
+/// `wm_string_free`.
+///
+/// Returns null until the server starts pairing after `wm_client_connect`,
+/// and once pairing has ended.
+///
+/// # Safety
+///
+/// `client` must come from `wm_client_new`.
+#[no_mangle]
+pub unsafe extern "C" fn wm_client_pair_qr(client: *mut WmClient) -> *mut c_char {
+    guard(ptr::null_mut(), || {
+        let Some(client) = client.as_ref() else {
+            set_last_error("null client");
+            return ptr::null_mut();
+        };
+        match client.call(Command::PairingQr).flatten() {
+            Some(code) => CString::new(code).map_or(ptr::null_mut(), CString::into_raw),
+            None => {
+                set_last_error("no pairing in progress");
+                ptr::null_mut()
+            }
+        }
+    })
+}
+
+/// Send a text message to `to`, a JID such as `123456789@s.whatsapp.net`.
+///
+/// If `out_id` is not null, it receives the message ID, to be freed with
+/// `wm_string_free`.
+///
+/// # Safety
+///
+/// `client` must come from `wm_client_new`; `to` and `text` must be
+/// NUL-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn wm_client_send_text(
+    client: *mut WmClient,
+    to: *const c_char,
+    text: *const c_char,
+    out_id: *mut *mut c_char,
+) -> WmStatus {
+    guard(WmStatus::Panicked, || {
+        let Some(client) = client.as_ref() else {
+            return fail(WmStatus::NullArgument, "null client");
+        };
+        let (to, text) = match (str_arg(to), str_arg(text)) {
+            (Ok(to), Ok(text)) => (to, text),
+            (Err(status), _) | (_, Err(status)) => return status,
+        };
+        let to: JID = match to.parse() {
+            Ok(to) => to,
+            Err(e) => return fail(WmStatus::InvalidJid, e),
+        };
+
+        let Some(handle) = client.handle.lock().unwrap().clone() else {
+            return fail(WmStatus::NotConnected, "not connected");
+        };
+        match client.runtime.block_on(handle.send_message(to, text)) {
+            Ok(id) => {
+                if !out_id.is_null() {
+                    *out_id = CString::new(id).map_or(ptr::null_mut(), CString::into_raw);
+                }
+                WmStatus::Ok
+            }
+            Err(ClientError::NotConnected) => fail(WmStatus::NotConnected, "not connected"),
+            Err(e) => fail(WmStatus::SendFailed, e),
+        }
+    })
+}
+
+/// Wait up to `timeout_ms` milliseconds for the next queued event. Events
+/// are only queued while no callback is registered.
+///
+/// On success `out_event` receives the event, to be freed with
+/// `wm_event_free`, or null if none arrived in time.
+///
+/// # Safety
+///
+/// `client` must come from `wm_client_new` and `out_event` must be valid
+/// for writes.
+#[no_mangle]
+pub unsafe extern "C" fn wm_client_poll_event(
+    client: *mut WmClient,
+    timeout_ms: u32,
+    out_event: *mut *mut WmEvent,
+) -> WmStatus {
+    guard(WmStatus::Panicked, || {
+        let (Some(client), false) = (client.as_ref(), out_event.is_null()) else {
+            return fail(WmStatus::NullArgument, "null client or out_event");
+        };
+        *out_event = ptr::null_mut();
+
+        let mut events = client.events.lock().unwrap();
+        let received = client.runtime.block_on(async {
+            tokio::time::timeout(Duration::from_millis(timeout_ms.into()), events.recv()).await
+        });
+        match received {
+            Ok(Some(event)) => {
+                *out_event = Box::into_raw(OwnedEvent::new(&event)).cast();
+                WmStatus::Ok
+            }
+            Err(_) => WmStatus::Ok,
+            Ok(None) => fail(WmStatus::Failed, "client stopped"),
+        }
+    })
+}
+
+/// Free an event from `wm_client_poll_event`.
+///
+/// # Safety
+///
+/// `event` must be null or come from `wm_client_poll_event`.
+#[no_mangle]
+pub unsafe extern "C" fn wm_event_free(event: *mut WmEvent) {
+    if !event.is_null() {
+        guard((), || drop(Box::from_raw(event.cast::<OwnedEvent>())));
+    }
+}
+
+/// Free a string returned by the library.
+///
+/// # Safety
+///
+/// `s` must be null or a string returned by the library.
+#[no_mangle]
+pub unsafe extern "C" fn wm_string_free(s: *mut c_char) {
+    if !s.is_null() {
+        guard((), || drop(CString::from_raw(s)));
+    }
+}
+
+/// Describe the last failure on this thread, or return null.
+///
+/// The string stays valid until the next failing call on this thread.
+#[no_mangle]
+pub extern "C" fn wm_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ref().map_or(ptr::null(), |e| e.as_ptr()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{Message, MessageContent, MessageInfo};
+
+    #[test]
+    fn test_client_lifecycle() {
+        unsafe {
+            let client = wm_client_new(ptr::null());
+            assert!(!client.is_null());
+
+            // No QR code before the server starts pairing
+            assert!(wm_client_pair_qr(client).is_null());
+            assert!(!wm_last_error().is_null());
+
+            let to = CString::new("1234@s.whatsapp.net").unwrap();
+            let text = CString::new("hi").unwrap();
+            let status = wm_client_send_text(client, to.as_ptr(), text.as_ptr(), ptr::null_mut());
+            assert_eq!(status, WmStatus::NotConnected);
+            assert!(!wm_last_error().is_null());
+
+            let bad = CString::new("a@b@c").unwrap();
+            assert_eq!(wm_client_send_text(client, bad.as_ptr(), text.as_ptr(), ptr::null_mut()), WmStatus::InvalidJid);
+
+            let mut event = ptr::null_mut();
+            assert_eq!(wm_client_poll_event(client, 10, &mut event), WmStatus::Ok);
+            assert!(event.is_null());
+
+            wm_client_free(client);
+        }
+    }
+
+    #[test]
+    fn test_client_with_database() {
+        let path = std::env::temp_dir().join(format!("whatsmeow-ffi-{}.db", uuid::Uuid::new_v4()));
+        let db_path = CString::new(path.to_str().unwrap()).unwrap();
+        unsafe {
+            let client = wm_client_new(db_path.as_ptr());
+            assert!(!client.is_null());
+            wm_client_free(client);
+        }
+        assert!(path.exists());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_panics_become_status() {
+        assert_eq!(guard(WmStatus::Panicked, || -> WmStatus { panic!("boom") }), WmStatus::Panicked);
+        let error = unsafe { CStr::from_ptr(wm_last_error()) };
+        assert_eq!(error.to_str().unwrap(), "panic: boom");
+    }
+
+    #[test]
+    fn test_message_event() {
+        let event = Event::Message(Message {
+            info: MessageInfo {
+                id: "3EB0AA".to_string(),
+                sender: JID::new("1", "s.whatsapp.net"),
+                chat: JID::new("1", "s.whatsapp.net"),
+                is_from_me: false,
+                is_group: false,
+                timestamp: 0,
+                push_name: None,
+                reply_to: None,
+            },
+            content: MessageContent::Text("hello".to_string()),
+        });
+
+        let event = Box::into_raw(OwnedEvent::new(&event)).cast::<WmEvent>();
+        unsafe {
+            assert_eq!((*event).kind, WmEventKind::Message);
+            assert_eq!(CStr::from_ptr((*event).text).to_str().unwrap(), "hello");
+            assert_eq!(CStr::from_ptr((*event).chat).to_str().unwrap(), "1@s.whatsapp.net");
+            wm_event_free(event);
+        }
+    }
+}
@@ -8,23 +8,51 @@
 //! - `types` - Core types like JID, MessageID, and events
 //! - `binary` - Binary XML encoding/decoding
 //! - `crypto` - Cryptographic primitives (Curve25519, AES-GCM, HKDF, Noise)
+//! - `transport` - Binary frame transports, including the browser's WebSocket
 //! - `socket` - WebSocket transport with Noise Protocol
 //! - `store` - Device storage and session management
+//! - `media` - Media download and decryption
 //! - `protocol` - High-level client implementation
+//! - `bot` - Command routing for bots
+//! - `ffi` - C ABI over the high-level client
+//! - `python` - Python bindings over the high-level client
+//!
+//! ## Features
+//!
+//! - `native` (default) - `socket`, `media`, `protocol`, `bot` and the
+//!   SQLite store, built on tokio
+//! - `wasm` - the browser transport. Build for `wasm32-unknown-unknown`
+//!   with `--no-default-features --features wasm` to get `types`,
+//!   `binary`, `crypto`, `proto`, the in-memory store and `transport`.
+//! - `ffi` - the C ABI in `ffi`, for embedding in other languages
+//! - `python` - the `whatsmeow_rust_py` Python module, built with maturin
 
 pub mod types;
 pub mod binary;
 pub mod crypto;
+pub mod transport;
+#[cfg(feature = "native")]
 pub mod socket;
 pub mod store;
+#[cfg(feature = "native")]
+pub mod media;
+#[cfg(feature = "native")]
 pub mod protocol;
 pub mod proto;
+#[cfg(feature = "native")]
+pub mod bot;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "python")]
+pub mod python;
 
 // Re-export existing scaffold modules (for backwards compat)
+#[cfg(feature = "native")]
 mod client;
 mod config;
 mod state;
 
+#[cfg(feature = "native")]
 pub use client::{WhatsmeowClient, ClientError as ScaffoldClientError};
 pub use config::WhatsmeowConfig;
 pub use state::{
@@ -36,5 +64,6 @@ pub use state::{
 pub use types::{JID, MessageID};
 pub use binary::{Node, encode, decode};
 pub use store::{Device, MemoryStore};
-pub use protocol::{Client, ClientConfig, ClientError};
+#[cfg(feature = "native")]
+pub use protocol::{Client, ClientConfig, ClientError, ClientHandle};
 
@@ -1,8 +1,14 @@
-use std::{fs, path::PathBuf};
+use std::fs;
+use std::path::{Path, PathBuf};
 
 use clap::{Parser, Subcommand};
 use uuid::Uuid;
-use whatsmeow_rust::{ScaffoldClientError as ClientError, MessageStatus, SessionState, WhatsmeowClient, WhatsmeowConfig};
+use whatsmeow_rust::media::{mime, MediaType};
+use tokio::io::{AsyncBufReadExt, BufReader};
+use whatsmeow_rust::protocol::{Client, MediaMessage, QRPairing};
+use whatsmeow_rust::types::{Event, MessageContent};
+use whatsmeow_rust::store::{ChatStore, MessagePage, SqliteStore};
+use whatsmeow_rust::{ScaffoldClientError as ClientError, MessageStatus, SessionState, WhatsmeowClient, WhatsmeowConfig, JID};
 
 /// Reference CLI demonstrating the Whatsmeow Rust scaffolding.
 #[derive(Parser, Debug)]
@@ -12,6 +18,10 @@ struct Cli {
     #[arg(long, default_value = "./data/session.json")]
     state_file: PathBuf,
 
+    /// Path to the SQLite database the protocol client keeps history in.
+    #[arg(long, default_value = "./data/whatsmeow.db")]
+    db: PathBuf,
+
     /// Override the user agent advertised by the client.
     #[arg(long)]
     user_agent: Option<String>,
@@ -45,10 +55,23 @@ enum Commands {
     MarkDelivered { id: String },
     /// Mark an outgoing message as read.
     MarkRead { id: String },
-    /// List known contacts.
-    ListContacts,
-    /// List stored message history.
-    ListMessages,
+    /// Explore the people we received messages from.
+    Contacts {
+        #[command(subcommand)]
+        command: ContactsCommand,
+    },
+    /// Explore the chats in the message history.
+    Chats {
+        #[command(subcommand)]
+        command: ChatsCommand,
+    },
+    /// Print the latest messages of a chat, oldest first.
+    History {
+        jid: String,
+        /// Number of messages to print.
+        #[arg(long, default_value_t = 20)]
+        limit: usize,
+    },
     /// List the recorded lifecycle events.
     ListEvents,
     /// Decrypt an outgoing message by id.
@@ -59,6 +82,43 @@ enum Commands {
     DownloadMedia { url: String, output: Option<String> },
     /// List recorded media downloads.
     ListMedia,
+    /// Upload a file and send it as an image, video, audio or document
+    /// message, chosen from its type.
+    SendMedia {
+        to: String,
+        path: PathBuf,
+        /// Text shown under the media.
+        #[arg(long)]
+        caption: Option<String>,
+    },
+    /// Stay connected, print incoming messages and send typed lines.
+    ///
+    /// `/to <jid>` picks the chat lines are sent to, `/quit` exits.
+    Repl,
+    /// Bring the message database schema up to date.
+    Migrate {
+        /// List the migrations that would run without applying them.
+        #[arg(long)]
+        dry_run: bool,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum ContactsCommand {
+    /// List every contact.
+    List,
+    /// List contacts whose number or push name contains the query.
+    Search { query: String },
+}
+
+#[derive(Subcommand, Debug)]
+enum ChatsCommand {
+    /// List chats, most recently active first.
+    List {
+        /// Number of chats to print.
+        #[arg(long, default_value_t = 50)]
+        limit: usize,
+    },
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -239,33 +299,76 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             },
             Err(err) => eprintln!("Invalid message id: {err}"),
         },
-        Commands::ListContacts => {
-            if client.state.contacts.is_empty() {
-                println!("No contacts stored.");
-            } else {
-                for contact in &client.state.contacts {
-                    println!("{} ({})", contact.display_name, contact.jid);
-                }
+        Commands::Contacts { command } => {
+            let store = SqliteStore::open(&cli.db)?;
+            let query = match &command {
+                ContactsCommand::List => None,
+                ContactsCommand::Search { query } => Some(query.as_str()),
+            };
+            let contacts = store.contacts(query)?;
+            if contacts.is_empty() {
+                println!("No contacts found.");
+            }
+            for contact in contacts {
+                println!("{} ({})", contact.push_name.as_deref().unwrap_or("-"), contact.jid);
             }
         }
-        Commands::ListMessages => {
-            if client.state.outgoing_messages.is_empty()
-                && client.state.incoming_messages.is_empty()
-            {
-                println!("No messages have been recorded.");
-            } else {
-                for msg in &client.state.outgoing_messages {
-                    println!(
-                        "[sent {}] to {} (id {}, status {:?}): {}",
-                        msg.sent_at, msg.to, msg.id, msg.status, msg.body
-                    );
-                }
-                for msg in &client.state.incoming_messages {
-                    println!(
-                        "[recv {}] from {} (id {}): {}",
-                        msg.received_at, msg.from, msg.id, msg.body
-                    );
+        Commands::Migrate { dry_run } => {
+            let pending = SqliteStore::pending_migrations(&cli.db)?;
+            if pending.is_empty() {
+                println!("Database schema is up to date.");
+            }
+            for migration in &pending {
+                let verb = if dry_run { "Would apply" } else { "Applying" };
+                println!("{verb} migration {}: {}", migration.version, migration.description);
+            }
+            if !dry_run && !pending.is_empty() {
+                let store = SqliteStore::open(&cli.db)?;
+                println!("Database schema is at version {}.", store.schema_version()?);
+            }
+        }
+        Commands::Chats { command: ChatsCommand::List { limit } } => {
+            let store = SqliteStore::open(&cli.db)?;
+            let chats = store.chats(limit)?;
+            if chats.is_empty() {
+                println!("No chats stored.");
+            }
+            for chat in chats {
+                println!(
+                    "{} ({} messages, last at {}): {}",
+                    chat.chat,
+                    chat.message_count,
+                    format_timestamp(chat.last_timestamp),
+                    chat.last_text.as_deref().unwrap_or("")
+                );
+            }
+        }
+        Commands::History { jid, limit } => {
+            let chat: JID = match jid.parse() {
+                Ok(chat) => chat,
+                Err(err) => {
+                    eprintln!("Invalid JID {jid}: {err}");
+                    return Ok(());
                 }
+            };
+            let store = SqliteStore::open(&cli.db)?;
+            let mut messages = store.get_messages(&chat, MessagePage::latest(limit))?;
+            if messages.is_empty() {
+                println!("No messages stored for {chat}.");
+            }
+            messages.reverse();
+            for msg in messages {
+                let from = if msg.is_from_me {
+                    "me".to_string()
+                } else {
+                    msg.push_name.clone().unwrap_or_else(|| msg.sender.to_string())
+                };
+                println!(
+                    "[{}] {}: {}",
+                    format_timestamp(msg.timestamp),
+                    from,
+                    msg.text.as_deref().unwrap_or("<media>")
+                );
             }
         }
         Commands::ListEvents => {
@@ -309,6 +412,36 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 Err(err) => return Err(err.into()),
             }
         }
+        Commands::SendMedia { to, path, caption } => {
+            let to: JID = match to.parse() {
+                Ok(to) => to,
+                Err(err) => {
+                    eprintln!("Invalid JID {to}: {err}");
+                    return Ok(());
+                }
+            };
+            let message = media_message(&path, caption)?;
+            println!(
+                "Sending {} ({}, {} bytes) to {to}",
+                message.media_type.as_str(),
+                message.mimetype,
+                message.data.len()
+            );
+            let runtime = tokio::runtime::Runtime::new()?;
+            let id = runtime.block_on(async {
+                let mut client = Client::new();
+                client.set_chat_store(SqliteStore::open(&cli.db)?);
+                client.connect().await?;
+                let sent = client.send_media(&to, &message).await;
+                let _ = client.disconnect().await;
+                Ok::<_, Box<dyn std::error::Error>>(sent?)
+            })?;
+            println!("Sent media message {id}");
+        }
+        Commands::Repl => {
+            let runtime = tokio::runtime::Runtime::new()?;
+            runtime.block_on(repl(&cli.db))?;
+        }
         Commands::ListMedia => {
             if client.state.media.is_empty() {
                 println!("No media downloaded yet.");
@@ -337,6 +470,156 @@ fn persist_state(client: &WhatsmeowClient, path: &PathBuf) -> Result<(), ClientE
     client.store_state(path)
 }
 
+const RESET: &str = "\x1b[0m";
+const DIM: &str = "\x1b[2m";
+const GREEN: &str = "\x1b[32m";
+const CYAN: &str = "\x1b[36m";
+const RED: &str = "\x1b[31m";
+
+/// Interactive chat over a real connection, for trying the stack end to end.
+async fn repl(db: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let mut client = Client::new();
+    client.set_chat_store(SqliteStore::open(db)?);
+    client.add_event_handler(|event| match event {
+        Event::QRCode(qr) => match QRPairing::render_qr_ascii(&qr.code) {
+            Ok(ascii) => println!("Scan with WhatsApp to link this device:\n{ascii}"),
+            Err(_) => println!("Link code: {}", qr.code),
+        },
+        Event::Connected(_) => println!("{GREEN}connected{RESET}"),
+        Event::Disconnected(disconnected) => println!("{RED}disconnected: {:?}{RESET}", disconnected.reason),
+        Event::Message(msg) => {
+            let from = msg.info.push_name.clone().unwrap_or_else(|| msg.info.sender.to_string());
+            let chat = if msg.info.is_group { format!(" in {}", msg.info.chat) } else { String::new() };
+            println!(
+                "{DIM}[{}]{RESET} {CYAN}{from}{RESET}{chat}: {}",
+                format_timestamp(msg.info.timestamp),
+                describe_content(&msg.content)
+            );
+        }
+        _ => {}
+    });
+    client.connect().await?;
+    println!("{DIM}/to <jid> picks the chat, other lines are sent to it, /quit exits{RESET}");
+
+    let mut to: Option<JID> = None;
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    while let Some(line) = lines.next_line().await? {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if line == "/quit" {
+            break;
+        }
+        if let Some(jid) = line.strip_prefix("/to ") {
+            match jid.trim().parse::<JID>() {
+                Ok(jid) => {
+                    println!("{DIM}sending to {jid}{RESET}");
+                    to = Some(jid);
+                }
+                Err(err) => println!("{RED}invalid JID {jid}: {err}{RESET}"),
+            }
+            continue;
+        }
+        let Some(chat) = to.clone() else {
+            println!("{RED}pick a chat first with /to <jid>{RESET}");
+            continue;
+        };
+        match client.send_message(chat, line).await {
+            Ok(id) => println!("{DIM}sent {id}{RESET}"),
+            Err(err) => println!("{RED}send failed: {err}{RESET}"),
+        }
+    }
+
+    client.disconnect().await?;
+    Ok(())
+}
+
+/// One-line description of a message for the terminal.
+fn describe_content(content: &MessageContent) -> String {
+    let with_caption = |kind: &str, caption: &Option<String>| match caption {
+        Some(caption) => format!("<{kind}> {caption}"),
+        None => format!("<{kind}>"),
+    };
+    match content {
+        MessageContent::Text(text) => text.clone(),
+        MessageContent::Image { caption, .. } => with_caption("image", caption),
+        MessageContent::Video { caption, .. } => with_caption("video", caption),
+        MessageContent::Audio { ptt: true, .. } => "<voice note>".to_string(),
+        MessageContent::Audio { .. } => "<audio>".to_string(),
+        MessageContent::Document { filename, .. } => format!("<document> {filename}"),
+        MessageContent::Sticker { .. } => "<sticker>".to_string(),
+        MessageContent::ViewOnce(content) => format!("<view once> {}", describe_content(content)),
+        MessageContent::StickerPack { name, stickers, .. } => format!("<sticker pack> {name} ({} stickers)", stickers.len()),
+        MessageContent::Location { latitude, longitude, .. } => format!("<location> {latitude}, {longitude}"),
+        MessageContent::Contact { display_name, .. } => format!("<contact> {display_name}"),
+        MessageContent::Reaction { emoji, .. } => format!("<reaction> {emoji}"),
+        MessageContent::ButtonResponse { display_text, .. } => display_text.clone(),
+        MessageContent::ListResponse { title, .. } => title.clone(),
+        MessageContent::Product { body, .. } => with_caption("product", body),
+        MessageContent::Poll { name, .. } => format!("<poll> {name}"),
+        MessageContent::PollVote { selected_options, .. } => format!("<poll vote> {} options", selected_options.len()),
+        MessageContent::EventResponse { response, .. } => format!("<event response> {response:?}"),
+        MessageContent::Unknown => "<unsupported message>".to_string(),
+    }
+}
+
+/// Read a file into a media message, with a thumbnail for images.
+fn media_message(path: &Path, caption: Option<String>) -> Result<MediaMessage, Box<dyn std::error::Error>> {
+    let data = fs::read(path)?;
+    let mimetype = detect_mimetype(path, &data);
+    let mut message = MediaMessage::new(data, mimetype);
+    if message.media_type == MediaType::Document {
+        if let Some(name) = path.file_name() {
+            message = message.with_filename(name.to_string_lossy());
+        }
+    }
+    #[cfg(feature = "thumbnails")]
+    if message.media_type == MediaType::Image {
+        match whatsmeow_rust::media::jpeg_thumbnail(&message.data) {
+            Ok(thumbnail) => message = message.with_thumbnail(thumbnail),
+            Err(err) => eprintln!("Sending without a thumbnail: {err}"),
+        }
+    }
+    if let Some(caption) = caption {
+        message = message.with_caption(caption);
+    }
+    Ok(message)
+}
+
+/// Guess the mime type of a file from its first bytes, then from its
+/// extension.
+fn detect_mimetype(path: &Path, data: &[u8]) -> &'static str {
+    let magic: &[(&[u8], &str)] = &[
+        (b"\xFF\xD8\xFF", "image/jpeg"),
+        (b"\x89PNG\r\n\x1A\n", "image/png"),
+        (b"GIF8", "image/gif"),
+        (b"%PDF-", "application/pdf"),
+        (b"OggS", "audio/ogg"),
+        (b"ID3", "audio/mpeg"),
+        (b"\x1A\x45\xDF\xA3", "video/webm"),
+    ];
+    if let Some((_, mimetype)) = magic.iter().find(|(prefix, _)| data.starts_with(prefix)) {
+        return mimetype;
+    }
+    if data.len() >= 12 && &data[0..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+        return "image/webp";
+    }
+    if data.len() >= 12 && &data[4..8] == b"ftyp" {
+        return if &data[8..11] == b"M4A" { "audio/mp4" } else { "video/mp4" };
+    }
+
+    path.extension()
+        .and_then(|extension| mime::mimetype_for_extension(&extension.to_string_lossy()))
+        .unwrap_or("application/octet-stream")
+}
+
+fn format_timestamp(timestamp: i64) -> String {
+    chrono::DateTime::from_timestamp(timestamp, 0)
+        .map(|at| at.format("%Y-%m-%d %H:%M").to_string())
+        .unwrap_or_else(|| timestamp.to_string())
+}
+
 fn parse_uuid(id: &str) -> Result<Uuid, uuid::Error> {
     Uuid::parse_str(id)
 }
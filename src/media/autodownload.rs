@@ -0,0 +1,187 @@
+//! Automatic download of incoming media.
+
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncWriteExt;
+
+use crate::media::mime::{self, AllowedDocumentTypes};
+use crate::media::{DownloadableMedia, MediaType};
+use crate::types::JID;
+
+/// Which incoming media is downloaded as it arrives, and where it is
+/// saved.
+///
+/// Each media type has its own size limit; types without one are not
+/// downloaded, nor are files whose size the message does not state.
+/// Documents must also be of an allowed mime type, and view-once media is
+/// only saved if enabled with `with_view_once`.
+#[derive(Debug, Clone)]
+pub struct MediaAutoDownloadPolicy {
+    directory: PathBuf,
+    limits: HashMap<MediaType, u64>,
+    document_types: AllowedDocumentTypes,
+    view_once: bool,
+}
+
+impl MediaAutoDownloadPolicy {
+    /// Policy saving to `directory` that downloads nothing until limits
+    /// are added.
+    pub fn new(directory: impl Into<PathBuf>) -> Self {
+        Self {
+            directory: directory.into(),
+            limits: HashMap::new(),
+            document_types: AllowedDocumentTypes::any(),
+            view_once: false,
+        }
+    }
+
+    /// Download media of a type up to `max_size` bytes.
+    pub fn with_limit(mut self, media_type: MediaType, max_size: u64) -> Self {
+        self.limits.insert(media_type, max_size);
+        self
+    }
+
+    /// Download only documents of these mime types.
+    pub fn with_document_types(mut self, document_types: AllowedDocumentTypes) -> Self {
+        self.document_types = document_types;
+        self
+    }
+
+    /// Whether to save view-once media too, which the sender meant to be
+    /// seen once and not kept.
+    pub fn with_view_once(mut self, view_once: bool) -> Self {
+        self.view_once = view_once;
+        self
+    }
+
+    /// Whether view-once media is saved.
+    pub fn saves_view_once(&self) -> bool {
+        self.view_once
+    }
+
+    /// Directory files are saved to.
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    /// Whether a file should be downloaded.
+    pub fn allows(&self, media: &DownloadableMedia) -> bool {
+        if media.media_type == MediaType::Document && !self.document_types.allows(&media.mimetype) {
+            return false;
+        }
+        match (self.limits.get(&media.media_type), media.details.file_length) {
+            (Some(&limit), Some(length)) => length <= limit,
+            _ => false,
+        }
+    }
+
+    /// Path to save the media of a message to, named after its chat,
+    /// sender and ID. IDs are chosen by the sender, so `save` never
+    /// overwrites a file already at this path.
+    pub fn path_for(&self, chat: &JID, sender: &JID, message_id: &str, media: &DownloadableMedia) -> PathBuf {
+        // Keep only what is safe in a file name
+        let jid = |jid: &JID| -> String {
+            jid.to_string().chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '-' }).collect()
+        };
+        let mut id: String = message_id.chars().filter(|c| c.is_ascii_alphanumeric()).collect();
+        if id.is_empty() {
+            id = uuid::Uuid::new_v4().simple().to_string();
+        }
+        let extension = mime::file_extension(media.media_type, &media.mimetype);
+        self.directory.join(format!("{}_{}_{}.{}", jid(chat), jid(sender), id, extension))
+    }
+
+    /// Save a downloaded file at `path`, or next to it with a numbered
+    /// suffix if a file is already there, returning where it was saved.
+    pub async fn save(&self, path: &Path, data: &[u8]) -> io::Result<PathBuf> {
+        tokio::fs::create_dir_all(&self.directory).await?;
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy().into_owned();
+        let extension = path.extension().unwrap_or_default().to_string_lossy().into_owned();
+        let mut candidate = path.to_path_buf();
+        for suffix in 1.. {
+            match tokio::fs::File::options().write(true).create_new(true).open(&candidate).await {
+                Ok(mut file) => {
+                    file.write_all(data).await?;
+                    file.flush().await?;
+                    return Ok(candidate);
+                }
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                    candidate = path.with_file_name(format!("{}-{}.{}", stem, suffix, extension));
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        unreachable!("ran out of file name suffixes")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::MediaDetails;
+
+    fn media(media_type: MediaType, mimetype: &str, file_length: Option<u64>) -> DownloadableMedia {
+        DownloadableMedia {
+            media_type,
+            url: String::new(),
+            mimetype: mimetype.to_string(),
+            details: MediaDetails { file_length, ..Default::default() },
+        }
+    }
+
+    #[test]
+    fn test_auto_download_policy() {
+        let policy = MediaAutoDownloadPolicy::new("/tmp/media").with_limit(MediaType::Image, 1_000_000);
+        assert!(policy.allows(&media(MediaType::Image, "image/jpeg", Some(500_000))));
+        assert!(!policy.allows(&media(MediaType::Image, "image/jpeg", Some(2_000_000))));
+        assert!(!policy.allows(&media(MediaType::Image, "image/jpeg", None)));
+        assert!(!policy.allows(&media(MediaType::Video, "video/mp4", Some(10))));
+
+        let documents = MediaAutoDownloadPolicy::new("/tmp/media")
+            .with_limit(MediaType::Document, 1_000_000)
+            .with_document_types(AllowedDocumentTypes::from_list("application/pdf"));
+        assert!(documents.allows(&media(MediaType::Document, "application/pdf", Some(10))));
+        assert!(!documents.allows(&media(MediaType::Document, "application/x-msdownload", Some(10))));
+
+        let chat = JID::new("123", "s.whatsapp.net");
+        let path = policy.path_for(&chat, &chat, "3EB0/../AB", &media(MediaType::Audio, "audio/ogg; codecs=opus", None));
+        assert_eq!(path, Path::new("/tmp/media/123-s-whatsapp-net_123-s-whatsapp-net_3EB0AB.ogg"));
+        let path = policy.path_for(&chat, &chat, "3EB0CD", &media(MediaType::Image, "", None));
+        assert_eq!(path, Path::new("/tmp/media/123-s-whatsapp-net_123-s-whatsapp-net_3EB0CD.jpg"));
+    }
+
+    #[test]
+    fn test_path_for_is_unique_per_chat_and_id() {
+        let policy = MediaAutoDownloadPolicy::new("/tmp/media");
+        let image = media(MediaType::Image, "image/jpeg", None);
+        let sender = JID::new("9", "s.whatsapp.net");
+
+        // A sender reusing the ID of a message in another chat
+        let first = policy.path_for(&JID::new("1", "s.whatsapp.net"), &sender, "3EB0AA", &image);
+        let second = policy.path_for(&JID::new("2", "g.us"), &sender, "3EB0AA", &image);
+        assert_ne!(first, second);
+
+        // IDs with nothing safe in them get a generated name each
+        let chat = JID::new("1", "s.whatsapp.net");
+        let first = policy.path_for(&chat, &sender, "../..", &image);
+        let second = policy.path_for(&chat, &sender, "../..", &image);
+        assert_ne!(first, second);
+        assert!(!first.file_stem().unwrap().to_str().unwrap().ends_with('_'));
+    }
+
+    #[tokio::test]
+    async fn test_save_does_not_overwrite() {
+        let dir = std::env::temp_dir().join(format!("whatsmeow-autodownload-{}", uuid::Uuid::new_v4()));
+        let policy = MediaAutoDownloadPolicy::new(&dir);
+        let chat = JID::new("1", "s.whatsapp.net");
+        let path = policy.path_for(&chat, &chat, "3EB0AA", &media(MediaType::Image, "image/jpeg", None));
+
+        assert_eq!(policy.save(&path, b"first").await.unwrap(), path);
+        let again = policy.save(&path, b"second").await.unwrap();
+        assert_eq!(again, dir.join("1-s-whatsapp-net_1-s-whatsapp-net_3EB0AA-1.jpg"));
+        assert_eq!(std::fs::read(&path).unwrap(), b"first");
+        assert_eq!(std::fs::read(&again).unwrap(), b"second");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,124 @@
+//! Blurhash strings of media previews.
+//!
+//! A blurhash is a short string describing the colors of an image, which
+//! UIs decode into a blurred placeholder without any image data. See
+//! <https://blurha.sh> for the format.
+
+use std::f32::consts::PI;
+
+use super::MediaError;
+
+/// Horizontal and vertical components of computed blurhashes.
+pub const BLURHASH_COMPONENTS: (u32, u32) = (4, 3);
+
+/// Side the image is scaled down to first; the hash does not need more.
+const SAMPLE_SIZE: u32 = 32;
+
+const BASE83: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz#$%*+,-.:;=?@[]^_{|}~";
+
+/// Compute the blurhash of an image, such as the `jpeg_thumbnail` of a
+/// media message, with `BLURHASH_COMPONENTS` components.
+pub fn blurhash(data: &[u8]) -> Result<String, MediaError> {
+    let image = image::load_from_memory(data).map_err(|e| MediaError::InvalidImage(e.to_string()))?;
+    let image = image.thumbnail(SAMPLE_SIZE, SAMPLE_SIZE).to_rgb8();
+    let (width, height) = image.dimensions();
+    let (components_x, components_y) = BLURHASH_COMPONENTS;
+
+    let mut factors = Vec::with_capacity((components_x * components_y) as usize);
+    for j in 0..components_y {
+        for i in 0..components_x {
+            let normalisation = if i == 0 && j == 0 { 1.0 } else { 2.0 };
+            let mut factor = [0.0f32; 3];
+            for (x, y, pixel) in image.enumerate_pixels() {
+                let basis = (PI * i as f32 * x as f32 / width as f32).cos()
+                    * (PI * j as f32 * y as f32 / height as f32).cos();
+                for (sum, channel) in factor.iter_mut().zip(pixel.0) {
+                    *sum += basis * srgb_to_linear(channel);
+                }
+            }
+            let scale = normalisation / (width * height) as f32;
+            factors.push(factor.map(|sum| sum * scale));
+        }
+    }
+
+    let mut hash = String::new();
+    encode83(&mut hash, (components_x - 1) + (components_y - 1) * 9, 1);
+
+    let (dc, ac) = factors.split_first().expect("at least one component");
+    let max_value = if ac.is_empty() {
+        encode83(&mut hash, 0, 1);
+        1.0
+    } else {
+        let actual_max = ac.iter().flatten().fold(0.0f32, |max, value| max.max(value.abs()));
+        let quantised_max = ((actual_max * 166.0 - 0.5).floor()).clamp(0.0, 82.0) as u32;
+        encode83(&mut hash, quantised_max, 1);
+        (quantised_max + 1) as f32 / 166.0
+    };
+
+    let [r, g, b] = dc.map(linear_to_srgb);
+    encode83(&mut hash, (r << 16) + (g << 8) + b, 4);
+    for factor in ac {
+        let [r, g, b] = factor.map(|value| {
+            (sign_pow(value / max_value, 0.5) * 9.0 + 9.5).floor().clamp(0.0, 18.0) as u32
+        });
+        encode83(&mut hash, r * 19 * 19 + g * 19 + b, 2);
+    }
+    Ok(hash)
+}
+
+fn encode83(out: &mut String, value: u32, length: u32) {
+    for i in 1..=length {
+        let digit = (value / 83u32.pow(length - i)) % 83;
+        out.push(BASE83[digit as usize] as char);
+    }
+}
+
+fn srgb_to_linear(value: u8) -> f32 {
+    let v = value as f32 / 255.0;
+    if v <= 0.04045 {
+        v / 12.92
+    } else {
+        ((v + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(value: f32) -> u32 {
+    let v = value.clamp(0.0, 1.0);
+    if v <= 0.003_130_8 {
+        (v * 12.92 * 255.0 + 0.5) as u32
+    } else {
+        ((1.055 * v.powf(1.0 / 2.4) - 0.055) * 255.0 + 0.5) as u32
+    }
+}
+
+fn sign_pow(value: f32, exp: f32) -> f32 {
+    value.abs().powf(exp).copysign(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageFormat, RgbImage};
+    use std::io::Cursor;
+
+    fn png(image: RgbImage) -> Vec<u8> {
+        let mut data = Cursor::new(Vec::new());
+        image.write_to(&mut data, ImageFormat::Png).unwrap();
+        data.into_inner()
+    }
+
+    #[test]
+    fn test_blurhash() {
+        // 4x3 components, then the average color as 0xFF0000
+        let red = png(RgbImage::from_pixel(16, 16, image::Rgb([255, 0, 0])));
+        let hash = blurhash(&red).unwrap();
+        assert_eq!(hash.len(), 28);
+        assert_eq!(&hash[..1], "L");
+        assert_eq!(&hash[2..6], "TI:j");
+
+        let gradient = png(RgbImage::from_fn(16, 16, |x, _| image::Rgb([(x * 16) as u8, 0, 0])));
+        assert_ne!(blurhash(&gradient).unwrap()[6..], hash[6..]);
+
+        assert!(matches!(blurhash(b"not an image"), Err(MediaError::InvalidImage(_))));
+    }
+}
@@ -0,0 +1,120 @@
+//! Mime types of media files and their file extensions.
+
+use std::collections::HashSet;
+
+use super::MediaType;
+
+/// Mime types and their extensions. The first extension listed for a mime
+/// type is the one files of that type are saved with.
+const EXTENSIONS: &[(&str, &str)] = &[
+    ("image/jpeg", "jpg"),
+    ("image/jpeg", "jpeg"),
+    ("image/png", "png"),
+    ("image/gif", "gif"),
+    ("image/webp", "webp"),
+    ("video/mp4", "mp4"),
+    ("video/mp4", "m4v"),
+    ("video/3gpp", "3gp"),
+    ("video/quicktime", "mov"),
+    ("video/webm", "webm"),
+    ("audio/ogg", "ogg"),
+    ("audio/ogg", "opus"),
+    ("audio/mpeg", "mp3"),
+    ("audio/mp4", "m4a"),
+    ("audio/aac", "aac"),
+    ("audio/amr", "amr"),
+    ("application/pdf", "pdf"),
+    ("application/zip", "zip"),
+    ("application/msword", "doc"),
+    ("application/vnd.openxmlformats-officedocument.wordprocessingml.document", "docx"),
+    ("application/vnd.ms-excel", "xls"),
+    ("application/vnd.openxmlformats-officedocument.spreadsheetml.sheet", "xlsx"),
+    ("application/vnd.ms-powerpoint", "ppt"),
+    ("application/vnd.openxmlformats-officedocument.presentationml.presentation", "pptx"),
+    ("text/plain", "txt"),
+    ("text/csv", "csv"),
+];
+
+/// The mime type without parameters, lowercased: `audio/ogg` for
+/// `audio/ogg; codecs=opus`.
+pub fn essence(mimetype: &str) -> String {
+    mimetype.split(';').next().unwrap_or_default().trim().to_ascii_lowercase()
+}
+
+/// Extension files of a mime type are saved with.
+pub fn extension_for_mimetype(mimetype: &str) -> Option<&'static str> {
+    let essence = essence(mimetype);
+    EXTENSIONS.iter().find(|(known, _)| *known == essence).map(|(_, extension)| *extension)
+}
+
+/// Mime type of files with an extension.
+pub fn mimetype_for_extension(extension: &str) -> Option<&'static str> {
+    let extension = extension.trim_start_matches('.').to_ascii_lowercase();
+    EXTENSIONS.iter().find(|(_, known)| *known == extension).map(|(mimetype, _)| *mimetype)
+}
+
+/// Extension of a media file, from its mime type or else from what the
+/// media type is usually sent as.
+pub fn file_extension(media_type: MediaType, mimetype: &str) -> &'static str {
+    extension_for_mimetype(mimetype).unwrap_or(match media_type {
+        MediaType::Image => "jpg",
+        MediaType::Video => "mp4",
+        MediaType::Audio => "ogg",
+        MediaType::Sticker => "webp",
+        MediaType::StickerPack => "zip",
+        MediaType::Document | MediaType::History => "bin",
+    })
+}
+
+/// Mime types of documents to accept, such as those
+/// `MediaAutoDownloadPolicy` downloads.
+///
+/// The list is set by hand; without one every type is allowed.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AllowedDocumentTypes {
+    mimetypes: Option<HashSet<String>>,
+}
+
+impl AllowedDocumentTypes {
+    /// Allow every mime type.
+    pub fn any() -> Self {
+        Self::default()
+    }
+
+    /// Allow only the mime types in a comma separated list, such as
+    /// `"application/pdf, text/plain"`. Parameters like `charset` are
+    /// ignored.
+    pub fn from_list(value: &str) -> Self {
+        let mimetypes = value.split(',').map(essence).filter(|mimetype| !mimetype.is_empty()).collect();
+        Self { mimetypes: Some(mimetypes) }
+    }
+
+    /// Whether a document of a mime type may be sent.
+    pub fn allows(&self, mimetype: &str) -> bool {
+        match &self.mimetypes {
+            Some(mimetypes) => mimetypes.contains(&essence(mimetype)),
+            None => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mime_extensions() {
+        assert_eq!(extension_for_mimetype("audio/ogg; codecs=opus"), Some("ogg"));
+        assert_eq!(extension_for_mimetype("IMAGE/JPEG"), Some("jpg"));
+        assert_eq!(extension_for_mimetype("application/x-unknown"), None);
+        assert_eq!(mimetype_for_extension(".JPEG"), Some("image/jpeg"));
+        assert_eq!(mimetype_for_extension("docx").and_then(extension_for_mimetype), Some("docx"));
+        assert_eq!(file_extension(MediaType::Sticker, ""), "webp");
+        assert_eq!(file_extension(MediaType::Document, "application/x-unknown"), "bin");
+
+        let allowed = AllowedDocumentTypes::from_list("application/pdf, text/plain");
+        assert!(allowed.allows("text/plain; charset=utf-8"));
+        assert!(!allowed.allows("application/zip"));
+        assert!(AllowedDocumentTypes::any().allows("application/zip"));
+    }
+}
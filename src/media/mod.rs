@@ -0,0 +1,344 @@
+//! Media download and decryption.
+//!
+//! Media files are stored on the WhatsApp CDN encrypted with AES-256-CBC
+//! using keys expanded from the per-file media key, followed by a
+//! truncated HMAC-SHA256 of the IV and ciphertext.
+
+use aes::cipher::{block_padding::Pkcs7, BlockDecryptMut, BlockEncryptMut, KeyIvInit};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use tokio_util::sync::CancellationToken;
+
+use crate::crypto::Hkdf;
+use crate::types::{MediaDetails, MessageContent};
+
+mod transfer;
+mod upload;
+mod retry;
+mod autodownload;
+pub mod mime;
+mod stickerpack;
+#[cfg(feature = "thumbnails")]
+mod thumbnail;
+#[cfg(feature = "blurhash")]
+mod blurhash;
+
+pub use transfer::{TransferProgress, ProgressCallback, Transfer};
+pub use upload::{MediaConn, MediaConnRequest, UploadedMedia, upload};
+pub use autodownload::MediaAutoDownloadPolicy;
+pub use stickerpack::{StickerFile, unzip_sticker_pack, MAX_PACK_SIZE, MAX_STICKER_SIZE};
+pub use retry::{
+    MediaRetryTarget, MediaRetryResult, build_media_retry_receipt, parse_media_retry_notification,
+    is_media_retry_notification, is_expired_media_error,
+};
+#[cfg(feature = "thumbnails")]
+pub use thumbnail::{jpeg_thumbnail, THUMBNAIL_SIZE};
+#[cfg(feature = "blurhash")]
+pub use blurhash::{blurhash, BLURHASH_COMPONENTS};
+pub(crate) use transfer::{ProgressReader, fetch};
+
+type Aes256CbcEnc = cbc::Encryptor<aes::Aes256>;
+type Aes256CbcDec = cbc::Decryptor<aes::Aes256>;
+type HmacSha256 = Hmac<Sha256>;
+
+/// Host serving media by direct path.
+pub const MEDIA_HOST: &str = "mmg.whatsapp.net";
+
+/// Length of the MAC appended to encrypted media.
+const MAC_LENGTH: usize = 10;
+
+/// Kind of media, which selects the key derivation info.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MediaType {
+    Image,
+    Video,
+    Audio,
+    Document,
+    Sticker,
+    /// Zip of the stickers of a sticker pack
+    StickerPack,
+    /// History sync data, sent as an encrypted file
+    History,
+}
+
+impl MediaType {
+    /// HKDF info used to expand the media key.
+    pub fn hkdf_info(&self) -> &'static str {
+        match self {
+            MediaType::Image | MediaType::Sticker => "WhatsApp Image Keys",
+            MediaType::Video => "WhatsApp Video Keys",
+            MediaType::Audio => "WhatsApp Audio Keys",
+            MediaType::Document => "WhatsApp Document Keys",
+            MediaType::StickerPack => "WhatsApp Sticker Pack Keys",
+            MediaType::History => "WhatsApp History Keys",
+        }
+    }
+
+    /// Name of the type in message nodes.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MediaType::Image => "image",
+            MediaType::Video => "video",
+            MediaType::Audio => "audio",
+            MediaType::Document => "document",
+            MediaType::Sticker => "sticker",
+            MediaType::StickerPack => "sticker-pack",
+            MediaType::History => "history",
+        }
+    }
+
+    /// Type to send a file of the given mime type as. Anything that is not
+    /// an image, video or audio goes as a document.
+    pub fn from_mimetype(mimetype: &str) -> Self {
+        match mimetype.split('/').next() {
+            Some("image") => MediaType::Image,
+            Some("video") => MediaType::Video,
+            Some("audio") => MediaType::Audio,
+            _ => MediaType::Document,
+        }
+    }
+}
+
+/// Media errors.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MediaError {
+    /// The message has neither a URL nor a direct path
+    MissingUrl,
+    /// The CDN answered with an error status
+    HttpStatus(u16),
+    /// The request failed before a response arrived
+    RequestFailed(String),
+    /// The encrypted file failed MAC verification
+    InvalidMac,
+    /// A downloaded or decrypted file did not match its expected hash
+    HashMismatch,
+    /// The file could not be decrypted
+    DecryptFailed(String),
+    /// Reading or writing a local file failed
+    Io(String),
+    /// An image could not be decoded or encoded
+    InvalidImage(String),
+    /// A zip archive, such as a sticker pack, could not be read
+    InvalidArchive(String),
+    /// The transfer was cancelled
+    Cancelled,
+}
+
+impl std::fmt::Display for MediaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MediaError::MissingUrl => write!(f, "media has no download url"),
+            MediaError::HttpStatus(code) => write!(f, "media server returned status {}", code),
+            MediaError::RequestFailed(e) => write!(f, "media request failed: {}", e),
+            MediaError::InvalidMac => write!(f, "media mac mismatch"),
+            MediaError::HashMismatch => write!(f, "media hash mismatch"),
+            MediaError::DecryptFailed(e) => write!(f, "media decryption failed: {}", e),
+            MediaError::Io(e) => write!(f, "media io error: {}", e),
+            MediaError::InvalidImage(e) => write!(f, "invalid image: {}", e),
+            MediaError::InvalidArchive(e) => write!(f, "invalid archive: {}", e),
+            MediaError::Cancelled => write!(f, "media transfer cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for MediaError {}
+
+impl From<std::io::Error> for MediaError {
+    fn from(e: std::io::Error) -> Self {
+        MediaError::Io(e.to_string())
+    }
+}
+
+/// Keys expanded from a media key.
+struct MediaKeys {
+    iv: [u8; 16],
+    cipher_key: [u8; 32],
+    mac_key: [u8; 32],
+}
+
+impl MediaKeys {
+    fn expand(media_key: &[u8], media_type: MediaType) -> Self {
+        let expanded = Hkdf::derive(None, media_key, media_type.hkdf_info().as_bytes(), 112);
+        let mut keys = Self { iv: [0; 16], cipher_key: [0; 32], mac_key: [0; 32] };
+        keys.iv.copy_from_slice(&expanded[..16]);
+        keys.cipher_key.copy_from_slice(&expanded[16..48]);
+        keys.mac_key.copy_from_slice(&expanded[48..80]);
+        keys
+    }
+
+    fn mac(&self, ciphertext: &[u8]) -> [u8; MAC_LENGTH] {
+        let mut mac = HmacSha256::new_from_slice(&self.mac_key)
+            .expect("HMAC can take key of any size");
+        mac.update(&self.iv);
+        mac.update(ciphertext);
+        let mut out = [0; MAC_LENGTH];
+        out.copy_from_slice(&mac.finalize().into_bytes()[..MAC_LENGTH]);
+        out
+    }
+}
+
+/// Encrypt a file for upload, returning the ciphertext with its MAC.
+pub fn encrypt_media(data: &[u8], media_key: &[u8], media_type: MediaType) -> Vec<u8> {
+    let keys = MediaKeys::expand(media_key, media_type);
+    let mut encrypted = Aes256CbcEnc::new(&keys.cipher_key.into(), &keys.iv.into())
+        .encrypt_padded_vec_mut::<Pkcs7>(data);
+    let mac = keys.mac(&encrypted);
+    encrypted.extend_from_slice(&mac);
+    encrypted
+}
+
+/// Verify and decrypt a downloaded file.
+pub fn decrypt_media(encrypted: &[u8], media_key: &[u8], media_type: MediaType) -> Result<Vec<u8>, MediaError> {
+    if encrypted.len() < MAC_LENGTH {
+        return Err(MediaError::DecryptFailed("file too short".to_string()));
+    }
+
+    let keys = MediaKeys::expand(media_key, media_type);
+    let (ciphertext, mac) = encrypted.split_at(encrypted.len() - MAC_LENGTH);
+    let expected = keys.mac(ciphertext);
+    if !constant_time_eq(mac, &expected) {
+        return Err(MediaError::InvalidMac);
+    }
+
+    Aes256CbcDec::new(&keys.cipher_key.into(), &keys.iv.into())
+        .decrypt_padded_vec_mut::<Pkcs7>(ciphertext)
+        .map_err(|e| MediaError::DecryptFailed(e.to_string()))
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// A media attachment that can be downloaded.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DownloadableMedia {
+    pub media_type: MediaType,
+    pub url: String,
+    pub mimetype: String,
+    pub details: MediaDetails,
+}
+
+impl DownloadableMedia {
+    /// Get the downloadable media of a message, if it has any.
+    pub fn from_content(content: &MessageContent) -> Option<Self> {
+        let (media_type, url, mimetype, details) = match content {
+            MessageContent::Image { url, mimetype, media, .. } => (MediaType::Image, url.as_str(), mimetype.as_str(), media),
+            MessageContent::Video { url, mimetype, media, .. } => (MediaType::Video, url.as_str(), mimetype.as_str(), media),
+            MessageContent::Audio { url, mimetype, media, .. } => (MediaType::Audio, url.as_str(), mimetype.as_str(), media),
+            MessageContent::Document { url, mimetype, media, .. } => (MediaType::Document, url.as_str(), mimetype.as_str(), media),
+            MessageContent::Sticker { url, media } => (MediaType::Sticker, url.as_str(), "image/webp", media),
+            MessageContent::StickerPack { media, .. } => (MediaType::StickerPack, "", "application/zip", media),
+            MessageContent::ViewOnce(content) => return Self::from_content(content),
+            _ => return None,
+        };
+
+        Some(Self {
+            media_type,
+            url: url.to_string(),
+            mimetype: mimetype.to_string(),
+            details: details.clone(),
+        })
+    }
+
+    /// URL to fetch the file from, preferring the direct path.
+    pub fn download_url(&self) -> Option<String> {
+        match &self.details.direct_path {
+            Some(path) => Some(format!("https://{}{}", MEDIA_HOST, path)),
+            None if !self.url.is_empty() => Some(self.url.clone()),
+            None => None,
+        }
+    }
+
+    /// Download, verify and decrypt the file.
+    pub async fn download(&self) -> Result<Vec<u8>, MediaError> {
+        let url = self.download_url().ok_or(MediaError::MissingUrl)?;
+        let data = tokio::task::spawn_blocking(move || fetch(&url, None, CancellationToken::new()))
+            .await
+            .map_err(|e| MediaError::RequestFailed(e.to_string()))??;
+        self.decrypt(&data)
+    }
+
+    /// Verify and decrypt downloaded bytes.
+    ///
+    /// Files without a media key are treated as plaintext.
+    pub fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>, MediaError> {
+        if let Some(expected) = &self.details.file_enc_sha256 {
+            if Sha256::digest(data).as_slice() != expected.as_slice() {
+                return Err(MediaError::HashMismatch);
+            }
+        }
+
+        let plaintext = match &self.details.media_key {
+            Some(media_key) => decrypt_media(data, media_key, self.media_type)?,
+            None => data.to_vec(),
+        };
+
+        if let Some(expected) = &self.details.file_sha256 {
+            if Sha256::digest(&plaintext).as_slice() != expected.as_slice() {
+                return Err(MediaError::HashMismatch);
+            }
+        }
+
+        Ok(plaintext)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_media_roundtrip() {
+        let media_key = [7u8; 32];
+        let data = b"not really a jpeg".to_vec();
+
+        let encrypted = encrypt_media(&data, &media_key, MediaType::Image);
+        assert_eq!(decrypt_media(&encrypted, &media_key, MediaType::Image).unwrap(), data);
+
+        // Keys depend on the media type
+        assert_eq!(
+            decrypt_media(&encrypted, &media_key, MediaType::Video),
+            Err(MediaError::InvalidMac),
+        );
+    }
+
+    #[test]
+    fn test_decrypt_verifies_hashes() {
+        let media_key = vec![1u8; 32];
+        let data = b"voice note".to_vec();
+        let encrypted = encrypt_media(&data, &media_key, MediaType::Audio);
+
+        let mut media = DownloadableMedia {
+            media_type: MediaType::Audio,
+            url: String::new(),
+            mimetype: "audio/ogg".to_string(),
+            details: MediaDetails {
+                media_key: Some(media_key),
+                file_sha256: Some(Sha256::digest(&data).to_vec()),
+                file_enc_sha256: Some(Sha256::digest(&encrypted).to_vec()),
+                ..Default::default()
+            },
+        };
+        assert_eq!(media.decrypt(&encrypted).unwrap(), data);
+        assert_eq!(media.download_url(), None);
+
+        media.details.file_sha256 = Some(vec![0; 32]);
+        assert_eq!(media.decrypt(&encrypted), Err(MediaError::HashMismatch));
+    }
+
+    #[test]
+    fn test_download_url_prefers_direct_path() {
+        let content = MessageContent::Sticker {
+            url: "https://example.com/old".to_string(),
+            media: MediaDetails {
+                direct_path: Some("/v/t62/abc".to_string()),
+                ..Default::default()
+            },
+        };
+        let media = DownloadableMedia::from_content(&content).unwrap();
+        assert_eq!(media.media_type, MediaType::Sticker);
+        assert_eq!(media.download_url().as_deref(), Some("https://mmg.whatsapp.net/v/t62/abc"));
+
+        assert!(DownloadableMedia::from_content(&MessageContent::Text("hi".to_string())).is_none());
+    }
+}
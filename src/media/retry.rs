@@ -0,0 +1,210 @@
+//! Media retry requests.
+//!
+//! When a direct path has expired, the CDN answers 404 or 410. The sender's
+//! phone can re-upload the file: we send a `server-error` receipt with the
+//! message ID encrypted under the media key, and it answers with a
+//! `mediaretry` notification holding a fresh direct path.
+
+use aes_gcm::{Aes256Gcm, Nonce, aead::{Aead, KeyInit, Payload}};
+use prost::Message as _;
+
+use crate::binary::Node;
+use crate::crypto::Hkdf;
+use crate::media::MediaError;
+use crate::proto::{media_retry_result, MediaRetryNotification, ServerErrorReceipt};
+use crate::types::JID;
+
+const MEDIA_RETRY_INFO: &[u8] = b"WhatsApp Media Retry Notification";
+
+/// Message whose media should be re-uploaded.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MediaRetryTarget {
+    pub message_id: String,
+    pub chat: JID,
+    /// Sender in group chats
+    pub sender: Option<JID>,
+    pub is_from_me: bool,
+}
+
+/// Outcome reported by a media retry notification.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MediaRetryResult {
+    /// The file was re-uploaded to a new direct path
+    Success { direct_path: String },
+    /// The sender no longer has the file
+    NotFound,
+    /// The sender could not decrypt the request
+    DecryptionError,
+    /// Any other failure
+    GeneralError,
+}
+
+fn retry_cipher(media_key: &[u8]) -> Aes256Gcm {
+    let key = Hkdf::derive(None, media_key, MEDIA_RETRY_INFO, 32);
+    Aes256Gcm::new_from_slice(&key).expect("key is 32 bytes")
+}
+
+/// Check whether a download failure means the direct path expired.
+pub fn is_expired_media_error(error: &MediaError) -> bool {
+    matches!(error, MediaError::HttpStatus(404 | 410))
+}
+
+/// Build the receipt asking the sender to re-upload media.
+pub fn build_media_retry_receipt(
+    target: &MediaRetryTarget,
+    own_jid: &JID,
+    media_key: &[u8],
+) -> Result<Node, MediaError> {
+    let plaintext = ServerErrorReceipt { stanza_id: Some(target.message_id.clone()) }.encode_to_vec();
+    let iv: [u8; 12] = rand::random();
+    let ciphertext = retry_cipher(media_key)
+        .encrypt(Nonce::from_slice(&iv), Payload { msg: &plaintext, aad: target.message_id.as_bytes() })
+        .map_err(|e| MediaError::DecryptFailed(e.to_string()))?;
+
+    let mut enc_p = Node::new("enc_p");
+    enc_p.set_bytes(ciphertext);
+    let mut enc_iv = Node::new("enc_iv");
+    enc_iv.set_bytes(iv.to_vec());
+    let mut encrypt = Node::new("encrypt");
+    encrypt.add_child(enc_p);
+    encrypt.add_child(enc_iv);
+
+    let mut rmr = Node::new("rmr");
+    rmr.set_attr("jid", target.chat.to_string());
+    rmr.set_attr("from_me", target.is_from_me.to_string());
+    if let Some(sender) = &target.sender {
+        rmr.set_attr("participant", sender.to_string());
+    }
+
+    let mut receipt = Node::new("receipt");
+    receipt.set_attr("id", target.message_id.clone());
+    receipt.set_attr("to", own_jid.to_non_ad().to_string());
+    receipt.set_attr("type", "server-error");
+    receipt.add_child(encrypt);
+    receipt.add_child(rmr);
+    Ok(receipt)
+}
+
+/// Check whether a node is a media retry notification.
+pub fn is_media_retry_notification(node: &Node) -> bool {
+    node.tag == "notification" && node.get_attr_str("type") == Some("mediaretry")
+}
+
+/// Decrypt a media retry notification.
+pub fn parse_media_retry_notification(node: &Node, media_key: &[u8]) -> Result<MediaRetryResult, MediaError> {
+    let message_id = node.get_attr_str("id").unwrap_or_default();
+
+    if let Some(error) = node.get_child_by_tag("error") {
+        return Err(MediaError::RequestFailed(format!(
+            "media retry failed with code {}",
+            error.get_attr_str("code").unwrap_or("unknown"),
+        )));
+    }
+
+    let encrypt = node.get_child_by_tag("encrypt")
+        .ok_or_else(|| MediaError::DecryptFailed("missing <encrypt>".to_string()))?;
+    let field = |tag: &str| {
+        encrypt.get_child_by_tag(tag)
+            .and_then(|n| n.get_bytes())
+            .ok_or_else(|| MediaError::DecryptFailed(format!("missing <{}>", tag)))
+    };
+    let ciphertext = field("enc_p")?;
+    let iv = field("enc_iv")?;
+    if iv.len() != 12 {
+        return Err(MediaError::DecryptFailed("invalid iv".to_string()));
+    }
+
+    let plaintext = retry_cipher(media_key)
+        .decrypt(Nonce::from_slice(iv), Payload { msg: ciphertext, aad: message_id.as_bytes() })
+        .map_err(|_| MediaError::InvalidMac)?;
+    let notification = MediaRetryNotification::decode(plaintext.as_slice())
+        .map_err(|e| MediaError::DecryptFailed(e.to_string()))?;
+
+    Ok(match notification.result.unwrap_or(media_retry_result::GENERAL_ERROR) {
+        media_retry_result::SUCCESS => match notification.direct_path {
+            Some(direct_path) if !direct_path.is_empty() => MediaRetryResult::Success { direct_path },
+            _ => MediaRetryResult::GeneralError,
+        },
+        media_retry_result::NOT_FOUND => MediaRetryResult::NotFound,
+        media_retry_result::DECRYPTION_ERROR => MediaRetryResult::DecryptionError,
+        _ => MediaRetryResult::GeneralError,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build a notification the way the sender's phone would.
+    fn notification(message_id: &str, media_key: &[u8], body: &MediaRetryNotification) -> Node {
+        let iv = [9u8; 12];
+        let ciphertext = retry_cipher(media_key)
+            .encrypt(Nonce::from_slice(&iv), Payload { msg: &body.encode_to_vec(), aad: message_id.as_bytes() })
+            .unwrap();
+
+        let mut enc_p = Node::new("enc_p");
+        enc_p.set_bytes(ciphertext);
+        let mut enc_iv = Node::new("enc_iv");
+        enc_iv.set_bytes(iv.to_vec());
+        let mut encrypt = Node::new("encrypt");
+        encrypt.add_child(enc_p);
+        encrypt.add_child(enc_iv);
+
+        let mut node = Node::new("notification");
+        node.set_attr("id", message_id);
+        node.set_attr("type", "mediaretry");
+        node.add_child(encrypt);
+        node
+    }
+
+    #[test]
+    fn test_build_media_retry_receipt() {
+        let target = MediaRetryTarget {
+            message_id: "ABC".to_string(),
+            chat: JID::new("123", "g.us"),
+            sender: Some(JID::new("456", "s.whatsapp.net")),
+            is_from_me: false,
+        };
+        let own = JID::new_ad("789", 0, 3);
+        let receipt = build_media_retry_receipt(&target, &own, &[1u8; 32]).unwrap();
+
+        assert_eq!(receipt.get_attr_str("type"), Some("server-error"));
+        assert_eq!(receipt.get_attr_str("to"), Some("789@s.whatsapp.net"));
+        let rmr = receipt.get_child_by_tag("rmr").unwrap();
+        assert_eq!(rmr.get_attr_str("participant"), Some("456@s.whatsapp.net"));
+        assert_eq!(rmr.get_attr_str("from_me"), Some("false"));
+
+        // The phone can decrypt the request with the same media key
+        let encrypt = receipt.get_child_by_tag("encrypt").unwrap();
+        let ciphertext = encrypt.get_child_by_tag("enc_p").unwrap().get_bytes().unwrap();
+        let iv = encrypt.get_child_by_tag("enc_iv").unwrap().get_bytes().unwrap();
+        let plaintext = retry_cipher(&[1u8; 32])
+            .decrypt(Nonce::from_slice(iv), Payload { msg: ciphertext, aad: b"ABC" })
+            .unwrap();
+        let body = ServerErrorReceipt::decode(plaintext.as_slice()).unwrap();
+        assert_eq!(body.stanza_id.as_deref(), Some("ABC"));
+    }
+
+    #[test]
+    fn test_parse_media_retry_notification() {
+        let media_key = [5u8; 32];
+        let node = notification("ABC", &media_key, &MediaRetryNotification {
+            stanza_id: Some("ABC".to_string()),
+            direct_path: Some("/v/t62/new".to_string()),
+            result: Some(media_retry_result::SUCCESS),
+        });
+
+        assert!(is_media_retry_notification(&node));
+        assert_eq!(
+            parse_media_retry_notification(&node, &media_key).unwrap(),
+            MediaRetryResult::Success { direct_path: "/v/t62/new".to_string() },
+        );
+        assert_eq!(parse_media_retry_notification(&node, &[6u8; 32]), Err(MediaError::InvalidMac));
+
+        let node = notification("ABC", &media_key, &MediaRetryNotification {
+            result: Some(media_retry_result::NOT_FOUND),
+            ..Default::default()
+        });
+        assert_eq!(parse_media_retry_notification(&node, &media_key).unwrap(), MediaRetryResult::NotFound);
+    }
+}
@@ -0,0 +1,212 @@
+//! Sticker pack archives.
+//!
+//! A sticker pack is sent as one zip file holding a WebP file per sticker.
+//! Only what packs use is read: stored and deflated entries, found through
+//! the central directory. Entry checksums are not checked, as the whole
+//! file is already verified against its SHA-256 when it is decrypted.
+
+use std::io::Read;
+
+use flate2::read::DeflateDecoder;
+
+use super::MediaError;
+
+const END_OF_CENTRAL_DIRECTORY: u32 = 0x0605_4b50;
+const CENTRAL_DIRECTORY_HEADER: u32 = 0x0201_4b50;
+const LOCAL_FILE_HEADER: u32 = 0x0403_4b50;
+
+/// Length of the end of central directory record without its comment.
+const END_RECORD_LEN: usize = 22;
+
+const METHOD_STORED: u16 = 0;
+const METHOD_DEFLATED: u16 = 8;
+
+/// Largest sticker file extracted from a pack.
+pub const MAX_STICKER_SIZE: usize = 8 * 1024 * 1024;
+
+/// Largest total size of the files extracted from a pack.
+pub const MAX_PACK_SIZE: usize = 64 * 1024 * 1024;
+
+/// A file extracted from a sticker pack.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StickerFile {
+    /// Name of the file in the pack, as listed in the pack message
+    pub file_name: String,
+    pub data: Vec<u8>,
+}
+
+fn invalid(reason: &str) -> MediaError {
+    MediaError::InvalidArchive(reason.to_string())
+}
+
+/// `at + len`, failing on overflow rather than wrapping.
+fn offset(at: usize, len: usize) -> Result<usize, MediaError> {
+    at.checked_add(len).ok_or_else(|| invalid("offset out of range"))
+}
+
+fn read_bytes(data: &[u8], at: usize, len: usize) -> Result<&[u8], MediaError> {
+    data.get(at..offset(at, len)?).ok_or_else(|| invalid("truncated"))
+}
+
+fn read_u16(data: &[u8], at: usize) -> Result<u16, MediaError> {
+    read_bytes(data, at, 2).map(|b| u16::from_le_bytes([b[0], b[1]]))
+}
+
+fn read_u32(data: &[u8], at: usize) -> Result<u32, MediaError> {
+    read_bytes(data, at, 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+/// Extract the files of a decrypted sticker pack zip, in archive order.
+/// Directory entries are skipped.
+///
+/// Files larger than `MAX_STICKER_SIZE`, or together larger than
+/// `MAX_PACK_SIZE`, fail the whole pack.
+pub fn unzip_sticker_pack(data: &[u8]) -> Result<Vec<StickerFile>, MediaError> {
+    // The end record is last, followed only by a comment of up to 64 KiB
+    let search_from = data.len().saturating_sub(END_RECORD_LEN + u16::MAX as usize);
+    let end = (search_from..=data.len().saturating_sub(END_RECORD_LEN))
+        .rev()
+        .find(|&at| read_u32(data, at).ok() == Some(END_OF_CENTRAL_DIRECTORY))
+        .ok_or_else(|| invalid("no end of central directory"))?;
+
+    let entries = read_u16(data, offset(end, 10)?)?;
+    let mut at = read_u32(data, offset(end, 16)?)? as usize;
+    let mut files = Vec::new();
+    let mut total = 0usize;
+    for _ in 0..entries {
+        if read_u32(data, at)? != CENTRAL_DIRECTORY_HEADER {
+            return Err(invalid("bad central directory header"));
+        }
+        let method = read_u16(data, offset(at, 10)?)?;
+        let compressed_size = read_u32(data, offset(at, 20)?)? as usize;
+        let size = read_u32(data, offset(at, 24)?)? as usize;
+        let name_len = read_u16(data, offset(at, 28)?)? as usize;
+        let extra_len = read_u16(data, offset(at, 30)?)? as usize;
+        let comment_len = read_u16(data, offset(at, 32)?)? as usize;
+        let local_offset = read_u32(data, offset(at, 42)?)? as usize;
+        let name = read_bytes(data, offset(at, 46)?, name_len)?;
+        let file_name = String::from_utf8_lossy(name).into_owned();
+        at = offset(offset(offset(offset(at, 46)?, name_len)?, extra_len)?, comment_len)?;
+
+        if file_name.ends_with('/') {
+            continue;
+        }
+        let stored_size = if method == METHOD_STORED { compressed_size } else { size };
+        total = offset(total, stored_size)?;
+        if stored_size > MAX_STICKER_SIZE || total > MAX_PACK_SIZE {
+            return Err(MediaError::InvalidArchive(format!("{} is too large", file_name)));
+        }
+        if read_u32(data, local_offset)? != LOCAL_FILE_HEADER {
+            return Err(invalid("bad local file header"));
+        }
+        let local_name_len = read_u16(data, offset(local_offset, 26)?)? as usize;
+        let local_extra_len = read_u16(data, offset(local_offset, 28)?)? as usize;
+        let start = offset(offset(offset(local_offset, 30)?, local_name_len)?, local_extra_len)?;
+        let compressed = read_bytes(data, start, compressed_size)?;
+
+        let data = match method {
+            METHOD_STORED => compressed.to_vec(),
+            METHOD_DEFLATED => {
+                let mut out = Vec::new();
+                // Never inflate past the declared size
+                DeflateDecoder::new(compressed)
+                    .take(size as u64)
+                    .read_to_end(&mut out)
+                    .map_err(|e| MediaError::InvalidArchive(e.to_string()))?;
+                out
+            }
+            other => return Err(MediaError::InvalidArchive(format!("unsupported compression method {}", other))),
+        };
+        files.push(StickerFile { file_name, data });
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use flate2::{write::DeflateEncoder, Compression};
+
+    /// Zip `files` as (name, data, deflate) entries.
+    fn zip(files: &[(&str, &[u8], bool)]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut directory = Vec::new();
+        for (name, data, deflate) in files {
+            let (method, stored) = if *deflate {
+                let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(data).unwrap();
+                (METHOD_DEFLATED, encoder.finish().unwrap())
+            } else {
+                (METHOD_STORED, data.to_vec())
+            };
+            let header = |signature: u32, extra: &[u8]| {
+                let mut h = signature.to_le_bytes().to_vec();
+                h.extend_from_slice(extra);
+                h.extend_from_slice(&[0; 4]); // version, flags
+                h.extend_from_slice(&method.to_le_bytes());
+                h.extend_from_slice(&[0; 8]); // time, date, crc
+                h.extend_from_slice(&(stored.len() as u32).to_le_bytes());
+                h.extend_from_slice(&(data.len() as u32).to_le_bytes());
+                h.extend_from_slice(&(name.len() as u16).to_le_bytes());
+                h.extend_from_slice(&[0; 2]); // extra length
+                h
+            };
+            let offset = out.len() as u32;
+            out.extend(header(LOCAL_FILE_HEADER, &[]));
+            out.extend_from_slice(name.as_bytes());
+            out.extend(&stored);
+
+            directory.extend(header(CENTRAL_DIRECTORY_HEADER, &[0; 2]));
+            directory.extend_from_slice(&[0; 10]); // comment length, disk, attributes
+            directory.extend_from_slice(&offset.to_le_bytes());
+            directory.extend_from_slice(name.as_bytes());
+        }
+        let directory_offset = out.len() as u32;
+        out.extend(&directory);
+        out.extend_from_slice(&END_OF_CENTRAL_DIRECTORY.to_le_bytes());
+        out.extend_from_slice(&[0; 4]);
+        out.extend_from_slice(&(files.len() as u16).to_le_bytes());
+        out.extend_from_slice(&(files.len() as u16).to_le_bytes());
+        out.extend_from_slice(&(directory.len() as u32).to_le_bytes());
+        out.extend_from_slice(&directory_offset.to_le_bytes());
+        out.extend_from_slice(&[0; 2]);
+        out
+    }
+
+    #[test]
+    fn test_unzip_sticker_pack() {
+        let webp = b"RIFF\x00\x00\x00\x00WEBPVP8 ".repeat(20);
+        let archive = zip(&[
+            ("stickers/", b"", false),
+            ("a.webp", &webp, true),
+            ("b.webp", b"RIFFWEBP", false),
+        ]);
+        let files = unzip_sticker_pack(&archive).unwrap();
+        assert_eq!(files, vec![
+            StickerFile { file_name: "a.webp".to_string(), data: webp },
+            StickerFile { file_name: "b.webp".to_string(), data: b"RIFFWEBP".to_vec() },
+        ]);
+
+        assert!(matches!(unzip_sticker_pack(b"not a zip"), Err(MediaError::InvalidArchive(_))));
+        assert!(matches!(unzip_sticker_pack(&archive[..archive.len() / 2]), Err(MediaError::InvalidArchive(_))));
+    }
+
+    #[test]
+    fn test_oversized_sticker_pack_is_refused() {
+        let big = vec![0; MAX_STICKER_SIZE + 1];
+        let archive = zip(&[("big.webp", &big, true)]);
+        assert!(matches!(unzip_sticker_pack(&archive), Err(MediaError::InvalidArchive(e)) if e.contains("too large")));
+
+        let part = vec![0; MAX_STICKER_SIZE];
+        let names: Vec<String> = (0..=MAX_PACK_SIZE / MAX_STICKER_SIZE).map(|i| format!("{}.webp", i)).collect();
+        let files: Vec<_> = names.iter().map(|name| (name.as_str(), &part[..], true)).collect();
+        assert!(matches!(unzip_sticker_pack(&zip(&files)), Err(MediaError::InvalidArchive(e)) if e.contains("too large")));
+
+        // Offsets near the end of the address space fail instead of wrapping
+        let mut archive = zip(&[("a.webp", b"RIFFWEBP", false)]);
+        let directory_offset = archive.len() - 6;
+        archive[directory_offset..directory_offset + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(unzip_sticker_pack(&archive), Err(MediaError::InvalidArchive(_))));
+    }
+}
@@ -0,0 +1,47 @@
+//! JPEG previews embedded in image messages.
+
+use std::io::Cursor;
+
+use image::codecs::jpeg::JpegEncoder;
+
+use super::MediaError;
+
+/// Longest side of a generated thumbnail, in pixels.
+pub const THUMBNAIL_SIZE: u32 = 72;
+
+/// JPEG quality of generated thumbnails.
+const THUMBNAIL_QUALITY: u8 = 60;
+
+/// Scale an image down to a small JPEG shown while the full file loads.
+///
+/// Accepts JPEG, PNG, GIF and WebP input; the aspect ratio is kept.
+pub fn jpeg_thumbnail(data: &[u8]) -> Result<Vec<u8>, MediaError> {
+    let image = image::load_from_memory(data).map_err(|e| MediaError::InvalidImage(e.to_string()))?;
+    let thumbnail = image.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE).to_rgb8();
+
+    let mut jpeg = Cursor::new(Vec::new());
+    JpegEncoder::new_with_quality(&mut jpeg, THUMBNAIL_QUALITY)
+        .encode_image(&thumbnail)
+        .map_err(|e| MediaError::InvalidImage(e.to_string()))?;
+    Ok(jpeg.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageFormat, RgbImage};
+
+    #[test]
+    fn test_jpeg_thumbnail() {
+        let mut png = Cursor::new(Vec::new());
+        RgbImage::from_pixel(400, 200, image::Rgb([200, 30, 30]))
+            .write_to(&mut png, ImageFormat::Png)
+            .unwrap();
+
+        let thumbnail = jpeg_thumbnail(png.get_ref()).unwrap();
+        let decoded = image::load_from_memory_with_format(&thumbnail, ImageFormat::Jpeg).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (THUMBNAIL_SIZE, THUMBNAIL_SIZE / 2));
+
+        assert!(matches!(jpeg_thumbnail(b"not an image"), Err(MediaError::InvalidImage(_))));
+    }
+}
@@ -0,0 +1,284 @@
+//! Progress reporting and cancellation for media transfers.
+
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+use crate::media::{DownloadableMedia, MediaError};
+
+/// Size of the chunks transfers are read in.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Progress of a transfer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransferProgress {
+    /// Bytes transferred so far
+    pub done: u64,
+    /// Total bytes, if known
+    pub total: Option<u64>,
+}
+
+/// Callback receiving transfer progress.
+pub type ProgressCallback = Arc<dyn Fn(TransferProgress) + Send + Sync>;
+
+/// A running upload or download.
+///
+/// Dropping the handle does not stop the transfer; call `cancel`.
+pub struct Transfer<T> {
+    cancel: CancellationToken,
+    task: JoinHandle<Result<T, MediaError>>,
+}
+
+impl<T: Send + 'static> Transfer<T> {
+    /// Run a blocking transfer on the blocking thread pool.
+    pub(crate) fn spawn<F>(f: F) -> Self
+    where
+        F: FnOnce(CancellationToken) -> Result<T, MediaError> + Send + 'static,
+    {
+        let cancel = CancellationToken::new();
+        let token = cancel.clone();
+        let task = tokio::task::spawn_blocking(move || f(token));
+        Self { cancel, task }
+    }
+
+    /// Stop the transfer. `wait` then returns `MediaError::Cancelled`.
+    pub fn cancel(&self) {
+        self.cancel.cancel();
+    }
+
+    /// Check if the transfer was cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.is_cancelled()
+    }
+
+    /// Wait for the transfer to finish.
+    pub async fn wait(self) -> Result<T, MediaError> {
+        self.task.await.map_err(|e| MediaError::RequestFailed(e.to_string()))?
+    }
+}
+
+/// Reader that reports progress and fails once cancelled.
+pub(crate) struct ProgressReader<R> {
+    inner: R,
+    progress: TransferProgress,
+    callback: Option<ProgressCallback>,
+    cancel: CancellationToken,
+}
+
+impl<R: Read> ProgressReader<R> {
+    pub(crate) fn new(
+        inner: R,
+        total: Option<u64>,
+        callback: Option<ProgressCallback>,
+        cancel: CancellationToken,
+    ) -> Self {
+        Self {
+            inner,
+            progress: TransferProgress { done: 0, total },
+            callback,
+            cancel,
+        }
+    }
+
+    /// Read everything, mapping a cancelled read to `MediaError::Cancelled`.
+    pub(crate) fn read_all(mut self) -> Result<Vec<u8>, MediaError> {
+        let mut data = Vec::with_capacity(self.progress.total.unwrap_or(0) as usize);
+        let mut chunk = vec![0; CHUNK_SIZE];
+        loop {
+            match self.read(&mut chunk) {
+                Ok(0) => return Ok(data),
+                Ok(n) => data.extend_from_slice(&chunk[..n]),
+                Err(e) => return Err(self.map_error(e)),
+            }
+        }
+    }
+
+    pub(crate) fn map_error(&self, e: io::Error) -> MediaError {
+        if self.cancel.is_cancelled() {
+            MediaError::Cancelled
+        } else {
+            MediaError::RequestFailed(e.to_string())
+        }
+    }
+}
+
+impl<R: Read> Read for ProgressReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.cancel.is_cancelled() {
+            return Err(io::Error::other("transfer cancelled"));
+        }
+
+        let n = self.inner.read(buf)?;
+        if n > 0 {
+            self.progress.done += n as u64;
+            if let Some(callback) = &self.callback {
+                callback(self.progress);
+            }
+        }
+        Ok(n)
+    }
+}
+
+/// Fetch a URL, reporting progress and stopping when cancelled.
+pub(crate) fn fetch(
+    url: &str,
+    callback: Option<ProgressCallback>,
+    cancel: CancellationToken,
+) -> Result<Vec<u8>, MediaError> {
+    let response = ureq::get(url).call().map_err(|e| match e {
+        ureq::Error::Status(code, _) => MediaError::HttpStatus(code),
+        e => MediaError::RequestFailed(e.to_string()),
+    })?;
+
+    let total = response.header("Content-Length").and_then(|l| l.parse().ok());
+    ProgressReader::new(response.into_reader(), total, callback, cancel).read_all()
+}
+
+/// Write a file through a `.part` file, removing it if anything fails.
+pub(crate) fn write_file(path: &Path, data: &[u8], cancel: &CancellationToken) -> Result<(), MediaError> {
+    let part = part_path(path);
+    let result = std::fs::write(&part, data)
+        .map_err(MediaError::from)
+        .and_then(|_| {
+            if cancel.is_cancelled() {
+                return Err(MediaError::Cancelled);
+            }
+            std::fs::rename(&part, path).map_err(MediaError::from)
+        });
+
+    if result.is_err() {
+        let _ = std::fs::remove_file(&part);
+    }
+    result
+}
+
+fn part_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".part");
+    path.with_file_name(name)
+}
+
+impl DownloadableMedia {
+    /// Download, verify and decrypt the file, reporting progress.
+    pub fn download_with_progress<F>(&self, progress: F) -> Transfer<Vec<u8>>
+    where
+        F: Fn(TransferProgress) + Send + Sync + 'static,
+    {
+        let media = self.clone();
+        let callback: ProgressCallback = Arc::new(progress);
+        Transfer::spawn(move |cancel| {
+            let url = media.download_url().ok_or(MediaError::MissingUrl)?;
+            let data = fetch(&url, Some(callback), cancel)?;
+            media.decrypt(&data)
+        })
+    }
+
+    /// Download the decrypted file to a path, reporting progress.
+    ///
+    /// Nothing is left at the path if the download fails or is cancelled.
+    pub fn download_to_file<F>(&self, path: impl Into<PathBuf>, progress: F) -> Transfer<PathBuf>
+    where
+        F: Fn(TransferProgress) + Send + Sync + 'static,
+    {
+        let media = self.clone();
+        let path = path.into();
+        let callback: ProgressCallback = Arc::new(progress);
+        Transfer::spawn(move |cancel| {
+            let url = media.download_url().ok_or(MediaError::MissingUrl)?;
+            let data = fetch(&url, Some(callback), cancel.clone())?;
+            let plaintext = media.decrypt(&data)?;
+            write_file(&path, &plaintext, &cancel)?;
+            Ok(path)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::net::TcpListener;
+    use std::sync::Mutex;
+    use std::time::Duration;
+    use crate::media::{encrypt_media, MediaType};
+    use crate::types::MediaDetails;
+
+    /// Serve one response slowly, in small chunks.
+    fn serve(body: Vec<u8>, chunk: usize, delay: Duration) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0u8; 1024];
+            let _ = io::Read::read(&mut stream, &mut request);
+            let header = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body.len());
+            let _ = stream.write_all(header.as_bytes());
+            for part in body.chunks(chunk) {
+                if stream.write_all(part).is_err() {
+                    return;
+                }
+                std::thread::sleep(delay);
+            }
+        });
+        format!("http://{}/file", addr)
+    }
+
+    fn media(url: String, media_key: &[u8]) -> DownloadableMedia {
+        DownloadableMedia {
+            media_type: MediaType::Video,
+            url,
+            mimetype: "video/mp4".to_string(),
+            details: MediaDetails {
+                media_key: Some(media_key.to_vec()),
+                ..Default::default()
+            },
+        }
+    }
+
+    fn temp_path() -> PathBuf {
+        std::env::temp_dir().join(format!("whatsmeow-download-{}.mp4", uuid::Uuid::new_v4()))
+    }
+
+    #[tokio::test]
+    async fn test_download_to_file_reports_progress() {
+        let media_key = [3u8; 32];
+        let data = vec![42u8; 4000];
+        let encrypted = encrypt_media(&data, &media_key, MediaType::Video);
+        let total = encrypted.len() as u64;
+        let url = serve(encrypted, 1000, Duration::ZERO);
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let recorded = seen.clone();
+        let path = temp_path();
+        let transfer = media(url, &media_key).download_to_file(&path, move |p| {
+            recorded.lock().unwrap().push(p);
+        });
+
+        assert_eq!(transfer.wait().await.unwrap(), path);
+        assert_eq!(std::fs::read(&path).unwrap(), data);
+        std::fs::remove_file(&path).unwrap();
+
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen.last(), Some(&TransferProgress { done: total, total: Some(total) }));
+        assert!(seen.windows(2).all(|w| w[0].done < w[1].done));
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_download_leaves_no_file() {
+        let media_key = [3u8; 32];
+        let encrypted = encrypt_media(&vec![0u8; 10_000], &media_key, MediaType::Video);
+        let url = serve(encrypted, 100, Duration::from_millis(5));
+
+        let path = temp_path();
+        let transfer = media(url, &media_key).download_to_file(&path, |_| {});
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        transfer.cancel();
+
+        assert!(transfer.is_cancelled());
+        assert_eq!(transfer.wait().await, Err(MediaError::Cancelled));
+        assert!(!path.exists());
+        assert!(!part_path(&path).exists());
+    }
+}
@@ -0,0 +1,207 @@
+//! Media uploads.
+//!
+//! Files are encrypted with a fresh media key and posted to one of the
+//! hosts returned by a `media_conn` query.
+
+use std::io::Cursor;
+use std::sync::Arc;
+use base64::{Engine as _, engine::general_purpose};
+use sha2::{Digest, Sha256};
+
+use crate::binary::Node;
+use crate::media::{encrypt_media, MediaError, MediaType, ProgressCallback, ProgressReader, Transfer, TransferProgress};
+use crate::protocol::{IqError, IqRequest, IqResponse, IqType};
+use crate::types::MediaDetails;
+
+/// Upload credentials and hosts.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MediaConn {
+    /// Auth token for upload URLs
+    pub auth: String,
+    /// Seconds the auth token stays valid
+    pub ttl: u64,
+    /// Upload hosts, in order of preference
+    pub hosts: Vec<String>,
+}
+
+impl IqResponse for MediaConn {
+    fn from_node(node: &Node) -> Result<Self, IqError> {
+        let conn = node.get_child_by_tag("media_conn")
+            .ok_or_else(|| IqError::MalformedResponse("missing <media_conn>".to_string()))?;
+        let auth = conn.get_attr_str("auth")
+            .ok_or_else(|| IqError::MalformedResponse("missing auth".to_string()))?
+            .to_string();
+
+        let hosts = conn.get_children()
+            .map(|children| {
+                children.iter()
+                    .filter(|n| n.tag == "host")
+                    .filter_map(|n| n.get_attr_str("hostname").map(String::from))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Ok(Self {
+            auth,
+            ttl: conn.get_attr_str("ttl").and_then(|t| t.parse().ok()).unwrap_or(0),
+            hosts,
+        })
+    }
+}
+
+/// Query for upload credentials.
+pub struct MediaConnRequest;
+
+impl IqRequest for MediaConnRequest {
+    type Response = MediaConn;
+
+    fn namespace(&self) -> &str {
+        "w:m"
+    }
+
+    fn iq_type(&self) -> IqType {
+        IqType::Set
+    }
+
+    fn content(&self) -> Vec<Node> {
+        vec![Node::new("media_conn")]
+    }
+}
+
+/// Result of an upload, used to build the media message.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UploadedMedia {
+    pub url: String,
+    pub direct_path: String,
+    pub media_key: Vec<u8>,
+    pub file_sha256: Vec<u8>,
+    pub file_enc_sha256: Vec<u8>,
+    pub file_length: u64,
+}
+
+impl UploadedMedia {
+    /// Download details to attach to the outgoing message.
+    pub fn details(&self) -> MediaDetails {
+        MediaDetails {
+            direct_path: Some(self.direct_path.clone()),
+            media_key: Some(self.media_key.clone()),
+            file_sha256: Some(self.file_sha256.clone()),
+            file_enc_sha256: Some(self.file_enc_sha256.clone()),
+            file_length: Some(self.file_length),
+            local_path: None,
+        }
+    }
+}
+
+impl MediaType {
+    /// Path segment of the upload URL.
+    pub fn mms_type(&self) -> &'static str {
+        match self {
+            MediaType::Image | MediaType::Sticker => "image",
+            MediaType::Video => "video",
+            MediaType::Audio => "audio",
+            MediaType::Document => "document",
+            MediaType::StickerPack => "sticker-pack",
+            MediaType::History => "md-msg-hist",
+        }
+    }
+}
+
+/// Upload URL for an encrypted file on a host, over HTTPS unless the
+/// host names its own scheme.
+fn upload_url(host: &str, media_type: MediaType, auth: &str, file_enc_sha256: &[u8]) -> String {
+    let token = general_purpose::URL_SAFE.encode(file_enc_sha256);
+    let scheme = if host.contains("://") { "" } else { "https://" };
+    format!("{}{}/mms/{}/{}?auth={}&token={}", scheme, host, media_type.mms_type(), token, auth, token)
+}
+
+/// Encrypt and upload a file, reporting progress.
+///
+/// Hosts are tried in order until one accepts the file.
+pub fn upload<F>(data: Vec<u8>, media_type: MediaType, conn: &MediaConn, progress: F) -> Transfer<UploadedMedia>
+where
+    F: Fn(TransferProgress) + Send + Sync + 'static,
+{
+    let conn = conn.clone();
+    let callback: ProgressCallback = Arc::new(progress);
+    Transfer::spawn(move |cancel| {
+        let media_key: [u8; 32] = rand::random();
+        let encrypted = encrypt_media(&data, &media_key, media_type);
+        let file_enc_sha256 = Sha256::digest(&encrypted).to_vec();
+
+        let mut last_error = MediaError::RequestFailed("no upload hosts".to_string());
+        for host in &conn.hosts {
+            let url = upload_url(host, media_type, &conn.auth, &file_enc_sha256);
+            let reader = ProgressReader::new(
+                Cursor::new(encrypted.as_slice()),
+                Some(encrypted.len() as u64),
+                Some(callback.clone()),
+                cancel.clone(),
+            );
+
+            let response = ureq::post(&url)
+                .set("Origin", "https://web.whatsapp.com")
+                .set("Content-Type", "application/octet-stream")
+                .send(reader);
+            if cancel.is_cancelled() {
+                return Err(MediaError::Cancelled);
+            }
+
+            let body: serde_json::Value = match response {
+                Ok(response) => response.into_json()?,
+                Err(ureq::Error::Status(code, _)) => {
+                    last_error = MediaError::HttpStatus(code);
+                    continue;
+                }
+                Err(e) => {
+                    last_error = MediaError::RequestFailed(e.to_string());
+                    continue;
+                }
+            };
+
+            let field = |name: &str| body.get(name).and_then(|v| v.as_str()).unwrap_or_default().to_string();
+            return Ok(UploadedMedia {
+                url: field("url"),
+                direct_path: field("direct_path"),
+                media_key: media_key.to_vec(),
+                file_sha256: Sha256::digest(&data).to_vec(),
+                file_enc_sha256,
+                file_length: data.len() as u64,
+            });
+        }
+
+        Err(last_error)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_media_conn() {
+        let mut conn = Node::new("media_conn");
+        conn.set_attr("auth", "secret");
+        conn.set_attr("ttl", "300");
+        for hostname in ["mmg.whatsapp.net", "media-fallback.whatsapp.net"] {
+            let mut host = Node::new("host");
+            host.set_attr("hostname", hostname);
+            conn.add_child(host);
+        }
+        let mut iq = Node::new("iq");
+        iq.add_child(conn);
+
+        let conn = MediaConn::from_node(&iq).unwrap();
+        assert_eq!(conn.auth, "secret");
+        assert_eq!(conn.ttl, 300);
+        assert_eq!(conn.hosts, vec!["mmg.whatsapp.net", "media-fallback.whatsapp.net"]);
+
+        assert!(MediaConn::from_node(&Node::new("iq")).is_err());
+    }
+
+    #[test]
+    fn test_upload_url() {
+        let url = upload_url("mmg.whatsapp.net", MediaType::Sticker, "abc", &[0xfb, 0xff]);
+        assert_eq!(url, "https://mmg.whatsapp.net/mms/image/-_8=?auth=abc&token=-_8=");
+    }
+}
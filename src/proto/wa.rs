@@ -192,6 +192,885 @@ pub struct DevicePairingData {
     pub device_props: Option<Vec<u8>>,
 }
 
+/// Properties of a companion device, shown on the phone's list of linked
+/// devices and deciding how much history the phone syncs to it.
+#[derive(Clone, PartialEq, Message)]
+pub struct DeviceProps {
+    #[prost(string, optional, tag = "1")]
+    pub os: Option<String>,
+    #[prost(message, optional, tag = "2")]
+    pub version: Option<AppVersion>,
+    #[prost(int32, optional, tag = "3")]
+    pub platform_type: Option<i32>,
+    #[prost(bool, optional, tag = "4")]
+    pub require_full_sync: Option<bool>,
+    #[prost(message, optional, tag = "5")]
+    pub history_sync_config: Option<HistorySyncConfig>,
+}
+
+/// Limits on the history the phone syncs to a new companion device.
+#[derive(Clone, PartialEq, Message)]
+pub struct HistorySyncConfig {
+    #[prost(uint32, optional, tag = "1")]
+    pub full_sync_days_limit: Option<u32>,
+    #[prost(uint32, optional, tag = "2")]
+    pub full_sync_size_mb_limit: Option<u32>,
+    #[prost(uint32, optional, tag = "3")]
+    pub storage_quota_mb: Option<u32>,
+    #[prost(bool, optional, tag = "4")]
+    pub inline_initial_payload_in_e2ee_msg: Option<bool>,
+    #[prost(uint32, optional, tag = "5")]
+    pub recent_sync_days_limit: Option<u32>,
+}
+
+/// Signed device identity sent with `pair-success`, authenticated with the
+/// ADV secret from the QR code.
+#[derive(Clone, PartialEq, Message)]
+pub struct AdvSignedDeviceIdentityHmac {
+    /// Encoded `AdvSignedDeviceIdentity`
+    #[prost(bytes, optional, tag = "1")]
+    pub details: Option<Vec<u8>>,
+    #[prost(bytes, optional, tag = "2")]
+    pub hmac: Option<Vec<u8>>,
+    #[prost(int32, optional, tag = "3")]
+    pub account_type: Option<i32>,
+}
+
+/// Identity of a companion device, signed by the account and the device.
+#[derive(Clone, PartialEq, Message)]
+pub struct AdvSignedDeviceIdentity {
+    /// Encoded `AdvDeviceIdentity`
+    #[prost(bytes, optional, tag = "1")]
+    pub details: Option<Vec<u8>>,
+    #[prost(bytes, optional, tag = "2")]
+    pub account_signature_key: Option<Vec<u8>>,
+    #[prost(bytes, optional, tag = "3")]
+    pub account_signature: Option<Vec<u8>>,
+    #[prost(bytes, optional, tag = "4")]
+    pub device_signature: Option<Vec<u8>>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct AdvDeviceIdentity {
+    #[prost(uint32, optional, tag = "1")]
+    pub raw_id: Option<u32>,
+    #[prost(uint64, optional, tag = "2")]
+    pub timestamp: Option<u64>,
+    #[prost(uint32, optional, tag = "3")]
+    pub key_index: Option<u32>,
+    #[prost(int32, optional, tag = "4")]
+    pub account_type: Option<i32>,
+    #[prost(int32, optional, tag = "5")]
+    pub device_type: Option<i32>,
+}
+
+/// Sender key sent to group members so they can decrypt our messages.
+#[derive(Clone, PartialEq, Message)]
+pub struct SenderKeyDistributionMessage {
+    #[prost(uint32, optional, tag = "1")]
+    pub id: Option<u32>,
+    #[prost(uint32, optional, tag = "2")]
+    pub iteration: Option<u32>,
+    #[prost(bytes, optional, tag = "3")]
+    pub chain_key: Option<Vec<u8>>,
+    #[prost(bytes, optional, tag = "4")]
+    pub signing_key: Option<Vec<u8>>,
+}
+
+/// Message encrypted with a Signal session, after its version byte.
+#[derive(Clone, PartialEq, Message)]
+pub struct SignalMessage {
+    #[prost(bytes, optional, tag = "1")]
+    pub ratchet_key: Option<Vec<u8>>,
+    #[prost(uint32, optional, tag = "2")]
+    pub counter: Option<u32>,
+    #[prost(uint32, optional, tag = "3")]
+    pub previous_counter: Option<u32>,
+    #[prost(bytes, optional, tag = "4")]
+    pub ciphertext: Option<Vec<u8>>,
+}
+
+/// First messages of a session, carrying what the recipient needs to
+/// build it, after its version byte.
+#[derive(Clone, PartialEq, Message)]
+pub struct PreKeySignalMessage {
+    #[prost(uint32, optional, tag = "1")]
+    pub pre_key_id: Option<u32>,
+    #[prost(bytes, optional, tag = "2")]
+    pub base_key: Option<Vec<u8>>,
+    #[prost(bytes, optional, tag = "3")]
+    pub identity_key: Option<Vec<u8>>,
+    #[prost(bytes, optional, tag = "4")]
+    pub message: Option<Vec<u8>>,
+    #[prost(uint32, optional, tag = "5")]
+    pub registration_id: Option<u32>,
+    #[prost(uint32, optional, tag = "6")]
+    pub signed_pre_key_id: Option<u32>,
+}
+
+/// Body of a media retry receipt, encrypted with the media key.
+#[derive(Clone, PartialEq, Message)]
+pub struct ServerErrorReceipt {
+    #[prost(string, optional, tag = "1")]
+    pub stanza_id: Option<String>,
+}
+
+/// Body of a media retry notification, encrypted with the media key.
+#[derive(Clone, PartialEq, Message)]
+pub struct MediaRetryNotification {
+    #[prost(string, optional, tag = "1")]
+    pub stanza_id: Option<String>,
+    #[prost(string, optional, tag = "2")]
+    pub direct_path: Option<String>,
+    #[prost(int32, optional, tag = "3")]
+    pub result: Option<i32>,
+}
+
+/// End-to-end message content. Only the fields used by the client are
+/// defined.
+#[derive(Clone, PartialEq, Message)]
+pub struct E2eMessage {
+    #[prost(string, optional, tag = "1")]
+    pub conversation: Option<String>,
+    #[prost(message, optional, tag = "6")]
+    pub extended_text_message: Option<ExtendedTextMessage>,
+    #[prost(message, optional, boxed, tag = "12")]
+    pub protocol_message: Option<Box<ProtocolMessage>>,
+    #[prost(message, optional, tag = "29")]
+    pub template_button_reply_message: Option<TemplateButtonReplyMessage>,
+    #[prost(message, optional, tag = "30")]
+    pub product_message: Option<ProductMessage>,
+    #[prost(message, optional, tag = "35")]
+    pub message_context_info: Option<MessageContextInfo>,
+    #[prost(message, optional, tag = "36")]
+    pub list_message: Option<ListMessage>,
+    #[prost(message, optional, tag = "39")]
+    pub list_response_message: Option<ListResponseMessage>,
+    #[prost(message, optional, tag = "42")]
+    pub buttons_message: Option<ButtonsMessage>,
+    #[prost(message, optional, tag = "43")]
+    pub buttons_response_message: Option<ButtonsResponseMessage>,
+    #[prost(message, optional, tag = "49")]
+    pub poll_creation_message: Option<PollCreationMessage>,
+    #[prost(message, optional, tag = "50")]
+    pub poll_update_message: Option<PollUpdateMessage>,
+    #[prost(message, optional, tag = "76")]
+    pub enc_event_response_message: Option<EncEventResponseMessage>,
+    #[prost(message, optional, tag = "85")]
+    pub keep_in_chat_message: Option<KeepInChatMessage>,
+    #[prost(message, optional, tag = "86")]
+    pub sticker_pack_message: Option<StickerPackMessage>,
+}
+
+/// Metadata attached to a message, such as the secret votes and
+/// responses to it are encrypted with.
+#[derive(Clone, PartialEq, Message)]
+pub struct MessageContextInfo {
+    #[prost(bytes = "vec", optional, tag = "3")]
+    pub message_secret: Option<Vec<u8>>,
+}
+
+/// Poll with options to vote on.
+#[derive(Clone, PartialEq, Message)]
+pub struct PollCreationMessage {
+    #[prost(bytes = "vec", optional, tag = "1")]
+    pub enc_key: Option<Vec<u8>>,
+    #[prost(string, optional, tag = "2")]
+    pub name: Option<String>,
+    #[prost(message, repeated, tag = "3")]
+    pub options: Vec<PollOption>,
+    /// How many options a voter may pick, 0 for any number
+    #[prost(uint32, optional, tag = "4")]
+    pub selectable_options_count: Option<u32>,
+    #[prost(message, optional, tag = "5")]
+    pub context_info: Option<ContextInfo>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct PollOption {
+    #[prost(string, optional, tag = "1")]
+    pub option_name: Option<String>,
+}
+
+/// Encrypted vote on a poll.
+#[derive(Clone, PartialEq, Message)]
+pub struct PollUpdateMessage {
+    #[prost(message, optional, tag = "1")]
+    pub poll_creation_message_key: Option<MessageKey>,
+    #[prost(message, optional, tag = "2")]
+    pub vote: Option<PollEncValue>,
+    #[prost(int64, optional, tag = "4")]
+    pub sender_timestamp_ms: Option<i64>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct PollEncValue {
+    #[prost(bytes = "vec", optional, tag = "1")]
+    pub enc_payload: Option<Vec<u8>>,
+    #[prost(bytes = "vec", optional, tag = "2")]
+    pub enc_iv: Option<Vec<u8>>,
+}
+
+/// Decrypted vote: SHA-256 hashes of the picked option names.
+#[derive(Clone, PartialEq, Message)]
+pub struct PollVoteMessage {
+    #[prost(bytes = "vec", repeated, tag = "1")]
+    pub selected_options: Vec<Vec<u8>>,
+}
+
+/// Encrypted response to an event.
+#[derive(Clone, PartialEq, Message)]
+pub struct EncEventResponseMessage {
+    #[prost(message, optional, tag = "1")]
+    pub event_creation_message_key: Option<MessageKey>,
+    #[prost(bytes = "vec", optional, tag = "2")]
+    pub enc_payload: Option<Vec<u8>>,
+    #[prost(bytes = "vec", optional, tag = "3")]
+    pub enc_iv: Option<Vec<u8>>,
+}
+
+/// Decrypted response to an event.
+#[derive(Clone, PartialEq, Message)]
+pub struct EventResponseMessage {
+    /// One of the `event_response_type` constants
+    #[prost(int32, optional, tag = "1")]
+    pub response: Option<i32>,
+    #[prost(int64, optional, tag = "2")]
+    pub timestamp_ms: Option<i64>,
+    #[prost(int32, optional, tag = "3")]
+    pub extra_guest_count: Option<i32>,
+}
+
+/// Keeps a message in a disappearing chat, or undoes that.
+#[derive(Clone, PartialEq, Message)]
+pub struct KeepInChatMessage {
+    #[prost(message, optional, tag = "1")]
+    pub key: Option<MessageKey>,
+    /// One of the `keep_type` constants
+    #[prost(int32, optional, tag = "2")]
+    pub keep_type: Option<i32>,
+    #[prost(int64, optional, tag = "3")]
+    pub timestamp_ms: Option<i64>,
+}
+
+/// Pack of stickers, sent as one encrypted zip file.
+#[derive(Clone, PartialEq, Message)]
+pub struct StickerPackMessage {
+    #[prost(string, optional, tag = "1")]
+    pub sticker_pack_id: Option<String>,
+    #[prost(string, optional, tag = "2")]
+    pub name: Option<String>,
+    #[prost(string, optional, tag = "3")]
+    pub publisher: Option<String>,
+    #[prost(message, repeated, tag = "4")]
+    pub stickers: Vec<StickerPackSticker>,
+    #[prost(uint64, optional, tag = "5")]
+    pub file_length: Option<u64>,
+    #[prost(bytes = "vec", optional, tag = "6")]
+    pub file_sha256: Option<Vec<u8>>,
+    #[prost(bytes = "vec", optional, tag = "7")]
+    pub file_enc_sha256: Option<Vec<u8>>,
+    #[prost(bytes = "vec", optional, tag = "8")]
+    pub media_key: Option<Vec<u8>>,
+    #[prost(string, optional, tag = "9")]
+    pub direct_path: Option<String>,
+    #[prost(string, optional, tag = "10")]
+    pub caption: Option<String>,
+    #[prost(string, optional, tag = "12")]
+    pub pack_description: Option<String>,
+}
+
+/// A sticker of a pack, stored in the pack's zip under `file_name`.
+#[derive(Clone, PartialEq, Message)]
+pub struct StickerPackSticker {
+    #[prost(string, optional, tag = "1")]
+    pub file_name: Option<String>,
+    #[prost(bool, optional, tag = "2")]
+    pub is_animated: Option<bool>,
+    #[prost(string, repeated, tag = "3")]
+    pub emojis: Vec<String>,
+    #[prost(string, optional, tag = "4")]
+    pub accessibility_label: Option<String>,
+    #[prost(string, optional, tag = "6")]
+    pub mimetype: Option<String>,
+}
+
+/// Text message with context, such as a reply or a disappearing timer.
+#[derive(Clone, PartialEq, Message)]
+pub struct ExtendedTextMessage {
+    #[prost(string, optional, tag = "1")]
+    pub text: Option<String>,
+    #[prost(message, optional, tag = "17")]
+    pub context_info: Option<ContextInfo>,
+}
+
+/// Reference to the message being replied to, and the users mentioned.
+#[derive(Clone, PartialEq, Message)]
+pub struct ContextInfo {
+    #[prost(string, optional, tag = "1")]
+    pub stanza_id: Option<String>,
+    #[prost(string, optional, tag = "2")]
+    pub participant: Option<String>,
+    #[prost(string, repeated, tag = "15")]
+    pub mentioned_jid: Vec<String>,
+    /// Disappearing message timer of the chat in seconds
+    #[prost(uint32, optional, tag = "25")]
+    pub expiration: Option<u32>,
+}
+
+/// Message with a menu of rows in sections.
+#[derive(Clone, PartialEq, Message)]
+pub struct ListMessage {
+    #[prost(string, optional, tag = "1")]
+    pub title: Option<String>,
+    #[prost(string, optional, tag = "2")]
+    pub description: Option<String>,
+    #[prost(string, optional, tag = "3")]
+    pub button_text: Option<String>,
+    #[prost(int32, optional, tag = "4")]
+    pub list_type: Option<i32>,
+    #[prost(message, repeated, tag = "5")]
+    pub sections: Vec<ListSection>,
+    #[prost(string, optional, tag = "7")]
+    pub footer_text: Option<String>,
+    #[prost(message, optional, tag = "8")]
+    pub context_info: Option<ContextInfo>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ListSection {
+    #[prost(string, optional, tag = "1")]
+    pub title: Option<String>,
+    #[prost(message, repeated, tag = "2")]
+    pub rows: Vec<ListRow>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ListRow {
+    #[prost(string, optional, tag = "1")]
+    pub title: Option<String>,
+    #[prost(string, optional, tag = "2")]
+    pub description: Option<String>,
+    #[prost(string, optional, tag = "3")]
+    pub row_id: Option<String>,
+}
+
+/// Row picked from a list message.
+#[derive(Clone, PartialEq, Message)]
+pub struct ListResponseMessage {
+    #[prost(string, optional, tag = "1")]
+    pub title: Option<String>,
+    #[prost(int32, optional, tag = "2")]
+    pub list_type: Option<i32>,
+    #[prost(message, optional, tag = "3")]
+    pub single_select_reply: Option<SingleSelectReply>,
+    #[prost(message, optional, tag = "4")]
+    pub context_info: Option<ContextInfo>,
+    #[prost(string, optional, tag = "5")]
+    pub description: Option<String>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct SingleSelectReply {
+    #[prost(string, optional, tag = "1")]
+    pub selected_row_id: Option<String>,
+}
+
+/// Message with quick-reply buttons.
+#[derive(Clone, PartialEq, Message)]
+pub struct ButtonsMessage {
+    /// Text header
+    #[prost(string, optional, tag = "1")]
+    pub text: Option<String>,
+    #[prost(string, optional, tag = "6")]
+    pub content_text: Option<String>,
+    #[prost(string, optional, tag = "7")]
+    pub footer_text: Option<String>,
+    #[prost(message, optional, tag = "8")]
+    pub context_info: Option<ContextInfo>,
+    #[prost(message, repeated, tag = "9")]
+    pub buttons: Vec<ButtonsMessageButton>,
+    #[prost(int32, optional, tag = "10")]
+    pub header_type: Option<i32>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ButtonsMessageButton {
+    #[prost(string, optional, tag = "1")]
+    pub button_id: Option<String>,
+    #[prost(message, optional, tag = "2")]
+    pub button_text: Option<ButtonText>,
+    #[prost(int32, optional, tag = "3")]
+    pub r#type: Option<i32>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ButtonText {
+    #[prost(string, optional, tag = "1")]
+    pub display_text: Option<String>,
+}
+
+/// Button pressed on a buttons message.
+#[derive(Clone, PartialEq, Message)]
+pub struct ButtonsResponseMessage {
+    #[prost(string, optional, tag = "1")]
+    pub selected_button_id: Option<String>,
+    #[prost(string, optional, tag = "2")]
+    pub selected_display_text: Option<String>,
+    #[prost(message, optional, tag = "3")]
+    pub context_info: Option<ContextInfo>,
+    #[prost(int32, optional, tag = "4")]
+    pub r#type: Option<i32>,
+}
+
+/// Button pressed on a template message.
+#[derive(Clone, PartialEq, Message)]
+pub struct TemplateButtonReplyMessage {
+    #[prost(string, optional, tag = "1")]
+    pub selected_id: Option<String>,
+    #[prost(string, optional, tag = "2")]
+    pub selected_display_text: Option<String>,
+    #[prost(message, optional, tag = "3")]
+    pub context_info: Option<ContextInfo>,
+    #[prost(uint32, optional, tag = "4")]
+    pub selected_index: Option<u32>,
+}
+
+/// Product shared from a business catalog.
+#[derive(Clone, PartialEq, Message)]
+pub struct ProductMessage {
+    #[prost(message, optional, tag = "1")]
+    pub product: Option<ProductSnapshot>,
+    #[prost(string, optional, tag = "2")]
+    pub business_owner_jid: Option<String>,
+    #[prost(message, optional, tag = "4")]
+    pub catalog: Option<CatalogSnapshot>,
+    #[prost(string, optional, tag = "5")]
+    pub body: Option<String>,
+    #[prost(string, optional, tag = "6")]
+    pub footer: Option<String>,
+    #[prost(message, optional, tag = "17")]
+    pub context_info: Option<ContextInfo>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ProductSnapshot {
+    #[prost(string, optional, tag = "2")]
+    pub product_id: Option<String>,
+    #[prost(string, optional, tag = "3")]
+    pub title: Option<String>,
+    #[prost(string, optional, tag = "4")]
+    pub description: Option<String>,
+    #[prost(string, optional, tag = "5")]
+    pub currency_code: Option<String>,
+    #[prost(int64, optional, tag = "6")]
+    pub price_amount_1000: Option<i64>,
+    #[prost(string, optional, tag = "7")]
+    pub retailer_id: Option<String>,
+    #[prost(string, optional, tag = "8")]
+    pub url: Option<String>,
+    #[prost(uint32, optional, tag = "9")]
+    pub product_image_count: Option<u32>,
+    #[prost(int64, optional, tag = "12")]
+    pub sale_price_amount_1000: Option<i64>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct CatalogSnapshot {
+    #[prost(string, optional, tag = "2")]
+    pub title: Option<String>,
+    #[prost(string, optional, tag = "3")]
+    pub description: Option<String>,
+}
+
+/// Key identifying a message.
+#[derive(Clone, PartialEq, Message)]
+pub struct MessageKey {
+    #[prost(string, optional, tag = "1")]
+    pub remote_jid: Option<String>,
+    #[prost(bool, optional, tag = "2")]
+    pub from_me: Option<bool>,
+    #[prost(string, optional, tag = "3")]
+    pub id: Option<String>,
+    #[prost(string, optional, tag = "4")]
+    pub participant: Option<String>,
+}
+
+/// Control message exchanged between devices of the same account.
+#[derive(Clone, PartialEq, Message)]
+pub struct ProtocolMessage {
+    #[prost(message, optional, tag = "1")]
+    pub key: Option<MessageKey>,
+    #[prost(int32, optional, tag = "2")]
+    pub r#type: Option<i32>,
+    #[prost(message, optional, tag = "6")]
+    pub history_sync_notification: Option<HistorySyncNotification>,
+    #[prost(message, optional, tag = "7")]
+    pub app_state_sync_key_share: Option<AppStateSyncKeyShare>,
+    #[prost(message, optional, tag = "8")]
+    pub app_state_sync_key_request: Option<AppStateSyncKeyRequest>,
+    /// Disappearing message timer in seconds, 0 for off
+    #[prost(uint32, optional, tag = "4")]
+    pub ephemeral_expiration: Option<u32>,
+    #[prost(int64, optional, tag = "5")]
+    pub ephemeral_setting_timestamp: Option<i64>,
+    #[prost(message, optional, tag = "16")]
+    pub peer_data_operation_request_message: Option<PeerDataOperationRequestMessage>,
+}
+
+/// Where to fetch a history sync blob from.
+#[derive(Clone, PartialEq, Message)]
+pub struct HistorySyncNotification {
+    #[prost(bytes, optional, tag = "1")]
+    pub file_sha256: Option<Vec<u8>>,
+    #[prost(uint64, optional, tag = "2")]
+    pub file_length: Option<u64>,
+    #[prost(bytes, optional, tag = "3")]
+    pub media_key: Option<Vec<u8>>,
+    #[prost(bytes, optional, tag = "4")]
+    pub file_enc_sha256: Option<Vec<u8>>,
+    #[prost(string, optional, tag = "5")]
+    pub direct_path: Option<String>,
+    #[prost(int32, optional, tag = "6")]
+    pub sync_type: Option<i32>,
+    #[prost(uint32, optional, tag = "7")]
+    pub chunk_order: Option<u32>,
+    #[prost(string, optional, tag = "8")]
+    pub original_message_id: Option<String>,
+    #[prost(uint32, optional, tag = "9")]
+    pub progress: Option<u32>,
+    #[prost(int64, optional, tag = "10")]
+    pub oldest_msg_in_chunk_timestamp_sec: Option<i64>,
+    #[prost(bytes, optional, tag = "11")]
+    pub initial_hist_bootstrap_inline_payload: Option<Vec<u8>>,
+    #[prost(string, optional, tag = "12")]
+    pub peer_data_request_session_id: Option<String>,
+}
+
+/// Decompressed history sync payload.
+///
+/// Only the fields used by this client are declared.
+#[derive(Clone, PartialEq, Message)]
+pub struct HistorySyncPayload {
+    #[prost(int32, optional, tag = "1")]
+    pub sync_type: Option<i32>,
+    #[prost(message, repeated, tag = "2")]
+    pub conversations: Vec<HistoryConversation>,
+    #[prost(uint32, optional, tag = "5")]
+    pub chunk_order: Option<u32>,
+    #[prost(uint32, optional, tag = "6")]
+    pub progress: Option<u32>,
+    #[prost(message, repeated, tag = "7")]
+    pub pushnames: Vec<Pushname>,
+}
+
+/// Chat in a history sync payload, with its synced messages.
+#[derive(Clone, PartialEq, Message)]
+pub struct HistoryConversation {
+    #[prost(string, optional, tag = "1")]
+    pub id: Option<String>,
+    #[prost(message, repeated, tag = "2")]
+    pub messages: Vec<HistorySyncMsg>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct HistorySyncMsg {
+    #[prost(message, optional, tag = "1")]
+    pub message: Option<WebMessageInfo>,
+    #[prost(uint64, optional, tag = "2")]
+    pub msg_order_id: Option<u64>,
+}
+
+/// Stored form of a message, as history sync sends it.
+#[derive(Clone, PartialEq, Message)]
+pub struct WebMessageInfo {
+    #[prost(message, optional, tag = "1")]
+    pub key: Option<MessageKey>,
+    #[prost(message, optional, tag = "2")]
+    pub message: Option<E2eMessage>,
+    #[prost(uint64, optional, tag = "3")]
+    pub message_timestamp: Option<u64>,
+    #[prost(string, optional, tag = "5")]
+    pub participant: Option<String>,
+    #[prost(string, optional, tag = "19")]
+    pub push_name: Option<String>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Pushname {
+    #[prost(string, optional, tag = "1")]
+    pub id: Option<String>,
+    #[prost(string, optional, tag = "2")]
+    pub pushname: Option<String>,
+}
+
+/// Contact entry from an app state mutation.
+#[derive(Clone, PartialEq, Message)]
+pub struct ContactAction {
+    #[prost(string, optional, tag = "1")]
+    pub full_name: Option<String>,
+    #[prost(string, optional, tag = "2")]
+    pub first_name: Option<String>,
+}
+
+/// Label created, changed or deleted, from an app state mutation.
+#[derive(Clone, PartialEq, Message)]
+pub struct LabelEditAction {
+    #[prost(string, optional, tag = "1")]
+    pub name: Option<String>,
+    #[prost(int32, optional, tag = "2")]
+    pub color: Option<i32>,
+    #[prost(int32, optional, tag = "3")]
+    pub predefined_id: Option<i32>,
+    #[prost(bool, optional, tag = "4")]
+    pub deleted: Option<bool>,
+}
+
+/// Label put on or taken off a chat or message, from an app state mutation.
+#[derive(Clone, PartialEq, Message)]
+pub struct LabelAssociationAction {
+    #[prost(bool, optional, tag = "1")]
+    pub labeled: Option<bool>,
+}
+
+/// Quick reply created, changed or deleted, from an app state mutation.
+#[derive(Clone, PartialEq, Message)]
+pub struct QuickReplyAction {
+    #[prost(string, optional, tag = "1")]
+    pub shortcut: Option<String>,
+    #[prost(string, optional, tag = "2")]
+    pub message: Option<String>,
+    #[prost(string, repeated, tag = "3")]
+    pub keywords: Vec<String>,
+    #[prost(int32, optional, tag = "4")]
+    pub count: Option<i32>,
+    #[prost(bool, optional, tag = "5")]
+    pub deleted: Option<bool>,
+}
+
+/// Greeting message configuration, from an app state mutation.
+#[derive(Clone, PartialEq, Message)]
+pub struct GreetingMessageAction {
+    #[prost(bool, optional, tag = "1")]
+    pub enabled: Option<bool>,
+    #[prost(string, optional, tag = "2")]
+    pub message: Option<String>,
+    #[prost(int32, optional, tag = "3")]
+    pub inactivity_days: Option<i32>,
+}
+
+/// Away message configuration, from an app state mutation.
+#[derive(Clone, PartialEq, Message)]
+pub struct AwayMessageAction {
+    #[prost(bool, optional, tag = "1")]
+    pub enabled: Option<bool>,
+    #[prost(string, optional, tag = "2")]
+    pub message: Option<String>,
+    /// One of the `away_schedule` constants
+    #[prost(int32, optional, tag = "3")]
+    pub schedule: Option<i32>,
+    #[prost(int64, optional, tag = "4")]
+    pub start_timestamp: Option<i64>,
+    #[prost(int64, optional, tag = "5")]
+    pub end_timestamp: Option<i64>,
+}
+
+/// Decrypted content of an app state mutation.
+#[derive(Clone, PartialEq, Message)]
+pub struct SyncActionData {
+    /// JSON array naming what the mutation applies to
+    #[prost(bytes = "vec", optional, tag = "1")]
+    pub index: Option<Vec<u8>>,
+    #[prost(message, optional, tag = "2")]
+    pub value: Option<SyncActionValue>,
+    #[prost(bytes = "vec", optional, tag = "3")]
+    pub padding: Option<Vec<u8>>,
+    #[prost(int32, optional, tag = "4")]
+    pub version: Option<i32>,
+}
+
+/// Value of an app state mutation; one action is set.
+#[derive(Clone, PartialEq, Message)]
+pub struct SyncActionValue {
+    #[prost(int64, optional, tag = "1")]
+    pub timestamp: Option<i64>,
+    #[prost(message, optional, tag = "3")]
+    pub contact_action: Option<ContactAction>,
+    #[prost(message, optional, tag = "8")]
+    pub quick_reply_action: Option<QuickReplyAction>,
+    #[prost(message, optional, tag = "14")]
+    pub label_edit_action: Option<LabelEditAction>,
+    #[prost(message, optional, tag = "15")]
+    pub label_association_action: Option<LabelAssociationAction>,
+    #[prost(message, optional, tag = "20")]
+    pub mark_chat_as_read_action: Option<MarkChatAsReadAction>,
+    #[prost(message, optional, tag = "80")]
+    pub greeting_message_action: Option<GreetingMessageAction>,
+    #[prost(message, optional, tag = "81")]
+    pub away_message_action: Option<AwayMessageAction>,
+}
+
+/// Chat read or marked unread, from an app state mutation.
+#[derive(Clone, PartialEq, Message)]
+pub struct MarkChatAsReadAction {
+    #[prost(bool, optional, tag = "1")]
+    pub read: Option<bool>,
+}
+
+/// App state sync keys shared by the primary device.
+#[derive(Clone, PartialEq, Message)]
+pub struct AppStateSyncKeyShare {
+    #[prost(message, repeated, tag = "1")]
+    pub keys: Vec<AppStateSyncKey>,
+}
+
+/// Request for app state sync keys we lack.
+#[derive(Clone, PartialEq, Message)]
+pub struct AppStateSyncKeyRequest {
+    #[prost(message, repeated, tag = "1")]
+    pub key_ids: Vec<AppStateSyncKeyId>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct AppStateSyncKey {
+    #[prost(message, optional, tag = "1")]
+    pub key_id: Option<AppStateSyncKeyId>,
+    #[prost(message, optional, tag = "2")]
+    pub key_data: Option<AppStateSyncKeyData>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct AppStateSyncKeyId {
+    #[prost(bytes, optional, tag = "1")]
+    pub key_id: Option<Vec<u8>>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct AppStateSyncKeyData {
+    #[prost(bytes, optional, tag = "1")]
+    pub key_data: Option<Vec<u8>>,
+    #[prost(message, optional, tag = "2")]
+    pub fingerprint: Option<AppStateSyncKeyFingerprint>,
+    #[prost(int64, optional, tag = "3")]
+    pub timestamp: Option<i64>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct AppStateSyncKeyFingerprint {
+    #[prost(uint32, optional, tag = "1")]
+    pub raw_id: Option<u32>,
+    #[prost(uint32, optional, tag = "2")]
+    pub current_index: Option<u32>,
+    #[prost(uint32, repeated, packed = "true", tag = "3")]
+    pub device_indexes: Vec<u32>,
+}
+
+/// Request sent to the primary device.
+#[derive(Clone, PartialEq, Message)]
+pub struct PeerDataOperationRequestMessage {
+    #[prost(int32, optional, tag = "1")]
+    pub peer_data_operation_request_type: Option<i32>,
+    #[prost(message, optional, tag = "4")]
+    pub history_sync_on_demand_request: Option<HistorySyncOnDemandRequest>,
+}
+
+/// Request for older messages of one chat.
+#[derive(Clone, PartialEq, Message)]
+pub struct HistorySyncOnDemandRequest {
+    #[prost(string, optional, tag = "1")]
+    pub chat_jid: Option<String>,
+    #[prost(string, optional, tag = "2")]
+    pub oldest_msg_id: Option<String>,
+    #[prost(bool, optional, tag = "3")]
+    pub oldest_msg_from_me: Option<bool>,
+    #[prost(int32, optional, tag = "4")]
+    pub on_demand_msg_count: Option<i32>,
+    #[prost(int64, optional, tag = "5")]
+    pub oldest_msg_timestamp_ms: Option<i64>,
+}
+
+// Protocol message type constants
+pub mod protocol_message_type {
+    pub const REVOKE: i32 = 0;
+    pub const EPHEMERAL_SETTING: i32 = 3;
+    pub const HISTORY_SYNC_NOTIFICATION: i32 = 5;
+    pub const APP_STATE_SYNC_KEY_SHARE: i32 = 6;
+    pub const APP_STATE_SYNC_KEY_REQUEST: i32 = 7;
+    pub const PEER_DATA_OPERATION_REQUEST_MESSAGE: i32 = 16;
+    pub const PEER_DATA_OPERATION_REQUEST_RESPONSE_MESSAGE: i32 = 17;
+}
+
+// History sync type constants
+pub mod history_sync_type {
+    pub const INITIAL_BOOTSTRAP: i32 = 0;
+    pub const INITIAL_STATUS_V3: i32 = 1;
+    pub const FULL: i32 = 2;
+    pub const RECENT: i32 = 3;
+    pub const PUSH_NAME: i32 = 4;
+    pub const NON_BLOCKING_DATA: i32 = 5;
+    pub const ON_DEMAND: i32 = 6;
+}
+
+// Peer data operation request type constants
+pub mod peer_data_operation_request_type {
+    pub const UPLOAD_STICKER: i32 = 0;
+    pub const SEND_RECENT_STICKER_BOOTSTRAP: i32 = 1;
+    pub const GENERATE_LINK_PREVIEW: i32 = 2;
+    pub const HISTORY_SYNC_ON_DEMAND: i32 = 3;
+    pub const PLACEHOLDER_MESSAGE_RESEND: i32 = 4;
+}
+
+// List message type constants
+pub mod list_type {
+    pub const SINGLE_SELECT: i32 = 1;
+}
+
+// Buttons message constants
+pub mod buttons_header_type {
+    pub const EMPTY: i32 = 1;
+    pub const TEXT: i32 = 2;
+}
+
+pub mod button_type {
+    pub const RESPONSE: i32 = 1;
+}
+
+// Event response constants
+pub mod event_response_type {
+    pub const UNKNOWN: i32 = 0;
+    pub const GOING: i32 = 1;
+    pub const NOT_GOING: i32 = 2;
+    pub const MAYBE: i32 = 3;
+}
+
+// Keep in chat type constants
+pub mod keep_type {
+    pub const UNKNOWN: i32 = 0;
+    pub const KEEP_FOR_ALL: i32 = 1;
+    pub const UNDO_KEEP_FOR_ALL: i32 = 2;
+}
+
+// Away message schedule constants
+pub mod away_schedule {
+    pub const ALWAYS: i32 = 0;
+    pub const OUTSIDE_BUSINESS_HOURS: i32 = 1;
+    pub const CUSTOM: i32 = 2;
+}
+
+// Media retry result constants
+pub mod media_retry_result {
+    pub const GENERAL_ERROR: i32 = 0;
+    pub const SUCCESS: i32 = 1;
+    pub const NOT_FOUND: i32 = 2;
+    pub const DECRYPTION_ERROR: i32 = 3;
+}
+
+// ADV account type constants
+pub mod adv_encryption_type {
+    pub const E2EE: i32 = 0;
+    pub const HOSTED: i32 = 1;
+}
+
 // Platform constants
 pub mod platform {
     pub const ANDROID: i32 = 0;
@@ -200,6 +1079,16 @@ pub mod platform {
     pub const MACOS: i32 = 24;
 }
 
+// Device props platform type constants
+pub mod device_platform {
+    pub const UNKNOWN: i32 = 0;
+    pub const CHROME: i32 = 1;
+    pub const FIREFOX: i32 = 2;
+    pub const SAFARI: i32 = 5;
+    pub const EDGE: i32 = 6;
+    pub const DESKTOP: i32 = 7;
+}
+
 // Connect type constants
 pub mod connect_type {
     pub const CELLULAR_UNKNOWN: i32 = 0;
@@ -283,6 +1172,35 @@ pub fn make_web_client_payload(push_name: Option<&str>) -> ClientPayload {
     }
 }
 
+/// Name the phone lists this library's linked devices under by default.
+pub const DEFAULT_DEVICE_OS: &str = "whatsmeow-rust";
+
+/// Create the properties of a companion device.
+pub fn make_device_props(os: &str, platform_type: i32, require_full_sync: bool) -> DeviceProps {
+    DeviceProps {
+        os: Some(os.to_string()),
+        version: Some(AppVersion {
+            primary: Some(0),
+            secondary: Some(1),
+            tertiary: Some(0),
+            quaternary: None,
+            quinary: None,
+        }),
+        platform_type: Some(platform_type),
+        require_full_sync: Some(require_full_sync),
+        history_sync_config: None,
+    }
+}
+
+/// Create a client payload logging in as a registered device.
+pub fn make_login_payload(push_name: Option<&str>, username: u64, device: u32) -> ClientPayload {
+    ClientPayload {
+        username: Some(username),
+        device: Some(device),
+        ..make_web_client_payload(push_name)
+    }
+}
+
 /// Create device pairing data for registration.
 pub fn make_device_pairing_data(
     reg_id: u32,
@@ -290,6 +1208,7 @@ pub fn make_device_pairing_data(
     signed_prekey_id: u32,
     signed_prekey: &[u8; 32],
     signed_prekey_sig: &[u8; 64],
+    device_props: &DeviceProps,
 ) -> DevicePairingData {
     // Encode registration ID as big-endian 4 bytes
     let e_reg_id = reg_id.to_be_bytes().to_vec();
@@ -322,6 +1241,6 @@ pub fn make_device_pairing_data(
         e_s_key_val: Some(e_s_key_val),
         e_s_key_sig: Some(signed_prekey_sig.to_vec()),
         build_hash: None,
-        device_props: None,
+        device_props: Some(device_props.encode_to_vec()),
     }
 }
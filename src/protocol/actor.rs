@@ -0,0 +1,320 @@
+//! Connection actor.
+//!
+//! Owns the socket of a single connection, writing nodes queued by client
+//! handles and dispatching received nodes as events.
+
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+use crate::binary::{decode, Encoder, Node};
+use crate::socket::{NoiseSocket, SocketError};
+use crate::transport::Transport;
+use crate::protocol::qr::handle_pairing_query;
+use crate::protocol::replay::FrameDirection;
+use crate::protocol::retry::{build_retry_receipt, fresh_pre_key};
+use crate::protocol::watchdog::{build_ping, Watchdog, WatchdogAction};
+use crate::media::DownloadableMedia;
+use crate::types::{
+    Event, Message, MessageInfo, MessageContent, MediaDetails, MediaDownloaded, Disconnected, DisconnectReason, StaleConnectionDetected,
+};
+use crate::protocol::client::{ClientError, ClientInner};
+use crate::protocol::handle::Command;
+
+/// Sleep until `deadline`, or forever without one.
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Download details of the media in a message, if it has any.
+fn media_details_mut(content: &mut MessageContent) -> Option<&mut MediaDetails> {
+    match content {
+        MessageContent::Image { media, .. }
+        | MessageContent::Video { media, .. }
+        | MessageContent::Audio { media, .. }
+        | MessageContent::Document { media, .. }
+        | MessageContent::Sticker { media, .. }
+        | MessageContent::StickerPack { media, .. } => Some(media),
+        MessageContent::ViewOnce(content) => media_details_mut(content),
+        _ => None,
+    }
+}
+
+/// Task owning the socket for one connection.
+pub(crate) struct ConnectionActor<T> {
+    inner: Arc<ClientInner>,
+    socket: NoiseSocket<T>,
+    /// Reused for every node sent on this connection
+    encoder: Encoder,
+    /// Closes the connection once it goes silent, if enabled
+    watchdog: Option<Watchdog>,
+    commands: mpsc::Receiver<Command>,
+    events: mpsc::UnboundedSender<Event>,
+    cancel: CancellationToken,
+}
+
+impl<T: Transport> ConnectionActor<T> {
+    pub(crate) fn new(
+        inner: Arc<ClientInner>,
+        socket: NoiseSocket<T>,
+        commands: mpsc::Receiver<Command>,
+        events: mpsc::UnboundedSender<Event>,
+        cancel: CancellationToken,
+    ) -> Self {
+        let watchdog = inner.config.stale_timeout.map(|timeout| Watchdog::new(timeout, Instant::now()));
+        Self { inner, socket, encoder: Encoder::new(), watchdog, commands, events, cancel }
+    }
+
+    /// Deliver an event to the middleware, the handlers and
+    /// `Client::receive`, returning the events that made it through the
+    /// middleware.
+    pub(crate) fn emit(&self, event: Event) -> Vec<Event> {
+        let emitted = self.inner.emit_event(event);
+        for event in &emitted {
+            // The receiver is gone once the client stops listening
+            let _ = self.events.send(event.clone());
+        }
+        emitted
+    }
+
+    /// Run until the connection is cancelled or the socket fails, then
+    /// report why it ended.
+    pub(crate) async fn run(mut self) {
+        let reason = loop {
+            tokio::select! {
+                _ = self.cancel.cancelled() => break DisconnectReason::UserRequested,
+                command = self.commands.recv() => match command {
+                    Some(command) => self.handle_command(command).await,
+                    None => break DisconnectReason::UserRequested,
+                },
+                data = self.socket.recv() => match data {
+                    Ok(data) => {
+                        if let Some(watchdog) = &mut self.watchdog {
+                            watchdog.received(Instant::now());
+                        }
+                        self.handle_frame(&data).await;
+                        // A stream error ends the connection
+                        if self.inner.close_cause.is_recorded() {
+                            break DisconnectReason::Unknown;
+                        }
+                    }
+                    Err(SocketError::NonceExhausted) => {
+                        self.end_exhausted();
+                        break DisconnectReason::NonceExhausted;
+                    }
+                    Err(e) => break DisconnectReason::NetworkError(e.to_string()),
+                },
+                _ = sleep_until(self.watchdog.as_ref().map(Watchdog::deadline)) => {
+                    if self.check_health().await {
+                        break DisconnectReason::Stale;
+                    }
+                }
+            }
+        };
+
+        // Flush whatever was queued before closing
+        self.commands.close();
+        while let Ok(command) = self.commands.try_recv() {
+            self.handle_command(command).await;
+        }
+        if let Err(e) = self.socket.close().await {
+            log::warn!("failed to close socket: {}", e);
+        }
+
+        // No responses can arrive for this connection anymore
+        self.inner.requests.cancel_all();
+        self.inner.media_retries.cancel_all();
+
+        // A cause recorded first, such as a stream error or our own
+        // disconnect, explains whatever the loop saw
+        let reason = self.inner.close_cause.take().unwrap_or(reason);
+        self.emit(Event::Disconnected(Disconnected { reason }));
+    }
+
+    /// Ping a connection that has gone quiet, returning true once it has
+    /// been silent for too long.
+    async fn check_health(&mut self) -> bool {
+        let Some(watchdog) = &mut self.watchdog else {
+            return false;
+        };
+        match watchdog.check(Instant::now()) {
+            WatchdogAction::Wait => false,
+            WatchdogAction::Ping => {
+                let ping = build_ping(&self.inner.tags.next());
+                if let Err(e) = self.send(&ping).await {
+                    log::warn!("failed to send ping: {}", e);
+                }
+                false
+            }
+            WatchdogAction::Stale(silent_for) => {
+                log::warn!("nothing received for {:?}, closing stale connection", silent_for);
+                self.emit(Event::StaleConnectionDetected(StaleConnectionDetected { silent_for }));
+                true
+            }
+        }
+    }
+
+    async fn handle_command(&mut self, command: Command) {
+        match command {
+            Command::Send { node, reply } => {
+                let result = self.send(&node)
+                    .await
+                    .map_err(|e| ClientError::SendFailed(e.to_string()));
+                let _ = reply.send(result);
+            }
+            Command::Emit(event) => {
+                self.emit(*event);
+            }
+        }
+    }
+
+    /// Encode and write a node, ending the connection once the send keys
+    /// are used up.
+    async fn send(&mut self, node: &Node) -> Result<(), SocketError> {
+        if self.inner.is_held_back(node) {
+            self.emit(Event::WouldHaveSent(node.clone()));
+            return Ok(());
+        }
+        let data = self.encoder.encode_node(node);
+        if let Some(recorder) = self.inner.frame_recorder.read().unwrap().as_ref() {
+            recorder.record(FrameDirection::Sent, data);
+        }
+        let result = self.socket.send(data).await;
+        if let Err(SocketError::NonceExhausted) = result {
+            self.end_exhausted();
+        }
+        result
+    }
+
+    /// Close a connection whose keys are used up, so nothing is encrypted
+    /// under a reused nonce. Keys are only renewed by a new handshake, so
+    /// the application has to connect again.
+    fn end_exhausted(&mut self) {
+        if self.cancel.is_cancelled() {
+            return;
+        }
+        log::warn!("nonce counter exhausted, closing connection");
+        self.inner.close_cause.record(DisconnectReason::NonceExhausted);
+        self.cancel.cancel();
+    }
+
+    async fn handle_frame(&mut self, data: &[u8]) {
+        if let Some(recorder) = self.inner.frame_recorder.read().unwrap().as_ref() {
+            recorder.record(FrameDirection::Received, data);
+        }
+        let node = match decode(data) {
+            Ok(node) => node,
+            Err(e) => {
+                log::warn!("failed to decode frame: {}", e);
+                return;
+            }
+        };
+
+        if let Some((ack, events)) = handle_pairing_query(&self.inner, &node).await {
+            if let Err(e) = self.send(&ack).await {
+                log::warn!("failed to acknowledge <{}> query: {}", node.tag, e);
+            }
+            for event in events {
+                self.emit(event);
+            }
+            return;
+        }
+
+        let mut emitted = Vec::new();
+        match self.inner.process_node(&node) {
+            Ok(events) => {
+                for event in events {
+                    emitted.extend(self.emit(event));
+                }
+            }
+            Err(e) => log::warn!("failed to process <{}> node: {}", node.tag, e),
+        }
+
+        for event in &emitted {
+            match event {
+                Event::Message(msg) => {
+                    if let Some(change) = self.inner.unread.message_received(msg) {
+                        self.emit(Event::UnreadCountChanged(change));
+                    }
+                    self.auto_download(msg);
+                }
+                Event::UndecryptableMessage(undecryptable) if self.inner.config.send_retry_receipts => {
+                    self.send_retry_receipt(&undecryptable.info).await
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Save the media of a message in the background if the auto-download
+    /// policy allows it, then deliver the message again with the saved
+    /// file's path as `Event::MediaDownloaded`.
+    fn auto_download(&self, msg: &Message) {
+        let policy = self.inner.auto_download.read().unwrap().clone();
+        let Some(policy) = policy else {
+            return;
+        };
+        if msg.content.is_view_once() && !policy.saves_view_once() {
+            return;
+        }
+        let Some(media) = DownloadableMedia::from_content(&msg.content).filter(|media| policy.allows(media)) else {
+            return;
+        };
+        let cache = self.inner.media_cache_for(&msg.content);
+        let (inner, events, mut msg) = (self.inner.clone(), self.events.clone(), msg.clone());
+        tokio::spawn(async move {
+            let data = match cache {
+                Some(cache) => cache.get_or_download(&media).await,
+                None => media.download().await,
+            };
+            let path = policy.path_for(&msg.info.chat, &msg.info.sender, &msg.info.id, &media);
+            let saved = match data {
+                Ok(data) => policy.save(&path, &data).await.map_err(|e| e.to_string()),
+                Err(e) => Err(e.to_string()),
+            };
+            let path = match saved {
+                Ok(path) => path,
+                Err(e) => {
+                    log::warn!("failed to download media of message {}: {}", msg.info.id, e);
+                    return;
+                }
+            };
+            if let Some(details) = media_details_mut(&mut msg.content) {
+                details.local_path = Some(path);
+            }
+            for event in inner.emit_event(Event::MediaDownloaded(MediaDownloaded { message: msg })) {
+                // The receiver is gone once the client stops listening
+                let _ = events.send(event);
+            }
+        });
+    }
+
+    /// Ask the sender of a message we could not decrypt to send it again,
+    /// with a fresh pre-key to start a new session from.
+    async fn send_retry_receipt(&mut self, info: &MessageInfo) {
+        let Some(count) = self.inner.retries.next(&info.chat, &info.id, self.inner.now().timestamp()) else {
+            log::warn!("giving up on undecryptable message {} from {}", info.id, self.inner.redact(&info.sender));
+            return;
+        };
+        let device = self.inner.device.read().await.clone();
+        let (Some(identity), Some(signed_pre_key)) = (&device.identity_key, &device.signed_pre_key) else {
+            return;
+        };
+        let pre_key = match fresh_pre_key(self.inner.store.as_ref()) {
+            Ok(pre_key) => pre_key,
+            Err(e) => {
+                log::warn!("failed to store pre-key for retry receipt: {}", e);
+                return;
+            }
+        };
+
+        let node = build_retry_receipt(info, count, device.registration_id, identity, signed_pre_key, &pre_key);
+        if let Err(e) = self.send(&node).await {
+            log::warn!("failed to send retry receipt to {}: {}", self.inner.redact(&info.sender), e);
+        }
+    }
+}
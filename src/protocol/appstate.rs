@@ -0,0 +1,222 @@
+//! App state sync keys.
+//!
+//! App state patches are encrypted with keys that only the primary device
+//! creates. It shares them with linked devices in protocol messages, and a
+//! linked device that meets a patch referencing a key it lacks asks for it
+//! with a key request.
+//!
+//! Changes made here are described as `AppStatePatch`es of mutations,
+//! each an index naming what changed and the action applied to it. They
+//! are plain descriptions: encrypting and sending them is left to the
+//! caller.
+
+use std::collections::HashSet;
+use std::sync::Mutex;
+use prost::Message as _;
+
+use crate::binary::Node;
+use crate::proto::{
+    protocol_message_type, AppStateSyncKeyId, AppStateSyncKeyRequest, E2eMessage, ProtocolMessage, SyncActionData,
+    SyncActionValue,
+};
+use crate::protocol::message::build_peer_message;
+use crate::store::AppStateSyncKey;
+use crate::types::JID;
+
+/// Extract shared app state keys from a received message node.
+///
+/// Keys without an ID or key data are skipped.
+pub fn parse_app_state_key_share(node: &Node) -> Option<Vec<AppStateSyncKey>> {
+    if node.tag != "message" {
+        return None;
+    }
+    let payload = node.get_child_by_tag("enc")?.get_bytes()?;
+    let share = E2eMessage::decode(payload).ok()?
+        .protocol_message?
+        .app_state_sync_key_share?;
+
+    Some(share.keys.into_iter()
+        .filter_map(|key| {
+            let key_id = key.key_id?.key_id?;
+            let data = key.key_data?;
+            Some(AppStateSyncKey {
+                key_id,
+                key_data: data.key_data?,
+                fingerprint: data.fingerprint.map(|f| f.encode_to_vec()).unwrap_or_default(),
+                timestamp: data.timestamp.unwrap_or_default(),
+            })
+        })
+        .collect())
+}
+
+/// Build the peer message asking the primary device for app state keys.
+pub fn build_app_state_key_request(own: &JID, message_id: &str, key_ids: &[Vec<u8>]) -> Node {
+    let request = E2eMessage {
+        protocol_message: Some(Box::new(ProtocolMessage {
+            r#type: Some(protocol_message_type::APP_STATE_SYNC_KEY_REQUEST),
+            app_state_sync_key_request: Some(AppStateSyncKeyRequest {
+                key_ids: key_ids.iter()
+                    .map(|id| AppStateSyncKeyId { key_id: Some(id.clone()) })
+                    .collect(),
+            }),
+            ..Default::default()
+        })),
+        ..Default::default()
+    };
+    build_peer_message(own, message_id, &request)
+}
+
+/// One change to an app state collection.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AppStateMutation {
+    /// What the mutation applies to, such as `["label_jid", label_id, chat]`
+    pub index: Vec<String>,
+    pub value: SyncActionValue,
+    /// Version of the action's format
+    pub version: i32,
+}
+
+impl AppStateMutation {
+    /// The index as the JSON array the patch MACs are computed over.
+    pub fn index_json(&self) -> String {
+        serde_json::to_string(&self.index).expect("strings serialize")
+    }
+}
+
+/// Decode the decrypted content of a mutation, a `SyncActionData`, into
+/// its index and value.
+///
+/// Feed the result to `Client::apply_app_state_mutations`.
+pub fn decode_sync_action(plaintext: &[u8]) -> Option<AppStateMutation> {
+    let data = SyncActionData::decode(plaintext).ok()?;
+    Some(AppStateMutation {
+        index: serde_json::from_slice(&data.index?).ok()?,
+        value: data.value?,
+        version: data.version.unwrap_or_default(),
+    })
+}
+
+/// Mutations to one app state collection, such as "regular".
+#[derive(Debug, Clone, PartialEq)]
+pub struct AppStatePatch {
+    pub collection: &'static str,
+    pub mutations: Vec<AppStateMutation>,
+}
+
+/// Key IDs requested from the primary device and not yet received.
+#[derive(Default)]
+pub(crate) struct KeyRequests {
+    pending: Mutex<HashSet<Vec<u8>>>,
+}
+
+impl KeyRequests {
+    /// Mark keys as requested, returning those not requested before.
+    pub(crate) fn claim(&self, key_ids: &[Vec<u8>]) -> Vec<Vec<u8>> {
+        let mut pending = self.pending.lock().unwrap();
+        key_ids.iter()
+            .filter(|id| pending.insert(id.to_vec()))
+            .cloned()
+            .collect()
+    }
+
+    /// Forget keys, because they arrived or requesting them failed.
+    pub(crate) fn release<'a>(&self, key_ids: impl IntoIterator<Item = &'a Vec<u8>>) {
+        let mut pending = self.pending.lock().unwrap();
+        for id in key_ids {
+            pending.remove(id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::{AppStateSyncKeyData, AppStateSyncKeyShare, MarkChatAsReadAction};
+
+    #[test]
+    fn test_decode_sync_action() {
+        let mutation = AppStateMutation {
+            index: vec!["markChatAsRead".to_string(), "1@s.whatsapp.net".to_string()],
+            value: SyncActionValue {
+                mark_chat_as_read_action: Some(MarkChatAsReadAction { read: Some(true) }),
+                ..Default::default()
+            },
+            version: 3,
+        };
+        let plaintext = SyncActionData {
+            index: Some(mutation.index_json().into_bytes()),
+            value: Some(mutation.value.clone()),
+            padding: Some(vec![0; 4]),
+            version: Some(3),
+        }.encode_to_vec();
+        assert_eq!(decode_sync_action(&plaintext), Some(mutation));
+        assert_eq!(decode_sync_action(&SyncActionData::default().encode_to_vec()), None);
+    }
+
+    fn share_node(keys: Vec<crate::proto::AppStateSyncKey>) -> Node {
+        let message = E2eMessage {
+            protocol_message: Some(Box::new(ProtocolMessage {
+                r#type: Some(protocol_message_type::APP_STATE_SYNC_KEY_SHARE),
+                app_state_sync_key_share: Some(AppStateSyncKeyShare { keys }),
+                ..Default::default()
+            })),
+            ..Default::default()
+        };
+        build_peer_message(&JID::new("1234", "s.whatsapp.net"), "3EB0AA", &message)
+    }
+
+    #[test]
+    fn test_parse_key_share() {
+        let node = share_node(vec![
+            crate::proto::AppStateSyncKey {
+                key_id: Some(AppStateSyncKeyId { key_id: Some(vec![0, 0, 1]) }),
+                key_data: Some(AppStateSyncKeyData {
+                    key_data: Some(vec![7; 32]),
+                    fingerprint: None,
+                    timestamp: Some(1_700_000_000_000),
+                }),
+            },
+            // No key data
+            crate::proto::AppStateSyncKey {
+                key_id: Some(AppStateSyncKeyId { key_id: Some(vec![0, 0, 2]) }),
+                key_data: None,
+            },
+        ]);
+
+        let keys = parse_app_state_key_share(&node).unwrap();
+        assert_eq!(keys.len(), 1);
+        assert_eq!(keys[0].key_id, [0, 0, 1]);
+        assert_eq!(keys[0].key_data, [7; 32]);
+        assert_eq!(keys[0].timestamp, 1_700_000_000_000);
+
+        let text = build_peer_message(&JID::new("1234", "s.whatsapp.net"), "3EB0AB", &E2eMessage {
+            conversation: Some("hi".to_string()),
+            ..Default::default()
+        });
+        assert!(parse_app_state_key_share(&text).is_none());
+    }
+
+    #[test]
+    fn test_build_key_request() {
+        let own = JID::new_ad("1234", 0, 7);
+        let node = build_app_state_key_request(&own, "3EB0AA", &[vec![0, 0, 1], vec![0, 0, 2]]);
+        assert_eq!(node.get_attr_str("to"), Some("1234@s.whatsapp.net"));
+
+        let payload = node.get_child_by_tag("enc").unwrap().get_bytes().unwrap();
+        let protocol = E2eMessage::decode(payload).unwrap().protocol_message.unwrap();
+        assert_eq!(protocol.r#type, Some(protocol_message_type::APP_STATE_SYNC_KEY_REQUEST));
+        let ids: Vec<_> = protocol.app_state_sync_key_request.unwrap().key_ids.into_iter()
+            .map(|id| id.key_id.unwrap())
+            .collect();
+        assert_eq!(ids, [vec![0, 0, 1], vec![0, 0, 2]]);
+    }
+
+    #[test]
+    fn test_key_requests_dedupe() {
+        let requests = KeyRequests::default();
+        assert_eq!(requests.claim(&[vec![1], vec![2]]), [vec![1], vec![2]]);
+        assert_eq!(requests.claim(&[vec![2], vec![3]]), [vec![3]]);
+        requests.release(&[vec![2]]);
+        assert_eq!(requests.claim(&[vec![2]]), [vec![2]]);
+    }
+}
@@ -0,0 +1,275 @@
+//! Automatic away replies.
+//!
+//! An `AutoResponder` attached with `Client::set_auto_responder` answers
+//! incoming one-to-one messages with a fixed text, optionally only during
+//! quiet hours, and at most once per sender within a cooldown. It runs as
+//! an event middleware stage and sends through the connection handle.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+use chrono::{DateTime, FixedOffset, NaiveTime, Utc};
+
+use crate::protocol::client::ClientInner;
+use crate::protocol::middleware::{EventMiddleware, Next};
+use crate::types::{Event, Message, MessageContent, JID};
+
+/// Daily time window, which may wrap past midnight.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuietHours {
+    /// Start of the window, inclusive
+    pub start: NaiveTime,
+    /// End of the window, exclusive
+    pub end: NaiveTime,
+    /// Time zone the window is given in
+    pub utc_offset: FixedOffset,
+}
+
+impl QuietHours {
+    /// Window from `start` to `end` in UTC.
+    pub fn utc(start: NaiveTime, end: NaiveTime) -> Self {
+        Self { start, end, utc_offset: FixedOffset::east_opt(0).unwrap() }
+    }
+
+    /// Check if a moment falls inside the window.
+    pub fn contains(&self, at: DateTime<Utc>) -> bool {
+        let time = at.with_timezone(&self.utc_offset).time();
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+/// Answers incoming messages with an away text.
+pub struct AutoResponder {
+    /// Text used for chats without their own
+    text: Option<String>,
+    /// Per-chat texts, taking precedence over `text`
+    chat_texts: HashMap<JID, String>,
+    /// Only reply inside this window, if set
+    quiet_hours: Option<QuietHours>,
+    /// Minimum time between replies to the same sender
+    cooldown: Duration,
+    /// Unix timestamp of the last reply to each sender
+    last_replies: Mutex<HashMap<JID, i64>>,
+}
+
+impl AutoResponder {
+    /// Reply to every chat with `text`.
+    ///
+    /// Replies are sent at any time and at most once an hour per sender.
+    pub fn new(text: impl Into<String>) -> Self {
+        Self {
+            text: Some(text.into()),
+            ..Self::per_chat()
+        }
+    }
+
+    /// Reply only in chats given a text with `for_chat`.
+    pub fn per_chat() -> Self {
+        Self {
+            text: None,
+            chat_texts: HashMap::new(),
+            quiet_hours: None,
+            cooldown: Duration::from_secs(60 * 60),
+            last_replies: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Reply in `chat` with `text` instead of the global text.
+    pub fn for_chat(mut self, chat: JID, text: impl Into<String>) -> Self {
+        self.chat_texts.insert(chat.to_non_ad(), text.into());
+        self
+    }
+
+    /// Only reply during `hours`.
+    pub fn with_quiet_hours(mut self, hours: QuietHours) -> Self {
+        self.quiet_hours = Some(hours);
+        self
+    }
+
+    /// Set the minimum time between replies to the same sender.
+    pub fn with_cooldown(mut self, cooldown: Duration) -> Self {
+        self.cooldown = cooldown;
+        self
+    }
+
+    /// Decide whether to answer a received message, and with what.
+    ///
+    /// Our own messages, group messages and reactions are never answered.
+    /// A returned reply counts towards the sender's cooldown. `now`, such
+    /// as the client's `ClientConfig::clock`, decides quiet hours and
+    /// cooldowns.
+    pub fn reply_for(&self, message: &Message, now: DateTime<Utc>) -> Option<String> {
+        if message.info.is_from_me || message.info.is_group {
+            return None;
+        }
+        if matches!(message.content, MessageContent::Reaction { .. }) {
+            return None;
+        }
+        if self.quiet_hours.is_some_and(|hours| !hours.contains(now)) {
+            return None;
+        }
+
+        let chat = message.info.chat.to_non_ad();
+        let text = self.chat_texts.get(&chat).or(self.text.as_ref())?;
+
+        let mut last_replies = self.last_replies.lock().unwrap();
+        let sender = message.info.sender.to_non_ad();
+        let now = now.timestamp();
+        if let Some(last) = last_replies.get(&sender) {
+            if now - last < self.cooldown.as_secs() as i64 {
+                return None;
+            }
+        }
+        last_replies.insert(sender, now);
+        Some(text.clone())
+    }
+}
+
+/// Middleware stage answering messages with an `AutoResponder`.
+pub(crate) struct AutoReplyMiddleware {
+    responder: AutoResponder,
+    /// Client whose connection replies go out on; weak, as the client
+    /// owns its middleware
+    client: Weak<ClientInner>,
+}
+
+impl AutoReplyMiddleware {
+    pub(crate) fn new(responder: AutoResponder, client: &Arc<ClientInner>) -> Self {
+        Self { responder, client: Arc::downgrade(client) }
+    }
+
+    /// Send the reply to `message`, if any, without waiting for it.
+    fn reply(&self, message: &Message) {
+        let Some(client) = self.client.upgrade() else {
+            return;
+        };
+        let Some(handle) = client.connection() else {
+            return;
+        };
+        let Some(text) = self.responder.reply_for(message, client.now()) else {
+            return;
+        };
+        let chat = message.info.chat.clone();
+        let redacted = client.redact(&chat).to_string();
+        tokio::spawn(async move {
+            if let Err(e) = handle.send_message(chat, &text).await {
+                log::warn!("failed to send auto-reply to {}: {}", redacted, e);
+            }
+        });
+    }
+}
+
+impl EventMiddleware for AutoReplyMiddleware {
+    fn handle(&self, event: Event, next: Next<'_>) {
+        if let Event::Message(message) = &event {
+            self.reply(message);
+        }
+        next.run(event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use crate::types::MessageInfo;
+
+    fn message(from: &str) -> Message {
+        let from: JID = from.parse().unwrap();
+        Message {
+            info: MessageInfo {
+                id: "3EB0000000000001".to_string(),
+                sender: from.clone(),
+                chat: from,
+                is_from_me: false,
+                is_group: false,
+                timestamp: 0,
+                push_name: None,
+                reply_to: None,
+            },
+            content: MessageContent::Text("hello?".to_string()),
+        }
+    }
+
+    fn at(hour: u32, minute: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 5, 1, hour, minute, 0).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_middleware_replies_through_connection() {
+        use tokio::sync::mpsc;
+        use tokio_util::sync::CancellationToken;
+        use crate::protocol::client::ClientConfig;
+        use crate::protocol::handle::Command;
+        use crate::store::MemoryStore;
+
+        let inner = Arc::new(ClientInner::new(ClientConfig::default(), Arc::new(MemoryStore::new())));
+        let (tx, mut rx) = mpsc::channel(4);
+        *inner.connection.write().unwrap() = Some((tx, CancellationToken::new()));
+        inner.middleware.write().unwrap().push(Arc::new(AutoReplyMiddleware::new(AutoResponder::new("Away"), &inner)));
+
+        let emitted = inner.emit_event(Event::Message(message("1@s.whatsapp.net")));
+        assert_eq!(emitted.len(), 1);
+        match rx.recv().await {
+            Some(Command::Send { node, reply }) => {
+                assert_eq!(node.get_attr_str("to"), Some("1@s.whatsapp.net"));
+                assert_eq!(node.get_child_by_tag("body").and_then(|b| b.get_bytes()), Some(&b"Away"[..]));
+                let _ = reply.send(Ok(()));
+            }
+            _ => panic!("expected the reply to be sent"),
+        }
+    }
+
+    #[test]
+    fn test_quiet_hours_wrap_midnight() {
+        let hours = QuietHours::utc(
+            NaiveTime::from_hms_opt(22, 0, 0).unwrap(),
+            NaiveTime::from_hms_opt(7, 0, 0).unwrap(),
+        );
+        assert!(hours.contains(at(23, 30)));
+        assert!(hours.contains(at(6, 59)));
+        assert!(!hours.contains(at(7, 0)));
+        assert!(!hours.contains(at(12, 0)));
+
+        let offset = QuietHours { utc_offset: FixedOffset::east_opt(2 * 3600).unwrap(), ..hours };
+        // 21:00 UTC is 23:00 at UTC+2
+        assert!(offset.contains(at(21, 0)));
+    }
+
+    #[test]
+    fn test_cooldown_and_chat_texts() {
+        let vip: JID = "2@s.whatsapp.net".parse().unwrap();
+        let responder = AutoResponder::new("Away until Monday")
+            .for_chat(vip, "Back soon!")
+            .with_cooldown(Duration::from_secs(600));
+
+        assert_eq!(responder.reply_for(&message("1@s.whatsapp.net"), at(10, 0)).as_deref(), Some("Away until Monday"));
+        // Another device of the same sender is still within the cooldown
+        assert_eq!(responder.reply_for(&message("1:2@s.whatsapp.net"), at(10, 5)), None);
+        assert!(responder.reply_for(&message("1@s.whatsapp.net"), at(10, 10)).is_some());
+        assert_eq!(responder.reply_for(&message("2@s.whatsapp.net"), at(10, 0)).as_deref(), Some("Back soon!"));
+
+        let mut own = message("3@s.whatsapp.net");
+        own.info.is_from_me = true;
+        assert_eq!(responder.reply_for(&own, at(10, 0)), None);
+    }
+
+    #[test]
+    fn test_per_chat_only_and_quiet_hours() {
+        let vip: JID = "2@s.whatsapp.net".parse().unwrap();
+        let responder = AutoResponder::per_chat()
+            .for_chat(vip, "Back soon!")
+            .with_quiet_hours(QuietHours::utc(
+                NaiveTime::from_hms_opt(18, 0, 0).unwrap(),
+                NaiveTime::from_hms_opt(9, 0, 0).unwrap(),
+            ));
+
+        assert_eq!(responder.reply_for(&message("1@s.whatsapp.net"), at(20, 0)), None);
+        assert_eq!(responder.reply_for(&message("2@s.whatsapp.net"), at(12, 0)), None);
+        assert!(responder.reply_for(&message("2@s.whatsapp.net"), at(20, 0)).is_some());
+    }
+}
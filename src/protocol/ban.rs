@@ -0,0 +1,96 @@
+//! Temporary bans and rate limiting.
+//!
+//! The server ends the stream with code 402 when the account is
+//! temporarily banned and 429 when we connect or send too often.
+//! Reconnecting right away only lengthens the ban, so the client refuses
+//! to connect until it runs out.
+
+use chrono::{DateTime, Duration, Utc};
+
+use crate::binary::Node;
+use crate::types::Banned;
+
+/// Stream error code of a temporary ban.
+pub const TEMPORARY_BAN_CODE: u16 = 402;
+
+/// Stream error code of rate limiting.
+pub const RATE_LIMIT_CODE: u16 = 429;
+
+/// How long to stay away when the server does not say.
+pub const DEFAULT_BAN_BACKOFF: Duration = Duration::minutes(5);
+
+/// Parse a ban from a `<stream:error>` or `<failure>` node.
+///
+/// The end of the ban is given either as a unix time in `t` or as seconds
+/// from now in `expire`.
+pub fn parse_ban(node: &Node) -> Option<Banned> {
+    parse_ban_at(node, Utc::now())
+}
+
+/// Parse a ban, counting a relative `expire` from `now`.
+pub(crate) fn parse_ban_at(node: &Node, now: DateTime<Utc>) -> Option<Banned> {
+    let code = match &*node.tag {
+        "stream:error" => node.get_attr_str("code"),
+        "failure" => node.get_attr_str("reason"),
+        _ => None,
+    }?;
+    let code: u16 = code.parse().ok()?;
+    if code != TEMPORARY_BAN_CODE && code != RATE_LIMIT_CODE {
+        return None;
+    }
+
+    // An `expire` too far out to represent counts as no known expiry
+    let expires = node.get_attr_unix_time("t").or_else(|| {
+        node.get_attr_int("expire")
+            .and_then(Duration::try_seconds)
+            .and_then(|expire| now.checked_add_signed(expire))
+    });
+    Some(Banned { code, expires })
+}
+
+/// Time until which connecting is refused after `ban`.
+pub(crate) fn blocked_until(ban: &Banned, now: DateTime<Utc>) -> DateTime<Utc> {
+    ban.expires.unwrap_or(now + DEFAULT_BAN_BACKOFF)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_ban() {
+        let mut node = Node::new("stream:error");
+        node.set_attr("code", "402");
+        node.set_attr("t", "1700000000");
+        let ban = parse_ban(&node).unwrap();
+        assert_eq!(ban.code, TEMPORARY_BAN_CODE);
+        assert_eq!(ban.expires, DateTime::from_timestamp(1700000000, 0));
+        assert!(!ban.is_rate_limit());
+
+        let mut node = Node::new("failure");
+        node.set_attr("reason", "429");
+        let ban = parse_ban(&node).unwrap();
+        assert!(ban.is_rate_limit());
+        assert_eq!(ban.expires, None);
+        let now = Utc::now();
+        assert_eq!(blocked_until(&ban, now), now + DEFAULT_BAN_BACKOFF);
+
+        let mut node = Node::new("stream:error");
+        node.set_attr("code", "515");
+        assert!(parse_ban(&node).is_none());
+    }
+
+    #[test]
+    fn test_parse_ban_out_of_range_expire() {
+        let now = Utc::now();
+        let mut node = Node::new("stream:error");
+        node.set_attr("code", "402");
+        node.set_attr("expire", i64::MAX.to_string());
+        let ban = parse_ban_at(&node, now).unwrap();
+        assert_eq!(ban.expires, None);
+
+        node.set_attr("expire", "60");
+        let ban = parse_ban_at(&node, now).unwrap();
+        assert_eq!(ban.expires, Some(now + Duration::seconds(60)));
+    }
+}
@@ -0,0 +1,172 @@
+//! Quick replies and automated messages of business accounts.
+//!
+//! Business accounts keep their quick replies and their greeting and away
+//! message settings in app state, so every device can use them.
+//! `BusinessTools` follows those mutations, which lets migration tools
+//! read the configuration of an account from a linked device.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::proto::{away_schedule, SyncActionValue};
+use crate::types::{AwayMessage, AwaySchedule, GreetingMessage, QuickReply};
+
+/// A business tooling change carried by an app state mutation.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BusinessToolsMutation {
+    QuickReply(QuickReply),
+    QuickReplyDeleted(String),
+    Greeting(GreetingMessage),
+    Away(AwayMessage),
+}
+
+/// Parse a quick reply, greeting or away message mutation.
+///
+/// `index` is the mutation's decoded index: `["quick_reply", "<id>"]`,
+/// `["greeting_message"]` or `["away_message"]`.
+pub fn parse_business_tools_mutation(index: &[String], value: &SyncActionValue) -> Option<BusinessToolsMutation> {
+    match index {
+        [kind, id, ..] if kind == "quick_reply" => {
+            let action = value.quick_reply_action.as_ref()?;
+            if action.deleted == Some(true) {
+                return Some(BusinessToolsMutation::QuickReplyDeleted(id.clone()));
+            }
+            Some(BusinessToolsMutation::QuickReply(QuickReply {
+                id: id.clone(),
+                shortcut: action.shortcut.clone().unwrap_or_default(),
+                message: action.message.clone().unwrap_or_default(),
+                keywords: action.keywords.clone(),
+                count: action.count.unwrap_or(0).max(0) as u32,
+            }))
+        }
+        [kind, ..] if kind == "greeting_message" => {
+            let action = value.greeting_message_action.as_ref()?;
+            Some(BusinessToolsMutation::Greeting(GreetingMessage {
+                enabled: action.enabled.unwrap_or(false),
+                message: action.message.clone().unwrap_or_default(),
+                inactivity_days: action.inactivity_days.unwrap_or(0).max(0) as u32,
+            }))
+        }
+        [kind, ..] if kind == "away_message" => {
+            let action = value.away_message_action.as_ref()?;
+            let schedule = match action.schedule {
+                Some(away_schedule::OUTSIDE_BUSINESS_HOURS) => AwaySchedule::OutsideBusinessHours,
+                Some(away_schedule::CUSTOM) => AwaySchedule::Custom {
+                    start: action.start_timestamp?,
+                    end: action.end_timestamp?,
+                },
+                _ => AwaySchedule::Always,
+            };
+            Some(BusinessToolsMutation::Away(AwayMessage {
+                enabled: action.enabled.unwrap_or(false),
+                message: action.message.clone().unwrap_or_default(),
+                schedule,
+            }))
+        }
+        _ => None,
+    }
+}
+
+/// Quick replies and automated message settings of a business account.
+#[derive(Default)]
+pub struct BusinessTools {
+    quick_replies: Mutex<HashMap<String, QuickReply>>,
+    greeting: Mutex<Option<GreetingMessage>>,
+    away: Mutex<Option<AwayMessage>>,
+}
+
+impl BusinessTools {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Apply a parsed mutation.
+    pub fn apply(&self, mutation: BusinessToolsMutation) {
+        match mutation {
+            BusinessToolsMutation::QuickReply(reply) => {
+                self.quick_replies.lock().unwrap().insert(reply.id.clone(), reply);
+            }
+            BusinessToolsMutation::QuickReplyDeleted(id) => {
+                self.quick_replies.lock().unwrap().remove(&id);
+            }
+            BusinessToolsMutation::Greeting(greeting) => *self.greeting.lock().unwrap() = Some(greeting),
+            BusinessToolsMutation::Away(away) => *self.away.lock().unwrap() = Some(away),
+        }
+    }
+
+    /// Quick replies, by shortcut.
+    pub fn quick_replies(&self) -> Vec<QuickReply> {
+        let mut replies: Vec<_> = self.quick_replies.lock().unwrap().values().cloned().collect();
+        replies.sort_by(|a, b| a.shortcut.cmp(&b.shortcut));
+        replies
+    }
+
+    /// Greeting message settings, if synced.
+    pub fn greeting(&self) -> Option<GreetingMessage> {
+        self.greeting.lock().unwrap().clone()
+    }
+
+    /// Away message settings, if synced.
+    pub fn away(&self) -> Option<AwayMessage> {
+        self.away.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::{AwayMessageAction, QuickReplyAction};
+
+    fn index(parts: &[&str]) -> Vec<String> {
+        parts.iter().map(|part| part.to_string()).collect()
+    }
+
+    #[test]
+    fn test_business_tools_mutations() {
+        let tools = BusinessTools::new();
+        let reply = SyncActionValue {
+            quick_reply_action: Some(QuickReplyAction {
+                shortcut: Some("thanks".to_string()),
+                message: Some("Thank you for your order!".to_string()),
+                keywords: vec!["order".to_string()],
+                count: Some(4),
+                deleted: None,
+            }),
+            ..Default::default()
+        };
+        tools.apply(parse_business_tools_mutation(&index(&["quick_reply", "7"]), &reply).unwrap());
+        assert_eq!(tools.quick_replies(), [QuickReply {
+            id: "7".to_string(),
+            shortcut: "thanks".to_string(),
+            message: "Thank you for your order!".to_string(),
+            keywords: vec!["order".to_string()],
+            count: 4,
+        }]);
+
+        let deleted = SyncActionValue {
+            quick_reply_action: Some(QuickReplyAction { deleted: Some(true), ..Default::default() }),
+            ..Default::default()
+        };
+        tools.apply(parse_business_tools_mutation(&index(&["quick_reply", "7"]), &deleted).unwrap());
+        assert!(tools.quick_replies().is_empty());
+
+        let away = SyncActionValue {
+            away_message_action: Some(AwayMessageAction {
+                enabled: Some(true),
+                message: Some("Back on Monday".to_string()),
+                schedule: Some(away_schedule::CUSTOM),
+                start_timestamp: Some(1_700_000_000),
+                end_timestamp: Some(1_700_200_000),
+            }),
+            ..Default::default()
+        };
+        tools.apply(parse_business_tools_mutation(&index(&["away_message"]), &away).unwrap());
+        let away = tools.away().unwrap();
+        assert!(away.enabled);
+        assert_eq!(away.schedule, AwaySchedule::Custom { start: 1_700_000_000, end: 1_700_200_000 });
+        assert!(tools.greeting().is_none());
+
+        assert!(parse_business_tools_mutation(&index(&["greeting_message"]), &SyncActionValue::default()).is_none());
+        assert!(parse_business_tools_mutation(&index(&["label_edit", "1"]), &reply).is_none());
+    }
+}
@@ -0,0 +1,334 @@
+//! Business profiles, catalogs and product messages.
+
+use prost::Message as _;
+
+use crate::binary::Node;
+use crate::proto::{E2eMessage, ProductMessage, ProductSnapshot};
+use crate::protocol::client::{Client, ClientError};
+use crate::protocol::intercept::{MessageKind, SendableMessage};
+use crate::protocol::message::build_e2e_message;
+use crate::protocol::request::{IqError, IqRequest, IqResponse, IqType};
+use crate::types::{BusinessCategory, BusinessProfile, Catalog, MessageContent, Product, JID};
+
+/// Query for the public profile of a business.
+pub struct BusinessProfileRequest {
+    pub jid: JID,
+}
+
+impl IqRequest for BusinessProfileRequest {
+    type Response = BusinessProfile;
+
+    fn namespace(&self) -> &str {
+        "w:biz"
+    }
+
+    fn iq_type(&self) -> IqType {
+        IqType::Get
+    }
+
+    fn content(&self) -> Vec<Node> {
+        let mut profile = Node::new("profile");
+        profile.set_attr("jid", self.jid.to_non_ad().to_string());
+        let mut query = Node::new("business_profile");
+        query.set_attr("v", "244");
+        query.add_child(profile);
+        vec![query]
+    }
+}
+
+impl IqResponse for BusinessProfile {
+    fn from_node(node: &Node) -> Result<Self, IqError> {
+        let profile = node.get_child_by_tag("business_profile")
+            .and_then(|b| b.get_child_by_tag("profile"))
+            .ok_or_else(|| IqError::MalformedResponse("missing <profile>".to_string()))?;
+        let jid = profile.parse_attr_jid("jid")
+            .ok_or_else(|| IqError::MalformedResponse("profile without jid".to_string()))?;
+
+        Ok(BusinessProfile {
+            jid,
+            description: child_text(profile, "description"),
+            address: child_text(profile, "address"),
+            email: child_text(profile, "email"),
+            websites: profile.get_children_by_tag("website").into_iter()
+                .filter_map(text)
+                .collect(),
+            categories: profile.get_child_by_tag("categories")
+                .map(|categories| categories.get_children_by_tag("category"))
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|category| Some(BusinessCategory {
+                    id: category.get_attr_str("id")?.to_string(),
+                    name: text(category).unwrap_or_default(),
+                }))
+                .collect(),
+        })
+    }
+}
+
+/// Query for a page of a business's product catalog.
+pub struct CatalogRequest {
+    pub jid: JID,
+    pub limit: u32,
+    /// Cursor from the previous page
+    pub after: Option<String>,
+}
+
+impl IqRequest for CatalogRequest {
+    type Response = Catalog;
+
+    fn namespace(&self) -> &str {
+        "w:biz:catalog"
+    }
+
+    fn iq_type(&self) -> IqType {
+        IqType::Get
+    }
+
+    fn content(&self) -> Vec<Node> {
+        let text_node = |tag: &'static str, value: String| {
+            let mut node = Node::new(tag);
+            node.set_bytes(value.into_bytes());
+            node
+        };
+
+        let mut catalog = Node::new("product_catalog");
+        catalog.set_attr("jid", self.jid.to_non_ad().to_string());
+        catalog.set_attr("allow_shop_source", "true");
+        catalog.add_child(text_node("limit", self.limit.to_string()));
+        catalog.add_child(text_node("width", "100".to_string()));
+        catalog.add_child(text_node("height", "100".to_string()));
+        if let Some(after) = &self.after {
+            catalog.add_child(text_node("after", after.clone()));
+        }
+        vec![catalog]
+    }
+}
+
+impl IqResponse for Catalog {
+    fn from_node(node: &Node) -> Result<Self, IqError> {
+        let catalog = node.get_child_by_tag("product_catalog")
+            .ok_or_else(|| IqError::MalformedResponse("missing <product_catalog>".to_string()))?;
+
+        Ok(Catalog {
+            products: catalog.get_children_by_tag("product").into_iter()
+                .filter_map(parse_catalog_product)
+                .collect(),
+            next_page: catalog.get_child_by_tag("paging").and_then(|paging| child_text(paging, "after")),
+        })
+    }
+}
+
+/// Parse a `<product>` of a catalog. Prices are given in thousandths.
+fn parse_catalog_product(node: &Node) -> Option<Product> {
+    let amount = |tag| child_text(node, tag).and_then(|price| price.parse().ok());
+    Some(Product {
+        id: child_text(node, "id")?,
+        retailer_id: child_text(node, "retailer_id"),
+        title: child_text(node, "name").unwrap_or_default(),
+        description: child_text(node, "description"),
+        currency: child_text(node, "currency"),
+        price_amount_1000: amount("price"),
+        sale_price_amount_1000: amount("sale_price"),
+        url: child_text(node, "url"),
+        image_urls: node.get_child_by_tag("media")
+            .map(|media| media.get_children_by_tag("image"))
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|image| child_text(image, "request_image_url"))
+            .collect(),
+    })
+}
+
+fn text(node: &Node) -> Option<String> {
+    node.get_bytes().map(|bytes| String::from_utf8_lossy(bytes).to_string())
+}
+
+fn child_text(node: &Node, tag: &str) -> Option<String> {
+    node.get_child_by_tag(tag).and_then(text)
+}
+
+/// Build a message sharing a product from `business_owner`'s catalog.
+pub fn build_product_message(
+    to: &JID,
+    message_id: &str,
+    product: &Product,
+    business_owner: &JID,
+    body: Option<&str>,
+) -> Node {
+    let message = E2eMessage {
+        product_message: Some(ProductMessage {
+            product: Some(ProductSnapshot {
+                product_id: Some(product.id.clone()),
+                title: Some(product.title.clone()),
+                description: product.description.clone(),
+                currency_code: product.currency.clone(),
+                price_amount_1000: product.price_amount_1000,
+                retailer_id: product.retailer_id.clone(),
+                url: product.url.clone(),
+                product_image_count: Some(product.image_urls.len() as u32),
+                sale_price_amount_1000: product.sale_price_amount_1000,
+            }),
+            business_owner_jid: Some(business_owner.to_non_ad().to_string()),
+            body: body.map(String::from),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    build_e2e_message(to, message_id, "media", Some("product"), &message)
+}
+
+/// Extract a product message from a received message node.
+pub fn parse_product_message(node: &Node) -> Option<MessageContent> {
+    let payload = node.get_child_by_tag("enc")?.get_bytes()?;
+    let message = E2eMessage::decode(payload).ok()?.product_message?;
+    let snapshot = message.product?;
+
+    Some(MessageContent::Product {
+        product: Product {
+            id: snapshot.product_id.unwrap_or_default(),
+            retailer_id: snapshot.retailer_id,
+            title: snapshot.title.unwrap_or_default(),
+            description: snapshot.description,
+            currency: snapshot.currency_code,
+            price_amount_1000: snapshot.price_amount_1000,
+            sale_price_amount_1000: snapshot.sale_price_amount_1000,
+            url: snapshot.url,
+            image_urls: Vec::new(),
+        },
+        business_owner: message.business_owner_jid.and_then(|jid| jid.parse().ok()),
+        body: message.body,
+        footer: message.footer,
+    })
+}
+
+impl Client {
+    /// Get the public profile of a business account.
+    pub async fn get_business_profile(&self, jid: &JID) -> Result<BusinessProfile, ClientError> {
+        self.query(&BusinessProfileRequest { jid: jid.clone() }).await
+    }
+
+    /// Get the first `limit` products of a business's catalog.
+    ///
+    /// Use `get_catalog_page` with `Catalog::next_page` for the rest.
+    pub async fn get_catalog(&self, jid: &JID, limit: u32) -> Result<Catalog, ClientError> {
+        self.get_catalog_page(jid, limit, None).await
+    }
+
+    /// Get a page of a business's catalog, starting after `after`.
+    pub async fn get_catalog_page(&self, jid: &JID, limit: u32, after: Option<String>) -> Result<Catalog, ClientError> {
+        self.query(&CatalogRequest { jid: jid.clone(), limit, after }).await
+    }
+
+    /// Share a product from `business_owner`'s catalog, returning the
+    /// message ID.
+    pub async fn send_product(
+        &self,
+        to: &JID,
+        product: &Product,
+        business_owner: &JID,
+        body: Option<&str>,
+    ) -> Result<String, ClientError> {
+        let handle = self.connection()?;
+        let id = handle.new_message_id();
+        let message = SendableMessage::new(&id, to.clone(), body.unwrap_or_default()).with_kind(MessageKind::Product);
+        handle.send_intercepted(message, |message| {
+            let body = (body.is_some() || !message.text.is_empty()).then_some(message.text.as_str());
+            build_product_message(&message.to, &id, product, business_owner, body)
+        }).await?;
+        Ok(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text_node(tag: &'static str, value: &str) -> Node {
+        let mut node = Node::new(tag);
+        node.set_bytes(value.as_bytes().to_vec());
+        node
+    }
+
+    #[test]
+    fn test_parse_business_profile() {
+        let mut category = text_node("category", "Bakery");
+        category.set_attr("id", "133436743388217");
+        let mut categories = Node::new("categories");
+        categories.add_child(category);
+
+        let mut profile = Node::new("profile");
+        profile.set_attr("jid", "1234@s.whatsapp.net");
+        profile.add_child(text_node("description", "Fresh bread daily"));
+        profile.add_child(text_node("website", "https://example.com"));
+        profile.add_child(text_node("website", "https://example.org"));
+        profile.add_child(categories);
+        let mut business = Node::new("business_profile");
+        business.add_child(profile);
+        let mut iq = Node::new("iq");
+        iq.add_child(business);
+
+        let profile = BusinessProfile::from_node(&iq).unwrap();
+        assert_eq!(profile.jid, JID::new("1234", "s.whatsapp.net"));
+        assert_eq!(profile.description.as_deref(), Some("Fresh bread daily"));
+        assert_eq!(profile.email, None);
+        assert_eq!(profile.websites.len(), 2);
+        assert_eq!(profile.categories[0].name, "Bakery");
+    }
+
+    #[test]
+    fn test_parse_catalog() {
+        let mut image = Node::new("image");
+        image.add_child(text_node("request_image_url", "https://example.com/bread.jpg"));
+        let mut media = Node::new("media");
+        media.add_child(image);
+
+        let mut product = Node::new("product");
+        product.add_child(text_node("id", "5501"));
+        product.add_child(text_node("name", "Sourdough"));
+        product.add_child(text_node("price", "4500"));
+        product.add_child(text_node("currency", "EUR"));
+        product.add_child(media);
+        let mut paging = Node::new("paging");
+        paging.add_child(text_node("after", "cursor-2"));
+        let mut catalog = Node::new("product_catalog");
+        catalog.add_child(product);
+        catalog.add_child(Node::new("product"));
+        catalog.add_child(paging);
+        let mut iq = Node::new("iq");
+        iq.add_child(catalog);
+
+        let catalog = Catalog::from_node(&iq).unwrap();
+        assert_eq!(catalog.products.len(), 1);
+        assert_eq!(catalog.products[0].title, "Sourdough");
+        assert_eq!(catalog.products[0].price_amount_1000, Some(4500));
+        assert_eq!(catalog.products[0].image_urls, ["https://example.com/bread.jpg"]);
+        assert_eq!(catalog.next_page.as_deref(), Some("cursor-2"));
+
+        let request = CatalogRequest { jid: JID::new_ad("1234", 0, 2), limit: 10, after: None };
+        let node = request.to_node("1");
+        let query = node.get_child_by_tag("product_catalog").unwrap();
+        assert_eq!(query.get_attr_str("jid"), Some("1234@s.whatsapp.net"));
+        assert_eq!(child_text(query, "limit").as_deref(), Some("10"));
+    }
+
+    #[test]
+    fn test_product_message() {
+        let product = Product {
+            id: "5501".to_string(),
+            title: "Sourdough".to_string(),
+            currency: Some("EUR".to_string()),
+            price_amount_1000: Some(4500),
+            ..Default::default()
+        };
+        let shop = JID::new("1234", "s.whatsapp.net");
+        let node = build_product_message(&JID::new("5678", "s.whatsapp.net"), "3EB0AA", &product, &shop, Some("Today only"));
+        assert_eq!(node.get_attr_str("type"), Some("media"));
+
+        let Some(MessageContent::Product { product: parsed, business_owner, body, .. }) = parse_product_message(&node) else {
+            panic!("not a product message");
+        };
+        assert_eq!(parsed, product);
+        assert_eq!(business_owner, Some(shop));
+        assert_eq!(body.as_deref(), Some("Today only"));
+    }
+}
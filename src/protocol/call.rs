@@ -0,0 +1,130 @@
+//! Call signaling.
+//!
+//! Calls are not supported, but offers are surfaced as events so they can
+//! be rejected.
+
+use crate::binary::Node;
+use crate::protocol::client::{Client, ClientError};
+use crate::types::{CallOffer, CallTerminate, Event, JID};
+
+/// Parse a `<call>` stanza into an event.
+///
+/// Only offers and terminations are modelled; other call signaling such as
+/// relay latency reports is ignored.
+pub fn parse_call(node: &Node) -> Option<Event> {
+    if node.tag != "call" {
+        return None;
+    }
+
+    let from = node.parse_attr_jid("from")?;
+    let timestamp = node.get_attr_int("t").unwrap_or(0);
+    let child = node.get_children()?.first()?;
+    let call_id = child.get_attr_str("call-id")?.to_string();
+    let call_creator = child.parse_attr_jid("call-creator").unwrap_or_else(|| from.clone());
+
+    match &*child.tag {
+        "offer" => Some(Event::CallOffer(CallOffer {
+            from,
+            call_id,
+            call_creator,
+            is_video: child.get_child_by_tag("video").is_some(),
+            is_group: child.get_attr_str("type") == Some("group")
+                || child.get_child_by_tag("group_info").is_some(),
+            timestamp,
+        })),
+        "terminate" => Some(Event::CallTerminate(CallTerminate {
+            from,
+            call_id,
+            call_creator,
+            reason: child.get_attr_str("reason").map(String::from),
+            timestamp,
+        })),
+        _ => None,
+    }
+}
+
+/// Build the stanza rejecting a call.
+pub fn build_reject_call(call_id: &str, call_creator: &JID, id: &str) -> Node {
+    let mut reject = Node::new("reject");
+    reject.set_attr("call-id", call_id);
+    reject.set_attr("call-creator", call_creator.to_string());
+    reject.set_attr("count", "0");
+
+    let mut node = Node::new("call");
+    node.set_attr("id", id);
+    node.set_attr("to", call_creator.to_string());
+    node.add_child(reject);
+    node
+}
+
+impl Client {
+    /// Reject an incoming call.
+    ///
+    /// `from` is the caller, as reported by `CallOffer::call_creator`.
+    pub async fn reject_call(&self, call_id: &str, from: &JID) -> Result<(), ClientError> {
+        let connection = self.connection()?;
+        connection.send_node(build_reject_call(call_id, from, &connection.next_tag())).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call(child: Node) -> Node {
+        let mut node = Node::new("call");
+        node.set_attr("from", JID::new("1", "s.whatsapp.net"));
+        node.set_attr("id", "STANZA");
+        node.set_attr("t", "1700000000");
+        node.add_child(child);
+        node
+    }
+
+    #[test]
+    fn test_parse_call_offer() {
+        let mut offer = Node::new("offer");
+        offer.set_attr("call-id", "CALL1");
+        offer.set_attr("call-creator", JID::new("1", "s.whatsapp.net"));
+        offer.add_child(Node::new("audio"));
+        offer.add_child(Node::new("video"));
+
+        match parse_call(&call(offer)) {
+            Some(Event::CallOffer(offer)) => {
+                assert_eq!(offer.call_id, "CALL1");
+                assert_eq!(offer.call_creator, JID::new("1", "s.whatsapp.net"));
+                assert!(offer.is_video);
+                assert!(!offer.is_group);
+                assert_eq!(offer.timestamp, 1700000000);
+            }
+            other => panic!("unexpected event: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_call_terminate_and_others() {
+        let mut terminate = Node::new("terminate");
+        terminate.set_attr("call-id", "CALL1");
+        terminate.set_attr("reason", "timeout");
+        match parse_call(&call(terminate)) {
+            Some(Event::CallTerminate(terminate)) => {
+                assert_eq!(terminate.reason.as_deref(), Some("timeout"));
+                assert_eq!(terminate.call_creator, JID::new("1", "s.whatsapp.net"));
+            }
+            other => panic!("unexpected event: {:?}", other),
+        }
+
+        let mut latency = Node::new("relaylatency");
+        latency.set_attr("call-id", "CALL1");
+        assert!(parse_call(&call(latency)).is_none());
+    }
+
+    #[test]
+    fn test_build_reject_call() {
+        let node = build_reject_call("CALL1", &JID::new("1", "s.whatsapp.net"), "1.2-3");
+        assert_eq!(node.get_attr_str("to"), Some("1@s.whatsapp.net"));
+        assert_eq!(node.get_attr_str("id"), Some("1.2-3"));
+        let reject = node.get_child_by_tag("reject").unwrap();
+        assert_eq!(reject.get_attr_str("call-id"), Some("CALL1"));
+        assert_eq!(reject.get_attr_str("count"), Some("0"));
+    }
+}
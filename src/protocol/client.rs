@@ -1,54 +1,867 @@
 //! WhatsApp Client implementation.
 //!
 //! High-level client for connecting to and interacting with WhatsApp.
+//!
+//! The socket is owned by a connection actor task spawned on `connect`.
+//! `Client` and any number of cloned `ClientHandle`s talk to it over a
+//! command channel, so sends and queries can be issued from any task,
+//! including from inside event handlers.
 
+use std::collections::HashMap;
+use std::path::PathBuf;
 use std::sync::Arc;
-use tokio::sync::RwLock;
+use std::time::Duration;
+use futures::stream::BoxStream;
+use tokio::sync::{mpsc, RwLock};
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use prost::Message as _;
 
-use crate::types::{JID, Event, Message, MessageInfo, MessageContent};
-use crate::binary::{Node, encode, decode};
-use crate::crypto::KeyPair;
-use crate::socket::{NoiseSocket, SocketError, endpoints};
-use crate::store::{Device, MemoryStore, Store, DeviceStore};
+use crate::proto::{
+    ClientPayload, DeviceProps, HistorySyncConfig, device_platform, make_device_pairing_data, make_device_props, make_login_payload,
+    make_web_client_payload, DEFAULT_DEVICE_OS,
+};
+use crate::types::{
+    JID, Event, DisconnectReason, Message, MessageInfo, MessageContent, Presence, Redacted, ContactsSynced, HistorySync,
+    HistorySyncType,
+    UndecryptableMessage, ConnectFailure, GroupInfo, MessageID, Label, LabelTarget,
+    QuickReply, GreetingMessage, AwayMessage, GroupSetting,
+};
+use crate::binary::{decode, Node};
+use crate::socket::{
+    NoiseSocket, SocketError, Endpoint, EndpointRotation, HandshakeConfig, HandshakeStage, endpoints,
+    parse_endpoint_hints,
+};
+use crate::transport::{Transport, WebSocketOptions};
+use crate::store::{
+    Device, MemoryStore, Store, ChatStore, OutboxStore, ScheduleStore, ScheduledMessage, StoredMessage,
+    MessageMatch, MessageRef, MediaCache, AppStateSyncKey, ContactInfo, ChatSettings,
+};
+use crate::media::{
+    DownloadableMedia, MediaError, MediaType, Transfer, TransferProgress,
+    UploadedMedia, MediaRetryTarget, MediaRetryResult, build_media_retry_receipt,
+    parse_media_retry_notification, is_media_retry_notification, is_expired_media_error, MediaAutoDownloadPolicy,
+};
+use crate::protocol::actor::ConnectionActor;
+use crate::protocol::handle::{ClientHandle, BulkContent, BulkSendResult, Command};
+use crate::protocol::ratelimit::{RateLimit, RateLimiter};
+use crate::protocol::group::{
+    is_group_notification, parse_participant_changes, parse_join_requests, parse_setting_changes,
+    parse_description_change, is_picture_notification, parse_photo_changes, GroupCache,
+};
+use crate::protocol::senderkey::SenderKeyManager;
+use crate::protocol::msgid::{generate_message_id_with, InvalidMessageId, SentIds, TagGenerator};
+use crate::protocol::call::parse_call;
+use crate::protocol::typing::TypingDuration;
+use crate::protocol::autoreply::{AutoReplyMiddleware, AutoResponder};
+use crate::protocol::qr::QRPairing;
+use crate::protocol::clock::{Clock, RandomSource, ServerClock, SystemClock, SystemRandom};
+use crate::protocol::replay::{FrameLog, FrameRecorder};
+use crate::protocol::template::{Template, TemplateError};
+use crate::protocol::middleware::{EventMiddleware, run_pipeline};
+use crate::protocol::intercept::SendInterceptor;
+use crate::protocol::history::{decompress_history_sync, parse_history_messages, parse_history_sync};
+use crate::protocol::appstate::{parse_app_state_key_share, AppStateMutation, AppStatePatch, KeyRequests};
+use crate::protocol::message::{parse_message_content, parse_message_info};
+use crate::protocol::msgsecret::parse_secret_content;
+use crate::protocol::keep::parse_keep_message;
+use crate::protocol::ephemeral::parse_ephemeral_setting;
+use crate::protocol::biztools::{parse_business_tools_mutation, BusinessTools};
+use crate::protocol::labels::{
+    LabelStore, build_label_chat_patch, build_label_edit_patch, build_label_message_patch, parse_label_mutation,
+};
+use crate::protocol::receipts::parse_receipt;
+use crate::protocol::devices::{is_devices_notification, parse_devices_notification, session_address, DeviceCache, DEVICE_MISMATCH_ERROR};
+use crate::protocol::unread::{parse_mark_chat_as_read_mutation, ChatReadState, UnreadTracker};
+use crate::protocol::presence::{PresenceStore, parse_presence};
+use crate::protocol::ban::{parse_ban_at, blocked_until};
+use crate::protocol::disconnect::{parse_stream_error, logged_out_event, should_reconnect, CloseCause, CLIENT_OUTDATED_CODE};
+use crate::protocol::retry::{has_undecryptable_enc, RetryCounter};
+use crate::protocol::newsletter::{is_newsletter_notification, parse_newsletter_updates};
+use crate::protocol::contacts::{ContactUpdate, parse_contact_mutation, parse_history_pushnames};
+use crate::protocol::request::{RequestTracker, IqRequest, IqError, is_iq_result, is_iq_error};
 
 /// Client configuration.
 #[derive(Clone)]
 pub struct ClientConfig {
-    /// WebSocket endpoint URL
+    /// WebSocket endpoint URL, tried first
     pub endpoint: String,
+    /// Endpoints tried in turn when connecting to `endpoint` fails
+    pub fallback_endpoints: Vec<String>,
     /// User agent string
     pub user_agent: String,
-    /// Auto-reconnect on disconnect
+    /// Connect again when a connection opened with `Client::connect` drops,
+    /// unless we closed it, the session ended, another client took over or
+    /// the client is outdated. `Client::receive` reconnects, backing off
+    /// between failed attempts, and emits `Connected` with `is_reconnect`.
     pub auto_reconnect: bool,
+    /// How long to wait for an IQ response before giving up
+    pub request_timeout: Duration,
+    /// Pacing applied to `send_bulk`
+    pub bulk_send_rate: RateLimit,
+    /// Unacknowledged outbox messages older than this are dropped instead
+    /// of being retried on connect
+    pub outbox_max_age: Duration,
+    /// Hide phone numbers and message text in log output
+    pub redact_logs: bool,
+    /// Try IPv6 addresses before IPv4 ones when connecting
+    pub prefer_ipv6: bool,
+    /// Never reveal reads or being online, like the phone's privacy
+    /// setting: `mark_read` sends delivery receipts instead of read
+    /// receipts, and `send_presence(true)` sends nothing
+    pub hide_reads_and_presence: bool,
+    /// Announce that we are online as soon as a registered device logs in.
+    /// The server holds back some traffic, such as the chat states of
+    /// others, until we have; see `Event::PresenceRequired`.
+    pub send_presence_on_connect: bool,
+    /// Answer messages we cannot decrypt with retry receipts carrying a
+    /// fresh pre-key. Messages are not decrypted with Signal sessions yet,
+    /// so this only makes senders resend what still cannot be read; it is
+    /// off until decryption exists.
+    pub send_retry_receipts: bool,
+    /// Save view-once messages to the chat store like other messages.
+    /// They are left out by default, as they are meant to be seen once.
+    pub store_view_once: bool,
+    /// Dry run: messages, receipts, presence, chat states, calls and `set`
+    /// queries, such as group and profile changes, are not sent but emitted
+    /// as `WouldHaveSent` events. Sends succeed as if they had been, and
+    /// held back queries as if answered with an empty result. Other
+    /// queries still go out, so the connection works.
+    pub read_only: bool,
+    /// Timeouts and limits for opening the connection and the Noise
+    /// handshake
+    pub handshake: HandshakeConfig,
+    /// Headers of the WebSocket upgrade request, size limits of the
+    /// connection and options of its TCP socket
+    pub websocket: WebSocketOptions,
+    /// How long `Client::get_cached_presence` trusts a presence update
+    pub presence_ttl: Duration,
+    /// How long group metadata is reused for sends and
+    /// `Client::get_group_info` before being fetched again
+    pub group_cache_ttl: Duration,
+    /// How long sent and received messages stay in the index that replies
+    /// are built and resolved through
+    pub message_index_ttl: Duration,
+    /// Operating system name the phone lists this device under
+    pub device_os: String,
+    /// Platform icon the phone shows for this device, one of the
+    /// `proto::device_platform` constants
+    pub device_platform: i32,
+    /// Ask the phone for the full chat history when pairing, rather than
+    /// the last three months. Only read when pairing a new device.
+    pub require_full_sync: bool,
+    /// Limits on the history synced when pairing, such as the number of
+    /// days or megabytes of a full sync
+    pub history_sync: Option<HistorySyncConfig>,
+    /// Silence after which the connection is closed as stale. A ping is
+    /// sent halfway through, so only a dead connection stays silent this
+    /// long. `None` turns the watchdog off.
+    pub stale_timeout: Option<Duration>,
+    /// Source of the current time, for message timestamps, bans and
+    /// quiet hours
+    pub clock: Arc<dyn Clock>,
+    /// Source of message IDs, tag prefixes and typing pauses. Keys never
+    /// come from it.
+    pub rng: Arc<dyn RandomSource>,
 }
 
 impl Default for ClientConfig {
     fn default() -> Self {
         Self {
             endpoint: endpoints::MAIN.to_string(),
+            fallback_endpoints: endpoints::fallbacks(),
             user_agent: "WhatsApp/2.24.0".to_string(),
             auto_reconnect: true,
+            request_timeout: Duration::from_secs(75),
+            bulk_send_rate: RateLimit::default(),
+            outbox_max_age: Duration::from_secs(24 * 60 * 60),
+            redact_logs: false,
+            prefer_ipv6: true,
+            hide_reads_and_presence: false,
+            send_presence_on_connect: false,
+            send_retry_receipts: false,
+            store_view_once: false,
+            read_only: false,
+            handshake: HandshakeConfig::default(),
+            websocket: WebSocketOptions::default(),
+            presence_ttl: Duration::from_secs(10 * 60),
+            group_cache_ttl: Duration::from_secs(5 * 60),
+            message_index_ttl: Duration::from_secs(30 * 24 * 60 * 60),
+            stale_timeout: Some(Duration::from_secs(60)),
+            device_os: DEFAULT_DEVICE_OS.to_string(),
+            device_platform: device_platform::CHROME,
+            require_full_sync: false,
+            history_sync: None,
+            clock: Arc::new(SystemClock),
+            rng: Arc::new(SystemRandom),
+        }
+    }
+}
+
+impl ClientConfig {
+    /// Properties sent when pairing, as the phone will show this device.
+    pub fn device_props(&self) -> DeviceProps {
+        DeviceProps {
+            history_sync_config: self.history_sync.clone(),
+            ..make_device_props(&self.device_os, self.device_platform, self.require_full_sync)
         }
     }
+
+    /// Encoded `ClientPayload` for the handshake: a login for a registered
+    /// device, or a registration with our keys and device props for one
+    /// still to be paired.
+    pub(crate) fn client_payload(&self, device: &Device) -> Vec<u8> {
+        let push_name = device.push_name.as_deref();
+        let payload = match (&device.jid, &device.identity_key, &device.signed_pre_key) {
+            (Some(jid), _, _) => make_login_payload(push_name, jid.user.parse().unwrap_or_default(), jid.device.into()),
+            (None, Some(identity), Some(signed_pre_key)) => ClientPayload {
+                device_pairing_data: Some(make_device_pairing_data(
+                    device.registration_id,
+                    &identity.public,
+                    signed_pre_key.key_id,
+                    &signed_pre_key.key_pair.public,
+                    &signed_pre_key.signature.unwrap_or([0; 64]),
+                    &self.device_props(),
+                )),
+                ..make_web_client_payload(push_name)
+            },
+            _ => make_web_client_payload(push_name),
+        };
+        payload.encode_to_vec()
+    }
 }
 
 /// Event handler type.
 pub type EventHandler = Box<dyn Fn(Event) + Send + Sync>;
 
-/// WhatsApp client for connecting and messaging.
-pub struct Client {
+/// Maximum number of queued outgoing commands per connection.
+const COMMAND_BUFFER: usize = 64;
+
+/// Messages indexed between prunes of the message index.
+const MESSAGE_INDEX_PRUNE_EVERY: usize = 1000;
+
+/// Wait before the first reconnect attempt; it doubles after each failure.
+const RECONNECT_MIN_DELAY: Duration = Duration::from_secs(1);
+
+/// Longest wait between reconnect attempts.
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(60);
+
+/// State shared between the client, its handles and the connection actor.
+pub(crate) struct ClientInner {
     /// Client configuration
-    config: ClientConfig,
+    pub(crate) config: ClientConfig,
     /// Device information and keys
-    device: Arc<RwLock<Device>>,
+    pub(crate) device: Arc<RwLock<Device>>,
+    /// JID of the device once paired, readable without the device lock
+    pub(crate) own_jid: std::sync::RwLock<Option<JID>>,
     /// Data store
-    store: Arc<dyn Store>,
-    /// Socket connection (when connected)
-    socket: Option<NoiseSocket>,
-    /// Whether currently connected
-    connected: bool,
+    pub(crate) store: Arc<dyn Store>,
     /// Event handlers
-    event_handlers: Vec<EventHandler>,
+    pub(crate) event_handlers: std::sync::RwLock<Vec<EventHandler>>,
+    /// Middleware run on events before the handlers, in order
+    pub(crate) middleware: std::sync::RwLock<Vec<Arc<dyn EventMiddleware>>>,
+    /// Interceptors run on outgoing messages before they are sent, in order
+    pub(crate) interceptors: std::sync::RwLock<Vec<Arc<dyn SendInterceptor>>>,
+    /// Pending IQ requests
+    pub(crate) requests: RequestTracker,
+    /// Tags of outgoing IQs, receipts and calls on this connection
+    pub(crate) tags: TagGenerator,
+    /// Limiter shared by all bulk sends
+    pub(crate) bulk_limiter: RateLimiter,
+    /// Optional message history store
+    pub(crate) chat_store: std::sync::RwLock<Option<Arc<dyn ChatStore>>>,
+    /// Optional durable queue of outgoing messages
+    pub(crate) outbox: std::sync::RwLock<Option<Arc<dyn OutboxStore>>>,
+    /// Optional policy for downloading incoming media as it arrives
+    pub(crate) auto_download: std::sync::RwLock<Option<Arc<MediaAutoDownloadPolicy>>>,
+    /// Optional cache for downloaded media
+    pub(crate) media_cache: std::sync::RwLock<Option<Arc<MediaCache>>>,
+    /// Pending media retry requests, keyed by message ID
+    pub(crate) media_retries: RequestTracker,
+    /// Our group sender keys and their distribution
+    pub(crate) sender_keys: SenderKeyManager,
+    /// Recently sent message IDs, for deduplicating retried sends
+    pub(crate) sent_ids: SentIds,
+    /// Endpoints to connect to, in order of preference
+    pub(crate) endpoints: std::sync::Mutex<EndpointRotation>,
+    /// Edge routing info sent ahead of the header on the next connect
+    pub(crate) routing_info: std::sync::RwLock<Option<Vec<u8>>>,
+    /// Messages waiting to be sent at a set time
+    pub(crate) schedule: std::sync::RwLock<Arc<dyn ScheduleStore>>,
+    /// Wakes the scheduler when the schedule changes
+    pub(crate) schedule_changed: tokio::sync::Notify,
+    /// Middleware stage sending away replies, if a responder is set
+    pub(crate) auto_reply: std::sync::RwLock<Option<Arc<dyn EventMiddleware>>>,
+    /// Messages indexed so far, counting towards the next prune
+    indexed_messages: std::sync::atomic::AtomicUsize,
+    /// Command queue and cancellation of the current connection
+    pub(crate) connection: std::sync::RwLock<Option<(mpsc::Sender<Command>, CancellationToken)>>,
+    /// App state keys requested from the primary device
+    pub(crate) app_state_key_requests: KeyRequests,
+    /// Last presence received from each user
+    pub(crate) presences: PresenceStore,
+    /// End of the last ban, before which connecting is refused
+    pub(crate) banned_until: std::sync::RwLock<Option<chrono::DateTime<chrono::Utc>>>,
+    /// Offset of the server's clock from `ClientConfig::clock`
+    pub(crate) server_clock: ServerClock,
+    /// Unread count and last read message of each chat
+    pub(crate) unread: UnreadTracker,
+    /// Device lists of users, refreshed when their hash changes
+    pub(crate) devices: DeviceCache,
+    /// Group metadata, dropped when participants change
+    pub(crate) groups: GroupCache,
+    /// Business labels and what they are on
+    pub(crate) labels: LabelStore,
+    /// Business quick replies and automated messages
+    pub(crate) business_tools: BusinessTools,
+    /// Why the current connection is ending, once known
+    pub(crate) close_cause: CloseCause,
+    /// Retry receipts sent for messages we could not decrypt
+    pub(crate) retries: RetryCounter,
+    /// QR codes of the pairing in progress, while unregistered
+    pub(crate) pairing: std::sync::Mutex<Option<QRPairing>>,
+    /// Wakes the QR code rotation when pairing starts or ends
+    pub(crate) pairing_changed: tokio::sync::Notify,
+    /// Optional log of the decrypted frames of each connection
+    pub(crate) frame_recorder: std::sync::RwLock<Option<Arc<FrameRecorder>>>,
+}
+
+impl ClientInner {
+    pub(crate) fn new(config: ClientConfig, store: Arc<dyn Store>) -> Self {
+        // A device paired before logs in again instead of pairing anew
+        let device = match store.get_first_device() {
+            Ok(Some(device)) => device,
+            stored => {
+                if let Err(e) = stored {
+                    log::warn!("failed to load the stored device, pairing a new one: {}", e);
+                }
+                let mut device = Device::new();
+                device.initialize();
+                device
+            }
+        };
+
+        let endpoints = EndpointRotation::new(
+            std::iter::once(config.endpoint.clone()).chain(config.fallback_endpoints.iter().cloned()),
+        );
+
+        Self {
+            bulk_limiter: RateLimiter::with_clock(config.bulk_send_rate, config.clock.clone()),
+            endpoints: std::sync::Mutex::new(endpoints),
+            presences: PresenceStore::new(config.presence_ttl),
+            groups: GroupCache::with_clock(config.group_cache_ttl, config.clock.clone()),
+            config,
+            own_jid: std::sync::RwLock::new(device.jid.clone()),
+            device: Arc::new(RwLock::new(device)),
+            store,
+            event_handlers: std::sync::RwLock::new(Vec::new()),
+            middleware: std::sync::RwLock::new(Vec::new()),
+            interceptors: std::sync::RwLock::new(Vec::new()),
+            requests: RequestTracker::new(),
+            tags: TagGenerator::new(),
+            chat_store: std::sync::RwLock::new(None),
+            outbox: std::sync::RwLock::new(None),
+            media_cache: std::sync::RwLock::new(None),
+            auto_download: std::sync::RwLock::new(None),
+            media_retries: RequestTracker::new(),
+            sender_keys: SenderKeyManager::new(),
+            sent_ids: SentIds::new(),
+            routing_info: std::sync::RwLock::new(None),
+            schedule: std::sync::RwLock::new(Arc::new(MemoryStore::new())),
+            schedule_changed: tokio::sync::Notify::new(),
+            auto_reply: std::sync::RwLock::new(None),
+            indexed_messages: std::sync::atomic::AtomicUsize::new(0),
+            connection: std::sync::RwLock::new(None),
+            app_state_key_requests: KeyRequests::default(),
+            banned_until: std::sync::RwLock::new(None),
+            server_clock: ServerClock::new(),
+            unread: UnreadTracker::new(),
+            devices: DeviceCache::new(),
+            labels: LabelStore::new(),
+            business_tools: BusinessTools::new(),
+            close_cause: CloseCause::default(),
+            retries: RetryCounter::default(),
+            pairing: std::sync::Mutex::new(None),
+            pairing_changed: tokio::sync::Notify::new(),
+            frame_recorder: std::sync::RwLock::new(None),
+        }
+    }
+
+    /// Index a sent or received message by ID, and save it to the chat
+    /// store if one is attached.
+    pub(crate) fn record_message(&self, message: &StoredMessage) {
+        // Indexed even without a chat store, so replies to it resolve
+        self.index_message(message);
+        let chat_store = self.chat_store.read().unwrap().clone();
+        if let Some(chat_store) = chat_store {
+            if let Err(e) = chat_store.put_message(message) {
+                log::warn!("failed to store message {}: {}", message.id, e);
+            }
+        }
+    }
+
+    /// Index messages synced from history by ID, and save them to the chat
+    /// store in one batch if one is attached.
+    pub(crate) fn record_messages(&self, messages: &[StoredMessage]) {
+        if messages.is_empty() {
+            return;
+        }
+        for message in messages {
+            self.index_message(message);
+        }
+        let chat_store = self.chat_store.read().unwrap().clone();
+        if let Some(chat_store) = chat_store {
+            if let Err(e) = chat_store.put_messages(messages) {
+                log::warn!("failed to store {} synced messages: {}", messages.len(), e);
+            }
+        }
+    }
+
+    /// Read the push names and messages of a history sync payload into
+    /// the contact store, message index and chat store. Returns whether
+    /// the payload carried push names.
+    fn apply_history_payload(&self, data: &[u8]) -> bool {
+        self.record_messages(&parse_history_messages(data, self.own_jid().as_ref()));
+        match parse_history_pushnames(data) {
+            Some(updates) => {
+                self.update_contacts(updates);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Index a message by ID only, so replies to it resolve. Every
+    /// `MESSAGE_INDEX_PRUNE_EVERY` messages, those older than
+    /// `ClientConfig::message_index_ttl` are dropped from the index.
+    pub(crate) fn index_message(&self, message: &StoredMessage) {
+        if let Err(e) = self.store.put_message_ref(&MessageRef::from(message)) {
+            log::warn!("failed to index message {}: {}", message.id, e);
+        }
+        let indexed = self.indexed_messages.fetch_add(1, std::sync::atomic::Ordering::Relaxed) + 1;
+        if indexed.is_multiple_of(MESSAGE_INDEX_PRUNE_EVERY) {
+            let before = self.now().timestamp() - self.config.message_index_ttl.as_secs() as i64;
+            if let Err(e) = self.store.prune_message_refs(before) {
+                log::warn!("failed to prune message index: {}", e);
+            }
+        }
+    }
+
+    /// Disappearing message timer of a chat, from its settings.
+    pub(crate) fn chat_ephemeral(&self, chat: &JID) -> Option<Duration> {
+        match self.store.get_chat_settings(chat) {
+            Ok(settings) => settings.and_then(|settings| settings.ephemeral),
+            Err(e) => {
+                log::warn!("failed to read settings of {}: {}", self.redact(chat), e);
+                None
+            }
+        }
+    }
+
+    /// Record the disappearing message timer of a chat in its settings.
+    pub(crate) fn set_chat_ephemeral(&self, chat: &JID, timer: Option<Duration>) {
+        let result = self.store.get_chat_settings(chat).and_then(|settings| {
+            let settings = ChatSettings { ephemeral: timer, ..settings.unwrap_or_default() };
+            self.store.put_chat_settings(chat, &settings)
+        });
+        if let Err(e) = result {
+            log::warn!("failed to store timer of {}: {}", self.redact(chat), e);
+        }
+    }
+
+    /// Media cache to download media of `content` through. View-once
+    /// media is kept off disk unless `ClientConfig::store_view_once` is set.
+    pub(crate) fn media_cache_for(&self, content: &MessageContent) -> Option<Arc<MediaCache>> {
+        if content.is_view_once() && !self.config.store_view_once {
+            return None;
+        }
+        self.media_cache.read().unwrap().clone()
+    }
+
+    /// Mark the device lists of everyone a message to `chat` went to as
+    /// stale: the user, or the members of a group and our own account.
+    fn mark_recipients_stale(&self, chat: &JID) {
+        if !chat.is_group() {
+            self.devices.mark_stale(chat);
+            return;
+        }
+        if let Some(info) = self.groups.get(chat) {
+            for participant in &info.participants {
+                self.devices.mark_stale(&participant.jid);
+            }
+        }
+        self.groups.invalidate(chat);
+        if let Some(own) = self.own_jid() {
+            self.devices.mark_stale(&own);
+        }
+    }
+
+    /// Whether a node was sent from our own account, by any of its devices.
+    fn is_own_account(&self, node: &Node) -> bool {
+        node.parse_attr_jid("from").zip(self.own_jid())
+            .is_some_and(|(from, own)| from.to_non_ad() == own.to_non_ad())
+    }
+
+    /// JID of the device, if it is paired.
+    pub(crate) fn own_jid(&self) -> Option<JID> {
+        self.own_jid.read().unwrap().clone()
+    }
+
+    /// Current time, from `ClientConfig::clock`.
+    pub(crate) fn now(&self) -> chrono::DateTime<chrono::Utc> {
+        self.config.clock.now()
+    }
+
+    /// Current time on the server's clock: `now` corrected by the offset
+    /// learned from the server's timestamps.
+    pub(crate) fn server_now(&self) -> chrono::DateTime<chrono::Utc> {
+        self.server_clock.server_time(self.now())
+    }
+
+    /// New message ID, from `ClientConfig::rng`.
+    pub(crate) fn new_message_id(&self) -> MessageID {
+        generate_message_id_with(self.config.rng.as_ref())
+    }
+
+    /// Whether `ClientConfig::read_only` keeps a node from being sent.
+    pub(crate) fn is_held_back(&self, node: &Node) -> bool {
+        self.config.read_only
+            && match &*node.tag {
+                "message" | "receipt" | "presence" | "chatstate" | "call" => true,
+                "iq" => node.get_attr_str("type") == Some("set"),
+                _ => false,
+            }
+    }
+
+    /// Wrap a value for logging, honouring `ClientConfig::redact_logs`.
+    pub(crate) fn redact<'a, T: ?Sized>(&self, value: &'a T) -> Redacted<'a, T> {
+        Redacted::new(value, self.config.redact_logs)
+    }
+
+    /// Apply synced app state mutations to labels, business tools, contacts
+    /// and unread counts, returning the events to emit. Contacts are
+    /// written in one batch; mutations of other kinds are ignored.
+    pub(crate) fn apply_app_state_mutations(&self, mutations: &[AppStateMutation]) -> Vec<Event> {
+        let mut events = Vec::new();
+        let mut contacts = Vec::new();
+        for AppStateMutation { index, value, .. } in mutations {
+            if let Some(event) = parse_label_mutation(index, value) {
+                self.labels.handle_event(&event);
+                events.push(event);
+            } else if let Some(mutation) = parse_business_tools_mutation(index, value) {
+                self.business_tools.apply(mutation);
+            } else if let Some(update) = value.contact_action.as_ref().and_then(|action| parse_contact_mutation(index, action)) {
+                contacts.push(update);
+            } else if let Some((chat, read)) = value.mark_chat_as_read_action.as_ref()
+                .and_then(|action| parse_mark_chat_as_read_mutation(index, action))
+            {
+                events.extend(self.unread.set_read(&chat, read).map(Event::UnreadCountChanged));
+            }
+        }
+        if !contacts.is_empty() {
+            self.update_contacts(contacts);
+        }
+        events
+    }
+
+    /// Merge contact updates into the store, writing them in one batch.
+    pub(crate) fn update_contacts(&self, updates: impl IntoIterator<Item = ContactUpdate>) {
+        let mut contacts: Vec<ContactInfo> = Vec::new();
+        // Later updates of the same contact merge into the earlier ones
+        let mut positions: HashMap<JID, usize> = HashMap::new();
+        for update in updates {
+            if let Some(&i) = positions.get(&update.jid) {
+                contacts[i] = update.apply(Some(contacts[i].clone()));
+                continue;
+            }
+            match self.store.get_contact(&update.jid) {
+                Ok(contact) => {
+                    positions.insert(update.jid.clone(), contacts.len());
+                    contacts.push(update.apply(contact));
+                }
+                Err(e) => log::warn!("failed to read contact {}: {}", self.redact(&update.jid), e),
+            }
+        }
+        if let Err(e) = self.store.put_contacts(&contacts) {
+            log::warn!("failed to store {} contacts: {}", contacts.len(), e);
+        }
+    }
+
+    /// Get the store of scheduled messages.
+    pub(crate) fn schedule(&self) -> Arc<dyn ScheduleStore> {
+        self.schedule.read().unwrap().clone()
+    }
+
+    /// Get the attached outbox, if any.
+    pub(crate) fn outbox(&self) -> Option<Arc<dyn OutboxStore>> {
+        self.outbox.read().unwrap().clone()
+    }
+
+    /// Process a received node into the events it carries.
+    pub(crate) fn process_node(&self, node: &Node) -> Result<Vec<Event>, ClientError> {
+        match &*node.tag {
+            "message" => {
+                if let Some(sync) = parse_history_sync(node) {
+                    // Only our primary device syncs history, push names included
+                    if !self.is_own_account(node) {
+                        log::warn!("ignoring history sync sent by another account");
+                        return Ok(Vec::new());
+                    }
+                    let mut events = Vec::new();
+                    let has_push_names = !sync.data.is_empty() && self.apply_history_payload(&sync.data);
+                    if has_push_names && sync.sync_type == HistorySyncType::Push {
+                        let count = self.store.get_all_contacts().map(|c| c.len()).unwrap_or_default();
+                        events.push(Event::ContactsSynced(ContactsSynced { count }));
+                    }
+                    events.insert(0, Event::HistorySync(sync));
+                    return Ok(events);
+                }
+                if let Some(keys) = parse_app_state_key_share(node) {
+                    // Only our primary device shares keys
+                    if !self.is_own_account(node) {
+                        log::warn!("ignoring app state keys shared by another account");
+                        return Ok(Vec::new());
+                    }
+                    if let Err(e) = self.store.put_app_state_keys(&keys) {
+                        log::warn!("failed to store {} app state keys: {}", keys.len(), e);
+                    }
+                    self.app_state_key_requests.release(keys.iter().map(|key| &key.key_id));
+                    return Ok(Vec::new());
+                }
+
+                if let Some((chat, timer)) = parse_ephemeral_setting(node) {
+                    self.set_chat_ephemeral(&chat, timer);
+                    return Ok(Vec::new());
+                }
+                if let Some(kept) = parse_keep_message(node, self.server_now().timestamp()) {
+                    return Ok(vec![Event::KeptMessage(kept)]);
+                }
+
+                let Some(mut info) = parse_message_info(node, self.server_now().timestamp()) else {
+                    return Ok(vec![Event::UnhandledNode(node.clone())]);
+                };
+                info.is_from_me = self.own_jid().is_some_and(|own| own.to_non_ad() == info.sender.to_non_ad());
+                if has_undecryptable_enc(node) {
+                    return Ok(vec![Event::UndecryptableMessage(UndecryptableMessage { info })]);
+                }
+
+                let content = parse_secret_content(&*self.store, node, &info.chat, &info.sender)
+                    .unwrap_or_else(|| parse_message_content(node));
+                let msg = Message { info, content };
+
+                Ok(vec![Event::Message(msg)])
+            }
+            "receipt" => Ok(vec![Event::Receipt(parse_receipt(node, self.server_now().timestamp()))]),
+            "presence" => {
+                let Some(presence) = parse_presence(node) else {
+                    return Ok(vec![Event::UnhandledNode(node.clone())]);
+                };
+                self.presences.record(&presence);
+                Ok(vec![Event::Presence(presence)])
+            }
+            "ack" if node.get_attr_str("class") == Some("message") => {
+                // We sent to the wrong devices: fetch the recipients' again and
+                // send again, leaving the message in the outbox until the
+                // resend is acknowledged
+                if node.get_attr_str("error") == Some(DEVICE_MISMATCH_ERROR) {
+                    if let Some(chat) = node.parse_attr_jid("from") {
+                        self.mark_recipients_stale(&chat);
+                    }
+                    if let Some(id) = node.get_attr_str("id") {
+                        self.devices.queue_resend(id);
+                    }
+                    return Ok(Vec::new());
+                }
+                // The server has the message; it no longer needs retrying
+                if let (Some(outbox), Some(id)) = (self.outbox(), node.get_attr_str("id")) {
+                    if let Err(e) = outbox.ack_outgoing(id) {
+                        log::warn!("failed to remove message {} from outbox: {}", id, e);
+                    }
+                }
+                Ok(Vec::new())
+            }
+            // Chat states are refused until we have announced that we
+            // are online
+            "ack" if node.get_attr_str("class") == Some("chatstate") && node.get_attr_str("error").is_some() => {
+                Ok(vec![Event::PresenceRequired])
+            }
+            "success" => {
+                if let Some(t) = node.get_attr_int("t") {
+                    self.server_clock.record(t, self.now());
+                }
+                Ok(vec![Event::UnhandledNode(node.clone())])
+            }
+            "iq" if is_iq_result(node) || is_iq_error(node) => {
+                // Ping responses carry the server's time
+                if let Some(t) = node.get_attr_int("t") {
+                    self.server_clock.record(t, self.now());
+                }
+                // Route responses to pending queries
+                if let Some(id) = node.get_attr_str("id") {
+                    self.requests.complete(id, node.clone());
+                }
+                Ok(Vec::new())
+            }
+            "notification" if is_group_notification(node) => {
+                let changes = parse_participant_changes(node);
+                for change in changes.iter().filter(|c| c.action.is_departure()) {
+                    if let Err(e) = self.sender_keys.revoke(self.store.as_ref(), &change.group, &change.participants) {
+                        log::warn!("failed to revoke sender keys for {}: {}", self.redact(&change.group), e);
+                    }
+                }
+
+                let events: Vec<Event> = changes.into_iter().map(Event::GroupParticipants)
+                    .chain(parse_join_requests(node).into_iter().map(Event::GroupJoinRequest))
+                    .chain(parse_setting_changes(node).into_iter().map(Event::GroupSettingChanged))
+                    .chain(parse_description_change(node).map(Event::GroupDescriptionChanged))
+                    .collect();
+                for event in &events {
+                    self.groups.handle_event(event);
+                    if let Event::GroupSettingChanged(change) = event {
+                        if let GroupSetting::Ephemeral(timer) = &change.setting {
+                            self.set_chat_ephemeral(&change.group, *timer);
+                        }
+                    }
+                }
+                Ok(events)
+            }
+            "notification" if is_picture_notification(node) => {
+                Ok(parse_photo_changes(node).into_iter().map(Event::GroupPhotoChanged).collect())
+            }
+            "notification" if is_newsletter_notification(node) => Ok(parse_newsletter_updates(node)),
+            "notification" if is_devices_notification(node) => {
+                let Some(change) = parse_devices_notification(node) else {
+                    return Ok(vec![Event::UnhandledNode(node.clone())]);
+                };
+                for device in &change.removed {
+                    if let Err(e) = self.store.delete_session(&session_address(device)) {
+                        log::warn!("failed to delete session of {}: {}", self.redact(device), e);
+                    }
+                }
+                match &change.device_hash {
+                    Some(hash) => {
+                        self.devices.check_hash(&change.user, hash);
+                    }
+                    None if self.devices.get(&change.user).is_some() => self.devices.mark_stale(&change.user),
+                    None => {}
+                }
+                Ok(vec![Event::LinkedDevicesChanged(change)])
+            }
+            "call" => Ok(parse_call(node).into_iter().collect()),
+            "stream:error" | "failure" => {
+                // Follow fallback hints on the next connect
+                self.endpoints.lock().unwrap().add_hints(parse_endpoint_hints(node), self.config.prefer_ipv6);
+
+                // The server closes the socket next; report this instead
+                let reason = parse_stream_error(node);
+                let logged_out = reason == DisconnectReason::LoggedOut;
+                self.close_cause.record(reason);
+                if logged_out {
+                    return Ok(vec![Event::LoggedOut(logged_out_event(node))]);
+                }
+
+                let now = self.now();
+                if let Some(ban) = parse_ban_at(node, now) {
+                    let until = blocked_until(&ban, now);
+                    log::warn!("banned by the server (code {}) until {}", ban.code, until);
+                    *self.banned_until.write().unwrap() = Some(until);
+                    return Ok(vec![Event::Banned(ban)]);
+                }
+                if node.tag == "failure" {
+                    let code = node.get_attr_str("reason");
+                    if code == Some(CLIENT_OUTDATED_CODE) {
+                        return Ok(vec![Event::ClientOutdated]);
+                    }
+                    return Ok(vec![Event::ConnectFailure(ConnectFailure {
+                        code: code.map(str::to_string),
+                        message: node.get_attr_str("message").map(str::to_string),
+                    })]);
+                }
+                // Other errors are left to the application
+                Ok(vec![Event::UnhandledNode(node.clone())])
+            }
+            "ib" => {
+                // Remember where the server wants us to reconnect
+                let routing_info = node.get_child_by_tag("edge_routing")
+                    .and_then(|e| e.get_child_by_tag("routing_info"))
+                    .and_then(|r| r.get_bytes());
+                if let Some(routing_info) = routing_info {
+                    *self.routing_info.write().unwrap() = Some(routing_info.to_vec());
+                }
+                Ok(Vec::new())
+            }
+            "notification" if is_media_retry_notification(node) => {
+                // Route re-upload answers to pending downloads
+                if let Some(id) = node.get_attr_str("id") {
+                    self.media_retries.complete(id, node.clone());
+                }
+                Ok(Vec::new())
+            }
+            _ => Ok(vec![Event::UnhandledNode(node.clone())]),
+        }
+    }
+
+    /// Handle of the current connection, if connected.
+    pub(crate) fn connection(self: &Arc<Self>) -> Option<ClientHandle> {
+        let (commands, cancel) = self.connection.read().unwrap().clone()?;
+        Some(ClientHandle::new(self.clone(), commands, cancel)).filter(ClientHandle::is_connected)
+    }
+
+    /// Run an event through the middleware and emit what comes out to all
+    /// handlers, returning the emitted events.
+    ///
+    /// Emitted messages are saved to the chat store first, except view-once
+    /// ones unless `ClientConfig::store_view_once` is set.
+    pub(crate) fn emit_event(&self, event: Event) -> Vec<Event> {
+        let middleware = self.middleware.read().unwrap().clone();
+        let emitted = std::cell::RefCell::new(Vec::new());
+        run_pipeline(&middleware, event, &|event| {
+            if let Event::Message(msg) = &event {
+                if msg.content.is_view_once() && !self.config.store_view_once {
+                    self.index_message(&StoredMessage::from(msg));
+                } else {
+                    self.record_message(&StoredMessage::from(msg));
+                }
+            }
+            let handlers = self.event_handlers.read().unwrap();
+            for handler in handlers.iter() {
+                handler(event.clone());
+            }
+            emitted.borrow_mut().push(event);
+        });
+        emitted.into_inner()
+    }
+}
+
+/// Download media through `cache`, if given.
+async fn fetch_media(cache: Option<&MediaCache>, media: &DownloadableMedia) -> Result<Vec<u8>, ClientError> {
+    let data = match cache {
+        Some(cache) => cache.get_or_download(media).await?,
+        None => media.download().await?,
+    };
+    Ok(data)
+}
+
+/// WhatsApp client for connecting and messaging.
+pub struct Client {
+    /// State shared with handles and the connection actor
+    inner: Arc<ClientInner>,
+    /// Handle to the running connection (when connected)
+    handle: Option<ClientHandle>,
+    /// Events forwarded by the connection actor for `receive`
+    events: Option<mpsc::UnboundedReceiver<Event>>,
+    /// Connection actor task
+    actor: Option<JoinHandle<()>>,
+    /// Cancelled on shutdown to stop receiving and pending requests
+    cancel: CancellationToken,
+    /// Whether the connection was opened by `connect`, and so can be
+    /// opened again the same way
+    reconnectable: bool,
+    /// Reason of the last `Disconnected` event `receive` returned
+    last_disconnect: Option<DisconnectReason>,
+    /// Whether a `receive` was dropped while reconnecting, so the next one
+    /// carries on
+    reconnect_pending: bool,
 }
 
 /// Client errors.
@@ -62,6 +875,19 @@ pub enum ClientError {
     SendFailed(String),
     ReceiveFailed(String),
     StoreError(String),
+    IqFailed(IqError),
+    MediaFailed(MediaError),
+    InvalidMessageId(String),
+    TemplateFailed(TemplateError),
+    /// The server did not answer in time while connecting
+    ConnectTimeout(HandshakeStage),
+    /// The server banned or rate limited us until the given time
+    Banned(chrono::DateTime<chrono::Utc>),
+    /// A device's signed pre-key was not signed by its identity key
+    InvalidPreKeyBundle(JID),
+    /// A pre-send interceptor blocked the message, for the given reason
+    MessageBlocked(String),
+    Cancelled,
 }
 
 impl std::fmt::Display for ClientError {
@@ -75,12 +901,45 @@ impl std::fmt::Display for ClientError {
             ClientError::SendFailed(e) => write!(f, "send failed: {}", e),
             ClientError::ReceiveFailed(e) => write!(f, "receive failed: {}", e),
             ClientError::StoreError(e) => write!(f, "store error: {}", e),
+            ClientError::IqFailed(e) => write!(f, "iq failed: {}", e),
+            ClientError::MediaFailed(e) => write!(f, "media failed: {}", e),
+            ClientError::InvalidMessageId(id) => write!(f, "invalid message id {:?}", id),
+            ClientError::TemplateFailed(e) => write!(f, "template failed: {}", e),
+            ClientError::ConnectTimeout(stage) => write!(f, "timed out {}", stage),
+            ClientError::Banned(until) => write!(f, "banned until {}", until),
+            ClientError::InvalidPreKeyBundle(device) => write!(f, "invalid pre-key bundle from {}", device),
+            ClientError::MessageBlocked(reason) => write!(f, "message blocked: {}", reason),
+            ClientError::Cancelled => write!(f, "client was shut down"),
         }
     }
 }
 
 impl std::error::Error for ClientError {}
 
+impl From<IqError> for ClientError {
+    fn from(e: IqError) -> Self {
+        ClientError::IqFailed(e)
+    }
+}
+
+impl From<MediaError> for ClientError {
+    fn from(e: MediaError) -> Self {
+        ClientError::MediaFailed(e)
+    }
+}
+
+impl From<TemplateError> for ClientError {
+    fn from(e: TemplateError) -> Self {
+        ClientError::TemplateFailed(e)
+    }
+}
+
+impl From<InvalidMessageId> for ClientError {
+    fn from(e: InvalidMessageId) -> Self {
+        ClientError::InvalidMessageId(e.0)
+    }
+}
+
 impl Client {
     /// Create a new client with default configuration.
     pub fn new() -> Self {
@@ -89,246 +948,1637 @@ impl Client {
 
     /// Create a new client with custom configuration.
     pub fn with_config(config: ClientConfig) -> Self {
-        let mut device = Device::new();
-        device.initialize();
-
-        Self {
-            config,
-            device: Arc::new(RwLock::new(device)),
-            store: Arc::new(MemoryStore::new()),
-            socket: None,
-            connected: false,
-            event_handlers: Vec::new(),
-        }
+        Self::from_inner(ClientInner::new(config, Arc::new(MemoryStore::new())))
     }
 
     /// Create a new client with a custom store.
+    ///
+    /// A device stored by an earlier pairing is loaded from it, so the
+    /// client logs in as that device instead of pairing again.
     pub fn with_store<S: Store + 'static>(config: ClientConfig, store: S) -> Self {
-        let mut device = Device::new();
-        device.initialize();
+        Self::from_inner(ClientInner::new(config, Arc::new(store)))
+    }
 
+    fn from_inner(inner: ClientInner) -> Self {
         Self {
-            config,
-            device: Arc::new(RwLock::new(device)),
-            store: Arc::new(store),
-            socket: None,
-            connected: false,
-            event_handlers: Vec::new(),
+            inner: Arc::new(inner),
+            handle: None,
+            events: None,
+            actor: None,
+            cancel: CancellationToken::new(),
+            reconnectable: false,
+            last_disconnect: None,
+            reconnect_pending: false,
         }
     }
 
     /// Add an event handler.
+    ///
+    /// Handlers run on the connection task. To send from a handler, move a
+    /// cloned `ClientHandle` into it and spawn the send:
+    ///
+    /// ```ignore
+    /// let handle = client.handle().unwrap();
+    /// client.add_event_handler(move |event| {
+    ///     if let Event::Message(msg) = event {
+    ///         let handle = handle.clone();
+    ///         tokio::spawn(async move {
+    ///             let _ = handle.send_message(msg.info.chat, "pong").await;
+    ///         });
+    ///     }
+    /// });
+    /// ```
     pub fn add_event_handler<F>(&mut self, handler: F)
     where
         F: Fn(Event) + Send + Sync + 'static,
     {
-        self.event_handlers.push(Box::new(handler));
+        self.inner.event_handlers.write().unwrap().push(Box::new(handler));
+    }
+
+    /// Add a middleware stage run on every event before the handlers and
+    /// `receive`.
+    ///
+    /// Stages run in the order they were added. A stage can be a closure
+    /// taking the event and the rest of the pipeline:
+    ///
+    /// ```ignore
+    /// client.add_middleware(|event: Event, next: Next<'_>| {
+    ///     log::debug!("event: {:?}", event);
+    ///     next.run(event);
+    /// });
+    /// ```
+    pub fn add_middleware<M: EventMiddleware + 'static>(&mut self, middleware: M) {
+        self.inner.middleware.write().unwrap().push(Arc::new(middleware));
+    }
+
+    /// Add an interceptor run on every outgoing message before it is sent,
+    /// and for text messages before it is saved to the outbox.
+    ///
+    /// Besides text, interceptors see media captions, polls, buttons,
+    /// lists, products, keeps and auto-replies; `SendableMessage::kind`
+    /// tells them apart.
+    ///
+    /// Interceptors run in the order they were added. A blocked message is
+    /// not sent: the send fails with `ClientError::MessageBlocked` and
+    /// `Event::MessageBlocked` is emitted. An interceptor can be a closure:
+    ///
+    /// ```ignore
+    /// client.add_send_interceptor(|message: &mut SendableMessage| {
+    ///     message.text.push_str("\n-- sent by bot");
+    ///     SendDecision::Send
+    /// });
+    /// ```
+    pub fn add_send_interceptor<I: SendInterceptor + 'static>(&mut self, interceptor: I) {
+        self.inner.interceptors.write().unwrap().push(Arc::new(interceptor));
     }
 
     /// Connect to WhatsApp servers.
+    ///
+    /// With `ClientConfig::auto_reconnect`, `receive` connects again when
+    /// this connection drops.
     pub async fn connect(&mut self) -> Result<(), ClientError> {
-        if self.connected {
+        self.prepare_connect()?;
+        let socket = self.connect_socket().await?;
+        self.start(socket, false).await?;
+        self.reconnectable = true;
+        Ok(())
+    }
+
+    /// Connect over an already open transport, such as one using another
+    /// runtime, a proxy or a test double.
+    ///
+    /// The endpoint rotation is not used; the transport decides where it is
+    /// connected to. Such connections are not reconnected automatically.
+    pub async fn connect_with_transport<T: Transport + 'static>(&mut self, transport: T) -> Result<(), ClientError> {
+        self.prepare_connect()?;
+        let routing_info = self.inner.routing_info.read().unwrap().clone();
+        self.start(NoiseSocket::new(transport, routing_info.as_deref()), false).await?;
+        self.reconnectable = false;
+        Ok(())
+    }
+
+    /// Connect again after the connection dropped, doubling the wait
+    /// between failed attempts up to `RECONNECT_MAX_DELAY`, until one
+    /// succeeds, the client is banned or it is shut down.
+    async fn reconnect(&mut self) -> Result<(), ClientError> {
+        if let Some(actor) = self.actor.take() {
+            let _ = actor.await;
+        }
+        self.handle = None;
+        self.events = None;
+
+        let mut delay = RECONNECT_MIN_DELAY;
+        loop {
+            let attempt = match self.prepare_connect() {
+                Ok(()) => match self.connect_socket().await {
+                    Ok(socket) => self.start(socket, true).await,
+                    Err(e) => Err(e),
+                },
+                Err(e) => Err(e),
+            };
+            match attempt {
+                Ok(()) => return Ok(()),
+                Err(e @ ClientError::Banned(_)) => return Err(e),
+                Err(e) => log::warn!("failed to reconnect, retrying in {:?}: {}", delay, e),
+            }
+            tokio::select! {
+                _ = self.cancel.cancelled() => return Err(ClientError::Cancelled),
+                _ = tokio::time::sleep(delay) => {}
+            }
+            delay = (delay * 2).min(RECONNECT_MAX_DELAY);
+        }
+    }
+
+    fn prepare_connect(&mut self) -> Result<(), ClientError> {
+        if self.is_connected() {
             return Err(ClientError::AlreadyConnected);
         }
+        if let Some(until) = self.banned_until() {
+            return Err(ClientError::Banned(until));
+        }
 
-        // Connect WebSocket
-        let mut socket = NoiseSocket::connect(&self.config.endpoint)
-            .await
-            .map_err(|e| ClientError::ConnectionFailed(e.to_string()))?;
+        // A previous shutdown leaves the token cancelled
+        if self.cancel.is_cancelled() {
+            self.cancel = CancellationToken::new();
+        }
+        Ok(())
+    }
 
+    /// Run the handshake on a new socket and hand it to a connection actor.
+    async fn start<T: Transport + 'static>(&mut self, mut socket: NoiseSocket<T>, is_reconnect: bool) -> Result<(), ClientError> {
         // Perform Noise handshake
-        let device = self.device.read().await;
+        let device = self.inner.device.read().await;
         let noise_key = device.noise_key.clone()
             .ok_or(ClientError::HandshakeFailed("no noise key".to_string()))?;
+        let client_payload = self.inner.config.client_payload(&device);
+        let registered = device.is_registered();
         drop(device);
 
-        let _remote_static = socket.handshake(noise_key)
+        let _remote_static = socket.handshake_with_payload(noise_key, &self.inner.config.handshake, &client_payload)
             .await
-            .map_err(|e| ClientError::HandshakeFailed(e.to_string()))?;
+            .map_err(|e| match e {
+                SocketError::Timeout(stage, _) => ClientError::ConnectTimeout(stage),
+                e => ClientError::HandshakeFailed(e.to_string()),
+            })?;
 
-        self.socket = Some(socket);
-        self.connected = true;
+        // Hand the socket over to the connection actor. Nothing below
+        // awaits, so a dropped `receive` cannot leave it half started.
+        self.inner.tags.reset_with(self.inner.config.rng.as_ref());
+        let connection_cancel = self.cancel.child_token();
+        let (command_tx, command_rx) = mpsc::channel(COMMAND_BUFFER);
+        let (event_tx, event_rx) = mpsc::unbounded_channel();
+
+        let actor = ConnectionActor::new(
+            self.inner.clone(),
+            socket,
+            command_rx,
+            event_tx,
+            connection_cancel.clone(),
+        );
 
         // Emit connected event
-        self.emit_event(Event::Connected(crate::types::Connected {
-            is_reconnect: false,
+        actor.emit(Event::Connected(crate::types::Connected {
+            is_reconnect,
         }));
 
+        self.actor = Some(tokio::spawn(actor.run()));
+        *self.inner.connection.write().unwrap() = Some((command_tx.clone(), connection_cancel.clone()));
+        let handle = ClientHandle::new(self.inner.clone(), command_tx, connection_cancel);
+        self.events = Some(event_rx);
+
+        // Retry sends left unacknowledged by a previous run
+        if self.inner.outbox().is_some() {
+            let handle = handle.clone();
+            tokio::spawn(async move { handle.resend_outbox().await });
+        }
+        // Send scheduled messages, starting with any that came due meanwhile
+        {
+            let handle = handle.clone();
+            tokio::spawn(async move { handle.run_scheduler().await });
+        }
+        {
+            let handle = handle.clone();
+            tokio::spawn(async move { handle.run_device_refresh().await });
+        }
+        if self.inner.config.send_presence_on_connect && registered {
+            let handle = handle.clone();
+            tokio::spawn(async move {
+                if let Err(e) = handle.send_presence(true).await {
+                    log::warn!("failed to send presence on connect: {}", e);
+                }
+            });
+        }
+        // Show QR codes as the server sends pairing refs
+        if !registered {
+            let handle = handle.clone();
+            tokio::spawn(async move { handle.run_qr_codes().await });
+        }
+        self.handle = Some(handle);
+        self.reconnect_pending = false;
+
         Ok(())
     }
 
+    /// Open a socket to the first endpoint that accepts the connection.
+    ///
+    /// Failed endpoints are moved to the back of the rotation and the one
+    /// that worked is tried first next time.
+    async fn connect_socket(&self) -> Result<NoiseSocket, ClientError> {
+        let routing_info = self.inner.routing_info.read().unwrap().clone();
+        let candidates = self.inner.endpoints.lock().unwrap().candidates();
+
+        let connect_timeout = self.inner.config.handshake.connect_timeout;
+        let mut websocket = self.inner.config.websocket.clone();
+        websocket.user_agent.get_or_insert_with(|| self.inner.config.user_agent.clone());
+        let mut last_error = None;
+        for endpoint in candidates {
+            let connect = NoiseSocket::connect_to(
+                &endpoint,
+                self.inner.config.prefer_ipv6,
+                &websocket,
+                routing_info.as_deref(),
+            );
+            let result = tokio::time::timeout(connect_timeout, connect).await
+                .unwrap_or(Err(SocketError::Timeout(HandshakeStage::Connect, connect_timeout)));
+            match result {
+                Ok(socket) => {
+                    self.inner.endpoints.lock().unwrap().record_success(&endpoint);
+                    return Ok(socket);
+                }
+                Err(e) => {
+                    log::warn!("failed to connect to {}: {}", endpoint, e);
+                    self.inner.endpoints.lock().unwrap().record_failure(&endpoint);
+                    last_error = Some(e);
+                }
+            }
+        }
+
+        Err(match last_error {
+            Some(SocketError::Timeout(stage, _)) => ClientError::ConnectTimeout(stage),
+            Some(e) => ClientError::ConnectionFailed(e.to_string()),
+            None => ClientError::ConnectionFailed("no endpoints configured".to_string()),
+        })
+    }
+
+    /// Get the current time on the server's clock.
+    ///
+    /// The offset of the server's clock from `ClientConfig::clock` is
+    /// learned from the timestamps of the login success and of ping
+    /// responses; until one arrives this is the local time. Timestamps of
+    /// sent messages and expirations of QR codes use it.
+    pub fn server_time(&self) -> chrono::DateTime<chrono::Utc> {
+        self.inner.server_now()
+    }
+
+    /// Get when the current ban ends, if the server banned or rate limited
+    /// us. `connect` fails until then.
+    pub fn banned_until(&self) -> Option<chrono::DateTime<chrono::Utc>> {
+        let until = *self.inner.banned_until.read().unwrap();
+        until.filter(|until| *until > self.inner.now())
+    }
+
+    /// Get the endpoint of the last successful connection, if any.
+    pub fn last_endpoint(&self) -> Option<Endpoint> {
+        self.inner.endpoints.lock().unwrap().last_success().cloned()
+    }
+
     /// Disconnect from WhatsApp servers.
+    ///
+    /// Queued outgoing nodes are flushed before the socket is closed, and
+    /// `Disconnected` is emitted with `DisconnectReason::UserRequested`.
     pub async fn disconnect(&mut self) -> Result<(), ClientError> {
-        if let Some(ref mut socket) = self.socket {
-            socket.close().await
+        if let Some(handle) = self.handle.take() {
+            self.inner.close_cause.record(DisconnectReason::UserRequested);
+            handle.close();
+        }
+        if let Some(actor) = self.actor.take() {
+            actor.await
                 .map_err(|e| ClientError::SendFailed(e.to_string()))?;
         }
-        
-        self.socket = None;
-        self.connected = false;
+        self.events = None;
+        self.reconnect_pending = false;
+        Ok(())
+    }
 
-        self.emit_event(Event::Disconnected(crate::types::Disconnected {
-            reason: crate::types::DisconnectReason::LoggedOut,
-        }));
+    /// Shut down the client.
+    ///
+    /// Cancels the shared cancellation token so any in-progress `receive` or
+    /// `query` returns, resolves all pending IQ requests with
+    /// `IqError::Cancelled`, drains the send queue and closes the socket
+    /// gracefully.
+    pub async fn shutdown(&mut self) -> Result<(), ClientError> {
+        self.cancel.cancel();
+        self.inner.requests.cancel_all();
+        self.inner.media_retries.cancel_all();
+
+        if self.handle.is_some() {
+            self.disconnect().await?;
+        }
 
         Ok(())
     }
 
+    /// Get a token that is cancelled when the client shuts down.
+    ///
+    /// Cancelling the returned token from another task stops the receive
+    /// loop the same way `shutdown` does.
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.cancel.clone()
+    }
+
+    /// Get a cloneable handle for sending from other tasks.
+    ///
+    /// Returns `None` while disconnected. Handles stop working once the
+    /// connection they were created for is closed.
+    pub fn handle(&self) -> Option<ClientHandle> {
+        self.handle.clone()
+    }
+
     /// Check if connected.
     pub fn is_connected(&self) -> bool {
-        self.connected
+        self.handle.as_ref().is_some_and(|h| h.is_connected())
     }
 
     /// Check if logged in (has JID).
     pub async fn is_logged_in(&self) -> bool {
-        let device = self.device.read().await;
+        let device = self.inner.device.read().await;
         device.is_registered()
     }
 
-    /// Get the device JID.
-    pub async fn get_jid(&self) -> Option<JID> {
-        let device = self.device.read().await;
-        device.jid.clone()
+    /// Get the QR code currently linking this device, or `None` if the
+    /// server has not started pairing or it has ended.
+    pub async fn pairing_qr(&self) -> Option<String> {
+        let pairing = self.inner.pairing.lock().unwrap();
+        pairing.as_ref().and_then(|p| p.current_code()).map(String::from)
     }
 
-    /// Send a text message.
-    pub async fn send_message(&mut self, to: JID, text: &str) -> Result<String, ClientError> {
-        if !self.connected {
-            return Err(ClientError::NotConnected);
-        }
+    /// Get the data store backing this client.
+    pub fn store(&self) -> Arc<dyn Store> {
+        self.inner.store.clone()
+    }
 
-        // Generate message ID
-        let message_id = format!("{:X}", rand::random::<u64>());
+    /// Get a contact from the store.
+    ///
+    /// Contacts are filled in from history sync push names as they arrive;
+    /// other sources can be merged in with `update_contacts`.
+    pub fn get_contact(&self, jid: &JID) -> Result<Option<ContactInfo>, ClientError> {
+        self.inner.store.get_contact(&jid.to_non_ad())
+            .map_err(|e| ClientError::StoreError(e.to_string()))
+    }
 
-        // Build message node
-        let mut node = Node::new("message");
-        node.set_attr("id", message_id.clone());
-        node.set_attr("type", "text");
-        node.set_attr("to", to.to_string());
-
-        let mut body = Node::new("body");
-        body.set_bytes(text.as_bytes().to_vec());
-        node.add_child(body);
-
-        // Encode and send
-        let data = encode(&node);
-        
-        if let Some(ref mut socket) = self.socket {
-            socket.send(&data)
-                .await
-                .map_err(|e| ClientError::SendFailed(e.to_string()))?;
-        }
+    /// Get all contacts in the store.
+    pub fn get_all_contacts(&self) -> Result<Vec<ContactInfo>, ClientError> {
+        self.inner.store.get_all_contacts()
+            .map_err(|e| ClientError::StoreError(e.to_string()))
+    }
 
-        Ok(message_id)
+    /// Merge contact updates, such as those from `parse_contact_mutation`
+    /// or `parse_usync_contacts`, into the store.
+    pub fn update_contacts(&self, updates: impl IntoIterator<Item = ContactUpdate>) {
+        self.inner.update_contacts(updates)
     }
 
-    /// Receive and process incoming data.
-    pub async fn receive(&mut self) -> Result<Option<Event>, ClientError> {
-        if !self.connected {
-            return Err(ClientError::NotConnected);
-        }
+    /// Attach a chat store to keep the history of sent and received messages.
+    pub fn set_chat_store<C: ChatStore + 'static>(&mut self, chat_store: C) {
+        *self.inner.chat_store.write().unwrap() = Some(Arc::new(chat_store));
+    }
 
-        let socket = self.socket.as_mut().ok_or(ClientError::NotConnected)?;
-        
-        let data = socket.recv()
-            .await
-            .map_err(|e| ClientError::ReceiveFailed(e.to_string()))?;
+    /// Get the attached chat store, if any.
+    pub fn chat_store(&self) -> Option<Arc<dyn ChatStore>> {
+        self.inner.chat_store.read().unwrap().clone()
+    }
 
-        // Decode the node
-        let node = decode(&data)
-            .map_err(|e| ClientError::ReceiveFailed(e.to_string()))?;
+    /// Attach a durable outbox so messages accepted by `send_message`
+    /// survive a crash.
+    ///
+    /// Messages are saved before they are sent and removed when the server
+    /// acknowledges them. Whatever is left is sent again on the next
+    /// `connect`, unless it is older than `ClientConfig::outbox_max_age`.
+    pub fn set_outbox<O: OutboxStore + 'static>(&mut self, outbox: O) {
+        *self.inner.outbox.write().unwrap() = Some(Arc::new(outbox));
+    }
 
-        // Process node based on tag
-        let event = self.process_node(&node)?;
-        
-        if let Some(ref evt) = event {
-            self.emit_event(evt.clone());
-        }
+    /// Get the attached outbox, if any.
+    pub fn outbox(&self) -> Option<Arc<dyn OutboxStore>> {
+        self.inner.outbox()
+    }
 
-        Ok(event)
+    /// Keep scheduled messages in `store` instead of in memory, so they
+    /// survive a restart.
+    pub fn set_schedule_store<S: ScheduleStore + 'static>(&mut self, store: S) {
+        *self.inner.schedule.write().unwrap() = Arc::new(store);
+        self.inner.schedule_changed.notify_one();
     }
 
-    /// Process a received node.
-    fn process_node(&self, node: &Node) -> Result<Option<Event>, ClientError> {
-        match node.tag.as_str() {
-            "message" => {
-                // Parse message
-                let id = node.get_attr_str("id").unwrap_or("").to_string();
-                let from_str = node.get_attr_str("from").unwrap_or("");
-                let from: JID = from_str.parse().unwrap_or_default();
-
-                // Get body content
-                let body = node.get_child_by_tag("body")
-                    .and_then(|b| b.get_bytes())
-                    .map(|b| String::from_utf8_lossy(b).to_string())
-                    .unwrap_or_default();
-
-                let msg = Message {
-                    info: MessageInfo {
-                        id,
-                        sender: from.clone(),
-                        chat: from,
-                        is_from_me: false,
-                        is_group: false,
-                        timestamp: chrono::Utc::now().timestamp(),
-                        push_name: None,
-                    },
-                    content: MessageContent::Text(body),
-                };
+    /// Schedule a text message to be sent at `at`.
+    ///
+    /// Returns the schedule ID, which becomes the message ID once sent.
+    /// Messages are sent while connected; ones that come due while
+    /// disconnected are sent on the next `connect`. A
+    /// `ScheduledMessageSent` event is emitted for each sent message.
+    pub fn schedule_message(
+        &self,
+        at: chrono::DateTime<chrono::Utc>,
+        to: JID,
+        text: &str,
+    ) -> Result<String, ClientError> {
+        let message = ScheduledMessage {
+            id: self.inner.new_message_id(),
+            to,
+            text: text.to_string(),
+            send_at: at.timestamp(),
+        };
+        self.inner.schedule().put_scheduled(&message)
+            .map_err(|e| ClientError::StoreError(e.to_string()))?;
+        self.inner.schedule_changed.notify_one();
+        Ok(message.id)
+    }
 
-                Ok(Some(Event::Message(msg)))
-            }
-            "receipt" => {
-                // Parse receipt
-                let receipt = crate::types::Receipt {
-                    message_ids: vec![node.get_attr_str("id").unwrap_or("").to_string()],
-                    chat: node.get_attr_str("from").unwrap_or("").parse().unwrap_or_default(),
-                    sender: node.get_attr_str("participant").unwrap_or("").parse().unwrap_or_default(),
-                    receipt_type: match node.get_attr_str("type") {
-                        Some("read") => crate::types::ReceiptType::Read,
-                        Some("played") => crate::types::ReceiptType::Played,
-                        _ => crate::types::ReceiptType::Delivered,
-                    },
-                    timestamp: chrono::Utc::now().timestamp(),
-                };
+    /// Cancel a scheduled message. Returns false if it was already sent or
+    /// cancelled.
+    pub fn cancel_scheduled(&self, id: &str) -> Result<bool, ClientError> {
+        let removed = self.inner.schedule().remove_scheduled(id)
+            .map_err(|e| ClientError::StoreError(e.to_string()))?;
+        self.inner.schedule_changed.notify_one();
+        Ok(removed)
+    }
 
-                Ok(Some(Event::Receipt(receipt)))
-            }
-            _ => Ok(None),
-        }
+    /// Get the messages waiting to be sent, earliest first.
+    pub fn scheduled_messages(&self) -> Result<Vec<ScheduledMessage>, ClientError> {
+        self.inner.schedule().pending_scheduled()
+            .map_err(|e| ClientError::StoreError(e.to_string()))
+    }
+
+    /// Answer incoming messages automatically, such as with an away text.
+    ///
+    /// The responder runs as the last middleware stage, seeing messages
+    /// as the middleware added before it passes them on, and replies with
+    /// `ClientHandle::send_message`, so replies go through the send
+    /// interceptors and outbox. Replaces any previous responder.
+    pub fn set_auto_responder(&mut self, responder: AutoResponder) {
+        self.remove_auto_responder();
+        let stage: Arc<dyn EventMiddleware> = Arc::new(AutoReplyMiddleware::new(responder, &self.inner));
+        self.inner.middleware.write().unwrap().push(stage.clone());
+        *self.inner.auto_reply.write().unwrap() = Some(stage);
     }
 
-    /// Emit an event to all handlers.
-    fn emit_event(&self, event: Event) {
-        for handler in &self.event_handlers {
-            handler(event.clone());
+    /// Stop answering incoming messages automatically.
+    pub fn remove_auto_responder(&mut self) {
+        if let Some(stage) = self.inner.auto_reply.write().unwrap().take() {
+            self.inner.middleware.write().unwrap().retain(|other| !Arc::ptr_eq(other, &stage));
         }
     }
-}
 
-impl Default for Client {
-    fn default() -> Self {
-        Self::new()
+    /// Attach a cache so repeated downloads of the same file are served
+    /// from disk.
+    pub fn set_media_cache(&mut self, cache: MediaCache) {
+        *self.inner.media_cache.write().unwrap() = Some(Arc::new(cache));
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// Download incoming media the policy allows as it arrives, reporting
+    /// each saved file with `Event::MediaDownloaded`.
+    ///
+    /// Messages are delivered as usual while their media downloads in the
+    /// background; messages dropped by middleware are not downloaded.
+    /// Failed downloads are logged.
+    pub fn set_media_auto_download(&mut self, policy: MediaAutoDownloadPolicy) {
+        *self.inner.auto_download.write().unwrap() = Some(Arc::new(policy));
+    }
 
-    #[test]
-    fn test_client_creation() {
-        let client = Client::new();
-        assert!(!client.is_connected());
+    /// Stop downloading incoming media automatically.
+    pub fn remove_media_auto_download(&mut self) {
+        *self.inner.auto_download.write().unwrap() = None;
     }
 
-    #[test]
-    fn test_client_with_config() {
-        let config = ClientConfig {
-            endpoint: "wss://custom.endpoint".to_string(),
-            ..Default::default()
-        };
-        let client = Client::with_config(config);
-        assert!(!client.is_connected());
+    /// Record the decrypted frames of every connection, for replaying
+    /// them with `replay`.
+    pub fn set_frame_recorder(&mut self, recorder: Arc<FrameRecorder>) {
+        *self.inner.frame_recorder.write().unwrap() = Some(recorder);
+    }
+
+    /// Stop recording frames.
+    pub fn remove_frame_recorder(&mut self) {
+        *self.inner.frame_recorder.write().unwrap() = None;
+    }
+
+    /// Feed the received frames of a log through the node dispatcher,
+    /// as if they arrived on a connection, returning the events that made
+    /// it through the middleware. Handlers see the events too.
+    ///
+    /// Nothing is sent, so frames that would be answered, such as pairing
+    /// queries, only produce the events of their parsing.
+    pub fn replay(&self, log: &FrameLog) -> Vec<Event> {
+        let mut emitted = Vec::new();
+        for data in log.received() {
+            let node = match decode(data) {
+                Ok(node) => node,
+                Err(e) => {
+                    log::warn!("failed to decode replayed frame: {}", e);
+                    continue;
+                }
+            };
+            match self.inner.process_node(&node) {
+                Ok(events) => {
+                    for event in events {
+                        emitted.extend(self.inner.emit_event(event));
+                    }
+                }
+                Err(e) => log::warn!("failed to process replayed <{}> node: {}", node.tag, e),
+            }
+        }
+        emitted
+    }
+
+    /// Get the attached media cache, if any.
+    pub fn media_cache(&self) -> Option<Arc<MediaCache>> {
+        self.inner.media_cache.read().unwrap().clone()
+    }
+
+    /// Download and decrypt the media of a message.
+    ///
+    /// Uses the media cache when one is attached, except for view-once
+    /// media unless `ClientConfig::store_view_once` is set. If the direct
+    /// path has expired, the sender is asked to re-upload the file and the
+    /// download is retried from the new path.
+    pub async fn download_media(&self, msg: &Message) -> Result<Vec<u8>, ClientError> {
+        let mut media = DownloadableMedia::from_content(&msg.content).ok_or(MediaError::MissingUrl)?;
+        let cache = self.inner.media_cache_for(&msg.content);
+        match fetch_media(cache.as_deref(), &media).await {
+            Err(ClientError::MediaFailed(e)) if is_expired_media_error(&e) && media.details.media_key.is_some() => {
+                let direct_path = self.request_media_retry(&msg.info, &media).await?;
+                media.details.direct_path = Some(direct_path);
+                fetch_media(cache.as_deref(), &media).await
+            }
+            result => result,
+        }
+    }
+
+    /// Get the payload of a history sync, decompressed and ready to decode
+    /// as a `proto::HistorySyncPayload`. Payloads that were not sent
+    /// inline, such as the chunks of a full sync, are downloaded.
+    ///
+    /// Push names and messages in downloaded payloads are merged into the
+    /// contact store, message index and chat store, as those of inline
+    /// payloads are when they arrive.
+    pub async fn download_history_sync(&self, sync: &HistorySync) -> Result<Vec<u8>, ClientError> {
+        let Some(media) = sync.downloadable() else {
+            return Ok(decompress_history_sync(&sync.data));
+        };
+        let data = decompress_history_sync(&fetch_media(self.media_cache().as_deref(), &media).await?);
+        self.inner.apply_history_payload(&data);
+        Ok(data)
+    }
+
+    /// Ask the sender to re-upload media and wait for the new direct path.
+    async fn request_media_retry(&self, info: &MessageInfo, media: &DownloadableMedia) -> Result<String, ClientError> {
+        let media_key = media.details.media_key.as_deref().ok_or(MediaError::MissingUrl)?;
+        let own_jid = self.get_jid().await.ok_or(ClientError::NotLoggedIn)?;
+        let target = MediaRetryTarget {
+            message_id: info.id.clone(),
+            chat: info.chat.clone(),
+            sender: info.is_group.then(|| info.sender.clone()),
+            is_from_me: info.is_from_me,
+        };
+        let receipt = build_media_retry_receipt(&target, &own_jid, media_key)?;
+
+        let retries = &self.inner.media_retries;
+        let rx = retries.register(&info.id);
+        if let Err(e) = self.connection()?.send_node(receipt).await {
+            retries.cancel(&info.id);
+            return Err(e);
+        }
+
+        let notification = match tokio::time::timeout(self.inner.config.request_timeout, rx).await {
+            Ok(Ok(node)) => node,
+            Ok(Err(_)) => return Err(ClientError::Cancelled),
+            Err(_) => {
+                retries.cancel(&info.id);
+                return Err(MediaError::RequestFailed("media retry timed out".to_string()).into());
+            }
+        };
+
+        match parse_media_retry_notification(&notification, media_key)? {
+            MediaRetryResult::Success { direct_path } => Ok(direct_path),
+            result => Err(MediaError::RequestFailed(format!("media retry failed: {:?}", result)).into()),
+        }
+    }
+
+    /// Download the media of a message to a file, reporting progress.
+    ///
+    /// The returned transfer can be cancelled; partial files are removed.
+    pub fn download_media_to_file<F>(
+        &self,
+        content: &MessageContent,
+        path: impl Into<PathBuf>,
+        progress: F,
+    ) -> Result<Transfer<PathBuf>, ClientError>
+    where
+        F: Fn(TransferProgress) + Send + Sync + 'static,
+    {
+        let media = DownloadableMedia::from_content(content).ok_or(MediaError::MissingUrl)?;
+        Ok(media.download_to_file(path, progress))
+    }
+
+    /// Encrypt and upload a media file, reporting progress.
+    ///
+    /// The returned transfer can be cancelled. Its result holds the
+    /// details needed to send the media in a message.
+    pub async fn upload_media<F>(
+        &self,
+        data: Vec<u8>,
+        media_type: MediaType,
+        progress: F,
+    ) -> Result<Transfer<UploadedMedia>, ClientError>
+    where
+        F: Fn(TransferProgress) + Send + Sync + 'static,
+    {
+        self.connection()?.upload_media(data, media_type, progress).await
+    }
+
+    /// Search the chat history for messages containing every word of the
+    /// query, best matches first.
+    ///
+    /// Requires a chat store; see `set_chat_store`.
+    pub fn search_messages(
+        &self,
+        query: &str,
+        chat: Option<&JID>,
+        limit: usize,
+    ) -> Result<Vec<MessageMatch>, ClientError> {
+        let chat_store = self.chat_store()
+            .ok_or_else(|| ClientError::StoreError("no chat store attached".to_string()))?;
+        chat_store.search(query, chat, limit)
+            .map_err(|e| ClientError::StoreError(e.to_string()))
+    }
+
+    /// Get the device JID.
+    pub async fn get_jid(&self) -> Option<JID> {
+        let device = self.inner.device.read().await;
+        device.jid.clone()
+    }
+
+    /// Send a text message.
+    pub async fn send_message(&self, to: JID, text: &str) -> Result<String, ClientError> {
+        self.connection()?.send_message(to, text).await
+    }
+
+    /// Send a text message with a caller-chosen ID.
+    ///
+    /// See `ClientHandle::send_message_with_id`.
+    pub async fn send_message_with_id(&self, to: JID, text: &str, id: &str) -> Result<String, ClientError> {
+        self.connection()?.send_message_with_id(to, text, id).await
+    }
+
+    /// Mark messages in a chat as read.
+    ///
+    /// See `ClientHandle::mark_read`.
+    pub async fn mark_read(&self, chat: &JID, message_ids: &[String]) -> Result<(), ClientError> {
+        self.connection()?.mark_read(chat, message_ids).await
+    }
+
+    /// Announce whether we are online.
+    ///
+    /// See `ClientHandle::send_presence`.
+    pub async fn send_presence(&self, available: bool) -> Result<(), ClientError> {
+        self.connection()?.send_presence(available).await
+    }
+
+    /// Get the last presence received from a user, if it is more recent
+    /// than `ClientConfig::presence_ttl`.
+    pub fn get_cached_presence(&self, jid: &JID) -> Option<Presence> {
+        self.inner.presences.get(jid)
+    }
+
+    /// Get the cached devices of a user, as last fetched by
+    /// `get_user_devices`.
+    pub fn get_cached_devices(&self, user: &JID) -> Option<Vec<JID>> {
+        self.inner.devices.get(user)
+    }
+
+    /// Get the cached metadata of a group, as last fetched by
+    /// `get_group_info`, unless it is older than
+    /// `ClientConfig::group_cache_ttl`.
+    pub fn get_cached_group_info(&self, group: &JID) -> Option<GroupInfo> {
+        self.inner.groups.get(group)
+    }
+
+    /// Get the labels of the account.
+    pub fn get_labels(&self) -> Vec<Label> {
+        self.inner.labels.labels()
+    }
+
+    /// Get the IDs of the labels on a chat or message.
+    pub fn get_labels_of(&self, target: &LabelTarget) -> Vec<String> {
+        self.inner.labels.labels_of(target)
+    }
+
+    /// Apply mutations synced through app state, such as those from
+    /// `decode_sync_action`: labels, quick replies, greeting and away
+    /// messages, contacts and chats read on another device. Emits
+    /// `LabelEdit`, `LabelAssociation` and `UnreadCountChanged` events.
+    pub async fn apply_app_state_mutations(&self, mutations: &[AppStateMutation]) {
+        for event in self.inner.apply_app_state_mutations(mutations) {
+            self.emit(event).await;
+        }
+    }
+
+    /// Describe creating, changing or deleting a label as an app state
+    /// patch, timestamped with the server's clock.
+    ///
+    /// The patch is neither encrypted nor sent, and the labels here do not
+    /// change; see `labels` for what is left to do with it.
+    pub fn label_edit_patch(&self, label: &Label) -> AppStatePatch {
+        build_label_edit_patch(label, self.inner.server_now().timestamp_millis())
+    }
+
+    /// Describe putting a label on a chat, or with `labeled` false taking
+    /// it off, as an app state patch. Like `label_edit_patch`, nothing is
+    /// sent or changed here.
+    pub fn label_chat_patch(&self, chat: &JID, label_id: &str, labeled: bool) -> AppStatePatch {
+        build_label_chat_patch(chat, label_id, labeled, self.inner.server_now().timestamp_millis())
+    }
+
+    /// Describe putting a label on a message, or with `labeled` false
+    /// taking it off, as an app state patch. Like `label_edit_patch`,
+    /// nothing is sent or changed here.
+    pub fn label_message_patch(&self, chat: &JID, message_id: &str, label_id: &str, labeled: bool) -> AppStatePatch {
+        let now = self.inner.server_now().timestamp_millis();
+        build_label_message_patch(chat, message_id, label_id, labeled, now)
+    }
+
+    /// Get the quick replies of a business account, as synced from app
+    /// state.
+    pub fn get_quick_replies(&self) -> Vec<QuickReply> {
+        self.inner.business_tools.quick_replies()
+    }
+
+    /// Get the greeting message settings of a business account, if synced.
+    pub fn get_greeting_message(&self) -> Option<GreetingMessage> {
+        self.inner.business_tools.greeting()
+    }
+
+    /// Get the away message settings of a business account, if synced.
+    pub fn get_away_message(&self) -> Option<AwayMessage> {
+        self.inner.business_tools.away()
+    }
+
+    /// Number of messages received in a chat since it was last read, here
+    /// or on another device.
+    pub fn get_unread_count(&self, chat: &JID) -> u32 {
+        self.inner.unread.get(chat).unread_count
+    }
+
+    /// Get the read state of a chat, including the last message read.
+    pub fn get_read_state(&self, chat: &JID) -> ChatReadState {
+        self.inner.unread.get(chat)
+    }
+
+    /// Apply a chat read or marked unread on another device, such as one
+    /// from `parse_mark_chat_as_read_mutation`, emitting
+    /// `UnreadCountChanged` if the count changed.
+    pub async fn apply_chat_read(&self, chat: &JID, read: bool) {
+        if let Some(change) = self.inner.unread.set_read(chat, read) {
+            self.emit(Event::UnreadCountChanged(change)).await;
+        }
+    }
+
+    /// Deliver an event raised outside the connection: through the actor
+    /// while connected, so `receive` sees it, and to the middleware and
+    /// handlers directly otherwise.
+    async fn emit(&self, event: Event) {
+        match &self.handle {
+            Some(handle) if handle.is_connected() => handle.emit(event).await,
+            _ => {
+                self.inner.emit_event(event);
+            }
+        }
+    }
+
+    /// Ask the primary device for older messages of a chat.
+    ///
+    /// See `ClientHandle::request_history`.
+    pub async fn request_history(&self, chat: &JID, before: i64, count: u32) -> Result<String, ClientError> {
+        self.connection()?.request_history(chat, before, count).await
+    }
+
+    /// Get an app state key, asking the primary device for it if missing.
+    ///
+    /// See `ClientHandle::get_app_state_key`.
+    pub async fn get_app_state_key(&self, key_id: &[u8]) -> Result<Option<AppStateSyncKey>, ClientError> {
+        self.connection()?.get_app_state_key(key_id).await
+    }
+
+    /// Show or clear "typing..." in a chat.
+    pub async fn send_chat_state(&self, to: &JID, composing: bool) -> Result<(), ClientError> {
+        self.connection()?.send_chat_state(to, composing).await
+    }
+
+    /// Show "typing..." for a while, then send a text message.
+    ///
+    /// See `ClientHandle::send_typing_then_message`.
+    pub async fn send_typing_then_message(
+        &self,
+        to: JID,
+        text: &str,
+        typing: TypingDuration,
+    ) -> Result<String, ClientError> {
+        self.connection()?.send_typing_then_message(to, text, typing).await
+    }
+
+    /// Send a raw node.
+    ///
+    /// For stanzas the crate does not model yet; nothing is checked or
+    /// filled in. Nodes the client does not handle itself are delivered
+    /// as `Event::UnhandledNode`.
+    pub async fn send_node(&self, node: Node) -> Result<(), ClientError> {
+        self.connection()?.send_node(node).await
+    }
+
+    /// Send text or media messages to many recipients.
+    ///
+    /// See `ClientHandle::send_bulk`.
+    pub fn send_bulk<I, C>(&self, messages: I) -> Result<BoxStream<'static, BulkSendResult>, ClientError>
+    where
+        I: IntoIterator<Item = (JID, C)>,
+        C: Into<BulkContent>,
+    {
+        Ok(self.connection()?.send_bulk(messages))
+    }
+
+    /// Send a template rendered with each recipient's variables.
+    ///
+    /// See `ClientHandle::send_bulk_template`.
+    pub fn send_bulk_template<I>(
+        &self,
+        template: &Template,
+        recipients: I,
+    ) -> Result<BoxStream<'static, BulkSendResult>, ClientError>
+    where
+        I: IntoIterator<Item = (JID, HashMap<String, String>)>,
+    {
+        Ok(self.connection()?.send_bulk_template(template, recipients)?)
+    }
+
+    /// Send a typed IQ query and wait for its response.
+    ///
+    /// The request ID and recipient are filled in automatically. Error
+    /// stanzas are mapped to `ClientError::IqFailed`, and the query fails
+    /// with `IqError::Timeout` if no response arrives within
+    /// `ClientConfig::request_timeout`.
+    pub async fn query<T: IqRequest>(&self, request: &T) -> Result<T::Response, ClientError> {
+        self.connection()?.query(request).await
+    }
+
+    /// Wait for the next event from the connection.
+    ///
+    /// With `ClientConfig::auto_reconnect`, once the events of a dropped
+    /// connection run out this connects again before returning the next
+    /// event; see `ClientConfig::auto_reconnect` for which drops count.
+    ///
+    /// This is cancel-safe, so it can be raced in `tokio::select!`: a
+    /// dropped call loses no event, and a reconnect it was waiting on
+    /// carries on at the next call.
+    pub async fn receive(&mut self) -> Result<Option<Event>, ClientError> {
+        loop {
+            if self.reconnect_pending {
+                let reconnected = self.reconnect().await;
+                self.reconnect_pending = false;
+                reconnected?;
+            }
+            let events = self.events.as_mut().ok_or(ClientError::NotConnected)?;
+            let event = tokio::select! {
+                _ = self.cancel.cancelled() => return Err(ClientError::Cancelled),
+                event = events.recv() => event,
+            };
+            match event {
+                Some(event) => {
+                    if let Event::Disconnected(disconnected) = &event {
+                        self.last_disconnect = Some(disconnected.reason.clone());
+                    }
+                    return Ok(Some(event));
+                }
+                None if self.should_reconnect() => {
+                    self.last_disconnect = None;
+                    self.reconnect_pending = true;
+                }
+                None => return Err(ClientError::NotConnected),
+            }
+        }
+    }
+
+    /// Whether the connection that just ended is to be opened again.
+    fn should_reconnect(&self) -> bool {
+        self.inner.config.auto_reconnect
+            && self.reconnectable
+            && self.last_disconnect.as_ref().is_some_and(should_reconnect)
+    }
+
+    /// Get the handle of the live connection.
+    pub(crate) fn connection(&self) -> Result<&ClientHandle, ClientError> {
+        self.handle.as_ref()
+            .filter(|h| h.is_connected())
+            .ok_or(ClientError::NotConnected)
+    }
+}
+
+impl Default for Client {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::oneshot;
+
+    #[test]
+    fn test_client_creation() {
+        let client = Client::new();
+        assert!(!client.is_connected());
+    }
+
+    #[tokio::test]
+    async fn test_client_loads_stored_device() {
+        use crate::store::DeviceStore;
+
+        let store = MemoryStore::new();
+        let mut device = Device::new();
+        device.initialize();
+        device.jid = Some(JID::new_ad("123", 0, 1));
+        store.put_device(&device).unwrap();
+
+        let client = Client::with_store(ClientConfig::default(), store);
+        assert!(client.is_logged_in().await);
+        assert_eq!(client.get_jid().await, device.jid);
+        let noise_key = client.inner.device.read().await.noise_key.clone().unwrap();
+        assert_eq!(noise_key.public, device.noise_key.unwrap().public);
+    }
+
+    #[test]
+    fn test_client_with_config() {
+        let config = ClientConfig {
+            endpoint: "wss://custom.endpoint".to_string(),
+            ..Default::default()
+        };
+        let client = Client::with_config(config);
+        assert!(!client.is_connected());
+    }
+
+    struct PingQuery;
+
+    impl IqRequest for PingQuery {
+        type Response = ();
+
+        fn namespace(&self) -> &str {
+            "urn:xmpp:ping"
+        }
+
+        fn iq_type(&self) -> crate::protocol::IqType {
+            crate::protocol::IqType::Get
+        }
+
+        fn content(&self) -> Vec<Node> {
+            Vec::new()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_query_requires_connection() {
+        let client = Client::new();
+        let result = client.query(&PingQuery).await;
+        assert!(matches!(result, Err(ClientError::NotConnected)));
+        assert!(client.handle().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_cancels_token_and_requests() {
+        let mut client = Client::new();
+        let token = client.cancellation_token();
+        let mut rx = client.inner.requests.register("1");
+
+        client.shutdown().await.unwrap();
+
+        assert!(token.is_cancelled());
+        assert!(matches!(rx.try_recv(), Err(oneshot::error::TryRecvError::Closed)));
+        assert!(!client.is_connected());
+    }
+
+    #[test]
+    fn test_iq_response_completes_pending_request() {
+        let client = Client::new();
+        let mut rx = client.inner.requests.register("42");
+
+        let mut response = Node::new("iq");
+        response.set_attr("id", "42");
+        response.set_attr("type", "result");
+        assert!(client.inner.process_node(&response).unwrap().is_empty());
+
+        assert!(rx.try_recv().is_ok());
+    }
+
+    #[test]
+    fn test_media_retry_notification_completes_pending_retry() {
+        let client = Client::new();
+        let mut rx = client.inner.media_retries.register("MSG1");
+
+        let mut notification = Node::new("notification");
+        notification.set_attr("id", "MSG1");
+        notification.set_attr("type", "mediaretry");
+        assert!(client.inner.process_node(&notification).unwrap().is_empty());
+
+        assert_eq!(rx.try_recv().unwrap().get_attr_str("type"), Some("mediaretry"));
+    }
+
+    #[test]
+    fn test_search_messages() {
+        let mut client = Client::new();
+        assert!(matches!(client.search_messages("hi", None, 10), Err(ClientError::StoreError(_))));
+
+        client.set_chat_store(crate::store::SqliteStore::open_in_memory().unwrap());
+        let chat = JID::new("123", "s.whatsapp.net");
+        client.inner.record_message(&StoredMessage {
+            id: "1".to_string(),
+            chat: chat.clone(),
+            sender: chat.clone(),
+            is_from_me: false,
+            timestamp: 1,
+            push_name: None,
+            text: Some("hi there".to_string()),
+        });
+
+        let found = client.search_messages("hi", Some(&chat), 10).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].message.sender, chat);
+        assert!(client.get_message_ref(&chat, "1").unwrap().is_some());
+    }
+
+    #[test]
+    fn test_view_once_is_not_stored_by_default() {
+        let chat = JID::new("1", "s.whatsapp.net");
+        let message = |id: &str| Event::Message(Message {
+            info: MessageInfo {
+                id: id.to_string(),
+                sender: chat.clone(),
+                chat: chat.clone(),
+                is_from_me: false,
+                is_group: false,
+                timestamp: 1,
+                push_name: None,
+                reply_to: None,
+            },
+            content: MessageContent::ViewOnce(Box::new(MessageContent::Image {
+                url: String::new(),
+                caption: None,
+                mimetype: "image/jpeg".to_string(),
+                media: Default::default(),
+                jpeg_thumbnail: None,
+            })),
+        });
+
+        for store_view_once in [false, true] {
+            let client = Client::with_config(ClientConfig { store_view_once, ..Default::default() });
+            let chat_store = Arc::new(MemoryStore::new());
+            *client.inner.chat_store.write().unwrap() = Some(chat_store.clone());
+            client.inner.emit_event(message("ONCE"));
+
+            let stored = chat_store.get_messages(&chat, crate::store::MessagePage::latest(10)).unwrap();
+            assert_eq!(stored.len(), store_view_once as usize);
+            assert!(client.get_message_ref(&chat, "ONCE").unwrap().is_some());
+
+            // Nor is its media cached
+            let dir = std::env::temp_dir().join(format!("whatsmeow-media-{}", uuid::Uuid::new_v4()));
+            *client.inner.media_cache.write().unwrap() = Some(Arc::new(MediaCache::new(dir, 1024).unwrap()));
+            let Event::Message(msg) = message("ONCE") else { unreachable!() };
+            assert_eq!(client.inner.media_cache_for(&msg.content).is_some(), store_view_once);
+        }
+    }
+
+    #[test]
+    fn test_middleware_filters_events() {
+        let mut client = Client::new();
+        let chat_store = Arc::new(MemoryStore::new());
+        *client.inner.chat_store.write().unwrap() = Some(chat_store.clone());
+
+        let spammer = JID::new("666", "s.whatsapp.net");
+        let blocked = spammer.clone();
+        client.add_middleware(move |event: Event, next: crate::protocol::Next<'_>| match &event {
+            Event::Message(msg) if msg.info.sender == blocked => {}
+            _ => next.run(event),
+        });
+        let seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let handler_seen = seen.clone();
+        client.add_event_handler(move |event| {
+            if let Event::Message(msg) = event {
+                handler_seen.lock().unwrap().push(msg.info.id);
+            }
+        });
+
+        let message = |id: &str, sender: &JID| Event::Message(Message {
+            info: MessageInfo {
+                id: id.to_string(),
+                sender: sender.clone(),
+                chat: sender.clone(),
+                is_from_me: false,
+                is_group: false,
+                timestamp: 1,
+                push_name: None,
+                reply_to: None,
+            },
+            content: MessageContent::Text("hi".to_string()),
+        });
+        assert!(client.inner.emit_event(message("spam", &spammer)).is_empty());
+        assert_eq!(client.inner.emit_event(message("ham", &JID::new("1", "s.whatsapp.net"))).len(), 1);
+
+        assert_eq!(*seen.lock().unwrap(), ["ham"]);
+        assert!(chat_store.get_messages(&spammer, crate::store::MessagePage::latest(10)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_push_name_sync_fills_contacts() {
+        use prost::Message as _;
+        use crate::proto::{
+            history_sync_type, E2eMessage, HistoryConversation, HistorySyncMsg, HistorySyncNotification,
+            HistorySyncPayload, MessageKey, ProtocolMessage, Pushname, WebMessageInfo,
+        };
+
+        let client = Client::new();
+        let chat_store = Arc::new(MemoryStore::new());
+        *client.inner.chat_store.write().unwrap() = Some(chat_store.clone());
+        let jid = JID::new("1", "s.whatsapp.net");
+        client.update_contacts([ContactUpdate { full_name: Some("Ana Lima".to_string()), ..ContactUpdate::new(jid.clone()) }]);
+
+        let synced = HistorySyncMsg {
+            message: Some(WebMessageInfo {
+                key: Some(MessageKey { id: Some("OLD1".to_string()), from_me: Some(false), ..Default::default() }),
+                message: Some(E2eMessage { conversation: Some("from last year".to_string()), ..Default::default() }),
+                message_timestamp: Some(1_600_000_000),
+                ..Default::default()
+            }),
+            msg_order_id: None,
+        };
+        let payload = HistorySyncPayload {
+            conversations: vec![HistoryConversation { id: Some(jid.to_string()), messages: vec![synced] }],
+            pushnames: vec![Pushname { id: Some(jid.to_string()), pushname: Some("Ana".to_string()) }],
+            ..Default::default()
+        };
+        let notification = E2eMessage {
+            protocol_message: Some(Box::new(ProtocolMessage {
+                history_sync_notification: Some(HistorySyncNotification {
+                    sync_type: Some(history_sync_type::PUSH_NAME),
+                    initial_hist_bootstrap_inline_payload: Some(payload.encode_to_vec()),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            })),
+            ..Default::default()
+        };
+        let mut enc = Node::new("enc");
+        enc.set_bytes(notification.encode_to_vec());
+        let mut node = Node::new("message");
+        node.set_attr("from", "2@s.whatsapp.net");
+        node.add_child(enc);
+        *client.inner.own_jid.write().unwrap() = Some(JID::new_ad("9", 0, 4));
+
+        assert!(client.inner.process_node(&node).unwrap().is_empty());
+        assert_eq!(client.get_contact(&jid).unwrap().unwrap().push_name, None);
+
+        node.set_attr("from", "9@s.whatsapp.net");
+        let events = client.inner.process_node(&node).unwrap();
+        assert!(matches!(events.as_slice(), [Event::HistorySync(_), Event::ContactsSynced(ContactsSynced { count: 1 })]));
+        let contact = client.get_contact(&JID::new_ad("1", 0, 3)).unwrap().unwrap();
+        assert_eq!(contact.full_name, "Ana Lima");
+        assert_eq!(contact.push_name.as_deref(), Some("Ana"));
+
+        // Synced messages are stored and can be replied to
+        let stored = chat_store.get_messages(&jid, crate::store::MessagePage::latest(10)).unwrap();
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].text.as_deref(), Some("from last year"));
+        assert_eq!(client.inner.store.get_message_ref(&jid, "OLD1").unwrap().unwrap().sender, jid);
+    }
+
+    #[test]
+    fn test_edge_routing_is_remembered() {
+        let client = Client::new();
+
+        let mut routing_info = Node::new("routing_info");
+        routing_info.set_bytes(vec![8, 2, 8, 5]);
+        let mut edge_routing = Node::new("edge_routing");
+        edge_routing.add_child(routing_info);
+        let mut ib = Node::new("ib");
+        ib.add_child(edge_routing);
+        assert!(client.inner.process_node(&ib).unwrap().is_empty());
+
+        assert_eq!(*client.inner.routing_info.read().unwrap(), Some(vec![8, 2, 8, 5]));
+    }
+
+    #[test]
+    fn test_unknown_node_is_passed_through() {
+        let client = Client::new();
+        let mut node = Node::new("experimental");
+        node.set_attr("id", "1");
+
+        let events = client.inner.process_node(&node).unwrap();
+        assert!(matches!(events.as_slice(), [Event::UnhandledNode(n)] if n.tag == "experimental"));
+    }
+
+    #[tokio::test]
+    async fn test_own_messages_are_from_me() {
+        let client = Client::new();
+        let message = |from: &str| {
+            let mut node = Node::new("message");
+            node.set_attr("id", "3EB1");
+            node.set_attr("from", from);
+            node
+        };
+        // Reading our JID does not wait on the device lock
+        let _device = client.inner.device.write().await;
+        *client.inner.own_jid.write().unwrap() = Some(JID::new_ad("1", 0, 4));
+
+        let events = client.inner.process_node(&message("1:2@s.whatsapp.net")).unwrap();
+        assert!(matches!(events.as_slice(), [Event::Message(m)] if m.info.is_from_me));
+        let events = client.inner.process_node(&message("2@s.whatsapp.net")).unwrap();
+        assert!(matches!(events.as_slice(), [Event::Message(m)] if !m.info.is_from_me));
+    }
+
+    #[test]
+    fn test_undecryptable_message_is_reported() {
+        let client = Client::new();
+        let mut enc = Node::new("enc");
+        enc.set_attr("type", "pkmsg");
+        enc.set_bytes(vec![0x33, 0x08, 0xFF]);
+        let mut node = Node::new("message");
+        node.set_attr("id", "3EB0");
+        node.set_attr("from", "1@s.whatsapp.net");
+        node.add_child(enc);
+
+        let events = client.inner.process_node(&node).unwrap();
+        assert!(matches!(events.as_slice(), [Event::UndecryptableMessage(m)] if m.info.id == "3EB0"));
+    }
+
+    #[test]
+    fn test_presence_is_cached() {
+        let client = Client::new();
+        let user = JID::new("1", "s.whatsapp.net");
+        assert!(client.get_cached_presence(&user).is_none());
+
+        let mut node = Node::new("presence");
+        node.set_attr("from", "1:3@s.whatsapp.net");
+        node.set_attr("type", "unavailable");
+        node.set_attr("last", "1700000000");
+        let events = client.inner.process_node(&node).unwrap();
+        assert!(matches!(events.as_slice(), [Event::Presence(p)] if !p.available));
+
+        assert_eq!(client.get_cached_presence(&user).unwrap().last_seen, Some(1700000000));
+    }
+
+    #[tokio::test]
+    async fn test_chat_read_on_other_device_updates_unread_count() {
+        let mut client = Client::new();
+        let changes = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = changes.clone();
+        client.add_event_handler(move |event| {
+            if let Event::UnreadCountChanged(change) = event {
+                seen.lock().unwrap().push(change.unread_count);
+            }
+        });
+        let chat = JID::new("1", "s.whatsapp.net");
+
+        client.apply_chat_read(&chat, false).await;
+        assert_eq!(client.get_unread_count(&chat), 1);
+        client.apply_chat_read(&chat, true).await;
+        client.apply_chat_read(&chat, true).await;
+        assert_eq!(client.get_unread_count(&chat), 0);
+        assert_eq!(*changes.lock().unwrap(), vec![1, 0]);
+    }
+
+    #[tokio::test]
+    async fn test_app_state_mutations_reach_their_handlers() {
+        use crate::proto::{ContactAction, LabelEditAction, MarkChatAsReadAction, QuickReplyAction, SyncActionValue};
+
+        let mut client = Client::new();
+        let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = events.clone();
+        client.add_event_handler(move |event| seen.lock().unwrap().push(event));
+        let mutation = |index: &[&str], value: SyncActionValue| AppStateMutation {
+            index: index.iter().map(|part| part.to_string()).collect(),
+            value,
+            version: 2,
+        };
+
+        client.apply_app_state_mutations(&[
+            mutation(&["label_edit", "1"], SyncActionValue {
+                label_edit_action: Some(LabelEditAction { name: Some("New customer".to_string()), color: Some(1), ..Default::default() }),
+                ..Default::default()
+            }),
+            mutation(&["quick_reply", "QR1"], SyncActionValue {
+                quick_reply_action: Some(QuickReplyAction { shortcut: Some("thanks".to_string()), ..Default::default() }),
+                ..Default::default()
+            }),
+            mutation(&["contact", "1@s.whatsapp.net"], SyncActionValue {
+                contact_action: Some(ContactAction { full_name: Some("Alice Smith".to_string()), first_name: None }),
+                ..Default::default()
+            }),
+            mutation(&["markChatAsRead", "2@s.whatsapp.net"], SyncActionValue {
+                mark_chat_as_read_action: Some(MarkChatAsReadAction { read: Some(false) }),
+                ..Default::default()
+            }),
+            mutation(&["pin_v1", "2@s.whatsapp.net"], SyncActionValue::default()),
+        ]).await;
+
+        assert_eq!(client.get_labels()[0].name, "New customer");
+        assert_eq!(client.get_quick_replies()[0].shortcut, "thanks");
+        let contact = client.get_contact(&JID::new("1", "s.whatsapp.net")).unwrap().unwrap();
+        assert_eq!(contact.full_name, "Alice Smith");
+        assert_eq!(client.get_unread_count(&JID::new("2", "s.whatsapp.net")), 1);
+        assert!(matches!(
+            events.lock().unwrap().as_slice(),
+            [Event::LabelEdit(_), Event::UnreadCountChanged(_)]
+        ));
+    }
+
+    #[test]
+    fn test_server_time_follows_server_timestamps() {
+        let clock = Arc::new(crate::protocol::clock::ManualClock::new(chrono::DateTime::from_timestamp(1700000000, 0).unwrap()));
+        let client = Client::with_config(ClientConfig { clock: clock.clone(), ..Default::default() });
+        assert_eq!(client.server_time().timestamp(), 1700000000);
+
+        let mut success = Node::new("success");
+        success.set_attr("t", "1700000120");
+        client.inner.process_node(&success).unwrap();
+        assert_eq!(client.server_time().timestamp(), 1700000120);
+
+        // A pong corrects the offset as our clock drifts
+        clock.advance(chrono::Duration::seconds(60));
+        let mut pong = Node::new("iq");
+        pong.set_attr("type", "result");
+        pong.set_attr("t", "1700000150");
+        client.inner.process_node(&pong).unwrap();
+        assert_eq!(client.server_time().timestamp(), 1700000150);
+    }
+
+    #[test]
+    fn test_refused_chat_state_requires_presence() {
+        let client = Client::new();
+        let mut ack = Node::new("ack");
+        ack.set_attr("class", "chatstate");
+        ack.set_attr("error", "403");
+        let events = client.inner.process_node(&ack).unwrap();
+        assert!(matches!(events.as_slice(), [Event::PresenceRequired]));
+
+        let mut ack = Node::new("ack");
+        ack.set_attr("class", "chatstate");
+        assert!(matches!(client.inner.process_node(&ack).unwrap().as_slice(), [Event::UnhandledNode(_)]));
+        assert!(!ClientConfig::default().send_presence_on_connect);
+        assert!(!ClientConfig::default().send_retry_receipts);
+    }
+
+    #[test]
+    fn test_device_notification_invalidates_sessions() {
+        use crate::protocol::devices::UserDevices;
+
+        let client = Client::new();
+        let user = JID::new("1", "s.whatsapp.net");
+        let laptop: JID = "1:3@s.whatsapp.net".parse().unwrap();
+        client.inner.devices.update(&UserDevices { user: user.clone(), devices: vec![user.clone(), laptop.clone()] });
+        client.inner.store.put_session("1.3", b"session").unwrap();
+
+        let mut device = Node::new("device");
+        device.set_attr("jid", "1:3@s.whatsapp.net");
+        let mut remove = Node::new("remove");
+        remove.set_attr("device_hash", "2:changed");
+        remove.add_child(device);
+        let mut node = Node::new("notification");
+        node.set_attr("type", "devices");
+        node.set_attr("from", "1@s.whatsapp.net");
+        node.add_child(remove);
+
+        let events = client.inner.process_node(&node).unwrap();
+        assert!(matches!(events.as_slice(), [Event::LinkedDevicesChanged(change)] if change.removed == [laptop]));
+        assert!(!client.inner.store.has_session("1.3").unwrap());
+        assert_eq!(client.inner.devices.take_stale(), vec![user]);
+    }
+
+    #[test]
+    fn test_device_mismatch_refreshes_group_and_resends() {
+        use crate::store::OutgoingMessage;
+        use crate::types::{GroupInfo, GroupParticipant};
+
+        let mut client = Client::new();
+        client.set_outbox(MemoryStore::new());
+        let own = JID::new_ad("9", 0, 4);
+        *client.inner.own_jid.write().unwrap() = Some(own.clone());
+        let group: JID = "123-456@g.us".parse().unwrap();
+        let member = JID::new("1", "s.whatsapp.net");
+        client.inner.groups.put(&GroupInfo {
+            jid: group.clone(),
+            participants: vec![GroupParticipant { jid: member.clone(), is_admin: false, is_super_admin: false }],
+            ..Default::default()
+        });
+        let outbox = client.outbox().unwrap();
+        outbox.put_outgoing(&OutgoingMessage {
+            id: "ABC".to_string(),
+            to: group.clone(),
+            text: "hi".to_string(),
+            created_at: 0,
+            attempts: 1,
+        }).unwrap();
+
+        let mut ack = Node::new("ack");
+        ack.set_attr("class", "message");
+        ack.set_attr("id", "ABC");
+        ack.set_attr("from", "123-456@g.us");
+        ack.set_attr("error", DEVICE_MISMATCH_ERROR);
+        assert!(client.inner.process_node(&ack).unwrap().is_empty());
+
+        let mut stale = client.inner.devices.take_stale();
+        stale.sort_by_key(|jid| jid.user.clone());
+        assert_eq!(stale, vec![member, own.to_non_ad()]);
+        assert!(client.inner.groups.get(&group).is_none());
+        assert_eq!(client.inner.devices.take_resends(), vec!["ABC".to_string()]);
+        assert_eq!(outbox.pending_outgoing().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_connect_rotates_through_endpoints() {
+        // Nothing listens on these ports, so every endpoint fails
+        let config = ClientConfig {
+            endpoint: "ws://127.0.0.1:1/ws/chat".to_string(),
+            fallback_endpoints: vec!["ws://127.0.0.1:2/ws/chat".to_string()],
+            ..Default::default()
+        };
+        let mut client = Client::with_config(config);
+
+        assert!(matches!(client.connect().await, Err(ClientError::ConnectionFailed(_))));
+        assert_eq!(client.last_endpoint(), None);
+        // Both were tried, so the first is at the back again
+        assert_eq!(client.inner.endpoints.lock().unwrap().candidates()[0].url, "ws://127.0.0.1:1/ws/chat");
+    }
+
+    #[tokio::test]
+    async fn test_receive_reconnects_after_stale_close() {
+        use crate::types::Disconnected;
+
+        let closed = |reason: DisconnectReason| {
+            let (tx, rx) = mpsc::unbounded_channel();
+            tx.send(Event::Disconnected(Disconnected { reason })).unwrap();
+            rx
+        };
+        let config = ClientConfig {
+            endpoint: "ws://127.0.0.1:1/ws/chat".to_string(),
+            ..Default::default()
+        };
+
+        // We closed it ourselves, so the events just run out
+        let mut client = Client::with_config(config.clone());
+        client.reconnectable = true;
+        client.events = Some(closed(DisconnectReason::UserRequested));
+        assert!(matches!(client.receive().await, Ok(Some(Event::Disconnected(_)))));
+        assert!(matches!(client.receive().await, Err(ClientError::NotConnected)));
+
+        // A stale connection is opened again until that works or the
+        // client shuts down
+        let mut client = Client::with_config(config.clone());
+        client.reconnectable = true;
+        client.events = Some(closed(DisconnectReason::Stale));
+        assert!(matches!(client.receive().await, Ok(Some(Event::Disconnected(_)))));
+        let cancel = client.cancellation_token();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            cancel.cancel();
+        });
+        assert!(matches!(client.receive().await, Err(ClientError::Cancelled)));
+
+        let mut client = Client::with_config(ClientConfig { auto_reconnect: false, ..config });
+        client.reconnectable = true;
+        client.events = Some(closed(DisconnectReason::Stale));
+        assert!(matches!(client.receive().await, Ok(Some(Event::Disconnected(_)))));
+        assert!(matches!(client.receive().await, Err(ClientError::NotConnected)));
+    }
+
+    #[tokio::test]
+    async fn test_receive_resumes_dropped_reconnect() {
+        use crate::types::Disconnected;
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        tx.send(Event::Disconnected(Disconnected { reason: DisconnectReason::Stale })).unwrap();
+        drop(tx);
+        let mut client = Client::with_config(ClientConfig {
+            endpoint: "ws://127.0.0.1:1/ws/chat".to_string(),
+            ..Default::default()
+        });
+        client.reconnectable = true;
+        client.events = Some(rx);
+        assert!(matches!(client.receive().await, Ok(Some(Event::Disconnected(_)))));
+
+        // Dropped while waiting to reconnect, as when losing a select
+        assert!(tokio::time::timeout(Duration::from_millis(20), client.receive()).await.is_err());
+        assert!(client.reconnect_pending);
+
+        // The next call keeps reconnecting instead of giving up
+        let cancel = client.cancellation_token();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            cancel.cancel();
+        });
+        assert!(matches!(client.receive().await, Err(ClientError::Cancelled)));
+        assert!(!client.reconnect_pending);
+    }
+
+    #[test]
+    fn test_stream_error_hints_next_endpoint() {
+        let client = Client::new();
+        let mut error = Node::new("stream:error");
+        error.set_attr("fallback_hostname", "w7.web.whatsapp.com");
+
+        let events = client.inner.process_node(&error).unwrap();
+        assert!(matches!(events.as_slice(), [Event::UnhandledNode(_)]));
+        assert_eq!(client.inner.endpoints.lock().unwrap().candidates()[0].url, endpoints::fallback(7));
+    }
+
+    #[test]
+    fn test_stream_error_records_close_cause() {
+        let client = Client::new();
+        let mut error = Node::new("stream:error");
+        error.set_attr("code", "401");
+
+        let events = client.inner.process_node(&error).unwrap();
+        assert!(matches!(events.as_slice(), [Event::LoggedOut(logged_out)] if !logged_out.by_user));
+        // The socket error that follows does not replace it
+        client.inner.close_cause.record(DisconnectReason::NetworkError("closed".to_string()));
+        assert_eq!(client.inner.close_cause.take(), Some(DisconnectReason::LoggedOut));
+
+        let mut conflict = Node::new("conflict");
+        conflict.set_attr("type", "replaced");
+        let mut error = Node::new("stream:error");
+        error.add_child(conflict);
+        let events = client.inner.process_node(&error).unwrap();
+        assert!(matches!(events.as_slice(), [Event::UnhandledNode(_)]));
+        assert_eq!(client.inner.close_cause.take(), Some(DisconnectReason::Replaced));
+    }
+
+    #[test]
+    fn test_client_payload_registers_or_logs_in() {
+        let config = ClientConfig { device_os: "Bot".to_string(), ..Default::default() };
+        let mut device = Device::new();
+        device.initialize();
+
+        let payload = ClientPayload::decode(&*config.client_payload(&device)).unwrap();
+        let pairing_data = payload.device_pairing_data.unwrap();
+        let props = DeviceProps::decode(&*pairing_data.device_props.unwrap()).unwrap();
+        assert_eq!(props.os.as_deref(), Some("Bot"));
+        assert_eq!(props.platform_type, Some(device_platform::CHROME));
+        assert_eq!(props.require_full_sync, Some(false));
+
+        let full_sync = ClientConfig {
+            require_full_sync: true,
+            history_sync: Some(HistorySyncConfig { full_sync_days_limit: Some(3650), ..Default::default() }),
+            ..Default::default()
+        };
+        let props = full_sync.device_props();
+        assert_eq!(props.require_full_sync, Some(true));
+        assert_eq!(props.history_sync_config.and_then(|c| c.full_sync_days_limit), Some(3650));
+        assert_eq!(payload.username, None);
+
+        device.jid = Some(JID::new_ad("123", 0, 4));
+        let payload = ClientPayload::decode(&*config.client_payload(&device)).unwrap();
+        assert_eq!((payload.username, payload.device), (Some(123), Some(4)));
+        assert!(payload.device_pairing_data.is_none());
+    }
+
+    #[test]
+    fn test_login_failure_events() {
+        let client = Client::new();
+        let mut failure = Node::new("failure");
+        failure.set_attr("reason", "405");
+        let events = client.inner.process_node(&failure).unwrap();
+        assert!(matches!(events.as_slice(), [Event::ClientOutdated]));
+
+        let mut failure = Node::new("failure");
+        failure.set_attr("reason", "500");
+        failure.set_attr("message", "internal error");
+        let events = client.inner.process_node(&failure).unwrap();
+        assert!(matches!(
+            events.as_slice(),
+            [Event::ConnectFailure(f)] if f.code.as_deref() == Some("500") && f.message.as_deref() == Some("internal error")
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_ban_blocks_connect() {
+        let mut client = Client::new();
+        let expires = chrono::Utc::now() + chrono::Duration::hours(1);
+        let mut error = Node::new("stream:error");
+        error.set_attr("code", "402");
+        error.set_attr("t", expires.timestamp().to_string());
+
+        let events = client.inner.process_node(&error).unwrap();
+        assert!(matches!(events.as_slice(), [Event::Banned(ban)] if ban.code == 402));
+        assert_eq!(client.banned_until().map(|until| until.timestamp()), Some(expires.timestamp()));
+        assert!(matches!(client.connect().await, Err(ClientError::Banned(_))));
+
+        // An expired ban no longer counts
+        *client.inner.banned_until.write().unwrap() = Some(chrono::Utc::now() - chrono::Duration::seconds(1));
+        assert_eq!(client.banned_until(), None);
     }
 }
@@ -0,0 +1,162 @@
+//! Time and randomness sources of the client.
+//!
+//! The client reads the time and picks message IDs, tag prefixes and
+//! typing pauses through `ClientConfig::clock` and `ClientConfig::rng`
+//! rather than the system directly, so tests and replays can pin both.
+//! Keys are always generated from the operating system's secure RNG,
+//! whatever the configured source.
+
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Mutex;
+use chrono::{DateTime, Duration, Utc};
+use rand::rngs::StdRng;
+use rand::{RngCore, SeedableRng};
+
+/// Source of the current time.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+/// The system clock.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// Clock that only moves when told to.
+pub struct ManualClock {
+    now: Mutex<DateTime<Utc>>,
+}
+
+impl ManualClock {
+    pub fn new(now: DateTime<Utc>) -> Self {
+        Self { now: Mutex::new(now) }
+    }
+
+    /// Set the current time.
+    pub fn set(&self, now: DateTime<Utc>) {
+        *self.now.lock().unwrap() = now;
+    }
+
+    /// Move the current time forward.
+    pub fn advance(&self, by: Duration) {
+        *self.now.lock().unwrap() += by;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> DateTime<Utc> {
+        *self.now.lock().unwrap()
+    }
+}
+
+/// How far the server's clock is ahead of ours.
+///
+/// The login success and ping responses carry the server's time in
+/// seconds. Offsets within that resolution are ignored, so a clock in sync
+/// is left alone.
+#[derive(Debug, Default)]
+pub struct ServerClock {
+    offset_ms: AtomicI64,
+}
+
+impl ServerClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a server timestamp, in seconds, received at `local`.
+    pub fn record(&self, server_seconds: i64, local: DateTime<Utc>) {
+        let offset_ms = server_seconds * 1000 - local.timestamp_millis();
+        let offset_ms = if offset_ms.abs() < 1000 { 0 } else { offset_ms };
+        self.offset_ms.store(offset_ms, Ordering::Relaxed);
+    }
+
+    /// The server's clock minus ours.
+    pub fn offset(&self) -> Duration {
+        Duration::milliseconds(self.offset_ms.load(Ordering::Relaxed))
+    }
+
+    /// The server's time when ours is `local`.
+    pub fn server_time(&self, local: DateTime<Utc>) -> DateTime<Utc> {
+        local + self.offset()
+    }
+}
+
+/// Source of non-secret randomness.
+pub trait RandomSource: Send + Sync {
+    fn next_u64(&self) -> u64;
+
+    /// A uniform sample in `[0, 1)`.
+    fn next_f64(&self) -> f64 {
+        // The top 53 bits fill the mantissa exactly
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// Randomness from the thread-local generator.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemRandom;
+
+impl RandomSource for SystemRandom {
+    fn next_u64(&self) -> u64 {
+        rand::random()
+    }
+}
+
+/// Randomness repeating the same sequence for the same seed.
+pub struct SeededRandom {
+    rng: Mutex<StdRng>,
+}
+
+impl SeededRandom {
+    pub fn new(seed: u64) -> Self {
+        Self { rng: Mutex::new(StdRng::seed_from_u64(seed)) }
+    }
+}
+
+impl RandomSource for SeededRandom {
+    fn next_u64(&self) -> u64 {
+        self.rng.lock().unwrap().next_u64()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manual_clock() {
+        let start = DateTime::from_timestamp(1700000000, 0).unwrap();
+        let clock = ManualClock::new(start);
+        assert_eq!(clock.now(), start);
+        clock.advance(Duration::seconds(90));
+        assert_eq!(clock.now().timestamp(), 1700000090);
+    }
+
+    #[test]
+    fn test_server_clock_offset() {
+        let local = DateTime::from_timestamp(1700000000, 400_000_000).unwrap();
+        let clock = ServerClock::new();
+        clock.record(1700000000, local);
+        assert_eq!(clock.server_time(local), local);
+
+        // Our clock is five minutes slow
+        clock.record(1700000300, local);
+        assert_eq!(clock.offset(), Duration::milliseconds(299_600));
+        assert_eq!(clock.server_time(local).timestamp(), 1700000300);
+    }
+
+    #[test]
+    fn test_seeded_random_repeats() {
+        let (a, b) = (SeededRandom::new(7), SeededRandom::new(7));
+        let first: Vec<u64> = (0..4).map(|_| a.next_u64()).collect();
+        assert_eq!(first, (0..4).map(|_| b.next_u64()).collect::<Vec<_>>());
+        assert_ne!(first[0], SeededRandom::new(8).next_u64());
+        assert!((0..100).map(|_| a.next_f64()).all(|x| (0.0..1.0).contains(&x)));
+    }
+}
@@ -0,0 +1,180 @@
+//! Contact sync.
+//!
+//! The contact store is filled from three sources: push names in history
+//! syncs, contact mutations in app state, and usync query results. Each
+//! gives part of a contact, so updates are merged into what is stored.
+
+use prost::Message as _;
+
+use crate::binary::Node;
+use crate::proto::{ContactAction, HistorySyncPayload};
+use crate::protocol::history::decompress_history_sync;
+use crate::store::ContactInfo;
+use crate::types::JID;
+
+/// Partial contact information from one source.
+///
+/// Fields left as `None` keep their stored value.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ContactUpdate {
+    pub jid: JID,
+    pub first_name: Option<String>,
+    pub full_name: Option<String>,
+    pub push_name: Option<String>,
+    pub business_name: Option<String>,
+}
+
+impl ContactUpdate {
+    /// Update for a contact, with nothing changed yet.
+    pub fn new(jid: JID) -> Self {
+        Self { jid: jid.to_non_ad(), ..Default::default() }
+    }
+
+    /// Merge the update into a stored contact, or create one.
+    pub fn apply(self, contact: Option<ContactInfo>) -> ContactInfo {
+        let mut contact = contact.unwrap_or_else(|| ContactInfo { jid: self.jid.clone(), ..Default::default() });
+        if let Some(first_name) = self.first_name {
+            contact.first_name = first_name;
+        }
+        if let Some(full_name) = self.full_name {
+            contact.full_name = full_name;
+        }
+        if self.push_name.is_some() {
+            contact.push_name = self.push_name;
+        }
+        if self.business_name.is_some() {
+            contact.business_name = self.business_name;
+        }
+        contact
+    }
+}
+
+/// Read the push names from a history sync payload.
+///
+/// The payload may still be zlib-compressed, as it is when sent inline or
+/// downloaded. Returns `None` if it cannot be decoded.
+pub fn parse_history_pushnames(data: &[u8]) -> Option<Vec<ContactUpdate>> {
+    let payload = HistorySyncPayload::decode(&*decompress_history_sync(data)).ok()?;
+
+    Some(payload.pushnames.into_iter()
+        .filter_map(|entry| {
+            let jid: JID = entry.id?.parse().ok()?;
+            let push_name = entry.pushname.filter(|name| !name.is_empty())?;
+            Some(ContactUpdate { push_name: Some(push_name), ..ContactUpdate::new(jid) })
+        })
+        .collect())
+}
+
+/// Turn an app state contact mutation into an update.
+///
+/// `index` is the mutation's decoded index, `["contact", "<jid>"]`.
+pub fn parse_contact_mutation(index: &[String], action: &ContactAction) -> Option<ContactUpdate> {
+    match index {
+        [kind, jid, ..] if kind == "contact" => Some(ContactUpdate {
+            first_name: action.first_name.clone(),
+            full_name: action.full_name.clone(),
+            ..ContactUpdate::new(jid.parse().ok()?)
+        }),
+        _ => None,
+    }
+}
+
+/// Read the users registered on WhatsApp from a usync query result.
+///
+/// Users whose `<contact>` is not of type "in" are left out. A verified
+/// business name is taken from `<business>` when present.
+pub fn parse_usync_contacts(node: &Node) -> Vec<ContactUpdate> {
+    let Some(list) = node.get_child_by_tag("usync").and_then(|usync| usync.get_child_by_tag("list")) else {
+        return Vec::new();
+    };
+
+    list.get_children_by_tag("user").into_iter()
+        .filter(|user| {
+            user.get_child_by_tag("contact")
+                .is_some_and(|contact| contact.get_attr_str("type") == Some("in"))
+        })
+        .filter_map(|user| {
+            let jid = user.parse_attr_jid("jid")?;
+            let business_name = user.get_child_by_tag("business")
+                .and_then(|business| business.get_attr_str("verified_name"))
+                .map(String::from);
+            Some(ContactUpdate { business_name, ..ContactUpdate::new(jid) })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use flate2::{write::ZlibEncoder, Compression};
+    use crate::proto::Pushname;
+
+    #[test]
+    fn test_parse_history_pushnames() {
+        let payload = HistorySyncPayload {
+            sync_type: Some(crate::proto::history_sync_type::PUSH_NAME),
+            pushnames: vec![
+                Pushname { id: Some("1@s.whatsapp.net".to_string()), pushname: Some("Ana".to_string()) },
+                Pushname { id: Some("2@s.whatsapp.net".to_string()), pushname: Some(String::new()) },
+            ],
+            ..Default::default()
+        };
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&payload.encode_to_vec()).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        for data in [compressed, payload.encode_to_vec()] {
+            let updates = parse_history_pushnames(&data).unwrap();
+            assert_eq!(updates.len(), 1);
+            assert_eq!(updates[0].jid, JID::new("1", "s.whatsapp.net"));
+            assert_eq!(updates[0].push_name.as_deref(), Some("Ana"));
+        }
+    }
+
+    #[test]
+    fn test_updates_merge() {
+        let jid = JID::new("1", "s.whatsapp.net");
+        let index = ["contact".to_string(), jid.to_string()];
+        let action = ContactAction { full_name: Some("Ana Lima".to_string()), first_name: Some("Ana".to_string()) };
+        let contact = parse_contact_mutation(&index, &action).unwrap().apply(None);
+        assert_eq!(contact.full_name, "Ana Lima");
+
+        let push = ContactUpdate { push_name: Some("ana ☀".to_string()), ..ContactUpdate::new(jid) };
+        let contact = push.apply(Some(contact));
+        assert_eq!(contact.first_name, "Ana");
+        assert_eq!(contact.push_name.as_deref(), Some("ana ☀"));
+
+        assert!(parse_contact_mutation(&["mute".to_string(), "1@s.whatsapp.net".to_string()], &action).is_none());
+    }
+
+    #[test]
+    fn test_parse_usync_contacts() {
+        let user = |jid: &str, kind: &str| {
+            let mut contact = Node::new("contact");
+            contact.set_attr("type", kind);
+            let mut user = Node::new("user");
+            user.set_attr("jid", jid);
+            user.add_child(contact);
+            user
+        };
+        let mut shop = user("2@s.whatsapp.net", "in");
+        let mut business = Node::new("business");
+        business.set_attr("verified_name", "Lima Bakery");
+        shop.add_child(business);
+
+        let mut list = Node::new("list");
+        list.add_child(user("1@s.whatsapp.net", "in"));
+        list.add_child(user("3@s.whatsapp.net", "out"));
+        list.add_child(shop);
+        let mut usync = Node::new("usync");
+        usync.add_child(list);
+        let mut iq = Node::new("iq");
+        iq.add_child(usync);
+
+        let updates = parse_usync_contacts(&iq);
+        let jids: Vec<_> = updates.iter().map(|u| u.jid.user.as_str()).collect();
+        assert_eq!(jids, ["1", "2"]);
+        assert_eq!(updates[1].business_name.as_deref(), Some("Lima Bakery"));
+    }
+}
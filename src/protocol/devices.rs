@@ -0,0 +1,361 @@
+//! Device lists.
+//!
+//! Every account has a primary phone (device 0) and up to a few linked
+//! companion devices. The list is fetched with a usync query, and the
+//! server sends a `devices` notification when a device is added or removed.
+//!
+//! Lists are cached with their hash. When a notification brings a
+//! different hash, or the server rejects a message because we sent it to
+//! the wrong devices, the list is fetched again and the sessions of
+//! devices that are gone are dropped.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+use base64::{engine::general_purpose, Engine as _};
+use sha2::{Digest, Sha256};
+use tokio::sync::Notify;
+
+use crate::binary::Node;
+use crate::protocol::client::{Client, ClientError};
+use crate::protocol::request::{IqError, IqRequest, IqResponse, IqType};
+use crate::types::{LinkedDevicesChanged, JID};
+
+/// Error of a message ack when the message was not encrypted for the
+/// recipient's current devices.
+pub const DEVICE_MISMATCH_ERROR: &str = "409";
+
+/// Query for the devices of some users.
+pub struct DeviceListRequest {
+    pub users: Vec<JID>,
+    /// Session ID of the query, a fresh message ID
+    pub sid: String,
+}
+
+/// Devices of one user.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UserDevices {
+    pub user: JID,
+    /// Device JIDs, the primary device first
+    pub devices: Vec<JID>,
+}
+
+impl IqRequest for DeviceListRequest {
+    type Response = Vec<UserDevices>;
+
+    fn namespace(&self) -> &str {
+        "usync"
+    }
+
+    fn iq_type(&self) -> IqType {
+        IqType::Get
+    }
+
+    fn content(&self) -> Vec<Node> {
+        let mut devices = Node::new("devices");
+        devices.set_attr("version", "2");
+        let mut query = Node::new("query");
+        query.add_child(devices);
+
+        let mut list = Node::new("list");
+        for user in &self.users {
+            let mut user_node = Node::new("user");
+            user_node.set_attr("jid", user.to_non_ad().to_string());
+            list.add_child(user_node);
+        }
+
+        let mut usync = Node::new("usync");
+        usync.set_attr("sid", self.sid.as_str());
+        usync.set_attr("mode", "query");
+        usync.set_attr("last", "true");
+        usync.set_attr("index", "0");
+        usync.set_attr("context", "message");
+        usync.add_child(query);
+        usync.add_child(list);
+        vec![usync]
+    }
+}
+
+impl IqResponse for Vec<UserDevices> {
+    fn from_node(node: &Node) -> Result<Self, IqError> {
+        let list = node.get_child_by_tag("usync")
+            .and_then(|usync| usync.get_child_by_tag("list"))
+            .ok_or_else(|| IqError::MalformedResponse("missing <usync><list>".to_string()))?;
+
+        Ok(list.get_children_by_tag("user").into_iter()
+            .filter_map(|user_node| {
+                let user = user_node.parse_attr_jid("jid")?.to_non_ad();
+                let mut devices: Vec<JID> = user_node.get_child_by_tag("devices")
+                    .and_then(|devices| devices.get_child_by_tag("device-list"))
+                    .map(|list| list.get_children_by_tag("device"))
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter_map(|device| device.get_attr_str("id")?.parse().ok())
+                    .map(|id| JID { device: id, ..user.clone() })
+                    .collect();
+                devices.sort_by_key(|device| device.device);
+                Some(UserDevices { user, devices })
+            })
+            .collect())
+    }
+}
+
+/// Hash of a device list, in the `2:<base64>` form of `device_hash`,
+/// computed over the devices' AD form as the server does.
+pub fn device_list_hash(devices: &[JID]) -> String {
+    let mut jids: Vec<String> = devices.iter().map(JID::ad_string).collect();
+    jids.sort();
+    let digest = Sha256::digest(jids.concat().as_bytes());
+    format!("2:{}", general_purpose::STANDARD_NO_PAD.encode(&digest[..6]))
+}
+
+/// Signal session address of a device.
+pub(crate) fn session_address(device: &JID) -> String {
+    format!("{}.{}", device.signal_address_user(), device.device)
+}
+
+/// Device lists of users, with the users whose list needs refreshing.
+#[derive(Default)]
+pub struct DeviceCache {
+    users: Mutex<HashMap<JID, (Vec<JID>, String)>>,
+    stale: Mutex<HashSet<JID>>,
+    /// IDs of messages to send again once stale lists are fetched
+    resends: Mutex<Vec<String>>,
+    /// Wakes the refresh task when a list goes stale
+    pub(crate) stale_changed: Notify,
+}
+
+impl DeviceCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cached devices of a user.
+    pub fn get(&self, user: &JID) -> Option<Vec<JID>> {
+        self.users.lock().unwrap().get(&user.to_non_ad()).map(|(devices, _)| devices.clone())
+    }
+
+    /// Users whose devices are not cached, or whose cached list is waiting
+    /// to be fetched again.
+    pub fn missing(&self, users: &[JID]) -> Vec<JID> {
+        let cached = self.users.lock().unwrap();
+        let stale = self.stale.lock().unwrap();
+        let mut missing: Vec<JID> = Vec::new();
+        for user in users.iter().map(JID::to_non_ad) {
+            if (!cached.contains_key(&user) || stale.contains(&user)) && !missing.contains(&user) {
+                missing.push(user);
+            }
+        }
+        missing
+    }
+
+    /// Hash of the cached devices of a user.
+    pub fn hash(&self, user: &JID) -> Option<String> {
+        self.users.lock().unwrap().get(&user.to_non_ad()).map(|(_, hash)| hash.clone())
+    }
+
+    /// Cache a fetched device list, returning the devices that were cached
+    /// before but are gone now.
+    pub fn update(&self, list: &UserDevices) -> Vec<JID> {
+        let user = list.user.to_non_ad();
+        self.stale.lock().unwrap().remove(&user);
+        let hash = device_list_hash(&list.devices);
+        let previous = self.users.lock().unwrap().insert(user, (list.devices.clone(), hash));
+        previous.map(|(devices, _)| devices).unwrap_or_default()
+            .into_iter()
+            .filter(|device| !list.devices.contains(device))
+            .collect()
+    }
+
+    /// Compare a hash announced by the server with the cached one, marking
+    /// the list stale if they differ. Users not cached are left alone; their
+    /// list is fetched when first needed.
+    pub fn check_hash(&self, user: &JID, hash: &str) -> bool {
+        let stale = self.hash(user).is_some_and(|cached| cached != hash);
+        if stale {
+            self.mark_stale(user);
+        }
+        stale
+    }
+
+    /// Ask for the device list of a user to be fetched again.
+    pub fn mark_stale(&self, user: &JID) {
+        self.stale.lock().unwrap().insert(user.to_non_ad());
+        self.stale_changed.notify_one();
+    }
+
+    /// Take the users whose list needs fetching.
+    pub fn take_stale(&self) -> Vec<JID> {
+        self.stale.lock().unwrap().drain().collect()
+    }
+
+    /// Put back users taken with `take_stale` whose list could not be
+    /// fetched, without waking the refresh task.
+    pub fn restore_stale(&self, users: Vec<JID>) {
+        self.stale.lock().unwrap().extend(users);
+    }
+
+    /// Ask for a message the server refused for going to outdated devices
+    /// to be sent again once the stale lists are fetched.
+    pub fn queue_resend(&self, id: &str) {
+        let mut resends = self.resends.lock().unwrap();
+        if !resends.iter().any(|queued| queued == id) {
+            resends.push(id.to_string());
+        }
+        self.stale_changed.notify_one();
+    }
+
+    /// Take the messages waiting to be sent again.
+    pub fn take_resends(&self) -> Vec<String> {
+        std::mem::take(&mut *self.resends.lock().unwrap())
+    }
+
+    /// Put back messages taken with `take_resends` that could not be sent.
+    pub fn restore_resends(&self, ids: Vec<String>) {
+        self.resends.lock().unwrap().extend(ids);
+    }
+}
+
+/// Whether a notification announces a device list change.
+pub fn is_devices_notification(node: &Node) -> bool {
+    node.tag == "notification" && node.get_attr_str("type") == Some("devices")
+}
+
+/// Parse a `devices` notification.
+///
+/// Added and removed devices are listed in `<add>` and `<remove>` children,
+/// which carry the new `device_hash`.
+pub fn parse_devices_notification(node: &Node) -> Option<LinkedDevicesChanged> {
+    let user = node.parse_attr_jid("from")?;
+    let mut change = LinkedDevicesChanged {
+        user: user.to_non_ad(),
+        added: Vec::new(),
+        removed: Vec::new(),
+        device_hash: None,
+    };
+
+    for child in node.get_children().into_iter().flatten() {
+        let devices = child.get_children_by_tag("device").into_iter()
+            .filter_map(|device| device.parse_attr_jid("jid"));
+        match &*child.tag {
+            "add" => change.added.extend(devices),
+            "remove" => change.removed.extend(devices),
+            "update" => {}
+            _ => continue,
+        }
+        if let Some(hash) = child.get_attr_str("device_hash").or_else(|| child.get_attr_str("hash")) {
+            change.device_hash = Some(hash.to_string());
+        }
+    }
+    Some(change)
+}
+
+impl Client {
+    /// Get the devices of users, the primary phone first.
+    ///
+    /// See `ClientHandle::get_user_devices`.
+    pub async fn get_user_devices(&self, users: &[JID]) -> Result<Vec<UserDevices>, ClientError> {
+        self.connection()?.get_user_devices(users).await
+    }
+
+    /// Get the devices linked to our own account, including the phone and
+    /// this device.
+    pub async fn get_linked_devices(&self) -> Result<Vec<JID>, ClientError> {
+        let own = self.get_jid().await.ok_or(ClientError::NotLoggedIn)?.to_non_ad();
+        let users = self.get_user_devices(std::slice::from_ref(&own)).await?;
+        Ok(users.into_iter()
+            .find(|user| user.user == own)
+            .map(|user| user.devices)
+            .unwrap_or_default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_device_list() {
+        let request = DeviceListRequest { users: vec!["1:4@s.whatsapp.net".parse().unwrap()], sid: "SID1".to_string() };
+        let iq = request.to_node("1");
+        assert_eq!(iq.get_child_by_tag("usync").unwrap().get_attr_str("sid"), Some("SID1"));
+        let user = iq.get_child_by_tag("usync").unwrap()
+            .get_child_by_tag("list").unwrap()
+            .get_child_by_tag("user").unwrap();
+        assert_eq!(user.get_attr_str("jid"), Some("1@s.whatsapp.net"));
+
+        let device = |id: &str| {
+            let mut device = Node::new("device");
+            device.set_attr("id", id.to_string());
+            device
+        };
+        let mut device_list = Node::new("device-list");
+        device_list.add_child(device("7"));
+        device_list.add_child(device("0"));
+        let mut devices = Node::new("devices");
+        devices.add_child(device_list);
+        let mut user = Node::new("user");
+        user.set_attr("jid", JID::new("1", "s.whatsapp.net"));
+        user.add_child(devices);
+        let mut list = Node::new("list");
+        list.add_child(user);
+        let mut usync = Node::new("usync");
+        usync.add_child(list);
+        let mut result = Node::new("iq");
+        result.add_child(usync);
+
+        let users = Vec::<UserDevices>::from_node(&result).unwrap();
+        let ids: Vec<_> = users[0].devices.iter().map(|d| d.to_string()).collect();
+        assert_eq!(ids, ["1@s.whatsapp.net", "1:7@s.whatsapp.net"]);
+    }
+
+    #[test]
+    fn test_cache_goes_stale_on_hash_change() {
+        let cache = DeviceCache::new();
+        let user = JID::new("1", "s.whatsapp.net");
+        let phone: JID = "1@s.whatsapp.net".parse().unwrap();
+        let laptop: JID = "1:3@s.whatsapp.net".parse().unwrap();
+
+        assert!(!cache.check_hash(&user, "2:abc"));
+        let removed = cache.update(&UserDevices { user: user.clone(), devices: vec![phone.clone(), laptop.clone()] });
+        assert!(removed.is_empty());
+        let hash = cache.hash(&user).unwrap();
+        assert_eq!(hash, device_list_hash(&[laptop.clone(), phone.clone()]));
+        assert_eq!(hash, "2:ckbjrxey");
+
+        assert!(!cache.check_hash(&user, &hash));
+        assert!(cache.missing(std::slice::from_ref(&laptop)).is_empty());
+        assert!(cache.check_hash(&user, "2:abc"));
+        assert_eq!(cache.missing(&[laptop.clone(), JID::new("2", "s.whatsapp.net")]).len(), 2);
+        assert_eq!(cache.take_stale(), vec![user.clone()]);
+        assert!(cache.take_stale().is_empty());
+        cache.restore_stale(vec![user.clone()]);
+        assert_eq!(cache.take_stale(), vec![user.clone()]);
+
+        let removed = cache.update(&UserDevices { user: user.clone(), devices: vec![phone.clone()] });
+        assert_eq!(removed, vec![laptop.clone()]);
+        assert_eq!(cache.get(&laptop), Some(vec![phone]));
+        assert_eq!(session_address(&laptop), "1.3");
+    }
+
+    #[test]
+    fn test_parse_devices_notification() {
+        let mut device = Node::new("device");
+        device.set_attr("jid", JID::new_ad("1", 0, 9));
+        let mut add = Node::new("add");
+        add.set_attr("device_hash", "2:abc");
+        add.add_child(device);
+        let mut node = Node::new("notification");
+        node.set_attr("type", "devices");
+        node.set_attr("from", JID::new("1", "s.whatsapp.net"));
+        node.add_child(add);
+
+        assert!(is_devices_notification(&node));
+        let change = parse_devices_notification(&node).unwrap();
+        assert_eq!(change.user, JID::new("1", "s.whatsapp.net"));
+        assert_eq!(change.added.len(), 1);
+        assert_eq!(change.added[0].device, 9);
+        assert!(change.removed.is_empty());
+        assert_eq!(change.device_hash.as_deref(), Some("2:abc"));
+    }
+}
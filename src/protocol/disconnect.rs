@@ -0,0 +1,134 @@
+//! Why a connection ended.
+//!
+//! A connection ends because we close it, because the server ends the
+//! stream with a `<stream:error>`, or because the socket fails. The server
+//! closes the socket right after a stream error, so its reason is recorded
+//! when the node arrives and reported instead of the socket error that
+//! follows.
+
+use std::sync::Mutex;
+
+use crate::binary::Node;
+use crate::types::{DisconnectReason, LoggedOut};
+
+/// Stream error code of a session that is no longer valid.
+pub const LOGGED_OUT_CODE: &str = "401";
+
+/// Login failure code of a client version the server no longer accepts.
+pub const CLIENT_OUTDATED_CODE: &str = "405";
+
+/// Stream error code asking the client to connect again.
+pub const RESTART_REQUIRED_CODE: &str = "515";
+
+/// Reason for a `<stream:error>`, or a `<failure>` during login.
+pub fn parse_stream_error(node: &Node) -> DisconnectReason {
+    let code = match &*node.tag {
+        "failure" => node.get_attr_str("reason"),
+        _ => node.get_attr_str("code"),
+    };
+    let conflict = node.get_child_by_tag("conflict").and_then(|conflict| conflict.get_attr_str("type"));
+    match (code, conflict) {
+        (_, Some("replaced")) => DisconnectReason::Replaced,
+        (_, Some("device_removed")) | (Some(LOGGED_OUT_CODE), _) => DisconnectReason::LoggedOut,
+        (Some(RESTART_REQUIRED_CODE), _) => DisconnectReason::ServerRequested,
+        (code, _) => DisconnectReason::StreamError { code: code.map(str::to_string) },
+    }
+}
+
+/// Whether a connection that ended for `reason` is opened again with
+/// `ClientConfig::auto_reconnect`: not when we closed it, the session
+/// ended, another client took over or the server refuses this version.
+pub fn should_reconnect(reason: &DisconnectReason) -> bool {
+    match reason {
+        DisconnectReason::UserRequested | DisconnectReason::LoggedOut | DisconnectReason::Replaced => false,
+        DisconnectReason::StreamError { code } => code.as_deref() != Some(CLIENT_OUTDATED_CODE),
+        _ => true,
+    }
+}
+
+/// The `LoggedOut` event for a stream error that ended the session.
+pub fn logged_out_event(node: &Node) -> LoggedOut {
+    let reason = node.get_child_by_tag("conflict")
+        .and_then(|conflict| conflict.get_attr_str("type"))
+        .or_else(|| node.get_attr_str("code"))
+        .or_else(|| node.get_attr_str("reason"));
+    LoggedOut { by_user: false, reason: reason.map(str::to_string) }
+}
+
+/// Cause of the end of the current connection, once known.
+#[derive(Default)]
+pub(crate) struct CloseCause(Mutex<Option<DisconnectReason>>);
+
+impl CloseCause {
+    /// Record why the connection is ending. The first cause wins, since
+    /// later ones are only its consequences.
+    pub(crate) fn record(&self, reason: DisconnectReason) {
+        self.0.lock().unwrap().get_or_insert(reason);
+    }
+
+    /// Whether the connection is ending.
+    pub(crate) fn is_recorded(&self) -> bool {
+        self.0.lock().unwrap().is_some()
+    }
+
+    /// Take the cause, leaving none for the next connection.
+    pub(crate) fn take(&self) -> Option<DisconnectReason> {
+        self.0.lock().unwrap().take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stream_error(code: &str, conflict: Option<&str>) -> Node {
+        let mut node = Node::new("stream:error");
+        node.set_attr("code", code.to_string());
+        if let Some(conflict) = conflict {
+            let mut child = Node::new("conflict");
+            child.set_attr("type", conflict.to_string());
+            node.add_child(child);
+        }
+        node
+    }
+
+    #[test]
+    fn test_parse_stream_error() {
+        assert_eq!(parse_stream_error(&stream_error("401", None)), DisconnectReason::LoggedOut);
+        assert_eq!(parse_stream_error(&stream_error("401", Some("device_removed"))), DisconnectReason::LoggedOut);
+        assert_eq!(parse_stream_error(&stream_error("409", Some("replaced"))), DisconnectReason::Replaced);
+        assert_eq!(parse_stream_error(&stream_error("515", None)), DisconnectReason::ServerRequested);
+        assert_eq!(
+            parse_stream_error(&stream_error("503", None)),
+            DisconnectReason::StreamError { code: Some("503".to_string()) },
+        );
+
+        let mut failure = Node::new("failure");
+        failure.set_attr("reason", "401");
+        assert_eq!(parse_stream_error(&failure), DisconnectReason::LoggedOut);
+        assert_eq!(logged_out_event(&failure).reason.as_deref(), Some("401"));
+        assert_eq!(logged_out_event(&stream_error("401", Some("device_removed"))).reason.as_deref(), Some("device_removed"));
+    }
+
+    #[test]
+    fn test_should_reconnect() {
+        assert!(should_reconnect(&DisconnectReason::Stale));
+        assert!(should_reconnect(&DisconnectReason::ServerRequested));
+        assert!(should_reconnect(&DisconnectReason::NetworkError("reset".to_string())));
+        assert!(should_reconnect(&DisconnectReason::StreamError { code: Some("503".to_string()) }));
+        assert!(!should_reconnect(&DisconnectReason::StreamError { code: Some(CLIENT_OUTDATED_CODE.to_string()) }));
+        assert!(!should_reconnect(&DisconnectReason::UserRequested));
+        assert!(!should_reconnect(&DisconnectReason::LoggedOut));
+        assert!(!should_reconnect(&DisconnectReason::Replaced));
+    }
+
+    #[test]
+    fn test_first_close_cause_wins() {
+        let cause = CloseCause::default();
+        assert!(!cause.is_recorded());
+        cause.record(DisconnectReason::Replaced);
+        cause.record(DisconnectReason::NetworkError("reset".to_string()));
+        assert_eq!(cause.take(), Some(DisconnectReason::Replaced));
+        assert_eq!(cause.take(), None);
+    }
+}
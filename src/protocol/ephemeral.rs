@@ -0,0 +1,150 @@
+//! Disappearing message timers.
+//!
+//! A chat with disappearing messages on expects every message sent to it
+//! to carry the timer in its `ContextInfo`. The timer of each chat is kept
+//! in its `ChatSettings`, filled from group metadata, group setting
+//! changes and the ephemeral setting protocol messages of one-to-one
+//! chats, and applied to outgoing messages as they are sent. Plain
+//! `<body>` text and `<media>` nodes have no context and carry the timer in
+//! an `expiration` attribute instead.
+
+use std::time::Duration;
+use prost::Message as _;
+
+use crate::binary::{Node, NodeContent};
+use crate::proto::{protocol_message_type, ContextInfo, E2eMessage, ExtendedTextMessage};
+use crate::types::JID;
+
+/// Set the disappearing timer on the context of a message.
+///
+/// Plain conversation text has no context, so it is turned into an
+/// extended text message.
+pub fn apply_expiration(message: &mut E2eMessage, expiration: u32) {
+    if let Some(text) = message.conversation.take() {
+        message.extended_text_message = Some(ExtendedTextMessage { text: Some(text), context_info: None });
+    }
+    let contexts = [
+        message.extended_text_message.as_mut().map(|m| &mut m.context_info),
+        message.poll_creation_message.as_mut().map(|m| &mut m.context_info),
+        message.list_message.as_mut().map(|m| &mut m.context_info),
+        message.product_message.as_mut().map(|m| &mut m.context_info),
+        message.buttons_message.as_mut().map(|m| &mut m.context_info),
+        message.buttons_response_message.as_mut().map(|m| &mut m.context_info),
+        message.list_response_message.as_mut().map(|m| &mut m.context_info),
+        message.template_button_reply_message.as_mut().map(|m| &mut m.context_info),
+    ];
+    for context in contexts.into_iter().flatten() {
+        context.get_or_insert_with(ContextInfo::default).expiration = Some(expiration);
+    }
+}
+
+/// Set the disappearing timer on an outgoing message node: in the context
+/// of an end-to-end `<enc>` payload, or on its `<body>` or `<media>`,
+/// view-once media included. Returns whether the node was changed.
+pub fn apply_ephemeral_timer(node: &mut Node, timer: Duration) -> bool {
+    if node.tag != "message" {
+        return false;
+    }
+    let expiration = timer.as_secs().min(u32::MAX as u64) as u32;
+    let NodeContent::Children(children) = &mut node.content else {
+        return false;
+    };
+    let mut applied = false;
+    for child in children.iter_mut() {
+        match &*child.tag {
+            "enc" if child.get_attr_str("type") == Some("msg") => {
+                let Some(mut message) = child.get_bytes().and_then(|payload| E2eMessage::decode(payload).ok()) else {
+                    continue;
+                };
+                apply_expiration(&mut message, expiration);
+                child.set_bytes(message.encode_to_vec());
+                applied = true;
+            }
+            "body" | "media" => {
+                child.set_attr("expiration", expiration.to_string());
+                applied = true;
+            }
+            "view_once" => {
+                if let NodeContent::Children(wrapped) = &mut child.content {
+                    for media in wrapped.iter_mut().filter(|media| media.tag == "media") {
+                        media.set_attr("expiration", expiration.to_string());
+                        applied = true;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    applied
+}
+
+/// Read an ephemeral setting protocol message: the chat whose timer
+/// changed and the new timer, `None` for off.
+pub fn parse_ephemeral_setting(node: &Node) -> Option<(JID, Option<Duration>)> {
+    let payload = node.get_child_by_tag("enc")?.get_bytes()?;
+    let protocol = E2eMessage::decode(payload).ok()?.protocol_message?;
+    if protocol.r#type != Some(protocol_message_type::EPHEMERAL_SETTING) {
+        return None;
+    }
+    let chat = node.parse_attr_jid("from")?;
+    let seconds = protocol.ephemeral_expiration.unwrap_or(0);
+    Some((chat, (seconds > 0).then(|| Duration::from_secs(seconds.into()))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::ProtocolMessage;
+    use crate::protocol::message::{build_e2e_message, build_media_message, build_text_message};
+    use crate::protocol::msgsecret::build_poll_message;
+
+    #[test]
+    fn test_apply_ephemeral_timer() {
+        let chat = JID::new("1", "s.whatsapp.net");
+        let text = E2eMessage { conversation: Some("hi".to_string()), ..Default::default() };
+        let mut node = build_e2e_message(&chat, "MSG1", "text", None, &text);
+        assert!(apply_ephemeral_timer(&mut node, Duration::from_secs(86400)));
+
+        let sent = E2eMessage::decode(node.get_child_by_tag("enc").unwrap().get_bytes().unwrap()).unwrap();
+        assert!(sent.conversation.is_none());
+        let extended = sent.extended_text_message.unwrap();
+        assert_eq!(extended.text.as_deref(), Some("hi"));
+        assert_eq!(extended.context_info.unwrap().expiration, Some(86400));
+
+        let poll = build_poll_message("Lunch?", &["yes".to_string()], 1).0;
+        let mut node = build_e2e_message(&chat, "MSG2", "poll", None, &poll);
+        assert!(apply_ephemeral_timer(&mut node, Duration::from_secs(3600)));
+        let sent = E2eMessage::decode(node.get_child_by_tag("enc").unwrap().get_bytes().unwrap()).unwrap();
+        assert_eq!(sent.poll_creation_message.unwrap().context_info.unwrap().expiration, Some(3600));
+
+        let mut body = build_text_message(&chat, "hi", Some("MSG3"));
+        assert!(apply_ephemeral_timer(&mut body, Duration::from_secs(86400)));
+        assert_eq!(body.get_child_by_tag("body").unwrap().get_attr_str("expiration"), Some("86400"));
+
+        let mut media = build_media_message(&chat, "image", "https://mmg.whatsapp.net/d/f/a.enc", "image/jpeg", None);
+        assert!(apply_ephemeral_timer(&mut media, Duration::from_secs(86400)));
+        assert_eq!(media.get_child_by_tag("media").unwrap().get_attr_str("expiration"), Some("86400"));
+
+        assert!(!apply_ephemeral_timer(&mut Node::new("message"), Duration::from_secs(86400)));
+    }
+
+    #[test]
+    fn test_parse_ephemeral_setting() {
+        let chat = JID::new("1", "s.whatsapp.net");
+        let setting = |seconds| E2eMessage {
+            protocol_message: Some(Box::new(ProtocolMessage {
+                r#type: Some(protocol_message_type::EPHEMERAL_SETTING),
+                ephemeral_expiration: Some(seconds),
+                ..Default::default()
+            })),
+            ..Default::default()
+        };
+        let mut node = build_e2e_message(&chat, "MSG1", "text", None, &setting(604800));
+        node.set_attr("from", chat.clone());
+        assert_eq!(parse_ephemeral_setting(&node), Some((chat.clone(), Some(Duration::from_secs(604800)))));
+
+        let mut node = build_e2e_message(&chat, "MSG2", "text", None, &setting(0));
+        node.set_attr("from", chat.clone());
+        assert_eq!(parse_ephemeral_setting(&node), Some((chat, None)));
+    }
+}
@@ -0,0 +1,184 @@
+//! Flood protection for incoming events.
+//!
+//! `FloodGuard` is an event middleware giving every sender a token bucket.
+//! Messages, receipts, presences, chat states and call offers from a
+//! sender whose bucket is empty are dropped. The flood is reported with a
+//! single `Event::FloodDetected` when it starts, and ends once the sender
+//! has a token again.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+
+use crate::protocol::client::ClientConfig;
+use crate::protocol::clock::{Clock, SystemClock};
+use crate::protocol::middleware::{EventMiddleware, Next};
+use crate::protocol::ratelimit::{RateLimit, RateLimiter};
+use crate::types::{Event, FloodDetected, Redacted, JID};
+
+/// Number of tracked senders above which idle ones are forgotten.
+const PRUNE_THRESHOLD: usize = 1024;
+
+struct SenderState {
+    limiter: RateLimiter,
+    last_seen: DateTime<Utc>,
+    flooding: bool,
+}
+
+/// Middleware limiting how many events each sender can raise.
+///
+/// Opt in with `Client::add_middleware`, passing the client's config so
+/// the guard keeps its clock and log redaction:
+///
+/// ```ignore
+/// let guard = FloodGuard::with_config(RateLimit { burst: 10, per_second: 1.0 }, &config);
+/// let mut client = Client::with_config(config);
+/// client.add_middleware(guard);
+/// ```
+pub struct FloodGuard {
+    limit: RateLimit,
+    clock: Arc<dyn Clock>,
+    redact_logs: bool,
+    senders: Mutex<HashMap<JID, SenderState>>,
+}
+
+impl FloodGuard {
+    /// Create a guard allowing each sender `limit`, timed by the system
+    /// clock and logging senders in full.
+    pub fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            clock: Arc::new(SystemClock),
+            redact_logs: false,
+            senders: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Create a guard allowing each sender `limit`, timed by
+    /// `ClientConfig::clock` and following `ClientConfig::redact_logs`.
+    pub fn with_config(limit: RateLimit, config: &ClientConfig) -> Self {
+        Self {
+            clock: config.clock.clone(),
+            redact_logs: config.redact_logs,
+            ..Self::new(limit)
+        }
+    }
+
+    /// Get the configured limit.
+    pub fn limit(&self) -> RateLimit {
+        self.limit
+    }
+
+    /// Whether an event from `sender` passes, and whether a flood starts
+    /// with it.
+    fn check(&self, sender: &JID) -> (bool, bool) {
+        let mut senders = self.senders.lock().unwrap();
+        let now = self.clock.now();
+        if senders.len() >= PRUNE_THRESHOLD {
+            // A sender idle long enough to refill the whole bucket loses
+            // nothing by being forgotten
+            let refill = if self.limit.per_second > 0.0 {
+                Duration::from_secs_f64(self.limit.burst.max(1) as f64 / self.limit.per_second)
+            } else {
+                Duration::MAX
+            };
+            senders.retain(|_, state| (now - state.last_seen).to_std().unwrap_or_default() < refill);
+        }
+
+        let state = senders.entry(sender.clone()).or_insert_with(|| SenderState {
+            limiter: RateLimiter::with_clock(self.limit, self.clock.clone()),
+            last_seen: now,
+            flooding: false,
+        });
+        state.last_seen = now;
+        if state.limiter.try_acquire() {
+            state.flooding = false;
+            (true, false)
+        } else {
+            let starts = !state.flooding;
+            state.flooding = true;
+            (false, starts)
+        }
+    }
+}
+
+/// Sender an event is limited by, if it is limited.
+fn limited_sender(event: &Event) -> Option<&JID> {
+    match event {
+        Event::Message(message) if !message.info.is_from_me => Some(&message.info.sender),
+        Event::Receipt(receipt) => Some(&receipt.sender),
+        Event::Presence(presence) => Some(&presence.from),
+        Event::ChatState(state) => Some(&state.sender),
+        Event::CallOffer(offer) => Some(&offer.from),
+        _ => None,
+    }
+}
+
+impl EventMiddleware for FloodGuard {
+    fn handle(&self, event: Event, next: Next<'_>) {
+        let Some(sender) = limited_sender(&event).map(JID::to_non_ad) else {
+            return next.run(event);
+        };
+        match self.check(&sender) {
+            (true, _) => next.run(event),
+            (false, true) => {
+                log::warn!(
+                    "dropping events from {}, which exceeds the flood limit",
+                    Redacted::new(&sender, self.redact_logs)
+                );
+                next.run(Event::FloodDetected(FloodDetected { sender }));
+            }
+            (false, false) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use crate::protocol::middleware::run_pipeline;
+    use crate::types::{ChatState, ChatStateType, Connected};
+
+    #[test]
+    fn test_flooding_sender_is_dropped() {
+        let stages: Vec<Arc<dyn EventMiddleware>> =
+            vec![Arc::new(FloodGuard::new(RateLimit { burst: 2, per_second: 0.001 }))];
+        let typing = |sender: &str| Event::ChatState(ChatState {
+            chat: JID::new("1", "s.whatsapp.net"),
+            sender: sender.parse().unwrap(),
+            state: ChatStateType::Composing,
+        });
+
+        let delivered = RefCell::new(Vec::new());
+        let deliver = |event| delivered.borrow_mut().push(event);
+        for sender in ["1@s.whatsapp.net", "1:2@s.whatsapp.net", "1@s.whatsapp.net", "1@s.whatsapp.net"] {
+            run_pipeline(&stages, typing(sender), &deliver);
+        }
+        run_pipeline(&stages, typing("2@s.whatsapp.net"), &deliver);
+        run_pipeline(&stages, Event::Connected(Connected { is_reconnect: false }), &deliver);
+
+        let delivered = delivered.into_inner();
+        assert_eq!(delivered.len(), 5);
+        assert!(matches!(&delivered[2], Event::FloodDetected(flood) if flood.sender.user == "1"));
+        assert!(matches!(&delivered[3], Event::ChatState(state) if state.sender.user == "2"));
+        assert!(matches!(delivered[4], Event::Connected(_)));
+    }
+
+    #[test]
+    fn test_flood_ends_on_client_clock() {
+        use crate::protocol::clock::ManualClock;
+
+        let clock = Arc::new(ManualClock::new(Utc::now()));
+        let config = ClientConfig { clock: clock.clone(), redact_logs: true, ..Default::default() };
+        let guard = FloodGuard::with_config(RateLimit { burst: 1, per_second: 1.0 }, &config);
+        let sender = JID::new("1", "s.whatsapp.net");
+
+        assert_eq!(guard.check(&sender), (true, false));
+        assert_eq!(guard.check(&sender), (false, true));
+        assert_eq!(guard.check(&sender), (false, false));
+        clock.advance(chrono::Duration::seconds(1));
+        assert_eq!(guard.check(&sender), (true, false));
+    }
+}
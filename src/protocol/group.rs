@@ -0,0 +1,1104 @@
+//! Group queries and notifications.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+
+use crate::binary::Node;
+use crate::proto::ContextInfo;
+use crate::protocol::client::{Client, ClientError};
+use crate::protocol::clock::{Clock, SystemClock};
+use crate::protocol::request::{build_iq_set, IqError, IqRequest, IqResponse, IqType};
+use crate::types::{
+    servers, Event, GroupDescriptionChanged, GroupInfo, GroupJoinRequest, GroupKind, GroupParticipant,
+    GroupParticipantsUpdate, GroupPhotoChanged, GroupSetting, GroupSettingChanged, JoinRequest, Mentions, ParticipantAction, ParticipantResult,
+    ParticipantsPage, SubGroup, JID, SERVER_JID,
+};
+
+/// Members fetched per page of a large group.
+pub const PARTICIPANTS_PAGE_SIZE: u32 = 500;
+
+/// Query for the metadata of a group.
+pub struct GroupInfoRequest {
+    pub group: JID,
+}
+
+impl IqRequest for GroupInfoRequest {
+    type Response = GroupInfo;
+
+    fn namespace(&self) -> &str {
+        "w:g2"
+    }
+
+    fn iq_type(&self) -> IqType {
+        IqType::Get
+    }
+
+    fn target(&self) -> JID {
+        self.group.clone()
+    }
+
+    fn content(&self) -> Vec<Node> {
+        let mut query = Node::new("query");
+        query.set_attr("request", "interactive");
+        vec![query]
+    }
+}
+
+impl IqResponse for GroupInfo {
+    fn from_node(node: &Node) -> Result<Self, IqError> {
+        let group = node.get_child_by_tag("group")
+            .ok_or_else(|| IqError::MalformedResponse("missing <group>".to_string()))?;
+        parse_group_node(group)
+    }
+}
+
+/// Query for one page of the members of a group, for groups too large
+/// to be listed whole in their metadata.
+pub struct GroupParticipantsRequest {
+    pub group: JID,
+    /// Cursor from the previous page, `None` for the first
+    pub after: Option<String>,
+    pub limit: u32,
+}
+
+impl IqRequest for GroupParticipantsRequest {
+    type Response = ParticipantsPage;
+
+    fn namespace(&self) -> &str {
+        "w:g2"
+    }
+
+    fn iq_type(&self) -> IqType {
+        IqType::Get
+    }
+
+    fn target(&self) -> JID {
+        self.group.clone()
+    }
+
+    fn content(&self) -> Vec<Node> {
+        let mut participants = Node::new("participants");
+        participants.set_attr("limit", self.limit.to_string());
+        if let Some(after) = &self.after {
+            participants.set_attr("after", after.clone());
+        }
+        vec![participants]
+    }
+}
+
+impl IqResponse for ParticipantsPage {
+    fn from_node(node: &Node) -> Result<Self, IqError> {
+        let participants = node.get_child_by_tag("participants")
+            .ok_or_else(|| IqError::MalformedResponse("missing <participants>".to_string()))?;
+        Ok(ParticipantsPage {
+            participants: participants.get_children_by_tag("participant")
+                .into_iter()
+                .filter_map(parse_participant)
+                .collect(),
+            next: participants.get_attr_str("next").map(String::from),
+        })
+    }
+}
+
+/// Query for the groups linked to a community.
+pub struct SubGroupsRequest {
+    pub community: JID,
+}
+
+impl IqRequest for SubGroupsRequest {
+    type Response = Vec<SubGroup>;
+
+    fn namespace(&self) -> &str {
+        "w:g2"
+    }
+
+    fn iq_type(&self) -> IqType {
+        IqType::Get
+    }
+
+    fn target(&self) -> JID {
+        self.community.clone()
+    }
+
+    fn content(&self) -> Vec<Node> {
+        vec![Node::new("sub_groups")]
+    }
+}
+
+impl IqResponse for Vec<SubGroup> {
+    fn from_node(node: &Node) -> Result<Self, IqError> {
+        let sub_groups = node.get_child_by_tag("sub_groups")
+            .ok_or_else(|| IqError::MalformedResponse("missing <sub_groups>".to_string()))?;
+
+        sub_groups.get_children_by_tag("group")
+            .into_iter()
+            .map(|group| {
+                Ok(SubGroup {
+                    jid: parse_group_id(group)?,
+                    name: group.get_attr_str("subject").unwrap_or_default().to_string(),
+                    is_default: group.get_child_by_tag("default_sub_group").is_some(),
+                })
+            })
+            .collect()
+    }
+}
+
+/// Query for pending join requests of a group.
+pub struct JoinRequestsRequest {
+    pub group: JID,
+}
+
+impl IqRequest for JoinRequestsRequest {
+    type Response = Vec<JoinRequest>;
+
+    fn namespace(&self) -> &str {
+        "w:g2"
+    }
+
+    fn iq_type(&self) -> IqType {
+        IqType::Get
+    }
+
+    fn target(&self) -> JID {
+        self.group.clone()
+    }
+
+    fn content(&self) -> Vec<Node> {
+        vec![Node::new("membership_approval_requests")]
+    }
+}
+
+impl IqResponse for Vec<JoinRequest> {
+    fn from_node(node: &Node) -> Result<Self, IqError> {
+        let requests = node.get_child_by_tag("membership_approval_requests")
+            .ok_or_else(|| IqError::MalformedResponse("missing <membership_approval_requests>".to_string()))?;
+
+        Ok(requests.get_children_by_tag("membership_approval_request")
+            .into_iter()
+            .filter_map(|request| {
+                Some(JoinRequest {
+                    jid: request.parse_attr_jid("jid")?,
+                    requested_at: request.get_attr_str("request_time")
+                        .and_then(|t| t.parse().ok())
+                        .unwrap_or(0),
+                })
+            })
+            .collect())
+    }
+}
+
+/// Approve or reject pending join requests.
+pub struct JoinRequestsActionRequest {
+    pub group: JID,
+    pub participants: Vec<JID>,
+    pub approve: bool,
+}
+
+impl JoinRequestsActionRequest {
+    fn action(&self) -> &'static str {
+        if self.approve { "approve" } else { "reject" }
+    }
+}
+
+impl IqRequest for JoinRequestsActionRequest {
+    type Response = Vec<ParticipantResult>;
+
+    fn namespace(&self) -> &str {
+        "w:g2"
+    }
+
+    fn iq_type(&self) -> IqType {
+        IqType::Set
+    }
+
+    fn target(&self) -> JID {
+        self.group.clone()
+    }
+
+    fn content(&self) -> Vec<Node> {
+        let mut action = Node::new(self.action());
+        for jid in &self.participants {
+            let mut participant = Node::new("participant");
+            participant.set_attr("jid", jid.to_string());
+            action.add_child(participant);
+        }
+        let mut node = Node::new("membership_requests_action");
+        node.add_child(action);
+        vec![node]
+    }
+}
+
+impl IqResponse for Vec<ParticipantResult> {
+    fn from_node(node: &Node) -> Result<Self, IqError> {
+        // Results are nested one level below the action node
+        let container = node.get_children()
+            .and_then(|children| children.first())
+            .ok_or_else(|| IqError::MalformedResponse("missing action results".to_string()))?;
+
+        Ok(container.get_children()
+            .into_iter()
+            .flatten()
+            .flat_map(|action| action.get_children_by_tag("participant"))
+            .filter_map(|participant| {
+                Some(ParticipantResult {
+                    jid: participant.parse_attr_jid("jid")?,
+                    error: participant.get_attr_str("error").and_then(|e| e.parse().ok()),
+                })
+            })
+            .collect())
+    }
+}
+
+/// Group administration request without a response body.
+struct GroupSetRequest {
+    target: JID,
+    content: Node,
+}
+
+impl IqRequest for GroupSetRequest {
+    type Response = ();
+
+    fn namespace(&self) -> &str {
+        "w:g2"
+    }
+
+    fn iq_type(&self) -> IqType {
+        IqType::Set
+    }
+
+    fn target(&self) -> JID {
+        self.target.clone()
+    }
+
+    fn content(&self) -> Vec<Node> {
+        vec![self.content.clone()]
+    }
+}
+
+/// Request setting or removing the photo of a group.
+pub struct GroupPhotoRequest {
+    pub group: JID,
+    /// JPEG image, `None` to remove the photo
+    pub image: Option<Vec<u8>>,
+}
+
+impl IqRequest for GroupPhotoRequest {
+    type Response = Node;
+
+    fn namespace(&self) -> &str {
+        "w:profile:picture"
+    }
+
+    fn iq_type(&self) -> IqType {
+        IqType::Set
+    }
+
+    fn content(&self) -> Vec<Node> {
+        let Some(image) = &self.image else {
+            return Vec::new();
+        };
+        let mut picture = Node::new("picture");
+        picture.set_attr("type", "image");
+        picture.set_bytes(image.clone());
+        vec![picture]
+    }
+
+    /// Photos go to the server, naming the group as the `target`.
+    fn to_node(&self, id: &str) -> Node {
+        let mut node = build_iq_set(id, self.namespace(), Some(&SERVER_JID.to_string()));
+        node.set_attr("target", self.group.clone());
+        node.set_children(self.content());
+        node
+    }
+}
+
+/// Build the node replacing the description `prev` of a group with
+/// `text`, under the new ID `id`. Empty text removes the description.
+pub fn build_description_node(text: &str, id: &str, prev: Option<&str>) -> Node {
+    let mut description = Node::new("description");
+    description.set_attr("id", id);
+    if let Some(prev) = prev {
+        description.set_attr("prev", prev);
+    }
+    if text.is_empty() {
+        description.set_attr("delete", "true");
+    } else {
+        let mut body = Node::new("body");
+        body.set_bytes(text.as_bytes().to_vec());
+        description.add_child(body);
+    }
+    description
+}
+
+/// Wrap a group JID in a `<group jid=...>` node under a parent tag.
+fn group_ref(parent: &'static str, attr: (&'static str, &str), group: &JID) -> Node {
+    let mut group_node = Node::new("group");
+    group_node.set_attr("jid", group.to_string());
+    let mut node = Node::new(parent);
+    node.set_attr(attr.0, attr.1);
+    node.add_child(group_node);
+    node
+}
+
+impl Client {
+    /// Get the metadata of a group, from the cache if it was fetched
+    /// within `ClientConfig::group_cache_ttl`.
+    ///
+    /// See `ClientHandle::get_group_info`.
+    pub async fn get_group_info(&self, group: &JID) -> Result<GroupInfo, ClientError> {
+        self.connection()?.get_group_info(group).await
+    }
+
+    /// Fetch the metadata of a group from the server, bypassing and
+    /// updating the cache.
+    pub async fn refresh_group_info(&self, group: &JID) -> Result<GroupInfo, ClientError> {
+        self.connection()?.refresh_group_info(group).await
+    }
+
+    /// Fetch all members of a group page by page, for groups too large to
+    /// be listed whole by `get_group_info`.
+    pub async fn get_group_participants(&self, group: &JID) -> Result<Vec<GroupParticipant>, ClientError> {
+        self.connection()?.get_group_participants(group).await
+    }
+
+    /// Build text mentioning every other member of a group, with the
+    /// JIDs a message needs to carry for the mentions to notify them.
+    ///
+    /// Uses the cached member list, which for large groups is fetched in
+    /// pages.
+    pub async fn mention_all(&self, group: &JID) -> Result<Mentions, ClientError> {
+        let info = self.get_group_info(group).await?;
+        let own = self.get_jid().await;
+        Ok(build_mentions(&info.participants, own.as_ref()))
+    }
+
+    /// Get the groups linked to a community.
+    pub async fn get_sub_groups(&self, community: &JID) -> Result<Vec<SubGroup>, ClientError> {
+        self.query(&SubGroupsRequest { community: community.clone() }).await
+    }
+
+    /// Join a group linked to a community we are a member of.
+    pub async fn join_linked_group(&self, community: &JID, group: &JID) -> Result<(), ClientError> {
+        let mut join = Node::new("join_linked_group");
+        join.set_attr("jid", group.to_string());
+        self.query(&GroupSetRequest { target: community.clone(), content: join }).await
+    }
+
+    /// Leave a group.
+    pub async fn leave_group(&self, group: &JID) -> Result<(), ClientError> {
+        let mut group_node = Node::new("group");
+        group_node.set_attr("id", group.to_string());
+        let mut leave = Node::new("leave");
+        leave.add_child(group_node);
+        let target = JID::new("", servers::GROUP);
+        self.query(&GroupSetRequest { target, content: leave }).await
+    }
+
+    /// Get the pending requests to join a group. Requires group admin.
+    pub async fn get_group_join_requests(&self, group: &JID) -> Result<Vec<JoinRequest>, ClientError> {
+        self.query(&JoinRequestsRequest { group: group.clone() }).await
+    }
+
+    /// Approve pending join requests. Requires group admin.
+    pub async fn approve_group_join_requests(
+        &self,
+        group: &JID,
+        participants: &[JID],
+    ) -> Result<Vec<ParticipantResult>, ClientError> {
+        self.query(&JoinRequestsActionRequest {
+            group: group.clone(),
+            participants: participants.to_vec(),
+            approve: true,
+        }).await
+    }
+
+    /// Reject pending join requests. Requires group admin.
+    pub async fn reject_group_join_requests(
+        &self,
+        group: &JID,
+        participants: &[JID],
+    ) -> Result<Vec<ParticipantResult>, ClientError> {
+        self.query(&JoinRequestsActionRequest {
+            group: group.clone(),
+            participants: participants.to_vec(),
+            approve: false,
+        }).await
+    }
+
+    /// Turn admin approval of new members on or off. Requires group admin.
+    pub async fn set_group_join_approval(&self, group: &JID, enabled: bool) -> Result<(), ClientError> {
+        let mut group_join = Node::new("group_join");
+        group_join.set_attr("state", if enabled { "on" } else { "off" });
+        let mut mode = Node::new("membership_approval_mode");
+        mode.add_child(group_join);
+        self.query(&GroupSetRequest { target: group.clone(), content: mode }).await
+    }
+
+    /// Allow only admins to send messages, or everyone. Requires group admin.
+    pub async fn set_group_announce(&self, group: &JID, announce: bool) -> Result<(), ClientError> {
+        self.set_group_setting(group, GroupSetting::Announce(announce)).await
+    }
+
+    /// Allow only admins to edit the group info, or everyone. Requires group admin.
+    pub async fn set_group_locked(&self, group: &JID, locked: bool) -> Result<(), ClientError> {
+        self.set_group_setting(group, GroupSetting::Locked(locked)).await
+    }
+
+    /// Set the default disappearing message timer of a group, or turn it
+    /// off with `None`. Requires group admin.
+    pub async fn set_group_default_ephemeral(
+        &self,
+        group: &JID,
+        timer: Option<Duration>,
+    ) -> Result<(), ClientError> {
+        let timer = timer.filter(|t| !t.is_zero());
+        self.set_group_setting(group, GroupSetting::Ephemeral(timer)).await
+    }
+
+    /// Set the description of a group, or remove it with empty text.
+    ///
+    /// The change must name the description it replaces, so the group's
+    /// current metadata is fetched first.
+    pub async fn set_group_description(&self, group: &JID, text: &str) -> Result<(), ClientError> {
+        let prev = self.refresh_group_info(group).await?.topic_id;
+        let id = self.connection()?.new_message_id();
+        let description = build_description_node(text, &id, prev.as_deref());
+        self.query(&GroupSetRequest { target: group.clone(), content: description }).await
+    }
+
+    /// Set the photo of a group from a JPEG image, returning the ID of the
+    /// new photo.
+    pub async fn set_group_photo(&self, group: &JID, image: Vec<u8>) -> Result<String, ClientError> {
+        let response = self.query(&GroupPhotoRequest { group: group.clone(), image: Some(image) }).await?;
+        response.get_child_by_tag("picture")
+            .and_then(|picture| picture.get_attr_str("id"))
+            .map(String::from)
+            .ok_or_else(|| ClientError::IqFailed(IqError::MalformedResponse("missing picture id".to_string())))
+    }
+
+    /// Remove the photo of a group.
+    pub async fn remove_group_photo(&self, group: &JID) -> Result<(), ClientError> {
+        self.query(&GroupPhotoRequest { group: group.clone(), image: None }).await?;
+        Ok(())
+    }
+
+    async fn set_group_setting(&self, group: &JID, setting: GroupSetting) -> Result<(), ClientError> {
+        self.query(&GroupSetRequest { target: group.clone(), content: setting.to_node() }).await
+    }
+
+    /// Link an existing group to a community. Requires community admin.
+    pub async fn link_group(&self, community: &JID, group: &JID) -> Result<(), ClientError> {
+        let mut links = Node::new("links");
+        links.add_child(group_ref("link", ("link_type", "sub_group"), group));
+        self.query(&GroupSetRequest { target: community.clone(), content: links }).await
+    }
+
+    /// Unlink a group from a community. Requires community admin.
+    pub async fn unlink_group(&self, community: &JID, group: &JID) -> Result<(), ClientError> {
+        let unlink = group_ref("unlink", ("unlink_type", "sub_group"), group);
+        self.query(&GroupSetRequest { target: community.clone(), content: unlink }).await
+    }
+}
+
+/// Get the JID of a `<group>` node, whose id may omit the server.
+fn parse_group_id(group: &Node) -> Result<JID, IqError> {
+    let id = group.get_attr_str("id")
+        .ok_or_else(|| IqError::MalformedResponse("missing group id".to_string()))?;
+    Ok(if id.contains('@') {
+        id.parse().unwrap_or_default()
+    } else {
+        JID::new(id, servers::GROUP)
+    })
+}
+
+/// Parse a `<participant>` node.
+fn parse_participant(node: &Node) -> Option<GroupParticipant> {
+    let jid = node.parse_attr_jid("jid")?;
+    let role = node.get_attr_str("type");
+    Some(GroupParticipant {
+        jid,
+        is_admin: matches!(role, Some("admin" | "superadmin")),
+        is_super_admin: role == Some("superadmin"),
+    })
+}
+
+/// Parse a `<group>` node.
+pub fn parse_group_node(group: &Node) -> Result<GroupInfo, IqError> {
+    let jid = parse_group_id(group)?;
+
+    let mut info = GroupInfo {
+        jid,
+        name: group.get_attr_str("subject").unwrap_or_default().to_string(),
+        topic: None,
+        topic_id: None,
+        owner: group.parse_attr_jid("creator"),
+        created: group.get_attr_str("creation").and_then(|c| c.parse().ok()).unwrap_or(0),
+        kind: GroupKind::Group,
+        is_announce: false,
+        is_locked: false,
+        ephemeral: None,
+        participants: Vec::new(),
+        size: group.get_attr_str("size").and_then(|s| s.parse().ok()),
+    };
+
+    for child in group.get_children().into_iter().flatten() {
+        if let Some(setting) = GroupSetting::from_node(child) {
+            match setting {
+                GroupSetting::Announce(on) => info.is_announce = on,
+                GroupSetting::Locked(on) => info.is_locked = on,
+                GroupSetting::Ephemeral(timer) => info.ephemeral = timer,
+            }
+            continue;
+        }
+
+        match &*child.tag {
+            "participant" => info.participants.extend(parse_participant(child)),
+            "parent" => info.kind = GroupKind::Community,
+            "linked_parent" => {
+                if let Some(parent) = child.parse_attr_jid("jid") {
+                    info.kind = GroupKind::Subgroup {
+                        parent,
+                        is_default: group.get_child_by_tag("default_sub_group").is_some(),
+                    };
+                }
+            }
+            "description" => {
+                info.topic_id = child.get_attr_str("id").map(String::from);
+                info.topic = child.get_child_by_tag("body")
+                    .and_then(|b| b.get_bytes())
+                    .map(|b| String::from_utf8_lossy(b).to_string());
+            }
+            _ => {}
+        }
+    }
+
+    Ok(info)
+}
+
+/// Mention every participant except `exclude`, usually ourselves.
+pub fn build_mentions(participants: &[GroupParticipant], exclude: Option<&JID>) -> Mentions {
+    let jids: Vec<JID> = participants.iter()
+        .map(|participant| participant.jid.to_non_ad())
+        .filter(|jid| exclude.is_none_or(|exclude| *jid != exclude.to_non_ad()))
+        .collect();
+    let text = jids.iter().map(|jid| format!("@{}", jid.user)).collect::<Vec<_>>().join(" ");
+    Mentions { text, jids }
+}
+
+impl Mentions {
+    /// Context info of a message carrying these mentions.
+    pub fn context_info(&self) -> ContextInfo {
+        ContextInfo {
+            mentioned_jid: self.jids.iter().map(|jid| jid.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+}
+
+/// Check whether a node is a group notification.
+pub fn is_group_notification(node: &Node) -> bool {
+    node.tag == "notification" && node.get_attr_str("type") == Some("w:gp2")
+}
+
+/// Parse the join requests announced by a group notification.
+pub fn parse_join_requests(node: &Node) -> Vec<GroupJoinRequest> {
+    let group = node.parse_attr_jid("from").unwrap_or_default();
+    let timestamp = node.get_attr_int("t").unwrap_or(0);
+
+    node.get_children_by_tag("created_membership_requests")
+        .into_iter()
+        .flat_map(|requests| {
+            let method = requests.get_attr_str("request_method").map(String::from);
+            requests.get_children_by_tag("requested_user")
+                .into_iter()
+                .filter_map(move |user| user.parse_attr_jid("jid"))
+                .map(move |requester| (requester, method.clone()))
+        })
+        .map(|(requester, method)| GroupJoinRequest {
+            group: group.clone(),
+            requester,
+            method,
+            timestamp,
+        })
+        .collect()
+}
+
+/// Parse the setting changes announced by a group notification.
+pub fn parse_setting_changes(node: &Node) -> Vec<GroupSettingChanged> {
+    let group = node.parse_attr_jid("from").unwrap_or_default();
+    let author = node.parse_attr_jid("participant");
+    let timestamp = node.get_attr_int("t").unwrap_or(0);
+
+    node.get_children()
+        .into_iter()
+        .flatten()
+        .filter_map(GroupSetting::from_node)
+        .map(|setting| GroupSettingChanged {
+            group: group.clone(),
+            author: author.clone(),
+            setting,
+            timestamp,
+        })
+        .collect()
+}
+
+/// Parse the description change announced by a group notification.
+pub fn parse_description_change(node: &Node) -> Option<GroupDescriptionChanged> {
+    let description = node.get_child_by_tag("description")?;
+    let text = description.get_child_by_tag("body")
+        .and_then(|body| body.get_bytes())
+        .map(|body| String::from_utf8_lossy(body).to_string());
+    Some(GroupDescriptionChanged {
+        group: node.parse_attr_jid("from").unwrap_or_default(),
+        author: node.parse_attr_jid("participant"),
+        description: text.filter(|_| description.get_attr_str("delete") != Some("true")),
+        description_id: description.get_attr_str("id").map(String::from),
+        timestamp: node.get_attr_int("t").unwrap_or(0),
+    })
+}
+
+/// Check whether a node is a profile picture notification.
+pub fn is_picture_notification(node: &Node) -> bool {
+    node.tag == "notification" && node.get_attr_str("type") == Some("picture")
+}
+
+/// Parse the group photo changes of a picture notification. Changes to
+/// the pictures of users are skipped.
+pub fn parse_photo_changes(node: &Node) -> Vec<GroupPhotoChanged> {
+    let timestamp = node.get_attr_int("t").unwrap_or(0);
+    node.get_children()
+        .into_iter()
+        .flatten()
+        .filter(|child| child.tag == "set" || child.tag == "delete")
+        .filter_map(|child| {
+            let group = child.parse_attr_jid("jid")?;
+            if group.server != servers::GROUP {
+                return None;
+            }
+            Some(GroupPhotoChanged {
+                group,
+                author: child.parse_attr_jid("author").or_else(|| node.parse_attr_jid("participant")),
+                picture_id: child.get_attr_str("id").filter(|_| child.tag == "set").map(String::from),
+                timestamp,
+            })
+        })
+        .collect()
+}
+
+/// Parse the participant changes of a group notification.
+pub fn parse_participant_changes(node: &Node) -> Vec<GroupParticipantsUpdate> {
+    let group = node.parse_attr_jid("from").unwrap_or_default();
+    let author = node.parse_attr_jid("participant");
+    let timestamp = node.get_attr_int("t").unwrap_or(0);
+
+    node.get_children()
+        .into_iter()
+        .flatten()
+        .filter_map(|child| {
+            let action = ParticipantAction::from_tag(&child.tag)?;
+            let participants = child.get_children()
+                .into_iter()
+                .flatten()
+                .filter(|n| n.tag == "participant")
+                .filter_map(|n| n.parse_attr_jid("jid"))
+                .collect();
+
+            Some(GroupParticipantsUpdate {
+                group: group.clone(),
+                author: author.clone(),
+                action,
+                participants,
+                timestamp,
+            })
+        })
+        .collect()
+}
+
+/// Metadata of groups, as last fetched, kept for a TTL.
+///
+/// Participant changes drop the group so the next lookup fetches it again;
+/// setting and description changes are applied in place.
+pub struct GroupCache {
+    ttl: Duration,
+    clock: Arc<dyn Clock>,
+    groups: Mutex<HashMap<JID, (GroupInfo, DateTime<Utc>)>>,
+}
+
+impl GroupCache {
+    /// Cache keeping group metadata for `ttl`.
+    pub fn new(ttl: Duration) -> Self {
+        Self::with_clock(ttl, Arc::new(SystemClock))
+    }
+
+    /// Cache keeping group metadata for `ttl`, as told by `clock`.
+    pub fn with_clock(ttl: Duration, clock: Arc<dyn Clock>) -> Self {
+        Self { ttl, clock, groups: Mutex::new(HashMap::new()) }
+    }
+
+    /// Cache fetched metadata.
+    pub fn put(&self, info: &GroupInfo) {
+        let now = self.clock.now();
+        self.groups.lock().unwrap().insert(info.jid.clone(), (info.clone(), now));
+    }
+
+    /// Get the cached metadata of a group, unless it is older than the TTL.
+    pub fn get(&self, group: &JID) -> Option<GroupInfo> {
+        let now = self.clock.now();
+        let mut groups = self.groups.lock().unwrap();
+        let (info, fetched) = groups.get(group)?;
+        if (now - *fetched).to_std().unwrap_or_default() > self.ttl {
+            groups.remove(group);
+            return None;
+        }
+        Some(info.clone())
+    }
+
+    /// Drop the cached metadata of a group.
+    pub fn invalidate(&self, group: &JID) {
+        self.groups.lock().unwrap().remove(group);
+    }
+
+    /// Feed an event to the cache; only group change events are used.
+    pub fn handle_event(&self, event: &Event) {
+        match event {
+            Event::GroupParticipants(update) => self.invalidate(&update.group),
+            Event::GroupDescriptionChanged(change) => {
+                if let Some((info, _)) = self.groups.lock().unwrap().get_mut(&change.group) {
+                    info.topic = change.description.clone();
+                    info.topic_id = change.description_id.clone();
+                }
+            }
+            Event::GroupSettingChanged(change) => {
+                if let Some((info, _)) = self.groups.lock().unwrap().get_mut(&change.group) {
+                    match &change.setting {
+                        GroupSetting::Announce(announce) => info.is_announce = *announce,
+                        GroupSetting::Locked(locked) => info.is_locked = *locked,
+                        GroupSetting::Ephemeral(timer) => info.ephemeral = *timer,
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Forget all cached groups.
+    pub fn clear(&self) {
+        self.groups.lock().unwrap().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn participant(jid: &str, role: Option<&str>) -> Node {
+        let mut node = Node::new("participant");
+        node.set_attr("jid", jid);
+        if let Some(role) = role {
+            node.set_attr("type", role);
+        }
+        node
+    }
+
+    #[test]
+    fn test_parse_group_info() {
+        let mut group = Node::new("group");
+        group.set_attr("id", "120363000000000000");
+        group.set_attr("subject", "Friends");
+        group.set_attr("creator", "1@s.whatsapp.net");
+        group.set_attr("creation", "1700000000");
+        group.add_child(participant("1@s.whatsapp.net", Some("superadmin")));
+        group.add_child(participant("2@s.whatsapp.net", None));
+        let mut iq = Node::new("iq");
+        iq.add_child(group);
+
+        let info = GroupInfo::from_node(&iq).unwrap();
+        assert_eq!(info.jid, JID::new("120363000000000000", "g.us"));
+        assert_eq!(info.name, "Friends");
+        assert_eq!(info.created, 1700000000);
+        assert_eq!(info.participants.len(), 2);
+        assert!(info.participants[0].is_super_admin && info.participants[0].is_admin);
+        assert!(!info.participants[1].is_admin);
+        assert_eq!(info.kind, GroupKind::Group);
+    }
+
+    #[test]
+    fn test_parse_community_kinds() {
+        let mut community = Node::new("group");
+        community.set_attr("id", "100");
+        community.add_child(Node::new("parent"));
+        assert_eq!(parse_group_node(&community).unwrap().kind, GroupKind::Community);
+
+        let mut linked_parent = Node::new("linked_parent");
+        linked_parent.set_attr("jid", "100@g.us");
+        let mut announcements = Node::new("group");
+        announcements.set_attr("id", "101");
+        announcements.add_child(linked_parent);
+        announcements.add_child(Node::new("default_sub_group"));
+        assert_eq!(
+            parse_group_node(&announcements).unwrap().kind,
+            GroupKind::Subgroup { parent: JID::new("100", "g.us"), is_default: true },
+        );
+    }
+
+    #[test]
+    fn test_parse_sub_groups() {
+        let mut default = Node::new("group");
+        default.set_attr("id", "101");
+        default.set_attr("subject", "Announcements");
+        default.add_child(Node::new("default_sub_group"));
+        let mut other = Node::new("group");
+        other.set_attr("id", "102@g.us");
+        other.set_attr("subject", "Off-topic");
+        let mut sub_groups = Node::new("sub_groups");
+        sub_groups.add_child(default);
+        sub_groups.add_child(other);
+        let mut iq = Node::new("iq");
+        iq.add_child(sub_groups);
+
+        let groups = Vec::<SubGroup>::from_node(&iq).unwrap();
+        assert_eq!(groups.len(), 2);
+        assert!(groups[0].is_default);
+        assert_eq!(groups[1].jid, JID::new("102", "g.us"));
+        assert_eq!(groups[1].name, "Off-topic");
+    }
+
+    #[test]
+    fn test_parse_join_requests() {
+        let mut request = Node::new("membership_approval_request");
+        request.set_attr("jid", "5@s.whatsapp.net");
+        request.set_attr("request_time", "1700000000");
+        let mut requests = Node::new("membership_approval_requests");
+        requests.add_child(request);
+        let mut iq = Node::new("iq");
+        iq.add_child(requests);
+
+        let pending = Vec::<JoinRequest>::from_node(&iq).unwrap();
+        assert_eq!(pending, vec![JoinRequest { jid: JID::new("5", "s.whatsapp.net"), requested_at: 1700000000 }]);
+
+        let mut user = Node::new("requested_user");
+        user.set_attr("jid", "5@s.whatsapp.net");
+        let mut created = Node::new("created_membership_requests");
+        created.set_attr("request_method", "invite_link");
+        created.add_child(user);
+        let mut notification = Node::new("notification");
+        notification.set_attr("type", "w:gp2");
+        notification.set_attr("from", "123@g.us");
+        notification.add_child(created);
+
+        let events = parse_join_requests(&notification);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].requester, JID::new("5", "s.whatsapp.net"));
+        assert_eq!(events[0].method.as_deref(), Some("invite_link"));
+        assert!(parse_participant_changes(&notification).is_empty());
+    }
+
+    #[test]
+    fn test_join_request_action() {
+        let request = JoinRequestsActionRequest {
+            group: JID::new("123", "g.us"),
+            participants: vec![JID::new("5", "s.whatsapp.net")],
+            approve: false,
+        };
+        let node = request.to_node("1");
+        let action = node.get_child_by_tag("membership_requests_action").unwrap();
+        assert!(action.get_child_by_tag("reject").is_some());
+
+        let mut participant = Node::new("participant");
+        participant.set_attr("jid", "5@s.whatsapp.net");
+        participant.set_attr("error", "404");
+        let mut reject = Node::new("reject");
+        reject.add_child(participant);
+        let mut container = Node::new("membership_requests_action");
+        container.add_child(reject);
+        let mut iq = Node::new("iq");
+        iq.add_child(container);
+
+        let results = Vec::<ParticipantResult>::from_node(&iq).unwrap();
+        assert_eq!(results, vec![ParticipantResult { jid: JID::new("5", "s.whatsapp.net"), error: Some(404) }]);
+    }
+
+    #[test]
+    fn test_group_settings() {
+        let mut ephemeral = Node::new("ephemeral");
+        ephemeral.set_attr("expiration", "604800");
+        let mut group = Node::new("group");
+        group.set_attr("id", "123");
+        group.add_child(Node::new("announcement"));
+        group.add_child(ephemeral);
+
+        let info = parse_group_node(&group).unwrap();
+        assert!(info.is_announce);
+        assert!(!info.is_locked);
+        assert_eq!(info.ephemeral, Some(Duration::from_secs(604800)));
+
+        let mut notification = Node::new("notification");
+        notification.set_attr("type", "w:gp2");
+        notification.set_attr("from", "123@g.us");
+        notification.set_attr("participant", "1@s.whatsapp.net");
+        notification.add_child(Node::new("locked"));
+        notification.add_child(Node::new("not_ephemeral"));
+
+        let changes = parse_setting_changes(&notification);
+        assert_eq!(changes.len(), 2);
+        assert_eq!(changes[0].setting, GroupSetting::Locked(true));
+        assert_eq!(changes[1].setting, GroupSetting::Ephemeral(None));
+        assert_eq!(changes[0].author, Some(JID::new("1", "s.whatsapp.net")));
+
+        let node = GroupSetting::Ephemeral(Some(Duration::from_secs(86400))).to_node();
+        assert_eq!(node.tag, "ephemeral");
+        assert_eq!(node.get_attr_str("expiration"), Some("86400"));
+    }
+
+    #[test]
+    fn test_link_request() {
+        let request = GroupSetRequest {
+            target: JID::new("100", "g.us"),
+            content: group_ref("unlink", ("unlink_type", "sub_group"), &JID::new("102", "g.us")),
+        };
+        let node = request.to_node("1");
+        assert_eq!(node.get_attr_str("to"), Some("100@g.us"));
+        let unlink = node.get_child_by_tag("unlink").unwrap();
+        assert_eq!(unlink.get_attr_str("unlink_type"), Some("sub_group"));
+        assert_eq!(unlink.get_child_by_tag("group").unwrap().get_attr_str("jid"), Some("102@g.us"));
+    }
+
+    #[test]
+    fn test_parse_participant_changes() {
+        let mut remove = Node::new("remove");
+        remove.add_child(participant("2@s.whatsapp.net", None));
+        remove.add_child(participant("3@s.whatsapp.net", None));
+
+        let mut node = Node::new("notification");
+        node.set_attr("type", "w:gp2");
+        node.set_attr("from", "123@g.us");
+        node.set_attr("participant", "1@s.whatsapp.net");
+        node.set_attr("t", "1700000000");
+        node.add_child(remove);
+        node.add_child(Node::new("subject"));
+
+        assert!(is_group_notification(&node));
+        let changes = parse_participant_changes(&node);
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].action, ParticipantAction::Remove);
+        assert_eq!(changes[0].group, JID::new("123", "g.us"));
+        assert_eq!(changes[0].author, Some(JID::new("1", "s.whatsapp.net")));
+        assert_eq!(changes[0].participants.len(), 2);
+    }
+
+    #[test]
+    fn test_group_cache() {
+        let group = JID::new("123", "g.us");
+        let info = GroupInfo { jid: group.clone(), name: "Friends".to_string(), ..Default::default() };
+        let clock = Arc::new(crate::protocol::clock::ManualClock::new(Utc::now()));
+        let cache = GroupCache::with_clock(Duration::from_secs(60), clock.clone());
+        cache.put(&info);
+        clock.advance(chrono::Duration::seconds(30));
+        assert!(cache.get(&group).is_some());
+        clock.advance(chrono::Duration::seconds(31));
+        assert!(cache.get(&group).is_none());
+
+        cache.put(&info);
+        cache.handle_event(&Event::GroupSettingChanged(GroupSettingChanged {
+            group: group.clone(),
+            author: None,
+            setting: GroupSetting::Announce(true),
+            timestamp: 0,
+        }));
+        assert!(cache.get(&group).unwrap().is_announce);
+
+        cache.handle_event(&Event::GroupParticipants(GroupParticipantsUpdate {
+            group: group.clone(),
+            author: None,
+            action: ParticipantAction::Add,
+            participants: vec![JID::new("4", "s.whatsapp.net")],
+            timestamp: 0,
+        }));
+        assert!(cache.get(&group).is_none());
+    }
+
+    #[test]
+    fn test_description_and_photo_changes() {
+        let node = build_description_node("Weekend plans", "D2", Some("D1"));
+        assert_eq!(node.get_attr_str("prev"), Some("D1"));
+        let mut group = Node::new("group");
+        group.set_attr("id", "123");
+        group.add_child(node.clone());
+        let info = parse_group_node(&group).unwrap();
+        assert_eq!(info.topic.as_deref(), Some("Weekend plans"));
+        assert_eq!(info.topic_id.as_deref(), Some("D2"));
+
+        let mut notification = Node::new("notification");
+        notification.set_attr("type", "w:gp2");
+        notification.set_attr("from", "123@g.us");
+        notification.set_attr("participant", "1@s.whatsapp.net");
+        notification.add_child(build_description_node("", "D3", Some("D2")));
+        let change = parse_description_change(&notification).unwrap();
+        assert_eq!(change.description, None);
+        assert_eq!(change.description_id.as_deref(), Some("D3"));
+        assert_eq!(change.author, Some(JID::new("1", "s.whatsapp.net")));
+
+        let cache = GroupCache::new(Duration::from_secs(60));
+        cache.put(&info);
+        cache.handle_event(&Event::GroupDescriptionChanged(change));
+        assert_eq!(cache.get(&info.jid).unwrap().topic, None);
+
+        let request = GroupPhotoRequest { group: JID::new("123", "g.us"), image: Some(vec![0xFF, 0xD8]) };
+        let node = request.to_node("1");
+        assert_eq!(node.parse_attr_jid("to"), Some(SERVER_JID.clone()));
+        assert_eq!(node.parse_attr_jid("target"), Some(JID::new("123", "g.us")));
+        let picture = request.content().remove(0);
+        assert_eq!(picture.get_attr_str("type"), Some("image"));
+        assert!(GroupPhotoRequest { group: JID::new("123", "g.us"), image: None }.content().is_empty());
+
+        let change = |tag: &'static str, jid: &str| {
+            let mut child = Node::new(tag);
+            child.set_attr("jid", jid);
+            child.set_attr("author", "1@s.whatsapp.net");
+            child.set_attr("id", "P1");
+            child
+        };
+        let mut notification = Node::new("notification");
+        notification.set_attr("type", "picture");
+        notification.add_child(change("set", "123@g.us"));
+        notification.add_child(change("delete", "456@g.us"));
+        notification.add_child(change("set", "2@s.whatsapp.net"));
+        assert!(is_picture_notification(&notification));
+        let changes = parse_photo_changes(&notification);
+        assert_eq!(changes.len(), 2);
+        assert_eq!(changes[0].picture_id.as_deref(), Some("P1"));
+        assert_eq!(changes[1].picture_id, None);
+        assert_eq!(changes[1].author, Some(JID::new("1", "s.whatsapp.net")));
+    }
+
+    #[test]
+    fn test_participant_pages_and_mentions() {
+        let request = GroupParticipantsRequest { group: JID::new("123", "g.us"), after: Some("c1".to_string()), limit: 500 };
+        let participants = request.to_node("1");
+        let participants = participants.get_child_by_tag("participants").unwrap();
+        assert_eq!((participants.get_attr_str("limit"), participants.get_attr_str("after")), (Some("500"), Some("c1")));
+
+        let mut page = Node::new("participants");
+        page.set_attr("next", "c2");
+        page.add_child(participant("1@s.whatsapp.net", Some("admin")));
+        page.add_child(participant("2:3@s.whatsapp.net", None));
+        let mut iq = Node::new("iq");
+        iq.add_child(page);
+        let page = ParticipantsPage::from_node(&iq).unwrap();
+        assert_eq!(page.next.as_deref(), Some("c2"));
+        assert!(page.participants[0].is_admin);
+
+        let mut group = Node::new("group");
+        group.set_attr("id", "123");
+        group.set_attr("size", "1500");
+        assert!(parse_group_node(&group).unwrap().is_truncated());
+
+        let own = JID::new("1", "s.whatsapp.net");
+        let mentions = build_mentions(&page.participants, Some(&own));
+        assert_eq!(mentions.text, "@2");
+        assert_eq!(mentions.jids, vec![JID::new("2", "s.whatsapp.net")]);
+        assert_eq!(mentions.context_info().mentioned_jid, vec!["2@s.whatsapp.net".to_string()]);
+    }
+}
@@ -0,0 +1,1900 @@
+//! Cloneable client handle.
+//!
+//! A `ClientHandle` forwards sends and queries to the connection actor over
+//! a command channel, so it can be shared freely between tasks.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+use futures::stream::{self, BoxStream, StreamExt};
+use tokio::sync::{mpsc, oneshot};
+use tokio_util::sync::CancellationToken;
+
+use sha2::{Digest, Sha256};
+
+use crate::binary::Node;
+use crate::media::{self, MediaConnRequest, MediaType, Transfer, TransferProgress, UploadedMedia};
+use crate::store::{StoredMessage, OutgoingMessage, AppStateSyncKey};
+use crate::types::{JID, Event, GroupInfo, GroupParticipant, MessageBlocked, MessageID, PairError, QRCode, ScheduledMessageSent, servers};
+use crate::protocol::client::{ClientError, ClientInner};
+use crate::protocol::group::{GroupInfoRequest, GroupParticipantsRequest, PARTICIPANTS_PAGE_SIZE};
+use crate::protocol::msgid::validate_message_id;
+use crate::protocol::request::{build_iq_result, IqRequest, IqError, parse_iq_response};
+use crate::protocol::message::{
+    build_chat_state, build_delivery_receipt, build_presence, build_read_receipt,
+};
+use crate::protocol::typing::TypingDuration;
+use crate::protocol::template::{Template, TemplateError};
+use crate::protocol::history::build_history_request;
+use crate::protocol::appstate::build_app_state_key_request;
+use crate::protocol::ephemeral::apply_ephemeral_timer;
+use crate::protocol::devices::{session_address, DeviceListRequest, UserDevices};
+use crate::protocol::prekeys::{build_session, EncryptedMessage, PreKeyBundleRequest, SessionState};
+use crate::protocol::qr::QRError;
+use crate::protocol::intercept::{run_interceptors, SendDecision, SendableMessage};
+use crate::protocol::mediamsg::MediaMessage;
+use crate::protocol::replies::{build_reply_context, build_reply_message};
+use crate::protocol::keep::build_message_key;
+use crate::proto::{ContextInfo, MessageKey};
+
+/// How long to wait before fetching device lists again after a failure.
+const DEVICE_REFRESH_RETRY: Duration = Duration::from_secs(30);
+
+/// Commands processed by the connection actor.
+pub(crate) enum Command {
+    /// Encode and send a node, reporting the outcome
+    Send {
+        node: Node,
+        reply: oneshot::Sender<Result<(), ClientError>>,
+    },
+    /// Deliver an event raised outside the actor, such as by the scheduler
+    Emit(Box<Event>),
+}
+
+/// Content of one message of a bulk send.
+#[derive(Debug, Clone)]
+pub enum BulkContent {
+    Text(String),
+    /// Media file, uploaded once however many recipients it goes to
+    Media(Arc<MediaMessage>),
+}
+
+impl From<String> for BulkContent {
+    fn from(text: String) -> Self {
+        BulkContent::Text(text)
+    }
+}
+
+impl From<&str> for BulkContent {
+    fn from(text: &str) -> Self {
+        BulkContent::Text(text.to_string())
+    }
+}
+
+impl From<MediaMessage> for BulkContent {
+    fn from(message: MediaMessage) -> Self {
+        BulkContent::Media(Arc::new(message))
+    }
+}
+
+impl From<Arc<MediaMessage>> for BulkContent {
+    fn from(message: Arc<MediaMessage>) -> Self {
+        BulkContent::Media(message)
+    }
+}
+
+/// A bulk send message with its media uploaded.
+enum PreparedContent {
+    Text(String),
+    Media(Arc<MediaMessage>, Result<Arc<UploadedMedia>, ClientError>),
+}
+
+/// Outcome of sending to one recipient of a bulk send.
+#[derive(Debug, Clone)]
+pub struct BulkSendResult {
+    /// Recipient of the message
+    pub to: JID,
+    /// Message ID on success
+    pub result: Result<String, ClientError>,
+}
+
+/// Build the `<participants>` node carrying our sender key, encrypted for
+/// each device.
+fn build_key_distribution(encrypted: Vec<(JID, EncryptedMessage)>) -> Node {
+    let mut participants = Node::new("participants");
+    for (device, message) in encrypted {
+        let mut enc = Node::new("enc");
+        enc.set_attr("v", "2");
+        enc.set_attr("type", message.kind);
+        enc.set_bytes(message.data);
+
+        let mut to = Node::new("to");
+        to.set_attr("jid", device.to_string());
+        to.add_child(enc);
+        participants.add_child(to);
+    }
+    participants
+}
+
+/// Cheap, cloneable handle to a connected client.
+#[derive(Clone)]
+pub struct ClientHandle {
+    inner: Arc<ClientInner>,
+    commands: mpsc::Sender<Command>,
+    cancel: CancellationToken,
+}
+
+impl ClientHandle {
+    pub(crate) fn new(
+        inner: Arc<ClientInner>,
+        commands: mpsc::Sender<Command>,
+        cancel: CancellationToken,
+    ) -> Self {
+        Self { inner, commands, cancel }
+    }
+
+    /// Check if the connection behind this handle is still alive.
+    pub fn is_connected(&self) -> bool {
+        !self.commands.is_closed() && !self.cancel.is_cancelled()
+    }
+
+    /// Ask the connection actor to flush queued sends and close.
+    pub(crate) fn close(&self) {
+        self.cancel.cancel();
+    }
+
+    /// Queue a raw node for sending and wait until it is written to the
+    /// socket.
+    ///
+    /// See `Client::send_node`.
+    pub async fn send_node(&self, mut node: Node) -> Result<(), ClientError> {
+        if node.tag == "message" {
+            if let Some(timer) = node.parse_attr_jid("to").and_then(|to| self.inner.chat_ephemeral(&to)) {
+                apply_ephemeral_timer(&mut node, timer);
+            }
+        }
+        if self.inner.is_held_back(&node) {
+            self.emit(Event::WouldHaveSent(node)).await;
+            return Ok(());
+        }
+        let (reply, rx) = oneshot::channel();
+        self.commands.send(Command::Send { node, reply })
+            .await
+            .map_err(|_| ClientError::NotConnected)?;
+        rx.await.map_err(|_| ClientError::NotConnected)?
+    }
+
+    /// Send a text message.
+    ///
+    /// With an outbox attached, the message is saved before it is sent and
+    /// stays queued for retry on reconnect until the server acknowledges it,
+    /// even if this call fails.
+    pub async fn send_message(&self, to: JID, text: &str) -> Result<String, ClientError> {
+        self.send_message_with_id(to, text, &self.inner.new_message_id()).await
+    }
+
+    /// Send a text message with a caller-chosen ID.
+    ///
+    /// The ID must pass `validate_message_id`. Sends are deduplicated by
+    /// ID: if a message with this ID was already sent, or is being sent,
+    /// nothing is sent again and the ID is returned. A failed send can be
+    /// retried with the same ID.
+    ///
+    /// The message passes through the send interceptors first; see
+    /// `Client::add_send_interceptor`.
+    pub async fn send_message_with_id(&self, to: JID, text: &str, id: &str) -> Result<String, ClientError> {
+        validate_message_id(id)?;
+        if !self.inner.sent_ids.claim(id) {
+            return Ok(id.to_string());
+        }
+
+        let result = match self.intercept(SendableMessage::new(id, to, text)).await {
+            Ok(message) => self.persist_and_send(id, message.to, &message.text).await,
+            Err(e) => Err(e),
+        };
+        if result.is_err() {
+            self.inner.sent_ids.release(id);
+        }
+        result.map(|()| id.to_string())
+    }
+
+    /// Send a text message quoting the message `quoted_id` of `to`,
+    /// returning its ID.
+    ///
+    /// The quote is built from the message index. A message missing from
+    /// it is quoted by ID alone, which the recipient may not be able to
+    /// show. The reply passes through the send interceptors but not the
+    /// outbox.
+    pub async fn send_reply(&self, to: JID, quoted_id: &str, text: &str) -> Result<String, ClientError> {
+        let quoted = self.inner.store.get_message_ref(&to, quoted_id)
+            .map_err(|e| ClientError::StoreError(e.to_string()))?;
+        let context = match &quoted {
+            Some(quoted) => build_reply_context(quoted),
+            None => ContextInfo { stanza_id: Some(quoted_id.to_string()), ..Default::default() },
+        };
+
+        let id = self.inner.new_message_id();
+        let message = self.intercept(SendableMessage::new(&id, to, text)).await?;
+        self.send_node(build_reply_message(&message.to, &id, &message.text, context)).await?;
+
+        let sender = self.inner.own_jid().unwrap_or_default();
+        self.inner.record_message(&StoredMessage {
+            id: id.clone(),
+            chat: message.to,
+            sender,
+            is_from_me: true,
+            timestamp: self.inner.server_now().timestamp(),
+            push_name: None,
+            text: Some(message.text),
+        });
+        Ok(id)
+    }
+
+    /// Mark messages in a chat as read.
+    ///
+    /// With `ClientConfig::hide_reads_and_presence` set, a delivery
+    /// receipt is sent instead, so the sender never sees the messages as
+    /// read.
+    pub async fn mark_read(&self, chat: &JID, message_ids: &[String]) -> Result<(), ClientError> {
+        let receipt = if self.inner.config.hide_reads_and_presence {
+            build_delivery_receipt(chat, message_ids)
+        } else {
+            build_read_receipt(chat, message_ids)
+        };
+        self.send_node(receipt).await?;
+        if let Some(change) = self.inner.unread.mark_read(chat, message_ids) {
+            self.emit(Event::UnreadCountChanged(change)).await;
+        }
+        Ok(())
+    }
+
+    /// Announce whether we are online.
+    ///
+    /// With `ClientConfig::hide_reads_and_presence` set, announcing that we
+    /// are online does nothing.
+    pub async fn send_presence(&self, available: bool) -> Result<(), ClientError> {
+        if available && self.inner.config.hide_reads_and_presence {
+            return Ok(());
+        }
+        self.send_node(build_presence(available)).await
+    }
+
+    /// Ask the primary device for up to `count` messages of `chat` older
+    /// than `before`, a Unix timestamp.
+    ///
+    /// The messages arrive later as an `Event::HistorySync` of type
+    /// `OnDemand`, whose `request_id` is the ID returned here.
+    pub async fn request_history(&self, chat: &JID, before: i64, count: u32) -> Result<String, ClientError> {
+        let own = self.inner.device.read().await.jid.clone().ok_or(ClientError::NotLoggedIn)?;
+        let id = self.inner.new_message_id();
+        self.send_node(build_history_request(&own, &id, chat, before, count)).await?;
+        Ok(id)
+    }
+
+    /// Ask the primary device for app state keys.
+    ///
+    /// Keys already requested and not yet received are left out; nothing
+    /// is sent if no key remains. The keys are stored as they arrive.
+    pub async fn request_app_state_keys(&self, key_ids: &[Vec<u8>]) -> Result<(), ClientError> {
+        let own = self.inner.device.read().await.jid.clone().ok_or(ClientError::NotLoggedIn)?;
+        let key_ids = self.inner.app_state_key_requests.claim(key_ids);
+        if key_ids.is_empty() {
+            return Ok(());
+        }
+        let node = build_app_state_key_request(&own, &self.inner.new_message_id(), &key_ids);
+        let result = self.send_node(node).await;
+        if result.is_err() {
+            self.inner.app_state_key_requests.release(&key_ids);
+        }
+        result
+    }
+
+    /// Get an app state key from the store.
+    ///
+    /// A key referenced by a patch but missing from the store is requested
+    /// from the primary device and `None` is returned; retry once it has
+    /// arrived.
+    pub async fn get_app_state_key(&self, key_id: &[u8]) -> Result<Option<AppStateSyncKey>, ClientError> {
+        let key = self.inner.store.get_app_state_key(key_id)
+            .map_err(|e| ClientError::StoreError(e.to_string()))?;
+        if let Some(key) = key {
+            return Ok(Some(key));
+        }
+        self.request_app_state_keys(&[key_id.to_vec()]).await?;
+        Ok(None)
+    }
+
+    /// Get the devices of users, the primary phone first.
+    ///
+    /// The lists are cached, and the sessions of devices that were cached
+    /// before but are gone now are deleted.
+    pub async fn get_user_devices(&self, users: &[JID]) -> Result<Vec<UserDevices>, ClientError> {
+        let lists = self.query(&DeviceListRequest { users: users.to_vec(), sid: self.inner.new_message_id() }).await?;
+        for list in &lists {
+            for device in self.inner.devices.update(list) {
+                if let Err(e) = self.inner.store.delete_session(&session_address(&device)) {
+                    log::warn!("failed to delete session of {}: {}", self.inner.redact(&device), e);
+                }
+            }
+        }
+        Ok(lists)
+    }
+
+    /// Get every device of some users, from the cache where it is current.
+    ///
+    /// Lists that are not cached, or that a device notification or a
+    /// rejected message made stale, are fetched in one query. A user the
+    /// server returns no list for is taken to have only their phone.
+    pub async fn resolve_devices(&self, users: &[JID]) -> Result<Vec<JID>, ClientError> {
+        let missing = self.inner.devices.missing(users);
+        if !missing.is_empty() {
+            self.get_user_devices(&missing).await?;
+        }
+        let mut devices = Vec::new();
+        for user in users.iter().map(JID::to_non_ad) {
+            for device in self.inner.devices.get(&user).unwrap_or_else(|| vec![user.clone()]) {
+                if !devices.contains(&device) {
+                    devices.push(device);
+                }
+            }
+        }
+        Ok(devices)
+    }
+
+    /// Get the metadata of a group, from the cache if it was fetched
+    /// within `ClientConfig::group_cache_ttl`.
+    pub async fn get_group_info(&self, group: &JID) -> Result<GroupInfo, ClientError> {
+        match self.inner.groups.get(group) {
+            Some(info) => Ok(info),
+            None => self.refresh_group_info(group).await,
+        }
+    }
+
+    /// Fetch the metadata of a group from the server and cache it.
+    ///
+    /// Members of large groups that the metadata does not list are fetched
+    /// in pages of `PARTICIPANTS_PAGE_SIZE`.
+    pub async fn refresh_group_info(&self, group: &JID) -> Result<GroupInfo, ClientError> {
+        let mut info = self.query(&GroupInfoRequest { group: group.clone() }).await?;
+        if info.is_truncated() {
+            info.participants = self.get_group_participants(group).await?;
+        }
+        self.inner.groups.put(&info);
+        self.inner.set_chat_ephemeral(group, info.ephemeral);
+        Ok(info)
+    }
+
+    /// Fetch all members of a group, page by page.
+    pub async fn get_group_participants(&self, group: &JID) -> Result<Vec<GroupParticipant>, ClientError> {
+        let mut participants = Vec::new();
+        let mut after = None;
+        loop {
+            let page = self.query(&GroupParticipantsRequest {
+                group: group.clone(),
+                after: after.take(),
+                limit: PARTICIPANTS_PAGE_SIZE,
+            }).await?;
+            // An empty page ends the list even if it carries a cursor
+            let exhausted = page.participants.is_empty();
+            participants.extend(page.participants);
+            match page.next {
+                Some(next) if !exhausted => after = Some(next),
+                _ => return Ok(participants),
+            }
+        }
+    }
+
+    /// Make sure we have a Signal session with each device.
+    ///
+    /// Devices we already have a session with are skipped, so bundles are
+    /// fetched once per device rather than per message. The others'
+    /// bundles are fetched in one query and turned into sessions. Returns
+    /// the devices no session could be built with, such as those that
+    /// have no bundle or a bundle that fails verification.
+    pub async fn ensure_sessions(&self, devices: &[JID]) -> Result<Vec<JID>, ClientError> {
+        let mut missing = Vec::new();
+        for device in devices {
+            let has_session = self.inner.store.has_session(&session_address(device))
+                .map_err(|e| ClientError::StoreError(e.to_string()))?;
+            if !has_session && !missing.contains(device) {
+                missing.push(device.clone());
+            }
+        }
+        if missing.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let identity = self.inner.device.read().await.identity_key.clone().ok_or(ClientError::NotLoggedIn)?;
+        let bundles = self.query(&PreKeyBundleRequest { devices: missing.clone(), reason: None }).await?;
+        for (device, bundle) in bundles {
+            let session = bundle.map_err(ClientError::from).and_then(|bundle| build_session(&identity, &bundle));
+            match session {
+                Ok(session) => {
+                    self.inner.store.put_session(&session_address(&device), &session.to_bytes())
+                        .map_err(|e| ClientError::StoreError(e.to_string()))?;
+                    missing.retain(|missing| *missing != device);
+                }
+                Err(e) => log::warn!("no session with {}: {}", self.inner.redact(&device), e),
+            }
+        }
+        Ok(missing)
+    }
+
+    /// Encrypt a message for each device with its Signal session, building
+    /// sessions first where needed.
+    ///
+    /// Devices no session could be built with are left out, so the caller
+    /// can try them again on a later send.
+    pub(crate) async fn encrypt_for_devices(
+        &self,
+        devices: &[JID],
+        plaintext: &[u8],
+    ) -> Result<Vec<(JID, EncryptedMessage)>, ClientError> {
+        if devices.is_empty() {
+            return Ok(Vec::new());
+        }
+        let missing = self.ensure_sessions(devices).await?;
+        let registration_id = self.inner.device.read().await.registration_id;
+        let store_error = |e: crate::store::StoreError| ClientError::StoreError(e.to_string());
+
+        let mut encrypted = Vec::new();
+        for device in devices.iter().filter(|device| !missing.contains(device)) {
+            let address = session_address(device);
+            let Some(record) = self.inner.store.get_session(&address).map_err(store_error)? else {
+                continue;
+            };
+            let mut session = SessionState::from_bytes(&record).map_err(store_error)?;
+            let message = session.encrypt(registration_id, plaintext);
+            self.inner.store.put_session(&address, &session.to_bytes()).map_err(store_error)?;
+            encrypted.push((device.clone(), message));
+        }
+        Ok(encrypted)
+    }
+
+    /// Fetch device lists again as they go stale, then send again the
+    /// messages refused for going to outdated devices, until the
+    /// connection closes.
+    ///
+    /// Lists that fail to fetch stay stale and are tried again after
+    /// `DEVICE_REFRESH_RETRY`.
+    pub(crate) async fn run_device_refresh(&self) {
+        loop {
+            let stale = self.inner.devices.take_stale();
+            let resends = self.inner.devices.take_resends();
+            if stale.is_empty() && resends.is_empty() {
+                tokio::select! {
+                    _ = self.cancel.cancelled() => return,
+                    _ = self.inner.devices.stale_changed.notified() => continue,
+                }
+            }
+            if !stale.is_empty() {
+                if let Err(e) = self.get_user_devices(&stale).await {
+                    self.inner.devices.restore_stale(stale);
+                    self.inner.devices.restore_resends(resends);
+                    if matches!(e, ClientError::NotConnected) {
+                        return;
+                    }
+                    log::warn!("failed to refresh device lists: {}", e);
+                    tokio::select! {
+                        _ = self.cancel.cancelled() => return,
+                        _ = tokio::time::sleep(DEVICE_REFRESH_RETRY) => continue,
+                    }
+                }
+            }
+            self.resend_outgoing(&resends).await;
+        }
+    }
+
+    /// Send outbox messages with the given IDs again.
+    async fn resend_outgoing(&self, ids: &[String]) {
+        if ids.is_empty() {
+            return;
+        }
+        let Some(outbox) = self.inner.outbox() else {
+            log::warn!("cannot send {} refused messages again without an outbox", ids.len());
+            return;
+        };
+        let pending = match outbox.pending_outgoing() {
+            Ok(pending) => pending,
+            Err(e) => {
+                log::warn!("failed to read outbox: {}", e);
+                return;
+            }
+        };
+        for mut message in pending.into_iter().filter(|message| ids.contains(&message.id)) {
+            message.attempts += 1;
+            if let Err(e) = outbox.put_outgoing(&message) {
+                log::warn!("failed to update outbox message {}: {}", message.id, e);
+            }
+            if let Err(e) = self.send_text(&message.id, message.to.clone(), &message.text).await {
+                log::warn!("failed to resend message {}: {}", message.id, e);
+            }
+        }
+    }
+
+    /// Emit a `QRCode` event for each code of the pairing in progress,
+    /// each after the previous one expires, until the device is paired or
+    /// the connection closes.
+    pub(crate) async fn run_qr_codes(&self) {
+        loop {
+            let current = self.inner.pairing.lock().unwrap().as_ref()
+                .and_then(|p| p.current_code().map(|code| (code.to_string(), p.current_timeout())));
+            let Some((code, timeout)) = current else {
+                if self.inner.device.read().await.is_registered() {
+                    return;
+                }
+                tokio::select! {
+                    _ = self.cancel.cancelled() => return,
+                    _ = self.inner.pairing_changed.notified() => continue,
+                }
+            };
+
+            let expires_at = self.inner.server_now().timestamp() + timeout.as_secs() as i64;
+            self.emit(Event::QRCode(QRCode { code, timeout_seconds: timeout.as_secs(), expires_at })).await;
+            tokio::select! {
+                _ = self.cancel.cancelled() => return,
+                _ = self.inner.pairing_changed.notified() => continue,
+                _ = tokio::time::sleep(timeout) => {}
+            }
+
+            let expired = {
+                let mut pairing = self.inner.pairing.lock().unwrap();
+                pairing.as_mut().is_some_and(|p| p.next_code().is_none()) && pairing.take().is_some()
+            };
+            if expired {
+                self.emit(Event::PairError(PairError { reason: QRError::Timeout.to_string() })).await;
+            }
+        }
+    }
+
+    /// Show or clear "typing..." in a chat.
+    pub async fn send_chat_state(&self, to: &JID, composing: bool) -> Result<(), ClientError> {
+        self.send_node(build_chat_state(to, composing)).await
+    }
+
+    /// Show "typing..." for a while, then send a text message.
+    ///
+    /// The typing time is picked by `typing` from the message length. The
+    /// state is set back to paused before the message is sent.
+    pub async fn send_typing_then_message(
+        &self,
+        to: JID,
+        text: &str,
+        typing: TypingDuration,
+    ) -> Result<String, ClientError> {
+        self.send_chat_state(&to, true).await?;
+        tokio::select! {
+            _ = self.cancel.cancelled() => return Err(ClientError::Cancelled),
+            _ = tokio::time::sleep(typing.duration_from(text, self.inner.config.rng.as_ref())) => {}
+        }
+        self.send_chat_state(&to, false).await?;
+        self.send_message(to, text).await
+    }
+
+    async fn persist_and_send(&self, id: &str, to: JID, text: &str) -> Result<(), ClientError> {
+        if let Some(outbox) = self.inner.outbox() {
+            outbox.put_outgoing(&OutgoingMessage {
+                id: id.to_string(),
+                to: to.clone(),
+                text: text.to_string(),
+                created_at: self.inner.now().timestamp(),
+                attempts: 1,
+            })
+            .map_err(|e| ClientError::StoreError(e.to_string()))?;
+        }
+
+        self.send_text(id, to, text).await
+    }
+
+    /// Send every unacknowledged outbox message again.
+    ///
+    /// Messages older than `ClientConfig::outbox_max_age` are dropped.
+    pub(crate) async fn resend_outbox(&self) {
+        let Some(outbox) = self.inner.outbox() else {
+            return;
+        };
+        let pending = match outbox.pending_outgoing() {
+            Ok(pending) => pending,
+            Err(e) => {
+                log::warn!("failed to read outbox: {}", e);
+                return;
+            }
+        };
+
+        let now = self.inner.now().timestamp();
+        let max_age = self.inner.config.outbox_max_age.as_secs() as i64;
+        for mut message in pending {
+            if now - message.created_at > max_age {
+                log::warn!(
+                    "dropping expired outbox message {} to {}",
+                    message.id,
+                    self.inner.redact(&message.to),
+                );
+                if let Err(e) = outbox.ack_outgoing(&message.id) {
+                    log::warn!("failed to remove message {} from outbox: {}", message.id, e);
+                }
+                continue;
+            }
+
+            message.attempts += 1;
+            if let Err(e) = outbox.put_outgoing(&message) {
+                log::warn!("failed to update outbox message {}: {}", message.id, e);
+            }
+            match self.send_text(&message.id, message.to.clone(), &message.text).await {
+                Ok(()) => {}
+                // Try again on the next connect
+                Err(ClientError::NotConnected) => return,
+                Err(e) => log::warn!("failed to resend message {}: {}", message.id, e),
+            }
+        }
+    }
+
+    /// Send scheduled messages as they come due, until the connection ends.
+    ///
+    /// Messages that came due while disconnected are sent right away. A
+    /// message whose send fails for any reason but a lost connection is
+    /// dropped from the schedule.
+    pub(crate) async fn run_scheduler(&self) {
+        loop {
+            let schedule = self.inner.schedule();
+            let next = match schedule.pending_scheduled() {
+                Ok(pending) => pending.into_iter().next(),
+                Err(e) => {
+                    log::warn!("failed to read schedule: {}", e);
+                    None
+                }
+            };
+
+            let now = self.inner.now().timestamp();
+            let message = match next {
+                Some(message) if message.send_at <= now => message,
+                next => {
+                    // Check again hourly in case the schedule store was
+                    // changed behind our back
+                    let wait = next.map(|m| (m.send_at - now) as u64).unwrap_or(60 * 60);
+                    tokio::select! {
+                        _ = self.cancel.cancelled() => return,
+                        _ = self.inner.schedule_changed.notified() => {}
+                        _ = tokio::time::sleep(Duration::from_secs(wait)) => {}
+                    }
+                    continue;
+                }
+            };
+
+            let result = self.send_message_with_id(message.to.clone(), &message.text, &message.id).await;
+            if let Err(ClientError::NotConnected) = result {
+                return;
+            }
+            if let Err(e) = schedule.remove_scheduled(&message.id) {
+                // Stop rather than send the message over and over
+                log::warn!("failed to remove scheduled message {}: {}", message.id, e);
+                return;
+            }
+            match result {
+                Ok(_) => self.emit(Event::ScheduledMessageSent(ScheduledMessageSent {
+                    id: message.id,
+                    to: message.to,
+                    scheduled_for: message.send_at,
+                    sent_at: self.inner.now().timestamp(),
+                })).await,
+                Err(e) => log::warn!("failed to send scheduled message {}: {}", message.id, e),
+            }
+        }
+    }
+
+    /// Deliver an event through the connection actor.
+    pub(crate) async fn emit(&self, event: Event) {
+        if self.commands.send(Command::Emit(Box::new(event))).await.is_err() {
+            log::warn!("connection closed before an event could be delivered");
+        }
+    }
+
+    /// Run a message through the send interceptors, then build its node
+    /// from the message they passed and send it.
+    ///
+    /// This is the way out for every message other than plain text, which
+    /// also goes through the outbox.
+    pub(crate) async fn send_intercepted<F>(&self, message: SendableMessage, build: F) -> Result<(), ClientError>
+    where
+        F: FnOnce(&SendableMessage) -> Node,
+    {
+        let message = self.intercept(message).await?;
+        self.send_node(build(&message)).await
+    }
+
+    /// Run a message through the send interceptors, reporting it with
+    /// `Event::MessageBlocked` if one blocks it.
+    pub(crate) async fn intercept(&self, mut message: SendableMessage) -> Result<SendableMessage, ClientError> {
+        let interceptors = self.inner.interceptors.read().unwrap().clone();
+        match run_interceptors(&interceptors, &mut message) {
+            SendDecision::Send => Ok(message),
+            SendDecision::Block(reason) => {
+                self.emit(Event::MessageBlocked(MessageBlocked {
+                    id: message.id().to_string(),
+                    to: message.to,
+                    reason: reason.clone(),
+                })).await;
+                Err(ClientError::MessageBlocked(reason))
+            }
+        }
+    }
+
+    /// Build, send and record a text message with the given ID.
+    async fn send_text(&self, message_id: &str, to: JID, text: &str) -> Result<(), ClientError> {
+        // Build message node
+        let mut node = Node::new("message");
+        node.set_attr("id", message_id);
+        node.set_attr("type", "text");
+        node.set_attr("to", to.to_string());
+
+        let mut body = Node::new("body");
+        body.set_bytes(text.as_bytes().to_vec());
+        node.add_child(body);
+
+        // Devices of members, and our own other devices, that lack our
+        // current sender key get it attached
+        let mut distributed = Vec::new();
+        if to.server == servers::GROUP {
+            let own = self.inner.device.read().await.jid.clone().ok_or(ClientError::NotLoggedIn)?;
+            let info = self.get_group_info(&to).await?;
+            let users: Vec<JID> = info.participants.into_iter()
+                .map(|p| p.jid)
+                .chain(std::iter::once(own.to_non_ad()))
+                .collect();
+            let devices = self.resolve_devices(&users).await?;
+            let plan = self.inner.sender_keys
+                .prepare_send(self.inner.store.as_ref(), &to, &own, &devices)
+                .map_err(|e| ClientError::StoreError(e.to_string()))?;
+
+            let distribution = plan.sender_key.distribution_message();
+            let encrypted = self.encrypt_for_devices(&plan.needs_key, &distribution).await?;
+            if !encrypted.is_empty() {
+                distributed = encrypted.iter().map(|(device, _)| device.clone()).collect();
+                node.add_child(build_key_distribution(encrypted));
+            }
+        }
+
+        self.send_node(node).await?;
+        if !distributed.is_empty() {
+            self.inner.sender_keys.mark_distributed(&to, &distributed);
+        }
+
+        let sender = self.inner.device.read().await.jid.clone().unwrap_or_default();
+        self.inner.record_message(&StoredMessage {
+            id: message_id.to_string(),
+            chat: to,
+            sender,
+            is_from_me: true,
+            timestamp: self.inner.server_now().timestamp(),
+            push_name: None,
+            text: Some(text.to_string()),
+        });
+
+        Ok(())
+    }
+
+    /// Encrypt and upload a media file, reporting progress.
+    ///
+    /// The returned transfer can be cancelled. Its result holds the
+    /// details needed to send the media in a message.
+    pub async fn upload_media<F>(
+        &self,
+        data: Vec<u8>,
+        media_type: MediaType,
+        progress: F,
+    ) -> Result<Transfer<UploadedMedia>, ClientError>
+    where
+        F: Fn(TransferProgress) + Send + Sync + 'static,
+    {
+        let conn = self.query(&MediaConnRequest).await?;
+        Ok(media::upload(data, media_type, &conn, progress))
+    }
+
+    /// Send text or media messages to many recipients.
+    ///
+    /// Before the first message, the devices of every recipient, and of
+    /// the members of group recipients, are resolved in one query, and
+    /// each distinct media file is uploaded once; every recipient of a
+    /// file is sent the same upload. Messages are then sent in order,
+    /// paced by the limiter configured with `ClientConfig::bulk_send_rate`,
+    /// which is shared by every bulk send of this client.
+    ///
+    /// Nothing is sent until the returned stream is polled; each item
+    /// reports the outcome for one recipient, and a failed recipient does
+    /// not stop the rest.
+    pub fn send_bulk<I, C>(&self, messages: I) -> BoxStream<'static, BulkSendResult>
+    where
+        I: IntoIterator<Item = (JID, C)>,
+        C: Into<BulkContent>,
+    {
+        let messages: Vec<(JID, BulkContent)> = messages.into_iter()
+            .map(|(to, content)| (to, content.into()))
+            .collect();
+        let handle = self.clone();
+        stream::once(async move {
+            let prepared = handle.prepare_bulk(messages).await;
+            (handle, prepared)
+        })
+        .flat_map(|(handle, prepared)| {
+            stream::iter(prepared).then(move |(to, content)| {
+                let handle = handle.clone();
+                async move {
+                    handle.inner.bulk_limiter.acquire().await;
+                    let result = match content {
+                        PreparedContent::Text(text) => handle.send_message(to.clone(), &text).await,
+                        PreparedContent::Media(message, uploaded) => match uploaded {
+                            Ok(uploaded) => handle.send_uploaded_media(&to, &message, &uploaded).await,
+                            Err(e) => Err(e),
+                        },
+                    };
+                    BulkSendResult { to, result }
+                }
+            })
+        })
+        .boxed()
+    }
+
+    /// Resolve the devices of every recipient of a bulk send and upload
+    /// its media, each distinct file once.
+    ///
+    /// Failing to resolve devices only means each send looks them up
+    /// again; a failed upload fails the recipients of that file.
+    async fn prepare_bulk(&self, messages: Vec<(JID, BulkContent)>) -> Vec<(JID, PreparedContent)> {
+        let mut users = Vec::new();
+        let mut seen = HashSet::new();
+        let own = self.inner.device.read().await.jid.as_ref().map(JID::to_non_ad);
+        for to in messages.iter().map(|(to, _)| to) {
+            let members = if to.server != servers::GROUP {
+                vec![to.to_non_ad()]
+            } else {
+                match self.get_group_info(to).await {
+                    Ok(info) => info.participants.into_iter().map(|p| p.jid).chain(own.clone()).collect(),
+                    Err(e) => {
+                        log::warn!("failed to get members of {}: {}", self.inner.redact(to), e);
+                        continue;
+                    }
+                }
+            };
+            users.extend(members.into_iter().filter(|user| seen.insert(user.clone())));
+        }
+        if let Err(e) = self.resolve_devices(&users).await {
+            log::warn!("failed to resolve devices for a bulk send: {}", e);
+        }
+
+        // The same file in several messages is uploaded once, and hashed
+        // once when they share it through one `Arc`
+        let mut uploads: HashMap<(MediaType, Vec<u8>), Result<Arc<UploadedMedia>, ClientError>> = HashMap::new();
+        let mut keys: HashMap<usize, (MediaType, Vec<u8>)> = HashMap::new();
+        let mut prepared = Vec::with_capacity(messages.len());
+        for (to, content) in messages {
+            let content = match content {
+                BulkContent::Text(text) => PreparedContent::Text(text),
+                BulkContent::Media(message) => {
+                    let key = keys.entry(Arc::as_ptr(&message) as usize)
+                        .or_insert_with(|| (message.media_type, Sha256::digest(&message.data).to_vec()))
+                        .clone();
+                    let uploaded = match uploads.get(&key) {
+                        Some(uploaded) => uploaded.clone(),
+                        None => {
+                            let uploaded = match self.upload_media(message.data.clone(), message.media_type, |_| {}).await {
+                                Ok(transfer) => transfer.wait().await.map(Arc::new).map_err(ClientError::from),
+                                Err(e) => Err(e),
+                            };
+                            uploads.insert(key, uploaded.clone());
+                            uploaded
+                        }
+                    };
+                    PreparedContent::Media(message, uploaded)
+                }
+            };
+            prepared.push((to, content));
+        }
+        prepared
+    }
+
+    /// Send a template rendered with each recipient's variables.
+    ///
+    /// Every message is rendered before anything is sent, so a recipient
+    /// missing a variable fails the whole send with
+    /// `TemplateError::MissingVariables`. Sending then works as in
+    /// `send_bulk`.
+    pub fn send_bulk_template<I>(
+        &self,
+        template: &Template,
+        recipients: I,
+    ) -> Result<BoxStream<'static, BulkSendResult>, TemplateError>
+    where
+        I: IntoIterator<Item = (JID, HashMap<String, String>)>,
+    {
+        let messages = recipients.into_iter()
+            .map(|(to, vars)| Ok((to, template.render(&vars)?)))
+            .collect::<Result<Vec<_>, TemplateError>>()?;
+        Ok(self.send_bulk(messages))
+    }
+
+    /// Get a new message ID, from `ClientConfig::rng`.
+    pub(crate) fn new_message_id(&self) -> MessageID {
+        self.inner.new_message_id()
+    }
+
+    /// Get the current time on the server's clock, for timestamps the
+    /// server or other devices read.
+    pub(crate) fn server_now(&self) -> chrono::DateTime<chrono::Utc> {
+        self.inner.server_now()
+    }
+
+    /// Get the key naming the message `id` in `chat`, with its sender
+    /// from the message index when the index knows it.
+    pub(crate) fn message_key(&self, chat: &JID, id: &str) -> Result<MessageKey, ClientError> {
+        let indexed = self.inner.store.get_message_ref(chat, id)
+            .map_err(|e| ClientError::StoreError(e.to_string()))?;
+        Ok(match indexed {
+            Some(message) => build_message_key(chat, id, &message.sender, self.inner.own_jid().as_ref()),
+            None => MessageKey { remote_jid: Some(chat.to_string()), id: Some(id.to_string()), ..Default::default() },
+        })
+    }
+
+    /// Get the tag for an outgoing node on this connection.
+    pub(crate) fn next_tag(&self) -> String {
+        self.inner.tags.next()
+    }
+
+    /// Send a typed IQ query and wait for its response.
+    ///
+    /// See `Client::query`.
+    pub async fn query<T: IqRequest>(&self, request: &T) -> Result<T::Response, ClientError> {
+        let requests = &self.inner.requests;
+        let id = self.next_tag();
+        let node = request.to_node(&id);
+        if self.inner.is_held_back(&node) {
+            // Nothing will answer, so answer as a bare acknowledgement would
+            self.emit(Event::WouldHaveSent(node)).await;
+            return Ok(parse_iq_response(&build_iq_result(&id, None))?);
+        }
+        let rx = requests.register(&id);
+
+        if let Err(e) = self.send_node(node).await {
+            requests.cancel(&id);
+            return Err(e);
+        }
+
+        let response = match tokio::time::timeout(self.inner.config.request_timeout, rx).await {
+            Ok(Ok(response)) => response,
+            // The tracker dropped the waiter: the connection shut down
+            Ok(Err(_)) => return Err(IqError::Cancelled.into()),
+            Err(_) => {
+                requests.cancel(&id);
+                return Err(IqError::Timeout.into());
+            }
+        };
+
+        Ok(parse_iq_response(&response)?)
+    }
+}
+
+#[cfg(test)]
+impl ClientHandle {
+    /// Handle with no connection behind it; every send fails with
+    /// `NotConnected`.
+    pub(crate) fn disconnected() -> Self {
+        let inner = Arc::new(ClientInner::new(Default::default(), Arc::new(crate::store::MemoryStore::new())));
+        let (tx, _) = mpsc::channel(1);
+        Self::new(inner, tx, CancellationToken::new())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{generate_message_id_with, ClientConfig, IqType};
+    use crate::store::{ChatStore, MemoryStore, MessagePage, OutboxStore, ScheduledMessage};
+    use crate::types::Event;
+
+    struct RawQuery;
+
+    impl IqRequest for RawQuery {
+        type Response = Node;
+
+        fn namespace(&self) -> &str {
+            "w"
+        }
+
+        fn iq_type(&self) -> IqType {
+            IqType::Get
+        }
+
+        fn content(&self) -> Vec<Node> {
+            Vec::new()
+        }
+    }
+
+    fn test_handle(config: ClientConfig) -> (ClientHandle, Arc<ClientInner>, mpsc::Receiver<Command>) {
+        let inner = Arc::new(ClientInner::new(config, Arc::new(MemoryStore::new())));
+        let (tx, rx) = mpsc::channel(4);
+        let handle = ClientHandle::new(inner.clone(), tx, CancellationToken::new());
+        (handle, inner, rx)
+    }
+
+    #[tokio::test]
+    async fn test_query_from_cloned_handle() {
+        let (handle, inner, mut commands) = test_handle(ClientConfig::default());
+
+        // Stand-in for the connection actor: answer every IQ with a result
+        tokio::spawn(async move {
+            while let Some(Command::Send { node, reply }) = commands.recv().await {
+                let _ = reply.send(Ok(()));
+                let mut response = Node::new("iq");
+                response.set_attr("id", node.get_attr_str("id").unwrap().to_string());
+                response.set_attr("type", "result");
+                inner.process_node(&response).unwrap();
+            }
+        });
+
+        let other = handle.clone();
+        let (a, b) = tokio::join!(handle.query(&RawQuery), other.query(&RawQuery));
+        assert_eq!(a.unwrap().tag, "iq");
+        assert_eq!(b.unwrap().tag, "iq");
+    }
+
+    #[tokio::test]
+    async fn test_send_typing_then_message() {
+        let (handle, _inner, mut commands) = test_handle(ClientConfig::default());
+
+        let sent = tokio::spawn(async move {
+            let mut sent = Vec::new();
+            while let Some(Command::Send { node, reply }) = commands.recv().await {
+                let _ = reply.send(Ok(()));
+                sent.push(node);
+            }
+            sent
+        });
+
+        let to: JID = "1234567890@s.whatsapp.net".parse().unwrap();
+        let typing = TypingDuration::Fixed(Duration::from_millis(20));
+        let start = std::time::Instant::now();
+        handle.send_typing_then_message(to, "hello", typing).await.unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(20));
+
+        drop(handle);
+        let sent = sent.await.unwrap();
+        let shape: Vec<_> = sent.iter()
+            .map(|n| (&*n.tag, n.get_children().and_then(|c| c.first()).map(|c| &*c.tag)))
+            .collect();
+        assert_eq!(shape, [
+            ("chatstate", Some("composing")),
+            ("chatstate", Some("paused")),
+            ("message", Some("body")),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_hide_reads_and_presence() {
+        let sent_by = |config: ClientConfig| async move {
+            let (handle, _inner, mut commands) = test_handle(config);
+            let sent = tokio::spawn(async move {
+                let mut sent = Vec::new();
+                while let Some(command) = commands.recv().await {
+                    // mark_read also emits the unread count change
+                    if let Command::Send { node, reply } = command {
+                        let _ = reply.send(Ok(()));
+                        sent.push(node);
+                    }
+                }
+                sent
+            });
+
+            let chat = JID::new("1", "s.whatsapp.net");
+            handle.mark_read(&chat, &["A".to_string()]).await.unwrap();
+            handle.send_presence(true).await.unwrap();
+            handle.send_presence(false).await.unwrap();
+            drop(handle);
+            sent.await.unwrap().iter()
+                .map(|n| format!("{}:{}", n.tag, n.get_attr_str("type").unwrap_or("")))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            sent_by(ClientConfig::default()).await,
+            ["receipt:read", "presence:available", "presence:unavailable"],
+        );
+        let private = ClientConfig { hide_reads_and_presence: true, ..Default::default() };
+        assert_eq!(sent_by(private).await, ["receipt:", "presence:unavailable"]);
+    }
+
+    #[tokio::test]
+    async fn test_clock_and_rng_are_injected() {
+        use crate::protocol::{ManualClock, SeededRandom};
+
+        let start = chrono::DateTime::from_timestamp(1700000000, 0).unwrap();
+        let config = ClientConfig {
+            clock: Arc::new(ManualClock::new(start)),
+            rng: Arc::new(SeededRandom::new(42)),
+            read_only: true,
+            ..Default::default()
+        };
+        let (handle, inner, _commands) = test_handle(config);
+        let chat_store = Arc::new(MemoryStore::new());
+        *inner.chat_store.write().unwrap() = Some(chat_store.clone());
+
+        let to = JID::new("1", "s.whatsapp.net");
+        let id = handle.send_message(to.clone(), "hi").await.unwrap();
+        assert_eq!(id, generate_message_id_with(&SeededRandom::new(42)));
+        let history = chat_store.get_messages(&to, MessagePage::latest(1)).unwrap();
+        assert_eq!(history[0].timestamp, 1700000000);
+    }
+
+    #[tokio::test]
+    async fn test_messages_to_disappearing_chats_carry_the_timer() {
+        let (handle, inner, mut commands) = test_handle(ClientConfig::default());
+        let to = JID::new("1", "s.whatsapp.net");
+        inner.set_chat_ephemeral(&to, Some(Duration::from_secs(604800)));
+        let sent = tokio::spawn(async move {
+            let Some(Command::Send { node, reply }) = commands.recv().await else { panic!("nothing sent") };
+            let _ = reply.send(Ok(()));
+            node
+        });
+
+        handle.send_message(to, "gone in a week").await.unwrap();
+        let node = sent.await.unwrap();
+        assert_eq!(node.get_child_by_tag("body").unwrap().get_attr_str("expiration"), Some("604800"));
+    }
+
+    #[tokio::test]
+    async fn test_read_only_emits_instead_of_sending() {
+        let (handle, _inner, mut commands) = test_handle(ClientConfig { read_only: true, ..Default::default() });
+        let to = JID::new("1", "s.whatsapp.net");
+        let id = handle.send_message(to.clone(), "hello").await.unwrap();
+        handle.send_chat_state(&to, true).await.unwrap();
+        handle.query(&crate::protocol::SetPassiveRequest { passive: true }).await.unwrap();
+
+        drop(handle);
+        let mut held_back = Vec::new();
+        while let Some(command) = commands.recv().await {
+            match command {
+                Command::Emit(event) => match *event {
+                    Event::WouldHaveSent(node) => held_back.push(node),
+                    other => panic!("unexpected event {:?}", other),
+                },
+                Command::Send { node, .. } => panic!("<{}> was sent", node.tag),
+            }
+        }
+        assert_eq!(held_back.len(), 3);
+        assert_eq!(held_back[0].get_attr_str("id"), Some(&*id));
+        assert_eq!(held_back[1].tag, "chatstate");
+        assert_eq!((&*held_back[2].tag, held_back[2].get_attr_str("type")), ("iq", Some("set")));
+    }
+
+    #[tokio::test]
+    async fn test_interceptors_change_or_block_sends() {
+        let (handle, inner, mut commands) = test_handle(ClientConfig { read_only: true, ..Default::default() });
+        inner.interceptors.write().unwrap().push(Arc::new(|message: &mut SendableMessage| {
+            if message.text.contains("secret") {
+                return SendDecision::Block("contains a secret".to_string());
+            }
+            message.text.push_str(" -- bot");
+            SendDecision::Send
+        }));
+        let to = JID::new("1", "s.whatsapp.net");
+        handle.send_message(to.clone(), "hello").await.unwrap();
+        let blocked = handle.send_message_with_id(to.clone(), "the secret", "3EB0BLOCKED").await;
+        assert!(matches!(blocked, Err(ClientError::MessageBlocked(reason)) if reason == "contains a secret"));
+
+        drop(handle);
+        let mut events = Vec::new();
+        while let Some(Command::Emit(event)) = commands.recv().await {
+            events.push(*event);
+        }
+        match events.as_slice() {
+            [Event::WouldHaveSent(node), Event::MessageBlocked(blocked)] => {
+                assert_eq!(node.get_child_by_tag("body").and_then(|b| b.get_bytes()), Some(&b"hello -- bot"[..]));
+                assert_eq!(blocked.id, "3EB0BLOCKED");
+                assert_eq!(blocked.to, to);
+            }
+            other => panic!("unexpected events {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reply_quotes_indexed_message() {
+        use prost::Message as _;
+        use crate::proto::E2eMessage;
+
+        let (handle, inner, mut commands) = test_handle(ClientConfig { read_only: true, ..Default::default() });
+        let group: JID = "123-456@g.us".parse().unwrap();
+        inner.index_message(&StoredMessage {
+            id: "3EB0AA".to_string(),
+            chat: group.clone(),
+            sender: "1:4@s.whatsapp.net".parse().unwrap(),
+            is_from_me: false,
+            timestamp: 1,
+            push_name: None,
+            text: Some("lunch?".to_string()),
+        });
+
+        let id = handle.send_reply(group.clone(), "3EB0AA", "yes").await.unwrap();
+        assert_eq!(inner.store.get_message_ref(&group, &id).unwrap().unwrap().snippet.as_deref(), Some("yes"));
+        drop(handle);
+        match commands.recv().await {
+            Some(Command::Emit(event)) => match *event {
+                Event::WouldHaveSent(node) => {
+                    let message = E2eMessage::decode(node.get_child_by_tag("enc").unwrap().get_bytes().unwrap()).unwrap();
+                    let context = message.extended_text_message.unwrap().context_info.unwrap();
+                    assert_eq!(context.stanza_id.as_deref(), Some("3EB0AA"));
+                    assert_eq!(context.participant.as_deref(), Some("1@s.whatsapp.net"));
+                }
+                other => panic!("unexpected event {:?}", other),
+            },
+            _ => panic!("expected the reply to be held back"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_interceptors_see_non_text_sends() {
+        use crate::protocol::intercept::MessageKind;
+
+        let (handle, inner, mut commands) = test_handle(ClientConfig { read_only: true, ..Default::default() });
+        inner.interceptors.write().unwrap().push(Arc::new(|message: &mut SendableMessage| {
+            if message.kind() == MessageKind::Keep {
+                return SendDecision::Block("no keeps".to_string());
+            }
+            message.text = message.text.to_uppercase();
+            SendDecision::Send
+        }));
+        let to = JID::new("1", "s.whatsapp.net");
+        let build = |message: &SendableMessage| {
+            let mut node = Node::new("message");
+            node.set_attr("to", message.to.clone());
+            node.set_attr("id", message.id());
+            node.set_bytes(message.text.as_bytes().to_vec());
+            node
+        };
+
+        let caption = SendableMessage::new("3EB0MEDIA", to.clone(), "caption").with_kind(MessageKind::Media);
+        handle.send_intercepted(caption, build).await.unwrap();
+        let keep = SendableMessage::new("3EB0KEEP", to.clone(), "").with_kind(MessageKind::Keep);
+        let blocked = handle.send_intercepted(keep, |_| unreachable!("blocked messages are not built")).await;
+        assert!(matches!(blocked, Err(ClientError::MessageBlocked(_))));
+
+        drop(handle);
+        let mut events = Vec::new();
+        while let Some(Command::Emit(event)) = commands.recv().await {
+            events.push(*event);
+        }
+        match events.as_slice() {
+            [Event::WouldHaveSent(node), Event::MessageBlocked(blocked)] => {
+                assert_eq!(node.get_bytes(), Some(&b"CAPTION"[..]));
+                assert_eq!(blocked.id, "3EB0KEEP");
+            }
+            other => panic!("unexpected events {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_missing_app_state_key_is_requested_once() {
+        use prost::Message as _;
+        use crate::proto::{self, protocol_message_type, AppStateSyncKeyShare, E2eMessage, ProtocolMessage};
+
+        let (handle, inner, mut commands) = test_handle(ClientConfig::default());
+        inner.device.write().await.jid = Some(JID::new_ad("me", 0, 2));
+        *inner.own_jid.write().unwrap() = Some(JID::new_ad("me", 0, 2));
+        let sent = tokio::spawn(async move {
+            let mut sent = Vec::new();
+            while let Some(Command::Send { node, reply }) = commands.recv().await {
+                let _ = reply.send(Ok(()));
+                sent.push(node);
+            }
+            sent
+        });
+
+        assert_eq!(handle.get_app_state_key(&[0, 1]).await.unwrap(), None);
+        assert_eq!(handle.get_app_state_key(&[0, 1]).await.unwrap(), None);
+
+        // The phone answers with the key
+        let share = E2eMessage {
+            protocol_message: Some(Box::new(ProtocolMessage {
+                r#type: Some(protocol_message_type::APP_STATE_SYNC_KEY_SHARE),
+                app_state_sync_key_share: Some(AppStateSyncKeyShare {
+                    keys: vec![proto::AppStateSyncKey {
+                        key_id: Some(proto::AppStateSyncKeyId { key_id: Some(vec![0, 1]) }),
+                        key_data: Some(proto::AppStateSyncKeyData {
+                            key_data: Some(vec![9; 32]),
+                            ..Default::default()
+                        }),
+                    }],
+                }),
+                ..Default::default()
+            })),
+            ..Default::default()
+        };
+        let mut enc = Node::new("enc");
+        enc.set_bytes(share.encode_to_vec());
+        let mut node = Node::new("message");
+        node.set_attr("from", "mallory@s.whatsapp.net");
+        node.add_child(enc);
+        assert!(inner.process_node(&node).unwrap().is_empty());
+        assert!(inner.store.get_app_state_key(&[0, 1]).unwrap().is_none());
+
+        node.set_attr("from", "me@s.whatsapp.net");
+        assert!(inner.process_node(&node).unwrap().is_empty());
+
+        let key = handle.get_app_state_key(&[0, 1]).await.unwrap().unwrap();
+        assert_eq!(key.key_data, [9; 32]);
+
+        drop(handle);
+        let sent = sent.await.unwrap();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].get_attr_str("category"), Some("peer"));
+    }
+
+    #[tokio::test]
+    async fn test_query_times_out() {
+        let config = ClientConfig {
+            request_timeout: Duration::from_millis(10),
+            ..Default::default()
+        };
+        let (handle, inner, mut commands) = test_handle(config);
+
+        tokio::spawn(async move {
+            while let Some(Command::Send { reply, .. }) = commands.recv().await {
+                let _ = reply.send(Ok(()));
+            }
+        });
+
+        let result = handle.query(&RawQuery).await;
+        assert!(matches!(result, Err(ClientError::IqFailed(IqError::Timeout))));
+        assert_eq!(inner.requests.pending_count(), 0);
+    }
+
+    /// Answer a usync device query as the server would.
+    fn answer_usync(inner: &ClientInner, query: &Node) {
+        let mut response = Node::new("iq");
+        response.set_attr("id", query.get_attr_str("id").unwrap().to_string());
+        response.set_attr("type", "result");
+        response.add_child(device_list_response(query));
+        inner.process_node(&response).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_send_bulk_reports_each_recipient() {
+        let config = ClientConfig {
+            bulk_send_rate: crate::protocol::RateLimit { burst: 10, per_second: 10.0 },
+            ..Default::default()
+        };
+        let (handle, inner, mut commands) = test_handle(config);
+
+        // Fail sends to the second recipient
+        tokio::spawn(async move {
+            while let Some(Command::Send { node, reply }) = commands.recv().await {
+                if node.tag == "iq" {
+                    let _ = reply.send(Ok(()));
+                    answer_usync(&inner, &node);
+                    continue;
+                }
+                let result = match node.get_attr_str("to") {
+                    Some("2@s.whatsapp.net") => Err(ClientError::SendFailed("rejected".to_string())),
+                    _ => Ok(()),
+                };
+                let _ = reply.send(result);
+            }
+        });
+
+        let messages = vec![
+            (JID::new("1", "s.whatsapp.net"), "hi 1".to_string()),
+            (JID::new("2", "s.whatsapp.net"), "hi 2".to_string()),
+            (JID::new("3", "s.whatsapp.net"), "hi 3".to_string()),
+        ];
+        let results: Vec<BulkSendResult> = handle.send_bulk(messages).collect().await;
+
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].to.user, "1");
+        assert!(results[0].result.is_ok());
+        assert!(results[1].result.is_err());
+        assert!(results[2].result.is_ok());
+    }
+
+    /// Accept media uploads over plain HTTP, counting them.
+    fn serve_uploads(uploads: Arc<std::sync::atomic::AtomicUsize>) -> String {
+        use std::io::{Read, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                // Read the whole chunked body before answering
+                let mut request = Vec::new();
+                let mut buf = [0u8; 4096];
+                while let Ok(n @ 1..) = stream.read(&mut buf) {
+                    request.extend_from_slice(&buf[..n]);
+                    if request.ends_with(b"\r\n0\r\n\r\n") {
+                        break;
+                    }
+                }
+                uploads.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                let body = r#"{"url":"https://mmg.whatsapp.net/abc.enc","direct_path":"/v/abc.enc"}"#;
+                let response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}", body.len(), body);
+                let _ = stream.write_all(response.as_bytes());
+            }
+        });
+        format!("http://{}", addr)
+    }
+
+    #[tokio::test]
+    async fn test_send_bulk_shares_uploads_and_device_lookups() {
+        let config = ClientConfig {
+            bulk_send_rate: crate::protocol::RateLimit { burst: 10, per_second: 10.0 },
+            ..Default::default()
+        };
+        let (handle, inner, mut commands) = test_handle(config);
+        let uploads = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let host = serve_uploads(uploads.clone());
+
+        // Stand-in actor: answer media_conn and usync queries, counting them
+        let queries = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = queries.clone();
+        let (sent_tx, mut sent) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Some(Command::Send { node, reply }) = commands.recv().await {
+                let _ = reply.send(Ok(()));
+                if node.tag != "iq" {
+                    let _ = sent_tx.send(node);
+                    continue;
+                }
+                let xmlns = node.get_attr_str("xmlns").unwrap_or_default().to_string();
+                let mut response = Node::new("iq");
+                response.set_attr("id", node.get_attr_str("id").unwrap().to_string());
+                response.set_attr("type", "result");
+                if xmlns == "usync" {
+                    response.add_child(device_list_response(&node));
+                } else {
+                    let mut conn = Node::new("media_conn");
+                    conn.set_attr("auth", "secret");
+                    let mut media_host = Node::new("host");
+                    media_host.set_attr("hostname", host.clone());
+                    conn.add_child(media_host);
+                    response.add_child(conn);
+                }
+                seen.lock().unwrap().push(xmlns);
+                inner.process_node(&response).unwrap();
+            }
+        });
+
+        // Three recipients share one file, a fourth has its own copy of it
+        let photo = vec![7u8; 2000];
+        let shared = Arc::new(MediaMessage::new(photo.clone(), "image/jpeg").with_caption("news"));
+        let recipients: Vec<JID> = (1..=5).map(|i| JID::new(i.to_string(), "s.whatsapp.net")).collect();
+        let messages = vec![
+            (recipients[0].clone(), BulkContent::from(shared.clone())),
+            (recipients[1].clone(), BulkContent::from(shared.clone())),
+            (recipients[2].clone(), BulkContent::from("text only")),
+            (recipients[3].clone(), BulkContent::from(shared)),
+            (recipients[4].clone(), BulkContent::from(MediaMessage::new(photo, "image/jpeg").with_caption("copy"))),
+        ];
+        let results: Vec<BulkSendResult> = handle.send_bulk(messages).collect().await;
+        assert!(results.iter().all(|r| r.result.is_ok()), "{:?}", results);
+        assert_eq!(results.iter().map(|r| &r.to).collect::<Vec<_>>(), recipients.iter().collect::<Vec<_>>());
+
+        assert_eq!(uploads.load(std::sync::atomic::Ordering::SeqCst), 1);
+        assert_eq!(*queries.lock().unwrap(), ["usync", "w:m"]);
+
+        // Every media message points at the one upload
+        let mut media_keys = Vec::new();
+        for _ in 0..4 {
+            let node = sent.recv().await.unwrap();
+            if let Some(media) = node.get_child_by_tag("media") {
+                assert_eq!(media.get_attr_str("direct_path"), Some("/v/abc.enc"));
+                media_keys.push(media.get_attr_str("media_key").unwrap().to_string());
+            }
+        }
+        let last = sent.recv().await.unwrap();
+        let caption = last.get_child_by_tag("media").unwrap().get_child_by_tag("caption").unwrap();
+        assert_eq!(caption.get_bytes(), Some(&b"copy"[..]));
+        media_keys.push(last.get_child_by_tag("media").unwrap().get_attr_str("media_key").unwrap().to_string());
+        assert_eq!(media_keys.len(), 4);
+        assert!(media_keys.iter().all(|key| *key == media_keys[0]));
+    }
+
+    #[tokio::test]
+    async fn test_send_bulk_template() {
+        let config = ClientConfig {
+            bulk_send_rate: crate::protocol::RateLimit { burst: 10, per_second: 10.0 },
+            ..Default::default()
+        };
+        let (handle, inner, mut commands) = test_handle(config);
+
+        let (sent_tx, mut sent) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Some(Command::Send { node, reply }) = commands.recv().await {
+                let _ = reply.send(Ok(()));
+                if node.tag == "iq" {
+                    answer_usync(&inner, &node);
+                } else {
+                    let _ = sent_tx.send(node);
+                }
+            }
+        });
+
+        let template = Template::parse("Hi {name}, your code is {code}").unwrap();
+        let vars = |name: &str, code: Option<&str>| {
+            let mut vars = HashMap::from([("name".to_string(), name.to_string())]);
+            if let Some(code) = code {
+                vars.insert("code".to_string(), code.to_string());
+            }
+            vars
+        };
+
+        // One recipient lacks a variable, so nothing is sent
+        let recipients = vec![
+            (JID::new("1", "s.whatsapp.net"), vars("Ana", Some("11"))),
+            (JID::new("2", "s.whatsapp.net"), vars("Bo", None)),
+        ];
+        let error = handle.send_bulk_template(&template, recipients).err().unwrap();
+        assert_eq!(error, TemplateError::MissingVariables(vec!["code".to_string()]));
+
+        let recipients = vec![(JID::new("1", "s.whatsapp.net"), vars("Ana", Some("11")))];
+        let results: Vec<BulkSendResult> = handle.send_bulk_template(&template, recipients).unwrap().collect().await;
+        assert!(results[0].result.is_ok());
+
+        let node = sent.recv().await.unwrap();
+        let body = node.get_child_by_tag("body").unwrap().get_bytes().unwrap();
+        assert_eq!(body, b"Hi Ana, your code is 11");
+        assert!(sent.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_sent_message_is_recorded() {
+        let (handle, inner, mut commands) = test_handle(ClientConfig::default());
+        *inner.chat_store.write().unwrap() = Some(Arc::new(MemoryStore::new()));
+
+        tokio::spawn(async move {
+            while let Some(Command::Send { reply, .. }) = commands.recv().await {
+                let _ = reply.send(Ok(()));
+            }
+        });
+
+        let to = JID::new("1", "s.whatsapp.net");
+        let id = handle.send_message(to.clone(), "hello").await.unwrap();
+
+        let chat_store = inner.chat_store.read().unwrap().clone().unwrap();
+        let history = chat_store.get_messages(&to, MessagePage::latest(10)).unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].id, id);
+        assert!(history[0].is_from_me);
+        assert_eq!(history[0].text.as_deref(), Some("hello"));
+    }
+
+    #[tokio::test]
+    async fn test_outbox_keeps_messages_until_acked() {
+        let (handle, inner, mut commands) = test_handle(ClientConfig::default());
+        let outbox = Arc::new(MemoryStore::new());
+        *inner.outbox.write().unwrap() = Some(outbox.clone());
+
+        tokio::spawn(async move {
+            while let Some(Command::Send { reply, .. }) = commands.recv().await {
+                let _ = reply.send(Ok(()));
+            }
+        });
+
+        let to = JID::new("1", "s.whatsapp.net");
+        let id = handle.send_message(to.clone(), "hello").await.unwrap();
+        let pending = outbox.pending_outgoing().unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].id, id);
+        assert_eq!(pending[0].to, to);
+
+        let mut ack = Node::new("ack");
+        ack.set_attr("class", "message");
+        ack.set_attr("id", id);
+        assert!(inner.process_node(&ack).unwrap().is_empty());
+        assert!(outbox.pending_outgoing().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_scheduler_sends_due_messages() {
+        let (handle, inner, mut commands) = test_handle(ClientConfig::default());
+        let schedule = inner.schedule();
+
+        let now = chrono::Utc::now().timestamp();
+        let scheduled = |id: &str, send_at| ScheduledMessage {
+            id: id.to_string(),
+            to: JID::new("1", "s.whatsapp.net"),
+            text: "reminder".to_string(),
+            send_at,
+        };
+        // Came due while disconnected
+        schedule.put_scheduled(&scheduled("MISSED", now - 60)).unwrap();
+        schedule.put_scheduled(&scheduled("LATER", now + 3600)).unwrap();
+
+        let (sent_tx, mut sent) = mpsc::unbounded_channel();
+        let (event_tx, mut events) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Some(command) = commands.recv().await {
+                match command {
+                    Command::Send { node, reply } => {
+                        let _ = reply.send(Ok(()));
+                        let _ = sent_tx.send(node);
+                    }
+                    Command::Emit(event) => {
+                        let _ = event_tx.send(*event);
+                    }
+                }
+            }
+        });
+
+        let scheduler = handle.clone();
+        let task = tokio::spawn(async move { scheduler.run_scheduler().await });
+
+        assert_eq!(sent.recv().await.unwrap().get_attr_str("id"), Some("MISSED"));
+        let Some(Event::ScheduledMessageSent(event)) = events.recv().await else {
+            panic!("expected ScheduledMessageSent");
+        };
+        assert_eq!((event.id.as_str(), event.scheduled_for), ("MISSED", now - 60));
+        assert_eq!(schedule.pending_scheduled().unwrap(), vec![scheduled("LATER", now + 3600)]);
+
+        // Moving the message forward wakes the scheduler
+        schedule.put_scheduled(&scheduled("LATER", now - 1)).unwrap();
+        inner.schedule_changed.notify_one();
+        assert_eq!(sent.recv().await.unwrap().get_attr_str("id"), Some("LATER"));
+
+        handle.close();
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_resend_outbox_drops_expired_messages() {
+        let config = ClientConfig {
+            outbox_max_age: Duration::from_secs(60),
+            ..Default::default()
+        };
+        let (handle, inner, mut commands) = test_handle(config);
+        let outbox = Arc::new(MemoryStore::new());
+        *inner.outbox.write().unwrap() = Some(outbox.clone());
+
+        let now = chrono::Utc::now().timestamp();
+        let outgoing = |id: &str, created_at| OutgoingMessage {
+            id: id.to_string(),
+            to: JID::new("1", "s.whatsapp.net"),
+            text: "hi".to_string(),
+            created_at,
+            attempts: 1,
+        };
+        outbox.put_outgoing(&outgoing("OLD", now - 3600)).unwrap();
+        outbox.put_outgoing(&outgoing("FRESH", now - 10)).unwrap();
+
+        let (sent_tx, mut sent) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Some(Command::Send { node, reply }) = commands.recv().await {
+                let _ = reply.send(Ok(()));
+                let _ = sent_tx.send(node);
+            }
+        });
+
+        handle.resend_outbox().await;
+
+        // The original ID is reused so the server can deduplicate
+        let node = sent.recv().await.unwrap();
+        assert_eq!(node.get_attr_str("id"), Some("FRESH"));
+        assert!(sent.try_recv().is_err());
+
+        let pending = outbox.pending_outgoing().unwrap();
+        assert_eq!(pending, vec![OutgoingMessage { attempts: 2, ..outgoing("FRESH", now - 10) }]);
+    }
+
+    #[tokio::test]
+    async fn test_send_with_same_id_is_sent_once() {
+        let (handle, _inner, mut commands) = test_handle(ClientConfig::default());
+        let (sent_tx, mut sent) = mpsc::unbounded_channel();
+
+        // Fail the first attempt only
+        tokio::spawn(async move {
+            let mut first = true;
+            while let Some(Command::Send { node, reply }) = commands.recv().await {
+                if std::mem::take(&mut first) {
+                    let _ = reply.send(Err(ClientError::SendFailed("timeout".to_string())));
+                    continue;
+                }
+                let _ = reply.send(Ok(()));
+                let _ = sent_tx.send(node);
+            }
+        });
+
+        let to = JID::new("1", "s.whatsapp.net");
+        assert!(handle.send_message_with_id(to.clone(), "paid", "PAY1").await.is_err());
+        assert_eq!(handle.send_message_with_id(to.clone(), "paid", "PAY1").await.unwrap(), "PAY1");
+        assert_eq!(handle.send_message_with_id(to.clone(), "paid", "PAY1").await.unwrap(), "PAY1");
+
+        assert_eq!(sent.recv().await.unwrap().get_attr_str("id"), Some("PAY1"));
+        assert!(sent.try_recv().is_err());
+
+        let result = handle.send_message_with_id(to, "paid", "bad id").await;
+        assert!(matches!(result, Err(ClientError::InvalidMessageId(_))));
+    }
+
+    /// Devices that received our sender key in a sent group message.
+    fn key_recipients(node: &Node) -> Vec<String> {
+        node.get_child_by_tag("participants")
+            .map(|p| p.get_children_by_tag("to")
+                .iter()
+                .filter_map(|to| to.get_attr_str("jid"))
+                .map(|jid| jid.split('@').next().unwrap().to_string())
+                .collect())
+            .unwrap_or_default()
+    }
+
+    /// Answer a usync device query: "me" and "b" have a linked device,
+    /// everyone else only their phone.
+    fn device_list_response(query: &Node) -> Node {
+        let mut list = Node::new("list");
+        let users = query.get_child_by_tag("usync").unwrap().get_child_by_tag("list").unwrap();
+        for requested in users.get_children_by_tag("user") {
+            let jid = requested.get_attr_str("jid").unwrap();
+            let mut device_list = Node::new("device-list");
+            let linked = match jid.split('@').next() {
+                Some("me") => Some("2"),
+                Some("b") => Some("3"),
+                _ => None,
+            };
+            for id in std::iter::once("0").chain(linked) {
+                let mut device = Node::new("device");
+                device.set_attr("id", id);
+                device_list.add_child(device);
+            }
+            let mut devices = Node::new("devices");
+            devices.add_child(device_list);
+            let mut user = Node::new("user");
+            user.set_attr("jid", jid.to_string());
+            user.add_child(devices);
+            list.add_child(user);
+        }
+        let mut usync = Node::new("usync");
+        usync.add_child(list);
+        usync
+    }
+
+    /// Answer a pre-key bundle query with a fresh bundle for every device
+    /// except those listed in `without`, which have none left.
+    fn bundle_response(query: &Node, without: &[&str]) -> Node {
+        use crate::crypto::{KeyPair, PreKey};
+
+        let bytes_node = |tag: &'static str, bytes: &[u8]| {
+            let mut node = Node::new(tag);
+            node.set_bytes(bytes.to_vec());
+            node
+        };
+        let mut list = Node::new("list");
+        for requested in query.get_child_by_tag("key").unwrap().get_children_by_tag("user") {
+            let jid = requested.get_attr_jid("jid").unwrap().clone();
+            let mut user = Node::new("user");
+            user.set_attr("jid", jid.clone());
+            if without.contains(&jid.to_string().split('@').next().unwrap()) {
+                let mut error = Node::new("error");
+                error.set_attr("code", "404");
+                error.set_attr("text", "item-not-found");
+                user.add_child(error);
+            } else {
+                let identity = KeyPair::generate();
+                let signed = PreKey::new_signed(1, &identity);
+                user.add_child(bytes_node("registration", &1234u32.to_be_bytes()));
+                user.add_child(bytes_node("type", &[5]));
+                user.add_child(bytes_node("identity", &identity.public));
+                let mut skey = Node::new("skey");
+                skey.add_child(bytes_node("id", &[0, 0, 1]));
+                skey.add_child(bytes_node("value", &signed.key_pair.public));
+                skey.add_child(bytes_node("signature", &signed.signature.unwrap()));
+                user.add_child(skey);
+            }
+            list.add_child(user);
+        }
+        list
+    }
+
+    /// Stand-in actor for group sends: answer group info, device list and
+    /// bundle queries, and pass sent messages on.
+    fn spawn_group_actor(
+        inner: Arc<ClientInner>,
+        mut commands: mpsc::Receiver<Command>,
+        members: Arc<std::sync::Mutex<Vec<&'static str>>>,
+        without_bundle: &'static [&'static str],
+    ) -> mpsc::UnboundedReceiver<Node> {
+        let (sent_tx, sent) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Some(Command::Send { node, reply }) = commands.recv().await {
+                let _ = reply.send(Ok(()));
+                if node.tag != "iq" {
+                    let _ = sent_tx.send(node);
+                    continue;
+                }
+
+                let mut response = Node::new("iq");
+                response.set_attr("id", node.get_attr_str("id").unwrap().to_string());
+                response.set_attr("type", "result");
+                match node.get_attr_str("xmlns") {
+                    Some("usync") => response.add_child(device_list_response(&node)),
+                    Some("encrypt") => response.add_child(bundle_response(&node, without_bundle)),
+                    _ => {
+                        let mut group = Node::new("group");
+                        group.set_attr("id", "123");
+                        for user in members.lock().unwrap().iter() {
+                            let mut participant = Node::new("participant");
+                            participant.set_attr("jid", format!("{}@s.whatsapp.net", user));
+                            group.add_child(participant);
+                        }
+                        response.add_child(group);
+                    }
+                }
+                inner.process_node(&response).unwrap();
+            }
+        });
+        sent
+    }
+
+    #[tokio::test]
+    async fn test_group_sender_key_rotates_after_departure() {
+        let (handle, inner, commands) = test_handle(ClientConfig::default());
+        inner.device.write().await.jid = Some(JID::new_ad("me", 0, 2));
+        let members = Arc::new(std::sync::Mutex::new(vec!["me", "a", "b"]));
+        let mut sent = spawn_group_actor(inner.clone(), commands, members.clone(), &[]);
+
+        // Every device gets the key, our phone included but not this device
+        let group = JID::new("123", "g.us");
+        handle.send_message(group.clone(), "one").await.unwrap();
+        assert_eq!(key_recipients(&sent.recv().await.unwrap()), vec!["me", "a", "b", "b:3"]);
+
+        handle.send_message(group.clone(), "two").await.unwrap();
+        assert!(key_recipients(&sent.recv().await.unwrap()).is_empty());
+
+        // "b" leaves the group
+        members.lock().unwrap().retain(|u| *u != "b");
+        let mut leave = Node::new("leave");
+        let mut participant = Node::new("participant");
+        participant.set_attr("jid", "b@s.whatsapp.net");
+        leave.add_child(participant);
+        let mut notification = Node::new("notification");
+        notification.set_attr("type", "w:gp2");
+        notification.set_attr("from", "123@g.us");
+        notification.add_child(leave);
+        let events = inner.process_node(&notification).unwrap();
+        assert!(matches!(events.as_slice(), [Event::GroupParticipants(_)]));
+
+        handle.send_message(group.clone(), "three").await.unwrap();
+        assert_eq!(key_recipients(&sent.recv().await.unwrap()), vec!["me", "a"]);
+    }
+
+    #[tokio::test]
+    async fn test_sender_key_is_only_distributed_encrypted() {
+        let (handle, inner, commands) = test_handle(ClientConfig::default());
+        inner.device.write().await.jid = Some(JID::new_ad("me", 0, 2));
+        let members = Arc::new(std::sync::Mutex::new(vec!["me", "a", "b"]));
+        let mut sent = spawn_group_actor(inner.clone(), commands, members, &["b:3"]);
+
+        let group = JID::new("123", "g.us");
+        handle.send_message(group.clone(), "one").await.unwrap();
+        let message = sent.recv().await.unwrap();
+        assert_eq!(key_recipients(&message), vec!["me", "a", "b"]);
+
+        // Each copy is a pre-key message, never the distribution itself
+        let own = JID::new_ad("me", 0, 2);
+        let plan = inner.sender_keys.prepare_send(inner.store.as_ref(), &group, &own, &[]).unwrap();
+        let distribution = plan.sender_key.distribution_message();
+        for to in message.get_child_by_tag("participants").unwrap().get_children_by_tag("to") {
+            let enc = to.get_child_by_tag("enc").unwrap();
+            assert_eq!(enc.get_attr_str("type"), Some("pkmsg"));
+            let data = enc.get_bytes().unwrap();
+            assert!(!data.windows(distribution.len()).any(|w| w == distribution.as_slice()));
+        }
+
+        // The device without a bundle did not get the key, so it is tried again
+        handle.send_message(group.clone(), "two").await.unwrap();
+        assert!(key_recipients(&sent.recv().await.unwrap()).is_empty());
+        let plan = inner.sender_keys.prepare_send(inner.store.as_ref(), &group, &own, &[JID::new_ad("b", 0, 3)]).unwrap();
+        assert_eq!(plan.needs_key, vec![JID::new_ad("b", 0, 3)]);
+    }
+
+    #[tokio::test]
+    async fn test_closed_handle_is_disconnected() {
+        let (handle, _inner, _commands) = test_handle(ClientConfig::default());
+        assert!(handle.is_connected());
+
+        handle.close();
+        assert!(!handle.is_connected());
+    }
+}
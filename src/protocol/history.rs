@@ -0,0 +1,256 @@
+//! History sync.
+//!
+//! After pairing, the phone sends the chat history as history sync
+//! notifications: small ones carry their payload inline, larger ones, such
+//! as the chunks of a full sync, point at an encrypted file to download.
+//!
+//! Older messages of a chat are requested from the primary device with a
+//! peer data operation, a protocol message sent to our own account. The
+//! phone answers with a history sync notification like those of the
+//! initial sync.
+
+use std::io::Read;
+use flate2::read::ZlibDecoder;
+use prost::Message as _;
+
+use crate::binary::Node;
+use crate::protocol::message::build_peer_message;
+use crate::proto::{
+    history_sync_type, peer_data_operation_request_type, protocol_message_type, E2eMessage,
+    HistorySyncOnDemandRequest, HistorySyncPayload, PeerDataOperationRequestMessage, ProtocolMessage,
+};
+use crate::media::{DownloadableMedia, MediaType};
+use crate::store::StoredMessage;
+use crate::types::{HistorySync, HistorySyncType, MediaDetails, JID};
+
+impl HistorySyncType {
+    /// Map a protobuf history sync type.
+    pub fn from_proto(value: i32) -> Self {
+        match value {
+            history_sync_type::INITIAL_BOOTSTRAP => HistorySyncType::Initial,
+            history_sync_type::RECENT => HistorySyncType::Recent,
+            history_sync_type::PUSH_NAME => HistorySyncType::Push,
+            history_sync_type::FULL => HistorySyncType::Full,
+            history_sync_type::ON_DEMAND => HistorySyncType::OnDemand,
+            other => HistorySyncType::Other(other),
+        }
+    }
+}
+
+impl HistorySync {
+    /// The file to download this sync's payload from, if it was not sent
+    /// inline.
+    pub fn downloadable(&self) -> Option<DownloadableMedia> {
+        if !self.data.is_empty() || self.media.direct_path.is_none() {
+            return None;
+        }
+        Some(DownloadableMedia {
+            media_type: MediaType::History,
+            url: String::new(),
+            mimetype: String::new(),
+            details: self.media.clone(),
+        })
+    }
+}
+
+/// Decompress a history sync payload. Payloads are zlib-compressed both
+/// inline and downloaded; data that does not inflate is returned as is.
+pub fn decompress_history_sync(data: &[u8]) -> Vec<u8> {
+    let mut decompressed = Vec::new();
+    match ZlibDecoder::new(data).read_to_end(&mut decompressed) {
+        Ok(_) => decompressed,
+        Err(_) => data.to_vec(),
+    }
+}
+
+/// Build the peer message asking the primary device for up to `count`
+/// messages of `chat` older than `before` (a Unix timestamp).
+pub fn build_history_request(own: &JID, message_id: &str, chat: &JID, before: i64, count: u32) -> Node {
+    let request = E2eMessage {
+        protocol_message: Some(Box::new(ProtocolMessage {
+            r#type: Some(protocol_message_type::PEER_DATA_OPERATION_REQUEST_MESSAGE),
+            peer_data_operation_request_message: Some(PeerDataOperationRequestMessage {
+                peer_data_operation_request_type: Some(peer_data_operation_request_type::HISTORY_SYNC_ON_DEMAND),
+                history_sync_on_demand_request: Some(HistorySyncOnDemandRequest {
+                    chat_jid: Some(chat.to_string()),
+                    on_demand_msg_count: Some(count.min(i32::MAX as u32) as i32),
+                    oldest_msg_timestamp_ms: Some(before.saturating_mul(1000)),
+                    ..Default::default()
+                }),
+            }),
+            ..Default::default()
+        })),
+        ..Default::default()
+    };
+    build_peer_message(own, message_id, &request)
+}
+
+/// Extract a history sync notification from a received message node.
+pub fn parse_history_sync(node: &Node) -> Option<HistorySync> {
+    if node.tag != "message" {
+        return None;
+    }
+    let payload = node.get_child_by_tag("enc")?.get_bytes()?;
+    let notification = E2eMessage::decode(payload).ok()?
+        .protocol_message?
+        .history_sync_notification?;
+
+    Some(HistorySync {
+        sync_type: HistorySyncType::from_proto(notification.sync_type.unwrap_or_default()),
+        data: notification.initial_hist_bootstrap_inline_payload.unwrap_or_default(),
+        media: MediaDetails {
+            direct_path: notification.direct_path,
+            media_key: notification.media_key,
+            file_sha256: notification.file_sha256,
+            file_enc_sha256: notification.file_enc_sha256,
+            file_length: notification.file_length,
+            local_path: None,
+        },
+        chunk_order: notification.chunk_order.unwrap_or_default(),
+        progress: notification.progress,
+        request_id: notification.peer_data_request_session_id,
+    })
+}
+
+/// Read the messages of a history sync payload, compressed or not, as
+/// they would be stored. Messages we sent are attributed to `own`.
+pub fn parse_history_messages(data: &[u8], own: Option<&JID>) -> Vec<StoredMessage> {
+    let Ok(payload) = HistorySyncPayload::decode(&*decompress_history_sync(data)) else {
+        return Vec::new();
+    };
+    let own = own.map(JID::to_non_ad).unwrap_or_default();
+
+    let mut messages = Vec::new();
+    for conversation in payload.conversations {
+        let Some(chat) = conversation.id.and_then(|id| id.parse::<JID>().ok()) else {
+            continue;
+        };
+        for info in conversation.messages.into_iter().filter_map(|entry| entry.message) {
+            let Some(key) = info.key else {
+                continue;
+            };
+            let Some(id) = key.id else {
+                continue;
+            };
+            let is_from_me = key.from_me.unwrap_or_default();
+            let sender = if is_from_me {
+                own.clone()
+            } else {
+                info.participant.or(key.participant)
+                    .and_then(|participant| participant.parse().ok())
+                    .unwrap_or_else(|| chat.clone())
+            };
+            let text = info.message.and_then(|message| {
+                message.conversation.or_else(|| message.extended_text_message.and_then(|extended| extended.text))
+            });
+            messages.push(StoredMessage {
+                id,
+                chat: chat.clone(),
+                sender,
+                is_from_me,
+                timestamp: info.message_timestamp.unwrap_or_default() as i64,
+                push_name: info.push_name,
+                text,
+            });
+        }
+    }
+    messages
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::HistorySyncNotification;
+
+    #[test]
+    fn test_build_history_request() {
+        let own = JID::new_ad("1234", 0, 7);
+        let chat = JID::new("5678", "s.whatsapp.net");
+        let node = build_history_request(&own, "3EB0AA", &chat, 1_700_000_000, 50);
+
+        assert_eq!(node.get_attr_str("to"), Some("1234@s.whatsapp.net"));
+        assert_eq!(node.get_attr_str("category"), Some("peer"));
+
+        let payload = node.get_child_by_tag("enc").unwrap().get_bytes().unwrap();
+        let request = E2eMessage::decode(payload).unwrap().protocol_message.unwrap()
+            .peer_data_operation_request_message.unwrap()
+            .history_sync_on_demand_request.unwrap();
+        assert_eq!(request.chat_jid.as_deref(), Some("5678@s.whatsapp.net"));
+        assert_eq!(request.on_demand_msg_count, Some(50));
+        assert_eq!(request.oldest_msg_timestamp_ms, Some(1_700_000_000_000));
+    }
+
+    #[test]
+    fn test_parse_history_sync() {
+        let notification = E2eMessage {
+            protocol_message: Some(Box::new(ProtocolMessage {
+                r#type: Some(protocol_message_type::HISTORY_SYNC_NOTIFICATION),
+                history_sync_notification: Some(HistorySyncNotification {
+                    direct_path: Some("/v/t62/history".to_string()),
+                    media_key: Some(vec![1; 32]),
+                    sync_type: Some(history_sync_type::ON_DEMAND),
+                    chunk_order: Some(2),
+                    peer_data_request_session_id: Some("3EB0AA".to_string()),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            })),
+            ..Default::default()
+        };
+        let mut enc = Node::new("enc");
+        enc.set_bytes(notification.encode_to_vec());
+        let mut node = Node::new("message");
+        node.add_child(enc);
+
+        let sync = parse_history_sync(&node).unwrap();
+        assert_eq!(sync.sync_type, HistorySyncType::OnDemand);
+        assert_eq!(sync.media.direct_path.as_deref(), Some("/v/t62/history"));
+        assert_eq!(sync.chunk_order, 2);
+        assert_eq!(sync.request_id.as_deref(), Some("3EB0AA"));
+        assert_eq!(sync.progress, None);
+        // Not inline, so it has to be downloaded
+        let media = sync.downloadable().unwrap();
+        assert_eq!(media.media_type, MediaType::History);
+        assert_eq!(media.download_url().as_deref(), Some("https://mmg.whatsapp.net/v/t62/history"));
+
+        assert!(parse_history_sync(&Node::new("message")).is_none());
+    }
+
+    #[test]
+    fn test_parse_history_messages() {
+        use crate::proto::{HistoryConversation, HistorySyncMsg, MessageKey, WebMessageInfo};
+
+        let own = JID::new_ad("1", 0, 4);
+        let group = JID::new("123-456", "g.us");
+        let entry = |id: &str, from_me: bool, participant: Option<&str>, text: &str| HistorySyncMsg {
+            message: Some(WebMessageInfo {
+                key: Some(MessageKey {
+                    remote_jid: Some(group.to_string()),
+                    from_me: Some(from_me),
+                    id: Some(id.to_string()),
+                    participant: None,
+                }),
+                message: Some(E2eMessage { conversation: Some(text.to_string()), ..Default::default() }),
+                message_timestamp: Some(1_700_000_000),
+                participant: participant.map(str::to_string),
+                push_name: None,
+            }),
+            msg_order_id: None,
+        };
+        let payload = HistorySyncPayload {
+            conversations: vec![HistoryConversation {
+                id: Some(group.to_string()),
+                messages: vec![entry("A", false, Some("2@s.whatsapp.net"), "hi"), entry("B", true, None, "hello")],
+            }],
+            ..Default::default()
+        };
+
+        let messages = parse_history_messages(&payload.encode_to_vec(), Some(&own));
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].sender, JID::new("2", "s.whatsapp.net"));
+        assert_eq!((messages[0].timestamp, messages[0].text.as_deref()), (1_700_000_000, Some("hi")));
+        assert!(messages[1].is_from_me);
+        assert_eq!(messages[1].sender, JID::new("1", "s.whatsapp.net"));
+        assert_eq!(messages[1].chat, group);
+    }
+}
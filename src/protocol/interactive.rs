@@ -0,0 +1,272 @@
+//! Buttons and list messages, and the replies to them.
+
+use prost::Message as _;
+
+use crate::binary::Node;
+use crate::proto::{
+    button_type, buttons_header_type, list_type, ButtonText, ButtonsMessage, ButtonsMessageButton,
+    E2eMessage, ListMessage, ListRow as ProtoListRow, ListSection as ProtoListSection,
+};
+use crate::protocol::client::{Client, ClientError};
+use crate::protocol::intercept::{MessageKind, SendableMessage};
+use crate::protocol::message::build_e2e_message;
+use crate::types::{Button, ListRow, ListSection, MessageContent, JID};
+
+/// Text message with quick-reply buttons.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Buttons {
+    pub text: String,
+    /// Bold line above the text
+    pub header: Option<String>,
+    pub footer: Option<String>,
+    pub buttons: Vec<Button>,
+}
+
+impl Buttons {
+    /// Buttons message with no buttons yet.
+    pub fn new(text: impl Into<String>) -> Self {
+        Self { text: text.into(), header: None, footer: None, buttons: Vec::new() }
+    }
+
+    /// Add a button.
+    pub fn with_button(mut self, id: impl Into<String>, text: impl Into<String>) -> Self {
+        self.buttons.push(Button { id: id.into(), text: text.into() });
+        self
+    }
+
+    /// Set the line above the text.
+    pub fn with_header(mut self, header: impl Into<String>) -> Self {
+        self.header = Some(header.into());
+        self
+    }
+
+    /// Set the line below the buttons.
+    pub fn with_footer(mut self, footer: impl Into<String>) -> Self {
+        self.footer = Some(footer.into());
+        self
+    }
+}
+
+/// Message opening a menu of rows grouped in sections.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct List {
+    pub title: String,
+    pub description: Option<String>,
+    /// Label of the button opening the menu
+    pub button_text: String,
+    pub footer: Option<String>,
+    pub sections: Vec<ListSection>,
+}
+
+impl List {
+    /// List with no sections yet.
+    pub fn new(title: impl Into<String>, button_text: impl Into<String>) -> Self {
+        Self {
+            title: title.into(),
+            description: None,
+            button_text: button_text.into(),
+            footer: None,
+            sections: Vec::new(),
+        }
+    }
+
+    /// Add a section of rows.
+    pub fn with_section(mut self, title: impl Into<String>, rows: Vec<ListRow>) -> Self {
+        self.sections.push(ListSection { title: title.into(), rows });
+        self
+    }
+
+    /// Set the text under the title.
+    pub fn with_description(mut self, description: impl Into<String>) -> Self {
+        self.description = Some(description.into());
+        self
+    }
+
+    /// Set the line below the menu button.
+    pub fn with_footer(mut self, footer: impl Into<String>) -> Self {
+        self.footer = Some(footer.into());
+        self
+    }
+}
+
+/// Build a buttons message.
+pub fn build_buttons_message(to: &JID, message_id: &str, buttons: &Buttons) -> Node {
+    let message = E2eMessage {
+        buttons_message: Some(ButtonsMessage {
+            text: buttons.header.clone(),
+            header_type: Some(match buttons.header {
+                Some(_) => buttons_header_type::TEXT,
+                None => buttons_header_type::EMPTY,
+            }),
+            content_text: Some(buttons.text.clone()),
+            footer_text: buttons.footer.clone(),
+            buttons: buttons.buttons.iter()
+                .map(|button| ButtonsMessageButton {
+                    button_id: Some(button.id.clone()),
+                    button_text: Some(ButtonText { display_text: Some(button.text.clone()) }),
+                    r#type: Some(button_type::RESPONSE),
+                })
+                .collect(),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    build_e2e_message(to, message_id, "text", Some("button"), &message)
+}
+
+/// Build a list message.
+pub fn build_list_message(to: &JID, message_id: &str, list: &List) -> Node {
+    let message = E2eMessage {
+        list_message: Some(ListMessage {
+            title: Some(list.title.clone()),
+            description: list.description.clone(),
+            button_text: Some(list.button_text.clone()),
+            list_type: Some(list_type::SINGLE_SELECT),
+            sections: list.sections.iter()
+                .map(|section| ProtoListSection {
+                    title: Some(section.title.clone()),
+                    rows: section.rows.iter()
+                        .map(|row| ProtoListRow {
+                            title: Some(row.title.clone()),
+                            description: row.description.clone(),
+                            row_id: Some(row.id.clone()),
+                        })
+                        .collect(),
+                })
+                .collect(),
+            footer_text: list.footer.clone(),
+            context_info: None,
+        }),
+        ..Default::default()
+    };
+    build_e2e_message(to, message_id, "text", Some("list"), &message)
+}
+
+/// Extract a button or list reply from a received message node.
+pub fn parse_interactive_response(node: &Node) -> Option<MessageContent> {
+    let payload = node.get_child_by_tag("enc")?.get_bytes()?;
+    let message = E2eMessage::decode(payload).ok()?;
+
+    if let Some(response) = message.buttons_response_message {
+        return Some(MessageContent::ButtonResponse {
+            button_id: response.selected_button_id?,
+            display_text: response.selected_display_text.unwrap_or_default(),
+            reply_to: response.context_info.and_then(|c| c.stanza_id),
+        });
+    }
+    if let Some(reply) = message.template_button_reply_message {
+        return Some(MessageContent::ButtonResponse {
+            button_id: reply.selected_id?,
+            display_text: reply.selected_display_text.unwrap_or_default(),
+            reply_to: reply.context_info.and_then(|c| c.stanza_id),
+        });
+    }
+    let response = message.list_response_message?;
+    Some(MessageContent::ListResponse {
+        row_id: response.single_select_reply?.selected_row_id?,
+        title: response.title.unwrap_or_default(),
+        reply_to: response.context_info.and_then(|c| c.stanza_id),
+    })
+}
+
+impl Client {
+    /// Send a message with quick-reply buttons, returning its ID.
+    ///
+    /// Presses arrive as messages with `MessageContent::ButtonResponse`.
+    pub async fn send_buttons(&self, to: &JID, buttons: &Buttons) -> Result<String, ClientError> {
+        let handle = self.connection()?;
+        let id = handle.new_message_id();
+        let message = SendableMessage::new(&id, to.clone(), &buttons.text).with_kind(MessageKind::Buttons);
+        handle.send_intercepted(message, |message| {
+            let buttons = Buttons { text: message.text.clone(), ..buttons.clone() };
+            build_buttons_message(&message.to, &id, &buttons)
+        }).await?;
+        Ok(id)
+    }
+
+    /// Send a list message, returning its ID.
+    ///
+    /// Picks arrive as messages with `MessageContent::ListResponse`.
+    pub async fn send_list(&self, to: &JID, list: &List) -> Result<String, ClientError> {
+        let handle = self.connection()?;
+        let id = handle.new_message_id();
+        let message = SendableMessage::new(&id, to.clone(), &list.title).with_kind(MessageKind::List);
+        handle.send_intercepted(message, |message| {
+            let list = List { title: message.text.clone(), ..list.clone() };
+            build_list_message(&message.to, &id, &list)
+        }).await?;
+        Ok(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::{ButtonsResponseMessage, ContextInfo, ListResponseMessage, SingleSelectReply};
+
+    fn decode(node: &Node) -> E2eMessage {
+        E2eMessage::decode(node.get_child_by_tag("enc").unwrap().get_bytes().unwrap()).unwrap()
+    }
+
+    fn reply_node(message: E2eMessage) -> Node {
+        build_e2e_message(&JID::new("1", "s.whatsapp.net"), "3EB0AB", "text", None, &message)
+    }
+
+    #[test]
+    fn test_build_buttons_and_list() {
+        let to = JID::new("1", "s.whatsapp.net");
+        let buttons = Buttons::new("Confirm your booking?")
+            .with_button("yes", "Yes")
+            .with_button("no", "No")
+            .with_footer("Reply within 24h");
+        let message = decode(&build_buttons_message(&to, "3EB0AA", &buttons)).buttons_message.unwrap();
+        assert_eq!(message.header_type, Some(buttons_header_type::EMPTY));
+        assert_eq!(message.buttons.len(), 2);
+        assert_eq!(message.buttons[1].button_id.as_deref(), Some("no"));
+
+        let list = List::new("Menu", "Choose")
+            .with_section("Drinks", vec![ListRow::new("tea", "Tea").with_description("Green or black")])
+            .with_section("Food", vec![ListRow::new("cake", "Cake")]);
+        let message = decode(&build_list_message(&to, "3EB0AA", &list)).list_message.unwrap();
+        assert_eq!(message.sections.len(), 2);
+        assert_eq!(message.sections[0].rows[0].row_id.as_deref(), Some("tea"));
+        assert_eq!(message.sections[0].rows[0].description.as_deref(), Some("Green or black"));
+    }
+
+    #[test]
+    fn test_parse_responses() {
+        let context_info = Some(ContextInfo { stanza_id: Some("3EB0AA".to_string()), ..Default::default() });
+
+        let node = reply_node(E2eMessage {
+            buttons_response_message: Some(ButtonsResponseMessage {
+                selected_button_id: Some("yes".to_string()),
+                selected_display_text: Some("Yes".to_string()),
+                context_info: context_info.clone(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        let Some(MessageContent::ButtonResponse { button_id, display_text, reply_to }) = parse_interactive_response(&node) else {
+            panic!("not a button response");
+        };
+        assert_eq!((button_id.as_str(), display_text.as_str()), ("yes", "Yes"));
+        assert_eq!(reply_to.as_deref(), Some("3EB0AA"));
+
+        let node = reply_node(E2eMessage {
+            list_response_message: Some(ListResponseMessage {
+                title: Some("Tea".to_string()),
+                single_select_reply: Some(SingleSelectReply { selected_row_id: Some("tea".to_string()) }),
+                context_info,
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        assert!(matches!(
+            parse_interactive_response(&node),
+            Some(MessageContent::ListResponse { row_id, .. }) if row_id == "tea"
+        ));
+
+        let text = reply_node(E2eMessage { conversation: Some("tea".to_string()), ..Default::default() });
+        assert!(parse_interactive_response(&text).is_none());
+    }
+}
@@ -0,0 +1,141 @@
+//! Pre-send interceptors.
+//!
+//! Interceptors see every outgoing message before it is sent: text,
+//! media captions, polls, buttons, lists, products, keeps and
+//! auto-replies. Each can change the message's recipient or visible text,
+//! for example to append a signature or strip personal data, or block it,
+//! for example to enforce a content policy. Interceptors run in the order they were added; once
+//! one blocks a message, the rest do not run and nothing is sent.
+
+use std::sync::Arc;
+
+use crate::types::JID;
+
+/// Kind of an outgoing message, and so what its text is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageKind {
+    /// Plain text, including auto-replies
+    Text,
+    /// Image, video, audio or document; the text is its caption
+    Media,
+    /// Poll; the text is its question
+    Poll,
+    /// Quick-reply buttons; the text is the body above them
+    Buttons,
+    /// List menu; the text is its title
+    List,
+    /// Shared catalog product; the text is the body sent with it
+    Product,
+    /// Keep or unkeep in a disappearing chat; has no text
+    Keep,
+}
+
+/// An outgoing message, as interceptors see it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SendableMessage {
+    id: String,
+    kind: MessageKind,
+    /// Recipient of the message
+    pub to: JID,
+    /// Visible text of the message, empty if it has none
+    pub text: String,
+}
+
+impl SendableMessage {
+    pub(crate) fn new(id: &str, to: JID, text: &str) -> Self {
+        Self { id: id.to_string(), kind: MessageKind::Text, to, text: text.to_string() }
+    }
+
+    pub(crate) fn with_kind(mut self, kind: MessageKind) -> Self {
+        self.kind = kind;
+        self
+    }
+
+    /// ID the message will be sent with.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Kind of the message.
+    pub fn kind(&self) -> MessageKind {
+        self.kind
+    }
+}
+
+/// What an interceptor decided about a message.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SendDecision {
+    /// Pass the message, as it now is, to the next interceptor
+    Send,
+    /// Do not send the message, for the given reason
+    Block(String),
+}
+
+/// A check run on outgoing messages before they are sent.
+pub trait SendInterceptor: Send + Sync {
+    /// Inspect or change a message, deciding whether it is sent.
+    fn intercept(&self, message: &mut SendableMessage) -> SendDecision;
+}
+
+impl<F> SendInterceptor for F
+where
+    F: Fn(&mut SendableMessage) -> SendDecision + Send + Sync,
+{
+    fn intercept(&self, message: &mut SendableMessage) -> SendDecision {
+        self(message)
+    }
+}
+
+/// Run a message through `interceptors` in order, stopping at the first
+/// that blocks it.
+pub(crate) fn run_interceptors(
+    interceptors: &[Arc<dyn SendInterceptor>],
+    message: &mut SendableMessage,
+) -> SendDecision {
+    for interceptor in interceptors {
+        if let SendDecision::Block(reason) = interceptor.intercept(message) {
+            return SendDecision::Block(reason);
+        }
+    }
+    SendDecision::Send
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn test_interceptors_run_in_order() {
+        let last_calls = Arc::new(AtomicUsize::new(0));
+        let counter = last_calls.clone();
+        let interceptors: Vec<Arc<dyn SendInterceptor>> = vec![
+            Arc::new(|message: &mut SendableMessage| {
+                message.text = message.text.replace("555-0100", "[redacted]");
+                SendDecision::Send
+            }),
+            Arc::new(|message: &mut SendableMessage| {
+                if message.text.contains("forbidden") {
+                    return SendDecision::Block("policy".to_string());
+                }
+                message.text.push_str("\n-- sent by bot");
+                SendDecision::Send
+            }),
+            Arc::new(move |_: &mut SendableMessage| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                SendDecision::Send
+            }),
+        ];
+        let to = JID::new("1234", "s.whatsapp.net");
+
+        let mut message = SendableMessage::new("3EB0AA", to.clone(), "call 555-0100");
+        assert_eq!(run_interceptors(&interceptors, &mut message), SendDecision::Send);
+        assert_eq!(message.text, "call [redacted]\n-- sent by bot");
+        assert_eq!(message.id(), "3EB0AA");
+        assert_eq!(message.kind(), MessageKind::Text);
+
+        let mut message = SendableMessage::new("3EB0AB", to, "forbidden words");
+        assert_eq!(run_interceptors(&interceptors, &mut message), SendDecision::Block("policy".to_string()));
+        assert_eq!(last_calls.load(Ordering::SeqCst), 1);
+    }
+}
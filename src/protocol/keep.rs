@@ -0,0 +1,139 @@
+//! Keeping messages in disappearing chats.
+//!
+//! In a chat with disappearing messages on, any member can keep a message
+//! so it stays after the timer runs out, and undo that again. Both are
+//! sent as a `KeepInChatMessage` naming the message.
+
+use prost::Message as _;
+
+use crate::binary::Node;
+use crate::proto::{keep_type, E2eMessage, KeepInChatMessage, MessageKey};
+use crate::protocol::client::{Client, ClientError};
+use crate::protocol::intercept::{MessageKind, SendableMessage};
+use crate::protocol::message::build_e2e_message;
+use crate::types::{KeptMessage, JID};
+
+/// Build the key naming the message `id` in `chat`, sent by `sender`.
+///
+/// Messages from `own` are marked `from_me`; in groups and broadcasts
+/// anyone else's message also names its sender as `participant`.
+pub fn build_message_key(chat: &JID, id: &str, sender: &JID, own: Option<&JID>) -> MessageKey {
+    let from_me = sender.is_empty() || own.is_some_and(|own| own.user == sender.user);
+    let participant = (!from_me && !chat.is_user()).then(|| sender.to_non_ad().to_string());
+    MessageKey {
+        remote_jid: Some(chat.to_string()),
+        from_me: Some(from_me),
+        id: Some(id.to_string()),
+        participant,
+    }
+}
+
+/// Build a message keeping or unkeeping the message named by `target` in
+/// `chat`.
+pub fn build_keep_message(chat: &JID, message_id: &str, target: MessageKey, keep: bool, timestamp_ms: i64) -> Node {
+    let message = E2eMessage {
+        keep_in_chat_message: Some(KeepInChatMessage {
+            key: Some(target),
+            keep_type: Some(if keep { keep_type::KEEP_FOR_ALL } else { keep_type::UNDO_KEEP_FOR_ALL }),
+            timestamp_ms: Some(timestamp_ms),
+        }),
+        ..Default::default()
+    };
+    build_e2e_message(chat, message_id, "text", None, &message)
+}
+
+/// Parse a received keep or unkeep, if the message is one.
+///
+/// `now` is used as the timestamp when the message carries none.
+pub fn parse_keep_message(node: &Node, now: i64) -> Option<KeptMessage> {
+    let payload = node.get_child_by_tag("enc")?.get_bytes()?;
+    let keep = E2eMessage::decode(payload).ok()?.keep_in_chat_message?;
+    let chat = node.parse_attr_jid("from")?;
+    let sender = node.parse_attr_jid("participant").unwrap_or_else(|| chat.clone());
+    let kept = match keep.keep_type? {
+        keep_type::KEEP_FOR_ALL => true,
+        keep_type::UNDO_KEEP_FOR_ALL => false,
+        _ => return None,
+    };
+    Some(KeptMessage {
+        chat,
+        sender,
+        message_id: keep.key?.id?,
+        kept,
+        timestamp: keep.timestamp_ms.map_or(now, |ms| ms / 1000),
+    })
+}
+
+impl Client {
+    /// Keep the message `id` in a disappearing chat, or with `keep` false
+    /// let it disappear again, returning the ID of the keep message.
+    ///
+    /// The message is looked up in the message index to name its sender;
+    /// one the index does not know is named by its ID alone.
+    ///
+    /// Other members' keeps arrive as `Event::KeptMessage`.
+    pub async fn keep_message(&self, chat: &JID, id: &str, keep: bool) -> Result<String, ClientError> {
+        let handle = self.connection()?;
+        let target = handle.message_key(chat, id)?;
+        let message_id = handle.new_message_id();
+        let timestamp_ms = handle.server_now().timestamp_millis();
+        let message = SendableMessage::new(&message_id, chat.clone(), "").with_kind(MessageKind::Keep);
+        handle.send_intercepted(message, |message| {
+            build_keep_message(&message.to, &message_id, target, keep, timestamp_ms)
+        }).await?;
+        Ok(message_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keep_round_trip() {
+        let group = JID::new("123", "g.us");
+        let member = JID::new("1", "s.whatsapp.net");
+
+        let key = build_message_key(&group, "MSG1", &member, None);
+        let mut node = build_keep_message(&group, "KEEP1", key.clone(), true, 1_700_000_000_500);
+        node.set_attr("from", group.clone());
+        node.set_attr("participant", member.clone());
+        assert_eq!(parse_keep_message(&node, 0), Some(KeptMessage {
+            chat: group.clone(),
+            sender: member,
+            message_id: "MSG1".to_string(),
+            kept: true,
+            timestamp: 1_700_000_000,
+        }));
+
+        let mut node = build_keep_message(&group, "KEEP2", key, false, 0);
+        node.set_attr("from", group.clone());
+        assert!(!parse_keep_message(&node, 0).unwrap().kept);
+
+        let mut text = build_e2e_message(&group, "TEXT", "text", None, &E2eMessage {
+            conversation: Some("hi".to_string()),
+            ..Default::default()
+        });
+        text.set_attr("from", group);
+        assert_eq!(parse_keep_message(&text, 0), None);
+    }
+
+    #[test]
+    fn test_message_key_names_sender() {
+        let group = JID::new("123", "g.us");
+        let own = JID::new_ad("1", 0, 4);
+        let member = JID::new_ad("2", 0, 3);
+
+        let key = build_message_key(&group, "MSG1", &member, Some(&own));
+        assert_eq!(key.from_me, Some(false));
+        assert_eq!(key.participant.as_deref(), Some("2@s.whatsapp.net"));
+
+        let key = build_message_key(&group, "MSG2", &own.to_non_ad(), Some(&own));
+        assert_eq!(key.from_me, Some(true));
+        assert_eq!(key.participant, None);
+
+        let chat = JID::new("2", "s.whatsapp.net");
+        let key = build_message_key(&chat, "MSG3", &chat, Some(&own));
+        assert_eq!((key.from_me, key.participant), (Some(false), None));
+    }
+}
@@ -0,0 +1,197 @@
+//! Business labels.
+//!
+//! Business accounts sort chats and messages with labels, which sync
+//! between devices through the "regular" app state collection: a
+//! `label_edit` mutation defines a label, and `label_jid` and
+//! `label_message` mutations put it on a chat or message. `LabelStore`
+//! follows those mutations, and the `build_*_patch` functions describe
+//! changes made here in the same form.
+//!
+//! This crate does not encrypt or send app state patches: a built patch
+//! still needs its mutations encrypted with an app state sync key, its
+//! hashes computed against the collection's current state and the result
+//! sent in a `w:sync:app:state` query. Labels here change only once the
+//! mutations come back through `Client::apply_app_state_mutations`.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+use crate::proto::{LabelAssociationAction, LabelEditAction, SyncActionValue};
+use crate::protocol::appstate::{AppStateMutation, AppStatePatch};
+use crate::types::{Event, Label, LabelAssociation, LabelEdit, LabelTarget, JID};
+
+const LABEL_EDIT: &str = "label_edit";
+const LABEL_CHAT: &str = "label_jid";
+const LABEL_MESSAGE: &str = "label_message";
+/// Version of the label actions' format
+const LABEL_ACTION_VERSION: i32 = 3;
+
+/// Parse a label mutation into the event it carries.
+pub fn parse_label_mutation(index: &[String], value: &SyncActionValue) -> Option<Event> {
+    match index {
+        [kind, id] if kind == LABEL_EDIT => {
+            let action = value.label_edit_action.as_ref()?;
+            Some(Event::LabelEdit(LabelEdit {
+                label: Label {
+                    id: id.clone(),
+                    name: action.name.clone().unwrap_or_default(),
+                    color: action.color.unwrap_or_default(),
+                    predefined_id: action.predefined_id,
+                    deleted: action.deleted.unwrap_or(false),
+                },
+            }))
+        }
+        [kind, label_id, chat] if kind == LABEL_CHAT => Some(Event::LabelAssociation(LabelAssociation {
+            label_id: label_id.clone(),
+            target: LabelTarget::Chat(chat.parse().ok()?),
+            labeled: value.label_association_action.as_ref()?.labeled?,
+        })),
+        [kind, label_id, chat, id, ..] if kind == LABEL_MESSAGE => Some(Event::LabelAssociation(LabelAssociation {
+            label_id: label_id.clone(),
+            target: LabelTarget::Message { chat: chat.parse().ok()?, id: id.clone() },
+            labeled: value.label_association_action.as_ref()?.labeled?,
+        })),
+        _ => None,
+    }
+}
+
+fn regular_patch(index: Vec<String>, value: SyncActionValue) -> AppStatePatch {
+    AppStatePatch {
+        collection: "regular",
+        mutations: vec![AppStateMutation { index, value, version: LABEL_ACTION_VERSION }],
+    }
+}
+
+/// Build the patch creating, changing or deleting a label.
+pub fn build_label_edit_patch(label: &Label, timestamp_ms: i64) -> AppStatePatch {
+    regular_patch(vec![LABEL_EDIT.to_string(), label.id.clone()], SyncActionValue {
+        timestamp: Some(timestamp_ms),
+        label_edit_action: Some(LabelEditAction {
+            name: Some(label.name.clone()),
+            color: Some(label.color),
+            predefined_id: label.predefined_id,
+            deleted: Some(label.deleted),
+        }),
+        ..Default::default()
+    })
+}
+
+/// Build the patch putting a label on a chat or taking it off.
+pub fn build_label_chat_patch(chat: &JID, label_id: &str, labeled: bool, timestamp_ms: i64) -> AppStatePatch {
+    regular_patch(vec![LABEL_CHAT.to_string(), label_id.to_string(), chat.to_string()], SyncActionValue {
+        timestamp: Some(timestamp_ms),
+        label_association_action: Some(LabelAssociationAction { labeled: Some(labeled) }),
+        ..Default::default()
+    })
+}
+
+/// Build the patch putting a label on a message or taking it off.
+pub fn build_label_message_patch(
+    chat: &JID,
+    message_id: &str,
+    label_id: &str,
+    labeled: bool,
+    timestamp_ms: i64,
+) -> AppStatePatch {
+    let index = [LABEL_MESSAGE, label_id, &chat.to_string(), message_id, "0", "0"].map(String::from).to_vec();
+    regular_patch(index, SyncActionValue {
+        timestamp: Some(timestamp_ms),
+        label_association_action: Some(LabelAssociationAction { labeled: Some(labeled) }),
+        ..Default::default()
+    })
+}
+
+/// Labels of the account and what they are on.
+#[derive(Default)]
+pub struct LabelStore {
+    labels: Mutex<HashMap<String, Label>>,
+    associations: Mutex<HashMap<String, HashSet<LabelTarget>>>,
+}
+
+impl LabelStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Feed an event to the store; only label events are used.
+    pub fn handle_event(&self, event: &Event) {
+        match event {
+            Event::LabelEdit(edit) if edit.label.deleted => {
+                self.labels.lock().unwrap().remove(&edit.label.id);
+                self.associations.lock().unwrap().remove(&edit.label.id);
+            }
+            Event::LabelEdit(edit) => {
+                self.labels.lock().unwrap().insert(edit.label.id.clone(), edit.label.clone());
+            }
+            Event::LabelAssociation(association) => {
+                let mut associations = self.associations.lock().unwrap();
+                let targets = associations.entry(association.label_id.clone()).or_default();
+                if association.labeled {
+                    targets.insert(association.target.clone());
+                } else {
+                    targets.remove(&association.target);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Labels that are not deleted, by ID.
+    pub fn labels(&self) -> Vec<Label> {
+        let mut labels: Vec<_> = self.labels.lock().unwrap().values().cloned().collect();
+        labels.sort_by(|a, b| a.id.cmp(&b.id));
+        labels
+    }
+
+    /// IDs of the labels on a chat or message.
+    pub fn labels_of(&self, target: &LabelTarget) -> Vec<String> {
+        let mut ids: Vec<_> = self.associations.lock().unwrap().iter()
+            .filter(|(_, targets)| targets.contains(target))
+            .map(|(id, _)| id.clone())
+            .collect();
+        ids.sort();
+        ids
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn label(id: &str, deleted: bool) -> Label {
+        Label { id: id.to_string(), name: "Paid".to_string(), color: 3, predefined_id: None, deleted }
+    }
+
+    fn apply(store: &LabelStore, patch: &AppStatePatch) {
+        for mutation in &patch.mutations {
+            store.handle_event(&parse_label_mutation(&mutation.index, &mutation.value).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_label_patches_round_trip() {
+        let store = LabelStore::new();
+        let chat = JID::new("1", "s.whatsapp.net");
+        let message = LabelTarget::Message { chat: chat.clone(), id: "MSG1".to_string() };
+
+        apply(&store, &build_label_edit_patch(&label("5", false), 0));
+        assert_eq!(store.labels(), [label("5", false)]);
+
+        let patch = build_label_chat_patch(&chat, "5", true, 0);
+        assert_eq!(patch.mutations[0].index_json(), r#"["label_jid","5","1@s.whatsapp.net"]"#);
+        apply(&store, &patch);
+        apply(&store, &build_label_message_patch(&chat, "MSG1", "5", true, 0));
+        assert_eq!(store.labels_of(&LabelTarget::Chat(chat.clone())), ["5"]);
+        assert_eq!(store.labels_of(&message), ["5"]);
+
+        apply(&store, &build_label_chat_patch(&chat, "5", false, 0));
+        assert!(store.labels_of(&LabelTarget::Chat(chat)).is_empty());
+
+        apply(&store, &build_label_edit_patch(&label("5", true), 0));
+        assert!(store.labels().is_empty());
+        assert!(store.labels_of(&message).is_empty());
+
+        let mute = ["mute".to_string(), "1@s.whatsapp.net".to_string()];
+        assert!(parse_label_mutation(&mute, &SyncActionValue::default()).is_none());
+    }
+}
@@ -0,0 +1,260 @@
+//! Sending images, videos, audio and documents.
+//!
+//! The file is encrypted and uploaded first; the message then carries the
+//! URL and the keys needed to download it, in the same `<media>` node that
+//! `parse_message` reads.
+
+use base64::{engine::general_purpose, Engine as _};
+
+use crate::binary::Node;
+use crate::media::{MediaType, UploadedMedia};
+use crate::protocol::client::{Client, ClientError};
+use crate::protocol::handle::ClientHandle;
+use crate::protocol::intercept::{MessageKind, SendableMessage};
+use crate::types::JID;
+
+/// Media file to send.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MediaMessage {
+    pub media_type: MediaType,
+    pub data: Vec<u8>,
+    pub mimetype: String,
+    /// Text shown under images and videos
+    pub caption: Option<String>,
+    /// File name shown for documents
+    pub filename: Option<String>,
+    /// Small JPEG shown while the file downloads
+    pub thumbnail: Option<Vec<u8>>,
+    /// Whether the media can be opened only once
+    pub view_once: bool,
+}
+
+impl MediaMessage {
+    /// Media message of a file, sent as the type its mime type suggests.
+    pub fn new(data: Vec<u8>, mimetype: impl Into<String>) -> Self {
+        let mimetype = mimetype.into();
+        Self {
+            media_type: MediaType::from_mimetype(&mimetype),
+            data,
+            mimetype,
+            caption: None,
+            filename: None,
+            thumbnail: None,
+            view_once: false,
+        }
+    }
+
+    /// Send as the given type instead, such as a photo as a document.
+    pub fn with_media_type(mut self, media_type: MediaType) -> Self {
+        self.media_type = media_type;
+        self
+    }
+
+    /// Set the text shown under the media.
+    pub fn with_caption(mut self, caption: impl Into<String>) -> Self {
+        self.caption = Some(caption.into());
+        self
+    }
+
+    /// Set the file name shown for documents.
+    pub fn with_filename(mut self, filename: impl Into<String>) -> Self {
+        self.filename = Some(filename.into());
+        self
+    }
+
+    /// Set the preview shown while the file downloads.
+    pub fn with_thumbnail(mut self, thumbnail: Vec<u8>) -> Self {
+        self.thumbnail = Some(thumbnail);
+        self
+    }
+
+    /// Send as view-once media, which recipients can open only once. Used
+    /// with images, videos and voice notes.
+    pub fn with_view_once(mut self) -> Self {
+        self.view_once = true;
+        self
+    }
+}
+
+/// Build the message for an uploaded media file. Binary values are base64.
+pub fn build_uploaded_media_message(
+    to: &JID,
+    message_id: &str,
+    message: &MediaMessage,
+    uploaded: &UploadedMedia,
+) -> Node {
+    let base64 = |bytes: &[u8]| general_purpose::STANDARD.encode(bytes);
+    let media_type = message.media_type.as_str();
+
+    let mut media = Node::new("media");
+    media.set_attr("type", media_type);
+    media.set_attr("url", uploaded.url.clone());
+    media.set_attr("mimetype", message.mimetype.clone());
+    media.set_attr("direct_path", uploaded.direct_path.clone());
+    media.set_attr("media_key", base64(&uploaded.media_key));
+    media.set_attr("file_sha256", base64(&uploaded.file_sha256));
+    media.set_attr("file_enc_sha256", base64(&uploaded.file_enc_sha256));
+    media.set_attr("file_length", uploaded.file_length.to_string());
+    if let Some(filename) = &message.filename {
+        media.set_attr("filename", filename.clone());
+    }
+    if let Some(caption) = &message.caption {
+        let mut caption_node = Node::new("caption");
+        caption_node.set_bytes(caption.as_bytes().to_vec());
+        media.add_child(caption_node);
+    }
+    if let Some(thumbnail) = &message.thumbnail {
+        let mut thumbnail_node = Node::new("jpeg_thumbnail");
+        thumbnail_node.set_bytes(thumbnail.clone());
+        media.add_child(thumbnail_node);
+    }
+
+    let mut node = Node::new("message");
+    node.set_attr("id", message_id);
+    node.set_attr("type", "media");
+    node.set_attr("to", to.to_string());
+    node.set_attr("mediatype", media_type);
+    if message.view_once {
+        let mut wrapper = Node::new("view_once");
+        wrapper.add_child(media);
+        node.add_child(wrapper);
+    } else {
+        node.add_child(media);
+    }
+    node
+}
+
+impl ClientHandle {
+    /// Run the caption of a media message through the send interceptors,
+    /// putting the caption they passed on the message and returning the
+    /// recipient.
+    async fn intercept_media(&self, id: &str, to: &JID, message: &mut MediaMessage) -> Result<JID, ClientError> {
+        let caption = message.caption.as_deref().unwrap_or_default();
+        let passed = self.intercept(SendableMessage::new(id, to.clone(), caption).with_kind(MessageKind::Media)).await?;
+        if message.caption.is_some() || !passed.text.is_empty() {
+            message.caption = Some(passed.text);
+        }
+        Ok(passed.to)
+    }
+
+    /// Send a media file that was already uploaded, returning the message
+    /// ID. The file's data is not used, so one upload can go to many
+    /// recipients.
+    pub(crate) async fn send_uploaded_media(
+        &self,
+        to: &JID,
+        message: &MediaMessage,
+        uploaded: &UploadedMedia,
+    ) -> Result<String, ClientError> {
+        let id = self.new_message_id();
+        let mut message = MediaMessage {
+            data: Vec::new(),
+            mimetype: message.mimetype.clone(),
+            caption: message.caption.clone(),
+            filename: message.filename.clone(),
+            thumbnail: message.thumbnail.clone(),
+            ..*message
+        };
+        let to = self.intercept_media(&id, to, &mut message).await?;
+        self.send_node(build_uploaded_media_message(&to, &id, &message, uploaded)).await?;
+        Ok(id)
+    }
+}
+
+impl Client {
+    /// Upload a media file and send it, returning the message ID.
+    ///
+    /// The caption passes through the send interceptors before anything is
+    /// uploaded.
+    pub async fn send_media(&self, to: &JID, message: &MediaMessage) -> Result<String, ClientError> {
+        let handle = self.connection()?;
+        let id = handle.new_message_id();
+        let mut message = message.clone();
+        let to = handle.intercept_media(&id, to, &mut message).await?;
+        let uploaded = handle.upload_media(std::mem::take(&mut message.data), message.media_type, |_| {})
+            .await?
+            .wait()
+            .await?;
+
+        handle.send_node(build_uploaded_media_message(&to, &id, &message, &uploaded)).await?;
+        Ok(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::message::parse_message;
+    use crate::types::MessageContent;
+
+    #[test]
+    fn test_uploaded_media_round_trip() {
+        let uploaded = UploadedMedia {
+            url: "https://mmg.whatsapp.net/d/f/abc.enc".to_string(),
+            direct_path: "/v/t62/abc.enc".to_string(),
+            media_key: vec![1; 32],
+            file_sha256: vec![2; 32],
+            file_enc_sha256: vec![3; 32],
+            file_length: 1234,
+        };
+        let message = MediaMessage::new(vec![0; 1234], "application/pdf")
+            .with_filename("invoice.pdf")
+            .with_thumbnail(vec![0xFF, 0xD8]);
+        assert_eq!(message.media_type, MediaType::Document);
+
+        let mut node = build_uploaded_media_message(&JID::new("1", "s.whatsapp.net"), "3EB0AA", &message, &uploaded);
+        assert_eq!(node.get_attr_str("mediatype"), Some("document"));
+        let thumbnail = node.get_child_by_tag("media").unwrap().get_child_by_tag("jpeg_thumbnail").unwrap();
+        assert_eq!(thumbnail.get_bytes(), Some(&[0xFF, 0xD8][..]));
+
+        // Read it back as the recipient would
+        node.set_attr("from", "2@s.whatsapp.net");
+        let (_, content) = parse_message(&node, 0).unwrap();
+        assert_eq!(content.jpeg_thumbnail(), Some(&[0xFF, 0xD8][..]));
+        let MessageContent::Document { filename, mimetype, media, .. } = content else {
+            panic!("not a document");
+        };
+        assert_eq!((filename.as_str(), mimetype.as_str()), ("invoice.pdf", "application/pdf"));
+        assert_eq!(media, uploaded.details());
+    }
+
+    #[test]
+    fn test_view_once_round_trip() {
+        let uploaded = UploadedMedia {
+            url: "https://mmg.whatsapp.net/d/f/abc.enc".to_string(),
+            direct_path: "/v/t62/abc.enc".to_string(),
+            media_key: vec![1; 32],
+            file_sha256: vec![2; 32],
+            file_enc_sha256: vec![3; 32],
+            file_length: 1234,
+        };
+        let message = MediaMessage::new(vec![0; 1234], "image/jpeg").with_caption("once").with_view_once();
+
+        let mut node = build_uploaded_media_message(&JID::new("1", "s.whatsapp.net"), "3EB0AA", &message, &uploaded);
+        assert!(node.get_child_by_tag("media").is_none());
+        assert!(node.get_child_by_tag("view_once").and_then(|w| w.get_child_by_tag("media")).is_some());
+
+        node.set_attr("from", "2@s.whatsapp.net");
+        let (_, content) = parse_message(&node, 0).unwrap();
+        assert!(content.is_view_once());
+        let MessageContent::ViewOnce(inner) = &content else {
+            panic!("not view-once");
+        };
+        assert!(matches!(&**inner, MessageContent::Image { caption: Some(caption), .. } if caption == "once"));
+        let media = crate::media::DownloadableMedia::from_content(&content).unwrap();
+        assert_eq!(media.media_type, MediaType::Image);
+        assert_eq!(media.details, uploaded.details());
+    }
+
+    #[test]
+    fn test_media_type_from_mimetype() {
+        assert_eq!(MediaMessage::new(Vec::new(), "image/jpeg").media_type, MediaType::Image);
+        assert_eq!(MediaMessage::new(Vec::new(), "video/mp4").media_type, MediaType::Video);
+        assert_eq!(MediaMessage::new(Vec::new(), "audio/ogg; codecs=opus").media_type, MediaType::Audio);
+        assert_eq!(MediaMessage::new(Vec::new(), "text/plain").media_type, MediaType::Document);
+        assert_eq!(
+            MediaMessage::new(Vec::new(), "image/png").with_media_type(MediaType::Document).media_type,
+            MediaType::Document,
+        );
+    }
+}
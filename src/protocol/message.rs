@@ -2,20 +2,14 @@
 //!
 //! Provides message building, sending, and receiving functionality.
 
-use crate::types::{JID, MessageContent, MessageInfo};
-use crate::binary::{Node, AttrValue};
-use chrono::Utc;
-use rand::Rng;
+use crate::types::{JID, MessageContent, MessageInfo, MediaDetails};
+use crate::binary::Node;
+use crate::proto::E2eMessage;
+use prost::Message as _;
+use base64::{Engine as _, engine::general_purpose};
+use crate::protocol::msgid::generate_message_id;
 
-/// Generate a unique message ID.
-pub fn generate_message_id() -> String {
-    let mut rng = rand::thread_rng();
-    let bytes: [u8; 8] = rng.gen();
-    let id: u64 = u64::from_be_bytes(bytes);
-    format!("{:X}", id)
-}
-
-/// Build a text message node.
+/// Build a text message node, with a new ID unless `message_id` is given.
 pub fn build_text_message(to: &JID, text: &str, message_id: Option<&str>) -> Node {
     let id = message_id.map(String::from).unwrap_or_else(generate_message_id);
     
@@ -76,6 +70,20 @@ pub fn build_receipt(to: &JID, message_ids: &[String], receipt_type: &str) -> No
     node
 }
 
+/// Build a delivery receipt node, which carries no type.
+pub fn build_delivery_receipt(to: &JID, message_ids: &[String]) -> Node {
+    let mut node = Node::new("receipt");
+    node.set_attr("to", to.to_string());
+
+    for id in message_ids {
+        let mut item = Node::new("item");
+        item.set_attr("id", id.clone());
+        node.add_child(item);
+    }
+
+    node
+}
+
 /// Build a read receipt node.
 pub fn build_read_receipt(to: &JID, message_ids: &[String]) -> Node {
     build_receipt(to, message_ids, "read")
@@ -99,37 +107,111 @@ pub fn build_chat_state(to: &JID, composing: bool) -> Node {
     node
 }
 
-/// Parse a message node into MessageInfo and MessageContent.
-pub fn parse_message(node: &Node) -> Option<(MessageInfo, MessageContent)> {
+/// Build a peer message: a protocol message sent to our own other devices.
+///
+/// Pairwise Signal sessions are not implemented yet, so the message is
+/// attached without encryption.
+pub fn build_peer_message(own: &JID, message_id: &str, message: &E2eMessage) -> Node {
+    let mut enc = Node::new("enc");
+    enc.set_attr("v", "2");
+    enc.set_attr("type", "msg");
+    enc.set_bytes(message.encode_to_vec());
+
+    let mut node = Node::new("message");
+    node.set_attr("id", message_id);
+    node.set_attr("to", own.to_non_ad().to_string());
+    node.set_attr("type", "text");
+    node.set_attr("category", "peer");
+    node.set_attr("push_priority", "high_force");
+    node.add_child(enc);
+    node
+}
+
+/// Build a message carrying a protobuf message, with the stanza `type` and
+/// optional `mediatype` the server expects for its content.
+///
+/// Signal sessions are not implemented yet, so the message is attached
+/// without encryption.
+pub fn build_e2e_message(
+    to: &JID,
+    message_id: &str,
+    message_type: &str,
+    mediatype: Option<&str>,
+    message: &E2eMessage,
+) -> Node {
+    let mut enc = Node::new("enc");
+    enc.set_attr("v", "2");
+    enc.set_attr("type", "msg");
+    if let Some(mediatype) = mediatype {
+        enc.set_attr("mediatype", mediatype);
+    }
+    enc.set_bytes(message.encode_to_vec());
+
+    let mut node = Node::new("message");
+    node.set_attr("id", message_id);
+    node.set_attr("to", to.to_string());
+    node.set_attr("type", message_type);
+    node.add_child(enc);
+    node
+}
+
+/// Parse message content carried as a protobuf in `<enc>`.
+pub(crate) fn parse_e2e_content(node: &Node) -> Option<MessageContent> {
+    crate::protocol::business::parse_product_message(node)
+        .or_else(|| crate::protocol::interactive::parse_interactive_response(node))
+        .or_else(|| crate::protocol::stickerpack::parse_sticker_pack_message(node))
+        .or_else(|| parse_e2e_text(node))
+}
+
+/// Parse text sent as an end-to-end message, plain or with context such as
+/// a quoted message.
+fn parse_e2e_text(node: &Node) -> Option<MessageContent> {
+    let message = E2eMessage::decode(node.get_child_by_tag("enc")?.get_bytes()?).ok()?;
+    message.conversation
+        .or_else(|| message.extended_text_message.and_then(|extended| extended.text))
+        .map(MessageContent::Text)
+}
+
+/// Parse a message node into MessageInfo and MessageContent. The
+/// timestamp is the server's `t`, or `now` without one.
+pub fn parse_message(node: &Node, now: i64) -> Option<(MessageInfo, MessageContent)> {
+    let info = parse_message_info(node, now)?;
+    Some((info, parse_message_content(node)))
+}
+
+/// Parse who sent a message node, and in which chat. The timestamp is the
+/// server's `t`, or `now` without one.
+pub fn parse_message_info(node: &Node, now: i64) -> Option<MessageInfo> {
     if node.tag != "message" {
         return None;
     }
-    
-    let id = node.get_attr_str("id")?.to_string();
-    let from_str = node.get_attr_str("from")?;
-    let from: JID = from_str.parse().ok()?;
-    let msg_type = node.get_attr_str("type").unwrap_or("text");
-    
-    let is_group = from.server == crate::types::servers::GROUP;
-    let sender = if is_group {
-        node.get_attr_str("participant")
-            .and_then(|s| s.parse().ok())
-            .unwrap_or(from.clone())
-    } else {
-        from.clone()
+
+    let chat = node.parse_attr_jid("from")?;
+    let is_group = chat.server == crate::types::servers::GROUP;
+    let sender = match is_group {
+        true => node.parse_attr_jid("participant").unwrap_or_else(|| chat.clone()),
+        false => chat.clone(),
     };
-    
-    let info = MessageInfo {
-        id,
+
+    Some(MessageInfo {
+        id: node.get_attr_str("id")?.to_string(),
         sender,
-        chat: from,
+        chat,
         is_from_me: false, // Will be determined by comparing to own JID
         is_group,
-        timestamp: Utc::now().timestamp(),
+        timestamp: node.get_attr_int("t").unwrap_or(now),
         push_name: node.get_attr_str("notify").map(String::from),
-    };
-    
-    let content = match msg_type {
+        reply_to: crate::protocol::replies::parse_reply_to(node),
+    })
+}
+
+/// Parse the content of a message node.
+pub fn parse_message_content(node: &Node) -> MessageContent {
+    if let Some(content) = parse_e2e_content(node) {
+        return content;
+    }
+
+    match node.get_attr_str("type").unwrap_or("text") {
         "text" => {
             let body = node.get_child_by_tag("body")
                 .and_then(|b| b.get_bytes())
@@ -137,46 +219,83 @@ pub fn parse_message(node: &Node) -> Option<(MessageInfo, MessageContent)> {
                 .unwrap_or_default();
             MessageContent::Text(body)
         }
-        "media" => {
-            parse_media_content(node).unwrap_or(MessageContent::Unknown)
+        "media" => match node.get_child_by_tag("view_once") {
+            Some(wrapper) => parse_media_content(node, wrapper).map(|content| MessageContent::ViewOnce(Box::new(content))),
+            None => parse_media_content(node, node),
         }
+        .unwrap_or(MessageContent::Unknown),
         _ => MessageContent::Unknown,
-    };
-    
-    Some((info, content))
+    }
 }
 
-/// Parse media content from a message node.
-fn parse_media_content(node: &Node) -> Option<MessageContent> {
-    let media = node.get_child_by_tag("media")?;
+/// Parse media content from a message node, with the `<media>` node in
+/// `container`: the message itself, or its view-once wrapper.
+fn parse_media_content(node: &Node, container: &Node) -> Option<MessageContent> {
+    let media = container.get_child_by_tag("media")?;
     let media_type = media.get_attr_str("type")?;
     let url = media.get_attr_str("url")?.to_string();
     let mimetype = media.get_attr_str("mimetype").unwrap_or("application/octet-stream").to_string();
     let caption = media.get_child_by_tag("caption")
         .and_then(|c| c.get_bytes())
         .map(|b| String::from_utf8_lossy(b).to_string());
+    let details = parse_media_details(media);
+    let jpeg_thumbnail = media.get_child_by_tag("jpeg_thumbnail")
+        .and_then(|t| t.get_bytes())
+        .map(<[u8]>::to_vec);
     
     Some(match media_type {
-        "image" => MessageContent::Image { url, caption, mimetype },
-        "video" => MessageContent::Video { url, caption, mimetype },
-        "audio" => MessageContent::Audio { url, mimetype, ptt: false },
+        "image" => MessageContent::Image { url, caption, mimetype, media: details, jpeg_thumbnail },
+        "video" => MessageContent::Video { url, caption, mimetype, media: details, jpeg_thumbnail },
+        // Voice notes are sent with the "ptt" (push to talk) type, or as
+        // audio flagged with a ptt attribute
+        "audio" | "ptt" => MessageContent::Audio {
+            url,
+            mimetype,
+            ptt: media_type == "ptt"
+                || node.get_attr_str("mediatype") == Some("ptt")
+                || media.get_attr_str("ptt") == Some("true"),
+            media: details,
+            seconds: media.get_attr_str("seconds").and_then(|s| s.parse().ok()),
+            waveform: media.get_child_by_tag("waveform")
+                .and_then(|w| w.get_bytes())
+                .map(<[u8]>::to_vec),
+        },
         "document" => MessageContent::Document {
             url,
             filename: media.get_attr_str("filename").unwrap_or("file").to_string(),
             mimetype,
+            media: details,
+            jpeg_thumbnail,
         },
-        "sticker" => MessageContent::Sticker { url },
+        "sticker" => MessageContent::Sticker { url, media: details },
         _ => MessageContent::Unknown,
     })
 }
 
+/// Parse the download details of a media node. Binary values are base64.
+fn parse_media_details(media: &Node) -> MediaDetails {
+    let bytes = |name: &str| {
+        media.get_attr_str(name)
+            .and_then(|value| general_purpose::STANDARD.decode(value).ok())
+    };
+
+    MediaDetails {
+        direct_path: media.get_attr_str("direct_path").map(String::from),
+        media_key: bytes("media_key"),
+        file_sha256: bytes("file_sha256"),
+        file_enc_sha256: bytes("file_enc_sha256"),
+        file_length: media.get_attr_str("file_length").and_then(|l| l.parse().ok()),
+        local_path: None,
+    }
+}
+
 /// Parse a receipt node.
 pub fn parse_receipt(node: &Node) -> Option<(JID, Vec<String>, String)> {
     if node.tag != "receipt" {
         return None;
     }
     
-    let from: JID = node.get_attr_str("from")?.parse().ok()?;
+    let from = node.parse_attr_jid("from")?;
     let receipt_type = node.get_attr_str("type").unwrap_or("delivery").to_string();
     
     let message_ids: Vec<String> = node.get_children()
@@ -195,15 +314,6 @@ pub fn parse_receipt(node: &Node) -> Option<(JID, Vec<String>, String)> {
 mod tests {
     use super::*;
 
-    #[test]
-    fn test_generate_message_id() {
-        let id1 = generate_message_id();
-        let id2 = generate_message_id();
-        
-        assert!(!id1.is_empty());
-        assert_ne!(id1, id2);
-    }
-
     #[test]
     fn test_build_text_message() {
         let to = JID::new("123456789", "s.whatsapp.net");
@@ -214,6 +324,66 @@ mod tests {
         assert!(node.get_attr_str("id").is_some());
     }
 
+    #[test]
+    fn test_parse_media_details() {
+        let mut media = Node::new("media");
+        media.set_attr("type", "image");
+        media.set_attr("url", "https://example.com/file");
+        media.set_attr("direct_path", "/v/t62/file");
+        media.set_attr("media_key", general_purpose::STANDARD.encode([1u8; 32]));
+        media.set_attr("file_length", "1234");
+
+        let mut node = Node::new("message");
+        node.set_attr("id", "ABC");
+        node.set_attr("from", "123456789@s.whatsapp.net");
+        node.set_attr("type", "media");
+        node.add_child(media);
+
+        let (_, content) = parse_message(&node, 0).unwrap();
+        match content {
+            MessageContent::Image { media, .. } => {
+                assert_eq!(media.direct_path.as_deref(), Some("/v/t62/file"));
+                assert_eq!(media.media_key, Some(vec![1u8; 32]));
+                assert_eq!(media.file_length, Some(1234));
+                assert_eq!(media.file_sha256, None);
+            }
+            other => panic!("unexpected content: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_voice_note() {
+        let audio = |media_type: &str, ptt: Option<&str>| {
+            let mut media = Node::new("media");
+            media.set_attr("type", media_type);
+            media.set_attr("url", "https://example.com/file");
+            media.set_attr("mimetype", "audio/ogg; codecs=opus");
+            media.set_attr("seconds", "7");
+            if let Some(ptt) = ptt {
+                media.set_attr("ptt", ptt);
+            }
+            let mut waveform = Node::new("waveform");
+            waveform.set_bytes(vec![0, 40, 99]);
+            media.add_child(waveform);
+
+            let mut node = Node::new("message");
+            node.set_attr("id", "ABC");
+            node.set_attr("from", "123456789@s.whatsapp.net");
+            node.set_attr("type", "media");
+            node.add_child(media);
+            parse_message(&node, 0).unwrap().1
+        };
+
+        let MessageContent::Audio { ptt, seconds, waveform, .. } = audio("ptt", None) else {
+            panic!("not audio");
+        };
+        assert!(ptt);
+        assert_eq!(seconds, Some(7));
+        assert_eq!(waveform.as_deref(), Some(&[0, 40, 99][..]));
+        assert!(matches!(audio("audio", Some("true")), MessageContent::Audio { ptt: true, .. }));
+        assert!(matches!(audio("audio", None), MessageContent::Audio { ptt: false, .. }));
+    }
+
     #[test]
     fn test_build_presence() {
         let available = build_presence(true);
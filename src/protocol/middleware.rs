@@ -0,0 +1,86 @@
+//! Event middleware.
+//!
+//! Middleware sees every event before the event handlers and
+//! `Client::receive` do. Each stage gets the event and the rest of the
+//! pipeline, and can pass the event on, change it, drop it by not calling
+//! `next`, or pass on several events. Stages run in the order they were
+//! added.
+
+use std::sync::Arc;
+
+use crate::types::Event;
+
+/// A stage of the event pipeline.
+pub trait EventMiddleware: Send + Sync {
+    /// Handle an event, calling `next.run` to pass it on.
+    fn handle(&self, event: Event, next: Next<'_>);
+}
+
+impl<F> EventMiddleware for F
+where
+    F: Fn(Event, Next<'_>) + Send + Sync,
+{
+    fn handle(&self, event: Event, next: Next<'_>) {
+        self(event, next)
+    }
+}
+
+/// The rest of the pipeline after a middleware stage.
+#[derive(Clone, Copy)]
+pub struct Next<'a> {
+    stages: &'a [Arc<dyn EventMiddleware>],
+    deliver: &'a dyn Fn(Event),
+}
+
+impl Next<'_> {
+    /// Pass an event to the next stage, or deliver it after the last one.
+    pub fn run(&self, event: Event) {
+        match self.stages.split_first() {
+            Some((stage, stages)) => stage.handle(event, Next { stages, deliver: self.deliver }),
+            None => (self.deliver)(event),
+        }
+    }
+}
+
+/// Run an event through `stages`, calling `deliver` for each event that
+/// comes out.
+pub(crate) fn run_pipeline(stages: &[Arc<dyn EventMiddleware>], event: Event, deliver: &dyn Fn(Event)) {
+    Next { stages, deliver }.run(event)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::sync::Mutex;
+    use crate::types::{Connected, Disconnected, DisconnectReason};
+
+    #[test]
+    fn test_stages_run_in_order() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let stage = |name: &'static str| {
+            let log = log.clone();
+            Arc::new(move |event: Event, next: Next<'_>| {
+                log.lock().unwrap().push(name);
+                next.run(event);
+            }) as Arc<dyn EventMiddleware>
+        };
+        // Drops disconnects and doubles connects
+        let filter: Arc<dyn EventMiddleware> = Arc::new(|event: Event, next: Next<'_>| match event {
+            Event::Disconnected(_) => {}
+            event => {
+                next.run(event.clone());
+                next.run(event);
+            }
+        });
+        let stages = vec![stage("first"), filter, stage("last")];
+
+        let delivered = RefCell::new(Vec::new());
+        let deliver = |event| delivered.borrow_mut().push(event);
+        run_pipeline(&stages, Event::Connected(Connected { is_reconnect: false }), &deliver);
+        run_pipeline(&stages, Event::Disconnected(Disconnected { reason: DisconnectReason::Unknown }), &deliver);
+
+        assert_eq!(delivered.borrow().len(), 2);
+        assert_eq!(*log.lock().unwrap(), ["first", "last", "last", "first"]);
+    }
+}
@@ -3,12 +3,114 @@
 //! Contains the main Client implementation, QR pairing, message handling,
 //! and request/response tracking.
 
+mod actor;
 mod client;
+mod handle;
 mod qr;
 mod message;
 mod request;
+mod ratelimit;
+mod group;
+mod senderkey;
+mod call;
+mod receipts;
+mod msgid;
+mod typing;
+mod autoreply;
+mod template;
+mod middleware;
+mod history;
+mod appstate;
+mod contacts;
+mod business;
+mod interactive;
+mod newsletter;
+mod presence;
+mod ban;
+mod mediamsg;
+mod unread;
+mod devices;
+mod passive;
+mod disconnect;
+mod prekeys;
+mod retry;
+mod watchdog;
+mod clock;
+mod replay;
+mod msgsecret;
+mod keep;
+mod status;
+mod labels;
+mod biztools;
+mod ephemeral;
+mod replies;
+mod intercept;
+mod flood;
+mod stickerpack;
 
 pub use client::{Client, ClientConfig, ClientError};
-pub use qr::{QRPairing, QREvent, QRError, QRChannel, start_qr_pairing};
+pub use handle::{ClientHandle, BulkContent, BulkSendResult};
+pub use ratelimit::{RateLimit, RateLimiter};
+pub use group::{
+    GroupInfoRequest, SubGroupsRequest, JoinRequestsRequest, JoinRequestsActionRequest,
+    parse_group_node, is_group_notification, parse_participant_changes, parse_join_requests,
+    parse_setting_changes, GroupCache, GroupParticipantsRequest, build_mentions, PARTICIPANTS_PAGE_SIZE,
+    GroupPhotoRequest, build_description_node, parse_description_change, is_picture_notification,
+    parse_photo_changes,
+};
+pub use clock::{Clock, SystemClock, ManualClock, ServerClock, RandomSource, SystemRandom, SeededRandom};
+pub use msgid::{generate_message_id, generate_message_id_with, validate_message_id, InvalidMessageId, TagGenerator, MAX_MESSAGE_ID_LEN};
+pub use receipts::{ReceiptTracker, MessageReceipts};
+pub use presence::{PresenceStore, parse_presence};
+pub use devices::{
+    DeviceCache, DeviceListRequest, UserDevices, device_list_hash, is_devices_notification, parse_devices_notification,
+    DEVICE_MISMATCH_ERROR,
+};
+pub use passive::SetPassiveRequest;
+pub use unread::{UnreadTracker, ChatReadState, parse_mark_chat_as_read_mutation};
+pub use mediamsg::{MediaMessage, build_uploaded_media_message};
+pub use retry::{build_retry_receipt, MAX_RETRIES};
+pub use prekeys::{PreKeyBundle, PreKeyBundleRequest, PendingPreKey, SessionState, build_session};
+pub use disconnect::{parse_stream_error, logged_out_event, LOGGED_OUT_CODE, RESTART_REQUIRED_CODE};
+pub use ban::{parse_ban, TEMPORARY_BAN_CODE, RATE_LIMIT_CODE, DEFAULT_BAN_BACKOFF};
+pub use typing::TypingDuration;
+pub use autoreply::{AutoResponder, QuietHours};
+pub use template::{Template, TemplateError, render};
+pub use middleware::{EventMiddleware, Next};
+pub use intercept::{MessageKind, SendableMessage, SendDecision, SendInterceptor};
+pub use flood::FloodGuard;
+pub use stickerpack::parse_sticker_pack_message;
+pub use history::{build_history_request, parse_history_messages, parse_history_sync};
+pub use appstate::{AppStateMutation, AppStatePatch, build_app_state_key_request, decode_sync_action, parse_app_state_key_share};
+pub use business::{BusinessProfileRequest, CatalogRequest, build_product_message, parse_product_message};
+pub use interactive::{Buttons, List, build_buttons_message, build_list_message, parse_interactive_response};
+pub use newsletter::{
+    build_newsletter_reaction, build_newsletter_view_receipt, is_newsletter_notification, parse_newsletter_updates,
+};
+pub use contacts::{ContactUpdate, parse_history_pushnames, parse_contact_mutation, parse_usync_contacts};
+pub use call::{parse_call, build_reject_call};
+pub use senderkey::{SenderKey, SenderKeyManager, GroupSendPlan};
+pub use qr::{
+    QRPairing, QRError, SignedDeviceIdentity, build_pair_device_sign, parse_pair_device, parse_pair_success,
+    sign_device_identity,
+};
+pub use msgsecret::{
+    MsgSecretError, SecretUseCase, MESSAGE_SECRET_LEN, build_poll_message, decrypt_with_secret, encrypt_with_secret,
+    generate_message_secret, poll_option_hash,
+};
+pub use keep::{build_keep_message, build_message_key, parse_keep_message};
+pub use status::{
+    StatusPrivacy, StatusPrivacySettings, StatusPrivacyType, GetStatusPrivacyRequest, SetStatusPrivacyRequest,
+};
+pub use labels::{
+    LabelStore, build_label_chat_patch, build_label_edit_patch, build_label_message_patch, parse_label_mutation,
+};
+pub use biztools::{BusinessTools, BusinessToolsMutation, parse_business_tools_mutation};
+pub use ephemeral::{apply_expiration, apply_ephemeral_timer, parse_ephemeral_setting};
+pub use replies::{build_reply_context, build_reply_message, parse_reply_to};
+pub use replay::{CapturedFrame, FrameDirection, FrameLog, FrameRecorder, ReplayError, DEFAULT_MAX_FRAMES};
 pub use message::*;
-pub use request::{RequestTracker, build_iq_get, build_iq_set, build_iq_result};
+pub use request::{
+    RequestTracker, IqRequest, IqResponse, IqType, IqError,
+    build_iq_get, build_iq_set, build_iq_result, parse_iq_response,
+};
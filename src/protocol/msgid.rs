@@ -0,0 +1,190 @@
+//! Outgoing message IDs.
+//!
+//! Generation and validation of message IDs, the tags of other outgoing
+//! nodes, and the record of recently sent IDs that makes sends with a
+//! caller-supplied ID idempotent.
+
+use std::collections::{HashSet, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, RwLock};
+
+use crate::protocol::clock::{RandomSource, SystemRandom};
+use crate::types::MessageID;
+
+/// Longest message ID accepted by `validate_message_id`.
+pub const MAX_MESSAGE_ID_LEN: usize = 64;
+
+/// Number of sent IDs remembered for deduplication.
+const RECENT_CAPACITY: usize = 4096;
+
+/// Generate a random message ID.
+pub fn generate_message_id() -> MessageID {
+    generate_message_id_with(&SystemRandom)
+}
+
+/// Generate a message ID from the given randomness.
+pub fn generate_message_id_with(rng: &dyn RandomSource) -> MessageID {
+    format!("3EB0{:016X}", rng.next_u64())
+}
+
+/// Check that a caller-supplied message ID is usable.
+///
+/// IDs must be 1 to `MAX_MESSAGE_ID_LEN` ASCII letters and digits.
+pub fn validate_message_id(id: &str) -> Result<(), InvalidMessageId> {
+    if id.is_empty() || id.len() > MAX_MESSAGE_ID_LEN || !id.bytes().all(|b| b.is_ascii_alphanumeric()) {
+        return Err(InvalidMessageId(id.to_string()));
+    }
+    Ok(())
+}
+
+/// Message ID rejected by `validate_message_id`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InvalidMessageId(pub String);
+
+impl std::fmt::Display for InvalidMessageId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid message id {:?}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidMessageId {}
+
+/// Generator of the tags of outgoing IQs, receipts and calls.
+///
+/// A tag is a random prefix followed by a counter, such as `51.204-12`.
+/// The prefix is picked again on each connection, so tags never repeat
+/// within one and all nodes of a connection share it in logs.
+pub struct TagGenerator {
+    prefix: RwLock<String>,
+    counter: AtomicU64,
+}
+
+impl TagGenerator {
+    pub fn new() -> Self {
+        Self { prefix: RwLock::new(Self::random_prefix(&SystemRandom)), counter: AtomicU64::new(0) }
+    }
+
+    fn random_prefix(rng: &dyn RandomSource) -> String {
+        let [a, b, ..] = rng.next_u64().to_le_bytes();
+        format!("{}.{}-", a, b)
+    }
+
+    /// Get the next tag.
+    pub fn next(&self) -> String {
+        let n = self.counter.fetch_add(1, Ordering::Relaxed) + 1;
+        format!("{}{}", self.prefix.read().unwrap(), n)
+    }
+
+    /// Start the tags of a new connection.
+    pub fn reset(&self) {
+        self.reset_with(&SystemRandom);
+    }
+
+    /// Start the tags of a new connection, picking the prefix from the
+    /// given randomness.
+    pub fn reset_with(&self, rng: &dyn RandomSource) {
+        *self.prefix.write().unwrap() = Self::random_prefix(rng);
+        self.counter.store(0, Ordering::Relaxed);
+    }
+}
+
+impl Default for TagGenerator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Bounded record of message IDs that were sent or are being sent.
+#[derive(Default)]
+pub(crate) struct SentIds {
+    /// Claimed IDs and their claim order, oldest first
+    state: Mutex<(HashSet<MessageID>, VecDeque<MessageID>)>,
+}
+
+impl SentIds {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Claim an ID for sending.
+    ///
+    /// Returns false if the ID was already claimed.
+    pub(crate) fn claim(&self, id: &str) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let (ids, order) = &mut *state;
+        if !ids.insert(id.to_string()) {
+            return false;
+        }
+
+        order.push_back(id.to_string());
+        if order.len() > RECENT_CAPACITY {
+            if let Some(oldest) = order.pop_front() {
+                ids.remove(&oldest);
+            }
+        }
+        true
+    }
+
+    /// Release the claim on an ID whose send failed, so it can be retried.
+    pub(crate) fn release(&self, id: &str) {
+        let mut state = self.state.lock().unwrap();
+        let (ids, order) = &mut *state;
+        if ids.remove(id) {
+            order.retain(|claimed| claimed != id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::clock::SeededRandom;
+
+    #[test]
+    fn test_validate_message_id() {
+        assert!(validate_message_id(&generate_message_id()).is_ok());
+        assert!(validate_message_id("ORDER42").is_ok());
+        assert!(validate_message_id("").is_err());
+        assert!(validate_message_id("has space").is_err());
+        assert!(validate_message_id(&"A".repeat(MAX_MESSAGE_ID_LEN + 1)).is_err());
+    }
+
+    #[test]
+    fn test_tags_count_per_connection() {
+        let tags = TagGenerator::new();
+        let first = tags.next();
+        let (prefix, count) = first.rsplit_once('-').unwrap();
+        assert_eq!(count, "1");
+        assert_eq!(tags.next(), format!("{}-2", prefix));
+
+        tags.reset();
+        assert!(tags.next().ends_with("-1"));
+
+        // The same randomness gives the same tags
+        tags.reset_with(&SeededRandom::new(1));
+        let seeded = tags.next();
+        tags.reset_with(&SeededRandom::new(1));
+        assert_eq!(tags.next(), seeded);
+        assert_eq!(generate_message_id_with(&SeededRandom::new(1)), generate_message_id_with(&SeededRandom::new(1)));
+    }
+
+    #[test]
+    fn test_sent_ids_claim_and_release() {
+        let sent = SentIds::new();
+        assert!(sent.claim("A"));
+        assert!(!sent.claim("A"));
+
+        sent.release("A");
+        assert!(sent.claim("A"));
+    }
+
+    #[test]
+    fn test_sent_ids_forget_oldest() {
+        let sent = SentIds::new();
+        for i in 0..=RECENT_CAPACITY {
+            assert!(sent.claim(&i.to_string()));
+        }
+        assert!(sent.claim("0"));
+        assert!(!sent.claim(&RECENT_CAPACITY.to_string()));
+    }
+}
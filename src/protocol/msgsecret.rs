@@ -0,0 +1,361 @@
+//! Message secrets of polls and events.
+//!
+//! A poll or event carries a random 32-byte secret in its
+//! `MessageContextInfo`. Votes and responses to it are encrypted under a
+//! key derived from that secret, the original message's ID and sender,
+//! and the voter, so only those who received the original can read them.
+//! The secrets of messages we send and receive are kept in the store's
+//! `MsgSecretStore`.
+
+use aes_gcm::{Aes256Gcm, Nonce, aead::{Aead, KeyInit, Payload}};
+use prost::Message as _;
+use sha2::{Digest, Sha256};
+
+use crate::binary::Node;
+use crate::crypto::Hkdf;
+use crate::protocol::client::{Client, ClientError};
+use crate::protocol::intercept::{MessageKind, SendableMessage};
+use crate::protocol::message::build_e2e_message;
+use crate::proto::{
+    event_response_type, E2eMessage, EventResponseMessage, MessageContextInfo, MessageKey, PollCreationMessage,
+    PollOption, PollVoteMessage,
+};
+use crate::store::MsgSecretStore;
+use crate::types::{servers, EventResponseType, MessageContent, JID};
+
+/// Length of a message secret.
+pub const MESSAGE_SECRET_LEN: usize = 32;
+
+/// What a key derived from a message secret encrypts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SecretUseCase {
+    PollVote,
+    EventResponse,
+}
+
+impl SecretUseCase {
+    fn as_bytes(self) -> &'static [u8] {
+        match self {
+            SecretUseCase::PollVote => b"Poll Vote",
+            SecretUseCase::EventResponse => b"Event Response",
+        }
+    }
+}
+
+/// Message secret errors.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MsgSecretError {
+    /// The secret of the original message is not stored
+    MissingSecret,
+    /// The payload did not decrypt or decode
+    DecryptFailed,
+}
+
+impl std::fmt::Display for MsgSecretError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MsgSecretError::MissingSecret => write!(f, "secret of the original message is unknown"),
+            MsgSecretError::DecryptFailed => write!(f, "failed to decrypt with message secret"),
+        }
+    }
+}
+
+impl std::error::Error for MsgSecretError {}
+
+/// Generate a secret for an outgoing poll or event.
+pub fn generate_message_secret() -> Vec<u8> {
+    rand::random::<[u8; MESSAGE_SECRET_LEN]>().to_vec()
+}
+
+/// Cipher and additional data for a modification of a message.
+fn secret_cipher(
+    use_case: SecretUseCase,
+    secret: &[u8],
+    original_id: &str,
+    original_sender: &JID,
+    modification_sender: &JID,
+) -> (Aes256Gcm, Vec<u8>) {
+    let original_sender = original_sender.to_non_ad().to_string();
+    let modification_sender = modification_sender.to_non_ad().to_string();
+    let info = [original_id.as_bytes(), original_sender.as_bytes(), modification_sender.as_bytes(), use_case.as_bytes()]
+        .concat();
+    let key = Hkdf::derive(None, secret, &info, 32);
+    let cipher = Aes256Gcm::new_from_slice(&key).expect("key is 32 bytes");
+    let aad = format!("{}\0{}", original_id, modification_sender).into_bytes();
+    (cipher, aad)
+}
+
+/// Encrypt a vote or response to a message, returning the payload and IV.
+pub fn encrypt_with_secret(
+    use_case: SecretUseCase,
+    secret: &[u8],
+    original_id: &str,
+    original_sender: &JID,
+    modification_sender: &JID,
+    plaintext: &[u8],
+) -> (Vec<u8>, [u8; 12]) {
+    let (cipher, aad) = secret_cipher(use_case, secret, original_id, original_sender, modification_sender);
+    let iv: [u8; 12] = rand::random();
+    let payload = cipher.encrypt(Nonce::from_slice(&iv), Payload { msg: plaintext, aad: &aad })
+        .expect("encryption with a valid key does not fail");
+    (payload, iv)
+}
+
+/// Decrypt a vote or response to a message.
+pub fn decrypt_with_secret(
+    use_case: SecretUseCase,
+    secret: &[u8],
+    original_id: &str,
+    original_sender: &JID,
+    modification_sender: &JID,
+    payload: &[u8],
+    iv: &[u8],
+) -> Result<Vec<u8>, MsgSecretError> {
+    if iv.len() != 12 {
+        return Err(MsgSecretError::DecryptFailed);
+    }
+    let (cipher, aad) = secret_cipher(use_case, secret, original_id, original_sender, modification_sender);
+    cipher.decrypt(Nonce::from_slice(iv), Payload { msg: payload, aad: &aad })
+        .map_err(|_| MsgSecretError::DecryptFailed)
+}
+
+/// Hash of a poll option name, as votes name the options they pick.
+pub fn poll_option_hash(option: &str) -> [u8; 32] {
+    Sha256::digest(option.as_bytes()).into()
+}
+
+/// Build a poll with a fresh secret, returning the message and the secret
+/// to store under its ID.
+pub fn build_poll_message(name: &str, options: &[String], selectable_count: u32) -> (E2eMessage, Vec<u8>) {
+    let secret = generate_message_secret();
+    let message = E2eMessage {
+        poll_creation_message: Some(PollCreationMessage {
+            name: Some(name.to_string()),
+            options: options.iter().map(|option| PollOption { option_name: Some(option.clone()) }).collect(),
+            selectable_options_count: Some(selectable_count),
+            ..Default::default()
+        }),
+        message_context_info: Some(MessageContextInfo { message_secret: Some(secret.clone()) }),
+        ..Default::default()
+    };
+    (message, secret)
+}
+
+/// Sender of the message a key points to, given the chat and sender of
+/// the message carrying the key.
+fn key_sender(key: &MessageKey, chat: &JID, sender: &JID) -> Option<JID> {
+    // The key was written by the sender, so it is theirs when from them
+    if key.from_me == Some(true) {
+        return Some(sender.clone());
+    }
+    match chat.server.as_str() {
+        servers::DEFAULT_USER | servers::HIDDEN_USER => key.remote_jid.as_deref()?.parse().ok(),
+        _ => key.participant.as_deref()?.parse().ok(),
+    }
+}
+
+/// Read the polls, votes and event responses of a message.
+///
+/// Secrets carried by the message are stored, so later votes and responses
+/// to it can be decrypted. Votes and responses whose original message we
+/// have no secret for are logged and dropped.
+pub(crate) fn parse_secret_content<S: MsgSecretStore + ?Sized>(
+    store: &S,
+    node: &Node,
+    chat: &JID,
+    sender: &JID,
+) -> Option<MessageContent> {
+    let payload = node.get_child_by_tag("enc")?.get_bytes()?;
+    let message = E2eMessage::decode(payload).ok()?;
+    // Secrets of a private chat are kept under the user, whichever device
+    let chat = &chat.to_non_ad();
+    let id = node.get_attr_str("id").unwrap_or_default();
+
+    let secret = message.message_context_info.as_ref().and_then(|info| info.message_secret.as_ref());
+    if let Some(secret) = secret {
+        if let Err(e) = store.put_message_secret(chat, sender, id, secret) {
+            log::warn!("failed to store secret of message {}: {}", id, e);
+        }
+    }
+
+    let decrypt = |use_case, key: &MessageKey, payload: Option<&Vec<u8>>, iv: Option<&Vec<u8>>| {
+        let original_id = key.id.as_deref().ok_or(MsgSecretError::MissingSecret)?;
+        let original_sender = key_sender(key, chat, sender).ok_or(MsgSecretError::MissingSecret)?;
+        let secret = store.get_message_secret(chat, &original_sender, original_id)
+            .ok()
+            .flatten()
+            .ok_or(MsgSecretError::MissingSecret)?;
+        let (payload, iv) = payload.zip(iv).ok_or(MsgSecretError::DecryptFailed)?;
+        decrypt_with_secret(use_case, &secret, original_id, &original_sender, sender, payload, iv)
+    };
+
+    if let Some(poll) = message.poll_creation_message {
+        return Some(MessageContent::Poll {
+            name: poll.name.unwrap_or_default(),
+            options: poll.options.into_iter().filter_map(|option| option.option_name).collect(),
+            selectable_count: poll.selectable_options_count.unwrap_or(0),
+        });
+    }
+    if let Some(update) = message.poll_update_message {
+        let key = update.poll_creation_message_key?;
+        let vote = update.vote.unwrap_or_default();
+        let vote = decrypt(SecretUseCase::PollVote, &key, vote.enc_payload.as_ref(), vote.enc_iv.as_ref())
+            .and_then(|plain| PollVoteMessage::decode(&plain[..]).map_err(|_| MsgSecretError::DecryptFailed));
+        return match vote {
+            Ok(vote) => Some(MessageContent::PollVote {
+                poll_id: key.id.unwrap_or_default(),
+                selected_options: vote.selected_options.into_iter()
+                    .filter_map(|hash| hash.try_into().ok())
+                    .collect(),
+            }),
+            Err(e) => {
+                log::warn!("dropping vote {}: {}", id, e);
+                None
+            }
+        };
+    }
+    if let Some(enc) = message.enc_event_response_message {
+        let key = enc.event_creation_message_key?;
+        let response = decrypt(SecretUseCase::EventResponse, &key, enc.enc_payload.as_ref(), enc.enc_iv.as_ref())
+            .and_then(|plain| EventResponseMessage::decode(&plain[..]).map_err(|_| MsgSecretError::DecryptFailed));
+        return match response {
+            Ok(response) => Some(MessageContent::EventResponse {
+                event_id: key.id.unwrap_or_default(),
+                response: match response.response {
+                    Some(event_response_type::GOING) => EventResponseType::Going,
+                    Some(event_response_type::NOT_GOING) => EventResponseType::NotGoing,
+                    Some(event_response_type::MAYBE) => EventResponseType::Maybe,
+                    _ => EventResponseType::Unknown,
+                },
+                extra_guests: response.extra_guest_count.unwrap_or(0).max(0) as u32,
+            }),
+            Err(e) => {
+                log::warn!("dropping event response {}: {}", id, e);
+                None
+            }
+        };
+    }
+    None
+}
+
+impl Client {
+    /// Send a poll, returning its ID.
+    ///
+    /// `selectable_count` is how many options a voter may pick, with 0
+    /// meaning any number. Votes arrive as messages with
+    /// `MessageContent::PollVote`, naming options by `poll_option_hash`.
+    pub async fn send_poll(
+        &self,
+        to: &JID,
+        name: &str,
+        options: &[String],
+        selectable_count: u32,
+    ) -> Result<String, ClientError> {
+        let own = self.get_jid().await.ok_or(ClientError::NotLoggedIn)?;
+        let handle = self.connection()?;
+        let id = handle.new_message_id();
+        let passed = handle.intercept(SendableMessage::new(&id, to.clone(), name).with_kind(MessageKind::Poll)).await?;
+        let (message, secret) = build_poll_message(&passed.text, options, selectable_count);
+        self.store().put_message_secret(&passed.to, &own, &id, &secret)
+            .map_err(|e| ClientError::StoreError(e.to_string()))?;
+        self.send_node(build_e2e_message(&passed.to, &id, "poll", None, &message)).await?;
+        Ok(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::{PollEncValue, PollUpdateMessage};
+    use crate::store::MemoryStore;
+
+    fn received(from: &JID, participant: Option<&JID>, id: &str, message: &E2eMessage) -> Node {
+        let mut node = build_e2e_message(from, id, "poll", None, message);
+        node.set_attr("from", from.clone());
+        if let Some(participant) = participant {
+            node.set_attr("participant", participant.clone());
+        }
+        node
+    }
+
+    #[test]
+    fn test_poll_vote_round_trip() {
+        let store = MemoryStore::new();
+        let group = JID::new("123", "g.us");
+        let creator = JID::new("1", "s.whatsapp.net");
+        let voter = JID::new_ad("2", 0, 3);
+
+        let options = vec!["tea".to_string(), "coffee".to_string()];
+        let (poll, secret) = build_poll_message("Drink?", &options, 1);
+        let content = parse_secret_content(&store, &received(&group, Some(&creator), "POLL1", &poll), &group, &creator);
+        assert!(matches!(
+            content,
+            Some(MessageContent::Poll { name, options: o, selectable_count: 1 }) if name == "Drink?" && o == options
+        ));
+        assert_eq!(store.get_message_secret(&group, &creator, "POLL1").unwrap(), Some(secret.clone()));
+
+        let plain = PollVoteMessage { selected_options: vec![poll_option_hash("coffee").to_vec()] }.encode_to_vec();
+        let (payload, iv) = encrypt_with_secret(SecretUseCase::PollVote, &secret, "POLL1", &creator, &voter, &plain);
+        let vote = E2eMessage {
+            poll_update_message: Some(PollUpdateMessage {
+                poll_creation_message_key: Some(MessageKey {
+                    remote_jid: Some(group.to_string()),
+                    from_me: Some(false),
+                    id: Some("POLL1".to_string()),
+                    participant: Some(creator.to_string()),
+                }),
+                vote: Some(PollEncValue { enc_payload: Some(payload), enc_iv: Some(iv.to_vec()) }),
+                sender_timestamp_ms: None,
+            }),
+            ..Default::default()
+        };
+        let node = received(&group, Some(&voter), "VOTE1", &vote);
+        assert!(matches!(
+            parse_secret_content(&store, &node, &group, &voter),
+            Some(MessageContent::PollVote { poll_id, selected_options })
+                if poll_id == "POLL1" && selected_options == [poll_option_hash("coffee")]
+        ));
+
+        // Another voter's key does not open this vote
+        let other = JID::new("4", "s.whatsapp.net");
+        assert!(parse_secret_content(&store, &node, &group, &other).is_none());
+    }
+
+    #[test]
+    fn test_private_poll_vote_round_trip() {
+        let store = MemoryStore::new();
+        let own = JID::new_ad("1", 0, 4);
+        let voter = JID::new_ad("2", 0, 3);
+
+        // Our poll, stored as `Client::send_poll` does
+        let (_, secret) = build_poll_message("Lunch?", &["yes".to_string()], 1);
+        store.put_message_secret(&voter.to_non_ad(), &own, "POLL2", &secret).unwrap();
+
+        let plain = PollVoteMessage { selected_options: vec![poll_option_hash("yes").to_vec()] }.encode_to_vec();
+        let (payload, iv) = encrypt_with_secret(SecretUseCase::PollVote, &secret, "POLL2", &own, &voter, &plain);
+        let vote = |from_me: bool, payload: Vec<u8>, iv: [u8; 12]| E2eMessage {
+            poll_update_message: Some(PollUpdateMessage {
+                poll_creation_message_key: Some(MessageKey {
+                    remote_jid: Some(own.to_non_ad().to_string()),
+                    from_me: Some(from_me),
+                    id: Some("POLL2".to_string()),
+                    participant: None,
+                }),
+                vote: Some(PollEncValue { enc_payload: Some(payload), enc_iv: Some(iv.to_vec()) }),
+                sender_timestamp_ms: None,
+            }),
+            ..Default::default()
+        };
+        let node = received(&voter, None, "VOTE2", &vote(false, payload, iv));
+        assert!(matches!(
+            parse_secret_content(&store, &node, &voter, &voter),
+            Some(MessageContent::PollVote { poll_id, .. }) if poll_id == "POLL2"
+        ));
+
+        // A vote on a poll of the voter's own is keyed by the voter
+        store.put_message_secret(&voter.to_non_ad(), &voter, "POLL2", &secret).unwrap();
+        let (payload, iv) = encrypt_with_secret(SecretUseCase::PollVote, &secret, "POLL2", &voter, &voter, &plain);
+        let node = received(&voter, None, "VOTE3", &vote(true, payload, iv));
+        assert!(matches!(parse_secret_content(&store, &node, &voter, &voter), Some(MessageContent::PollVote { .. })));
+    }
+}
@@ -0,0 +1,233 @@
+//! Newsletter (channel) reactions and views.
+//!
+//! Newsletter messages are addressed by their server ID rather than the
+//! message ID. Reaction and view counts are pushed as live update
+//! notifications to clients that subscribed to them.
+
+use std::time::Duration;
+
+use crate::binary::Node;
+use crate::protocol::client::{Client, ClientError};
+use crate::protocol::request::{IqError, IqRequest, IqResponse, IqType};
+use crate::types::{Event, MessageServerID, NewsletterReaction, NewsletterViews, JID};
+
+/// Build a reaction to a newsletter message. An empty `reaction` removes
+/// ours.
+pub fn build_newsletter_reaction(
+    newsletter: &JID,
+    server_id: MessageServerID,
+    message_id: &str,
+    reaction: &str,
+) -> Node {
+    let mut node = Node::new("message");
+    node.set_attr("to", newsletter.to_string());
+    node.set_attr("id", message_id);
+    node.set_attr("server_id", server_id.to_string());
+    node.set_attr("type", "reaction");
+
+    let mut reaction_node = Node::new("reaction");
+    if reaction.is_empty() {
+        // Sender revoke
+        node.set_attr("edit", "7");
+    } else {
+        reaction_node.set_attr("code", reaction);
+    }
+    node.add_child(reaction_node);
+    node
+}
+
+/// Build the receipt marking newsletter messages as viewed.
+pub fn build_newsletter_view_receipt(newsletter: &JID, id: &str, server_ids: &[MessageServerID]) -> Node {
+    let mut list = Node::new("list");
+    for server_id in server_ids {
+        let mut item = Node::new("item");
+        item.set_attr("server_id", server_id.to_string());
+        list.add_child(item);
+    }
+
+    let mut node = Node::new("receipt");
+    node.set_attr("to", newsletter.to_string());
+    node.set_attr("type", "view");
+    node.set_attr("id", id);
+    node.add_child(list);
+    node
+}
+
+/// Check if a node is a newsletter live update notification.
+pub fn is_newsletter_notification(node: &Node) -> bool {
+    node.tag == "notification" && node.get_attr_str("type") == Some("newsletter")
+}
+
+/// Parse the reaction and view counts of a newsletter live update.
+pub fn parse_newsletter_updates(node: &Node) -> Vec<Event> {
+    let Some(newsletter) = node.parse_attr_jid("from") else {
+        return Vec::new();
+    };
+    let messages = node.get_child_by_tag("live_updates")
+        .and_then(|updates| updates.get_child_by_tag("messages"))
+        .map(|messages| messages.get_children_by_tag("message"))
+        .unwrap_or_default();
+
+    let count = |node: &Node| node.get_attr_str("count").and_then(|count| count.parse().ok());
+    let mut events = Vec::new();
+    for message in messages {
+        let Some(server_id) = message.get_attr_str("server_id").and_then(|id| id.parse().ok()) else {
+            continue;
+        };
+        if let Some(reactions) = message.get_child_by_tag("reactions") {
+            let reactions = reactions.get_children_by_tag("reaction").into_iter()
+                .filter_map(|reaction| Some((reaction.get_attr_str("code")?.to_string(), count(reaction)?)))
+                .collect();
+            events.push(Event::NewsletterReaction(NewsletterReaction {
+                newsletter: newsletter.clone(),
+                server_id,
+                reactions,
+            }));
+        }
+        if let Some(count) = message.get_child_by_tag("views_count").and_then(count) {
+            events.push(Event::NewsletterViews(NewsletterViews {
+                newsletter: newsletter.clone(),
+                server_id,
+                count,
+            }));
+        }
+    }
+    events
+}
+
+/// Subscription to a newsletter's live updates.
+struct LiveUpdatesRequest {
+    newsletter: JID,
+}
+
+/// How long a live update subscription lasts.
+struct LiveUpdatesDuration(Duration);
+
+impl IqRequest for LiveUpdatesRequest {
+    type Response = LiveUpdatesDuration;
+
+    fn namespace(&self) -> &str {
+        "newsletter"
+    }
+
+    fn iq_type(&self) -> IqType {
+        IqType::Set
+    }
+
+    fn target(&self) -> JID {
+        self.newsletter.clone()
+    }
+
+    fn content(&self) -> Vec<Node> {
+        vec![Node::new("live_updates")]
+    }
+}
+
+impl IqResponse for LiveUpdatesDuration {
+    fn from_node(node: &Node) -> Result<Self, IqError> {
+        node.get_child_by_tag("live_updates")
+            .and_then(|updates| updates.get_attr_str("duration"))
+            .and_then(|duration| duration.parse().ok())
+            .map(|secs| LiveUpdatesDuration(Duration::from_secs(secs)))
+            .ok_or_else(|| IqError::MalformedResponse("missing live_updates duration".to_string()))
+    }
+}
+
+impl Client {
+    /// React to a newsletter message, or remove our reaction with an empty
+    /// `reaction`.
+    pub async fn send_newsletter_reaction(
+        &self,
+        newsletter: &JID,
+        server_id: MessageServerID,
+        reaction: &str,
+    ) -> Result<(), ClientError> {
+        let id = self.connection()?.new_message_id();
+        self.send_node(build_newsletter_reaction(newsletter, server_id, &id, reaction)).await
+    }
+
+    /// Mark newsletter messages as viewed.
+    pub async fn mark_newsletter_viewed(
+        &self,
+        newsletter: &JID,
+        server_ids: &[MessageServerID],
+    ) -> Result<(), ClientError> {
+        let connection = self.connection()?;
+        connection.send_node(build_newsletter_view_receipt(newsletter, &connection.next_tag(), server_ids)).await
+    }
+
+    /// Receive reaction and view count updates for a newsletter as
+    /// `NewsletterReaction` and `NewsletterViews` events.
+    ///
+    /// Returns how long the subscription lasts; subscribe again before it
+    /// runs out to keep receiving updates.
+    pub async fn subscribe_newsletter_live_updates(&self, newsletter: &JID) -> Result<Duration, ClientError> {
+        let duration = self.query(&LiveUpdatesRequest { newsletter: newsletter.clone() }).await?;
+        Ok(duration.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn newsletter() -> JID {
+        "120363012345678901@newsletter".parse().unwrap()
+    }
+
+    #[test]
+    fn test_build_reaction() {
+        let node = build_newsletter_reaction(&newsletter(), 142, "3EB0AA", "👍");
+        assert_eq!(node.get_attr_str("server_id"), Some("142"));
+        assert_eq!(node.get_child_by_tag("reaction").unwrap().get_attr_str("code"), Some("👍"));
+        assert_eq!(node.get_attr_str("edit"), None);
+
+        let node = build_newsletter_reaction(&newsletter(), 142, "3EB0AB", "");
+        assert_eq!(node.get_attr_str("edit"), Some("7"));
+        assert_eq!(node.get_child_by_tag("reaction").unwrap().get_attr_str("code"), None);
+
+        let receipt = build_newsletter_view_receipt(&newsletter(), "1.2-3", &[1, 2]);
+        assert_eq!(receipt.get_attr_str("type"), Some("view"));
+        assert_eq!(receipt.get_attr_str("id"), Some("1.2-3"));
+        assert_eq!(receipt.get_child_by_tag("list").unwrap().get_children_by_tag("item").len(), 2);
+    }
+
+    #[test]
+    fn test_parse_live_updates() {
+        let mut reaction = Node::new("reaction");
+        reaction.set_attr("code", "❤️");
+        reaction.set_attr("count", "12");
+        let mut reactions = Node::new("reactions");
+        reactions.add_child(reaction);
+        let mut views = Node::new("views_count");
+        views.set_attr("count", "3400");
+
+        let mut first = Node::new("message");
+        first.set_attr("server_id", "142");
+        first.add_child(reactions);
+        first.add_child(views);
+        let mut second = Node::new("message");
+        second.set_attr("server_id", "143");
+        let mut views = Node::new("views_count");
+        views.set_attr("count", "10");
+        second.add_child(views);
+
+        let mut messages = Node::new("messages");
+        messages.add_child(first);
+        messages.add_child(second);
+        let mut live_updates = Node::new("live_updates");
+        live_updates.add_child(messages);
+        let mut node = Node::new("notification");
+        node.set_attr("type", "newsletter");
+        node.set_attr("from", newsletter());
+        node.add_child(live_updates);
+
+        assert!(is_newsletter_notification(&node));
+        let events = parse_newsletter_updates(&node);
+        assert_eq!(events.len(), 3);
+        let Event::NewsletterReaction(reaction) = &events[0] else { panic!("expected reaction") };
+        assert_eq!(reaction.reactions, [("❤️".to_string(), 12)]);
+        let Event::NewsletterViews(views) = &events[2] else { panic!("expected views") };
+        assert_eq!((views.server_id, views.count), (143, 10));
+    }
+}
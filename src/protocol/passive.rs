@@ -0,0 +1,54 @@
+//! Passive mode.
+//!
+//! A passive client stays connected without the server delivering queued
+//! offline messages to it, which suits tools that only pair, query or check
+//! status. Switching back to active starts the delivery.
+
+use crate::binary::Node;
+use crate::protocol::client::{Client, ClientError};
+use crate::protocol::request::{IqRequest, IqType};
+
+/// Switch between passive and active mode.
+pub struct SetPassiveRequest {
+    pub passive: bool,
+}
+
+impl IqRequest for SetPassiveRequest {
+    type Response = ();
+
+    fn namespace(&self) -> &str {
+        "passive"
+    }
+
+    fn iq_type(&self) -> IqType {
+        IqType::Set
+    }
+
+    fn content(&self) -> Vec<Node> {
+        vec![Node::new(if self.passive { "passive" } else { "active" })]
+    }
+}
+
+impl Client {
+    /// Pause (`true`) or resume (`false`) delivery of offline messages to
+    /// this connection.
+    pub async fn set_passive(&self, passive: bool) -> Result<(), ClientError> {
+        self.query(&SetPassiveRequest { passive }).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set_passive_request() {
+        let node = SetPassiveRequest { passive: true }.to_node("1");
+        assert_eq!(node.get_attr_str("xmlns"), Some("passive"));
+        assert_eq!(node.get_attr_str("type"), Some("set"));
+        assert!(node.get_child_by_tag("passive").is_some());
+
+        let node = SetPassiveRequest { passive: false }.to_node("2");
+        assert!(node.get_child_by_tag("active").is_some());
+    }
+}
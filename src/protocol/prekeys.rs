@@ -0,0 +1,525 @@
+//! Pre-key bundles and new Signal sessions.
+//!
+//! The first message to a device needs a Signal session with it. The
+//! device's pre-key bundle (registration ID, identity key, signed pre-key
+//! and, while the server has any left, a one-time pre-key) is fetched with
+//! an `encrypt` get query, and X3DH turns it into a session. Sessions are
+//! kept in the session store under the device's address, so bundles are
+//! only fetched for devices we have no session with yet.
+//!
+//! Messages to a device are encrypted with `SessionState::encrypt`. Until
+//! the device answers, they are pre-key messages carrying what it needs to
+//! build the session on its side.
+
+use aes::cipher::{block_padding::Pkcs7, BlockEncryptMut, KeyIvInit};
+use hmac::{Hmac, Mac};
+use prost::Message as _;
+use sha2::Sha256;
+
+use crate::binary::Node;
+use crate::crypto::{verify_signature, Hkdf, KeyPair};
+use crate::proto::{PreKeySignalMessage, SignalMessage};
+use crate::protocol::client::{Client, ClientError};
+use crate::protocol::request::{IqError, IqRequest, IqResponse, IqType};
+use crate::store::{StoreError, StoreResult};
+use crate::types::JID;
+
+/// Pre-keys published by one device.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PreKeyBundle {
+    pub device: JID,
+    pub registration_id: u32,
+    pub identity_key: [u8; 32],
+    pub signed_pre_key_id: u32,
+    pub signed_pre_key: [u8; 32],
+    pub signed_pre_key_signature: [u8; 64],
+    /// One-time pre-key, absent once the device has run out
+    pub pre_key: Option<(u32, [u8; 32])>,
+}
+
+impl PreKeyBundle {
+    /// Whether the signed pre-key was signed by the identity key.
+    pub fn verify(&self) -> bool {
+        let mut message = [0u8; 33];
+        message[0] = 0x05;
+        message[1..].copy_from_slice(&self.signed_pre_key);
+        verify_signature(&self.identity_key, &message, &self.signed_pre_key_signature)
+    }
+}
+
+/// Query for the pre-key bundles of devices.
+pub struct PreKeyBundleRequest {
+    pub devices: Vec<JID>,
+    /// Why the bundles are fetched again, such as `identity` after a
+    /// retry receipt
+    pub reason: Option<String>,
+}
+
+impl IqRequest for PreKeyBundleRequest {
+    type Response = Vec<(JID, Result<PreKeyBundle, IqError>)>;
+
+    fn namespace(&self) -> &str {
+        "encrypt"
+    }
+
+    fn iq_type(&self) -> IqType {
+        IqType::Get
+    }
+
+    fn content(&self) -> Vec<Node> {
+        let mut key = Node::new("key");
+        for device in &self.devices {
+            let mut user = Node::new("user");
+            user.set_attr("jid", device.clone());
+            if let Some(reason) = &self.reason {
+                user.set_attr("reason", reason.clone());
+            }
+            key.add_child(user);
+        }
+        vec![key]
+    }
+}
+
+/// Bundles of the requested devices, or why a device has none.
+impl IqResponse for Vec<(JID, Result<PreKeyBundle, IqError>)> {
+    fn from_node(node: &Node) -> Result<Self, IqError> {
+        let list = node.get_child_by_tag("list")
+            .ok_or_else(|| IqError::MalformedResponse("missing <list>".to_string()))?;
+
+        list.get_children_by_tag("user").into_iter()
+            .map(|user| {
+                let device = user.get_attr_jid("jid").cloned()
+                    .ok_or_else(|| IqError::MalformedResponse("user without jid".to_string()))?;
+                let bundle = if user.get_child_by_tag("error").is_some() {
+                    Err(IqError::from_error_node(user))
+                } else {
+                    parse_bundle(device.clone(), user)
+                };
+                Ok((device, bundle))
+            })
+            .collect()
+    }
+}
+
+fn child_bytes<'a>(node: &'a Node, tag: &str) -> Result<&'a [u8], IqError> {
+    node.get_child_by_tag(tag)
+        .and_then(Node::get_bytes)
+        .ok_or_else(|| IqError::MalformedResponse(format!("missing <{}>", tag)))
+}
+
+fn fixed<const N: usize>(node: &Node, tag: &str) -> Result<[u8; N], IqError> {
+    child_bytes(node, tag)?.try_into()
+        .map_err(|_| IqError::MalformedResponse(format!("<{}> is not {} bytes", tag, N)))
+}
+
+/// Read a `<key>` or `<skey>` node: a 3-byte ID and a public key.
+fn parse_key(node: &Node) -> Result<(u32, [u8; 32]), IqError> {
+    let id: [u8; 3] = fixed(node, "id")?;
+    Ok((u32::from_be_bytes([0, id[0], id[1], id[2]]), fixed(node, "value")?))
+}
+
+fn parse_bundle(device: JID, user: &Node) -> Result<PreKeyBundle, IqError> {
+    let signed = user.get_child_by_tag("skey")
+        .ok_or_else(|| IqError::MalformedResponse("missing <skey>".to_string()))?;
+    let (signed_pre_key_id, signed_pre_key) = parse_key(signed)?;
+    Ok(PreKeyBundle {
+        device,
+        registration_id: u32::from_be_bytes(fixed(user, "registration")?),
+        identity_key: fixed(user, "identity")?,
+        signed_pre_key_id,
+        signed_pre_key,
+        signed_pre_key_signature: fixed(signed, "signature")?,
+        pre_key: user.get_child_by_tag("key").map(parse_key).transpose()?,
+    })
+}
+
+/// Pre-keys a new session was built from. Messages carry them until the
+/// other device answers, so it can build the same session.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PendingPreKey {
+    pub pre_key_id: Option<u32>,
+    pub signed_pre_key_id: u32,
+    /// Our ephemeral X3DH key
+    pub base_key: [u8; 32],
+}
+
+/// Double ratchet state of a session with one device.
+#[derive(Debug, Clone)]
+pub struct SessionState {
+    pub remote_registration_id: u32,
+    pub remote_identity: [u8; 32],
+    pub local_identity: [u8; 32],
+    pub root_key: [u8; 32],
+    pub sending_ratchet: KeyPair,
+    pub sending_chain_key: [u8; 32],
+    pub sending_counter: u32,
+    pub receiving_ratchet: [u8; 32],
+    pub receiving_chain_key: [u8; 32],
+    pub pending_pre_key: Option<PendingPreKey>,
+}
+
+impl SessionState {
+    /// Length of a serialized session without a pending pre-key.
+    const BASE_LEN: usize = 4 + 32 * 4 + 32 + 4 + 32 * 2 + 1;
+
+    /// Serialize for the session store.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(Self::BASE_LEN + 4 + 32 + 5);
+        bytes.extend_from_slice(&self.remote_registration_id.to_be_bytes());
+        bytes.extend_from_slice(&self.remote_identity);
+        bytes.extend_from_slice(&self.local_identity);
+        bytes.extend_from_slice(&self.root_key);
+        bytes.extend_from_slice(self.sending_ratchet.private_key());
+        bytes.extend_from_slice(&self.sending_chain_key);
+        bytes.extend_from_slice(&self.sending_counter.to_be_bytes());
+        bytes.extend_from_slice(&self.receiving_ratchet);
+        bytes.extend_from_slice(&self.receiving_chain_key);
+        match &self.pending_pre_key {
+            None => bytes.push(0),
+            Some(pending) => {
+                bytes.push(1);
+                bytes.extend_from_slice(&pending.signed_pre_key_id.to_be_bytes());
+                bytes.extend_from_slice(&pending.base_key);
+                if let Some(id) = pending.pre_key_id {
+                    bytes.extend_from_slice(&id.to_be_bytes());
+                }
+            }
+        }
+        bytes
+    }
+
+    /// Deserialize from the session store.
+    pub fn from_bytes(bytes: &[u8]) -> StoreResult<Self> {
+        let invalid = || StoreError::SerializationError("invalid session length".to_string());
+        if bytes.len() < Self::BASE_LEN {
+            return Err(invalid());
+        }
+        let u32_at = |at: usize| u32::from_be_bytes(bytes[at..at + 4].try_into().unwrap());
+        let key_at = |at: usize| -> [u8; 32] { bytes[at..at + 32].try_into().unwrap() };
+
+        let pending_pre_key = match (bytes[Self::BASE_LEN - 1], bytes.len() - Self::BASE_LEN) {
+            (0, 0) => None,
+            (1, rest @ (36 | 40)) => Some(PendingPreKey {
+                signed_pre_key_id: u32_at(Self::BASE_LEN),
+                base_key: key_at(Self::BASE_LEN + 4),
+                pre_key_id: (rest == 40).then(|| u32_at(Self::BASE_LEN + 36)),
+            }),
+            _ => return Err(invalid()),
+        };
+
+        Ok(Self {
+            remote_registration_id: u32_at(0),
+            remote_identity: key_at(4),
+            local_identity: key_at(36),
+            root_key: key_at(68),
+            sending_ratchet: KeyPair::from_private_key(key_at(100)),
+            sending_chain_key: key_at(132),
+            sending_counter: u32_at(164),
+            receiving_ratchet: key_at(168),
+            receiving_chain_key: key_at(200),
+            pending_pre_key,
+        })
+    }
+}
+
+type Aes256CbcEnc = cbc::Encryptor<aes::Aes256>;
+type HmacSha256 = Hmac<Sha256>;
+
+/// Version byte of Signal messages, current and minimum version 3.
+const SIGNAL_VERSION: u8 = 0x33;
+
+/// Length of the truncated MAC ending a Signal message.
+const SIGNAL_MAC_LEN: usize = 8;
+
+/// Public key with the Curve25519 type prefix, as Signal messages carry it.
+fn signal_key(key: &[u8; 32]) -> Vec<u8> {
+    let mut prefixed = Vec::with_capacity(33);
+    prefixed.push(5);
+    prefixed.extend_from_slice(key);
+    prefixed
+}
+
+fn hmac_sha256(key: &[u8], parts: &[&[u8]]) -> [u8; 32] {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts any key length");
+    for part in parts {
+        mac.update(part);
+    }
+    mac.finalize().into_bytes().into()
+}
+
+/// A message encrypted for one device.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EncryptedMessage {
+    /// `pkmsg` while the session is pending, `msg` afterwards, as the
+    /// `type` of the `<enc>` node carrying it
+    pub kind: &'static str,
+    pub data: Vec<u8>,
+}
+
+impl SessionState {
+    /// Encrypt a message to the device, stepping the sending chain so the
+    /// session has to be stored again afterwards.
+    ///
+    /// `registration_id` is our own, which a pre-key message carries.
+    pub fn encrypt(&mut self, registration_id: u32, plaintext: &[u8]) -> EncryptedMessage {
+        let seed = hmac_sha256(&self.sending_chain_key, &[&[0x01]]);
+        self.sending_chain_key = hmac_sha256(&self.sending_chain_key, &[&[0x02]]);
+        let keys = Hkdf::derive(None, &seed, b"WhisperMessageKeys", 80);
+        let (cipher_key, mac_key, iv) = (&keys[..32], &keys[32..64], &keys[64..]);
+
+        let ciphertext = Aes256CbcEnc::new(cipher_key.into(), iv.into())
+            .encrypt_padded_vec_mut::<Pkcs7>(plaintext);
+        let mut message = vec![SIGNAL_VERSION];
+        SignalMessage {
+            ratchet_key: Some(signal_key(self.sending_ratchet.public_key())),
+            counter: Some(self.sending_counter),
+            previous_counter: Some(0),
+            ciphertext: Some(ciphertext),
+        }
+        .encode(&mut message)
+        .expect("Vec grows as needed");
+        let mac = hmac_sha256(mac_key, &[
+            &signal_key(&self.local_identity),
+            &signal_key(&self.remote_identity),
+            &message,
+        ]);
+        message.extend_from_slice(&mac[..SIGNAL_MAC_LEN]);
+        self.sending_counter += 1;
+
+        let Some(pending) = &self.pending_pre_key else {
+            return EncryptedMessage { kind: "msg", data: message };
+        };
+        let mut data = vec![SIGNAL_VERSION];
+        PreKeySignalMessage {
+            pre_key_id: pending.pre_key_id,
+            base_key: Some(signal_key(&pending.base_key)),
+            identity_key: Some(signal_key(&self.local_identity)),
+            message: Some(message),
+            registration_id: Some(registration_id),
+            signed_pre_key_id: Some(pending.signed_pre_key_id),
+        }
+        .encode(&mut data)
+        .expect("Vec grows as needed");
+        EncryptedMessage { kind: "pkmsg", data }
+    }
+}
+
+/// Start a session with a device from its bundle, as the X3DH initiator.
+///
+/// Fails if the signed pre-key was not signed by the device's identity.
+pub fn build_session(identity: &KeyPair, bundle: &PreKeyBundle) -> Result<SessionState, ClientError> {
+    if !bundle.verify() {
+        return Err(ClientError::InvalidPreKeyBundle(bundle.device.clone()));
+    }
+
+    let base_key = KeyPair::generate();
+    let mut secret = vec![0xFF; 32];
+    secret.extend_from_slice(&identity.dh(&bundle.signed_pre_key));
+    secret.extend_from_slice(&base_key.dh(&bundle.identity_key));
+    secret.extend_from_slice(&base_key.dh(&bundle.signed_pre_key));
+    if let Some((_, pre_key)) = &bundle.pre_key {
+        secret.extend_from_slice(&base_key.dh(pre_key));
+    }
+    let derived = Hkdf::derive(None, &secret, b"WhisperText", 64);
+    let root_key: [u8; 32] = derived[..32].try_into().unwrap();
+    let receiving_chain_key: [u8; 32] = derived[32..].try_into().unwrap();
+
+    // First step of the ratchet, towards the signed pre-key
+    let sending_ratchet = KeyPair::generate();
+    let ratchet = Hkdf::derive(Some(&root_key), &sending_ratchet.dh(&bundle.signed_pre_key), b"WhisperRatchet", 64);
+
+    Ok(SessionState {
+        remote_registration_id: bundle.registration_id,
+        remote_identity: bundle.identity_key,
+        local_identity: identity.public,
+        root_key: ratchet[..32].try_into().unwrap(),
+        sending_ratchet,
+        sending_chain_key: ratchet[32..].try_into().unwrap(),
+        sending_counter: 0,
+        receiving_ratchet: bundle.signed_pre_key,
+        receiving_chain_key,
+        pending_pre_key: Some(PendingPreKey {
+            pre_key_id: bundle.pre_key.map(|(id, _)| id),
+            signed_pre_key_id: bundle.signed_pre_key_id,
+            base_key: base_key.public,
+        }),
+    })
+}
+
+impl Client {
+    /// Make sure we have a session with each device, fetching the bundles
+    /// of those we have none with.
+    ///
+    /// See `ClientHandle::ensure_sessions`.
+    pub async fn ensure_sessions(&self, devices: &[JID]) -> Result<Vec<JID>, ClientError> {
+        self.connection()?.ensure_sessions(devices).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::PreKey;
+
+    fn bytes_node(tag: &'static str, bytes: &[u8]) -> Node {
+        let mut node = Node::new(tag);
+        node.set_bytes(bytes.to_vec());
+        node
+    }
+
+    fn key_node(tag: &'static str, id: u32, key: &[u8; 32]) -> Node {
+        let mut node = Node::new(tag);
+        node.add_child(bytes_node("id", &id.to_be_bytes()[1..]));
+        node.add_child(bytes_node("value", key));
+        node
+    }
+
+    /// A `<user>` node as the server sends it for a device.
+    fn user_node(jid: &str, identity: &KeyPair, signed: &PreKey, one_time: Option<&PreKey>) -> Node {
+        let mut user = Node::new("user");
+        user.set_attr("jid", jid.parse::<JID>().unwrap());
+        user.add_child(bytes_node("registration", &1234u32.to_be_bytes()));
+        user.add_child(bytes_node("type", &[5]));
+        user.add_child(bytes_node("identity", &identity.public));
+        let mut skey = key_node("skey", signed.key_id, &signed.key_pair.public);
+        skey.add_child(bytes_node("signature", &signed.signature.unwrap()));
+        user.add_child(skey);
+        if let Some(pre_key) = one_time {
+            user.add_child(key_node("key", pre_key.key_id, &pre_key.key_pair.public));
+        }
+        user
+    }
+
+    #[test]
+    fn test_bundle_request_and_response() {
+        let device: JID = "1:2@s.whatsapp.net".parse().unwrap();
+        let request = PreKeyBundleRequest { devices: vec![device.clone()], reason: Some("identity".to_string()) };
+        let iq = request.to_node("1");
+        assert_eq!(iq.get_attr_str("xmlns"), Some("encrypt"));
+        assert_eq!(iq.get_attr_str("type"), Some("get"));
+        let user = iq.get_child_by_tag("key").unwrap().get_child_by_tag("user").unwrap();
+        assert_eq!(user.get_attr_jid("jid"), Some(&device));
+        assert_eq!(user.get_attr_str("reason"), Some("identity"));
+
+        let identity = KeyPair::generate();
+        let signed = PreKey::new_signed(0x010203, &identity);
+        let one_time = PreKey::new(77);
+        let mut failed = Node::new("user");
+        failed.set_attr("jid", "3@s.whatsapp.net".parse::<JID>().unwrap());
+        let mut error = Node::new("error");
+        error.set_attr("code", "404");
+        error.set_attr("text", "item-not-found");
+        failed.add_child(error);
+        let mut list = Node::new("list");
+        list.add_child(user_node("1:2@s.whatsapp.net", &identity, &signed, Some(&one_time)));
+        list.add_child(failed);
+        let mut result = Node::new("iq");
+        result.add_child(list);
+
+        let bundles = Vec::<(JID, Result<PreKeyBundle, IqError>)>::from_node(&result).unwrap();
+        let bundle = bundles[0].1.as_ref().unwrap();
+        assert_eq!(bundles[0].0, device);
+        assert_eq!(bundle.registration_id, 1234);
+        assert_eq!(bundle.signed_pre_key_id, 0x010203);
+        assert_eq!(bundle.pre_key, Some((77, one_time.key_pair.public)));
+        assert!(bundle.verify());
+        assert!(matches!(bundles[1].1, Err(IqError::ServerError { code: 404, .. })));
+    }
+
+    #[test]
+    fn test_build_session_matches_responder() {
+        let ours = KeyPair::generate();
+        let identity = KeyPair::generate();
+        let signed = PreKey::new_signed(1, &identity);
+        let one_time = PreKey::new(9);
+        let bundle = PreKeyBundle {
+            device: "1:2@s.whatsapp.net".parse().unwrap(),
+            registration_id: 1234,
+            identity_key: identity.public,
+            signed_pre_key_id: signed.key_id,
+            signed_pre_key: signed.key_pair.public,
+            signed_pre_key_signature: signed.signature.unwrap(),
+            pre_key: Some((one_time.key_id, one_time.key_pair.public)),
+        };
+
+        let session = build_session(&ours, &bundle).unwrap();
+        let pending = session.pending_pre_key.clone().unwrap();
+        assert_eq!((pending.pre_key_id, pending.signed_pre_key_id), (Some(9), 1));
+
+        // The other device derives the same keys from its private halves
+        let mut secret = vec![0xFF; 32];
+        secret.extend_from_slice(&signed.key_pair.dh(&ours.public));
+        secret.extend_from_slice(&identity.dh(&pending.base_key));
+        secret.extend_from_slice(&signed.key_pair.dh(&pending.base_key));
+        secret.extend_from_slice(&one_time.key_pair.dh(&pending.base_key));
+        let derived = Hkdf::derive(None, &secret, b"WhisperText", 64);
+        assert_eq!(&derived[32..], &session.receiving_chain_key);
+        let ratchet = Hkdf::derive(
+            Some(&derived[..32]),
+            &signed.key_pair.dh(session.sending_ratchet.public_key()),
+            b"WhisperRatchet",
+            64,
+        );
+        assert_eq!(&ratchet[..32], &session.root_key);
+        assert_eq!(&ratchet[32..], &session.sending_chain_key);
+
+        let decoded = SessionState::from_bytes(&session.to_bytes()).unwrap();
+        assert_eq!(decoded.to_bytes(), session.to_bytes());
+        assert_eq!(decoded.pending_pre_key, Some(pending));
+        assert!(SessionState::from_bytes(&session.to_bytes()[1..]).is_err());
+
+        // A bundle whose signed pre-key was swapped is refused
+        let forged = PreKeyBundle { signed_pre_key: KeyPair::generate().public, ..bundle };
+        assert!(build_session(&ours, &forged).is_err());
+    }
+
+    #[test]
+    fn test_encrypt_for_responder() {
+        use aes::cipher::BlockDecryptMut;
+
+        let ours = KeyPair::generate();
+        let identity = KeyPair::generate();
+        let signed = PreKey::new_signed(1, &identity);
+        let bundle = PreKeyBundle {
+            device: "1:2@s.whatsapp.net".parse().unwrap(),
+            registration_id: 1234,
+            identity_key: identity.public,
+            signed_pre_key_id: signed.key_id,
+            signed_pre_key: signed.key_pair.public,
+            signed_pre_key_signature: signed.signature.unwrap(),
+            pre_key: None,
+        };
+        let mut session = build_session(&ours, &bundle).unwrap();
+        let mut chain_key = session.sending_chain_key;
+
+        for counter in 0..2 {
+            let encrypted = session.encrypt(42, b"sender key");
+            assert_eq!(encrypted.kind, "pkmsg");
+            assert_eq!(encrypted.data[0], SIGNAL_VERSION);
+            let pre_key_message = PreKeySignalMessage::decode(&encrypted.data[1..]).unwrap();
+            assert_eq!(pre_key_message.registration_id, Some(42));
+            assert_eq!(pre_key_message.signed_pre_key_id, Some(1));
+            assert_eq!(pre_key_message.identity_key, Some(signal_key(&ours.public)));
+
+            // The responder steps the same chain and checks the MAC
+            let message = pre_key_message.message.unwrap();
+            let (body, mac) = message.split_at(message.len() - SIGNAL_MAC_LEN);
+            let seed = hmac_sha256(&chain_key, &[&[0x01]]);
+            chain_key = hmac_sha256(&chain_key, &[&[0x02]]);
+            let keys = Hkdf::derive(None, &seed, b"WhisperMessageKeys", 80);
+            let expected = hmac_sha256(&keys[32..64], &[&signal_key(&ours.public), &signal_key(&identity.public), body]);
+            assert_eq!(mac, &expected[..SIGNAL_MAC_LEN]);
+
+            let signal_message = SignalMessage::decode(&body[1..]).unwrap();
+            assert_eq!(signal_message.counter, Some(counter));
+            let plaintext = cbc::Decryptor::<aes::Aes256>::new(keys[..32].into(), keys[64..].into())
+                .decrypt_padded_vec_mut::<Pkcs7>(&signal_message.ciphertext.unwrap())
+                .unwrap();
+            assert_eq!(plaintext, b"sender key");
+        }
+
+        // Once the session is confirmed, messages are no longer pre-key ones
+        session.pending_pre_key = None;
+        assert_eq!(session.encrypt(42, b"sender key").kind, "msg");
+    }
+}
@@ -0,0 +1,134 @@
+//! Presence parsing and caching.
+//!
+//! `PresenceStore` remembers the last presence seen from each user, so the
+//! availability of a contact can be shown without subscribing and waiting
+//! for the next update.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::binary::Node;
+use crate::types::{Event, Presence, JID};
+
+/// Parse a `<presence>` node.
+///
+/// `last` holds the last seen time, or "deny" if the user hides it.
+pub fn parse_presence(node: &Node) -> Option<Presence> {
+    let from = node.parse_attr_jid("from")?;
+    Some(Presence {
+        from,
+        available: node.get_attr_str("type") != Some("unavailable"),
+        last_seen: node.get_attr_str("last").and_then(|last| last.parse().ok()),
+    })
+}
+
+/// Last known presence of each user, forgotten after a TTL.
+pub struct PresenceStore {
+    ttl: Duration,
+    presences: Mutex<HashMap<JID, (Presence, Instant)>>,
+}
+
+impl PresenceStore {
+    /// Store keeping presences for `ttl`.
+    pub fn new(ttl: Duration) -> Self {
+        Self { ttl, presences: Mutex::new(HashMap::new()) }
+    }
+
+    /// Feed an event to the store; only presence events are used.
+    pub fn handle_event(&self, event: &Event) {
+        if let Event::Presence(presence) = event {
+            self.record(presence);
+        }
+    }
+
+    /// Record a presence update.
+    pub fn record(&self, presence: &Presence) {
+        self.record_at(presence, Instant::now());
+    }
+
+    fn record_at(&self, presence: &Presence, now: Instant) {
+        let mut presences = self.presences.lock().unwrap();
+        let key = presence.from.to_non_ad();
+        let mut presence = presence.clone();
+        // Updates without a time keep the last seen time known so far
+        if presence.last_seen.is_none() {
+            presence.last_seen = presences.get(&key).and_then(|(previous, _)| previous.last_seen);
+        }
+        presences.insert(key, (presence, now));
+    }
+
+    /// Get the last known presence of a user, unless it is older than the
+    /// TTL.
+    pub fn get(&self, jid: &JID) -> Option<Presence> {
+        self.get_at(jid, Instant::now())
+    }
+
+    fn get_at(&self, jid: &JID, now: Instant) -> Option<Presence> {
+        let mut presences = self.presences.lock().unwrap();
+        let key = jid.to_non_ad();
+        let (presence, recorded) = presences.get(&key)?;
+        if now.duration_since(*recorded) > self.ttl {
+            presences.remove(&key);
+            return None;
+        }
+        Some(presence.clone())
+    }
+
+    /// Forget all cached presences.
+    pub fn clear(&self) {
+        self.presences.lock().unwrap().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn presence_node(from: &str, kind: Option<&'static str>, last: Option<&str>) -> Node {
+        let mut node = Node::new("presence");
+        node.set_attr("from", from.to_string());
+        if let Some(kind) = kind {
+            node.set_attr("type", kind);
+        }
+        if let Some(last) = last {
+            node.set_attr("last", last.to_string());
+        }
+        node
+    }
+
+    #[test]
+    fn test_parse_presence() {
+        let presence = parse_presence(&presence_node("1@s.whatsapp.net", None, None)).unwrap();
+        assert!(presence.available);
+
+        let presence = parse_presence(&presence_node("1@s.whatsapp.net", Some("unavailable"), Some("1700000000"))).unwrap();
+        assert!(!presence.available);
+        assert_eq!(presence.last_seen, Some(1700000000));
+
+        let hidden = parse_presence(&presence_node("1@s.whatsapp.net", Some("unavailable"), Some("deny"))).unwrap();
+        assert_eq!(hidden.last_seen, None);
+        assert!(parse_presence(&Node::new("presence")).is_none());
+    }
+
+    #[test]
+    fn test_store_expires_and_keeps_last_seen() {
+        let store = PresenceStore::new(Duration::from_secs(60));
+        let user = JID::new("1", "s.whatsapp.net");
+        let start = Instant::now();
+
+        let offline = parse_presence(&presence_node("1@s.whatsapp.net", Some("unavailable"), Some("1700000000"))).unwrap();
+        store.record_at(&offline, start);
+        let online = parse_presence(&presence_node("1:2@s.whatsapp.net", None, None)).unwrap();
+        store.record_at(&online, start);
+        let offline = parse_presence(&presence_node("1@s.whatsapp.net", Some("unavailable"), None)).unwrap();
+        store.record_at(&offline, start);
+
+        let cached = store.get_at(&user, start + Duration::from_secs(30)).unwrap();
+        assert!(!cached.available);
+        assert_eq!(cached.last_seen, Some(1700000000));
+
+        assert!(store.get_at(&user, start + Duration::from_secs(61)).is_none());
+        assert!(store.get_at(&user, start).is_none());
+    }
+}
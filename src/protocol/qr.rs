@@ -1,84 +1,66 @@
 //! QR code pairing for WhatsApp authentication.
 //!
-//! Handles QR code generation and pairing flow for linking devices.
+//! When a device without a JID connects, the server sends a `pair-device`
+//! query with a handful of refs. Each ref becomes a QR code, shown in turn
+//! as `QRCode` events: the first for 60 seconds and the rest for 20 each.
+//! Once the phone scans one, the server sends `pair-success` with our new
+//! JID and our device identity, signed by the phone's account key and
+//! authenticated with the ADV secret from the QR code. Only once both
+//! check out is the identity countersigned and sent back, and the JID
+//! saved before `PairSuccess` is emitted. The server then restarts the
+//! stream, and the next connection logs in.
 
-use tokio::sync::mpsc;
 use std::time::Duration;
+use hmac::{Hmac, Mac};
+use prost::Message as _;
 use qrcode::{QrCode, render::unicode};
+use base64::{Engine as _, engine::general_purpose};
+use sha2::Sha256;
 
-use crate::crypto::KeyPair;
+use crate::binary::Node;
+use crate::crypto::verify_signature;
+use crate::proto::{adv_encryption_type, AdvDeviceIdentity, AdvSignedDeviceIdentity, AdvSignedDeviceIdentityHmac};
+use crate::protocol::client::ClientInner;
+use crate::protocol::request::build_iq_result;
 use crate::store::Device;
+use crate::types::{Event, PairError, PairSuccess};
 
-/// QR channel event types.
-#[derive(Debug, Clone)]
-pub enum QREvent {
-    /// New QR code to display
-    Code {
-        /// The QR code data string
-        data: String,
-        /// Timeout before next code
-        timeout: Duration,
-    },
-    /// Pairing successful
-    Success,
-    /// Pairing timed out
-    Timeout,
-    /// Error during pairing
-    Error(String),
-    /// Client outdated
-    ClientOutdated,
-}
+const ADV_ACCOUNT_SIGNATURE_PREFIX: [u8; 2] = [6, 0];
+const ADV_DEVICE_SIGNATURE_PREFIX: [u8; 2] = [6, 1];
+const ADV_HOSTED_ACCOUNT_SIGNATURE_PREFIX: [u8; 2] = [6, 5];
+const ADV_HOSTED_DEVICE_SIGNATURE_PREFIX: [u8; 2] = [6, 6];
 
-/// QR channel for receiving pairing events.
-pub type QRChannel = mpsc::Receiver<QREvent>;
-
-/// QR pairing state.
+/// QR codes built from the refs of a `pair-device` query.
 pub struct QRPairing {
-    /// Device being paired
-    device: Device,
-    /// QR codes to emit
+    /// QR codes to show, in order
     codes: Vec<String>,
     /// Current code index
     current_index: usize,
-    /// Whether pairing is complete
-    complete: bool,
 }
 
 impl QRPairing {
-    /// Create a new QR pairing session.
-    pub fn new(device: Device) -> Self {
-        // Generate QR code data
-        // Format: ref,publicKey,advSecretKey,serverRef
-        let codes = Self::generate_codes(&device);
-        
-        Self {
-            device,
-            codes,
-            current_index: 0,
-            complete: false,
-        }
+    /// Create a pairing session from the refs the server sent.
+    pub fn new(device: &Device, refs: Vec<String>) -> Self {
+        let codes = refs.iter().map(|r| Self::code(device, r)).collect();
+        Self { codes, current_index: 0 }
     }
 
-    /// Generate QR codes for pairing.
+    /// Build the QR code for one ref.
     /// Format: ref,noisePublicKey,identityPublicKey,advSecretKey
-    fn generate_codes(device: &Device) -> Vec<String> {
+    fn code(device: &Device, ref_id: &str) -> String {
         let noise_pub = device.noise_key.as_ref()
-            .map(|k| base64::encode(&k.public))
+            .map(|k| general_purpose::STANDARD.encode(k.public))
             .unwrap_or_default();
-        
+
         let identity_pub = device.identity_key.as_ref()
-            .map(|k| base64::encode(&k.public))
+            .map(|k| general_purpose::STANDARD.encode(k.public))
             .unwrap_or_default();
 
         let adv_secret = device.adv_secret_key.as_ref()
-            .map(|k| base64::encode(k))
+            .map(|k| general_purpose::STANDARD.encode(k))
             .unwrap_or_default();
 
-        // Generate multiple refs for timeout rotation (6 codes with 20s timeout each)
-        (0..6).map(|_| {
-            let ref_id = format!("{:X}", rand::random::<u64>());
-            format!("{},{},{},{}", ref_id, noise_pub, identity_pub, adv_secret)
-        }).collect()
+        format!("{},{},{},{}", ref_id, noise_pub, identity_pub, adv_secret)
     }
 
     /// Get the current QR code data.
@@ -105,26 +87,16 @@ impl QRPairing {
         }
     }
 
-    /// Mark pairing as complete.
-    pub fn mark_complete(&mut self) {
-        self.complete = true;
-    }
-
-    /// Check if pairing is complete.
-    pub fn is_complete(&self) -> bool {
-        self.complete
-    }
-
     /// Render QR code as ASCII for terminal display.
     pub fn render_qr_ascii(data: &str) -> Result<String, QRError> {
         let code = QrCode::new(data.as_bytes())
             .map_err(|e| QRError::GenerationFailed(e.to_string()))?;
-        
+
         let image = code.render::<unicode::Dense1x2>()
             .dark_color(unicode::Dense1x2::Light)
             .light_color(unicode::Dense1x2::Dark)
             .build();
-        
+
         Ok(image)
     }
 }
@@ -134,6 +106,10 @@ impl QRPairing {
 pub enum QRError {
     GenerationFailed(String),
     PairingFailed(String),
+    /// The device identity was not authenticated with our ADV secret
+    InvalidDeviceIdentityHmac,
+    /// The device identity was not signed by the account for our key
+    InvalidAccountSignature,
     Timeout,
 }
 
@@ -142,6 +118,8 @@ impl std::fmt::Display for QRError {
         match self {
             QRError::GenerationFailed(e) => write!(f, "QR generation failed: {}", e),
             QRError::PairingFailed(e) => write!(f, "pairing failed: {}", e),
+            QRError::InvalidDeviceIdentityHmac => write!(f, "pairing failed: device identity HMAC mismatch"),
+            QRError::InvalidAccountSignature => write!(f, "pairing failed: invalid account signature"),
             QRError::Timeout => write!(f, "pairing timed out"),
         }
     }
@@ -149,38 +127,240 @@ impl std::fmt::Display for QRError {
 
 impl std::error::Error for QRError {}
 
-/// Start QR pairing and return a channel for events.
-pub fn start_qr_pairing(device: Device) -> (QRPairing, mpsc::Sender<QREvent>) {
-    let (tx, _rx) = mpsc::channel(16);
-    let pairing = QRPairing::new(device);
-    (pairing, tx)
+/// The child of a pairing query from the server, if `node` is one.
+fn pairing_query<'a>(node: &'a Node, tag: &str) -> Option<&'a Node> {
+    if node.tag != "iq" || node.get_attr_str("type") != Some("set") {
+        return None;
+    }
+    node.get_child_by_tag(tag)
+}
+
+/// Refs of a `pair-device` query, one per QR code.
+pub fn parse_pair_device(node: &Node) -> Option<Vec<String>> {
+    let pair_device = pairing_query(node, "pair-device")?;
+    Some(pair_device.get_children_by_tag("ref").into_iter()
+        .filter_map(|r| r.get_bytes())
+        .map(|r| String::from_utf8_lossy(r).into_owned())
+        .collect())
+}
+
+/// Parse a `pair-success` query. The device identity it carries is
+/// checked separately, with `sign_device_identity`.
+pub fn parse_pair_success(node: &Node) -> Option<Result<PairSuccess, QRError>> {
+    let success = pairing_query(node, "pair-success")?;
+    let jid = success.get_child_by_tag("device").and_then(|device| device.get_attr_jid("jid")).cloned();
+    let Some(jid) = jid else {
+        return Some(Err(QRError::PairingFailed("missing device JID".to_string())));
+    };
+    let device = success.get_child_by_tag("device");
+    Some(Ok(PairSuccess {
+        jid,
+        lid: device.and_then(|device| device.get_attr_jid("lid")).cloned(),
+        platform: success.get_child_by_tag("platform")
+            .and_then(|platform| platform.get_attr_str("name"))
+            .unwrap_or_default()
+            .to_string(),
+        business_name: success.get_child_by_tag("biz")
+            .and_then(|biz| biz.get_attr_str("name"))
+            .map(String::from),
+    }))
+}
+
+/// Our device identity from `pair-success`, countersigned.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SignedDeviceIdentity {
+    /// The identity, with the account's signature key, to keep as
+    /// `Device::account`
+    pub account: AdvSignedDeviceIdentity,
+    /// Index of the account key the identity is signed with
+    pub key_index: u32,
+}
+
+/// Check the device identity of a `pair-success` and sign it with our
+/// identity key.
+///
+/// The identity must be authenticated with our ADV secret, proving the
+/// phone scanned our QR code, and signed by the account over our identity
+/// key, proving the account linked this device.
+pub fn sign_device_identity(device: &Device, container: &[u8]) -> Result<SignedDeviceIdentity, QRError> {
+    let malformed = |what: &str| QRError::PairingFailed(format!("malformed {}", what));
+    let (Some(identity_key), Some(adv_secret)) = (&device.identity_key, &device.adv_secret_key) else {
+        return Err(QRError::PairingFailed("device keys are missing".to_string()));
+    };
+    let container = AdvSignedDeviceIdentityHmac::decode(container).map_err(|_| malformed("device identity"))?;
+    let hosted = container.account_type == Some(adv_encryption_type::HOSTED);
+    let details = container.details.unwrap_or_default();
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(adv_secret).expect("HMAC takes keys of any length");
+    if hosted {
+        mac.update(&ADV_HOSTED_ACCOUNT_SIGNATURE_PREFIX);
+    }
+    mac.update(&details);
+    mac.verify_slice(container.hmac.as_deref().unwrap_or_default())
+        .map_err(|_| QRError::InvalidDeviceIdentityHmac)?;
+
+    let mut identity = AdvSignedDeviceIdentity::decode(&details[..]).map_err(|_| malformed("device identity"))?;
+    let identity_details = identity.details.clone().unwrap_or_default();
+    let key_index = AdvDeviceIdentity::decode(&identity_details[..])
+        .map_err(|_| malformed("device identity details"))?
+        .key_index
+        .unwrap_or_default();
+
+    let account_key: [u8; 32] = identity.account_signature_key.as_deref()
+        .and_then(|key| key.try_into().ok())
+        .ok_or(QRError::InvalidAccountSignature)?;
+    let account_signature: [u8; 64] = identity.account_signature.as_deref()
+        .and_then(|signature| signature.try_into().ok())
+        .ok_or(QRError::InvalidAccountSignature)?;
+    let prefix = if hosted { ADV_HOSTED_ACCOUNT_SIGNATURE_PREFIX } else { ADV_ACCOUNT_SIGNATURE_PREFIX };
+    let message = [&prefix[..], &identity_details, &identity_key.public].concat();
+    if !verify_signature(&account_key, &message, &account_signature) {
+        return Err(QRError::InvalidAccountSignature);
+    }
+
+    let prefix = if hosted { ADV_HOSTED_DEVICE_SIGNATURE_PREFIX } else { ADV_DEVICE_SIGNATURE_PREFIX };
+    let message = [&prefix[..], &identity_details, &identity_key.public, &account_key].concat();
+    identity.device_signature = Some(identity_key.sign_message(&message).to_vec());
+    Ok(SignedDeviceIdentity { account: identity, key_index })
+}
+
+/// Build the answer to `pair-success` carrying our signed device identity,
+/// which is sent without the account's signature key.
+pub fn build_pair_device_sign(id: &str, signed: &SignedDeviceIdentity) -> Node {
+    let identity = AdvSignedDeviceIdentity { account_signature_key: None, ..signed.account.clone() };
+    let mut device_identity = Node::new("device-identity");
+    device_identity.set_attr("key-index", signed.key_index.to_string());
+    device_identity.set_bytes(identity.encode_to_vec());
+    let mut sign = Node::new("pair-device-sign");
+    sign.add_child(device_identity);
+
+    let mut iq = build_iq_result(id, None);
+    iq.set_attr("xmlns", "md");
+    iq.add_child(sign);
+    iq
+}
+
+/// Build the answer refusing a pairing.
+fn build_pair_error(id: &str, error: &QRError) -> Node {
+    let (code, text) = match error {
+        QRError::InvalidDeviceIdentityHmac => ("401", "hmac-mismatch"),
+        QRError::InvalidAccountSignature => ("401", "signature-mismatch"),
+        _ => ("500", "internal-error"),
+    };
+    let mut error = Node::new("error");
+    error.set_attr("code", code);
+    error.set_attr("text", text);
+    let mut iq = Node::new("iq");
+    iq.set_attr("id", id);
+    iq.set_attr("type", "error");
+    iq.add_child(error);
+    iq
+}
+
+/// Answer a pairing query, returning the answer to send and the events to
+/// emit, or `None` if `node` is not a pairing query.
+pub(crate) async fn handle_pairing_query(inner: &ClientInner, node: &Node) -> Option<(Node, Vec<Event>)> {
+    let id = node.get_attr_str("id").unwrap_or_default();
+    let reply = |mut answer: Node| {
+        if let Some(from) = node.get_attr("from") {
+            answer.set_attr("to", from.clone());
+        }
+        answer
+    };
+    let ack = || reply(build_iq_result(id, None));
+
+    if let Some(refs) = parse_pair_device(node) {
+        let pairing = QRPairing::new(&*inner.device.read().await, refs);
+        *inner.pairing.lock().unwrap() = Some(pairing);
+        inner.pairing_changed.notify_one();
+        return Some((ack(), Vec::new()));
+    }
+
+    let result = parse_pair_success(node)?;
+    inner.pairing.lock().unwrap().take();
+    inner.pairing_changed.notify_one();
+    let mut device = inner.device.write().await;
+    let identity = pairing_query(node, "pair-success")
+        .and_then(|success| success.get_child_by_tag("device-identity"))
+        .and_then(|identity| identity.get_bytes());
+    let signed = result.and_then(|success| {
+        let identity = identity.ok_or_else(|| QRError::PairingFailed("missing device identity".to_string()))?;
+        Ok((success, sign_device_identity(&device, identity)?))
+    });
+    match signed {
+        Ok((success, signed)) => {
+            device.jid = Some(success.jid.clone());
+            *inner.own_jid.write().unwrap() = Some(success.jid.clone());
+            device.lid = success.lid.clone();
+            device.platform = success.platform.clone();
+            device.business_name = success.business_name.clone();
+            device.account = Some(signed.account.encode_to_vec());
+            if let Err(e) = inner.store.put_device(&device) {
+                log::warn!("failed to save paired device: {}", e);
+            }
+            Some((reply(build_pair_device_sign(id, &signed)), vec![Event::PairSuccess(success)]))
+        }
+        Err(e) => {
+            log::warn!("refusing pairing: {}", e);
+            Some((reply(build_pair_error(id, &e)), vec![Event::PairError(PairError { reason: e.to_string() })]))
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::sync::Arc;
+    use crate::crypto::KeyPair;
+    use crate::store::MemoryStore;
+    use crate::types::JID;
 
-    #[test]
-    fn test_qr_pairing_creation() {
-        let mut device = Device::new();
-        device.initialize();
-        
-        let pairing = QRPairing::new(device);
-        assert!(!pairing.is_complete());
-        assert!(pairing.current_code().is_some());
+    /// Device identity for `device` as the phone sends it, signed by
+    /// `account`.
+    fn phone_identity(device: &Device, account: &KeyPair) -> Vec<u8> {
+        let details = AdvDeviceIdentity { raw_id: Some(7), key_index: Some(3), ..Default::default() }.encode_to_vec();
+        let message = [&ADV_ACCOUNT_SIGNATURE_PREFIX[..], &details, &device.identity_key.as_ref().unwrap().public].concat();
+        let identity = AdvSignedDeviceIdentity {
+            details: Some(details),
+            account_signature_key: Some(account.public.to_vec()),
+            account_signature: Some(account.sign_message(&message).to_vec()),
+            device_signature: None,
+        }
+        .encode_to_vec();
+        let mut mac = Hmac::<Sha256>::new_from_slice(device.adv_secret_key.as_ref().unwrap()).unwrap();
+        mac.update(&identity);
+        AdvSignedDeviceIdentityHmac {
+            details: Some(identity),
+            hmac: Some(mac.finalize().into_bytes().to_vec()),
+            account_type: None,
+        }
+        .encode_to_vec()
+    }
+
+    fn pairing_iq(child: Node) -> Node {
+        let mut iq = Node::new("iq");
+        iq.set_attr("id", "1");
+        iq.set_attr("type", "set");
+        iq.set_attr("from", JID::new("", "s.whatsapp.net"));
+        iq.add_child(child);
+        iq
     }
 
     #[test]
     fn test_qr_code_rotation() {
         let mut device = Device::new();
         device.initialize();
-        
-        let mut pairing = QRPairing::new(device);
+
+        let mut pairing = QRPairing::new(&device, vec!["ref1".to_string(), "ref2".to_string()]);
         let first = pairing.current_code().unwrap().to_string();
-        
-        let second = pairing.next_code();
-        assert!(second.is_some());
-        assert_ne!(first, second.unwrap());
+        assert!(first.starts_with("ref1,"));
+        assert_eq!(first.split(',').count(), 4);
+        assert_eq!(pairing.current_timeout(), Duration::from_secs(60));
+
+        let second = pairing.next_code().unwrap().to_string();
+        assert!(second.starts_with("ref2,"));
+        assert_eq!(pairing.current_timeout(), Duration::from_secs(20));
+        assert!(pairing.next_code().is_none());
     }
 
     #[test]
@@ -189,4 +369,89 @@ mod tests {
         assert!(result.is_ok());
         assert!(!result.unwrap().is_empty());
     }
+
+    #[test]
+    fn test_parse_pairing_queries() {
+        let mut pair_device = Node::new("pair-device");
+        for r in ["2@abc", "2@def"] {
+            let mut ref_node = Node::new("ref");
+            ref_node.set_bytes(r.as_bytes().to_vec());
+            pair_device.add_child(ref_node);
+        }
+        assert_eq!(parse_pair_device(&pairing_iq(pair_device)).unwrap(), ["2@abc", "2@def"]);
+        assert!(parse_pair_device(&Node::new("iq")).is_none());
+
+        let mut device = Node::new("device");
+        device.set_attr("jid", "1:7@s.whatsapp.net".parse::<JID>().unwrap());
+        let mut platform = Node::new("platform");
+        platform.set_attr("name", "android");
+        let mut success = Node::new("pair-success");
+        success.add_child(device);
+        success.add_child(platform);
+        let paired = parse_pair_success(&pairing_iq(success)).unwrap().unwrap();
+        assert_eq!(paired.jid, JID::new_ad("1", 0, 7));
+        assert_eq!(paired.platform, "android");
+        assert_eq!(paired.business_name, None);
+
+        assert!(parse_pair_success(&pairing_iq(Node::new("pair-success"))).unwrap().is_err());
+    }
+
+    #[test]
+    fn test_sign_device_identity() {
+        let mut device = Device::new();
+        device.initialize();
+        let account = KeyPair::generate();
+        let container = phone_identity(&device, &account);
+
+        let signed = sign_device_identity(&device, &container).unwrap();
+        assert_eq!(signed.key_index, 3);
+        let details = signed.account.details.clone().unwrap();
+        let identity_key = device.identity_key.as_ref().unwrap().public;
+        let message = [&ADV_DEVICE_SIGNATURE_PREFIX[..], &details, &identity_key, &account.public].concat();
+        let signature: [u8; 64] = signed.account.device_signature.as_deref().unwrap().try_into().unwrap();
+        assert!(verify_signature(&identity_key, &message, &signature));
+
+        let sign = build_pair_device_sign("9", &signed);
+        let identity = sign.get_child_by_tag("pair-device-sign").unwrap().get_child_by_tag("device-identity").unwrap();
+        assert_eq!(identity.get_attr_str("key-index"), Some("3"));
+        let sent = AdvSignedDeviceIdentity::decode(identity.get_bytes().unwrap()).unwrap();
+        assert_eq!(sent.account_signature_key, None);
+
+        // Another device's secret, or another device's key, does not pass
+        let mut other = Device::new();
+        other.initialize();
+        assert!(matches!(sign_device_identity(&other, &container), Err(QRError::InvalidDeviceIdentityHmac)));
+        other.adv_secret_key = device.adv_secret_key.clone();
+        assert!(matches!(sign_device_identity(&other, &container), Err(QRError::InvalidAccountSignature)));
+    }
+
+    #[tokio::test]
+    async fn test_pair_success_needs_a_valid_identity() {
+        let inner = ClientInner::new(Default::default(), Arc::new(MemoryStore::new()));
+        let account = KeyPair::generate();
+        let pair_success = |identity: Vec<u8>| {
+            let mut device = Node::new("device");
+            device.set_attr("jid", JID::new_ad("1", 0, 7));
+            let mut device_identity = Node::new("device-identity");
+            device_identity.set_bytes(identity);
+            let mut success = Node::new("pair-success");
+            success.add_child(device);
+            success.add_child(device_identity);
+            pairing_iq(success)
+        };
+
+        let mut forged = phone_identity(&*inner.device.read().await, &account);
+        *forged.last_mut().unwrap() ^= 1;
+        let (answer, events) = handle_pairing_query(&inner, &pair_success(forged)).await.unwrap();
+        assert_eq!(answer.get_attr_str("type"), Some("error"));
+        assert!(matches!(events.as_slice(), [Event::PairError(_)]));
+        assert!(inner.own_jid().is_none());
+
+        let identity = phone_identity(&*inner.device.read().await, &account);
+        let (answer, events) = handle_pairing_query(&inner, &pair_success(identity)).await.unwrap();
+        assert!(answer.get_child_by_tag("pair-device-sign").is_some());
+        assert!(matches!(events.as_slice(), [Event::PairSuccess(_)]));
+        assert_eq!(inner.own_jid(), Some(JID::new_ad("1", 0, 7)));
+        assert!(inner.device.read().await.account.is_some());
+    }
 }
@@ -0,0 +1,129 @@
+//! Token bucket rate limiting.
+//!
+//! Used to pace outgoing traffic such as bulk sends.
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+
+use crate::protocol::clock::{Clock, SystemClock};
+
+/// Rate limit settings.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    /// Number of operations allowed in a burst
+    pub burst: u32,
+    /// Sustained operations per second
+    pub per_second: f64,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        Self {
+            burst: 5,
+            per_second: 1.0,
+        }
+    }
+}
+
+/// Token bucket rate limiter.
+pub struct RateLimiter {
+    limit: RateLimit,
+    clock: Arc<dyn Clock>,
+    /// Available tokens and the time they were last refilled
+    state: Mutex<(f64, DateTime<Utc>)>,
+}
+
+impl RateLimiter {
+    /// Create a new rate limiter with a full bucket.
+    pub fn new(limit: RateLimit) -> Self {
+        Self::with_clock(limit, Arc::new(SystemClock))
+    }
+
+    /// Create a new rate limiter with a full bucket, refilled by `clock`.
+    pub fn with_clock(limit: RateLimit, clock: Arc<dyn Clock>) -> Self {
+        let now = clock.now();
+        Self {
+            limit,
+            clock,
+            state: Mutex::new((limit.burst as f64, now)),
+        }
+    }
+
+    /// Get the configured limit.
+    pub fn limit(&self) -> RateLimit {
+        self.limit
+    }
+
+    /// Take a token if one is available.
+    pub fn try_acquire(&self) -> bool {
+        self.reserve().is_none()
+    }
+
+    /// Wait until a token is available and take it.
+    pub async fn acquire(&self) {
+        while let Some(wait) = self.reserve() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Take a token, or return how long to wait until one is available.
+    fn reserve(&self) -> Option<Duration> {
+        let mut state = self.state.lock().unwrap();
+        let (tokens, last) = &mut *state;
+
+        // A clock set back refills nothing
+        let now = self.clock.now();
+        let elapsed = (now - *last).to_std().unwrap_or_default();
+        let refill = elapsed.as_secs_f64() * self.limit.per_second;
+        *tokens = (*tokens + refill).min(self.limit.burst.max(1) as f64);
+        *last = now.max(*last);
+
+        if *tokens >= 1.0 {
+            *tokens -= 1.0;
+            None
+        } else if self.limit.per_second <= 0.0 {
+            // Never refills; poll again later
+            Some(Duration::from_secs(1))
+        } else {
+            Some(Duration::from_secs_f64((1.0 - *tokens) / self.limit.per_second))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_burst_then_exhausted() {
+        let limiter = RateLimiter::new(RateLimit { burst: 2, per_second: 0.001 });
+
+        assert!(limiter.try_acquire());
+        assert!(limiter.try_acquire());
+        assert!(!limiter.try_acquire());
+    }
+
+    #[tokio::test]
+    async fn test_acquire_waits_for_refill() {
+        let limiter = RateLimiter::new(RateLimit { burst: 1, per_second: 50.0 });
+        limiter.acquire().await;
+
+        let start = std::time::Instant::now();
+        limiter.acquire().await;
+        assert!(start.elapsed() >= Duration::from_millis(15));
+    }
+
+    #[test]
+    fn test_refills_from_clock() {
+        use crate::protocol::clock::ManualClock;
+
+        let clock = Arc::new(ManualClock::new(Utc::now()));
+        let limiter = RateLimiter::with_clock(RateLimit { burst: 1, per_second: 1.0 }, clock.clone());
+        assert!(limiter.try_acquire());
+        assert!(!limiter.try_acquire());
+
+        clock.advance(chrono::Duration::seconds(1));
+        assert!(limiter.try_acquire());
+    }
+}
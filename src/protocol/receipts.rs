@@ -0,0 +1,308 @@
+//! Receipt parsing and aggregation.
+//!
+//! `ReceiptTracker` folds receipt events into per-message delivery state,
+//! so bots can ask who has received or read a message.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+use crate::binary::Node;
+use crate::types::{Event, MessageID, Receipt, ReceiptType, JID};
+
+/// Parse a `<receipt>` node.
+///
+/// Receipts for several messages list the others as `<item>`s after the
+/// `id` attribute. Receipts from our own devices name the chat in
+/// `recipient`, as `from` is then our own JID. The timestamp is the
+/// server's `t`, or `now` without one.
+pub fn parse_receipt(node: &Node, now: i64) -> Receipt {
+    let jid = |attr| node.parse_attr_jid(attr);
+
+    let mut message_ids: Vec<String> = node.get_attr_str("id").map(String::from).into_iter().collect();
+    if let Some(list) = node.get_child_by_tag("list") {
+        message_ids.extend(list.get_children_by_tag("item").into_iter()
+            .filter_map(|item| item.get_attr_str("id"))
+            .map(String::from));
+    }
+
+    let from = jid("from").unwrap_or_default();
+    let (chat, sender) = match (jid("recipient"), jid("participant")) {
+        // Our own device receipting a message in a 1:1 chat
+        (Some(recipient), None) => (recipient, from),
+        (_, Some(participant)) => (from, participant),
+        (None, None) => (from, JID::default()),
+    };
+
+    Receipt {
+        message_ids,
+        chat,
+        sender,
+        receipt_type: ReceiptType::from_attr(node.get_attr_str("type")),
+        timestamp: node.get_attr_int("t").unwrap_or(now),
+    }
+}
+
+/// Delivery state of one sent message.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MessageReceipts {
+    /// Chat the message was sent to
+    pub chat: JID,
+    /// Users expected to receive the message, if known
+    pub recipients: HashSet<JID>,
+    /// Users whose device received the message
+    pub delivered_to: HashSet<JID>,
+    /// Users who read the message
+    pub read_by: HashSet<JID>,
+    /// Users who played the media of the message
+    pub played_by: HashSet<JID>,
+}
+
+impl MessageReceipts {
+    /// Fraction of recipients that received the message.
+    ///
+    /// `None` if the recipients are unknown.
+    pub fn fraction_delivered(&self) -> Option<f64> {
+        self.fraction(&self.delivered_to)
+    }
+
+    /// Fraction of recipients that read the message.
+    ///
+    /// `None` if the recipients are unknown.
+    pub fn fraction_read(&self) -> Option<f64> {
+        self.fraction(&self.read_by)
+    }
+
+    fn fraction(&self, users: &HashSet<JID>) -> Option<f64> {
+        if self.recipients.is_empty() {
+            return None;
+        }
+        let count = users.intersection(&self.recipients).count();
+        Some(count as f64 / self.recipients.len() as f64)
+    }
+}
+
+/// Tracks delivery and read state of sent messages from receipt events.
+#[derive(Default)]
+pub struct ReceiptTracker {
+    messages: Mutex<HashMap<MessageID, MessageReceipts>>,
+}
+
+impl ReceiptTracker {
+    /// Create an empty tracker.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start tracking a message, with the users expected to receive it.
+    ///
+    /// Tracking is optional: receipts for unknown messages are recorded
+    /// too, but fractions need the recipient list.
+    pub fn track<I>(&self, message_id: &str, chat: &JID, recipients: I)
+    where
+        I: IntoIterator<Item = JID>,
+    {
+        let mut messages = self.messages.lock().unwrap();
+        let entry = messages.entry(message_id.to_string()).or_default();
+        entry.chat = chat.clone();
+        entry.recipients = recipients.into_iter().map(|jid| jid.to_non_ad()).collect();
+    }
+
+    /// Feed an event; anything but a receipt is ignored.
+    pub fn handle_event(&self, event: &Event) {
+        if let Event::Receipt(receipt) = event {
+            self.record(receipt);
+        }
+    }
+
+    /// Record a receipt.
+    pub fn record(&self, receipt: &Receipt) {
+        // Receipts in 1:1 chats come from the chat itself
+        let user = if receipt.sender.is_empty() { &receipt.chat } else { &receipt.sender };
+        let user = user.to_non_ad();
+
+        let mut messages = self.messages.lock().unwrap();
+        for id in &receipt.message_ids {
+            let entry = messages.entry(id.clone()).or_insert_with(|| MessageReceipts {
+                chat: receipt.chat.clone(),
+                ..Default::default()
+            });
+
+            match receipt.receipt_type {
+                // Not from the recipient
+                ReceiptType::Server
+                | ReceiptType::Sender
+                | ReceiptType::Retry
+                | ReceiptType::ReadSelf
+                | ReceiptType::PlayedSelf
+                | ReceiptType::PeerMsg
+                | ReceiptType::Other(_) => {}
+                ReceiptType::Inactive => {
+                    entry.delivered_to.insert(user.clone());
+                }
+                ReceiptType::Delivered => {
+                    entry.delivered_to.insert(user.clone());
+                }
+                ReceiptType::Read => {
+                    entry.delivered_to.insert(user.clone());
+                    entry.read_by.insert(user.clone());
+                }
+                ReceiptType::Played => {
+                    entry.delivered_to.insert(user.clone());
+                    entry.read_by.insert(user.clone());
+                    entry.played_by.insert(user.clone());
+                }
+            }
+        }
+    }
+
+    /// Get the state of a message.
+    pub fn get(&self, message_id: &str) -> Option<MessageReceipts> {
+        self.messages.lock().unwrap().get(message_id).cloned()
+    }
+
+    /// Users whose device received a message.
+    pub fn delivered_to(&self, message_id: &str) -> Vec<JID> {
+        self.get(message_id).map(|m| m.delivered_to.into_iter().collect()).unwrap_or_default()
+    }
+
+    /// Users who read a message.
+    pub fn read_by(&self, message_id: &str) -> Vec<JID> {
+        self.get(message_id).map(|m| m.read_by.into_iter().collect()).unwrap_or_default()
+    }
+
+    /// Fraction of recipients that read a message.
+    pub fn fraction_read(&self, message_id: &str) -> Option<f64> {
+        self.messages.lock().unwrap().get(message_id)?.fraction_read()
+    }
+
+    /// Fraction of recipients that received a message.
+    pub fn fraction_delivered(&self, message_id: &str) -> Option<f64> {
+        self.messages.lock().unwrap().get(message_id)?.fraction_delivered()
+    }
+
+    /// Stop tracking a message.
+    pub fn forget(&self, message_id: &str) -> Option<MessageReceipts> {
+        self.messages.lock().unwrap().remove(message_id)
+    }
+
+    /// Number of tracked messages.
+    pub fn len(&self) -> usize {
+        self.messages.lock().unwrap().len()
+    }
+
+    /// Check if no messages are tracked.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(name: &str) -> JID {
+        JID::new(name, "s.whatsapp.net")
+    }
+
+    fn receipt(id: &str, sender: JID, receipt_type: ReceiptType) -> Event {
+        Event::Receipt(Receipt {
+            message_ids: vec![id.to_string()],
+            chat: JID::new("123", "g.us"),
+            sender,
+            receipt_type,
+            timestamp: 0,
+        })
+    }
+
+    #[test]
+    fn test_group_read_fraction() {
+        let tracker = ReceiptTracker::new();
+        let group = JID::new("123", "g.us");
+        tracker.track("M1", &group, vec![user("a"), user("b"), user("c"), user("d")]);
+
+        tracker.handle_event(&receipt("M1", user("a"), ReceiptType::Delivered));
+        tracker.handle_event(&receipt("M1", JID::new_ad("b", 0, 2), ReceiptType::Read));
+        tracker.handle_event(&receipt("M1", user("c"), ReceiptType::Server));
+
+        assert_eq!(tracker.fraction_delivered("M1"), Some(0.5));
+        assert_eq!(tracker.fraction_read("M1"), Some(0.25));
+        assert_eq!(tracker.read_by("M1"), vec![user("b")]);
+
+        // Duplicate receipts from other devices don't count twice
+        tracker.handle_event(&receipt("M1", JID::new_ad("b", 0, 5), ReceiptType::Read));
+        assert_eq!(tracker.fraction_read("M1"), Some(0.25));
+    }
+
+    #[test]
+    fn test_untracked_message() {
+        let tracker = ReceiptTracker::new();
+        tracker.record(&Receipt {
+            message_ids: vec!["M2".to_string()],
+            chat: user("a"),
+            sender: JID::default(),
+            receipt_type: ReceiptType::Played,
+            timestamp: 0,
+        });
+
+        let state = tracker.get("M2").unwrap();
+        assert_eq!(state.played_by.len(), 1);
+        assert!(state.delivered_to.contains(&user("a")));
+        assert_eq!(tracker.fraction_read("M2"), None);
+
+        assert!(tracker.forget("M2").is_some());
+        assert!(tracker.is_empty());
+    }
+
+    #[test]
+    fn test_parse_receipt() {
+        let mut item = Node::new("item");
+        item.set_attr("id", "M2");
+        let mut list = Node::new("list");
+        list.add_child(item);
+        let mut node = Node::new("receipt");
+        node.set_attr("from", "123@g.us");
+        node.set_attr("participant", "a@s.whatsapp.net");
+        node.set_attr("id", "M1");
+        node.set_attr("type", "read");
+        node.set_attr("t", "1700000000");
+        node.add_child(list);
+
+        let receipt = parse_receipt(&node, 0);
+        assert_eq!(receipt.message_ids, ["M1", "M2"]);
+        assert_eq!((receipt.chat, receipt.sender), (JID::new("123", "g.us"), user("a")));
+        assert_eq!(receipt.receipt_type, ReceiptType::Read);
+        assert_eq!(receipt.timestamp, 1700000000);
+
+        // Our phone read a 1:1 message; decoded nodes carry JID values
+        let mut node = Node::new("receipt");
+        node.set_attr("from", user("me"));
+        node.set_attr("recipient", user("b"));
+        node.set_attr("id", "M3");
+        node.set_attr("type", "read-self");
+        let receipt = parse_receipt(&node, 1_700_000_500);
+        assert_eq!((receipt.chat, receipt.sender), (user("b"), user("me")));
+        assert_eq!(receipt.timestamp, 1_700_000_500);
+        assert!(receipt.receipt_type.is_self());
+
+        for (attr, expected) in [
+            (None, ReceiptType::Delivered),
+            (Some("sender"), ReceiptType::Sender),
+            (Some("retry"), ReceiptType::Retry),
+            (Some("played-self"), ReceiptType::PlayedSelf),
+            (Some("inactive"), ReceiptType::Inactive),
+            (Some("peer_msg"), ReceiptType::PeerMsg),
+            (Some("hist_sync"), ReceiptType::Other("hist_sync".to_string())),
+        ] {
+            assert_eq!(ReceiptType::from_attr(attr), expected);
+        }
+    }
+
+    #[test]
+    fn test_self_receipts_not_counted() {
+        let tracker = ReceiptTracker::new();
+        tracker.track("M1", &user("a"), vec![user("a")]);
+        tracker.handle_event(&receipt("M1", user("me"), ReceiptType::ReadSelf));
+        tracker.handle_event(&receipt("M1", user("me"), ReceiptType::Retry));
+        assert_eq!(tracker.fraction_delivered("M1"), Some(0.0));
+    }
+}
@@ -0,0 +1,175 @@
+//! Recording and replaying decrypted frames.
+//!
+//! A `FrameRecorder` attached with `Client::set_frame_recorder` keeps every
+//! frame the connection sends and receives, after Noise decryption. Saved
+//! as a `FrameLog`, one JSON object per line, the received frames can be
+//! fed back through `Client::replay` to check the events a session
+//! produces, so parsing changes can be tested against real traffic without
+//! connecting.
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use serde::{Deserialize, Serialize};
+
+/// Which way a frame went.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FrameDirection {
+    #[serde(rename = "in")]
+    Received,
+    #[serde(rename = "out")]
+    Sent,
+}
+
+/// One decrypted frame: an encoded node.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CapturedFrame {
+    #[serde(rename = "dir")]
+    pub direction: FrameDirection,
+    #[serde(rename = "frame", with = "hex_bytes")]
+    pub data: Vec<u8>,
+}
+
+mod hex_bytes {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&hex::encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        hex::decode(s).map_err(serde::de::Error::custom)
+    }
+}
+
+/// Frames of a session, in the order they went over the wire.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FrameLog {
+    pub frames: Vec<CapturedFrame>,
+}
+
+impl FrameLog {
+    /// Parse a log of one JSON frame per line, such as
+    /// `{"dir":"in","frame":"f80102"}`. Blank lines and lines starting
+    /// with `#` are skipped.
+    pub fn parse(text: &str) -> Result<Self, ReplayError> {
+        let frames = text.lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty() && !line.trim_start().starts_with('#'))
+            .map(|(i, line)| {
+                serde_json::from_str(line).map_err(|e| ReplayError::InvalidLine { line: i + 1, reason: e.to_string() })
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self { frames })
+    }
+
+    /// Write the log in the form `parse` reads.
+    pub fn to_jsonl(&self) -> String {
+        self.frames.iter()
+            .map(|frame| serde_json::to_string(frame).expect("frames serialize") + "\n")
+            .collect()
+    }
+
+    /// Frames received from the server.
+    pub fn received(&self) -> impl Iterator<Item = &[u8]> {
+        self.frames.iter()
+            .filter(|frame| frame.direction == FrameDirection::Received)
+            .map(|frame| frame.data.as_slice())
+    }
+}
+
+/// Frame log errors.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReplayError {
+    /// A line is not a frame; lines are numbered from 1
+    InvalidLine { line: usize, reason: String },
+}
+
+impl std::fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReplayError::InvalidLine { line, reason } => write!(f, "invalid frame on line {}: {}", line, reason),
+        }
+    }
+}
+
+impl std::error::Error for ReplayError {}
+
+/// Frames a `FrameRecorder` keeps unless created with another limit.
+pub const DEFAULT_MAX_FRAMES: usize = 10_000;
+
+/// Collects the frames of a connection into a `FrameLog`.
+///
+/// At most `max_frames` are kept; once full, the oldest frame is dropped
+/// for each new one, so a recorder left attached does not grow without
+/// bound. Take the frames regularly to keep a whole session.
+///
+/// Frames hold message text and keys in the clear, so logs should be
+/// handled like the session store.
+pub struct FrameRecorder {
+    frames: Mutex<VecDeque<CapturedFrame>>,
+    max_frames: usize,
+}
+
+impl Default for FrameRecorder {
+    fn default() -> Self {
+        Self::with_max_frames(DEFAULT_MAX_FRAMES)
+    }
+}
+
+impl FrameRecorder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Recorder keeping at most the last `max_frames` frames.
+    pub fn with_max_frames(max_frames: usize) -> Self {
+        Self { frames: Mutex::new(VecDeque::new()), max_frames }
+    }
+
+    /// Record a frame.
+    pub fn record(&self, direction: FrameDirection, data: &[u8]) {
+        let mut frames = self.frames.lock().unwrap();
+        if self.max_frames == 0 {
+            return;
+        }
+        if frames.len() == self.max_frames {
+            frames.pop_front();
+        }
+        frames.push_back(CapturedFrame { direction, data: data.to_vec() });
+    }
+
+    /// Take the frames recorded so far.
+    pub fn take(&self) -> FrameLog {
+        FrameLog { frames: std::mem::take(&mut *self.frames.lock().unwrap()).into() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frame_log_round_trip() {
+        let recorder = FrameRecorder::new();
+        recorder.record(FrameDirection::Sent, &[0xF8, 0x01, 0x02]);
+        recorder.record(FrameDirection::Received, &[0xF8, 0x01, 0x03]);
+        let log = recorder.take();
+        assert!(recorder.take().frames.is_empty());
+
+        let text = log.to_jsonl();
+        assert_eq!(text.lines().next(), Some(r#"{"dir":"out","frame":"f80102"}"#));
+        assert_eq!(FrameLog::parse(&format!("# capture\n\n{}", text)).unwrap(), log);
+        assert_eq!(log.received().collect::<Vec<_>>(), [&[0xF8, 0x01, 0x03][..]]);
+
+        let err = FrameLog::parse("{\"dir\":\"in\",\"frame\":\"zz\"}").unwrap_err();
+        assert!(matches!(err, ReplayError::InvalidLine { line: 1, .. }));
+
+        // Only the newest frames are kept
+        let recorder = FrameRecorder::with_max_frames(2);
+        for byte in 1..=3 {
+            recorder.record(FrameDirection::Received, &[byte]);
+        }
+        assert_eq!(recorder.take().received().collect::<Vec<_>>(), [&[2][..], &[3][..]]);
+    }
+}
@@ -0,0 +1,116 @@
+//! Replies to messages by ID.
+//!
+//! A reply names the message it quotes in its `ContextInfo`: the ID and,
+//! in groups, the sender. Sent and received messages are indexed in the
+//! store by chat and ID, so a reply can be built from an ID alone, and a
+//! received reply resolved to what it quotes even without a chat store.
+
+use prost::Message as _;
+
+use crate::binary::Node;
+use crate::proto::{ContextInfo, E2eMessage, ExtendedTextMessage};
+use crate::protocol::client::{Client, ClientError};
+use crate::protocol::message::build_e2e_message;
+use crate::store::MessageRef;
+use crate::types::{MessageInfo, JID};
+
+/// Build the context of a reply quoting a message.
+pub fn build_reply_context(quoted: &MessageRef) -> ContextInfo {
+    ContextInfo {
+        stanza_id: Some(quoted.id.clone()),
+        participant: Some(quoted.sender.to_non_ad().to_string()),
+        ..Default::default()
+    }
+}
+
+/// Build a text message quoting another, with the context from
+/// `build_reply_context`.
+pub fn build_reply_message(to: &JID, message_id: &str, text: &str, context: ContextInfo) -> Node {
+    let message = E2eMessage {
+        extended_text_message: Some(ExtendedTextMessage { text: Some(text.to_string()), context_info: Some(context) }),
+        ..Default::default()
+    };
+    build_e2e_message(to, message_id, "text", None, &message)
+}
+
+/// ID of the message a received message quotes, from the context of its
+/// content.
+pub fn parse_reply_to(node: &Node) -> Option<String> {
+    let message = E2eMessage::decode(node.get_child_by_tag("enc")?.get_bytes()?).ok()?;
+    let context = message.extended_text_message.and_then(|m| m.context_info)
+        .or_else(|| message.buttons_response_message.and_then(|m| m.context_info))
+        .or_else(|| message.list_response_message.and_then(|m| m.context_info))
+        .or_else(|| message.template_button_reply_message.and_then(|m| m.context_info))
+        .or_else(|| message.product_message.and_then(|m| m.context_info))
+        .or_else(|| message.poll_creation_message.and_then(|m| m.context_info));
+    context?.stanza_id
+}
+
+impl Client {
+    /// Get a sent or received message from the index by chat and ID.
+    pub fn get_message_ref(&self, chat: &JID, id: &str) -> Result<Option<MessageRef>, ClientError> {
+        self.store().get_message_ref(chat, id).map_err(|e| ClientError::StoreError(e.to_string()))
+    }
+
+    /// Build the context of a reply quoting the message `id` of a chat,
+    /// or `None` if the message is not indexed.
+    pub fn reply_context(&self, chat: &JID, id: &str) -> Result<Option<ContextInfo>, ClientError> {
+        Ok(self.get_message_ref(chat, id)?.as_ref().map(build_reply_context))
+    }
+
+    /// Find the message a received message quotes, if it is a reply and
+    /// the quoted message is indexed.
+    pub fn resolve_reply(&self, info: &MessageInfo) -> Result<Option<MessageRef>, ClientError> {
+        let Some(id) = &info.reply_to else {
+            return Ok(None);
+        };
+        self.get_message_ref(&info.chat, id)
+    }
+
+    /// Send a text message quoting the message `quoted_id` of `to`,
+    /// returning its ID.
+    ///
+    /// See `ClientHandle::send_reply`.
+    pub async fn send_reply(&self, to: JID, quoted_id: &str, text: &str) -> Result<String, ClientError> {
+        self.connection()?.send_reply(to, quoted_id, text).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::StoredMessage;
+
+    #[test]
+    fn test_reply_resolution() {
+        let client = Client::new();
+        let group = JID::new("123", "g.us");
+        let sender: JID = "1:4@s.whatsapp.net".parse().unwrap();
+        let message = StoredMessage {
+            id: "3EB0AA".to_string(),
+            chat: group.clone(),
+            sender,
+            is_from_me: false,
+            timestamp: 1700000000,
+            push_name: None,
+            text: Some("x".repeat(300)),
+        };
+        client.store().put_message_ref(&MessageRef::from(&message)).unwrap();
+
+        let context = client.reply_context(&group, "3EB0AA").unwrap().unwrap();
+        assert_eq!(context.participant.as_deref(), Some("1@s.whatsapp.net"));
+        let mut reply = build_reply_message(&group, "3EB0BB", "agreed", context);
+        reply.set_attr("from", group.clone());
+        reply.set_attr("participant", "2@s.whatsapp.net");
+        let (mut info, content) = crate::protocol::message::parse_message(&reply, 0).unwrap();
+        assert_eq!(info.reply_to.as_deref(), Some("3EB0AA"));
+        assert!(matches!(content, crate::types::MessageContent::Text(text) if text == "agreed"));
+        let quoted = client.resolve_reply(&info).unwrap().unwrap();
+        assert_eq!(quoted.timestamp, 1700000000);
+        assert_eq!(quoted.snippet.map(|s| s.len()), Some(100));
+
+        assert!(client.reply_context(&group, "UNKNOWN").unwrap().is_none());
+        info.chat = JID::new("456", "g.us");
+        assert!(client.resolve_reply(&info).unwrap().is_none());
+    }
+}
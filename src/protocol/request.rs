@@ -3,14 +3,17 @@
 //! Handles WhatsApp IQ (Info/Query) protocol messages.
 
 use crate::binary::Node;
+use crate::types::{JID, SERVER_JID};
 use std::collections::HashMap;
 use std::sync::{Arc, RwLock};
 use tokio::sync::oneshot;
 
 /// Request tracker for IQ messages.
+///
+/// Requests are registered under the tag they were sent with, which comes
+/// from the connection's `TagGenerator`.
 pub struct RequestTracker {
     pending: Arc<RwLock<HashMap<String, oneshot::Sender<Node>>>>,
-    counter: Arc<RwLock<u64>>,
 }
 
 impl RequestTracker {
@@ -18,17 +21,9 @@ impl RequestTracker {
     pub fn new() -> Self {
         Self {
             pending: Arc::new(RwLock::new(HashMap::new())),
-            counter: Arc::new(RwLock::new(0)),
         }
     }
 
-    /// Generate a new unique request ID.
-    pub fn next_id(&self) -> String {
-        let mut counter = self.counter.write().unwrap();
-        *counter += 1;
-        format!("{:X}.{}", rand::random::<u16>(), counter)
-    }
-
     /// Register a pending request and get a receiver for the response.
     pub fn register(&self, id: &str) -> oneshot::Receiver<Node> {
         let (tx, rx) = oneshot::channel();
@@ -50,6 +45,13 @@ impl RequestTracker {
         self.pending.write().unwrap().remove(id);
     }
 
+    /// Cancel all pending requests.
+    ///
+    /// Waiters observe a closed channel, which `Client::query` reports as `IqError::Cancelled`.
+    pub fn cancel_all(&self) {
+        self.pending.write().unwrap().clear();
+    }
+
     /// Get count of pending requests.
     pub fn pending_count(&self) -> usize {
         self.pending.read().unwrap().len()
@@ -97,6 +99,127 @@ pub fn build_iq_result(id: &str, to: Option<&str>) -> Node {
     node
 }
 
+/// Type of an outgoing IQ query.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IqType {
+    Get,
+    Set,
+}
+
+impl IqType {
+    /// Get the wire value of the `type` attribute.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            IqType::Get => "get",
+            IqType::Set => "set",
+        }
+    }
+}
+
+/// IQ errors.
+#[derive(Debug, Clone, PartialEq)]
+pub enum IqError {
+    /// The server answered with an error stanza
+    ServerError { code: u16, text: String },
+    /// No response arrived before the request timeout
+    Timeout,
+    /// The request was dropped before a response arrived
+    Cancelled,
+    /// The response did not have the expected structure
+    MalformedResponse(String),
+}
+
+impl IqError {
+    /// Build an error from an IQ error node.
+    pub fn from_error_node(node: &Node) -> Self {
+        let code = node.get_child_by_tag("error")
+            .and_then(|e| e.get_attr_str("code"))
+            .and_then(|c| c.parse().ok())
+            .unwrap_or(0);
+        let text = get_iq_error(node).unwrap_or_default();
+        IqError::ServerError { code, text }
+    }
+}
+
+impl std::fmt::Display for IqError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IqError::ServerError { code, text } => write!(f, "server returned error {}: {}", code, text),
+            IqError::Timeout => write!(f, "request timed out"),
+            IqError::Cancelled => write!(f, "request cancelled"),
+            IqError::MalformedResponse(e) => write!(f, "malformed response: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for IqError {}
+
+/// A typed IQ query.
+///
+/// Implementors only describe the namespace and content of the query;
+/// `Client::query` takes care of ids, addressing, timeouts and error mapping.
+pub trait IqRequest {
+    /// Typed response produced by this query.
+    type Response: IqResponse;
+
+    /// Namespace of the query (the `xmlns` attribute).
+    fn namespace(&self) -> &str;
+
+    /// Whether this is a get or set query.
+    fn iq_type(&self) -> IqType;
+
+    /// Recipient of the query. Defaults to the WhatsApp server.
+    fn target(&self) -> JID {
+        SERVER_JID.clone()
+    }
+
+    /// Child nodes of the query.
+    fn content(&self) -> Vec<Node>;
+
+    /// Build the complete IQ node with the given request ID.
+    fn to_node(&self, id: &str) -> Node {
+        let to = self.target().to_string();
+        let mut node = match self.iq_type() {
+            IqType::Get => build_iq_get(id, self.namespace(), Some(&to)),
+            IqType::Set => build_iq_set(id, self.namespace(), Some(&to)),
+        };
+        let content = self.content();
+        if !content.is_empty() {
+            node.set_children(content);
+        }
+        node
+    }
+}
+
+/// A typed IQ response parsed from a result node.
+pub trait IqResponse: Sized {
+    /// Parse the response from an IQ result node.
+    fn from_node(node: &Node) -> Result<Self, IqError>;
+}
+
+impl IqResponse for Node {
+    fn from_node(node: &Node) -> Result<Self, IqError> {
+        Ok(node.clone())
+    }
+}
+
+impl IqResponse for () {
+    fn from_node(_node: &Node) -> Result<Self, IqError> {
+        Ok(())
+    }
+}
+
+/// Convert a raw IQ response into a typed response, mapping error stanzas to `IqError`.
+pub fn parse_iq_response<R: IqResponse>(node: &Node) -> Result<R, IqError> {
+    if is_iq_error(node) {
+        return Err(IqError::from_error_node(node));
+    }
+    if !is_iq_result(node) {
+        return Err(IqError::MalformedResponse(format!("unexpected <{}> response", node.tag)));
+    }
+    R::from_node(node)
+}
+
 /// Check if a node is an IQ result.
 pub fn is_iq_result(node: &Node) -> bool {
     node.tag == "iq" && node.get_attr_str("type") == Some("result")
@@ -118,7 +241,7 @@ pub fn get_iq_error(node: &Node) -> Option<String> {
         .map(String::from)
         .or_else(|| {
             node.get_child_by_tag("error")
-                .map(|e| e.tag.clone())
+                .map(|e| e.tag.to_string())
         })
 }
 
@@ -130,8 +253,8 @@ mod tests {
     fn test_request_tracker() {
         let tracker = RequestTracker::new();
         
-        let id = tracker.next_id();
-        let rx = tracker.register(&id);
+        let id = crate::protocol::msgid::TagGenerator::new().next();
+        let _rx = tracker.register(&id);
         
         assert_eq!(tracker.pending_count(), 1);
         
@@ -141,6 +264,19 @@ mod tests {
         assert_eq!(tracker.pending_count(), 0);
     }
 
+    #[test]
+    fn test_cancel_all_resolves_waiters() {
+        let tracker = RequestTracker::new();
+        let mut rx1 = tracker.register("1");
+        let mut rx2 = tracker.register("2");
+
+        tracker.cancel_all();
+
+        assert_eq!(tracker.pending_count(), 0);
+        assert!(matches!(rx1.try_recv(), Err(oneshot::error::TryRecvError::Closed)));
+        assert!(matches!(rx2.try_recv(), Err(oneshot::error::TryRecvError::Closed)));
+    }
+
     #[test]
     fn test_build_iq_get() {
         let node = build_iq_get("123", "w:profile:picture", Some("user@server"));
@@ -150,6 +286,48 @@ mod tests {
         assert_eq!(node.get_attr_str("xmlns"), Some("w:profile:picture"));
     }
 
+    struct PictureQuery;
+
+    impl IqRequest for PictureQuery {
+        type Response = ();
+
+        fn namespace(&self) -> &str {
+            "w:profile:picture"
+        }
+
+        fn iq_type(&self) -> IqType {
+            IqType::Get
+        }
+
+        fn content(&self) -> Vec<Node> {
+            vec![Node::new("picture")]
+        }
+    }
+
+    #[test]
+    fn test_iq_request_to_node() {
+        let node = PictureQuery.to_node("abc.1");
+
+        assert_eq!(node.get_attr_str("id"), Some("abc.1"));
+        assert_eq!(node.get_attr_str("type"), Some("get"));
+        assert_eq!(node.get_attr_str("xmlns"), Some("w:profile:picture"));
+        assert_eq!(node.get_attr_str("to"), Some("s.whatsapp.net"));
+        assert!(node.get_child_by_tag("picture").is_some());
+    }
+
+    #[test]
+    fn test_parse_iq_error_response() {
+        let mut response = Node::new("iq");
+        response.set_attr("type", "error");
+        let mut error = Node::new("error");
+        error.set_attr("code", "404");
+        error.set_attr("text", "item-not-found");
+        response.add_child(error);
+
+        let result: Result<(), IqError> = parse_iq_response(&response);
+        assert_eq!(result, Err(IqError::ServerError { code: 404, text: "item-not-found".to_string() }));
+    }
+
     #[test]
     fn test_is_iq_result() {
         let mut result = Node::new("iq");
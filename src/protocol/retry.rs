@@ -0,0 +1,207 @@
+//! Recovery from messages we cannot decrypt.
+//!
+//! When the `<enc>` of a message cannot be read, we answer with a retry
+//! receipt carrying our registration ID and keys, including a fresh
+//! one-time pre-key, so the sender can start a new session and send the
+//! message again. Meanwhile the app gets an `UndecryptableMessage` event
+//! to show a "waiting for this message" placeholder. A message is only
+//! retried a few times before we give up on it.
+//!
+//! Messages are not decrypted with Signal sessions yet, so a resent
+//! message could not be read either. Retry receipts are therefore only
+//! sent with `ClientConfig::send_retry_receipts`.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use prost::Message as _;
+
+use crate::binary::Node;
+use crate::crypto::{KeyPair, PreKey};
+use crate::proto::E2eMessage;
+use crate::store::{PreKeyRecord, PreKeyStore, StoreResult};
+use crate::types::{MessageInfo, JID};
+
+/// Retry receipts sent for one message before giving up on it.
+pub const MAX_RETRIES: u32 = 5;
+
+/// How long the retries of a message are counted after the first.
+const RETRY_WINDOW_SECS: i64 = 60 * 60;
+
+/// `<enc>` types of pairwise (`pkmsg`, `msg`) and group (`skmsg`) messages.
+const ENC_TYPES: [&str; 3] = ["pkmsg", "msg", "skmsg"];
+
+/// Whether a message carries an `<enc>` we cannot read.
+///
+/// Messages are not decrypted with Signal sessions yet, so only an
+/// `<enc>` holding a plain message can be read.
+pub(crate) fn has_undecryptable_enc(node: &Node) -> bool {
+    node.get_children_by_tag("enc").into_iter().any(|enc| {
+        enc.get_attr_str("type").is_some_and(|enc_type| ENC_TYPES.contains(&enc_type))
+            && enc.get_bytes().is_none_or(|bytes| E2eMessage::decode(bytes).is_err())
+    })
+}
+
+fn bytes_node(tag: &'static str, bytes: &[u8]) -> Node {
+    let mut node = Node::new(tag);
+    node.set_bytes(bytes.to_vec());
+    node
+}
+
+/// A `<key>` or `<skey>` node: a 3-byte ID and a public key.
+fn key_node(tag: &'static str, pre_key: &PreKey) -> Node {
+    let mut node = Node::new(tag);
+    node.add_child(bytes_node("id", &pre_key.key_id.to_be_bytes()[1..]));
+    node.add_child(bytes_node("value", &pre_key.key_pair.public));
+    if let Some(signature) = &pre_key.signature {
+        node.add_child(bytes_node("signature", signature));
+    }
+    node
+}
+
+/// Build a retry receipt asking the sender of a message to send it again.
+///
+/// `count` is how many times this message has been retried, starting at 1.
+pub fn build_retry_receipt(
+    info: &MessageInfo,
+    count: u32,
+    registration_id: u32,
+    identity: &KeyPair,
+    signed_pre_key: &PreKey,
+    pre_key: &PreKey,
+) -> Node {
+    let mut retry = Node::new("retry");
+    retry.set_attr("count", count.to_string());
+    retry.set_attr("id", info.id.clone());
+    retry.set_attr("t", info.timestamp.to_string());
+    retry.set_attr("v", "1");
+
+    let mut keys = Node::new("keys");
+    keys.add_child(bytes_node("type", &[0x05]));
+    keys.add_child(bytes_node("identity", &identity.public));
+    keys.add_child(key_node("key", pre_key));
+    keys.add_child(key_node("skey", signed_pre_key));
+
+    let mut receipt = Node::new("receipt");
+    receipt.set_attr("id", info.id.clone());
+    receipt.set_attr("type", "retry");
+    receipt.set_attr("to", info.chat.clone());
+    if info.is_group {
+        receipt.set_attr("participant", info.sender.clone());
+    }
+    receipt.add_child(retry);
+    receipt.add_child(bytes_node("registration", &registration_id.to_be_bytes()));
+    receipt.add_child(keys);
+    receipt
+}
+
+/// Generate a one-time pre-key with an unused ID and store it, so the
+/// session the sender builds from it can be completed when its message
+/// arrives.
+pub(crate) fn fresh_pre_key<S: PreKeyStore + ?Sized>(store: &S) -> StoreResult<PreKey> {
+    loop {
+        // Pre-key IDs are sent as 3 bytes
+        let id = rand::random::<u32>() & 0xFF_FFFF;
+        if id == 0 || store.get_pre_key(id)?.is_some() {
+            continue;
+        }
+        let pre_key = PreKey::new(id);
+        store.put_pre_key(&PreKeyRecord::from(&pre_key))?;
+        return Ok(pre_key);
+    }
+}
+
+/// Retry receipts sent per message, with when the first was sent.
+///
+/// Counts are forgotten an hour after the first retry, by which time the
+/// sender has given up too.
+#[derive(Default)]
+pub(crate) struct RetryCounter(Mutex<HashMap<(JID, String), (u32, i64)>>);
+
+impl RetryCounter {
+    /// Count another retry of a message at unix time `now`, returning its
+    /// number, or `None` once the message has been retried `MAX_RETRIES`
+    /// times.
+    pub(crate) fn next(&self, chat: &JID, id: &str, now: i64) -> Option<u32> {
+        let mut counts = self.0.lock().unwrap();
+        counts.retain(|_, (_, first)| now - *first < RETRY_WINDOW_SECS);
+        let (count, _) = counts.entry((chat.clone(), id.to_string())).or_insert((0, now));
+        if *count >= MAX_RETRIES {
+            return None;
+        }
+        *count += 1;
+        Some(*count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+
+    fn enc(enc_type: &str, bytes: Vec<u8>) -> Node {
+        let mut enc = Node::new("enc");
+        enc.set_attr("type", enc_type.to_string());
+        enc.set_bytes(bytes);
+        let mut message = Node::new("message");
+        message.add_child(enc);
+        message
+    }
+
+    #[test]
+    fn test_undecryptable_enc() {
+        let plain = E2eMessage { conversation: Some("hi".to_string()), ..Default::default() };
+        assert!(!has_undecryptable_enc(&enc("msg", plain.encode_to_vec())));
+        assert!(has_undecryptable_enc(&enc("pkmsg", vec![0x33, 0xFF, 0x01])));
+        assert!(has_undecryptable_enc(&enc("skmsg", vec![0xFF; 8])));
+        // Other payloads, such as history sync notifications, are not ours to retry
+        assert!(!has_undecryptable_enc(&enc("unknown", vec![0xFF; 8])));
+        assert!(!has_undecryptable_enc(&Node::new("message")));
+    }
+
+    #[test]
+    fn test_retry_receipt() {
+        let store = MemoryStore::new();
+        let identity = KeyPair::generate();
+        let signed = PreKey::new_signed(1, &identity);
+        let pre_key = fresh_pre_key(&store).unwrap();
+        assert!(store.get_pre_key(pre_key.key_id).unwrap().is_some());
+
+        let info = MessageInfo {
+            id: "3EB0".to_string(),
+            sender: JID::new_ad("1", 0, 2),
+            chat: JID::new("123", "g.us"),
+            is_from_me: false,
+            is_group: true,
+            timestamp: 1700000000,
+            push_name: None,
+            reply_to: None,
+        };
+        let receipt = build_retry_receipt(&info, 2, 0x1234, &identity, &signed, &pre_key);
+        assert_eq!(receipt.get_attr_str("type"), Some("retry"));
+        assert_eq!(receipt.get_attr_jid("participant"), Some(&info.sender));
+        let retry = receipt.get_child_by_tag("retry").unwrap();
+        assert_eq!((retry.get_attr_str("count"), retry.get_attr_str("t")), (Some("2"), Some("1700000000")));
+        assert_eq!(receipt.get_child_by_tag("registration").unwrap().get_bytes(), Some(&[0, 0, 0x12, 0x34][..]));
+
+        let keys = receipt.get_child_by_tag("keys").unwrap();
+        let key = keys.get_child_by_tag("key").unwrap();
+        assert_eq!(key.get_child_by_tag("id").unwrap().get_bytes(), Some(&pre_key.key_id.to_be_bytes()[1..]));
+        assert!(key.get_child_by_tag("signature").is_none());
+        assert!(keys.get_child_by_tag("skey").unwrap().get_child_by_tag("signature").is_some());
+    }
+
+    #[test]
+    fn test_retries_are_capped() {
+        let counter = RetryCounter::default();
+        let chat = JID::new("1", "s.whatsapp.net");
+        let counts: Vec<_> = (0..=MAX_RETRIES).map(|_| counter.next(&chat, "A", 0)).collect();
+        assert_eq!(counts[0], Some(1));
+        assert_eq!(counts[MAX_RETRIES as usize - 1], Some(MAX_RETRIES));
+        assert_eq!(counts[MAX_RETRIES as usize], None);
+        assert_eq!(counter.next(&chat, "B", 0), Some(1));
+
+        // Old counts are forgotten
+        assert_eq!(counter.next(&chat, "C", RETRY_WINDOW_SECS), Some(1));
+        assert_eq!(counter.0.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,262 @@
+//! Sender keys for group messages.
+//!
+//! Group messages are encrypted once with our sender key, which is
+//! distributed to every member. When a member leaves, our key is rotated so
+//! the old one stops being used, and the new key is distributed to the
+//! remaining members on the next send.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use prost::Message as _;
+
+use crate::crypto::KeyPair;
+use crate::proto::SenderKeyDistributionMessage;
+use crate::store::{SenderKeyName, Store, StoreError, StoreResult};
+use crate::types::JID;
+
+/// Our sender key for one group.
+#[derive(Clone)]
+pub struct SenderKey {
+    pub key_id: u32,
+    pub iteration: u32,
+    pub chain_key: [u8; 32],
+    pub signing_key: KeyPair,
+}
+
+impl SenderKey {
+    /// Length of a serialized sender key.
+    const ENCODED_LEN: usize = 4 + 4 + 32 + 32;
+
+    /// Generate a fresh sender key.
+    pub fn generate() -> Self {
+        Self {
+            // Key IDs are 31-bit in libsignal
+            key_id: rand::random::<u32>() >> 1,
+            iteration: 0,
+            chain_key: rand::random(),
+            signing_key: KeyPair::generate(),
+        }
+    }
+
+    /// Serialize for the sender key store.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(Self::ENCODED_LEN);
+        bytes.extend_from_slice(&self.key_id.to_be_bytes());
+        bytes.extend_from_slice(&self.iteration.to_be_bytes());
+        bytes.extend_from_slice(&self.chain_key);
+        bytes.extend_from_slice(self.signing_key.private_key());
+        bytes
+    }
+
+    /// Deserialize from the sender key store.
+    pub fn from_bytes(bytes: &[u8]) -> StoreResult<Self> {
+        if bytes.len() != Self::ENCODED_LEN {
+            return Err(StoreError::SerializationError("invalid sender key length".to_string()));
+        }
+
+        let mut chain_key = [0u8; 32];
+        chain_key.copy_from_slice(&bytes[8..40]);
+        let mut private = [0u8; 32];
+        private.copy_from_slice(&bytes[40..72]);
+
+        Ok(Self {
+            key_id: u32::from_be_bytes(bytes[0..4].try_into().unwrap()),
+            iteration: u32::from_be_bytes(bytes[4..8].try_into().unwrap()),
+            chain_key,
+            signing_key: KeyPair::from_private_key(private),
+        })
+    }
+
+    /// Build the distribution message members need to decrypt our messages.
+    pub fn distribution_message(&self) -> Vec<u8> {
+        // Public keys carry the Curve25519 type prefix
+        let mut signing_key = Vec::with_capacity(33);
+        signing_key.push(5);
+        signing_key.extend_from_slice(self.signing_key.public_key());
+
+        SenderKeyDistributionMessage {
+            id: Some(self.key_id),
+            iteration: Some(self.iteration),
+            chain_key: Some(self.chain_key.to_vec()),
+            signing_key: Some(signing_key),
+        }
+        .encode_to_vec()
+    }
+}
+
+/// What to do before sending to a group.
+pub struct GroupSendPlan {
+    /// Our current sender key
+    pub sender_key: SenderKey,
+    /// Devices that have not received this key yet
+    pub needs_key: Vec<JID>,
+}
+
+/// Distribution state of our sender key in one group.
+#[derive(Default)]
+struct GroupKeyState {
+    /// Devices that received our current key
+    distributed_to: HashSet<JID>,
+    /// Our key must be replaced before the next send
+    rotate: bool,
+}
+
+/// Tracks our sender keys and who has received them.
+#[derive(Default)]
+pub struct SenderKeyManager {
+    groups: Mutex<HashMap<JID, GroupKeyState>>,
+}
+
+impl SenderKeyManager {
+    /// Create an empty manager.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get our sender key for a group, rotating it if needed, and the
+    /// devices it still has to be distributed to.
+    ///
+    /// `devices` are every device of every member, including our own other
+    /// devices; `own` is this device, which is skipped.
+    pub fn prepare_send(
+        &self,
+        store: &dyn Store,
+        group: &JID,
+        own: &JID,
+        devices: &[JID],
+    ) -> StoreResult<GroupSendPlan> {
+        let name = SenderKeyName::new(group.clone(), own.clone());
+        let mut groups = self.groups.lock().unwrap();
+        let state = groups.entry(group.clone()).or_default();
+
+        let existing = if state.rotate {
+            None
+        } else {
+            store.get_sender_key_by_name(&name)?
+                .map(|bytes| SenderKey::from_bytes(&bytes))
+                .transpose()?
+        };
+
+        let sender_key = match existing {
+            Some(key) => key,
+            None => {
+                let key = SenderKey::generate();
+                store.put_sender_key_by_name(&name, &key.to_bytes())?;
+                state.distributed_to.clear();
+                state.rotate = false;
+                key
+            }
+        };
+
+        let needs_key = devices.iter()
+            .filter(|device| *device != own && !state.distributed_to.contains(device))
+            .cloned()
+            .collect();
+
+        Ok(GroupSendPlan { sender_key, needs_key })
+    }
+
+    /// Record that devices received our current key.
+    pub fn mark_distributed(&self, group: &JID, devices: &[JID]) {
+        let mut groups = self.groups.lock().unwrap();
+        let state = groups.entry(group.clone()).or_default();
+        state.distributed_to.extend(devices.iter().cloned());
+    }
+
+    /// Handle members leaving a group: forget their sender keys and rotate
+    /// ours so they can't read future messages.
+    pub fn revoke(&self, store: &dyn Store, group: &JID, departed: &[JID]) -> StoreResult<()> {
+        for jid in departed {
+            store.delete_user_sender_keys(group, jid)?;
+        }
+
+        let mut groups = self.groups.lock().unwrap();
+        let state = groups.entry(group.clone()).or_default();
+        state.rotate = true;
+        state.distributed_to.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::{MemoryStore, SenderKeyStore};
+
+    fn jid(user: &str) -> JID {
+        JID::new(user, "s.whatsapp.net")
+    }
+
+    #[test]
+    fn test_sender_key_roundtrip() {
+        let key = SenderKey::generate();
+        let decoded = SenderKey::from_bytes(&key.to_bytes()).unwrap();
+
+        assert_eq!(decoded.key_id, key.key_id);
+        assert_eq!(decoded.chain_key, key.chain_key);
+        assert_eq!(decoded.signing_key.public_key(), key.signing_key.public_key());
+        assert!(SenderKey::from_bytes(&[0; 3]).is_err());
+
+        let message = SenderKeyDistributionMessage::decode(key.distribution_message().as_slice()).unwrap();
+        assert_eq!(message.id, Some(key.key_id));
+        assert_eq!(message.signing_key.unwrap().len(), 33);
+    }
+
+    #[test]
+    fn test_key_is_distributed_once() {
+        let store = MemoryStore::new();
+        let manager = SenderKeyManager::new();
+        let group = JID::new("123", "g.us");
+        let members = vec![jid("me"), jid("a"), jid("b")];
+
+        let plan = manager.prepare_send(&store, &group, &jid("me"), &members).unwrap();
+        assert_eq!(plan.needs_key, vec![jid("a"), jid("b")]);
+        manager.mark_distributed(&group, &plan.needs_key);
+
+        let again = manager.prepare_send(&store, &group, &jid("me"), &members).unwrap();
+        assert!(again.needs_key.is_empty());
+        assert_eq!(again.sender_key.key_id, plan.sender_key.key_id);
+    }
+
+    #[test]
+    fn test_key_reaches_every_device() {
+        let store = MemoryStore::new();
+        let manager = SenderKeyManager::new();
+        let group = JID::new("123", "g.us");
+        let own = JID::new_ad("me", 0, 2);
+        let devices = vec![jid("me"), own.clone(), jid("a"), JID::new_ad("a", 0, 5)];
+
+        // Our phone gets the key too, but not this device
+        let plan = manager.prepare_send(&store, &group, &own, &devices).unwrap();
+        assert_eq!(plan.needs_key, vec![jid("me"), jid("a"), JID::new_ad("a", 0, 5)]);
+        manager.mark_distributed(&group, &plan.needs_key);
+
+        // A newly linked device of a member still needs it
+        let linked = JID::new_ad("a", 0, 9);
+        let plan = manager.prepare_send(&store, &group, &own, &[devices, vec![linked.clone()]].concat()).unwrap();
+        assert_eq!(plan.needs_key, vec![linked]);
+    }
+
+    #[test]
+    fn test_departure_rotates_key() {
+        let store = MemoryStore::new();
+        let manager = SenderKeyManager::new();
+        let group = JID::new("123", "g.us");
+        let theirs = SenderKeyName::new(group.clone(), JID::new_ad("b", 0, 3));
+
+        let plan = manager.prepare_send(&store, &group, &jid("me"), &[jid("a"), jid("b")]).unwrap();
+        manager.mark_distributed(&group, &plan.needs_key);
+        store.put_sender_key_by_name(&theirs, b"their key").unwrap();
+
+        manager.revoke(&store, &group, &[jid("b")]).unwrap();
+        assert!(store.get_sender_key_by_name(&theirs).unwrap().is_none());
+
+        // The remaining member gets a fresh key on the next send
+        let rotated = manager.prepare_send(&store, &group, &jid("me"), &[jid("a")]).unwrap();
+        assert_ne!(rotated.sender_key.chain_key, plan.sender_key.chain_key);
+        assert_eq!(rotated.needs_key, vec![jid("a")]);
+
+        let stored = store.get_sender_key_by_name(&SenderKeyName::new(group.clone(), jid("me"))).unwrap().unwrap();
+        assert_eq!(SenderKey::from_bytes(&stored).unwrap().chain_key, rotated.sender_key.chain_key);
+    }
+}
@@ -0,0 +1,195 @@
+//! Who sees status posts.
+//!
+//! Status updates go to the account's contacts, to its contacts minus a
+//! list of exclusions, or only to a list of chosen users. The server keeps
+//! one list of each kind and marks the one in use as the default.
+
+use crate::binary::Node;
+use crate::protocol::client::{Client, ClientError};
+use crate::protocol::request::{IqError, IqRequest, IqResponse, IqType};
+use crate::types::JID;
+
+/// Kind of status audience.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatusPrivacyType {
+    /// All contacts
+    Contacts,
+    /// All contacts except the listed users
+    ContactsExcept,
+    /// Only the listed users
+    OnlyShareWith,
+}
+
+impl StatusPrivacyType {
+    fn as_str(self) -> &'static str {
+        match self {
+            StatusPrivacyType::Contacts => "contacts",
+            StatusPrivacyType::ContactsExcept => "blacklist",
+            StatusPrivacyType::OnlyShareWith => "whitelist",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "contacts" => Some(StatusPrivacyType::Contacts),
+            "blacklist" => Some(StatusPrivacyType::ContactsExcept),
+            "whitelist" => Some(StatusPrivacyType::OnlyShareWith),
+            _ => None,
+        }
+    }
+}
+
+/// One status audience list.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StatusPrivacy {
+    pub kind: StatusPrivacyType,
+    /// Users excluded or included, by kind; empty for `Contacts`
+    pub users: Vec<JID>,
+    /// Whether this is the audience in use
+    pub is_default: bool,
+}
+
+/// The status audience lists of the account.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StatusPrivacySettings {
+    pub lists: Vec<StatusPrivacy>,
+}
+
+impl StatusPrivacySettings {
+    /// The audience in use, if the server marked one.
+    pub fn current(&self) -> Option<&StatusPrivacy> {
+        self.lists.iter().find(|list| list.is_default)
+    }
+}
+
+/// Query for the status audience lists.
+pub struct GetStatusPrivacyRequest;
+
+impl IqRequest for GetStatusPrivacyRequest {
+    type Response = StatusPrivacySettings;
+
+    fn namespace(&self) -> &str {
+        "status"
+    }
+
+    fn iq_type(&self) -> IqType {
+        IqType::Get
+    }
+
+    fn content(&self) -> Vec<Node> {
+        vec![Node::new("privacy")]
+    }
+}
+
+impl IqResponse for StatusPrivacySettings {
+    fn from_node(node: &Node) -> Result<Self, IqError> {
+        let privacy = node.get_child_by_tag("privacy")
+            .ok_or_else(|| IqError::MalformedResponse("missing <privacy>".to_string()))?;
+        let lists = privacy.get_children_by_tag("list").into_iter()
+            .filter_map(|list| {
+                let Some(kind) = list.get_attr_str("type").and_then(StatusPrivacyType::parse) else {
+                    log::warn!("skipping status privacy list of unknown type {:?}", list.get_attr_str("type"));
+                    return None;
+                };
+                Some(StatusPrivacy {
+                    kind,
+                    users: list.get_children_by_tag("user").into_iter()
+                        .filter_map(|user| user.parse_attr_jid("jid"))
+                        .collect(),
+                    is_default: list.get_attr_str("default") == Some("true"),
+                })
+            })
+            .collect();
+        Ok(StatusPrivacySettings { lists })
+    }
+}
+
+/// Set the status audience, making it the default.
+pub struct SetStatusPrivacyRequest {
+    pub kind: StatusPrivacyType,
+    /// Users to exclude or include; ignored for `Contacts`
+    pub users: Vec<JID>,
+}
+
+impl IqRequest for SetStatusPrivacyRequest {
+    type Response = ();
+
+    fn namespace(&self) -> &str {
+        "status"
+    }
+
+    fn iq_type(&self) -> IqType {
+        IqType::Set
+    }
+
+    fn content(&self) -> Vec<Node> {
+        let mut list = Node::new("list");
+        list.set_attr("type", self.kind.as_str());
+        if self.kind != StatusPrivacyType::Contacts {
+            for jid in &self.users {
+                let mut user = Node::new("user");
+                user.set_attr("jid", jid.to_non_ad());
+                list.add_child(user);
+            }
+        }
+        let mut privacy = Node::new("privacy");
+        privacy.add_child(list);
+        vec![privacy]
+    }
+}
+
+impl Client {
+    /// Get the status audience lists, and which one is in use.
+    pub async fn get_status_privacy(&self) -> Result<StatusPrivacySettings, ClientError> {
+        self.query(&GetStatusPrivacyRequest).await
+    }
+
+    /// Set who sees status posts: all contacts, all contacts but `users`,
+    /// or only `users`.
+    pub async fn set_status_privacy(&self, kind: StatusPrivacyType, users: &[JID]) -> Result<(), ClientError> {
+        self.query(&SetStatusPrivacyRequest { kind, users: users.to_vec() }).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_privacy_requests() {
+        let user = JID::new("1", "s.whatsapp.net");
+        let node = SetStatusPrivacyRequest { kind: StatusPrivacyType::OnlyShareWith, users: vec![JID::new_ad("1", 0, 2)] }
+            .to_node("1");
+        assert_eq!(node.get_attr_str("xmlns"), Some("status"));
+        assert_eq!(node.get_attr_str("type"), Some("set"));
+        let list = node.get_child_by_tag("privacy").unwrap().get_child_by_tag("list").unwrap();
+        assert_eq!(list.get_attr_str("type"), Some("whitelist"));
+        assert_eq!(list.get_child_by_tag("user").unwrap().parse_attr_jid("jid"), Some(user.clone()));
+
+        let node = SetStatusPrivacyRequest { kind: StatusPrivacyType::Contacts, users: vec![user.clone()] }.to_node("2");
+        let list = node.get_child_by_tag("privacy").unwrap().get_child_by_tag("list").unwrap();
+        assert!(list.get_child_by_tag("user").is_none());
+
+        let mut excluded = Node::new("user");
+        excluded.set_attr("jid", user.clone());
+        let mut blacklist = Node::new("list");
+        blacklist.set_attr("type", "blacklist");
+        blacklist.set_attr("default", "true");
+        blacklist.add_child(excluded);
+        let mut contacts = Node::new("list");
+        contacts.set_attr("type", "contacts");
+        let mut privacy = Node::new("privacy");
+        privacy.add_child(contacts);
+        privacy.add_child(blacklist);
+        let mut response = Node::new("iq");
+        response.add_child(privacy);
+
+        let settings = StatusPrivacySettings::from_node(&response).unwrap();
+        assert_eq!(settings.lists.len(), 2);
+        assert_eq!(settings.current(), Some(&StatusPrivacy {
+            kind: StatusPrivacyType::ContactsExcept,
+            users: vec![user],
+            is_default: true,
+        }));
+    }
+}
@@ -0,0 +1,107 @@
+//! Sticker pack messages.
+//!
+//! A pack lists its stickers in the message and ships their files in one
+//! encrypted zip, downloaded like any other media.
+
+use prost::Message as _;
+
+use crate::binary::Node;
+use crate::media::{unzip_sticker_pack, MediaError, StickerFile};
+use crate::proto::E2eMessage;
+use crate::protocol::client::{Client, ClientError};
+use crate::types::{MediaDetails, Message, MessageContent, PackSticker};
+
+/// Extract a sticker pack from a received message node.
+pub fn parse_sticker_pack_message(node: &Node) -> Option<MessageContent> {
+    let payload = node.get_child_by_tag("enc")?.get_bytes()?;
+    let pack = E2eMessage::decode(payload).ok()?.sticker_pack_message?;
+
+    Some(MessageContent::StickerPack {
+        pack_id: pack.sticker_pack_id.unwrap_or_default(),
+        name: pack.name.unwrap_or_default(),
+        publisher: pack.publisher.unwrap_or_default(),
+        description: pack.pack_description,
+        stickers: pack.stickers.into_iter()
+            .map(|sticker| PackSticker {
+                file_name: sticker.file_name.unwrap_or_default(),
+                emojis: sticker.emojis,
+                is_animated: sticker.is_animated.unwrap_or(false),
+                mimetype: sticker.mimetype.unwrap_or_else(|| "image/webp".to_string()),
+            })
+            .collect(),
+        media: MediaDetails {
+            direct_path: pack.direct_path,
+            media_key: pack.media_key,
+            file_sha256: pack.file_sha256,
+            file_enc_sha256: pack.file_enc_sha256,
+            file_length: pack.file_length,
+            local_path: None,
+        },
+    })
+}
+
+/// Files of a pack's zip that are stickers it lists, in the pack's order.
+fn listed_stickers(stickers: &[PackSticker], mut files: Vec<StickerFile>) -> Vec<StickerFile> {
+    stickers.iter()
+        .filter_map(|sticker| {
+            let at = files.iter().position(|file| file.file_name == sticker.file_name)?;
+            Some(files.swap_remove(at))
+        })
+        .collect()
+}
+
+impl Client {
+    /// Download and decrypt a sticker pack, returning the file of each
+    /// sticker it lists, in order. Stickers missing from the zip are
+    /// left out.
+    pub async fn download_sticker_pack(&self, msg: &Message) -> Result<Vec<StickerFile>, ClientError> {
+        let MessageContent::StickerPack { stickers, .. } = &msg.content else {
+            return Err(ClientError::MediaFailed(MediaError::MissingUrl));
+        };
+        let data = self.download_media(msg).await?;
+        Ok(listed_stickers(stickers, unzip_sticker_pack(&data)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::{StickerPackMessage, StickerPackSticker};
+    use crate::protocol::message::{build_e2e_message, parse_message};
+    use crate::types::JID;
+
+    #[test]
+    fn test_parse_sticker_pack() {
+        let sticker = |name: &str, emoji: &str| StickerPackSticker {
+            file_name: Some(name.to_string()),
+            emojis: vec![emoji.to_string()],
+            ..Default::default()
+        };
+        let chat = JID::new("1", "s.whatsapp.net");
+        let mut node = build_e2e_message(&chat, "PACK1", "media", Some("sticker_pack"), &E2eMessage {
+            sticker_pack_message: Some(StickerPackMessage {
+                sticker_pack_id: Some("pack-1".to_string()),
+                name: Some("Cats".to_string()),
+                stickers: vec![sticker("b.webp", "😺"), sticker("a.webp", "😸")],
+                direct_path: Some("/v/t62/pack".to_string()),
+                media_key: Some(vec![7; 32]),
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        node.set_attr("from", chat.to_string());
+
+        let (_, content) = parse_message(&node, 0).unwrap();
+        let MessageContent::StickerPack { pack_id, name, stickers, media, .. } = content else {
+            panic!("not a sticker pack: {:?}", content);
+        };
+        assert_eq!((pack_id.as_str(), name.as_str()), ("pack-1", "Cats"));
+        assert_eq!(stickers[0].emojis, vec!["😺"]);
+        assert_eq!(stickers[1].mimetype, "image/webp");
+        assert_eq!(media.direct_path.as_deref(), Some("/v/t62/pack"));
+
+        let file = |name: &str| StickerFile { file_name: name.to_string(), data: name.as_bytes().to_vec() };
+        let files = listed_stickers(&stickers, vec![file("a.webp"), file("tray.png"), file("b.webp")]);
+        assert_eq!(files, vec![file("b.webp"), file("a.webp")]);
+    }
+}
@@ -0,0 +1,165 @@
+//! Message templates with `{name}` placeholders.
+//!
+//! Used to personalise bulk sends: one template is rendered with a
+//! different set of variables per recipient. `{{` and `}}` stand for
+//! literal braces.
+
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+
+/// Error parsing or rendering a template.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TemplateError {
+    /// A `{` without a matching `}`, at this byte offset
+    Unclosed(usize),
+    /// A `}` without a preceding `{`, at this byte offset
+    UnmatchedClose(usize),
+    /// A placeholder with no name, at this byte offset
+    EmptyPlaceholder(usize),
+    /// Placeholders without a value
+    MissingVariables(Vec<String>),
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TemplateError::Unclosed(pos) => write!(f, "unclosed placeholder at {}", pos),
+            TemplateError::UnmatchedClose(pos) => write!(f, "unmatched '}}' at {}", pos),
+            TemplateError::EmptyPlaceholder(pos) => write!(f, "empty placeholder at {}", pos),
+            TemplateError::MissingVariables(names) => write!(f, "missing variables: {}", names.join(", ")),
+        }
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+/// Piece of a parsed template.
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    Literal(String),
+    Placeholder(String),
+}
+
+/// A parsed message template.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+impl Template {
+    /// Parse a template such as `"Hello {name}, your code is {code}"`.
+    pub fn parse(source: &str) -> Result<Self, TemplateError> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut chars = source.char_indices().peekable();
+
+        while let Some((pos, c)) = chars.next() {
+            match c {
+                '{' if chars.peek().map(|&(_, c)| c) == Some('{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek().map(|&(_, c)| c) == Some('}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let rest = &source[pos + 1..];
+                    let len = rest.find('}').ok_or(TemplateError::Unclosed(pos))?;
+                    let name = rest[..len].trim();
+                    if name.is_empty() {
+                        return Err(TemplateError::EmptyPlaceholder(pos));
+                    }
+                    if name.contains('{') {
+                        return Err(TemplateError::Unclosed(pos));
+                    }
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(Part::Placeholder(name.to_string()));
+                    // Skip the name and the closing brace
+                    while chars.next_if(|&(i, _)| i <= pos + 1 + len).is_some() {}
+                }
+                '}' => return Err(TemplateError::UnmatchedClose(pos)),
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+        Ok(Self { parts })
+    }
+
+    /// Names of the placeholders, sorted and without duplicates.
+    pub fn placeholders(&self) -> Vec<&str> {
+        let names: BTreeSet<&str> = self.parts.iter()
+            .filter_map(|part| match part {
+                Part::Placeholder(name) => Some(name.as_str()),
+                Part::Literal(_) => None,
+            })
+            .collect();
+        names.into_iter().collect()
+    }
+
+    /// Fill in the placeholders.
+    ///
+    /// Fails listing every placeholder without a value. Unused variables
+    /// are ignored.
+    pub fn render(&self, vars: &HashMap<String, String>) -> Result<String, TemplateError> {
+        let missing: Vec<String> = self.placeholders().into_iter()
+            .filter(|name| !vars.contains_key(*name))
+            .map(str::to_string)
+            .collect();
+        if !missing.is_empty() {
+            return Err(TemplateError::MissingVariables(missing));
+        }
+
+        Ok(self.parts.iter()
+            .map(|part| match part {
+                Part::Literal(text) => text.as_str(),
+                Part::Placeholder(name) => vars[name].as_str(),
+            })
+            .collect())
+    }
+}
+
+/// Parse and render a template in one go.
+pub fn render(template: &str, vars: &HashMap<String, String>) -> Result<String, TemplateError> {
+    Template::parse(template)?.render(vars)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn test_render() {
+        let text = render("Hello {name}, your code is { code }.", &vars(&[("name", "Ana"), ("code", "4711")]));
+        assert_eq!(text.unwrap(), "Hello Ana, your code is 4711.");
+
+        let text = render("{{literal}} {x}{x}", &vars(&[("x", "é")]));
+        assert_eq!(text.unwrap(), "{literal} éé");
+    }
+
+    #[test]
+    fn test_missing_variables() {
+        let template = Template::parse("{b} {a} {b} {c}").unwrap();
+        assert_eq!(template.placeholders(), ["a", "b", "c"]);
+        assert_eq!(
+            template.render(&vars(&[("b", "1")])),
+            Err(TemplateError::MissingVariables(vec!["a".to_string(), "c".to_string()])),
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(Template::parse("Hi {name"), Err(TemplateError::Unclosed(3)));
+        assert_eq!(Template::parse("Hi {a{b}"), Err(TemplateError::Unclosed(3)));
+        assert_eq!(Template::parse("Hi {}"), Err(TemplateError::EmptyPlaceholder(3)));
+        assert_eq!(Template::parse("Hi }"), Err(TemplateError::UnmatchedClose(3)));
+    }
+}
@@ -0,0 +1,128 @@
+//! Typing simulation.
+//!
+//! Bots that answer instantly stand out. `TypingDuration` picks how long
+//! to show "typing..." before a reply, scaled by the reply length.
+
+use std::time::Duration;
+
+use crate::protocol::clock::{RandomSource, SystemRandom};
+
+/// How long to type before sending a message.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TypingDuration {
+    /// Always type for the same duration
+    Fixed(Duration),
+    /// Type at a steady speed, clamped to a range
+    Linear {
+        /// Typing speed
+        chars_per_second: f64,
+        /// Shortest duration
+        min: Duration,
+        /// Longest duration
+        max: Duration,
+    },
+    /// Type at a steady speed plus an exponentially distributed pause,
+    /// clamped to a range
+    Exponential {
+        /// Typing speed
+        chars_per_second: f64,
+        /// Average pause added on top of typing
+        mean_pause: Duration,
+        /// Shortest duration
+        min: Duration,
+        /// Longest duration
+        max: Duration,
+    },
+}
+
+impl Default for TypingDuration {
+    fn default() -> Self {
+        TypingDuration::Exponential {
+            chars_per_second: 5.0,
+            mean_pause: Duration::from_millis(800),
+            min: Duration::from_secs(1),
+            max: Duration::from_secs(10),
+        }
+    }
+}
+
+impl TypingDuration {
+    /// Pick a typing duration for `text`.
+    pub fn duration_for(&self, text: &str) -> Duration {
+        self.duration_from(text, &SystemRandom)
+    }
+
+    /// Pick a typing duration for `text` from the given randomness.
+    pub fn duration_from(&self, text: &str, rng: &dyn RandomSource) -> Duration {
+        self.duration_with_sample(text, rng.next_f64())
+    }
+
+    /// Typing duration for `text`, given a uniform sample in `[0, 1)`
+    /// driving the random pause.
+    fn duration_with_sample(&self, text: &str, sample: f64) -> Duration {
+        let chars = text.chars().count() as f64;
+        let typing = |chars_per_second: f64| {
+            if chars_per_second > 0.0 {
+                // Clamped to `max` below, like the pause
+                Duration::try_from_secs_f64(chars / chars_per_second).unwrap_or(Duration::MAX)
+            } else {
+                Duration::ZERO
+            }
+        };
+
+        match *self {
+            TypingDuration::Fixed(duration) => duration,
+            TypingDuration::Linear { chars_per_second, min, max } => {
+                typing(chars_per_second).clamp(min, max.max(min))
+            }
+            TypingDuration::Exponential { chars_per_second, mean_pause, min, max } => {
+                let pause = mean_pause.as_secs_f64() * -(1.0 - sample).ln();
+                let pause = Duration::try_from_secs_f64(pause).unwrap_or(max);
+                typing(chars_per_second).saturating_add(pause).clamp(min, max.max(min))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_duration_scales_with_length() {
+        let strategy = TypingDuration::Linear {
+            chars_per_second: 10.0,
+            min: Duration::from_millis(500),
+            max: Duration::from_secs(5),
+        };
+
+        assert_eq!(strategy.duration_for("hi"), Duration::from_millis(500));
+        assert_eq!(strategy.duration_for(&"a".repeat(20)), Duration::from_secs(2));
+        assert_eq!(strategy.duration_for(&"a".repeat(500)), Duration::from_secs(5));
+
+        // Too slow to represent is cut off at the maximum, not a panic
+        let slow = TypingDuration::Linear {
+            chars_per_second: 1e-300,
+            min: Duration::from_millis(500),
+            max: Duration::from_secs(5),
+        };
+        assert_eq!(slow.duration_for("hi"), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_exponential_pause() {
+        let strategy = TypingDuration::Exponential {
+            chars_per_second: 10.0,
+            mean_pause: Duration::from_secs(1),
+            min: Duration::ZERO,
+            max: Duration::from_secs(30),
+        };
+        let text = "a".repeat(10);
+
+        assert_eq!(strategy.duration_with_sample(&text, 0.0), Duration::from_secs(1));
+        let median = strategy.duration_with_sample(&text, 0.5).as_secs_f64();
+        assert!((median - (1.0 + std::f64::consts::LN_2)).abs() < 1e-6);
+        // The tail is cut off at the maximum
+        assert_eq!(strategy.duration_with_sample(&text, 1.0), Duration::from_secs(30));
+    }
+}
@@ -0,0 +1,148 @@
+//! Unread counts.
+//!
+//! Each chat counts the messages received since it was last read. Reading
+//! happens here through `mark_read`, or on another device, which shows up
+//! as a `markChatAsRead` app state mutation.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::proto::MarkChatAsReadAction;
+use crate::types::{Message, UnreadCountChanged, JID};
+
+/// Read state of one chat.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ChatReadState {
+    /// ID of the last message marked read, if known
+    pub last_read: Option<String>,
+    pub unread_count: u32,
+}
+
+/// Read state of every chat.
+#[derive(Default)]
+pub struct UnreadTracker {
+    chats: Mutex<HashMap<JID, ChatReadState>>,
+}
+
+impl UnreadTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Read state of a chat; chats never seen have nothing unread.
+    pub fn get(&self, chat: &JID) -> ChatReadState {
+        self.chats.lock().unwrap().get(&chat.to_non_ad()).cloned().unwrap_or_default()
+    }
+
+    /// Count a received message. Sending a message, even from another
+    /// device, reads the chat.
+    pub fn message_received(&self, msg: &Message) -> Option<UnreadCountChanged> {
+        if msg.info.is_from_me {
+            return self.update(&msg.info.chat, |state| state.unread_count = 0);
+        }
+        self.update(&msg.info.chat, |state| state.unread_count += 1)
+    }
+
+    /// Record that we read a chat up to the last of `message_ids`.
+    pub fn mark_read(&self, chat: &JID, message_ids: &[String]) -> Option<UnreadCountChanged> {
+        self.update(chat, |state| {
+            state.unread_count = 0;
+            if let Some(id) = message_ids.last() {
+                state.last_read = Some(id.clone());
+            }
+        })
+    }
+
+    /// Record a chat read, or marked unread, on another device. A chat
+    /// marked unread counts at least one unread message.
+    pub fn set_read(&self, chat: &JID, read: bool) -> Option<UnreadCountChanged> {
+        self.update(chat, |state| {
+            state.unread_count = if read { 0 } else { state.unread_count.max(1) };
+        })
+    }
+
+    /// Forget the read state of every chat.
+    pub fn clear(&self) {
+        self.chats.lock().unwrap().clear();
+    }
+
+    /// Apply a change, returning the event for it if anything changed.
+    fn update(&self, chat: &JID, change: impl FnOnce(&mut ChatReadState)) -> Option<UnreadCountChanged> {
+        let chat = chat.to_non_ad();
+        let mut chats = self.chats.lock().unwrap();
+        let state = chats.entry(chat.clone()).or_default();
+        let before = state.clone();
+        change(state);
+        (*state != before).then(|| UnreadCountChanged {
+            chat,
+            unread_count: state.unread_count,
+            last_read: state.last_read.clone(),
+        })
+    }
+}
+
+/// Turn an app state `markChatAsRead` mutation into the chat and whether it
+/// is now read.
+///
+/// `index` is the mutation's decoded index, `["markChatAsRead", "<jid>"]`.
+pub fn parse_mark_chat_as_read_mutation(index: &[String], action: &MarkChatAsReadAction) -> Option<(JID, bool)> {
+    match index {
+        [kind, jid, ..] if kind == "markChatAsRead" => Some((jid.parse().ok()?, action.read?)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{MessageContent, MessageInfo};
+
+    fn message(chat: &JID, id: &str, is_from_me: bool) -> Message {
+        Message {
+            info: MessageInfo {
+                id: id.to_string(),
+                sender: chat.clone(),
+                chat: chat.clone(),
+                is_from_me,
+                is_group: false,
+                timestamp: 0,
+                push_name: None,
+                reply_to: None,
+            },
+            content: MessageContent::Text("hi".to_string()),
+        }
+    }
+
+    #[test]
+    fn test_unread_counts() {
+        let tracker = UnreadTracker::new();
+        let chat = JID::new("1", "s.whatsapp.net");
+
+        tracker.message_received(&message(&chat, "A", false));
+        let change = tracker.message_received(&message(&chat, "B", false)).unwrap();
+        assert_eq!(change.unread_count, 2);
+
+        let change = tracker.mark_read(&chat, &["A".to_string(), "B".to_string()]).unwrap();
+        assert_eq!((change.unread_count, change.last_read.as_deref()), (0, Some("B")));
+        // Nothing changes when read again
+        assert!(tracker.mark_read(&chat, &["B".to_string()]).is_none());
+
+        assert_eq!(tracker.set_read(&chat, false).unwrap().unread_count, 1);
+        tracker.message_received(&message(&chat, "C", false));
+        assert_eq!(tracker.get(&chat).unread_count, 2);
+        assert_eq!(tracker.message_received(&message(&chat, "D", true)).unwrap().unread_count, 0);
+        assert_eq!(tracker.get(&JID::new("2", "s.whatsapp.net")), ChatReadState::default());
+    }
+
+    #[test]
+    fn test_parse_mark_chat_as_read_mutation() {
+        let index = ["markChatAsRead".to_string(), "1@s.whatsapp.net".to_string()];
+        let (chat, read) = parse_mark_chat_as_read_mutation(&index, &MarkChatAsReadAction { read: Some(true) }).unwrap();
+        assert_eq!(chat, JID::new("1", "s.whatsapp.net"));
+        assert!(read);
+
+        assert!(parse_mark_chat_as_read_mutation(&index, &MarkChatAsReadAction { read: None }).is_none());
+        let index = ["mute".to_string(), "1@s.whatsapp.net".to_string()];
+        assert!(parse_mark_chat_as_read_mutation(&index, &MarkChatAsReadAction { read: Some(true) }).is_none());
+    }
+}
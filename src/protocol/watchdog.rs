@@ -0,0 +1,108 @@
+//! Detection of connections that went silent.
+//!
+//! A socket can stay "up" long after the path to the server is gone, for
+//! instance when a NAT drops its mapping. The watchdog tracks when the last
+//! frame arrived; after half the stale timeout of silence it sends a ping,
+//! which a live server answers, and once the full timeout passes without a
+//! frame the connection is closed as stale.
+
+use std::time::Duration;
+use tokio::time::Instant;
+
+use crate::binary::Node;
+use crate::types::SERVER_JID;
+
+/// What the watchdog wants done when its deadline passes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum WatchdogAction {
+    /// Nothing yet; check again at the next deadline
+    Wait,
+    /// Send a ping to get a frame out of the server
+    Ping,
+    /// Nothing arrived for the whole timeout: close the connection
+    Stale(Duration),
+}
+
+/// Tracks the last received frame of one connection.
+pub(crate) struct Watchdog {
+    timeout: Duration,
+    last_received: Instant,
+    pinged: bool,
+}
+
+impl Watchdog {
+    pub(crate) fn new(timeout: Duration, now: Instant) -> Self {
+        Self { timeout, last_received: now, pinged: false }
+    }
+
+    /// Record that a frame arrived.
+    pub(crate) fn received(&mut self, now: Instant) {
+        self.last_received = now;
+        self.pinged = false;
+    }
+
+    /// When the watchdog next needs checking.
+    pub(crate) fn deadline(&self) -> Instant {
+        if self.pinged {
+            self.last_received + self.timeout
+        } else {
+            self.last_received + self.timeout / 2
+        }
+    }
+
+    /// Decide what to do at `now`.
+    pub(crate) fn check(&mut self, now: Instant) -> WatchdogAction {
+        let silent_for = now.saturating_duration_since(self.last_received);
+        if silent_for >= self.timeout {
+            WatchdogAction::Stale(silent_for)
+        } else if !self.pinged && silent_for >= self.timeout / 2 {
+            self.pinged = true;
+            WatchdogAction::Ping
+        } else {
+            WatchdogAction::Wait
+        }
+    }
+}
+
+/// Build a ping query; any response proves the connection alive.
+pub(crate) fn build_ping(id: &str) -> Node {
+    let mut node = Node::new("iq");
+    node.set_attr("id", id);
+    node.set_attr("type", "get");
+    node.set_attr("xmlns", "w:p");
+    node.set_attr("to", SERVER_JID.clone());
+    node.add_child(Node::new("ping"));
+    node
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_watchdog_pings_then_gives_up() {
+        let start = Instant::now();
+        let timeout = Duration::from_secs(60);
+        let mut watchdog = Watchdog::new(timeout, start);
+        assert_eq!(watchdog.deadline(), start + Duration::from_secs(30));
+        assert_eq!(watchdog.check(start + Duration::from_secs(10)), WatchdogAction::Wait);
+
+        assert_eq!(watchdog.check(start + Duration::from_secs(30)), WatchdogAction::Ping);
+        assert_eq!(watchdog.deadline(), start + timeout);
+        assert_eq!(watchdog.check(start + Duration::from_secs(45)), WatchdogAction::Wait);
+        assert_eq!(watchdog.check(start + Duration::from_secs(61)), WatchdogAction::Stale(Duration::from_secs(61)));
+    }
+
+    #[test]
+    fn test_frames_reset_the_watchdog() {
+        let start = Instant::now();
+        let mut watchdog = Watchdog::new(Duration::from_secs(60), start);
+        assert_eq!(watchdog.check(start + Duration::from_secs(30)), WatchdogAction::Ping);
+
+        // The pong arrives
+        let later = start + Duration::from_secs(31);
+        watchdog.received(later);
+        assert_eq!(watchdog.deadline(), later + Duration::from_secs(30));
+        assert_eq!(watchdog.check(start + Duration::from_secs(61)), WatchdogAction::Ping);
+    }
+}
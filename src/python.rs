@@ -0,0 +1,243 @@
+//! Python bindings.
+//!
+//! Builds the `whatsmeow_rust_py` extension module with maturin (see
+//! `pyproject.toml`). `Client` methods that talk to the server return
+//! awaitables run on a shared tokio runtime, so they can be used from
+//! asyncio:
+//!
+//! ```python
+//! from whatsmeow_rust_py import Client, JID
+//!
+//! client = Client()
+//! await client.connect()
+//! async for event in client:
+//!     if event.kind == "message" and event.text == "ping":
+//!         await client.send_message(event.chat, "pong")
+//! ```
+
+use std::sync::Arc;
+
+use pyo3::create_exception;
+use pyo3::exceptions::{PyException, PyStopAsyncIteration, PyValueError};
+use pyo3::prelude::*;
+use pyo3_async_runtimes::tokio::future_into_py;
+use tokio::sync::{mpsc, Mutex};
+
+use crate::protocol::{Client as RustClient, ClientError};
+use crate::store::StoredMessage;
+use crate::types::{Event as RustEvent, JID as RustJID};
+
+create_exception!(whatsmeow_rust_py, WhatsmeowError, PyException, "Error from the WhatsApp client.");
+
+fn to_py_err(e: ClientError) -> PyErr {
+    WhatsmeowError::new_err(e.to_string())
+}
+
+/// WhatsApp user, group or server address.
+#[pyclass(name = "JID", module = "whatsmeow_rust_py", frozen, eq, hash)]
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct JID(RustJID);
+
+#[pymethods]
+impl JID {
+    #[new]
+    fn new(user: &str, server: &str) -> Self {
+        Self(RustJID::new(user, server))
+    }
+
+    /// Parse a JID such as `123456789@s.whatsapp.net`.
+    #[staticmethod]
+    fn parse(s: &str) -> PyResult<Self> {
+        s.parse().map(Self).map_err(|e| PyValueError::new_err(e.to_string()))
+    }
+
+    #[getter]
+    fn user(&self) -> &str {
+        &self.0.user
+    }
+
+    #[getter]
+    fn server(&self) -> &str {
+        &self.0.server
+    }
+
+    #[getter]
+    fn device(&self) -> u16 {
+        self.0.device
+    }
+
+    fn __str__(&self) -> String {
+        self.0.to_string()
+    }
+
+    fn __repr__(&self) -> String {
+        format!("JID('{}')", self.0)
+    }
+}
+
+/// Event from the connection.
+///
+/// `kind` is one of "connected", "disconnected", "logged_out", "qr_code",
+/// "pairing_code", "message", "receipt" or "other". Fields that do not
+/// apply to the kind are `None`.
+#[pyclass(name = "Event", module = "whatsmeow_rust_py", frozen, get_all)]
+#[derive(Clone)]
+pub struct Event {
+    kind: &'static str,
+    chat: Option<JID>,
+    sender: Option<JID>,
+    /// Message ID; for receipts, the first receipted message
+    id: Option<String>,
+    /// Message text or caption, or the QR or pairing code
+    text: Option<String>,
+}
+
+#[pymethods]
+impl Event {
+    fn __repr__(&self) -> String {
+        format!("Event(kind='{}', id={:?})", self.kind, self.id)
+    }
+}
+
+impl From<&RustEvent> for Event {
+    fn from(event: &RustEvent) -> Self {
+        let kind_only = |kind| Self { kind, chat: None, sender: None, id: None, text: None };
+        match event {
+            RustEvent::Connected(_) => kind_only("connected"),
+            RustEvent::Disconnected(_) => kind_only("disconnected"),
+            RustEvent::LoggedOut(_) => kind_only("logged_out"),
+            RustEvent::QRCode(qr) => Self { text: Some(qr.code.clone()), ..kind_only("qr_code") },
+            RustEvent::PairingCode(code) => Self { text: Some(code.code.clone()), ..kind_only("pairing_code") },
+            RustEvent::Message(msg) => Self {
+                kind: "message",
+                chat: Some(JID(msg.info.chat.clone())),
+                sender: Some(JID(msg.info.sender.clone())),
+                id: Some(msg.info.id.clone()),
+                text: StoredMessage::from(msg).text,
+            },
+            RustEvent::Receipt(receipt) => Self {
+                kind: "receipt",
+                chat: Some(JID(receipt.chat.clone())),
+                sender: Some(JID(receipt.sender.clone())),
+                id: receipt.message_ids.first().cloned(),
+                text: None,
+            },
+            _ => kind_only("other"),
+        }
+    }
+}
+
+/// WhatsApp client.
+///
+/// Events are queued from creation on and read with `receive` or
+/// `async for`.
+#[pyclass(name = "Client", module = "whatsmeow_rust_py")]
+pub struct Client {
+    client: Arc<Mutex<RustClient>>,
+    events: Arc<Mutex<mpsc::UnboundedReceiver<RustEvent>>>,
+}
+
+#[pymethods]
+impl Client {
+    #[new]
+    fn new() -> Self {
+        let mut client = RustClient::new();
+        let (tx, rx) = mpsc::unbounded_channel();
+        client.add_event_handler(move |event| {
+            // The receiver is gone once the Python object is
+            let _ = tx.send(event);
+        });
+        Self { client: Arc::new(Mutex::new(client)), events: Arc::new(Mutex::new(rx)) }
+    }
+
+    /// Connect to WhatsApp.
+    fn connect<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let client = self.client.clone();
+        future_into_py(py, async move { client.lock().await.connect().await.map_err(to_py_err) })
+    }
+
+    /// Disconnect from WhatsApp.
+    fn disconnect<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let client = self.client.clone();
+        future_into_py(py, async move { client.lock().await.disconnect().await.map_err(to_py_err) })
+    }
+
+    /// Whether the client is connected.
+    fn is_connected(&self) -> bool {
+        self.client.try_lock().is_ok_and(|client| client.is_connected())
+    }
+
+    /// Get the QR code data currently linking this device, or `None` until
+    /// the server starts pairing after `connect` and once pairing has ended.
+    fn pairing_qr<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let client = self.client.clone();
+        future_into_py(py, async move { Ok(client.lock().await.pairing_qr().await) })
+    }
+
+    /// Send a text message, returning its ID.
+    fn send_message<'py>(&self, py: Python<'py>, to: JID, text: String) -> PyResult<Bound<'py, PyAny>> {
+        let client = self.client.clone();
+        future_into_py(py, async move {
+            let handle = client.lock().await.handle().ok_or(ClientError::NotConnected).map_err(to_py_err)?;
+            handle.send_message(to.0, &text).await.map_err(to_py_err)
+        })
+    }
+
+    /// Wait for the next event.
+    fn receive<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let events = self.events.clone();
+        future_into_py(py, async move {
+            match events.lock().await.recv().await {
+                Some(event) => Ok(Event::from(&event)),
+                None => Err(PyStopAsyncIteration::new_err(())),
+            }
+        })
+    }
+
+    fn __aiter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __anext__<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        self.receive(py)
+    }
+}
+
+#[pymodule]
+fn whatsmeow_rust_py(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<JID>()?;
+    m.add_class::<Event>()?;
+    m.add_class::<Client>()?;
+    m.add("WhatsmeowError", m.py().get_type::<WhatsmeowError>())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{Message, MessageContent, MessageInfo, QRCode};
+
+    #[test]
+    fn test_event_conversion() {
+        let event = Event::from(&RustEvent::Message(Message {
+            info: MessageInfo {
+                id: "3EB0AA".to_string(),
+                sender: RustJID::new("1", "s.whatsapp.net"),
+                chat: RustJID::new("1", "s.whatsapp.net"),
+                is_from_me: false,
+                is_group: false,
+                timestamp: 0,
+                push_name: None,
+                reply_to: None,
+            },
+            content: MessageContent::Text("hello".to_string()),
+        }));
+        assert_eq!(event.kind, "message");
+        assert_eq!(event.text.as_deref(), Some("hello"));
+        assert!(event.chat == Some(JID(RustJID::new("1", "s.whatsapp.net"))));
+
+        let event = Event::from(&RustEvent::QRCode(QRCode { code: "2@abc".to_string(), timeout_seconds: 60, expires_at: 1700000060 }));
+        assert_eq!((event.kind, event.text.as_deref()), ("qr_code", Some("2@abc")));
+        assert!(event.chat.is_none());
+    }
+}
@@ -0,0 +1,337 @@
+//! Frame layer of the WhatsApp socket.
+//!
+//! Every WebSocket message carries one or more frames, each a 3-byte
+//! big-endian length followed by the payload. The very first frame of a
+//! connection is preceded by the connection header, itself prefixed with
+//! the edge routing info when the server handed us some. Frames can also
+//! be split across WebSocket messages, so incoming data is reassembled.
+//!
+//! Decrypted frame payloads start with a flags byte; `pack_payload` and
+//! `unpack_payload` add and strip it.
+
+use std::io::Read;
+use flate2::read::ZlibDecoder;
+
+use crate::socket::SocketError;
+use crate::socket::rotation::Endpoint;
+use crate::transport::{Transport, TransportError, TungsteniteTransport, WebSocketOptions};
+
+pub use crate::binary::DICT_VERSION;
+
+/// Magic value of the connection header.
+pub const WA_MAGIC_VALUE: u8 = 6;
+
+/// Connection header sent before the first frame.
+pub const WA_HEADER: [u8; 4] = [b'W', b'A', WA_MAGIC_VALUE, DICT_VERSION];
+
+/// Marker of the edge routing prefix.
+const ROUTING_MAGIC: [u8; 4] = [b'E', b'D', 0, 1];
+
+/// Size of the frame length prefix.
+const LENGTH_SIZE: usize = 3;
+
+/// Largest payload a frame can carry.
+pub const MAX_FRAME_SIZE: usize = (1 << 24) - 1;
+
+/// Bits of the payload flags byte.
+pub mod frame_flags {
+    /// Payload is zlib-compressed
+    pub const COMPRESSED: u8 = 0x02;
+}
+
+/// Build the bytes sent ahead of the first frame.
+fn connection_header(routing_info: Option<&[u8]>) -> Vec<u8> {
+    let mut header = Vec::new();
+    if let Some(routing_info) = routing_info {
+        header.extend_from_slice(&ROUTING_MAGIC);
+        header.extend_from_slice(&(routing_info.len() as u32).to_be_bytes()[1..]);
+        header.extend_from_slice(routing_info);
+    }
+    header.extend_from_slice(&WA_HEADER);
+    header
+}
+
+/// Stateful frame encoder and decoder for one connection.
+pub struct FrameCodec {
+    /// Connection header, until it has been sent
+    header: Option<Vec<u8>>,
+    /// Received bytes not yet forming a complete frame
+    incoming: Vec<u8>,
+}
+
+impl FrameCodec {
+    /// Create a codec for a new connection.
+    pub fn new(routing_info: Option<&[u8]>) -> Self {
+        Self {
+            header: Some(connection_header(routing_info)),
+            incoming: Vec::new(),
+        }
+    }
+
+    /// The connection header, as authenticated by the Noise handshake.
+    ///
+    /// The routing prefix is not part of it.
+    pub fn noise_header(&self) -> &'static [u8] {
+        &WA_HEADER
+    }
+
+    /// Check if the connection header has been sent.
+    pub fn header_sent(&self) -> bool {
+        self.header.is_none()
+    }
+
+    /// Frame a payload, prefixing the connection header on the first call.
+    pub fn encode(&mut self, payload: &[u8]) -> Result<Vec<u8>, SocketError> {
+        if payload.len() > MAX_FRAME_SIZE {
+            return Err(SocketError::FrameTooLarge(payload.len()));
+        }
+
+        let mut frame = self.header.take().unwrap_or_default();
+        frame.reserve(LENGTH_SIZE + payload.len());
+        frame.extend_from_slice(&(payload.len() as u32).to_be_bytes()[1..]);
+        frame.extend_from_slice(payload);
+        Ok(frame)
+    }
+
+    /// Add received bytes.
+    pub fn push(&mut self, data: &[u8]) {
+        self.incoming.extend_from_slice(data);
+    }
+
+    /// Take the next complete frame payload, if one has arrived.
+    pub fn next_frame(&mut self) -> Option<Vec<u8>> {
+        if self.incoming.len() < LENGTH_SIZE {
+            return None;
+        }
+        let len = u32::from_be_bytes([0, self.incoming[0], self.incoming[1], self.incoming[2]]) as usize;
+        if self.incoming.len() < LENGTH_SIZE + len {
+            return None;
+        }
+
+        let payload = self.incoming[LENGTH_SIZE..LENGTH_SIZE + len].to_vec();
+        self.incoming.drain(..LENGTH_SIZE + len);
+        Some(payload)
+    }
+}
+
+/// Prefix a plaintext payload with its flags byte.
+///
+/// Outgoing payloads are never compressed.
+pub fn pack_payload(data: &[u8]) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(data.len() + 1);
+    payload.push(0);
+    payload.extend_from_slice(data);
+    payload
+}
+
+/// Strip the flags byte of a plaintext payload, inflating it if needed.
+pub fn unpack_payload(data: &[u8]) -> Result<Vec<u8>, SocketError> {
+    let (&flags, body) = data.split_first().ok_or(SocketError::InvalidFrame)?;
+    if flags & frame_flags::COMPRESSED == 0 {
+        return Ok(body.to_vec());
+    }
+
+    let mut inflated = Vec::new();
+    ZlibDecoder::new(body)
+        .read_to_end(&mut inflated)
+        .map_err(|_| SocketError::InvalidFrame)?;
+    Ok(inflated)
+}
+
+/// Transport carrying length-prefixed frames.
+pub struct FrameSocket<T = TungsteniteTransport> {
+    transport: T,
+    codec: FrameCodec,
+}
+
+impl FrameSocket {
+    /// Connect to a WebSocket endpoint.
+    ///
+    /// `routing_info` is the edge routing info from a previous connection.
+    pub async fn connect(url: &str, routing_info: Option<&[u8]>) -> Result<Self, SocketError> {
+        Self::connect_to(&Endpoint::new(url), false, &WebSocketOptions::default(), routing_info).await
+    }
+
+    /// Connect to an endpoint, possibly at a fixed IP address.
+    ///
+    /// See `TungsteniteTransport::connect_to`.
+    pub async fn connect_to(
+        endpoint: &Endpoint,
+        prefer_ipv6: bool,
+        options: &WebSocketOptions,
+        routing_info: Option<&[u8]>,
+    ) -> Result<Self, SocketError> {
+        let transport = TungsteniteTransport::connect_to(endpoint, prefer_ipv6, options)
+            .await
+            .map_err(|e| socket_error(e, SocketError::ConnectionFailed))?;
+        Ok(Self::new(transport, routing_info))
+    }
+}
+
+impl<T: Transport> FrameSocket<T> {
+    /// Frame an established connection.
+    ///
+    /// `routing_info` is the edge routing info from a previous connection.
+    pub fn new(transport: T, routing_info: Option<&[u8]>) -> Self {
+        Self { transport, codec: FrameCodec::new(routing_info) }
+    }
+
+    /// The connection header, as authenticated by the Noise handshake.
+    pub fn noise_header(&self) -> &'static [u8] {
+        self.codec.noise_header()
+    }
+
+    /// Send one frame.
+    pub async fn send_frame(&mut self, payload: &[u8]) -> Result<(), SocketError> {
+        let frame = self.codec.encode(payload)?;
+        self.transport
+            .send(frame)
+            .await
+            .map_err(|e| socket_error(e, SocketError::SendFailed))
+    }
+
+    /// Receive one frame, reading more messages until it is complete.
+    pub async fn recv_frame(&mut self) -> Result<Vec<u8>, SocketError> {
+        self.recv_frame_within(usize::MAX).await
+    }
+
+    /// Receive one frame, failing with `SocketError::TooManyMessages` if it
+    /// is still incomplete after `max_messages` transport messages.
+    pub async fn recv_frame_within(&mut self, max_messages: usize) -> Result<Vec<u8>, SocketError> {
+        let mut read = 0;
+        loop {
+            if let Some(frame) = self.codec.next_frame() {
+                return Ok(frame);
+            }
+            if read == max_messages {
+                return Err(SocketError::TooManyMessages(max_messages));
+            }
+            read += 1;
+
+            match self.transport.recv().await {
+                Ok(Some(data)) => self.codec.push(&data),
+                Ok(None) => return Err(SocketError::ConnectionClosed),
+                Err(e) => return Err(socket_error(e, SocketError::ReceiveFailed)),
+            }
+        }
+    }
+
+    /// Close the connection.
+    pub async fn close(&mut self) -> Result<(), SocketError> {
+        self.transport
+            .close()
+            .await
+            .map_err(|e| socket_error(e, SocketError::SendFailed))
+    }
+}
+
+/// Turn a transport error into a socket error, using `failed` for I/O
+/// errors of the operation at hand.
+fn socket_error(error: TransportError, failed: fn(String) -> SocketError) -> SocketError {
+    match error {
+        TransportError::ConnectFailed(e) => SocketError::ConnectionFailed(e),
+        TransportError::Closed => SocketError::ConnectionClosed,
+        TransportError::ClosedByPeer(close) => SocketError::ClosedByPeer(close),
+        TransportError::Io(e) => failed(e),
+        e @ TransportError::TooLarge(_) => failed(e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use flate2::{write::ZlibEncoder, Compression};
+
+    #[test]
+    fn test_header_sent_once() {
+        let mut codec = FrameCodec::new(None);
+        let first = codec.encode(b"abc").unwrap();
+        assert_eq!(first, [b'W', b'A', 6, 3, 0, 0, 3, b'a', b'b', b'c']);
+        assert!(codec.header_sent());
+
+        let second = codec.encode(b"de").unwrap();
+        assert_eq!(second, [0, 0, 2, b'd', b'e']);
+    }
+
+    #[test]
+    fn test_routing_info_prefix() {
+        let mut codec = FrameCodec::new(Some(&[9, 9]));
+        let first = codec.encode(b"x").unwrap();
+        assert_eq!(first, [b'E', b'D', 0, 1, 0, 0, 2, 9, 9, b'W', b'A', 6, 3, 0, 0, 1, b'x']);
+        assert_eq!(codec.noise_header(), WA_HEADER);
+    }
+
+    #[test]
+    fn test_reassembles_split_and_merged_frames() {
+        let mut codec = FrameCodec::new(None);
+        codec.push(&[0, 0, 3, b'a']);
+        assert_eq!(codec.next_frame(), None);
+
+        codec.push(&[b'b', b'c', 0, 0, 1, b'd', 0]);
+        assert_eq!(codec.next_frame(), Some(b"abc".to_vec()));
+        assert_eq!(codec.next_frame(), Some(b"d".to_vec()));
+        assert_eq!(codec.next_frame(), None);
+    }
+
+    #[test]
+    fn test_payload_flags() {
+        assert_eq!(unpack_payload(&pack_payload(b"node")).unwrap(), b"node");
+
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(b"compressed node").unwrap();
+        let mut payload = vec![frame_flags::COMPRESSED];
+        payload.extend(encoder.finish().unwrap());
+        assert_eq!(unpack_payload(&payload).unwrap(), b"compressed node");
+
+        assert!(unpack_payload(&[]).is_err());
+    }
+
+    /// In-memory transport replaying canned messages.
+    #[derive(Default)]
+    struct MemoryTransport {
+        incoming: std::collections::VecDeque<Vec<u8>>,
+        sent: Vec<Vec<u8>>,
+    }
+
+    impl Transport for MemoryTransport {
+        async fn send(&mut self, frame: Vec<u8>) -> Result<(), TransportError> {
+            self.sent.push(frame);
+            Ok(())
+        }
+
+        async fn recv(&mut self) -> Result<Option<Vec<u8>>, TransportError> {
+            Ok(self.incoming.pop_front())
+        }
+
+        async fn close(&mut self) -> Result<(), TransportError> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_frames_over_custom_transport() {
+        let transport = MemoryTransport {
+            incoming: [vec![0, 0, 3, b'a'], vec![b'b', b'c']].into(),
+            ..Default::default()
+        };
+        let mut socket = FrameSocket::new(transport, None);
+
+        socket.send_frame(b"hi").await.unwrap();
+        assert_eq!(socket.transport.sent, [[b'W', b'A', 6, 3, 0, 0, 2, b'h', b'i']]);
+
+        assert_eq!(socket.recv_frame().await.unwrap(), b"abc");
+        assert!(matches!(socket.recv_frame().await, Err(SocketError::ConnectionClosed)));
+    }
+
+    #[tokio::test]
+    async fn test_recv_frame_within() {
+        let transport = MemoryTransport {
+            incoming: [vec![0, 0, 3], vec![b'a'], vec![b'b', b'c']].into(),
+            ..Default::default()
+        };
+        let mut socket = FrameSocket::new(transport, None);
+        assert!(matches!(socket.recv_frame_within(2).await, Err(SocketError::TooManyMessages(2))));
+        assert_eq!(socket.recv_frame_within(1).await.unwrap(), b"abc");
+    }
+}
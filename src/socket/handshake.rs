@@ -5,18 +5,19 @@
 use std::time::Duration;
 use tokio::time::timeout;
 use tokio_tungstenite::{connect_async, tungstenite::Message, MaybeTlsStream, WebSocketStream};
-use tokio_tungstenite::tungstenite::http::Request;
 use tokio::net::TcpStream;
 use futures::{SinkExt, StreamExt};
 use prost::Message as ProstMessage;
 use sha2::{Sha256, Digest};
 use aes_gcm::{Aes256Gcm, KeyInit, aead::Aead, Nonce};
 
-use crate::crypto::{KeyPair, Hkdf};
+use crate::crypto::Hkdf;
+use crate::socket::frame::{FrameCodec, pack_payload, unpack_payload};
 use crate::store::Device;
+use crate::transport::WebSocketOptions;
 use crate::proto::{
     HandshakeMessage, ClientHello, ClientFinish,
-    ClientPayload, make_web_client_payload, make_device_pairing_data,
+    make_web_client_payload, make_device_pairing_data, make_device_props, device_platform, DEFAULT_DEVICE_OS,
 };
 
 /// WhatsApp WebSocket endpoints
@@ -26,33 +27,94 @@ pub const WA_ORIGIN: &str = "https://web.whatsapp.com";
 /// Noise protocol pattern name (exactly 32 bytes)
 const NOISE_PATTERN: &[u8; 32] = b"Noise_XX_25519_AESGCM_SHA256\x00\x00\x00\x00";
 
-/// WhatsApp connection header: 'W', 'A', MagicValue(6), DictVersion(3)
-const WA_HEADER: [u8; 4] = [b'W', b'A', 6, 3];
+/// Timeouts and limits of the Noise handshake
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HandshakeConfig {
+    /// How long to wait for the WebSocket connection to open
+    pub connect_timeout: Duration,
+    /// How long to wait for each message from the server during the
+    /// handshake
+    pub response_timeout: Duration,
+    /// WebSocket messages read before giving up on a complete server
+    /// hello frame
+    pub max_frames: usize,
+}
+
+impl Default for HandshakeConfig {
+    fn default() -> Self {
+        Self {
+            connect_timeout: Duration::from_secs(10),
+            response_timeout: Duration::from_secs(20),
+            max_frames: 10,
+        }
+    }
+}
+
+/// Step of the connection that timed out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeStage {
+    /// Opening the WebSocket connection
+    Connect,
+    /// Waiting for the server hello
+    ServerHello,
+    /// Waiting for a frame on the established connection
+    Frame,
+}
+
+impl std::fmt::Display for HandshakeStage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HandshakeStage::Connect => write!(f, "connecting"),
+            HandshakeStage::ServerHello => write!(f, "waiting for the server hello"),
+            HandshakeStage::Frame => write!(f, "waiting for a frame"),
+        }
+    }
+}
+
+/// How long `WhatsAppConnection::recv` waits for the next message
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
 
 /// Handshake errors
 #[derive(Debug)]
 pub enum HandshakeError {
     ConnectionFailed(String),
-    Timeout,
+    /// No answer within the configured time
+    Timeout(HandshakeStage, Duration),
+    /// The server hello was still incomplete after this many messages
+    TooManyFrames(usize),
     InvalidResponse(String),
     CryptoError(String),
     ProtocolError(String),
+    /// The 32-bit IV counter ran out; reconnect for new keys
+    NonceExhausted,
 }
 
 impl std::fmt::Display for HandshakeError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             HandshakeError::ConnectionFailed(e) => write!(f, "connection failed: {}", e),
-            HandshakeError::Timeout => write!(f, "timeout"),
+            HandshakeError::Timeout(stage, after) => write!(f, "timed out after {:?} {}", after, stage),
+            HandshakeError::TooManyFrames(n) => write!(f, "server hello incomplete after {} messages", n),
             HandshakeError::InvalidResponse(e) => write!(f, "invalid response: {}", e),
             HandshakeError::CryptoError(e) => write!(f, "crypto error: {}", e),
             HandshakeError::ProtocolError(e) => write!(f, "protocol error: {}", e),
+            HandshakeError::NonceExhausted => write!(f, "nonce counter exhausted"),
         }
     }
 }
 
 impl std::error::Error for HandshakeError {}
 
+/// Take the current value of an IV counter and advance it.
+///
+/// WhatsApp puts the counter in the last 4 bytes of the IV, so it cannot
+/// grow past `u32::MAX`; fail there rather than wrap and reuse an IV.
+fn next_counter(counter: &mut u32) -> Result<u32, HandshakeError> {
+    let current = *counter;
+    *counter = current.checked_add(1).ok_or(HandshakeError::NonceExhausted)?;
+    Ok(current)
+}
+
 /// Noise handshake state matching whatsmeow's implementation
 pub struct NoiseHandshake {
     /// Hash state (h)
@@ -84,23 +146,23 @@ impl NoiseHandshake {
     /// Mix data into the hash (authenticate)
     fn authenticate(&mut self, data: &[u8]) {
         let mut hasher = Sha256::new();
-        hasher.update(&self.hash);
+        hasher.update(self.hash);
         hasher.update(data);
         self.hash = hasher.finalize().into();
     }
 
     /// Generate IV for AES-GCM from counter
-    fn generate_iv(&self) -> [u8; 12] {
+    fn generate_iv(&mut self) -> Result<[u8; 12], HandshakeError> {
         let mut iv = [0u8; 12];
-        iv[8..12].copy_from_slice(&self.counter.to_be_bytes());
-        iv
+        iv[8..12].copy_from_slice(&next_counter(&mut self.counter)?.to_be_bytes());
+        Ok(iv)
     }
 
     /// Encrypt using current key
     fn encrypt(&mut self, plaintext: &[u8]) -> Result<Vec<u8>, HandshakeError> {
         let cipher = Aes256Gcm::new_from_slice(&self.key)
             .map_err(|_| HandshakeError::CryptoError("invalid key".to_string()))?;
-        let iv = self.generate_iv();
+        let iv = self.generate_iv()?;
         let nonce = Nonce::from_slice(&iv);
         
         // GCM with AAD = hash
@@ -109,7 +171,6 @@ impl NoiseHandshake {
             aad: &self.hash,
         }).map_err(|_| HandshakeError::CryptoError("encryption failed".to_string()))?;
         
-        self.counter += 1;
         self.authenticate(&ciphertext);
         
         Ok(ciphertext)
@@ -119,7 +180,7 @@ impl NoiseHandshake {
     fn decrypt(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>, HandshakeError> {
         let cipher = Aes256Gcm::new_from_slice(&self.key)
             .map_err(|_| HandshakeError::CryptoError("invalid key".to_string()))?;
-        let iv = self.generate_iv();
+        let iv = self.generate_iv()?;
         let nonce = Nonce::from_slice(&iv);
         
         let plaintext = cipher.decrypt(nonce, aes_gcm::aead::Payload {
@@ -127,7 +188,6 @@ impl NoiseHandshake {
             aad: &self.hash,
         }).map_err(|_| HandshakeError::CryptoError("decryption failed".to_string()))?;
         
-        self.counter += 1;
         self.authenticate(ciphertext);
         
         Ok(plaintext)
@@ -174,6 +234,8 @@ pub struct WhatsAppConnection {
     pub write_counter: u32,
     pub read_counter: u32,
     pub device: Device,
+    /// Framing state, continued from the handshake
+    pub frames: FrameCodec,
 }
 
 impl WhatsAppConnection {
@@ -183,95 +245,43 @@ impl WhatsAppConnection {
             .map_err(|_| HandshakeError::CryptoError("invalid key".to_string()))?;
         
         let mut iv = [0u8; 12];
-        iv[8..12].copy_from_slice(&self.write_counter.to_be_bytes());
+        iv[8..12].copy_from_slice(&next_counter(&mut self.write_counter)?.to_be_bytes());
         let nonce = Nonce::from_slice(&iv);
         
-        let encrypted = cipher.encrypt(nonce, data)
+        let encrypted = cipher.encrypt(nonce, pack_payload(data).as_slice())
             .map_err(|_| HandshakeError::CryptoError("encryption failed".to_string()))?;
         
-        self.write_counter += 1;
-        
-        // Frame format: length (3 bytes) + encrypted data
-        let len = encrypted.len();
-        let mut frame = Vec::with_capacity(len + 3);
-        frame.push(((len >> 16) & 0xFF) as u8);
-        frame.push(((len >> 8) & 0xFF) as u8);
-        frame.push((len & 0xFF) as u8);
-        frame.extend_from_slice(&encrypted);
-        
-        self.ws.send(Message::Binary(frame.into())).await
+        let frame = self.frames.encode(&encrypted)
+            .map_err(|e| HandshakeError::ProtocolError(e.to_string()))?;
+        self.ws.send(Message::Binary(frame)).await
             .map_err(|e| HandshakeError::ConnectionFailed(e.to_string()))
     }
 
     /// Receive and decrypt a frame
     pub async fn recv(&mut self) -> Result<Vec<u8>, HandshakeError> {
         loop {
-            let msg = timeout(Duration::from_secs(30), self.ws.next()).await
-                .map_err(|_| HandshakeError::Timeout)?
+            // Frames may arrive split across or merged into messages
+            if let Some(encrypted) = self.frames.next_frame() {
+                let cipher = Aes256Gcm::new_from_slice(&self.read_key)
+                    .map_err(|_| HandshakeError::CryptoError("invalid key".to_string()))?;
+
+                let mut iv = [0u8; 12];
+                iv[8..12].copy_from_slice(&next_counter(&mut self.read_counter)?.to_be_bytes());
+                let nonce = Nonce::from_slice(&iv);
+
+                let decrypted = cipher.decrypt(nonce, encrypted.as_slice())
+                    .map_err(|_| HandshakeError::CryptoError("decryption failed".to_string()))?;
+                return unpack_payload(&decrypted)
+                    .map_err(|e| HandshakeError::ProtocolError(e.to_string()));
+            }
+
+            let msg = timeout(READ_TIMEOUT, self.ws.next()).await
+                .map_err(|_| HandshakeError::Timeout(HandshakeStage::Frame, READ_TIMEOUT))?
                 .ok_or(HandshakeError::ConnectionFailed("connection closed".to_string()))?
                 .map_err(|e| HandshakeError::ConnectionFailed(e.to_string()))?;
 
             match msg {
-                Message::Binary(data) => {
-                    // Skip very short frames (likely keep-alive or error)
-                    if data.len() < 4 {
-                        println!("   [recv] Skipping short frame ({} bytes)", data.len());
-                        continue;
-                    }
-                    
-                    // Parse length header
-                    let frame_len = ((data[0] as usize) << 16) 
-                                  | ((data[1] as usize) << 8) 
-                                  | (data[2] as usize);
-                    
-                    // Sanity check - if frame_len is way off, the data might be malformed
-                    if frame_len > 100000 || frame_len + 3 > data.len() + 100 {
-                        println!("   [recv] Skipping malformed frame (len={}, data={})", frame_len, data.len());
-                        continue;
-                    }
-                    
-                    println!("   [recv] Frame: {} total, header says {}, counter={}", 
-                             data.len(), frame_len, self.read_counter);
-                    
-                    // The encrypted data is after the 3-byte length prefix
-                    let encrypted = &data[3..];
-                    
-                    if encrypted.is_empty() {
-                        println!("   [recv] Empty encrypted data, skipping");
-                        continue;
-                    }
-                    
-                    let cipher = Aes256Gcm::new_from_slice(&self.read_key)
-                        .map_err(|_| HandshakeError::CryptoError("invalid key".to_string()))?;
-                    
-                    let mut iv = [0u8; 12];
-                    iv[8..12].copy_from_slice(&self.read_counter.to_be_bytes());
-                    let nonce = Nonce::from_slice(&iv);
-                    
-                    match cipher.decrypt(nonce, encrypted) {
-                        Ok(decrypted) => {
-                            self.read_counter += 1;
-                            return Ok(decrypted);
-                        }
-                        Err(_) => {
-                            println!("   [recv] Decryption failed, trying next counter...");
-                            // Try incrementing counter in case we missed a message
-                            self.read_counter += 1;
-                            let mut iv2 = [0u8; 12];
-                            iv2[8..12].copy_from_slice(&self.read_counter.to_be_bytes());
-                            let nonce2 = Nonce::from_slice(&iv2);
-                            
-                            if let Ok(decrypted) = cipher.decrypt(nonce2, encrypted) {
-                                self.read_counter += 1;
-                                return Ok(decrypted);
-                            }
-                            
-                            // Give up on this frame
-                            println!("   [recv] Still failed, skipping frame");
-                            continue;
-                        }
-                    }
-                }
+                Message::Binary(data) => self.frames.push(&data),
                 Message::Close(frame) => {
                     let reason = frame.map(|f| format!("{}: {}", f.code, f.reason)).unwrap_or_default();
                     return Err(HandshakeError::ConnectionFailed(format!("connection closed: {}", reason)));
@@ -291,6 +301,14 @@ impl WhatsAppConnection {
 
 /// Perform complete WhatsApp handshake
 pub async fn do_handshake(device: &Device) -> Result<WhatsAppConnection, HandshakeError> {
+    do_handshake_with_config(device, &HandshakeConfig::default()).await
+}
+
+/// Perform complete WhatsApp handshake with the given timeouts and limits
+pub async fn do_handshake_with_config(
+    device: &Device,
+    config: &HandshakeConfig,
+) -> Result<WhatsAppConnection, HandshakeError> {
     // Get device keys
     let noise_key = device.noise_key.as_ref()
         .ok_or(HandshakeError::ProtocolError("no noise key".to_string()))?;
@@ -306,13 +324,16 @@ pub async fn do_handshake(device: &Device) -> Result<WhatsAppConnection, Handsha
     // Connect to WhatsApp
     println!("   Connecting to {}...", WA_ENDPOINT);
 
-    let (mut ws, _) = timeout(Duration::from_secs(10), connect_async(WA_ENDPOINT)).await
-        .map_err(|_| HandshakeError::Timeout)?
+    let request = WebSocketOptions::default().request(WA_ENDPOINT)
+        .map_err(|e| HandshakeError::ConnectionFailed(e.to_string()))?;
+    let (mut ws, _) = timeout(config.connect_timeout, connect_async(request)).await
+        .map_err(|_| HandshakeError::Timeout(HandshakeStage::Connect, config.connect_timeout))?
         .map_err(|e| HandshakeError::ConnectionFailed(e.to_string()))?;
     println!("   ✓ Connected");
 
     // Initialize Noise handshake state
-    let mut noise = NoiseHandshake::new(&WA_HEADER);
+    let mut frames = FrameCodec::new(None);
+    let mut noise = NoiseHandshake::new(frames.noise_header());
 
     // === Message 1: -> e (send ephemeral public key) ===
     println!("   Sending handshake message 1 (-> e)...");
@@ -333,57 +354,39 @@ pub async fn do_handshake(device: &Device) -> Result<WhatsAppConnection, Handsha
         .map_err(|e| HandshakeError::ProtocolError(e.to_string()))?;
 
     // First frame: WA header + 3-byte length + protobuf
-    let mut frame = Vec::new();
-    frame.extend_from_slice(&WA_HEADER);
     let len = msg1_proto.len();
-    frame.push(((len >> 16) & 0xFF) as u8);
-    frame.push(((len >> 8) & 0xFF) as u8);
-    frame.push((len & 0xFF) as u8);
-    frame.extend_from_slice(&msg1_proto);
+    let frame = frames.encode(&msg1_proto)
+        .map_err(|e| HandshakeError::ProtocolError(e.to_string()))?;
     
     println!("   Sending {} bytes: header={:02x?}, length={}", 
              frame.len(), &frame[..4], len);
     
-    ws.send(Message::Binary(frame.into())).await
+    ws.send(Message::Binary(frame)).await
         .map_err(|e| HandshakeError::ConnectionFailed(e.to_string()))?;
     println!("   ✓ Message 1 sent");
 
     // === Message 2: <- e, ee, s, es ===
     println!("   Waiting for handshake message 2...");
     
-    // Accumulate response data
-    let mut response_data = Vec::new();
-    let mut frame_len: Option<usize> = None;
+    // Accumulate response data until the frame is complete
+    let mut response_data = None;
     
-    for attempt in 0..10 {
-        let response = timeout(Duration::from_secs(5), ws.next()).await
-            .map_err(|_| HandshakeError::Timeout)?
+    for attempt in 0..config.max_frames {
+        let response = timeout(config.response_timeout, ws.next()).await
+            .map_err(|_| HandshakeError::Timeout(HandshakeStage::ServerHello, config.response_timeout))?
             .ok_or(HandshakeError::ConnectionFailed("no response".to_string()))?
             .map_err(|e| HandshakeError::ConnectionFailed(e.to_string()))?;
 
         match response {
             Message::Binary(data) => {
-                response_data.extend_from_slice(&data);
+                frames.push(&data);
                 println!("   ✓ Received {} bytes (attempt {}): {:02x?}...", 
                          data.len(), attempt + 1, &data[..data.len().min(20)]);
                 
-                // Parse frame length once we have at least 3 bytes
-                if frame_len.is_none() && response_data.len() >= 3 {
-                    let len = ((response_data[0] as usize) << 16) 
-                            | ((response_data[1] as usize) << 8) 
-                            | (response_data[2] as usize);
-                    frame_len = Some(len);
-                    println!("   Frame declares {} bytes of content", len);
-                }
-                
-                // Check if we have complete frame
-                if let Some(len) = frame_len {
-                    if response_data.len() >= len + 3 {
-                        // Extract just the protobuf content
-                        response_data = response_data[3..3+len].to_vec();
-                        println!("   ✓ Complete frame received: {} bytes protobuf", response_data.len());
-                        break;
-                    }
+                if let Some(frame) = frames.next_frame() {
+                    println!("   ✓ Complete frame received: {} bytes protobuf", frame.len());
+                    response_data = Some(frame);
+                    break;
                 }
             }
             Message::Close(frame) => {
@@ -393,10 +396,7 @@ pub async fn do_handshake(device: &Device) -> Result<WhatsAppConnection, Handsha
             _ => {}
         }
     }
-    
-    if response_data.is_empty() {
-        return Err(HandshakeError::InvalidResponse("no data received".to_string()));
-    }
+    let response_data = response_data.ok_or(HandshakeError::TooManyFrames(config.max_frames))?;
 
     // Decode server hello
     let server_hello_msg = HandshakeMessage::decode(&response_data[..])
@@ -466,6 +466,7 @@ pub async fn do_handshake(device: &Device) -> Result<WhatsAppConnection, Handsha
         signed_prekey.key_id,
         &signed_prekey.key_pair.public,
         &signature,
+        &make_device_props(DEFAULT_DEVICE_OS, device_platform::CHROME, false),
     );
 
     let mut client_payload = make_web_client_payload(device.push_name.as_deref());
@@ -492,13 +493,10 @@ pub async fn do_handshake(device: &Device) -> Result<WhatsAppConnection, Handsha
 
     // Frame: 3-byte length + protobuf (no header on subsequent frames)
     let len3 = msg3_data.len();
-    let mut frame3 = Vec::with_capacity(len3 + 3);
-    frame3.push(((len3 >> 16) & 0xFF) as u8);
-    frame3.push(((len3 >> 8) & 0xFF) as u8);
-    frame3.push((len3 & 0xFF) as u8);
-    frame3.extend_from_slice(&msg3_data);
+    let frame3 = frames.encode(&msg3_data)
+        .map_err(|e| HandshakeError::ProtocolError(e.to_string()))?;
 
-    ws.send(Message::Binary(frame3.into())).await
+    ws.send(Message::Binary(frame3)).await
         .map_err(|e| HandshakeError::ConnectionFailed(e.to_string()))?;
     println!("   ✓ Message 3 sent ({} bytes)", len3);
 
@@ -513,5 +511,19 @@ pub async fn do_handshake(device: &Device) -> Result<WhatsAppConnection, Handsha
         write_counter: 0,
         read_counter: 0,
         device: device.clone(),
+        frames,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counter_does_not_wrap() {
+        let mut counter = u32::MAX - 1;
+        assert_eq!(next_counter(&mut counter).unwrap(), u32::MAX - 1);
+        assert!(matches!(next_counter(&mut counter), Err(HandshakeError::NonceExhausted)));
+        assert_eq!(counter, u32::MAX);
+    }
+}
@@ -3,27 +3,46 @@
 //! Provides connection management to WhatsApp servers using WebSocket + Noise Protocol.
 
 pub mod handshake;
+pub mod frame;
+pub mod rotation;
 
-use tokio::net::TcpStream;
-use tokio_tungstenite::{
-    connect_async, tungstenite::Message, MaybeTlsStream, WebSocketStream,
-};
-use futures::{SinkExt, StreamExt};
-
-use crate::crypto::{Cipher, NoiseHandshake, KeyPair};
+use crate::crypto::{Cipher, CipherError, NoiseHandshake, KeyPair};
+use crate::transport::{CloseReason, Transport, TungsteniteTransport, WebSocketOptions};
 
-pub use handshake::{do_handshake, WhatsAppConnection, HandshakeError};
+pub use handshake::{
+    do_handshake, do_handshake_with_config, WhatsAppConnection, HandshakeConfig, HandshakeError, HandshakeStage,
+};
+pub use frame::{FrameCodec, FrameSocket, WA_HEADER, pack_payload, unpack_payload};
+pub use rotation::{Endpoint, EndpointRotation, parse_endpoint_hints};
 
 /// WhatsApp WebSocket endpoints.
 pub mod endpoints {
     pub const MAIN: &str = "wss://web.whatsapp.com/ws/chat";
     pub const FALLBACK: &str = "wss://w1.web.whatsapp.com/ws/chat";
+
+    /// Number of numbered fallback hosts (w1 to w8).
+    pub const FALLBACK_COUNT: u8 = 8;
+
+    /// URL of the numbered fallback host `w{n}.web.whatsapp.com`.
+    pub fn fallback(n: u8) -> String {
+        super::rotation::chat_url(&format!("w{}.web.whatsapp.com", n))
+    }
+
+    /// All numbered fallback hosts, in order.
+    pub fn fallbacks() -> Vec<String> {
+        (1..=FALLBACK_COUNT).map(fallback).collect()
+    }
+
+    /// The main endpoint followed by every fallback.
+    pub fn default_rotation() -> Vec<String> {
+        std::iter::once(MAIN.to_string()).chain(fallbacks()).collect()
+    }
 }
 
-/// WebSocket connection to WhatsApp servers.
-pub struct NoiseSocket {
-    /// The underlying WebSocket stream
-    ws: WebSocketStream<MaybeTlsStream<TcpStream>>,
+/// Noise-encrypted connection to WhatsApp servers over a `Transport`.
+pub struct NoiseSocket<T = TungsteniteTransport> {
+    /// The underlying frame transport
+    frames: FrameSocket<T>,
     /// Send cipher (after handshake)
     send_cipher: Option<Cipher>,
     /// Receive cipher (after handshake)
@@ -35,49 +54,97 @@ pub struct NoiseSocket {
 impl NoiseSocket {
     /// Connect to WhatsApp servers.
     pub async fn connect(url: &str) -> Result<Self, SocketError> {
-        let (ws, _response) = connect_async(url)
-            .await
-            .map_err(|e| SocketError::ConnectionFailed(e.to_string()))?;
+        Self::connect_with_routing(url, None).await
+    }
 
-        Ok(Self {
-            ws,
-            send_cipher: None,
-            recv_cipher: None,
-            handshake_complete: false,
-        })
+    /// Connect to WhatsApp servers, passing the edge routing info of a
+    /// previous connection.
+    pub async fn connect_with_routing(url: &str, routing_info: Option<&[u8]>) -> Result<Self, SocketError> {
+        Self::connect_to(&Endpoint::new(url), false, &WebSocketOptions::default(), routing_info).await
+    }
+
+    /// Connect to an endpoint, possibly at a fixed IP address, passing the
+    /// edge routing info of a previous connection.
+    ///
+    /// See `FrameSocket::connect_to`.
+    pub async fn connect_to(
+        endpoint: &Endpoint,
+        prefer_ipv6: bool,
+        options: &WebSocketOptions,
+        routing_info: Option<&[u8]>,
+    ) -> Result<Self, SocketError> {
+        let frames = FrameSocket::connect_to(endpoint, prefer_ipv6, options, routing_info).await?;
+        Ok(Self::from_frames(frames))
     }
 
     /// Connect to the main WhatsApp endpoint.
     pub async fn connect_main() -> Result<Self, SocketError> {
         Self::connect(endpoints::MAIN).await
     }
+}
+
+impl<T: Transport> NoiseSocket<T> {
+    /// Use an established connection, passing the edge routing info of a
+    /// previous connection.
+    pub fn new(transport: T, routing_info: Option<&[u8]>) -> Self {
+        Self::from_frames(FrameSocket::new(transport, routing_info))
+    }
+
+    fn from_frames(frames: FrameSocket<T>) -> Self {
+        Self {
+            frames,
+            send_cipher: None,
+            recv_cipher: None,
+            handshake_complete: false,
+        }
+    }
 
     /// Perform Noise Protocol handshake.
     pub async fn handshake(&mut self, static_key: KeyPair) -> Result<[u8; 32], SocketError> {
+        self.handshake_with_config(static_key, &HandshakeConfig::default()).await
+    }
+
+    /// Perform Noise Protocol handshake, waiting for the server as long as
+    /// `config` allows.
+    pub async fn handshake_with_config(
+        &mut self,
+        static_key: KeyPair,
+        config: &HandshakeConfig,
+    ) -> Result<[u8; 32], SocketError> {
+        let client_payload = self.build_client_payload();
+        self.handshake_with_payload(static_key, config, &client_payload).await
+    }
+
+    /// Perform Noise Protocol handshake, sending an encoded `ClientPayload`
+    /// to log in or register with.
+    pub async fn handshake_with_payload(
+        &mut self,
+        static_key: KeyPair,
+        config: &HandshakeConfig,
+        client_payload: &[u8],
+    ) -> Result<[u8; 32], SocketError> {
         let mut noise = NoiseHandshake::new_initiator(static_key);
 
         // Send message 1 (-> e)
         let msg1 = noise.write_message_1();
-        let frame1 = self.build_handshake_frame(&msg1);
-        self.send_raw(&frame1).await?;
+        self.frames.send_frame(&msg1).await?;
 
         // Receive message 2 (<- e, ee, s, es)
-        let response = self.recv_raw().await?;
-        let msg2 = self.parse_handshake_frame(&response)?;
+        let msg2 = tokio::time::timeout(config.response_timeout, self.frames.recv_frame_within(config.max_frames))
+            .await
+            .map_err(|_| SocketError::Timeout(HandshakeStage::ServerHello, config.response_timeout))??;
         let _payload = noise.read_message_2(&msg2)
             .map_err(|e| SocketError::HandshakeFailed(e.to_string()))?;
 
         // Send message 3 (-> s, se, payload)
-        let client_payload = self.build_client_payload();
-        let msg3 = noise.write_message_3(&client_payload)
+        let msg3 = noise.write_message_3(client_payload)
             .map_err(|e| SocketError::HandshakeFailed(e.to_string()))?;
-        let frame3 = self.build_handshake_frame(&msg3);
-        self.send_raw(&frame3).await?;
+        self.frames.send_frame(&msg3).await?;
 
         // Get remote static key
         let remote_static = noise.remote_static_key()
-            .ok_or(SocketError::HandshakeFailed("no remote static key".to_string()))?
-            .clone();
+            .copied()
+            .ok_or(SocketError::HandshakeFailed("no remote static key".to_string()))?;
 
         // Split into transport ciphers
         let (send_cipher, recv_cipher) = noise.split();
@@ -88,52 +155,12 @@ impl NoiseSocket {
         Ok(remote_static)
     }
 
-    /// Build handshake frame header.
-    fn build_handshake_frame(&self, data: &[u8]) -> Vec<u8> {
-        let mut frame = Vec::with_capacity(data.len() + 6);
-        // WhatsApp-specific header
-        frame.extend_from_slice(b"WA");
-        frame.push(6); // Major version
-        frame.push(0); // Minor version
-        // Length prefix
-        let len = data.len() as u32;
-        frame.extend_from_slice(&len.to_be_bytes()[1..]); // 3 bytes
-        frame.extend_from_slice(data);
-        frame
-    }
-
-    /// Parse handshake frame.
-    fn parse_handshake_frame(&self, data: &[u8]) -> Result<Vec<u8>, SocketError> {
-        if data.len() < 4 {
-            return Err(SocketError::InvalidFrame);
-        }
-        // Skip header and extract payload
-        Ok(data[4..].to_vec())
-    }
-
     /// Build client payload for handshake.
     fn build_client_payload(&self) -> Vec<u8> {
         // Minimal client payload - real implementation needs protobuf
         vec![0u8; 16]
     }
 
-    /// Send raw bytes (before encryption).
-    async fn send_raw(&mut self, data: &[u8]) -> Result<(), SocketError> {
-        self.ws
-            .send(Message::Binary(data.to_vec().into()))
-            .await
-            .map_err(|e| SocketError::SendFailed(e.to_string()))
-    }
-
-    /// Receive raw bytes.
-    async fn recv_raw(&mut self) -> Result<Vec<u8>, SocketError> {
-        match self.ws.next().await {
-            Some(Ok(Message::Binary(data))) => Ok(data.to_vec()),
-            Some(Err(e)) => Err(SocketError::ReceiveFailed(e.to_string())),
-            _ => Err(SocketError::ConnectionClosed),
-        }
-    }
-
     /// Send an encrypted frame.
     pub async fn send(&mut self, data: &[u8]) -> Result<(), SocketError> {
         if !self.handshake_complete {
@@ -142,17 +169,11 @@ impl NoiseSocket {
 
         let cipher = self.send_cipher.as_mut().ok_or(SocketError::NotConnected)?;
         
-        // Encrypt the data
-        let encrypted = cipher.encrypt(data, &[])
-            .map_err(|_| SocketError::EncryptionFailed)?;
+        // Encrypt the data behind its flags byte
+        let encrypted = cipher.encrypt(&pack_payload(data), &[])
+            .map_err(|e| cipher_error(e, SocketError::EncryptionFailed))?;
 
-        // Build frame with length prefix
-        let mut frame = Vec::with_capacity(encrypted.len() + 3);
-        let len = encrypted.len() as u32;
-        frame.extend_from_slice(&len.to_be_bytes()[1..]); // 3 bytes
-        frame.extend_from_slice(&encrypted);
-
-        self.send_raw(&frame).await
+        self.frames.send_frame(&encrypted).await
     }
 
     /// Receive and decrypt a frame.
@@ -161,18 +182,12 @@ impl NoiseSocket {
             return Err(SocketError::NotConnected);
         }
 
-        let data = self.recv_raw().await?;
-        
-        if data.len() < 3 {
-            return Err(SocketError::InvalidFrame);
-        }
-
+        let encrypted = self.frames.recv_frame().await?;
         let cipher = self.recv_cipher.as_mut().ok_or(SocketError::NotConnected)?;
-        
-        // Skip length prefix and decrypt
-        let encrypted = &data[3..];
-        cipher.decrypt(encrypted, &[])
-            .map_err(|_| SocketError::DecryptionFailed)
+
+        let payload = cipher.decrypt(&encrypted, &[])
+            .map_err(|e| cipher_error(e, SocketError::DecryptionFailed))?;
+        unpack_payload(&payload)
     }
 
     /// Check if the socket is connected and handshake is complete.
@@ -182,10 +197,16 @@ impl NoiseSocket {
 
     /// Close the connection.
     pub async fn close(&mut self) -> Result<(), SocketError> {
-        self.ws
-            .close(None)
-            .await
-            .map_err(|e| SocketError::SendFailed(e.to_string()))
+        self.frames.close().await
+    }
+}
+
+/// Keep nonce exhaustion apart from other cipher failures, as it calls
+/// for a reconnect rather than dropping the frame.
+fn cipher_error(error: CipherError, other: SocketError) -> SocketError {
+    match error {
+        CipherError::NonceExhausted => SocketError::NonceExhausted,
+        _ => other,
     }
 }
 
@@ -198,9 +219,18 @@ pub enum SocketError {
     ReceiveFailed(String),
     EncryptionFailed,
     DecryptionFailed,
+    /// The session keys ran out of nonces; reconnect for new ones
+    NonceExhausted,
     InvalidFrame,
+    FrameTooLarge(usize),
+    /// A frame was still incomplete after this many transport messages
+    TooManyMessages(usize),
+    /// No answer within the configured time
+    Timeout(HandshakeStage, std::time::Duration),
     NotConnected,
     ConnectionClosed,
+    /// The server closed the WebSocket with a close frame
+    ClosedByPeer(CloseReason),
 }
 
 impl std::fmt::Display for SocketError {
@@ -212,11 +242,48 @@ impl std::fmt::Display for SocketError {
             SocketError::ReceiveFailed(e) => write!(f, "receive failed: {}", e),
             SocketError::EncryptionFailed => write!(f, "encryption failed"),
             SocketError::DecryptionFailed => write!(f, "decryption failed"),
+            SocketError::NonceExhausted => write!(f, "nonce counter exhausted, reconnect required"),
             SocketError::InvalidFrame => write!(f, "invalid frame"),
+            SocketError::FrameTooLarge(len) => write!(f, "frame of {} bytes is too large", len),
+            SocketError::TooManyMessages(n) => write!(f, "frame incomplete after {} messages", n),
+            SocketError::Timeout(stage, after) => write!(f, "timed out after {:?} {}", after, stage),
             SocketError::NotConnected => write!(f, "not connected"),
             SocketError::ConnectionClosed => write!(f, "connection closed"),
+            SocketError::ClosedByPeer(close) => write!(f, "connection closed by server ({})", close),
         }
     }
 }
 
 impl std::error::Error for SocketError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::TransportError;
+
+    /// Transport whose peer never answers.
+    struct SilentTransport;
+
+    impl Transport for SilentTransport {
+        async fn send(&mut self, _frame: Vec<u8>) -> Result<(), TransportError> {
+            Ok(())
+        }
+
+        async fn recv(&mut self) -> Result<Option<Vec<u8>>, TransportError> {
+            std::future::pending().await
+        }
+
+        async fn close(&mut self) -> Result<(), TransportError> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_handshake_timeout() {
+        let config = HandshakeConfig { response_timeout: std::time::Duration::from_millis(10), ..Default::default() };
+        let mut socket = NoiseSocket::new(SilentTransport, None);
+        let result = socket.handshake_with_config(KeyPair::generate(), &config).await;
+        assert!(matches!(result, Err(SocketError::Timeout(HandshakeStage::ServerHello, _))));
+        assert!(!socket.is_connected());
+    }
+}
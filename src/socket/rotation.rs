@@ -0,0 +1,256 @@
+//! Endpoint selection.
+//!
+//! `EndpointRotation` orders the endpoints to try when connecting: the one
+//! that worked last, then hints from the server, then the configured
+//! endpoints. Endpoints that just failed are moved to the back.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::net::IpAddr;
+
+use crate::binary::Node;
+use crate::socket::endpoints;
+
+/// Build the WebSocket URL for a chat host name.
+pub fn chat_url(host: &str) -> String {
+    format!("wss://{}/ws/chat", host)
+}
+
+/// Server to connect to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Endpoint {
+    /// WebSocket URL; its host is used for TLS SNI and the Host header
+    pub url: String,
+    /// Address to connect to instead of resolving the URL host
+    pub ip: Option<IpAddr>,
+}
+
+impl Endpoint {
+    /// Endpoint reached by resolving the URL host.
+    pub fn new(url: impl Into<String>) -> Self {
+        Self { url: url.into(), ip: None }
+    }
+
+    /// Endpoint reached at a fixed address, still presenting the URL host.
+    pub fn with_ip(url: impl Into<String>, ip: IpAddr) -> Self {
+        Self { url: url.into(), ip: Some(ip) }
+    }
+}
+
+impl From<String> for Endpoint {
+    fn from(url: String) -> Self {
+        Self::new(url)
+    }
+}
+
+impl From<&str> for Endpoint {
+    fn from(url: &str) -> Self {
+        Self::new(url)
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.ip {
+            Some(ip) => write!(f, "{} ({})", self.url, ip),
+            None => write!(f, "{}", self.url),
+        }
+    }
+}
+
+/// Ordered set of endpoints to connect to.
+#[derive(Debug, Clone)]
+pub struct EndpointRotation {
+    /// Candidates in the order they will be tried
+    endpoints: VecDeque<Endpoint>,
+    /// Endpoint of the last successful connection
+    last_success: Option<Endpoint>,
+}
+
+impl EndpointRotation {
+    /// Create a rotation over the given endpoints, tried in order.
+    pub fn new<I>(endpoints: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Into<Endpoint>,
+    {
+        let mut rotation = Self { endpoints: VecDeque::new(), last_success: None };
+        for endpoint in endpoints {
+            let endpoint = endpoint.into();
+            if !rotation.endpoints.contains(&endpoint) {
+                rotation.endpoints.push_back(endpoint);
+            }
+        }
+        rotation
+    }
+
+    /// Endpoints in the order they should be tried.
+    pub fn candidates(&self) -> Vec<Endpoint> {
+        self.endpoints.iter().cloned().collect()
+    }
+
+    /// Endpoint of the last successful connection, if any.
+    pub fn last_success(&self) -> Option<&Endpoint> {
+        self.last_success.as_ref()
+    }
+
+    /// Record a successful connection; the endpoint is tried first next time.
+    pub fn record_success(&mut self, endpoint: &Endpoint) {
+        self.move_to_front(endpoint);
+        self.last_success = Some(endpoint.clone());
+    }
+
+    /// Record a failed connection attempt; the endpoint is tried last.
+    pub fn record_failure(&mut self, endpoint: &Endpoint) {
+        if let Some(pos) = self.endpoints.iter().position(|e| e == endpoint) {
+            let endpoint = self.endpoints.remove(pos).unwrap();
+            self.endpoints.push_back(endpoint);
+        }
+        if self.last_success.as_ref() == Some(endpoint) {
+            self.last_success = None;
+        }
+    }
+
+    /// Add an endpoint suggested by the server, tried first next time.
+    pub fn add_hint(&mut self, endpoint: Endpoint) {
+        self.move_to_front(&endpoint);
+    }
+
+    /// Add the endpoints suggested together by the server, all tried
+    /// before the others next time and in the given order, except that IP
+    /// literals of the preferred family come before those of the other
+    /// and host names come last.
+    pub fn add_hints(&mut self, hints: impl IntoIterator<Item = Endpoint>, prefer_ipv6: bool) {
+        let mut hints: Vec<Endpoint> = hints.into_iter().collect();
+        hints.sort_by_key(|hint| match hint.ip {
+            Some(ip) if ip.is_ipv6() == prefer_ipv6 => 0,
+            Some(_) => 1,
+            None => 2,
+        });
+        for hint in hints.iter().rev() {
+            self.move_to_front(hint);
+        }
+    }
+
+    fn move_to_front(&mut self, endpoint: &Endpoint) {
+        self.endpoints.retain(|e| e != endpoint);
+        self.endpoints.push_front(endpoint.clone());
+    }
+}
+
+/// Read a hint given as an attribute or as a child node.
+fn hint_values(node: &Node, name: &str) -> Vec<String> {
+    let mut values: Vec<String> = node.get_attr_str(name).map(str::to_string).into_iter().collect();
+    for child in node.get_children_by_tag(name) {
+        let value = child.get_attr_str("value")
+            .map(str::to_string)
+            .or_else(|| child.get_bytes().map(|b| String::from_utf8_lossy(b).to_string()));
+        values.extend(value.filter(|v| !v.is_empty()));
+    }
+    values
+}
+
+/// Extract fallback endpoint hints from a stream error or similar node.
+///
+/// Hints are given as `fallback_hostname`, `fallback_ip4` and
+/// `fallback_ip6` attributes or child nodes, on the node itself or its
+/// direct children. IP literals are reached with the hinted host name, or
+/// the main endpoint's, for TLS and the Host header.
+pub fn parse_endpoint_hints(node: &Node) -> Vec<Endpoint> {
+    let mut hints = Vec::new();
+    let mut collect = |node: &Node| {
+        let hosts: Vec<String> = hint_values(node, "fallback_hostname").iter().map(|h| chat_url(h)).collect();
+        let url = hosts.first().cloned().unwrap_or_else(|| endpoints::MAIN.to_string());
+
+        for ip in hint_values(node, "fallback_ip6").iter().chain(&hint_values(node, "fallback_ip4")) {
+            match ip.parse() {
+                Ok(ip) => hints.push(Endpoint::with_ip(url.clone(), ip)),
+                Err(_) => log::warn!("ignoring invalid fallback ip {:?}", ip),
+            }
+        }
+        hints.extend(hosts.into_iter().map(Endpoint::new));
+    };
+
+    collect(node);
+    for child in node.get_children().unwrap_or_default() {
+        collect(child);
+    }
+    hints
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::socket::endpoints;
+
+    #[test]
+    fn test_rotation_moves_failures_back() {
+        let mut rotation = EndpointRotation::new(endpoints::default_rotation());
+        assert_eq!(rotation.candidates()[0].url, endpoints::MAIN);
+
+        rotation.record_failure(&endpoints::MAIN.into());
+        let candidates = rotation.candidates();
+        assert_eq!(candidates[0], endpoints::fallback(1).into());
+        assert_eq!(candidates.last().unwrap().url, endpoints::MAIN);
+    }
+
+    #[test]
+    fn test_success_is_tried_first() {
+        let mut rotation = EndpointRotation::new(endpoints::default_rotation());
+        let endpoint = Endpoint::new(endpoints::fallback(3));
+        rotation.record_success(&endpoint);
+
+        assert_eq!(rotation.candidates()[0], endpoint);
+        assert_eq!(rotation.last_success(), Some(&endpoint));
+        assert_eq!(rotation.candidates().len(), 9);
+    }
+
+    #[test]
+    fn test_endpoint_hints() {
+        let mut error = Node::new("stream:error");
+        error.set_attr("code", "503");
+        let mut hint = Node::new("fallback_hostname");
+        hint.set_bytes(b"w5.web.whatsapp.com".to_vec());
+        error.add_child(hint);
+
+        let hints = parse_endpoint_hints(&error);
+        assert_eq!(hints, vec![Endpoint::new(endpoints::fallback(5))]);
+
+        let mut rotation = EndpointRotation::new(endpoints::default_rotation());
+        rotation.add_hint(hints[0].clone());
+        assert_eq!(rotation.candidates()[0], hints[0]);
+    }
+
+    #[test]
+    fn test_ip_literal_hints() {
+        let mut error = Node::new("stream:error");
+        error.set_attr("fallback_hostname", "w2.web.whatsapp.com");
+        error.set_attr("fallback_ip4", "157.240.0.53");
+        error.set_attr("fallback_ip6", "2a03:2880:f200::35");
+
+        let hints = parse_endpoint_hints(&error);
+        assert_eq!(hints, vec![
+            Endpoint::with_ip(endpoints::fallback(2), "2a03:2880:f200::35".parse().unwrap()),
+            Endpoint::with_ip(endpoints::fallback(2), "157.240.0.53".parse().unwrap()),
+            Endpoint::new(endpoints::fallback(2)),
+        ]);
+    }
+
+    #[test]
+    fn test_hints_keep_order_and_preference() {
+        let mut error = Node::new("stream:error");
+        error.set_attr("fallback_hostname", "w2.web.whatsapp.com");
+        error.set_attr("fallback_ip4", "157.240.0.53");
+        error.set_attr("fallback_ip6", "2a03:2880:f200::35");
+        let hints = parse_endpoint_hints(&error);
+
+        let mut rotation = EndpointRotation::new(endpoints::default_rotation());
+        rotation.add_hints(hints.clone(), true);
+        assert_eq!(rotation.candidates()[..3], hints[..]);
+
+        let mut rotation = EndpointRotation::new(endpoints::default_rotation());
+        rotation.add_hints(hints.clone(), false);
+        assert_eq!(rotation.candidates()[..3], [hints[1].clone(), hints[0].clone(), hints[2].clone()]);
+        assert_eq!(rotation.candidates()[3].url, endpoints::MAIN);
+    }
+}
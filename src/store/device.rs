@@ -2,9 +2,10 @@
 //!
 //! Stores device identity, keys, and session data required for WhatsApp connection.
 
-use crate::types::JID;
-use crate::crypto::{KeyPair, PreKey};
 use std::collections::HashMap;
+use crate::types::{servers, JID, MessageID, Message, MessageContent};
+use crate::store::{StoreError, StoreResult};
+use crate::crypto::{KeyPair, PreKey};
 
 /// Device represents a WhatsApp device/session.
 #[derive(Clone)]
@@ -29,6 +30,9 @@ pub struct Device {
     pub business_name: Option<String>,
     /// Push name
     pub push_name: Option<String>,
+    /// Our signed device identity from pairing, an encoded
+    /// `proto::AdvSignedDeviceIdentity`
+    pub account: Option<Vec<u8>>,
     /// Whether the device has been initialized
     pub initialized: bool,
 }
@@ -47,6 +51,7 @@ impl Device {
             platform: String::new(),
             business_name: None,
             push_name: None,
+            account: None,
             initialized: false,
         }
     }
@@ -102,6 +107,171 @@ pub struct ChatSettings {
     pub muted_until: Option<i64>,
     pub pinned: bool,
     pub archived: bool,
+    /// Disappearing message timer, applied to messages sent to the chat
+    pub ephemeral: Option<std::time::Duration>,
+}
+
+/// Message record for chat history.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredMessage {
+    pub id: MessageID,
+    pub chat: JID,
+    pub sender: JID,
+    pub is_from_me: bool,
+    pub timestamp: i64,
+    pub push_name: Option<String>,
+    /// Message text, or the caption for media messages
+    pub text: Option<String>,
+}
+
+impl From<&Message> for StoredMessage {
+    fn from(msg: &Message) -> Self {
+        let text = match &msg.content {
+            MessageContent::Text(text) => Some(text.clone()),
+            MessageContent::Image { caption, .. } | MessageContent::Video { caption, .. } => caption.clone(),
+            _ => None,
+        };
+
+        Self {
+            id: msg.info.id.clone(),
+            chat: msg.info.chat.clone(),
+            sender: msg.info.sender.clone(),
+            is_from_me: msg.info.is_from_me,
+            timestamp: msg.info.timestamp,
+            push_name: msg.info.push_name.clone(),
+            text,
+        }
+    }
+}
+
+/// Characters of text kept in a `MessageRef`.
+const SNIPPET_LENGTH: usize = 100;
+
+/// What is known of a message by its ID: enough to quote it in a reply,
+/// or to show what a received reply quotes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MessageRef {
+    pub id: MessageID,
+    pub chat: JID,
+    pub sender: JID,
+    pub timestamp: i64,
+    /// Start of the text or caption
+    pub snippet: Option<String>,
+}
+
+impl From<&StoredMessage> for MessageRef {
+    fn from(message: &StoredMessage) -> Self {
+        Self {
+            id: message.id.clone(),
+            chat: message.chat.clone(),
+            sender: message.sender.clone(),
+            timestamp: message.timestamp,
+            snippet: message.text.as_ref().map(|text| text.chars().take(SNIPPET_LENGTH).collect()),
+        }
+    }
+}
+
+/// Page of chat history, newest messages first.
+///
+/// Timestamps are in whole seconds, so messages sharing one are ordered
+/// by ID, and a page continues after the exact message the last one
+/// ended at.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MessagePage {
+    /// Only return messages older than this timestamp and ID
+    pub before: Option<(i64, String)>,
+    /// Maximum number of messages to return
+    pub limit: usize,
+}
+
+impl MessagePage {
+    /// The most recent messages of a chat.
+    pub fn latest(limit: usize) -> Self {
+        Self { before: None, limit }
+    }
+
+    /// Messages older than the one with the given timestamp and ID.
+    pub fn before(timestamp: i64, id: impl Into<String>, limit: usize) -> Self {
+        Self { before: Some((timestamp, id.into())), limit }
+    }
+
+    /// The page after one ending at `last`, its oldest message.
+    pub fn older_than(last: &StoredMessage, limit: usize) -> Self {
+        Self::before(last.timestamp, last.id.clone(), limit)
+    }
+
+    /// Whether a message sorts before the cursor, newest first.
+    pub fn includes(&self, message: &StoredMessage) -> bool {
+        self.before.as_ref().is_none_or(|(timestamp, id)| (message.timestamp, &message.id) < (*timestamp, id))
+    }
+}
+
+/// Message found by a chat history search.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MessageMatch {
+    pub message: StoredMessage,
+    /// Relevance of the match; higher is better
+    pub score: f64,
+}
+
+/// Chat in the stored history, with its latest message.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChatSummary {
+    pub chat: JID,
+    /// Number of stored messages
+    pub message_count: usize,
+    /// Unix timestamp of the latest message
+    pub last_timestamp: i64,
+    /// Text of the latest message
+    pub last_text: Option<String>,
+}
+
+/// Outgoing message persisted until the server acknowledges it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutgoingMessage {
+    pub id: MessageID,
+    pub to: JID,
+    pub text: String,
+    /// Unix timestamp of when the message was first accepted
+    pub created_at: i64,
+    /// Number of times the message was handed to the socket
+    pub attempts: u32,
+}
+
+/// Message waiting to be sent at a set time.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScheduledMessage {
+    /// Schedule ID, also used as the message ID when sent
+    pub id: MessageID,
+    pub to: JID,
+    pub text: String,
+    /// Unix timestamp of when to send
+    pub send_at: i64,
+}
+
+/// Key for decrypting app state patches, shared by the primary device.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AppStateSyncKey {
+    pub key_id: Vec<u8>,
+    pub key_data: Vec<u8>,
+    /// Serialized key fingerprint
+    pub fingerprint: Vec<u8>,
+    /// Creation time, in milliseconds since the Unix epoch
+    pub timestamp: i64,
+}
+
+/// Progress of a multi-step conversation in a chat.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ConversationState {
+    pub chat: JID,
+    /// Name of the flow being run, such as "booking"
+    pub flow: String,
+    /// Step of the flow waiting for a reply
+    pub step: String,
+    /// Answers collected so far
+    pub data: HashMap<String, String>,
+    /// Unix timestamp of the last reply or step change
+    pub updated_at: i64,
 }
 
 /// Pre-key record for storage.
@@ -126,6 +296,41 @@ impl From<&PreKey> for PreKeyRecord {
     }
 }
 
+/// Key of a sender key: the group, and the sender's device that
+/// distributed it.
+///
+/// Each device of a member has its own sender key, and the same number
+/// can appear both as a phone number and as a LID, so the sender is a full
+/// JID including its device and server.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SenderKeyName {
+    pub group: JID,
+    pub sender: JID,
+}
+
+impl SenderKeyName {
+    pub fn new(group: JID, sender: JID) -> Self {
+        Self { group, sender }
+    }
+
+    /// Build a name from the `group` and `user` strings the store used to
+    /// be keyed by. Bare users are taken as phone numbers, users without a
+    /// device as the primary device, and bare groups as group IDs.
+    pub fn from_legacy(group: &str, user: &str) -> StoreResult<Self> {
+        let parse = |s: &str, server: &str| {
+            let jid = if s.contains('@') { s.to_string() } else { format!("{}@{}", s, server) };
+            jid.parse::<JID>().map_err(|e| StoreError::SerializationError(e.to_string()))
+        };
+        Ok(Self::new(parse(group, servers::GROUP)?, parse(user, servers::DEFAULT_USER)?))
+    }
+}
+
+impl std::fmt::Display for SenderKeyName {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}::{}", self.group, self.sender.ad_string())
+    }
+}
+
 /// Session record for Signal Protocol sessions.
 #[derive(Debug, Clone)]
 pub struct SessionRecord {
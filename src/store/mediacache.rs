@@ -0,0 +1,212 @@
+//! Content-addressed media cache.
+//!
+//! Decrypted media is stored on disk under the hex SHA256 of the file, so
+//! forwarded media is only downloaded once. When the cache grows past its
+//! maximum size, the least recently used files are evicted.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+use sha2::{Digest, Sha256};
+
+use crate::media::{DownloadableMedia, MediaError};
+
+/// Default maximum cache size (512 MiB).
+pub const DEFAULT_MAX_SIZE: u64 = 512 * 1024 * 1024;
+
+/// On-disk media cache.
+pub struct MediaCache {
+    dir: PathBuf,
+    max_size: u64,
+    /// Serializes writes and eviction
+    write_lock: Mutex<()>,
+}
+
+impl MediaCache {
+    /// Open a cache in a directory, creating it if needed.
+    pub fn new<P: AsRef<Path>>(dir: P, max_size: u64) -> io::Result<Self> {
+        fs::create_dir_all(dir.as_ref())?;
+        Ok(Self {
+            dir: dir.as_ref().to_path_buf(),
+            max_size,
+            write_lock: Mutex::new(()),
+        })
+    }
+
+    /// Get the cache directory.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Get the maximum cache size in bytes.
+    pub fn max_size(&self) -> u64 {
+        self.max_size
+    }
+
+    /// Path of the cache entry for a file hash.
+    pub fn path_for(&self, sha256: &[u8]) -> PathBuf {
+        self.dir.join(hex::encode(sha256))
+    }
+
+    /// Look up a file by its SHA256.
+    pub fn get(&self, sha256: &[u8]) -> Option<Vec<u8>> {
+        let path = self.path_for(sha256);
+        let data = fs::read(&path).ok()?;
+
+        // Entries are evicted by modification time, so mark this one as used
+        if let Ok(file) = fs::File::options().append(true).open(&path) {
+            let _ = file.set_modified(SystemTime::now());
+        }
+        Some(data)
+    }
+
+    /// Add a file to the cache, evicting old entries if it grew too large.
+    pub fn put(&self, data: &[u8]) -> io::Result<PathBuf> {
+        let path = self.path_for(&Sha256::digest(data));
+        let _guard = self.write_lock.lock().unwrap();
+
+        // Write to a temporary file first so readers never see partial files
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, data)?;
+        fs::rename(&tmp, &path)?;
+
+        self.evict(&path)?;
+        Ok(path)
+    }
+
+    /// Total size of the cached files.
+    pub fn size(&self) -> io::Result<u64> {
+        Ok(self.entries()?.iter().map(|(_, size, _)| size).sum())
+    }
+
+    /// Remove every cached file.
+    pub fn clear(&self) -> io::Result<()> {
+        let _guard = self.write_lock.lock().unwrap();
+        for (path, _, _) in self.entries()? {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    /// Get a media file from the cache, downloading it on a miss.
+    pub async fn get_or_download(&self, media: &DownloadableMedia) -> Result<Vec<u8>, MediaError> {
+        if let Some(data) = media.details.file_sha256.as_deref().and_then(|sha| self.get(sha)) {
+            return Ok(data);
+        }
+
+        let data = media.download().await?;
+        if let Err(e) = self.put(&data) {
+            log::warn!("failed to cache media: {}", e);
+        }
+        Ok(data)
+    }
+
+    /// Cached files with their size and last use.
+    fn entries(&self) -> io::Result<Vec<(PathBuf, u64, SystemTime)>> {
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            if path.extension().is_some() {
+                continue;
+            }
+            let metadata = entry.metadata()?;
+            if metadata.is_file() {
+                entries.push((path, metadata.len(), metadata.modified()?));
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Remove least recently used files until the cache fits, keeping `keep`.
+    fn evict(&self, keep: &Path) -> io::Result<()> {
+        let mut entries = self.entries()?;
+        let mut size: u64 = entries.iter().map(|(_, size, _)| size).sum();
+        entries.sort_by_key(|(_, _, modified)| *modified);
+
+        for (path, file_size, _) in entries {
+            if size <= self.max_size {
+                break;
+            }
+            if path == keep {
+                continue;
+            }
+            fs::remove_file(&path)?;
+            size -= file_size;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use crate::media::MediaType;
+    use crate::types::MediaDetails;
+
+    fn test_cache(max_size: u64) -> MediaCache {
+        let dir = std::env::temp_dir().join(format!("whatsmeow-media-{}", uuid::Uuid::new_v4()));
+        MediaCache::new(dir, max_size).unwrap()
+    }
+
+    fn age(cache: &MediaCache, data: &[u8], secs: u64) {
+        let path = cache.path_for(&Sha256::digest(data));
+        let file = fs::File::options().append(true).open(path).unwrap();
+        file.set_modified(SystemTime::now() - Duration::from_secs(secs)).unwrap();
+    }
+
+    #[test]
+    fn test_put_and_get() {
+        let cache = test_cache(DEFAULT_MAX_SIZE);
+        let path = cache.put(b"hello").unwrap();
+
+        assert!(path.starts_with(cache.dir()));
+        assert_eq!(cache.get(&Sha256::digest(b"hello")).unwrap(), b"hello");
+        assert!(cache.get(&Sha256::digest(b"other")).is_none());
+        assert_eq!(cache.size().unwrap(), 5);
+
+        cache.clear().unwrap();
+        assert_eq!(cache.size().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let cache = test_cache(10);
+        cache.put(b"aaaa").unwrap();
+        age(&cache, b"aaaa", 20);
+        cache.put(b"bbbb").unwrap();
+        age(&cache, b"bbbb", 10);
+
+        // Reading refreshes the entry, so "bbbb" is now the oldest
+        cache.get(&Sha256::digest(b"aaaa")).unwrap();
+        cache.put(b"cccc").unwrap();
+
+        assert!(cache.get(&Sha256::digest(b"aaaa")).is_some());
+        assert!(cache.get(&Sha256::digest(b"bbbb")).is_none());
+        assert!(cache.get(&Sha256::digest(b"cccc")).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_get_or_download_hits_cache() {
+        let cache = test_cache(DEFAULT_MAX_SIZE);
+        cache.put(b"forwarded").unwrap();
+
+        // No URL: anything but a cache hit would fail
+        let media = DownloadableMedia {
+            media_type: MediaType::Image,
+            url: String::new(),
+            mimetype: "image/jpeg".to_string(),
+            details: MediaDetails {
+                file_sha256: Some(Sha256::digest(b"forwarded").to_vec()),
+                ..Default::default()
+            },
+        };
+        assert_eq!(cache.get_or_download(&media).await.unwrap(), b"forwarded");
+
+        cache.clear().unwrap();
+        assert_eq!(cache.get_or_download(&media).await, Err(MediaError::MissingUrl));
+    }
+}
@@ -7,21 +7,33 @@ use std::sync::RwLock;
 
 use crate::types::JID;
 use crate::store::{
-    Device, ContactInfo, ChatSettings, PreKeyRecord,
-    IdentityStore, SessionStore, PreKeyStore, SenderKeyStore, 
-    ContactStore, ChatSettingsStore, DeviceStore,
+    Device, ContactInfo, ChatSettings, MessageRef, PreKeyRecord, StoredMessage, MessagePage, MessageMatch, OutgoingMessage,
+    IdentityStore, SessionStore, PreKeyStore, SenderKeyStore, SenderKeyName, AppStateKeyStore, AppStateSyncKey,
+    ContactStore, ChatSettingsStore, MsgSecretStore, MessageIndexStore, DeviceStore, ChatStore, OutboxStore,
+    ConversationStore, ConversationState, ScheduleStore, ScheduledMessage,
     StoreError, StoreResult,
 };
 
+/// Most messages kept in the message index; past this the oldest quarter
+/// is dropped.
+const MESSAGE_REF_CAPACITY: usize = 100_000;
+
 /// In-memory implementation of all store traits.
 pub struct MemoryStore {
     devices: RwLock<HashMap<String, Device>>,
     identities: RwLock<HashMap<String, [u8; 32]>>,
     sessions: RwLock<HashMap<String, Vec<u8>>>,
     pre_keys: RwLock<HashMap<u32, PreKeyRecord>>,
-    sender_keys: RwLock<HashMap<String, Vec<u8>>>,
+    sender_keys: RwLock<HashMap<SenderKeyName, Vec<u8>>>,
+    app_state_keys: RwLock<HashMap<Vec<u8>, AppStateSyncKey>>,
     contacts: RwLock<HashMap<String, ContactInfo>>,
     chat_settings: RwLock<HashMap<String, ChatSettings>>,
+    message_secrets: RwLock<HashMap<(String, String, String), Vec<u8>>>,
+    message_refs: RwLock<HashMap<(String, String), MessageRef>>,
+    messages: RwLock<HashMap<String, Vec<StoredMessage>>>,
+    outbox: RwLock<HashMap<String, OutgoingMessage>>,
+    conversations: RwLock<HashMap<String, ConversationState>>,
+    scheduled: RwLock<HashMap<String, ScheduledMessage>>,
 }
 
 impl MemoryStore {
@@ -33,8 +45,15 @@ impl MemoryStore {
             sessions: RwLock::new(HashMap::new()),
             pre_keys: RwLock::new(HashMap::new()),
             sender_keys: RwLock::new(HashMap::new()),
+            app_state_keys: RwLock::new(HashMap::new()),
             contacts: RwLock::new(HashMap::new()),
             chat_settings: RwLock::new(HashMap::new()),
+            message_secrets: RwLock::new(HashMap::new()),
+            message_refs: RwLock::new(HashMap::new()),
+            messages: RwLock::new(HashMap::new()),
+            outbox: RwLock::new(HashMap::new()),
+            conversations: RwLock::new(HashMap::new()),
+            scheduled: RwLock::new(HashMap::new()),
         }
     }
 }
@@ -174,20 +193,73 @@ impl PreKeyStore for MemoryStore {
 }
 
 impl SenderKeyStore for MemoryStore {
-    fn get_sender_key(&self, group: &str, user: &str) -> StoreResult<Option<Vec<u8>>> {
-        let key = format!("{}:{}", group, user);
+    fn get_sender_key_by_name(&self, name: &SenderKeyName) -> StoreResult<Option<Vec<u8>>> {
         let sender_keys = self.sender_keys.read()
             .map_err(|_| StoreError::DatabaseError("lock poisoned".to_string()))?;
-        Ok(sender_keys.get(&key).cloned())
+        Ok(sender_keys.get(name).cloned())
+    }
+
+    fn put_sender_key_by_name(&self, name: &SenderKeyName, session: &[u8]) -> StoreResult<()> {
+        let mut sender_keys = self.sender_keys.write()
+            .map_err(|_| StoreError::DatabaseError("lock poisoned".to_string()))?;
+        sender_keys.insert(name.clone(), session.to_vec());
+        Ok(())
     }
 
-    fn put_sender_key(&self, group: &str, user: &str, session: &[u8]) -> StoreResult<()> {
-        let key = format!("{}:{}", group, user);
+    fn delete_sender_key_by_name(&self, name: &SenderKeyName) -> StoreResult<()> {
         let mut sender_keys = self.sender_keys.write()
             .map_err(|_| StoreError::DatabaseError("lock poisoned".to_string()))?;
-        sender_keys.insert(key, session.to_vec());
+        sender_keys.remove(name);
         Ok(())
     }
+
+    fn delete_user_sender_keys(&self, group: &JID, user: &JID) -> StoreResult<()> {
+        let mut sender_keys = self.sender_keys.write()
+            .map_err(|_| StoreError::DatabaseError("lock poisoned".to_string()))?;
+        sender_keys.retain(|name, _| {
+            name.group != *group || name.sender.user != user.user || name.sender.server != user.server
+        });
+        Ok(())
+    }
+
+    fn sender_key_devices(&self, group: &JID, user: &JID) -> StoreResult<Vec<JID>> {
+        let sender_keys = self.sender_keys.read()
+            .map_err(|_| StoreError::DatabaseError("lock poisoned".to_string()))?;
+        let mut devices: Vec<JID> = sender_keys.keys()
+            .filter(|name| name.group == *group && name.sender.user == user.user && name.sender.server == user.server)
+            .map(|name| name.sender.clone())
+            .collect();
+        devices.sort_by_key(|sender| sender.device);
+        Ok(devices)
+    }
+}
+
+impl AppStateKeyStore for MemoryStore {
+    fn get_app_state_key(&self, key_id: &[u8]) -> StoreResult<Option<AppStateSyncKey>> {
+        let keys = self.app_state_keys.read()
+            .map_err(|_| StoreError::DatabaseError("lock poisoned".to_string()))?;
+        Ok(keys.get(key_id).cloned())
+    }
+
+    fn put_app_state_key(&self, key: &AppStateSyncKey) -> StoreResult<()> {
+        let mut keys = self.app_state_keys.write()
+            .map_err(|_| StoreError::DatabaseError("lock poisoned".to_string()))?;
+        keys.insert(key.key_id.clone(), key.clone());
+        Ok(())
+    }
+
+    fn put_app_state_keys(&self, batch: &[AppStateSyncKey]) -> StoreResult<()> {
+        let mut keys = self.app_state_keys.write()
+            .map_err(|_| StoreError::DatabaseError("lock poisoned".to_string()))?;
+        keys.extend(batch.iter().map(|key| (key.key_id.clone(), key.clone())));
+        Ok(())
+    }
+
+    fn latest_app_state_key(&self) -> StoreResult<Option<AppStateSyncKey>> {
+        let keys = self.app_state_keys.read()
+            .map_err(|_| StoreError::DatabaseError("lock poisoned".to_string()))?;
+        Ok(keys.values().max_by_key(|key| key.timestamp).cloned())
+    }
 }
 
 impl ContactStore for MemoryStore {
@@ -209,6 +281,13 @@ impl ContactStore for MemoryStore {
             .map_err(|_| StoreError::DatabaseError("lock poisoned".to_string()))?;
         Ok(contacts.values().cloned().collect())
     }
+
+    fn put_contacts(&self, batch: &[ContactInfo]) -> StoreResult<()> {
+        let mut contacts = self.contacts.write()
+            .map_err(|_| StoreError::DatabaseError("lock poisoned".to_string()))?;
+        contacts.extend(batch.iter().map(|contact| (contact.jid.to_string(), contact.clone())));
+        Ok(())
+    }
 }
 
 impl ChatSettingsStore for MemoryStore {
@@ -226,10 +305,231 @@ impl ChatSettingsStore for MemoryStore {
     }
 }
 
+/// Key of a message secret: the sender without its device, as secrets
+/// are shared by all devices of an account.
+fn secret_key(chat: &JID, sender: &JID, id: &str) -> (String, String, String) {
+    (chat.to_string(), sender.to_non_ad().to_string(), id.to_string())
+}
+
+impl MsgSecretStore for MemoryStore {
+    fn get_message_secret(&self, chat: &JID, sender: &JID, id: &str) -> StoreResult<Option<Vec<u8>>> {
+        let secrets = self.message_secrets.read()
+            .map_err(|_| StoreError::DatabaseError("lock poisoned".to_string()))?;
+        Ok(secrets.get(&secret_key(chat, sender, id)).cloned())
+    }
+
+    fn put_message_secret(&self, chat: &JID, sender: &JID, id: &str, secret: &[u8]) -> StoreResult<()> {
+        let mut secrets = self.message_secrets.write()
+            .map_err(|_| StoreError::DatabaseError("lock poisoned".to_string()))?;
+        secrets.insert(secret_key(chat, sender, id), secret.to_vec());
+        Ok(())
+    }
+}
+
+impl MessageIndexStore for MemoryStore {
+    fn get_message_ref(&self, chat: &JID, id: &str) -> StoreResult<Option<MessageRef>> {
+        let refs = self.message_refs.read()
+            .map_err(|_| StoreError::DatabaseError("lock poisoned".to_string()))?;
+        Ok(refs.get(&(chat.to_string(), id.to_string())).cloned())
+    }
+
+    fn put_message_ref(&self, message: &MessageRef) -> StoreResult<()> {
+        let mut refs = self.message_refs.write()
+            .map_err(|_| StoreError::DatabaseError("lock poisoned".to_string()))?;
+        refs.insert((message.chat.to_string(), message.id.clone()), message.clone());
+        if refs.len() > MESSAGE_REF_CAPACITY {
+            let mut timestamps: Vec<i64> = refs.values().map(|r| r.timestamp).collect();
+            let (_, &mut cutoff, _) = timestamps.select_nth_unstable(MESSAGE_REF_CAPACITY / 4);
+            refs.retain(|_, r| r.timestamp > cutoff);
+        }
+        Ok(())
+    }
+
+    fn prune_message_refs(&self, before: i64) -> StoreResult<()> {
+        let mut refs = self.message_refs.write()
+            .map_err(|_| StoreError::DatabaseError("lock poisoned".to_string()))?;
+        refs.retain(|_, r| r.timestamp >= before);
+        Ok(())
+    }
+}
+
+impl ChatStore for MemoryStore {
+    fn put_message(&self, message: &StoredMessage) -> StoreResult<()> {
+        let mut messages = self.messages.write()
+            .map_err(|_| StoreError::DatabaseError("lock poisoned".to_string()))?;
+        let chat = messages.entry(message.chat.to_string()).or_default();
+        chat.retain(|m| m.id != message.id);
+        chat.push(message.clone());
+        Ok(())
+    }
+
+    fn put_messages(&self, batch: &[StoredMessage]) -> StoreResult<()> {
+        let mut messages = self.messages.write()
+            .map_err(|_| StoreError::DatabaseError("lock poisoned".to_string()))?;
+        for message in batch {
+            let chat = messages.entry(message.chat.to_string()).or_default();
+            chat.retain(|m| m.id != message.id);
+            chat.push(message.clone());
+        }
+        Ok(())
+    }
+
+    fn get_messages(&self, chat: &JID, page: MessagePage) -> StoreResult<Vec<StoredMessage>> {
+        let messages = self.messages.read()
+            .map_err(|_| StoreError::DatabaseError("lock poisoned".to_string()))?;
+        let mut result: Vec<StoredMessage> = messages.get(&chat.to_string())
+            .map(|chat| {
+                chat.iter()
+                    .filter(|m| page.includes(m))
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default();
+        result.sort_by(|a, b| (b.timestamp, &b.id).cmp(&(a.timestamp, &a.id)));
+        result.truncate(page.limit);
+        Ok(result)
+    }
+
+    fn search(&self, query: &str, chat: Option<&JID>, limit: usize) -> StoreResult<Vec<MessageMatch>> {
+        let messages = self.messages.read()
+            .map_err(|_| StoreError::DatabaseError("lock poisoned".to_string()))?;
+        let terms: Vec<String> = query.split_whitespace().map(str::to_lowercase).collect();
+        if terms.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut result: Vec<MessageMatch> = messages.iter()
+            .filter(|(key, _)| chat.is_none_or(|chat| **key == chat.to_string()))
+            .flat_map(|(_, chat)| chat.iter())
+            .filter_map(|m| {
+                let text = m.text.as_ref()?.to_lowercase();
+                let words: Vec<&str> = text.split(|c: char| !c.is_alphanumeric())
+                    .filter(|w| !w.is_empty())
+                    .collect();
+
+                // Every term must match the start of some word
+                let mut hits = 0;
+                for term in &terms {
+                    let count = words.iter().filter(|w| w.starts_with(term.as_str())).count();
+                    if count == 0 {
+                        return None;
+                    }
+                    hits += count;
+                }
+
+                Some(MessageMatch {
+                    message: m.clone(),
+                    score: hits as f64 / words.len() as f64,
+                })
+            })
+            .collect();
+        result.sort_by(|a, b| {
+            b.score.total_cmp(&a.score)
+                .then(b.message.timestamp.cmp(&a.message.timestamp))
+        });
+        result.truncate(limit);
+        Ok(result)
+    }
+}
+
+impl OutboxStore for MemoryStore {
+    fn put_outgoing(&self, message: &OutgoingMessage) -> StoreResult<()> {
+        let mut outbox = self.outbox.write()
+            .map_err(|_| StoreError::DatabaseError("lock poisoned".to_string()))?;
+        outbox.insert(message.id.clone(), message.clone());
+        Ok(())
+    }
+
+    fn ack_outgoing(&self, id: &str) -> StoreResult<()> {
+        let mut outbox = self.outbox.write()
+            .map_err(|_| StoreError::DatabaseError("lock poisoned".to_string()))?;
+        outbox.remove(id);
+        Ok(())
+    }
+
+    fn pending_outgoing(&self) -> StoreResult<Vec<OutgoingMessage>> {
+        let outbox = self.outbox.read()
+            .map_err(|_| StoreError::DatabaseError("lock poisoned".to_string()))?;
+        let mut result: Vec<OutgoingMessage> = outbox.values().cloned().collect();
+        result.sort_by_key(|m| m.created_at);
+        Ok(result)
+    }
+}
+
+impl ScheduleStore for MemoryStore {
+    fn put_scheduled(&self, message: &ScheduledMessage) -> StoreResult<()> {
+        let mut scheduled = self.scheduled.write()
+            .map_err(|_| StoreError::DatabaseError("lock poisoned".to_string()))?;
+        scheduled.insert(message.id.clone(), message.clone());
+        Ok(())
+    }
+
+    fn remove_scheduled(&self, id: &str) -> StoreResult<bool> {
+        let mut scheduled = self.scheduled.write()
+            .map_err(|_| StoreError::DatabaseError("lock poisoned".to_string()))?;
+        Ok(scheduled.remove(id).is_some())
+    }
+
+    fn pending_scheduled(&self) -> StoreResult<Vec<ScheduledMessage>> {
+        let scheduled = self.scheduled.read()
+            .map_err(|_| StoreError::DatabaseError("lock poisoned".to_string()))?;
+        let mut result: Vec<ScheduledMessage> = scheduled.values().cloned().collect();
+        result.sort_by_key(|m| m.send_at);
+        Ok(result)
+    }
+}
+
+impl ConversationStore for MemoryStore {
+    fn get_conversation(&self, chat: &JID) -> StoreResult<Option<ConversationState>> {
+        let conversations = self.conversations.read()
+            .map_err(|_| StoreError::DatabaseError("lock poisoned".to_string()))?;
+        Ok(conversations.get(&chat.to_string()).cloned())
+    }
+
+    fn put_conversation(&self, state: &ConversationState) -> StoreResult<()> {
+        let mut conversations = self.conversations.write()
+            .map_err(|_| StoreError::DatabaseError("lock poisoned".to_string()))?;
+        conversations.insert(state.chat.to_string(), state.clone());
+        Ok(())
+    }
+
+    fn delete_conversation(&self, chat: &JID) -> StoreResult<()> {
+        let mut conversations = self.conversations.write()
+            .map_err(|_| StoreError::DatabaseError("lock poisoned".to_string()))?;
+        conversations.remove(&chat.to_string());
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_message_refs_are_pruned_and_capped() {
+        let store = MemoryStore::new();
+        let chat = JID::new("1", "s.whatsapp.net");
+        let message_ref = |id: usize, timestamp: i64| MessageRef {
+            id: id.to_string(),
+            chat: chat.clone(),
+            sender: chat.clone(),
+            timestamp,
+            snippet: None,
+        };
+        store.put_message_ref(&message_ref(0, 100)).unwrap();
+        store.put_message_ref(&message_ref(1, 200)).unwrap();
+        store.prune_message_refs(150).unwrap();
+        assert!(store.get_message_ref(&chat, "0").unwrap().is_none());
+        assert!(store.get_message_ref(&chat, "1").unwrap().is_some());
+
+        for id in 2..=MESSAGE_REF_CAPACITY + 1 {
+            store.put_message_ref(&message_ref(id, 200 + id as i64)).unwrap();
+        }
+        let refs = store.message_refs.read().unwrap();
+        assert!(refs.len() <= MESSAGE_REF_CAPACITY * 3 / 4 + 1);
+        assert!(refs.contains_key(&(chat.to_string(), (MESSAGE_REF_CAPACITY + 1).to_string())));
+    }
+
     #[test]
     fn test_memory_store_identity() {
         let store = MemoryStore::new();
@@ -252,6 +552,132 @@ mod tests {
         assert_eq!(store.get_session("user@domain").unwrap(), Some(session));
     }
 
+    #[test]
+    #[allow(deprecated)]
+    fn test_memory_store_sender_keys() {
+        let store = MemoryStore::new();
+        let group = JID::new("120363000000000000", "g.us");
+        let phone = SenderKeyName::new(group.clone(), JID::new_ad("123", 0, 1));
+        let other_device = SenderKeyName::new(group.clone(), JID::new_ad("123", 0, 2));
+        let lid = SenderKeyName::new(group.clone(), JID { device: 1, ..JID::new("123", "lid") });
+
+        store.put_sender_key_by_name(&phone, &[1]).unwrap();
+        store.put_sender_key_by_name(&other_device, &[2]).unwrap();
+        store.put_sender_key_by_name(&lid, &[3]).unwrap();
+        assert_eq!(store.get_sender_key_by_name(&phone).unwrap(), Some(vec![1]));
+        assert_eq!(store.get_sender_key_by_name(&other_device).unwrap(), Some(vec![2]));
+        assert_eq!(store.get_sender_key_by_name(&lid).unwrap(), Some(vec![3]));
+
+        // The old string keys still reach the same entries, a bare user
+        // the device that has a key rather than device 0
+        assert_eq!(store.get_sender_key("120363000000000000", "123:1").unwrap(), Some(vec![1]));
+        assert_eq!(store.get_sender_key("120363000000000000", "123").unwrap(), Some(vec![1]));
+        store.put_sender_key("120363000000000000@g.us", "123", &[4]).unwrap();
+        assert_eq!(store.get_sender_key_by_name(&phone).unwrap(), Some(vec![4]));
+        assert_eq!(store.sender_key_devices(&group, &JID::new("123", "s.whatsapp.net")).unwrap().len(), 2);
+        store.delete_sender_key("120363000000000000@g.us", "123:2@s.whatsapp.net").unwrap();
+        assert_eq!(store.get_sender_key_by_name(&other_device).unwrap(), None);
+
+        // A departing member loses the keys of all their devices
+        store.put_sender_key_by_name(&other_device, &[2]).unwrap();
+        store.delete_user_sender_keys(&group, &JID::new("123", "s.whatsapp.net")).unwrap();
+        assert_eq!(store.get_sender_key_by_name(&phone).unwrap(), None);
+        assert_eq!(store.get_sender_key_by_name(&other_device).unwrap(), None);
+        assert_eq!(store.get_sender_key_by_name(&lid).unwrap(), Some(vec![3]));
+        assert!(store.get_sender_key("120363000000000000", "123:x").is_err());
+
+        // With no key stored, a bare user is the primary device
+        store.put_sender_key("120363000000000000", "456", &[5]).unwrap();
+        let primary = SenderKeyName::new(group.clone(), JID::new("456", "s.whatsapp.net"));
+        assert_eq!(store.get_sender_key_by_name(&primary).unwrap(), Some(vec![5]));
+        store.delete_sender_key("120363000000000000", "456").unwrap();
+        assert_eq!(store.get_sender_key_by_name(&primary).unwrap(), None);
+    }
+
+    #[test]
+    fn test_memory_store_batches() {
+        let store = MemoryStore::new();
+        let contacts: Vec<_> = (1..=3)
+            .map(|i| ContactInfo { jid: JID::new(i.to_string(), "s.whatsapp.net"), ..Default::default() })
+            .collect();
+        store.put_contacts(&contacts).unwrap();
+        assert_eq!(store.get_all_contacts().unwrap().len(), 3);
+
+        let key = |id: u8, timestamp| AppStateSyncKey { key_id: vec![id], key_data: vec![0; 32], fingerprint: Vec::new(), timestamp };
+        store.put_app_state_keys(&[key(1, 10), key(2, 20)]).unwrap();
+        assert_eq!(store.latest_app_state_key().unwrap().unwrap().key_id, vec![2]);
+
+        let chat = JID::new("1", "s.whatsapp.net");
+        store.put_messages(&[stored("a", &chat, 1, "one"), stored("a", &chat, 2, "two")]).unwrap();
+        let messages = store.get_messages(&chat, MessagePage::latest(10)).unwrap();
+        assert_eq!(messages.iter().map(|m| m.text.as_deref()).collect::<Vec<_>>(), [Some("two")]);
+    }
+
+    fn stored(id: &str, chat: &JID, timestamp: i64, text: &str) -> StoredMessage {
+        StoredMessage {
+            id: id.to_string(),
+            chat: chat.clone(),
+            sender: chat.clone(),
+            is_from_me: false,
+            timestamp,
+            push_name: None,
+            text: Some(text.to_string()),
+        }
+    }
+
+    #[test]
+    fn test_memory_store_messages() {
+        let store = MemoryStore::new();
+        let chat = JID::new("123", "s.whatsapp.net");
+        store.put_message(&stored("a", &chat, 1, "hello there")).unwrap();
+        store.put_message(&stored("b", &chat, 2, "general kenobi")).unwrap();
+        store.put_message(&stored("c", &chat, 3, "hello again")).unwrap();
+
+        let latest = store.get_messages(&chat, MessagePage::latest(2)).unwrap();
+        assert_eq!(latest.iter().map(|m| m.id.as_str()).collect::<Vec<_>>(), vec!["c", "b"]);
+
+        let older = store.get_messages(&chat, MessagePage::older_than(&latest[1], 10)).unwrap();
+        assert_eq!(older.len(), 1);
+        assert_eq!(older[0].id, "a");
+
+        let found = store.search("HELLO", Some(&chat), 10).unwrap();
+        assert_eq!(found.len(), 2);
+        assert!(store.search("hello kenobi", Some(&chat), 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_memory_store_pages_through_shared_timestamp() {
+        let store = MemoryStore::new();
+        let chat = JID::new("123", "s.whatsapp.net");
+        for id in ["a", "b", "c", "d", "e"] {
+            store.put_message(&stored(id, &chat, 7, "burst")).unwrap();
+        }
+        store.put_message(&stored("z", &chat, 6, "before")).unwrap();
+
+        let mut ids = Vec::new();
+        let mut page = MessagePage::latest(2);
+        loop {
+            let messages = store.get_messages(&chat, page.clone()).unwrap();
+            let Some(last) = messages.last() else { break };
+            page = MessagePage::older_than(last, 2);
+            ids.extend(messages.iter().map(|m| m.id.clone()));
+        }
+        assert_eq!(ids, ["e", "d", "c", "b", "a", "z"]);
+    }
+
+    #[test]
+    fn test_memory_store_search_ranking() {
+        let store = MemoryStore::new();
+        let chat = JID::new("123", "s.whatsapp.net");
+        store.put_message(&stored("a", &chat, 1, "lunch today?")).unwrap();
+        store.put_message(&stored("b", &chat, 2, "maybe lunch, maybe dinner, who knows")).unwrap();
+
+        let found = store.search("lunch", None, 10).unwrap();
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[0].message.id, "a");
+        assert!(found[0].score > found[1].score);
+    }
+
     #[test]
     fn test_memory_store_contact() {
         let store = MemoryStore::new();
@@ -0,0 +1,266 @@
+//! Schema versions of the SQLite store.
+//!
+//! Each schema change is a numbered migration. The versions applied to a
+//! database are kept in its `schema_migrations` table, so opening an older
+//! database applies only the migrations it is missing, each in its own
+//! transaction, and nobody has to delete their database to upgrade.
+//! Migrations are only ever appended; a released one never changes.
+
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::store::{StoreError, StoreResult};
+
+/// One schema change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Migration {
+    /// Position in the sequence, starting at 1
+    pub version: u32,
+    pub description: &'static str,
+    /// Statements applying the change
+    pub sql: &'static str,
+}
+
+/// Migrations of `SqliteStore`, oldest first.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "messages with full-text search, outbox, schedule and conversations",
+        // Databases from before versioning already have these tables
+        sql: "
+            CREATE TABLE IF NOT EXISTS messages (
+                chat TEXT NOT NULL,
+                id TEXT NOT NULL,
+                sender TEXT NOT NULL,
+                from_me INTEGER NOT NULL,
+                timestamp INTEGER NOT NULL,
+                push_name TEXT,
+                text TEXT,
+                PRIMARY KEY (chat, id)
+            );
+            CREATE INDEX IF NOT EXISTS messages_chat_timestamp ON messages (chat, timestamp);
+            CREATE VIRTUAL TABLE IF NOT EXISTS messages_fts USING fts5 (text);
+            CREATE TABLE IF NOT EXISTS outbox (
+                id TEXT PRIMARY KEY,
+                recipient TEXT NOT NULL,
+                text TEXT NOT NULL,
+                created_at INTEGER NOT NULL,
+                attempts INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS scheduled (
+                id TEXT PRIMARY KEY,
+                recipient TEXT NOT NULL,
+                text TEXT NOT NULL,
+                send_at INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS conversations (
+                chat TEXT PRIMARY KEY,
+                flow TEXT NOT NULL,
+                step TEXT NOT NULL,
+                data TEXT NOT NULL,
+                updated_at INTEGER NOT NULL
+            );
+        ",
+    },
+    Migration {
+        version: 2,
+        description: "signal sessions and pre-keys",
+        sql: "
+            CREATE TABLE sessions (
+                address TEXT PRIMARY KEY,
+                record BLOB NOT NULL
+            );
+            CREATE TABLE pre_keys (
+                id INTEGER PRIMARY KEY,
+                public_key BLOB NOT NULL,
+                private_key BLOB NOT NULL,
+                signature BLOB,
+                uploaded INTEGER NOT NULL
+            );
+        ",
+    },
+    Migration {
+        version: 3,
+        description: "contacts and app state sync keys",
+        sql: "
+            CREATE TABLE contacts (
+                jid TEXT PRIMARY KEY,
+                first_name TEXT NOT NULL,
+                full_name TEXT NOT NULL,
+                push_name TEXT,
+                business_name TEXT
+            );
+            CREATE TABLE app_state_keys (
+                key_id BLOB PRIMARY KEY,
+                key_data BLOB NOT NULL,
+                fingerprint BLOB NOT NULL,
+                timestamp INTEGER NOT NULL
+            );
+        ",
+    },
+    Migration {
+        version: 4,
+        description: "devices, identities, sender keys, chat settings, message secrets and message index",
+        sql: "
+            CREATE TABLE devices (
+                jid TEXT PRIMARY KEY,
+                noise_key BLOB,
+                identity_key BLOB,
+                signed_pre_key_id INTEGER,
+                signed_pre_key BLOB,
+                signed_pre_key_signature BLOB,
+                registration_id INTEGER NOT NULL,
+                adv_secret_key BLOB,
+                lid TEXT,
+                platform TEXT NOT NULL,
+                business_name TEXT,
+                push_name TEXT,
+                account BLOB
+            );
+            CREATE TABLE identities (
+                address TEXT PRIMARY KEY,
+                key BLOB NOT NULL
+            );
+            CREATE TABLE sender_keys (
+                group_jid TEXT NOT NULL,
+                sender TEXT NOT NULL,
+                sender_user TEXT NOT NULL,
+                sender_server TEXT NOT NULL,
+                sender_device INTEGER NOT NULL,
+                record BLOB NOT NULL,
+                PRIMARY KEY (group_jid, sender)
+            );
+            CREATE TABLE chat_settings (
+                chat TEXT PRIMARY KEY,
+                muted_until INTEGER,
+                pinned INTEGER NOT NULL,
+                archived INTEGER NOT NULL,
+                ephemeral INTEGER
+            );
+            CREATE TABLE message_secrets (
+                chat TEXT NOT NULL,
+                sender TEXT NOT NULL,
+                id TEXT NOT NULL,
+                secret BLOB NOT NULL,
+                PRIMARY KEY (chat, sender, id)
+            );
+            CREATE TABLE message_refs (
+                chat TEXT NOT NULL,
+                id TEXT NOT NULL,
+                sender TEXT NOT NULL,
+                timestamp INTEGER NOT NULL,
+                snippet TEXT,
+                PRIMARY KEY (chat, id)
+            );
+            CREATE INDEX message_refs_timestamp ON message_refs (timestamp);
+        ",
+    },
+];
+
+const VERSION_TABLE: &str = "
+    CREATE TABLE IF NOT EXISTS schema_migrations (
+        version INTEGER PRIMARY KEY,
+        description TEXT NOT NULL,
+        applied_at INTEGER NOT NULL
+    );
+";
+
+fn db_error(e: rusqlite::Error) -> StoreError {
+    StoreError::DatabaseError(e.to_string())
+}
+
+/// Latest version applied to a database, 0 for a new one.
+pub fn schema_version(conn: &Connection) -> StoreResult<u32> {
+    let has_table = conn.query_row(
+        "SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'schema_migrations'",
+        [],
+        |_| Ok(()),
+    )
+    .optional()
+    .map_err(db_error)?
+    .is_some();
+    if !has_table {
+        return Ok(0);
+    }
+    conn.query_row("SELECT COALESCE(MAX(version), 0) FROM schema_migrations", [], |row| row.get(0))
+        .map_err(db_error)
+}
+
+/// Migrations a database is missing, without applying them.
+///
+/// Fails if the database was written by a newer version of the store,
+/// which this one cannot safely use.
+pub fn pending_migrations<'a>(conn: &Connection, migrations: &'a [Migration]) -> StoreResult<Vec<&'a Migration>> {
+    let current = schema_version(conn)?;
+    let latest = migrations.last().map_or(0, |migration| migration.version);
+    if current > latest {
+        return Err(StoreError::DatabaseError(format!(
+            "database schema version {} is newer than the supported version {}",
+            current, latest,
+        )));
+    }
+    Ok(migrations.iter().filter(|migration| migration.version > current).collect())
+}
+
+/// Apply the migrations a database is missing, in order, returning the
+/// versions applied. A failing migration is rolled back and stops the
+/// run, leaving the earlier ones in place.
+pub fn run_migrations(conn: &mut Connection, migrations: &[Migration]) -> StoreResult<Vec<u32>> {
+    conn.execute_batch(VERSION_TABLE).map_err(db_error)?;
+    let pending: Vec<Migration> = pending_migrations(conn, migrations)?.into_iter().copied().collect();
+
+    let mut applied = Vec::with_capacity(pending.len());
+    for migration in pending {
+        let tx = conn.transaction().map_err(db_error)?;
+        tx.execute_batch(migration.sql).map_err(|e| {
+            StoreError::DatabaseError(format!("migration {} failed: {}", migration.version, e))
+        })?;
+        tx.execute(
+            "INSERT INTO schema_migrations (version, description, applied_at) VALUES (?1, ?2, ?3)",
+            params![migration.version, migration.description, chrono::Utc::now().timestamp()],
+        )
+        .map_err(db_error)?;
+        tx.commit().map_err(db_error)?;
+        applied.push(migration.version);
+    }
+    Ok(applied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_MIGRATIONS: &[Migration] = &[
+        Migration { version: 1, description: "notes", sql: "CREATE TABLE notes (id INTEGER PRIMARY KEY);" },
+        Migration { version: 2, description: "note text", sql: "ALTER TABLE notes ADD COLUMN text TEXT;" },
+        Migration { version: 3, description: "broken", sql: "ALTER TABLE missing ADD COLUMN x;" },
+    ];
+
+    #[test]
+    fn test_migrations_are_ordered() {
+        assert!(MIGRATIONS.windows(2).all(|pair| pair[1].version == pair[0].version + 1));
+        assert_eq!(MIGRATIONS[0].version, 1);
+    }
+
+    #[test]
+    fn test_run_migrations() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), 0);
+
+        // A dry run changes nothing
+        let pending = pending_migrations(&conn, &TEST_MIGRATIONS[..2]).unwrap();
+        assert_eq!(pending.iter().map(|m| m.version).collect::<Vec<_>>(), [1, 2]);
+        assert_eq!(schema_version(&conn).unwrap(), 0);
+
+        assert_eq!(run_migrations(&mut conn, &TEST_MIGRATIONS[..1]).unwrap(), [1]);
+        assert_eq!(run_migrations(&mut conn, &TEST_MIGRATIONS[..2]).unwrap(), [2]);
+        assert!(run_migrations(&mut conn, &TEST_MIGRATIONS[..2]).unwrap().is_empty());
+        conn.execute("INSERT INTO notes (text) VALUES ('kept')", []).unwrap();
+
+        // A failed migration is rolled back and not recorded
+        assert!(run_migrations(&mut conn, TEST_MIGRATIONS).is_err());
+        assert_eq!(schema_version(&conn).unwrap(), 2);
+
+        // Code older than the database refuses it
+        assert!(pending_migrations(&conn, &TEST_MIGRATIONS[..1]).is_err());
+    }
+}
@@ -6,7 +6,21 @@
 mod device;
 mod traits;
 mod memory;
+#[cfg(feature = "native")]
+mod sqlite;
+#[cfg(feature = "native")]
+pub mod mediacache;
+#[cfg(feature = "native")]
+pub mod migrations;
+#[cfg(feature = "native")]
+pub mod encryption;
 
 pub use device::*;
 pub use traits::*;
 pub use memory::*;
+#[cfg(feature = "native")]
+pub use sqlite::*;
+#[cfg(feature = "native")]
+pub use mediacache::MediaCache;
+#[cfg(feature = "native")]
+pub use encryption::{ColumnCipher, KeyProvider, StaticKeyProvider};
@@ -0,0 +1,1321 @@
+//! SQLite-backed implementation of all store traits: the device and its
+//! Signal state, contacts, app state keys and the message index, plus chat
+//! history, outbox, schedule and conversations.
+//!
+//! Message text is indexed with FTS5 for `ChatStore::search`. Opening a
+//! database brings its schema up to date with the `migrations`. Private
+//! keys, session and sender key records, message secrets and app state
+//! key data go through a `ColumnCipher`, so they can be encrypted with
+//! `with_encryption` while the rest stays queryable.
+
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension, Row};
+
+use crate::crypto::{KeyPair, PreKey};
+use crate::types::JID;
+use crate::store::{
+    Device, StoredMessage, MessagePage, MessageMatch, OutgoingMessage, ConversationState, ScheduledMessage,
+    ChatSummary, ContactInfo, ChatSettings, MessageRef, PreKeyRecord, AppStateSyncKey, SenderKeyName,
+    ColumnCipher, KeyProvider, ChatStore, OutboxStore, ConversationStore, ScheduleStore, DeviceStore,
+    IdentityStore, SessionStore, PreKeyStore, SenderKeyStore, ContactStore, AppStateKeyStore,
+    ChatSettingsStore, MsgSecretStore, MessageIndexStore, StoreError, StoreResult,
+};
+use crate::store::migrations::{pending_migrations, run_migrations, schema_version, Migration, MIGRATIONS};
+
+const COLUMNS: &str = "id, chat, sender, from_me, timestamp, push_name, text";
+const QUALIFIED_COLUMNS: &str = "m.id, m.chat, m.sender, m.from_me, m.timestamp, m.push_name, m.text";
+
+/// Every store trait persisted in a SQLite database, so a client built
+/// with it keeps its pairing, keys and history across restarts.
+pub struct SqliteStore {
+    conn: Mutex<Connection>,
+    cipher: ColumnCipher,
+}
+
+impl SqliteStore {
+    /// Open (or create) a database file.
+    pub fn open<P: AsRef<Path>>(path: P) -> StoreResult<Self> {
+        Self::from_connection(Connection::open(path).map_err(db_error)?)
+    }
+
+    /// Open a database that lives only in memory.
+    pub fn open_in_memory() -> StoreResult<Self> {
+        Self::from_connection(Connection::open_in_memory().map_err(db_error)?)
+    }
+
+    /// List the migrations opening a database file would apply, without
+    /// changing it.
+    pub fn pending_migrations<P: AsRef<Path>>(path: P) -> StoreResult<Vec<&'static Migration>> {
+        if !path.as_ref().exists() {
+            return Ok(MIGRATIONS.iter().collect());
+        }
+        let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY).map_err(db_error)?;
+        pending_migrations(&conn, MIGRATIONS)
+    }
+
+    fn from_connection(mut conn: Connection) -> StoreResult<Self> {
+        run_migrations(&mut conn, MIGRATIONS)?;
+        Ok(Self { conn: Mutex::new(conn), cipher: ColumnCipher::plain() })
+    }
+
+    /// Encrypt private keys, session and sender key records, message
+    /// secrets and app state key data with keys from `provider`. Values
+    /// written before stay readable and are encrypted the next time they
+    /// are written.
+    pub fn with_encryption(mut self, provider: Arc<dyn KeyProvider>) -> Self {
+        self.cipher = ColumnCipher::new(provider);
+        self
+    }
+
+    /// Latest schema migration applied to the database.
+    pub fn schema_version(&self) -> StoreResult<u32> {
+        schema_version(&*self.lock()?)
+    }
+
+    fn lock(&self) -> StoreResult<std::sync::MutexGuard<'_, Connection>> {
+        self.conn.lock()
+            .map_err(|_| StoreError::DatabaseError("lock poisoned".to_string()))
+    }
+
+    /// Look up a single message.
+    pub fn get_message(&self, chat: &JID, id: &str) -> StoreResult<Option<StoredMessage>> {
+        let conn = self.lock()?;
+        conn.query_row(
+            &format!("SELECT {} FROM messages WHERE chat = ?1 AND id = ?2", COLUMNS),
+            params![chat.to_string(), id],
+            read_message,
+        )
+        .optional()
+        .map_err(db_error)
+    }
+
+    /// Chats with stored messages, most recently active first.
+    pub fn chats(&self, limit: usize) -> StoreResult<Vec<ChatSummary>> {
+        let conn = self.lock()?;
+        // SQLite takes the bare text column from the row with the maximum
+        let mut stmt = conn.prepare(
+            "SELECT chat, COUNT(*), MAX(timestamp), text FROM messages \
+             GROUP BY chat ORDER BY MAX(timestamp) DESC LIMIT ?1",
+        )
+        .map_err(db_error)?;
+
+        let rows = stmt.query_map(params![limit as i64], |row| {
+            let chat: String = row.get(0)?;
+            Ok(ChatSummary {
+                chat: chat.parse().unwrap_or_default(),
+                message_count: row.get::<_, i64>(1)? as usize,
+                last_timestamp: row.get(2)?,
+                last_text: row.get(3)?,
+            })
+        })
+        .map_err(db_error)?;
+        rows.collect::<Result<_, _>>().map_err(db_error)
+    }
+
+    /// People we received messages from, with the latest push name they
+    /// sent, optionally only those whose number or name contains `query`.
+    pub fn contacts(&self, query: Option<&str>) -> StoreResult<Vec<ContactInfo>> {
+        let conn = self.lock()?;
+        let mut stmt = conn.prepare(
+            "SELECT sender, push_name FROM messages \
+             WHERE from_me = 0 AND sender != '' ORDER BY timestamp DESC",
+        )
+        .map_err(db_error)?;
+        let rows = stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, Option<String>>(1)?)))
+            .map_err(db_error)?;
+
+        let query = query.map(str::to_lowercase);
+        let mut seen = HashSet::new();
+        let mut contacts = Vec::new();
+        for row in rows {
+            let (sender, push_name) = row.map_err(db_error)?;
+            let Ok(jid) = sender.parse::<JID>() else {
+                continue;
+            };
+            // Every device of a user is the same contact
+            let jid = jid.to_non_ad();
+            if !seen.insert(jid.clone()) {
+                continue;
+            }
+            let matches = |text: &str| query.as_ref().is_none_or(|query| text.to_lowercase().contains(query));
+            if matches(&jid.user) || push_name.as_deref().is_some_and(matches) {
+                contacts.push(ContactInfo { jid, push_name, ..Default::default() });
+            }
+        }
+        Ok(contacts)
+    }
+}
+
+fn db_error(e: rusqlite::Error) -> StoreError {
+    StoreError::DatabaseError(e.to_string())
+}
+
+/// Turn free text into an FTS5 query matching every word as a prefix.
+fn fts_query(query: &str) -> Option<String> {
+    let terms: Vec<String> = query.split_whitespace()
+        .map(|term| format!("\"{}\"*", term.replace('"', "\"\"")))
+        .collect();
+    (!terms.is_empty()).then(|| terms.join(" "))
+}
+
+/// Insert or replace a message and its search index entry.
+fn insert_message(tx: &rusqlite::Transaction<'_>, message: &StoredMessage) -> StoreResult<()> {
+    // Upsert keeps the rowid stable so the index entry can be replaced
+    let rowid: i64 = tx.query_row(
+        &format!(
+            "INSERT INTO messages ({}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7) \
+             ON CONFLICT (chat, id) DO UPDATE SET sender = excluded.sender, \
+             from_me = excluded.from_me, timestamp = excluded.timestamp, \
+             push_name = excluded.push_name, text = excluded.text \
+             RETURNING rowid",
+            COLUMNS,
+        ),
+        params![
+            message.id,
+            message.chat.to_string(),
+            message.sender.to_string(),
+            message.is_from_me,
+            message.timestamp,
+            message.push_name,
+            message.text,
+        ],
+        |row| row.get(0),
+    )
+    .map_err(db_error)?;
+
+    tx.execute("DELETE FROM messages_fts WHERE rowid = ?1", params![rowid])
+        .map_err(db_error)?;
+    if let Some(text) = &message.text {
+        tx.execute("INSERT INTO messages_fts (rowid, text) VALUES (?1, ?2)", params![rowid, text])
+            .map_err(db_error)?;
+    }
+    Ok(())
+}
+
+fn read_message(row: &Row<'_>) -> rusqlite::Result<StoredMessage> {
+    let chat: String = row.get(1)?;
+    let sender: String = row.get(2)?;
+    Ok(StoredMessage {
+        id: row.get(0)?,
+        chat: chat.parse().unwrap_or_default(),
+        sender: sender.parse().unwrap_or_default(),
+        is_from_me: row.get(3)?,
+        timestamp: row.get(4)?,
+        push_name: row.get(5)?,
+        text: row.get(6)?,
+    })
+}
+
+impl ChatStore for SqliteStore {
+    fn put_message(&self, message: &StoredMessage) -> StoreResult<()> {
+        self.put_messages(std::slice::from_ref(message))
+    }
+
+    fn put_messages(&self, messages: &[StoredMessage]) -> StoreResult<()> {
+        let mut conn = self.lock()?;
+        let tx = conn.transaction().map_err(db_error)?;
+        for message in messages {
+            insert_message(&tx, message)?;
+        }
+        tx.commit().map_err(db_error)
+    }
+
+    fn get_messages(&self, chat: &JID, page: MessagePage) -> StoreResult<Vec<StoredMessage>> {
+        let conn = self.lock()?;
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM messages WHERE chat = ?1 AND (?2 IS NULL OR (timestamp, id) < (?2, ?3)) \
+             ORDER BY timestamp DESC, id DESC LIMIT ?4",
+            COLUMNS,
+        ))
+        .map_err(db_error)?;
+
+        let (timestamp, id) = page.before.unzip();
+        let rows = stmt.query_map(
+            params![chat.to_string(), timestamp, id, page.limit as i64],
+            read_message,
+        )
+        .map_err(db_error)?;
+        rows.collect::<Result<_, _>>().map_err(db_error)
+    }
+
+    fn search(&self, query: &str, chat: Option<&JID>, limit: usize) -> StoreResult<Vec<MessageMatch>> {
+        let Some(query) = fts_query(query) else {
+            return Ok(Vec::new());
+        };
+
+        let conn = self.lock()?;
+        // bm25() is lower for better matches
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {}, -bm25(messages_fts) AS score FROM messages_fts \
+             JOIN messages m ON m.rowid = messages_fts.rowid \
+             WHERE messages_fts MATCH ?1 AND (?2 IS NULL OR m.chat = ?2) \
+             ORDER BY score DESC, m.timestamp DESC LIMIT ?3",
+            QUALIFIED_COLUMNS,
+        ))
+        .map_err(db_error)?;
+
+        let rows = stmt.query_map(
+            params![query, chat.map(|c| c.to_string()), limit as i64],
+            |row| Ok(MessageMatch { message: read_message(row)?, score: row.get(7)? }),
+        )
+        .map_err(db_error)?;
+        rows.collect::<Result<_, _>>().map_err(db_error)
+    }
+}
+
+impl OutboxStore for SqliteStore {
+    fn put_outgoing(&self, message: &OutgoingMessage) -> StoreResult<()> {
+        let conn = self.lock()?;
+        conn.execute(
+            "INSERT OR REPLACE INTO outbox (id, recipient, text, created_at, attempts) \
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![message.id, message.to.to_string(), message.text, message.created_at, message.attempts],
+        )
+        .map_err(db_error)?;
+        Ok(())
+    }
+
+    fn ack_outgoing(&self, id: &str) -> StoreResult<()> {
+        let conn = self.lock()?;
+        conn.execute("DELETE FROM outbox WHERE id = ?1", params![id]).map_err(db_error)?;
+        Ok(())
+    }
+
+    fn pending_outgoing(&self) -> StoreResult<Vec<OutgoingMessage>> {
+        let conn = self.lock()?;
+        let mut stmt = conn.prepare(
+            "SELECT id, recipient, text, created_at, attempts FROM outbox ORDER BY created_at",
+        )
+        .map_err(db_error)?;
+
+        let rows = stmt.query_map([], |row| {
+            Ok(OutgoingMessage {
+                id: row.get(0)?,
+                to: row.get::<_, String>(1)?.parse().unwrap_or_default(),
+                text: row.get(2)?,
+                created_at: row.get(3)?,
+                attempts: row.get(4)?,
+            })
+        })
+        .map_err(db_error)?;
+        rows.collect::<Result<_, _>>().map_err(db_error)
+    }
+}
+
+impl ScheduleStore for SqliteStore {
+    fn put_scheduled(&self, message: &ScheduledMessage) -> StoreResult<()> {
+        let conn = self.lock()?;
+        conn.execute(
+            "INSERT OR REPLACE INTO scheduled (id, recipient, text, send_at) VALUES (?1, ?2, ?3, ?4)",
+            params![message.id, message.to.to_string(), message.text, message.send_at],
+        )
+        .map_err(db_error)?;
+        Ok(())
+    }
+
+    fn remove_scheduled(&self, id: &str) -> StoreResult<bool> {
+        let conn = self.lock()?;
+        let removed = conn.execute("DELETE FROM scheduled WHERE id = ?1", params![id]).map_err(db_error)?;
+        Ok(removed > 0)
+    }
+
+    fn pending_scheduled(&self) -> StoreResult<Vec<ScheduledMessage>> {
+        let conn = self.lock()?;
+        let mut stmt = conn.prepare("SELECT id, recipient, text, send_at FROM scheduled ORDER BY send_at")
+            .map_err(db_error)?;
+
+        let rows = stmt.query_map([], |row| {
+            Ok(ScheduledMessage {
+                id: row.get(0)?,
+                to: row.get::<_, String>(1)?.parse().unwrap_or_default(),
+                text: row.get(2)?,
+                send_at: row.get(3)?,
+            })
+        })
+        .map_err(db_error)?;
+        rows.collect::<Result<_, _>>().map_err(db_error)
+    }
+}
+
+impl ConversationStore for SqliteStore {
+    fn get_conversation(&self, chat: &JID) -> StoreResult<Option<ConversationState>> {
+        let conn = self.lock()?;
+        let row = conn.query_row(
+            "SELECT flow, step, data, updated_at FROM conversations WHERE chat = ?1",
+            params![chat.to_string()],
+            |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?, row.get::<_, String>(2)?, row.get(3)?)),
+        )
+        .optional()
+        .map_err(db_error)?;
+
+        row.map(|(flow, step, data, updated_at)| {
+            Ok(ConversationState {
+                chat: chat.clone(),
+                flow,
+                step,
+                data: serde_json::from_str(&data).map_err(|e| StoreError::SerializationError(e.to_string()))?,
+                updated_at,
+            })
+        })
+        .transpose()
+    }
+
+    fn put_conversation(&self, state: &ConversationState) -> StoreResult<()> {
+        let data = serde_json::to_string(&state.data).map_err(|e| StoreError::SerializationError(e.to_string()))?;
+        let conn = self.lock()?;
+        conn.execute(
+            "INSERT OR REPLACE INTO conversations (chat, flow, step, data, updated_at) \
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![state.chat.to_string(), state.flow, state.step, data, state.updated_at],
+        )
+        .map_err(db_error)?;
+        Ok(())
+    }
+
+    fn delete_conversation(&self, chat: &JID) -> StoreResult<()> {
+        let conn = self.lock()?;
+        conn.execute("DELETE FROM conversations WHERE chat = ?1", params![chat.to_string()]).map_err(db_error)?;
+        Ok(())
+    }
+}
+
+impl SessionStore for SqliteStore {
+    fn get_session(&self, address: &str) -> StoreResult<Option<Vec<u8>>> {
+        let conn = self.lock()?;
+        let record: Option<Vec<u8>> = conn.query_row(
+            "SELECT record FROM sessions WHERE address = ?1",
+            params![address],
+            |row| row.get(0),
+        )
+        .optional()
+        .map_err(db_error)?;
+        record.map(|record| self.cipher.open(&session_context(address), &record)).transpose()
+    }
+
+    fn has_session(&self, address: &str) -> StoreResult<bool> {
+        let conn = self.lock()?;
+        let found = conn.query_row("SELECT 1 FROM sessions WHERE address = ?1", params![address], |_| Ok(()))
+            .optional()
+            .map_err(db_error)?;
+        Ok(found.is_some())
+    }
+
+    fn put_session(&self, address: &str, session: &[u8]) -> StoreResult<()> {
+        let record = self.cipher.seal(&session_context(address), session)?;
+        let conn = self.lock()?;
+        conn.execute(
+            "INSERT OR REPLACE INTO sessions (address, record) VALUES (?1, ?2)",
+            params![address, record],
+        )
+        .map_err(db_error)?;
+        Ok(())
+    }
+
+    fn delete_session(&self, address: &str) -> StoreResult<()> {
+        let conn = self.lock()?;
+        conn.execute("DELETE FROM sessions WHERE address = ?1", params![address]).map_err(db_error)?;
+        Ok(())
+    }
+}
+
+fn session_context(address: &str) -> String {
+    format!("sessions.record:{}", address)
+}
+
+fn pre_key_context(id: u32) -> String {
+    format!("pre_keys.private_key:{}", id)
+}
+
+/// Public key, sealed private key, signature and upload flag of a pre-key.
+type PreKeyRow = (Vec<u8>, Vec<u8>, Option<Vec<u8>>, bool);
+
+fn key_bytes<const N: usize>(bytes: &[u8]) -> StoreResult<[u8; N]> {
+    bytes.try_into()
+        .map_err(|_| StoreError::SerializationError(format!("expected {} bytes, got {}", N, bytes.len())))
+}
+
+impl PreKeyStore for SqliteStore {
+    fn get_pre_key(&self, id: u32) -> StoreResult<Option<PreKeyRecord>> {
+        let conn = self.lock()?;
+        let row: Option<PreKeyRow> = conn.query_row(
+            "SELECT public_key, private_key, signature, uploaded FROM pre_keys WHERE id = ?1",
+            params![id],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+        )
+        .optional()
+        .map_err(db_error)?;
+        let Some((public_key, private_key, signature, uploaded)) = row else {
+            return Ok(None);
+        };
+        Ok(Some(PreKeyRecord {
+            key_id: id,
+            public_key: key_bytes(&public_key)?,
+            private_key: key_bytes(&self.cipher.open(&pre_key_context(id), &private_key)?)?,
+            signature: signature.as_deref().map(key_bytes).transpose()?,
+            uploaded,
+        }))
+    }
+
+    fn put_pre_key(&self, record: &PreKeyRecord) -> StoreResult<()> {
+        let private_key = self.cipher.seal(&pre_key_context(record.key_id), &record.private_key)?;
+        let conn = self.lock()?;
+        conn.execute(
+            "INSERT OR REPLACE INTO pre_keys (id, public_key, private_key, signature, uploaded)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                record.key_id,
+                record.public_key.as_slice(),
+                private_key,
+                record.signature.as_ref().map(|signature| signature.as_slice()),
+                record.uploaded,
+            ],
+        )
+        .map_err(db_error)?;
+        Ok(())
+    }
+
+    fn remove_pre_key(&self, id: u32) -> StoreResult<()> {
+        let conn = self.lock()?;
+        conn.execute("DELETE FROM pre_keys WHERE id = ?1", params![id]).map_err(db_error)?;
+        Ok(())
+    }
+
+    fn uploaded_pre_key_count(&self) -> StoreResult<usize> {
+        let conn = self.lock()?;
+        let count: i64 = conn.query_row("SELECT COUNT(*) FROM pre_keys WHERE uploaded", [], |row| row.get(0))
+            .map_err(db_error)?;
+        Ok(count as usize)
+    }
+
+    fn mark_pre_keys_uploaded(&self, up_to_id: u32) -> StoreResult<()> {
+        let conn = self.lock()?;
+        conn.execute("UPDATE pre_keys SET uploaded = 1 WHERE id <= ?1", params![up_to_id]).map_err(db_error)?;
+        Ok(())
+    }
+}
+
+const CONTACT_COLUMNS: &str = "jid, first_name, full_name, push_name, business_name";
+
+fn read_contact(row: &Row<'_>) -> rusqlite::Result<ContactInfo> {
+    let jid: String = row.get(0)?;
+    Ok(ContactInfo {
+        jid: jid.parse().unwrap_or_default(),
+        first_name: row.get(1)?,
+        full_name: row.get(2)?,
+        push_name: row.get(3)?,
+        business_name: row.get(4)?,
+    })
+}
+
+fn insert_contact(conn: &Connection, contact: &ContactInfo) -> StoreResult<()> {
+    conn.execute(
+        &format!("INSERT OR REPLACE INTO contacts ({}) VALUES (?1, ?2, ?3, ?4, ?5)", CONTACT_COLUMNS),
+        params![
+            contact.jid.to_string(),
+            contact.first_name,
+            contact.full_name,
+            contact.push_name,
+            contact.business_name,
+        ],
+    )
+    .map_err(db_error)?;
+    Ok(())
+}
+
+impl ContactStore for SqliteStore {
+    fn get_contact(&self, jid: &JID) -> StoreResult<Option<ContactInfo>> {
+        let conn = self.lock()?;
+        conn.query_row(
+            &format!("SELECT {} FROM contacts WHERE jid = ?1", CONTACT_COLUMNS),
+            params![jid.to_string()],
+            read_contact,
+        )
+        .optional()
+        .map_err(db_error)
+    }
+
+    fn put_contact(&self, contact: &ContactInfo) -> StoreResult<()> {
+        insert_contact(&*self.lock()?, contact)
+    }
+
+    fn get_all_contacts(&self) -> StoreResult<Vec<ContactInfo>> {
+        let conn = self.lock()?;
+        let mut stmt = conn.prepare(&format!("SELECT {} FROM contacts ORDER BY jid", CONTACT_COLUMNS))
+            .map_err(db_error)?;
+        let rows = stmt.query_map([], read_contact).map_err(db_error)?;
+        rows.collect::<Result<_, _>>().map_err(db_error)
+    }
+
+    fn put_contacts(&self, contacts: &[ContactInfo]) -> StoreResult<()> {
+        let mut conn = self.lock()?;
+        let tx = conn.transaction().map_err(db_error)?;
+        for contact in contacts {
+            insert_contact(&tx, contact)?;
+        }
+        tx.commit().map_err(db_error)
+    }
+}
+
+fn app_state_key_context(key_id: &[u8]) -> String {
+    format!("app_state_keys.key_data:{}", hex::encode(key_id))
+}
+
+/// Key ID, sealed key data, fingerprint and timestamp of an app state key.
+type AppStateKeyRow = (Vec<u8>, Vec<u8>, Vec<u8>, i64);
+
+impl SqliteStore {
+    fn read_app_state_key(&self, row: Option<AppStateKeyRow>) -> StoreResult<Option<AppStateSyncKey>> {
+        let Some((key_id, key_data, fingerprint, timestamp)) = row else {
+            return Ok(None);
+        };
+        Ok(Some(AppStateSyncKey {
+            key_data: self.cipher.open(&app_state_key_context(&key_id), &key_data)?,
+            key_id,
+            fingerprint,
+            timestamp,
+        }))
+    }
+
+    fn insert_app_state_key(&self, conn: &Connection, key: &AppStateSyncKey) -> StoreResult<()> {
+        let key_data = self.cipher.seal(&app_state_key_context(&key.key_id), &key.key_data)?;
+        conn.execute(
+            "INSERT OR REPLACE INTO app_state_keys (key_id, key_data, fingerprint, timestamp)
+             VALUES (?1, ?2, ?3, ?4)",
+            params![key.key_id, key_data, key.fingerprint, key.timestamp],
+        )
+        .map_err(db_error)?;
+        Ok(())
+    }
+}
+
+impl AppStateKeyStore for SqliteStore {
+    fn get_app_state_key(&self, key_id: &[u8]) -> StoreResult<Option<AppStateSyncKey>> {
+        let row = self.lock()?.query_row(
+            "SELECT key_id, key_data, fingerprint, timestamp FROM app_state_keys WHERE key_id = ?1",
+            params![key_id],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+        )
+        .optional()
+        .map_err(db_error)?;
+        self.read_app_state_key(row)
+    }
+
+    fn put_app_state_key(&self, key: &AppStateSyncKey) -> StoreResult<()> {
+        self.insert_app_state_key(&*self.lock()?, key)
+    }
+
+    fn latest_app_state_key(&self) -> StoreResult<Option<AppStateSyncKey>> {
+        let row = self.lock()?.query_row(
+            "SELECT key_id, key_data, fingerprint, timestamp FROM app_state_keys \
+             ORDER BY timestamp DESC LIMIT 1",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+        )
+        .optional()
+        .map_err(db_error)?;
+        self.read_app_state_key(row)
+    }
+
+    fn put_app_state_keys(&self, keys: &[AppStateSyncKey]) -> StoreResult<()> {
+        let mut conn = self.lock()?;
+        let tx = conn.transaction().map_err(db_error)?;
+        for key in keys {
+            self.insert_app_state_key(&tx, key)?;
+        }
+        tx.commit().map_err(db_error)
+    }
+}
+
+fn device_context(jid: &str, column: &str) -> String {
+    format!("devices.{}:{}", column, jid)
+}
+
+/// Columns of a device row after its JID.
+const DEVICE_COLUMNS: &str = "noise_key, identity_key, signed_pre_key_id, signed_pre_key, signed_pre_key_signature, \
+    registration_id, adv_secret_key, lid, platform, business_name, push_name, account";
+
+impl SqliteStore {
+    /// Read the device with the given JID, or the first one stored.
+    fn read_device(&self, conn: &Connection, jid: Option<&JID>) -> StoreResult<Option<Device>> {
+        let filter = if jid.is_some() { "WHERE jid = ?1" } else { "ORDER BY rowid LIMIT 1" };
+        let mut stmt = conn.prepare(&format!("SELECT jid, {} FROM devices {}", DEVICE_COLUMNS, filter))
+            .map_err(db_error)?;
+        let mut rows = match jid {
+            Some(jid) => stmt.query(params![jid.to_string()]),
+            None => stmt.query([]),
+        }
+        .map_err(db_error)?;
+        let Some(row) = rows.next().map_err(db_error)? else {
+            return Ok(None);
+        };
+
+        let jid: String = row.get(0).map_err(db_error)?;
+        let key_pair = |index: usize, column: &str| -> StoreResult<Option<KeyPair>> {
+            let sealed: Option<Vec<u8>> = row.get(index).map_err(db_error)?;
+            sealed.map(|sealed| {
+                let private = self.cipher.open(&device_context(&jid, column), &sealed)?;
+                Ok(KeyPair::from_private_key(key_bytes(&private)?))
+            })
+            .transpose()
+        };
+        let signed_pre_key = match (row.get::<_, Option<u32>>(3).map_err(db_error)?, key_pair(4, "signed_pre_key")?) {
+            (Some(key_id), Some(key_pair)) => {
+                let signature: Option<Vec<u8>> = row.get(5).map_err(db_error)?;
+                Some(PreKey { key_pair, key_id, signature: signature.as_deref().map(key_bytes).transpose()? })
+            }
+            _ => None,
+        };
+        let adv_secret_key: Option<Vec<u8>> = row.get(7).map_err(db_error)?;
+        let lid: Option<String> = row.get(8).map_err(db_error)?;
+        Ok(Some(Device {
+            noise_key: key_pair(1, "noise_key")?,
+            identity_key: key_pair(2, "identity_key")?,
+            signed_pre_key,
+            registration_id: row.get(6).map_err(db_error)?,
+            adv_secret_key: adv_secret_key
+                .map(|sealed| self.cipher.open(&device_context(&jid, "adv_secret_key"), &sealed))
+                .transpose()?,
+            lid: lid.and_then(|lid| lid.parse().ok()),
+            platform: row.get(9).map_err(db_error)?,
+            business_name: row.get(10).map_err(db_error)?,
+            push_name: row.get(11).map_err(db_error)?,
+            account: row.get(12).map_err(db_error)?,
+            initialized: true,
+            jid: Some(jid.parse().map_err(|e| StoreError::SerializationError(format!("device JID {}: {}", jid, e)))?),
+        }))
+    }
+}
+
+impl DeviceStore for SqliteStore {
+    fn get_device(&self, jid: &JID) -> StoreResult<Option<Device>> {
+        self.read_device(&*self.lock()?, Some(jid))
+    }
+
+    fn put_device(&self, device: &Device) -> StoreResult<()> {
+        // Like the in-memory store, only paired devices are kept
+        let Some(jid) = device.jid.as_ref().map(JID::to_string) else {
+            return Ok(());
+        };
+        let seal_private = |key: &Option<KeyPair>, column: &str| {
+            key.as_ref().map(|key| self.cipher.seal(&device_context(&jid, column), &key.private)).transpose()
+        };
+        let signed_pre_key = device.signed_pre_key.as_ref();
+        let adv_secret_key = device.adv_secret_key.as_ref()
+            .map(|secret| self.cipher.seal(&device_context(&jid, "adv_secret_key"), secret))
+            .transpose()?;
+        let conn = self.lock()?;
+        conn.execute(
+            &format!("INSERT OR REPLACE INTO devices (jid, {}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)", DEVICE_COLUMNS),
+            params![
+                jid,
+                seal_private(&device.noise_key, "noise_key")?,
+                seal_private(&device.identity_key, "identity_key")?,
+                signed_pre_key.map(|key| key.key_id),
+                seal_private(&signed_pre_key.map(|key| key.key_pair.clone()), "signed_pre_key")?,
+                signed_pre_key.and_then(|key| key.signature).map(|signature| signature.to_vec()),
+                device.registration_id,
+                adv_secret_key,
+                device.lid.as_ref().map(JID::to_string),
+                device.platform,
+                device.business_name,
+                device.push_name,
+                device.account,
+            ],
+        )
+        .map_err(db_error)?;
+        Ok(())
+    }
+
+    fn delete_device(&self, jid: &JID) -> StoreResult<()> {
+        let conn = self.lock()?;
+        conn.execute("DELETE FROM devices WHERE jid = ?1", params![jid.to_string()]).map_err(db_error)?;
+        Ok(())
+    }
+
+    fn get_first_device(&self) -> StoreResult<Option<Device>> {
+        self.read_device(&*self.lock()?, None)
+    }
+}
+
+impl IdentityStore for SqliteStore {
+    fn put_identity(&self, address: &str, key: [u8; 32]) -> StoreResult<()> {
+        let conn = self.lock()?;
+        conn.execute(
+            "INSERT OR REPLACE INTO identities (address, key) VALUES (?1, ?2)",
+            params![address, key.as_slice()],
+        )
+        .map_err(db_error)?;
+        Ok(())
+    }
+
+    fn get_identity(&self, address: &str) -> StoreResult<Option<[u8; 32]>> {
+        let conn = self.lock()?;
+        let key: Option<Vec<u8>> = conn.query_row(
+            "SELECT key FROM identities WHERE address = ?1",
+            params![address],
+            |row| row.get(0),
+        )
+        .optional()
+        .map_err(db_error)?;
+        key.as_deref().map(key_bytes).transpose()
+    }
+
+    fn is_trusted_identity(&self, address: &str, key: &[u8; 32]) -> StoreResult<bool> {
+        // Trust on first use
+        Ok(self.get_identity(address)?.is_none_or(|stored| stored == *key))
+    }
+
+    fn delete_identity(&self, address: &str) -> StoreResult<()> {
+        let conn = self.lock()?;
+        conn.execute("DELETE FROM identities WHERE address = ?1", params![address]).map_err(db_error)?;
+        Ok(())
+    }
+}
+
+fn sender_key_context(name: &SenderKeyName) -> String {
+    format!("sender_keys.record:{}:{}", name.group, name.sender)
+}
+
+impl SenderKeyStore for SqliteStore {
+    fn get_sender_key_by_name(&self, name: &SenderKeyName) -> StoreResult<Option<Vec<u8>>> {
+        let conn = self.lock()?;
+        let record: Option<Vec<u8>> = conn.query_row(
+            "SELECT record FROM sender_keys WHERE group_jid = ?1 AND sender = ?2",
+            params![name.group.to_string(), name.sender.to_string()],
+            |row| row.get(0),
+        )
+        .optional()
+        .map_err(db_error)?;
+        record.map(|record| self.cipher.open(&sender_key_context(name), &record)).transpose()
+    }
+
+    fn put_sender_key_by_name(&self, name: &SenderKeyName, key: &[u8]) -> StoreResult<()> {
+        let record = self.cipher.seal(&sender_key_context(name), key)?;
+        let conn = self.lock()?;
+        conn.execute(
+            "INSERT OR REPLACE INTO sender_keys (group_jid, sender, sender_user, sender_server, sender_device, record)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                name.group.to_string(),
+                name.sender.to_string(),
+                name.sender.user,
+                name.sender.server,
+                name.sender.device,
+                record,
+            ],
+        )
+        .map_err(db_error)?;
+        Ok(())
+    }
+
+    fn delete_sender_key_by_name(&self, name: &SenderKeyName) -> StoreResult<()> {
+        let conn = self.lock()?;
+        conn.execute(
+            "DELETE FROM sender_keys WHERE group_jid = ?1 AND sender = ?2",
+            params![name.group.to_string(), name.sender.to_string()],
+        )
+        .map_err(db_error)?;
+        Ok(())
+    }
+
+    fn delete_user_sender_keys(&self, group: &JID, user: &JID) -> StoreResult<()> {
+        let conn = self.lock()?;
+        conn.execute(
+            "DELETE FROM sender_keys WHERE group_jid = ?1 AND sender_user = ?2 AND sender_server = ?3",
+            params![group.to_string(), user.user, user.server],
+        )
+        .map_err(db_error)?;
+        Ok(())
+    }
+
+    fn sender_key_devices(&self, group: &JID, user: &JID) -> StoreResult<Vec<JID>> {
+        let conn = self.lock()?;
+        let mut stmt = conn.prepare(
+            "SELECT sender FROM sender_keys WHERE group_jid = ?1 AND sender_user = ?2 AND sender_server = ?3 \
+             ORDER BY sender_device",
+        )
+        .map_err(db_error)?;
+        let rows = stmt.query_map(params![group.to_string(), user.user, user.server], |row| row.get::<_, String>(0))
+            .map_err(db_error)?;
+        let senders: Vec<String> = rows.collect::<Result<_, _>>().map_err(db_error)?;
+        Ok(senders.iter().filter_map(|sender| sender.parse().ok()).collect())
+    }
+}
+
+impl ChatSettingsStore for SqliteStore {
+    fn get_chat_settings(&self, chat: &JID) -> StoreResult<Option<ChatSettings>> {
+        let conn = self.lock()?;
+        conn.query_row(
+            "SELECT muted_until, pinned, archived, ephemeral FROM chat_settings WHERE chat = ?1",
+            params![chat.to_string()],
+            |row| Ok(ChatSettings {
+                muted_until: row.get(0)?,
+                pinned: row.get(1)?,
+                archived: row.get(2)?,
+                ephemeral: row.get::<_, Option<i64>>(3)?.map(|secs| std::time::Duration::from_secs(secs as u64)),
+            }),
+        )
+        .optional()
+        .map_err(db_error)
+    }
+
+    fn put_chat_settings(&self, chat: &JID, settings: &ChatSettings) -> StoreResult<()> {
+        let conn = self.lock()?;
+        conn.execute(
+            "INSERT OR REPLACE INTO chat_settings (chat, muted_until, pinned, archived, ephemeral)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                chat.to_string(),
+                settings.muted_until,
+                settings.pinned,
+                settings.archived,
+                settings.ephemeral.map(|timer| timer.as_secs() as i64),
+            ],
+        )
+        .map_err(db_error)?;
+        Ok(())
+    }
+}
+
+/// Chat, sender and ID of a message secret. Secrets are shared by all
+/// devices of an account, so the sender has no device.
+fn secret_key(chat: &JID, sender: &JID, id: &str) -> (String, String, String) {
+    (chat.to_string(), sender.to_non_ad().to_string(), id.to_string())
+}
+
+impl MsgSecretStore for SqliteStore {
+    fn get_message_secret(&self, chat: &JID, sender: &JID, id: &str) -> StoreResult<Option<Vec<u8>>> {
+        let (chat, sender, id) = secret_key(chat, sender, id);
+        let conn = self.lock()?;
+        let secret: Option<Vec<u8>> = conn.query_row(
+            "SELECT secret FROM message_secrets WHERE chat = ?1 AND sender = ?2 AND id = ?3",
+            params![chat, sender, id],
+            |row| row.get(0),
+        )
+        .optional()
+        .map_err(db_error)?;
+        let context = format!("message_secrets.secret:{}:{}:{}", chat, sender, id);
+        secret.map(|secret| self.cipher.open(&context, &secret)).transpose()
+    }
+
+    fn put_message_secret(&self, chat: &JID, sender: &JID, id: &str, secret: &[u8]) -> StoreResult<()> {
+        let (chat, sender, id) = secret_key(chat, sender, id);
+        let secret = self.cipher.seal(&format!("message_secrets.secret:{}:{}:{}", chat, sender, id), secret)?;
+        let conn = self.lock()?;
+        conn.execute(
+            "INSERT OR REPLACE INTO message_secrets (chat, sender, id, secret) VALUES (?1, ?2, ?3, ?4)",
+            params![chat, sender, id, secret],
+        )
+        .map_err(db_error)?;
+        Ok(())
+    }
+}
+
+impl MessageIndexStore for SqliteStore {
+    fn get_message_ref(&self, chat: &JID, id: &str) -> StoreResult<Option<MessageRef>> {
+        let conn = self.lock()?;
+        conn.query_row(
+            "SELECT sender, timestamp, snippet FROM message_refs WHERE chat = ?1 AND id = ?2",
+            params![chat.to_string(), id],
+            |row| Ok(MessageRef {
+                id: id.to_string(),
+                chat: chat.clone(),
+                sender: row.get::<_, String>(0)?.parse().unwrap_or_default(),
+                timestamp: row.get(1)?,
+                snippet: row.get(2)?,
+            }),
+        )
+        .optional()
+        .map_err(db_error)
+    }
+
+    fn put_message_ref(&self, message: &MessageRef) -> StoreResult<()> {
+        let conn = self.lock()?;
+        conn.execute(
+            "INSERT OR REPLACE INTO message_refs (chat, id, sender, timestamp, snippet) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![message.chat.to_string(), message.id, message.sender.to_string(), message.timestamp, message.snippet],
+        )
+        .map_err(db_error)?;
+        Ok(())
+    }
+
+    fn prune_message_refs(&self, before: i64) -> StoreResult<()> {
+        let conn = self.lock()?;
+        conn.execute("DELETE FROM message_refs WHERE timestamp < ?1", params![before]).map_err(db_error)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stored(id: &str, chat: &JID, timestamp: i64, text: &str) -> StoredMessage {
+        StoredMessage {
+            id: id.to_string(),
+            chat: chat.clone(),
+            sender: JID::new("999", "s.whatsapp.net"),
+            is_from_me: false,
+            timestamp,
+            push_name: Some("Bob".to_string()),
+            text: Some(text.to_string()),
+        }
+    }
+
+    #[test]
+    fn test_sqlite_store_messages() {
+        let store = SqliteStore::open_in_memory().unwrap();
+        let chat = JID::new("123", "s.whatsapp.net");
+        let other = JID::new("456", "s.whatsapp.net");
+        store.put_message(&stored("a", &chat, 1, "hello there")).unwrap();
+        store.put_message(&stored("b", &chat, 2, "general kenobi")).unwrap();
+        store.put_message(&stored("c", &chat, 3, "hello again")).unwrap();
+        store.put_message(&stored("d", &other, 4, "hello elsewhere")).unwrap();
+
+        let latest = store.get_messages(&chat, MessagePage::latest(2)).unwrap();
+        assert_eq!(latest.iter().map(|m| m.id.as_str()).collect::<Vec<_>>(), vec!["c", "b"]);
+        assert_eq!(latest[0], stored("c", &chat, 3, "hello again"));
+
+        let older = store.get_messages(&chat, MessagePage::older_than(&latest[1], 10)).unwrap();
+        assert_eq!(older.len(), 1);
+        assert_eq!(older[0].id, "a");
+
+        assert_eq!(store.search("Hello", Some(&chat), 10).unwrap().len(), 2);
+        assert_eq!(store.search("hello", None, 10).unwrap().len(), 3);
+        assert!(store.search("hello kenobi", None, 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_sqlite_store_pages_through_shared_timestamp() {
+        let store = SqliteStore::open_in_memory().unwrap();
+        let chat = JID::new("123", "s.whatsapp.net");
+        for id in ["c", "a", "e", "b", "d"] {
+            store.put_message(&stored(id, &chat, 7, "burst")).unwrap();
+        }
+        store.put_message(&stored("z", &chat, 6, "before")).unwrap();
+
+        let mut ids = Vec::new();
+        let mut page = MessagePage::latest(2);
+        loop {
+            let messages = store.get_messages(&chat, page.clone()).unwrap();
+            let Some(last) = messages.last() else { break };
+            page = MessagePage::older_than(last, 2);
+            ids.extend(messages.iter().map(|m| m.id.clone()));
+        }
+        assert_eq!(ids, ["e", "d", "c", "b", "a", "z"]);
+    }
+
+    #[test]
+    fn test_sqlite_store_migrates_unversioned_database() {
+        let path = std::env::temp_dir().join(format!("whatsmeow-store-{}.db", uuid::Uuid::new_v4()));
+        assert_eq!(SqliteStore::pending_migrations(&path).unwrap().len(), MIGRATIONS.len());
+
+        // A database from before versioning, with a message in it
+        let conn = Connection::open(&path).unwrap();
+        conn.execute_batch(MIGRATIONS[0].sql).unwrap();
+        conn.execute(
+            "INSERT INTO messages (chat, id, sender, from_me, timestamp) VALUES ('1@s.whatsapp.net', 'a', '1@s.whatsapp.net', 0, 1)",
+            [],
+        ).unwrap();
+        drop(conn);
+        assert_eq!(SqliteStore::pending_migrations(&path).unwrap().len(), MIGRATIONS.len());
+
+        let store = SqliteStore::open(&path).unwrap();
+        assert_eq!(store.schema_version().unwrap(), MIGRATIONS.last().unwrap().version);
+        assert!(store.get_message(&JID::new("1", "s.whatsapp.net"), "a").unwrap().is_some());
+        drop(store);
+        assert!(SqliteStore::pending_migrations(&path).unwrap().is_empty());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_sqlite_store_message_batch() {
+        let store = SqliteStore::open_in_memory().unwrap();
+        let chat = JID::new("123", "s.whatsapp.net");
+        let batch: Vec<_> = (0..500).map(|i| stored(&format!("m{i}"), &chat, i, &format!("synced {i}"))).collect();
+        store.put_messages(&batch).unwrap();
+        // A second copy replaces the first, index entry included
+        store.put_messages(&[stored("m0", &chat, 0, "edited")]).unwrap();
+
+        assert_eq!(store.get_messages(&chat, MessagePage::latest(1000)).unwrap().len(), 500);
+        assert_eq!(store.search("synced", None, 1000).unwrap().len(), 499);
+        assert_eq!(store.search("edited", None, 10).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_sqlite_store_chats_and_contacts() {
+        let store = SqliteStore::open_in_memory().unwrap();
+        let chat = JID::new("123", "s.whatsapp.net");
+        let group = JID::new("456", "g.us");
+        store.put_message(&stored("a", &chat, 1, "hi")).unwrap();
+        store.put_message(&stored("b", &group, 2, "hello group")).unwrap();
+        store.put_message(&StoredMessage {
+            sender: JID::new_ad("999", 0, 3),
+            push_name: Some("Robert".to_string()),
+            ..stored("c", &chat, 3, "bye")
+        }).unwrap();
+
+        let chats = store.chats(10).unwrap();
+        assert_eq!(chats.iter().map(|c| c.chat.clone()).collect::<Vec<_>>(), [chat.clone(), group]);
+        assert_eq!((chats[0].message_count, chats[0].last_text.as_deref()), (2, Some("bye")));
+        assert_eq!(store.chats(1).unwrap().len(), 1);
+
+        let contacts = store.contacts(None).unwrap();
+        assert_eq!(contacts.len(), 1);
+        assert_eq!(contacts[0].jid, JID::new("999", "s.whatsapp.net"));
+        assert_eq!(contacts[0].push_name.as_deref(), Some("Robert"));
+        assert_eq!(store.contacts(Some("rob")).unwrap().len(), 1);
+        assert_eq!(store.contacts(Some("99")).unwrap().len(), 1);
+        assert!(store.contacts(Some("alice")).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_sqlite_store_contacts_and_app_state_keys() {
+        let store = SqliteStore::open_in_memory().unwrap().with_encryption(Arc::new(crate::store::StaticKeyProvider::new([3; 32])));
+        let contact = |user: &str, push_name: &str| ContactInfo {
+            jid: JID::new(user, "s.whatsapp.net"),
+            push_name: Some(push_name.to_string()),
+            ..Default::default()
+        };
+        store.put_contacts(&[contact("1", "Ana"), contact("2", "Bo"), contact("1", "Ana Lima")]).unwrap();
+        store.put_contact(&ContactInfo { full_name: "Cy Doe".to_string(), ..contact("3", "Cy") }).unwrap();
+
+        assert_eq!(store.get_all_contacts().unwrap().len(), 3);
+        let ana = store.get_contact(&JID::new("1", "s.whatsapp.net")).unwrap().unwrap();
+        assert_eq!(ana.push_name.as_deref(), Some("Ana Lima"));
+        assert_eq!(store.get_contact(&JID::new("3", "s.whatsapp.net")).unwrap().unwrap().full_name, "Cy Doe");
+        assert!(store.get_contact(&JID::new("4", "s.whatsapp.net")).unwrap().is_none());
+
+        let key = |id: u8, timestamp: i64| AppStateSyncKey {
+            key_id: vec![id],
+            key_data: vec![id; 32],
+            fingerprint: vec![],
+            timestamp,
+        };
+        store.put_app_state_keys(&[key(1, 10), key(2, 20)]).unwrap();
+        store.put_app_state_key(&key(3, 5)).unwrap();
+        assert_eq!(store.get_app_state_key(&[1]).unwrap().unwrap().key_data, vec![1; 32]);
+        assert_eq!(store.latest_app_state_key().unwrap().unwrap().key_id, vec![2]);
+        assert!(store.get_app_state_key(&[4]).unwrap().is_none());
+
+        let sealed: Vec<u8> = store.lock().unwrap()
+            .query_row("SELECT key_data FROM app_state_keys WHERE key_id = ?1", params![vec![1u8]], |row| row.get(0))
+            .unwrap();
+        assert!(!sealed.windows(32).any(|w| w == [1; 32]));
+    }
+
+    #[test]
+    fn test_sqlite_store_device() {
+        let store = SqliteStore::open_in_memory().unwrap().with_encryption(Arc::new(crate::store::StaticKeyProvider::new([4; 32])));
+        let mut device = Device::new();
+        device.initialize();
+        // Unpaired devices are not kept
+        store.put_device(&device).unwrap();
+        assert!(store.get_first_device().unwrap().is_none());
+
+        device.jid = Some(JID::new_ad("123", 0, 7));
+        device.lid = Some(JID::new("456", "lid"));
+        device.platform = "android".to_string();
+        device.account = Some(vec![1, 2, 3]);
+        store.put_device(&device).unwrap();
+
+        let stored = store.get_first_device().unwrap().unwrap();
+        assert_eq!(stored.jid, device.jid);
+        assert_eq!(stored.lid, device.lid);
+        assert_eq!((stored.platform.as_str(), stored.account.as_deref()), ("android", Some(&[1, 2, 3][..])));
+        assert_eq!(stored.registration_id, device.registration_id);
+        assert_eq!(stored.noise_key.unwrap().public, device.noise_key.as_ref().unwrap().public);
+        assert_eq!(stored.identity_key.unwrap().private, device.identity_key.as_ref().unwrap().private);
+        let (signed, expected) = (stored.signed_pre_key.unwrap(), device.signed_pre_key.as_ref().unwrap());
+        assert_eq!((signed.key_id, signed.key_pair.public, signed.signature), (expected.key_id, expected.key_pair.public, expected.signature));
+        assert_eq!(stored.adv_secret_key, device.adv_secret_key);
+        assert!(store.get_device(device.jid.as_ref().unwrap()).unwrap().is_some());
+
+        let noise_key: Vec<u8> = store.lock().unwrap()
+            .query_row("SELECT noise_key FROM devices", [], |row| row.get(0))
+            .unwrap();
+        assert!(!noise_key.windows(32).any(|w| w == device.noise_key.as_ref().unwrap().private));
+
+        store.delete_device(device.jid.as_ref().unwrap()).unwrap();
+        assert!(store.get_first_device().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_sqlite_store_signal_state() {
+        let store = SqliteStore::open_in_memory().unwrap();
+        assert!(store.is_trusted_identity("1.0", &[1; 32]).unwrap());
+        store.put_identity("1.0", [1; 32]).unwrap();
+        assert!(store.is_trusted_identity("1.0", &[1; 32]).unwrap());
+        assert!(!store.is_trusted_identity("1.0", &[2; 32]).unwrap());
+        store.delete_identity("1.0").unwrap();
+        assert!(store.get_identity("1.0").unwrap().is_none());
+
+        let group = JID::new("120363", "g.us");
+        let user = JID::new("999", "s.whatsapp.net");
+        let name = |device| SenderKeyName::new(group.clone(), JID::new_ad("999", 0, device));
+        store.put_sender_key_by_name(&name(3), b"three").unwrap();
+        store.put_sender_key_by_name(&name(1), b"one").unwrap();
+        assert_eq!(store.get_sender_key_by_name(&name(3)).unwrap().unwrap(), b"three");
+        assert_eq!(store.sender_key_devices(&group, &user).unwrap(), vec![name(1).sender, name(3).sender]);
+        store.delete_sender_key_by_name(&name(1)).unwrap();
+        assert_eq!(store.sender_key_devices(&group, &user).unwrap().len(), 1);
+        store.delete_user_sender_keys(&group, &user).unwrap();
+        assert!(store.get_sender_key_by_name(&name(3)).unwrap().is_none());
+
+        let settings = ChatSettings { pinned: true, ephemeral: Some(std::time::Duration::from_secs(86400)), ..Default::default() };
+        store.put_chat_settings(&user, &settings).unwrap();
+        let stored = store.get_chat_settings(&user).unwrap().unwrap();
+        assert_eq!((stored.pinned, stored.archived, stored.ephemeral), (true, false, settings.ephemeral));
+
+        // Secrets are shared by every device of the sender
+        store.put_message_secret(&group, &name(2).sender, "A1", &[5; 32]).unwrap();
+        assert_eq!(store.get_message_secret(&group, &user, "A1").unwrap(), Some(vec![5; 32]));
+
+        let message_ref = |id: &str, timestamp| MessageRef {
+            id: id.to_string(),
+            chat: group.clone(),
+            sender: user.clone(),
+            timestamp,
+            snippet: Some("hi".to_string()),
+        };
+        store.put_message_ref(&message_ref("A1", 10)).unwrap();
+        store.put_message_ref(&message_ref("A2", 20)).unwrap();
+        assert_eq!(store.get_message_ref(&group, "A1").unwrap(), Some(message_ref("A1", 10)));
+        store.prune_message_refs(15).unwrap();
+        assert!(store.get_message_ref(&group, "A1").unwrap().is_none());
+        assert!(store.get_message_ref(&group, "A2").unwrap().is_some());
+    }
+
+    #[test]
+    fn test_sqlite_store_search_ranking() {
+        let store = SqliteStore::open_in_memory().unwrap();
+        let chat = JID::new("123", "s.whatsapp.net");
+        store.put_message(&stored("a", &chat, 1, "lunch today?")).unwrap();
+        store.put_message(&stored("b", &chat, 2, "maybe lunch, maybe dinner, who knows")).unwrap();
+        store.put_message(&stored("c", &chat, 3, "see you")).unwrap();
+
+        let found = store.search("lun", None, 10).unwrap();
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[0].message.id, "a");
+        assert!(found[0].score > found[1].score);
+
+        // Query syntax is treated as plain text
+        assert!(store.search("\"lunch OR", None, 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_sqlite_store_replaces_duplicates() {
+        let store = SqliteStore::open_in_memory().unwrap();
+        let chat = JID::new("123", "s.whatsapp.net");
+        store.put_message(&stored("a", &chat, 1, "first")).unwrap();
+        store.put_message(&stored("a", &chat, 1, "edited")).unwrap();
+
+        let message = store.get_message(&chat, "a").unwrap().unwrap();
+        assert_eq!(message.text.as_deref(), Some("edited"));
+        assert!(store.search("first", None, 10).unwrap().is_empty());
+        assert_eq!(store.search("edited", None, 10).unwrap().len(), 1);
+        assert_eq!(store.get_messages(&chat, MessagePage::latest(10)).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_sqlite_store_outbox() {
+        let store = SqliteStore::open_in_memory().unwrap();
+        let outgoing = |id: &str, created_at| OutgoingMessage {
+            id: id.to_string(),
+            to: JID::new("123", "s.whatsapp.net"),
+            text: "hi".to_string(),
+            created_at,
+            attempts: 0,
+        };
+        store.put_outgoing(&outgoing("b", 2)).unwrap();
+        store.put_outgoing(&outgoing("a", 1)).unwrap();
+        store.put_outgoing(&OutgoingMessage { attempts: 1, ..outgoing("b", 2) }).unwrap();
+
+        let pending = store.pending_outgoing().unwrap();
+        assert_eq!(pending, vec![outgoing("a", 1), OutgoingMessage { attempts: 1, ..outgoing("b", 2) }]);
+
+        store.ack_outgoing("a").unwrap();
+        assert_eq!(store.pending_outgoing().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_sqlite_store_schedule() {
+        let store = SqliteStore::open_in_memory().unwrap();
+        let scheduled = |id: &str, send_at| ScheduledMessage {
+            id: id.to_string(),
+            to: JID::new("123", "s.whatsapp.net"),
+            text: "later".to_string(),
+            send_at,
+        };
+        store.put_scheduled(&scheduled("b", 20)).unwrap();
+        store.put_scheduled(&scheduled("a", 10)).unwrap();
+        assert_eq!(store.pending_scheduled().unwrap(), vec![scheduled("a", 10), scheduled("b", 20)]);
+
+        assert!(store.remove_scheduled("a").unwrap());
+        assert!(!store.remove_scheduled("a").unwrap());
+        assert_eq!(store.pending_scheduled().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_sqlite_store_conversations() {
+        let store = SqliteStore::open_in_memory().unwrap();
+        let chat = JID::new("123", "s.whatsapp.net");
+        let state = ConversationState {
+            chat: chat.clone(),
+            flow: "booking".to_string(),
+            step: "date".to_string(),
+            data: [("room".to_string(), "4".to_string())].into_iter().collect(),
+            updated_at: 10,
+        };
+        store.put_conversation(&state).unwrap();
+        assert_eq!(store.get_conversation(&chat).unwrap(), Some(state));
+
+        store.delete_conversation(&chat).unwrap();
+        assert_eq!(store.get_conversation(&chat).unwrap(), None);
+    }
+
+    #[test]
+    fn test_sqlite_store_encrypts_secret_columns() {
+        use crate::store::StaticKeyProvider;
+
+        let path = std::env::temp_dir().join(format!("whatsmeow-store-{}.db", uuid::Uuid::new_v4()));
+        let provider = Arc::new(StaticKeyProvider::new([9; 32]));
+
+        // Written before encryption was turned on
+        SqliteStore::open(&path).unwrap().put_session("1.0", b"old session").unwrap();
+
+        let store = SqliteStore::open(&path).unwrap().with_encryption(provider.clone());
+        assert_eq!(store.get_session("1.0").unwrap().unwrap(), b"old session");
+        store.put_session("1.0", b"new session").unwrap();
+        let record = PreKeyRecord {
+            key_id: 5,
+            public_key: [1; 32],
+            private_key: [2; 32],
+            signature: None,
+            uploaded: false,
+        };
+        store.put_pre_key(&record).unwrap();
+        store.mark_pre_keys_uploaded(5).unwrap();
+        assert_eq!(store.get_pre_key(5).unwrap().unwrap().private_key, [2; 32]);
+        assert_eq!(store.uploaded_pre_key_count().unwrap(), 1);
+        drop(store);
+
+        // Secrets are unreadable on disk, metadata is not
+        let conn = Connection::open(&path).unwrap();
+        let session: Vec<u8> = conn.query_row("SELECT record FROM sessions", [], |row| row.get(0)).unwrap();
+        assert!(!session.windows(11).any(|w| w == b"new session"));
+        let (public_key, private_key): (Vec<u8>, Vec<u8>) = conn
+            .query_row("SELECT public_key, private_key FROM pre_keys WHERE id = 5", [], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap();
+        assert_eq!(public_key, [1; 32]);
+        assert!(!private_key.windows(32).any(|w| w == [2; 32]));
+        drop(conn);
+
+        let wrong_key = SqliteStore::open(&path).unwrap().with_encryption(Arc::new(StaticKeyProvider::new([8; 32])));
+        assert!(matches!(wrong_key.get_session("1.0"), Err(StoreError::EncryptionError(_))));
+        assert!(SqliteStore::open(&path).unwrap().get_pre_key(5).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
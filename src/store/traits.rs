@@ -4,8 +4,7 @@
 //! needed by the WhatsApp client.
 
 use crate::types::JID;
-use crate::store::{Device, ContactInfo, ChatSettings, PreKeyRecord, SessionRecord, IdentityRecord};
-use std::future::Future;
+use crate::store::{Device, ContactInfo, ChatSettings, MessageRef, PreKeyRecord, StoredMessage, MessagePage, MessageMatch, OutgoingMessage, ConversationState, ScheduledMessage, AppStateSyncKey, SenderKeyName};
 
 /// Error type for store operations.
 #[derive(Debug, Clone)]
@@ -13,6 +12,7 @@ pub enum StoreError {
     NotFound,
     DatabaseError(String),
     SerializationError(String),
+    EncryptionError(String),
 }
 
 impl std::fmt::Display for StoreError {
@@ -21,6 +21,7 @@ impl std::fmt::Display for StoreError {
             StoreError::NotFound => write!(f, "not found"),
             StoreError::DatabaseError(e) => write!(f, "database error: {}", e),
             StoreError::SerializationError(e) => write!(f, "serialization error: {}", e),
+            StoreError::EncryptionError(e) => write!(f, "encryption error: {}", e),
         }
     }
 }
@@ -78,12 +79,88 @@ pub trait PreKeyStore: Send + Sync {
 }
 
 /// Sender key store for group messaging.
+///
+/// Keys are named by the group and the sender's device. The methods taking
+/// `group` and `user` strings are the store's old interface, kept for
+/// existing callers: a user given without a device refers to whichever of
+/// their devices has a key stored.
 pub trait SenderKeyStore: Send + Sync {
     /// Get a sender key.
-    fn get_sender_key(&self, group: &str, user: &str) -> StoreResult<Option<Vec<u8>>>;
-    
+    fn get_sender_key_by_name(&self, name: &SenderKeyName) -> StoreResult<Option<Vec<u8>>>;
+
     /// Store a sender key.
-    fn put_sender_key(&self, group: &str, user: &str, key: &[u8]) -> StoreResult<()>;
+    fn put_sender_key_by_name(&self, name: &SenderKeyName, key: &[u8]) -> StoreResult<()>;
+
+    /// Delete a sender key.
+    fn delete_sender_key_by_name(&self, name: &SenderKeyName) -> StoreResult<()>;
+
+    /// Delete the sender keys of every device of a user in a group.
+    fn delete_user_sender_keys(&self, group: &JID, user: &JID) -> StoreResult<()>;
+
+    /// Get the devices of a user that have a sender key in a group,
+    /// lowest device first.
+    fn sender_key_devices(&self, group: &JID, user: &JID) -> StoreResult<Vec<JID>>;
+
+    /// Get a sender key by group and user.
+    #[deprecated(note = "use get_sender_key_by_name with a SenderKeyName")]
+    fn get_sender_key(&self, group: &str, user: &str) -> StoreResult<Option<Vec<u8>>> {
+        self.get_sender_key_by_name(&legacy_sender_key_name(self, group, user)?)
+    }
+
+    /// Store a sender key by group and user.
+    #[deprecated(note = "use put_sender_key_by_name with a SenderKeyName")]
+    fn put_sender_key(&self, group: &str, user: &str, key: &[u8]) -> StoreResult<()> {
+        self.put_sender_key_by_name(&legacy_sender_key_name(self, group, user)?, key)
+    }
+
+    /// Delete the sender key of a user in a group; without a device, that
+    /// of every device of the user.
+    #[deprecated(note = "use delete_sender_key_by_name with a SenderKeyName")]
+    fn delete_sender_key(&self, group: &str, user: &str) -> StoreResult<()> {
+        let name = SenderKeyName::from_legacy(group, user)?;
+        if legacy_user_has_device(user) {
+            self.delete_sender_key_by_name(&name)
+        } else {
+            self.delete_user_sender_keys(&name.group, &name.sender)
+        }
+    }
+}
+
+/// Whether an old-style user string names a device, as in `123:2`.
+fn legacy_user_has_device(user: &str) -> bool {
+    user.split('@').next().is_some_and(|user| user.contains(':'))
+}
+
+/// Name the key an old-style `group` and `user` refer to. A user without a
+/// device refers to their lowest device with a stored key, or the primary
+/// device if none has one.
+fn legacy_sender_key_name<S: SenderKeyStore + ?Sized>(store: &S, group: &str, user: &str) -> StoreResult<SenderKeyName> {
+    let name = SenderKeyName::from_legacy(group, user)?;
+    if legacy_user_has_device(user) {
+        return Ok(name);
+    }
+    match store.sender_key_devices(&name.group, &name.sender)?.into_iter().next() {
+        Some(sender) => Ok(SenderKeyName::new(name.group, sender)),
+        None => Ok(name),
+    }
+}
+
+/// Store of app state sync keys shared by the primary device.
+pub trait AppStateKeyStore: Send + Sync {
+    /// Get a key by its ID.
+    fn get_app_state_key(&self, key_id: &[u8]) -> StoreResult<Option<AppStateSyncKey>>;
+
+    /// Store a key, replacing any previous one with the same ID.
+    fn put_app_state_key(&self, key: &AppStateSyncKey) -> StoreResult<()>;
+
+    /// Get the most recently created key.
+    fn latest_app_state_key(&self) -> StoreResult<Option<AppStateSyncKey>>;
+
+    /// Store several keys in one write. Stores that can should write all
+    /// or none of them; the default stores them one at a time.
+    fn put_app_state_keys(&self, keys: &[AppStateSyncKey]) -> StoreResult<()> {
+        keys.iter().try_for_each(|key| self.put_app_state_key(key))
+    }
 }
 
 /// Contact store for contact information.
@@ -96,6 +173,12 @@ pub trait ContactStore: Send + Sync {
     
     /// Get all contacts.
     fn get_all_contacts(&self) -> StoreResult<Vec<ContactInfo>>;
+
+    /// Store several contacts in one write. Stores that can should write
+    /// all or none of them; the default stores them one at a time.
+    fn put_contacts(&self, contacts: &[ContactInfo]) -> StoreResult<()> {
+        contacts.iter().try_for_each(|contact| self.put_contact(contact))
+    }
 }
 
 /// Chat settings store.
@@ -107,6 +190,95 @@ pub trait ChatSettingsStore: Send + Sync {
     fn put_chat_settings(&self, chat: &JID, settings: &ChatSettings) -> StoreResult<()>;
 }
 
+/// Store of message secrets, which votes and responses to polls and
+/// events are encrypted with.
+pub trait MsgSecretStore: Send + Sync {
+    /// Get the secret of a message, identified by its chat, sender and ID.
+    fn get_message_secret(&self, chat: &JID, sender: &JID, id: &str) -> StoreResult<Option<Vec<u8>>>;
+
+    /// Store the secret of a message.
+    fn put_message_secret(&self, chat: &JID, sender: &JID, id: &str, secret: &[u8]) -> StoreResult<()>;
+}
+
+/// Index of sent and received messages by ID, which replies are resolved
+/// through.
+pub trait MessageIndexStore: Send + Sync {
+    /// Get a message by its chat and ID.
+    fn get_message_ref(&self, chat: &JID, id: &str) -> StoreResult<Option<MessageRef>>;
+
+    /// Index a message, replacing any previous entry with the same chat
+    /// and ID.
+    fn put_message_ref(&self, message: &MessageRef) -> StoreResult<()>;
+
+    /// Drop the entries of messages sent before `before`, a Unix
+    /// timestamp.
+    fn prune_message_refs(&self, before: i64) -> StoreResult<()>;
+}
+
+/// Chat history store.
+///
+/// Optional: when a chat store is attached to the client, it is fed with
+/// every received and sent message.
+pub trait ChatStore: Send + Sync {
+    /// Store a message, replacing any previous copy with the same chat and ID.
+    fn put_message(&self, message: &StoredMessage) -> StoreResult<()>;
+
+    /// Get a page of messages in a chat, newest first.
+    fn get_messages(&self, chat: &JID, page: MessagePage) -> StoreResult<Vec<StoredMessage>>;
+
+    /// Find messages containing every word of the query, best matches first.
+    fn search(&self, query: &str, chat: Option<&JID>, limit: usize) -> StoreResult<Vec<MessageMatch>>;
+
+    /// Store several messages in one write. Stores that can should write
+    /// all or none of them; the default stores them one at a time.
+    fn put_messages(&self, messages: &[StoredMessage]) -> StoreResult<()> {
+        messages.iter().try_for_each(|message| self.put_message(message))
+    }
+}
+
+/// Durable queue of outgoing messages.
+///
+/// Optional: when an outbox is attached to the client, messages are saved
+/// before they are sent and removed once the server acknowledges them, so
+/// sends interrupted by a crash are retried on the next connect.
+pub trait OutboxStore: Send + Sync {
+    /// Store a message, replacing any previous copy with the same ID.
+    fn put_outgoing(&self, message: &OutgoingMessage) -> StoreResult<()>;
+
+    /// Remove a message once the server acknowledged it.
+    fn ack_outgoing(&self, id: &str) -> StoreResult<()>;
+
+    /// Get all unacknowledged messages, oldest first.
+    fn pending_outgoing(&self) -> StoreResult<Vec<OutgoingMessage>>;
+}
+
+/// Store of messages scheduled for later sending.
+pub trait ScheduleStore: Send + Sync {
+    /// Store a scheduled message, replacing any previous one with the same ID.
+    fn put_scheduled(&self, message: &ScheduledMessage) -> StoreResult<()>;
+
+    /// Remove a scheduled message. Returns false if there was none.
+    fn remove_scheduled(&self, id: &str) -> StoreResult<bool>;
+
+    /// Get all scheduled messages, earliest first.
+    fn pending_scheduled(&self) -> StoreResult<Vec<ScheduledMessage>>;
+}
+
+/// Store of in-progress conversations, one per chat.
+///
+/// Optional: used by `bot::ConversationManager` so dialogs survive a
+/// restart.
+pub trait ConversationStore: Send + Sync {
+    /// Get the conversation running in a chat.
+    fn get_conversation(&self, chat: &JID) -> StoreResult<Option<ConversationState>>;
+
+    /// Store a conversation, replacing the chat's previous one.
+    fn put_conversation(&self, state: &ConversationState) -> StoreResult<()>;
+
+    /// Remove the conversation running in a chat.
+    fn delete_conversation(&self, chat: &JID) -> StoreResult<()>;
+}
+
 /// Device container for storing device data.
 pub trait DeviceStore: Send + Sync {
     /// Get a device by JID.
@@ -123,11 +295,14 @@ pub trait DeviceStore: Send + Sync {
 }
 
 /// Combined store interface for all stores.
-pub trait Store: DeviceStore + IdentityStore + SessionStore + PreKeyStore + SenderKeyStore + ContactStore + ChatSettingsStore {
+pub trait Store: DeviceStore + IdentityStore + SessionStore + PreKeyStore + SenderKeyStore + AppStateKeyStore + ContactStore + ChatSettingsStore + MsgSecretStore
+    + MessageIndexStore
+{
 }
 
 // Blanket implementation for any type that implements all store traits
 impl<T> Store for T 
 where 
-    T: DeviceStore + IdentityStore + SessionStore + PreKeyStore + SenderKeyStore + ContactStore + ChatSettingsStore 
+    T: DeviceStore + IdentityStore + SessionStore + PreKeyStore + SenderKeyStore + AppStateKeyStore + ContactStore + ChatSettingsStore
+        + MsgSecretStore + MessageIndexStore
 {}
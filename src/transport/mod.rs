@@ -0,0 +1,96 @@
+//! Transports carrying binary WebSocket frames.
+//!
+//! The Noise layer only needs to send and receive whole binary frames, so
+//! the socket underneath is abstracted behind `Transport`. Natively
+//! `TungsteniteTransport` connects over TCP and TLS; in the browser (the
+//! `wasm` feature) `WebSocketTransport` uses the page's `WebSocket`. Other
+//! runtimes, proxies or test doubles can implement `Transport` themselves
+//! and pass it to `Client::connect_with_transport`.
+
+use std::fmt;
+use std::future::Future;
+
+#[cfg(feature = "native")]
+mod tungstenite;
+#[cfg(all(feature = "wasm", target_arch = "wasm32"))]
+mod web;
+
+#[cfg(feature = "native")]
+pub use tungstenite::{TungsteniteTransport, WebSocketOptions};
+#[cfg(all(feature = "wasm", target_arch = "wasm32"))]
+pub use web::WebSocketTransport;
+
+/// Code and reason of a WebSocket close frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CloseReason {
+    /// Close status code, such as 1000 for a normal closure
+    pub code: u16,
+    /// Reason text, often empty
+    pub reason: String,
+}
+
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.reason.is_empty() {
+            write!(f, "code {}", self.code)
+        } else {
+            write!(f, "code {}: {}", self.code, self.reason)
+        }
+    }
+}
+
+/// Error from a transport.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransportError {
+    /// Opening the connection failed
+    ConnectFailed(String),
+    /// The connection is closed
+    Closed,
+    /// The peer closed the connection with a close frame
+    ClosedByPeer(CloseReason),
+    /// Sending or receiving failed
+    Io(String),
+    /// A message was over the configured size limit
+    TooLarge(String),
+}
+
+impl fmt::Display for TransportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransportError::ConnectFailed(e) => write!(f, "connect failed: {}", e),
+            TransportError::Closed => write!(f, "connection closed"),
+            TransportError::ClosedByPeer(close) => write!(f, "connection closed by peer ({})", close),
+            TransportError::Io(e) => write!(f, "transport error: {}", e),
+            TransportError::TooLarge(e) => write!(f, "message over the WebSocket size limit: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for TransportError {}
+
+/// `Send` on native targets, nothing in the browser, where JavaScript
+/// objects cannot leave their thread.
+#[cfg(not(target_arch = "wasm32"))]
+pub trait MaybeSend: Send {}
+#[cfg(not(target_arch = "wasm32"))]
+impl<T: Send + ?Sized> MaybeSend for T {}
+
+/// `Send` on native targets, nothing in the browser, where JavaScript
+/// objects cannot leave their thread.
+#[cfg(target_arch = "wasm32")]
+pub trait MaybeSend {}
+#[cfg(target_arch = "wasm32")]
+impl<T: ?Sized> MaybeSend for T {}
+
+/// A connection carrying binary frames.
+pub trait Transport: MaybeSend {
+    /// Send one binary frame.
+    fn send(&mut self, frame: Vec<u8>) -> impl Future<Output = Result<(), TransportError>> + MaybeSend;
+
+    /// Receive the next binary frame, or `None` once the connection ended
+    /// without a close reason. Control and text frames are not returned.
+    fn recv(&mut self) -> impl Future<Output = Result<Option<Vec<u8>>, TransportError>> + MaybeSend;
+
+    /// Close the connection.
+    fn close(&mut self) -> impl Future<Output = Result<(), TransportError>> + MaybeSend;
+}
@@ -0,0 +1,396 @@
+//! Native WebSocket transport.
+
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use futures::{SinkExt, StreamExt};
+use socket2::{Domain, Protocol, Socket, TcpKeepalive, Type};
+use tokio::net::{TcpSocket, TcpStream};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::handshake::client::Request;
+use tokio_tungstenite::tungstenite::http::header::{HeaderValue, ORIGIN, SEC_WEBSOCKET_PROTOCOL, USER_AGENT};
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+use tokio_tungstenite::tungstenite::Error as WsError;
+use tokio_tungstenite::{client_async_tls_with_config, tungstenite::Message, MaybeTlsStream, WebSocketStream};
+
+use crate::socket::handshake::WA_ORIGIN;
+use crate::socket::rotation::Endpoint;
+use crate::transport::{CloseReason, Transport, TransportError};
+
+/// How long to wait for the TCP connection to each address.
+const TCP_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Headers, size limits and TCP socket options of the WebSocket
+/// connection.
+///
+/// The server checks the `Origin` of the upgrade request, so it is set to
+/// WhatsApp Web's by default.
+///
+/// The connection stays idle between keepalive pings, long enough for
+/// some NATs to drop it without telling either end. TCP keepalive probes
+/// keep the mapping alive and notice a dead path sooner; like Go's
+/// defaults, they are sent every 15 seconds and Nagle's algorithm is off.
+///
+/// History syncs arrive as single messages of several megabytes, so the
+/// limits are higher than tungstenite's. Compression (permessage-deflate)
+/// is not offered: tungstenite does not implement the extension.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WebSocketOptions {
+    /// `Origin` header, or `None` to leave it out
+    pub origin: Option<String>,
+    /// `User-Agent` header; the client fills in `ClientConfig::user_agent`
+    /// when this is `None`
+    pub user_agent: Option<String>,
+    /// `Sec-WebSocket-Protocol` header. The server must accept the protocol
+    /// or the connection fails.
+    pub protocol: Option<String>,
+    /// Largest frame accepted, `None` for no limit
+    pub max_frame_size: Option<usize>,
+    /// Largest message accepted, across its frames, `None` for no limit
+    pub max_message_size: Option<usize>,
+    /// Bytes buffered before writing to the socket
+    pub write_buffer_size: usize,
+    /// Bytes buffered before sends fail because the socket is not keeping up
+    pub max_write_buffer_size: usize,
+    /// Idle time before TCP keepalive probes, and the interval between
+    /// them; `None` leaves keepalive off
+    pub tcp_keepalive: Option<Duration>,
+    /// Send small frames at once rather than batching them (`TCP_NODELAY`)
+    pub tcp_nodelay: bool,
+    /// Local address to connect from. Server addresses of the other IP
+    /// family are skipped.
+    pub local_address: Option<IpAddr>,
+    /// Network interface to connect through, such as `wlan0`. Only
+    /// supported on Linux and Android.
+    pub bind_interface: Option<String>,
+}
+
+impl Default for WebSocketOptions {
+    fn default() -> Self {
+        Self {
+            origin: Some(WA_ORIGIN.to_string()),
+            user_agent: None,
+            protocol: None,
+            max_frame_size: Some(64 << 20),
+            max_message_size: Some(128 << 20),
+            write_buffer_size: 128 << 10,
+            max_write_buffer_size: 16 << 20,
+            tcp_keepalive: Some(Duration::from_secs(15)),
+            tcp_nodelay: true,
+            local_address: None,
+            bind_interface: None,
+        }
+    }
+}
+
+impl WebSocketOptions {
+    /// Build the upgrade request for a URL, with the configured headers.
+    pub fn request(&self, url: &str) -> Result<Request, TransportError> {
+        let mut request = url.into_client_request()
+            .map_err(|e| TransportError::ConnectFailed(e.to_string()))?;
+        let headers = [
+            (ORIGIN, &self.origin),
+            (USER_AGENT, &self.user_agent),
+            (SEC_WEBSOCKET_PROTOCOL, &self.protocol),
+        ];
+        for (name, value) in headers {
+            if let Some(value) = value {
+                let value = HeaderValue::from_str(value)
+                    .map_err(|e| TransportError::ConnectFailed(format!("invalid {} header: {}", name, e)))?;
+                request.headers_mut().insert(name, value);
+            }
+        }
+        Ok(request)
+    }
+
+    fn to_config(&self) -> WebSocketConfig {
+        WebSocketConfig {
+            max_frame_size: self.max_frame_size,
+            max_message_size: self.max_message_size,
+            write_buffer_size: self.write_buffer_size,
+            max_write_buffer_size: self.max_write_buffer_size,
+            ..Default::default()
+        }
+    }
+}
+
+/// Transport over a tokio-tungstenite WebSocket.
+pub struct TungsteniteTransport {
+    ws: WebSocketStream<MaybeTlsStream<TcpStream>>,
+}
+
+impl TungsteniteTransport {
+    /// Connect to a WebSocket URL.
+    pub async fn connect(url: &str) -> Result<Self, TransportError> {
+        Self::connect_to(&Endpoint::new(url), false, &WebSocketOptions::default()).await
+    }
+
+    /// Connect to an endpoint, possibly at a fixed IP address.
+    ///
+    /// The URL host is always used for TLS SNI and the Host header. Each
+    /// address is tried in turn, IPv6 first when `prefer_ipv6` is set.
+    pub async fn connect_to(
+        endpoint: &Endpoint,
+        prefer_ipv6: bool,
+        options: &WebSocketOptions,
+    ) -> Result<Self, TransportError> {
+        let request = options.request(&endpoint.url)?;
+        let uri = request.uri();
+        let host = uri.host()
+            .ok_or_else(|| TransportError::ConnectFailed(format!("no host in {}", endpoint.url)))?
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_string();
+        let port = uri.port_u16()
+            .unwrap_or(if uri.scheme_str() == Some("ws") { 80 } else { 443 });
+
+        let addrs = match endpoint.ip {
+            Some(ip) => vec![SocketAddr::new(ip, port)],
+            None => tokio::net::lookup_host((host.as_str(), port))
+                .await
+                .map_err(|e| TransportError::ConnectFailed(e.to_string()))?
+                .collect(),
+        };
+
+        let stream = connect_tcp(order_addrs(addrs, prefer_ipv6), options).await?;
+        let (ws, _response) = client_async_tls_with_config(request, stream, Some(options.to_config()), None)
+            .await
+            .map_err(|e| TransportError::ConnectFailed(e.to_string()))?;
+        Ok(Self { ws })
+    }
+}
+
+impl Transport for TungsteniteTransport {
+    async fn send(&mut self, frame: Vec<u8>) -> Result<(), TransportError> {
+        self.ws
+            .send(Message::Binary(frame))
+            .await
+            .map_err(io_error)
+    }
+
+    async fn recv(&mut self) -> Result<Option<Vec<u8>>, TransportError> {
+        loop {
+            match self.ws.next().await {
+                Some(Ok(Message::Binary(data))) => return Ok(Some(data)),
+                Some(Ok(Message::Ping(_))) => {
+                    // tungstenite queued the pong; send it now rather than
+                    // with our next frame, which may be a while
+                    self.ws.flush().await.map_err(io_error)?;
+                }
+                Some(Ok(Message::Pong(_) | Message::Frame(_))) => continue,
+                Some(Ok(Message::Text(text))) => {
+                    log::warn!("ignoring {}-byte text frame", text.len());
+                }
+                Some(Ok(Message::Close(Some(frame)))) => {
+                    return Err(TransportError::ClosedByPeer(CloseReason {
+                        code: frame.code.into(),
+                        reason: frame.reason.into_owned(),
+                    }));
+                }
+                Some(Ok(Message::Close(None))) | None => return Ok(None),
+                Some(Err(e)) => return Err(io_error(e)),
+            }
+        }
+    }
+
+    async fn close(&mut self) -> Result<(), TransportError> {
+        self.ws
+            .close(None)
+            .await
+            .map_err(|e| TransportError::Io(e.to_string()))
+    }
+}
+
+/// Map a WebSocket error, calling out messages over the size limits.
+fn io_error(error: WsError) -> TransportError {
+    match error {
+        WsError::Capacity(e) => TransportError::TooLarge(e.to_string()),
+        e => TransportError::Io(e.to_string()),
+    }
+}
+
+/// Order addresses for connecting, keeping the resolver order within a family.
+fn order_addrs(mut addrs: Vec<SocketAddr>, prefer_ipv6: bool) -> Vec<SocketAddr> {
+    if prefer_ipv6 {
+        addrs.sort_by_key(|addr| !addr.is_ipv6());
+    } else {
+        addrs.sort_by_key(|addr| addr.is_ipv6());
+    }
+    addrs
+}
+
+/// Open a TCP connection to the first address that accepts it.
+async fn connect_tcp(addrs: Vec<SocketAddr>, options: &WebSocketOptions) -> Result<TcpStream, TransportError> {
+    let mut last_error = None;
+    for addr in addrs {
+        if options.local_address.is_some_and(|local| local.is_ipv4() != addr.is_ipv4()) {
+            continue;
+        }
+        let socket = match tcp_socket(addr, options) {
+            Ok(socket) => socket,
+            Err(e) => {
+                last_error = Some(format!("{}: {}", addr, e));
+                continue;
+            }
+        };
+        match tokio::time::timeout(TCP_CONNECT_TIMEOUT, socket.connect(addr)).await {
+            Ok(Ok(stream)) => return Ok(stream),
+            Ok(Err(e)) => last_error = Some(format!("{}: {}", addr, e)),
+            Err(_) => last_error = Some(format!("{}: timed out", addr)),
+        }
+    }
+    Err(TransportError::ConnectFailed(
+        last_error.unwrap_or_else(|| "no addresses resolved".to_string()),
+    ))
+}
+
+/// Create a socket for connecting to `addr`, with the configured options.
+fn tcp_socket(addr: SocketAddr, options: &WebSocketOptions) -> std::io::Result<TcpSocket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_nonblocking(true)?;
+    socket.set_tcp_nodelay(options.tcp_nodelay)?;
+    if let Some(idle) = options.tcp_keepalive {
+        socket.set_tcp_keepalive(&TcpKeepalive::new().with_time(idle).with_interval(idle))?;
+    }
+    if let Some(interface) = &options.bind_interface {
+        bind_interface(&socket, interface)?;
+    }
+    if let Some(local) = options.local_address {
+        socket.bind(&SocketAddr::new(local, 0).into())?;
+    }
+    Ok(TcpSocket::from_std_stream(socket.into()))
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn bind_interface(socket: &Socket, interface: &str) -> std::io::Result<()> {
+    socket.bind_device(Some(interface.as_bytes()))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn bind_interface(_socket: &Socket, _interface: &str) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "binding to an interface is not supported on this platform",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::IpAddr;
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::tungstenite::handshake::server::{Request, Response};
+
+    #[test]
+    fn test_order_addrs() {
+        let v4: SocketAddr = "10.0.0.1:443".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:443".parse().unwrap();
+
+        assert_eq!(order_addrs(vec![v4, v6], true), vec![v6, v4]);
+        assert_eq!(order_addrs(vec![v6, v4], false), vec![v4, v6]);
+    }
+
+    #[tokio::test]
+    #[allow(clippy::result_large_err)]
+    async fn test_connect_to_ip_literal_keeps_host() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut host = None;
+            let _ws = tokio_tungstenite::accept_hdr_async(stream, |req: &Request, res: Response| {
+                host = req.headers().get("host").map(|h| h.to_str().unwrap().to_string());
+                Ok(res)
+            }).await.unwrap();
+            host
+        });
+
+        // The host name does not resolve; only the IP literal is used
+        let endpoint = Endpoint::with_ip(
+            format!("ws://chat.invalid:{}/ws/chat", port),
+            IpAddr::from([127, 0, 0, 1]),
+        );
+        let _transport = TungsteniteTransport::connect_to(&endpoint, true, &WebSocketOptions::default()).await.unwrap();
+
+        assert_eq!(server.await.unwrap(), Some(format!("chat.invalid:{}", port)));
+    }
+
+    #[tokio::test]
+    async fn test_tcp_socket_options() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let options = WebSocketOptions { local_address: Some(IpAddr::from([127, 0, 0, 1])), ..Default::default() };
+
+        let stream = connect_tcp(vec![addr], &options).await.unwrap();
+        assert!(stream.nodelay().unwrap());
+        let (_, peer) = listener.accept().await.unwrap();
+        assert_eq!(peer.ip(), IpAddr::from([127, 0, 0, 1]));
+
+        // Addresses of the other family cannot be reached from the local address
+        let options = WebSocketOptions { local_address: Some("::1".parse().unwrap()), ..Default::default() };
+        assert!(connect_tcp(vec![addr], &options).await.is_err());
+    }
+
+    #[test]
+    fn test_upgrade_request_headers() {
+        let options = WebSocketOptions { user_agent: Some("Mozilla/5.0".to_string()), ..Default::default() };
+        let request = options.request("wss://web.whatsapp.com/ws/chat").unwrap();
+        let header = |name| request.headers().get(name).and_then(|v| v.to_str().ok());
+        assert_eq!(header("origin"), Some(WA_ORIGIN));
+        assert_eq!(header("user-agent"), Some("Mozilla/5.0"));
+        assert_eq!(header("sec-websocket-protocol"), None);
+
+        let options = WebSocketOptions { origin: None, ..Default::default() };
+        assert!(options.request("wss://web.whatsapp.com/ws/chat").unwrap().headers().get("origin").is_none());
+        let options = WebSocketOptions { protocol: Some("bad\nvalue".to_string()), ..Default::default() };
+        assert!(options.request("wss://web.whatsapp.com/ws/chat").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_control_frames_are_handled() {
+        use tokio_tungstenite::tungstenite::protocol::{frame::coding::CloseCode, CloseFrame};
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+            ws.send(Message::Ping(b"hi".to_vec())).await.unwrap();
+            ws.send(Message::Text("hello".to_string())).await.unwrap();
+            ws.send(Message::Binary(vec![1, 2])).await.unwrap();
+            let pong = ws.next().await.unwrap().unwrap();
+            ws.send(Message::Close(Some(CloseFrame { code: CloseCode::Library(4000), reason: "bye".into() })))
+                .await
+                .unwrap();
+            pong
+        });
+
+        let endpoint = Endpoint::new(format!("ws://127.0.0.1:{}/ws/chat", port));
+        let mut transport = TungsteniteTransport::connect_to(&endpoint, false, &WebSocketOptions::default()).await.unwrap();
+
+        // The ping and the text frame are skipped, not taken for the end
+        assert_eq!(transport.recv().await, Ok(Some(vec![1, 2])));
+        assert_eq!(
+            transport.recv().await,
+            Err(TransportError::ClosedByPeer(CloseReason { code: 4000, reason: "bye".to_string() })),
+        );
+        assert_eq!(server.await.unwrap(), Message::Pong(b"hi".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn test_message_over_limit_is_reported() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+            ws.send(Message::Binary(vec![0; 2048])).await.unwrap();
+        });
+
+        let options = WebSocketOptions { max_message_size: Some(1024), ..Default::default() };
+        let endpoint = Endpoint::new(format!("ws://127.0.0.1:{}/ws/chat", port));
+        let mut transport = TungsteniteTransport::connect_to(&endpoint, false, &options).await.unwrap();
+
+        assert!(matches!(transport.recv().await, Err(TransportError::TooLarge(_))));
+    }
+}
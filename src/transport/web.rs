@@ -0,0 +1,116 @@
+//! Browser WebSocket transport.
+
+use futures::channel::mpsc;
+use futures::StreamExt;
+use js_sys::{ArrayBuffer, Uint8Array};
+use wasm_bindgen::closure::Closure;
+use wasm_bindgen::JsCast;
+use web_sys::{BinaryType, CloseEvent, Event, MessageEvent, WebSocket};
+
+use crate::transport::{CloseReason, Transport, TransportError};
+
+/// What the socket's event handlers report.
+enum Incoming {
+    Open,
+    Frame(Vec<u8>),
+    Closed(CloseReason),
+    Error,
+}
+
+/// Transport over the browser's `WebSocket`.
+///
+/// Text frames are ignored; WhatsApp only sends binary ones. The browser
+/// answers pings itself.
+pub struct WebSocketTransport {
+    socket: WebSocket,
+    incoming: mpsc::UnboundedReceiver<Incoming>,
+    // Kept alive for as long as the socket may call them
+    _on_open: Closure<dyn FnMut(Event)>,
+    _on_message: Closure<dyn FnMut(MessageEvent)>,
+    _on_close: Closure<dyn FnMut(CloseEvent)>,
+    _on_error: Closure<dyn FnMut(Event)>,
+}
+
+impl WebSocketTransport {
+    /// Open a connection to `url` and wait until it is established.
+    pub async fn connect(url: &str) -> Result<Self, TransportError> {
+        let socket = WebSocket::new(url)
+            .map_err(|e| TransportError::ConnectFailed(format!("{:?}", e)))?;
+        socket.set_binary_type(BinaryType::Arraybuffer);
+
+        let (tx, incoming) = mpsc::unbounded();
+        let report = |message: fn() -> Incoming| {
+            let tx = tx.clone();
+            move || {
+                let _ = tx.unbounded_send(message());
+            }
+        };
+
+        let open = report(|| Incoming::Open);
+        let on_open = Closure::<dyn FnMut(Event)>::new(move |_| open());
+        let close = tx.clone();
+        let on_close = Closure::<dyn FnMut(CloseEvent)>::new(move |event: CloseEvent| {
+            let reason = CloseReason { code: event.code(), reason: event.reason() };
+            let _ = close.unbounded_send(Incoming::Closed(reason));
+        });
+        let error = report(|| Incoming::Error);
+        let on_error = Closure::<dyn FnMut(Event)>::new(move |_| error());
+        let frames = tx.clone();
+        let on_message = Closure::<dyn FnMut(MessageEvent)>::new(move |event: MessageEvent| {
+            if let Ok(buffer) = event.data().dyn_into::<ArrayBuffer>() {
+                let _ = frames.unbounded_send(Incoming::Frame(Uint8Array::new(&buffer).to_vec()));
+            }
+        });
+
+        socket.set_onopen(Some(on_open.as_ref().unchecked_ref()));
+        socket.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+        socket.set_onclose(Some(on_close.as_ref().unchecked_ref()));
+        socket.set_onerror(Some(on_error.as_ref().unchecked_ref()));
+
+        let mut transport = Self {
+            socket,
+            incoming,
+            _on_open: on_open,
+            _on_message: on_message,
+            _on_close: on_close,
+            _on_error: on_error,
+        };
+        match transport.incoming.next().await {
+            Some(Incoming::Open) => Ok(transport),
+            _ => Err(TransportError::ConnectFailed(format!("could not connect to {}", url))),
+        }
+    }
+}
+
+impl Transport for WebSocketTransport {
+    async fn send(&mut self, frame: Vec<u8>) -> Result<(), TransportError> {
+        self.socket.send_with_u8_array(&frame)
+            .map_err(|e| TransportError::Io(format!("{:?}", e)))
+    }
+
+    async fn recv(&mut self) -> Result<Option<Vec<u8>>, TransportError> {
+        loop {
+            match self.incoming.next().await {
+                Some(Incoming::Frame(frame)) => return Ok(Some(frame)),
+                Some(Incoming::Open) => continue,
+                Some(Incoming::Error) => return Err(TransportError::Io("websocket error".to_string())),
+                Some(Incoming::Closed(reason)) => return Err(TransportError::ClosedByPeer(reason)),
+                None => return Ok(None),
+            }
+        }
+    }
+
+    async fn close(&mut self) -> Result<(), TransportError> {
+        self.socket.close().map_err(|e| TransportError::Io(format!("{:?}", e)))
+    }
+}
+
+impl Drop for WebSocketTransport {
+    fn drop(&mut self) {
+        self.socket.set_onopen(None);
+        self.socket.set_onmessage(None);
+        self.socket.set_onclose(None);
+        self.socket.set_onerror(None);
+        let _ = self.socket.close();
+    }
+}
@@ -0,0 +1,90 @@
+//! Business account types.
+
+use crate::types::JID;
+
+/// Public profile of a business account.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BusinessProfile {
+    pub jid: JID,
+    pub description: Option<String>,
+    pub address: Option<String>,
+    pub email: Option<String>,
+    pub websites: Vec<String>,
+    pub categories: Vec<BusinessCategory>,
+}
+
+/// Category a business lists itself under.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BusinessCategory {
+    pub id: String,
+    pub name: String,
+}
+
+/// Product in a business catalog.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Product {
+    /// Catalog product ID
+    pub id: String,
+    /// The business's own ID for the product, such as a SKU
+    pub retailer_id: Option<String>,
+    pub title: String,
+    pub description: Option<String>,
+    /// ISO 4217 currency code
+    pub currency: Option<String>,
+    /// Price in thousandths of the currency unit
+    pub price_amount_1000: Option<i64>,
+    /// Sale price in thousandths of the currency unit
+    pub sale_price_amount_1000: Option<i64>,
+    pub url: Option<String>,
+    /// Image URLs, when listed in a catalog
+    pub image_urls: Vec<String>,
+}
+
+/// Page of a business catalog.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Catalog {
+    pub products: Vec<Product>,
+    /// Cursor for the next page, if there is one
+    pub next_page: Option<String>,
+}
+
+/// Canned reply a business account inserts by typing its shortcut.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct QuickReply {
+    pub id: String,
+    /// Shortcut typed after "/" to insert the reply
+    pub shortcut: String,
+    pub message: String,
+    pub keywords: Vec<String>,
+    /// How many times the reply was used
+    pub count: u32,
+}
+
+/// Message sent to customers writing for the first time, or after a
+/// while without contact.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GreetingMessage {
+    pub enabled: bool,
+    pub message: String,
+    /// Days without contact after which a customer is greeted again
+    pub inactivity_days: u32,
+}
+
+/// When an away message is sent.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum AwaySchedule {
+    #[default]
+    Always,
+    /// Outside the business hours of the profile
+    OutsideBusinessHours,
+    /// Between two Unix timestamps
+    Custom { start: i64, end: i64 },
+}
+
+/// Message sent to customers writing while the business is away.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AwayMessage {
+    pub enabled: bool,
+    pub message: String,
+    pub schedule: AwaySchedule,
+}
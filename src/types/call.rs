@@ -0,0 +1,35 @@
+//! Call types.
+
+use crate::types::JID;
+
+/// Event for an incoming call.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CallOffer {
+    /// Who sent the call stanza
+    pub from: JID,
+    /// ID of the call, used to reject it
+    pub call_id: String,
+    /// Who started the call
+    pub call_creator: JID,
+    /// Whether the call includes video
+    pub is_video: bool,
+    /// Whether this is a group call
+    pub is_group: bool,
+    /// Timestamp of the offer
+    pub timestamp: i64,
+}
+
+/// Event for a call that ended or was declined elsewhere.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CallTerminate {
+    /// Who sent the call stanza
+    pub from: JID,
+    /// ID of the call
+    pub call_id: String,
+    /// Who started the call
+    pub call_creator: JID,
+    /// Why the call ended, e.g. "timeout"
+    pub reason: Option<String>,
+    /// Timestamp of the termination
+    pub timestamp: i64,
+}
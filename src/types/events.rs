@@ -2,7 +2,12 @@
 //!
 //! These events are emitted when various things happen on the WhatsApp connection.
 
-use crate::types::JID;
+use crate::binary::Node;
+use crate::types::{
+    JID, GroupParticipantsUpdate, GroupJoinRequest, GroupSettingChanged, GroupDescriptionChanged, GroupPhotoChanged,
+    CallOffer, CallTerminate, Product, NewsletterReaction, NewsletterViews, LabelEdit,
+    LabelAssociation,
+};
 
 /// Connected event is emitted when the client connects to WhatsApp servers.
 #[derive(Debug, Clone)]
@@ -21,18 +26,38 @@ pub struct Disconnected {
 /// Reason for disconnection
 #[derive(Debug, Clone, PartialEq)]
 pub enum DisconnectReason {
-    /// Normal logout by user
+    /// `Client::disconnect` or `shutdown` closed the connection
+    UserRequested,
+    /// The session was logged out or the device removed; a `LoggedOut`
+    /// event follows
     LoggedOut,
     /// Connection replaced by another device
     Replaced,
-    /// Server requested disconnect
+    /// Server asked for a reconnect
     ServerRequested,
+    /// Server ended the stream with another error code
+    StreamError { code: Option<String> },
     /// Network error
     NetworkError(String),
+    /// The session keys ran out of nonces; connect again for new ones
+    NonceExhausted,
+    /// Nothing arrived for longer than `ClientConfig::stale_timeout`, even
+    /// after a ping; a `StaleConnectionDetected` event came first.
+    /// `Client::receive` connects again if `ClientConfig::auto_reconnect`
+    /// is set.
+    Stale,
     /// Unknown reason
     Unknown,
 }
 
+/// StaleConnectionDetected event is emitted when a connection has been
+/// silent for too long and is about to be closed.
+#[derive(Debug, Clone)]
+pub struct StaleConnectionDetected {
+    /// How long nothing was received
+    pub silent_for: std::time::Duration,
+}
+
 /// LoggedOut event is emitted when the user is logged out.
 #[derive(Debug, Clone)]
 pub struct LoggedOut {
@@ -49,6 +74,9 @@ pub struct QRCode {
     pub code: String,
     /// How many seconds the code is valid
     pub timeout_seconds: u64,
+    /// When the code stops being valid, a Unix timestamp on the server's
+    /// clock
+    pub expires_at: i64,
 }
 
 /// Pairing code event (alternative to QR)
@@ -58,6 +86,39 @@ pub struct PairingCode {
     pub code: String,
 }
 
+/// PairSuccess event is emitted when a phone has linked this device.
+///
+/// The new identity is saved to the store before this is emitted; the
+/// server then restarts the stream so the next connection logs in.
+#[derive(Debug, Clone)]
+pub struct PairSuccess {
+    /// JID of this device
+    pub jid: JID,
+    /// LID of this device, if the server sent one
+    pub lid: Option<JID>,
+    /// Platform of the phone that linked us
+    pub platform: String,
+    /// Business name, for business accounts
+    pub business_name: Option<String>,
+}
+
+/// PairError event is emitted when pairing ends without a link, such as
+/// when every QR code has expired.
+#[derive(Debug, Clone)]
+pub struct PairError {
+    /// Why pairing failed
+    pub reason: String,
+}
+
+/// ConnectFailure event is emitted when the server refuses to log in.
+#[derive(Debug, Clone)]
+pub struct ConnectFailure {
+    /// The `reason` code of the `<failure>`
+    pub code: Option<String>,
+    /// Message sent along with the code, if any
+    pub message: Option<String>,
+}
+
 /// Message event containing a received message
 #[derive(Debug, Clone)]
 pub struct Message {
@@ -84,6 +145,39 @@ pub struct MessageInfo {
     pub timestamp: i64,
     /// Push name of sender
     pub push_name: Option<String>,
+    /// ID of the message this one replies to, in the same chat; see
+    /// `Client::resolve_reply`
+    pub reply_to: Option<String>,
+}
+
+/// Download and decryption details of a media message.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MediaDetails {
+    /// CDN path of the encrypted file
+    pub direct_path: Option<String>,
+    /// Key the file was encrypted with
+    pub media_key: Option<Vec<u8>>,
+    /// SHA256 of the decrypted file
+    pub file_sha256: Option<Vec<u8>>,
+    /// SHA256 of the encrypted file
+    pub file_enc_sha256: Option<Vec<u8>>,
+    /// Size of the decrypted file
+    pub file_length: Option<u64>,
+    /// Where the file was saved, if it was downloaded automatically; set
+    /// in the message of `Event::MediaDownloaded`, see
+    /// `Client::set_media_auto_download`
+    pub local_path: Option<std::path::PathBuf>,
+}
+
+/// A sticker of a sticker pack.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PackSticker {
+    /// Name of the sticker's file in the pack
+    pub file_name: String,
+    /// Emojis the sticker stands for
+    pub emojis: Vec<String>,
+    pub is_animated: bool,
+    pub mimetype: String,
 }
 
 /// Content of a message
@@ -96,29 +190,59 @@ pub enum MessageContent {
         url: String,
         caption: Option<String>,
         mimetype: String,
+        media: MediaDetails,
+        /// Small JPEG preview sent with the message
+        jpeg_thumbnail: Option<Vec<u8>>,
     },
     /// Video message
     Video {
         url: String,
         caption: Option<String>,
         mimetype: String,
+        media: MediaDetails,
+        /// Small JPEG preview sent with the message
+        jpeg_thumbnail: Option<Vec<u8>>,
     },
     /// Audio message
     Audio {
         url: String,
         mimetype: String,
         ptt: bool, // Voice note
+        media: MediaDetails,
+        /// Length in seconds, if sent
+        seconds: Option<u32>,
+        /// Loudness samples voice notes are drawn with, if sent
+        waveform: Option<Vec<u8>>,
     },
     /// Document message
     Document {
         url: String,
         filename: String,
         mimetype: String,
+        media: MediaDetails,
+        /// Small JPEG preview of the first page, if sent
+        jpeg_thumbnail: Option<Vec<u8>>,
     },
     /// Sticker message
     Sticker {
         url: String,
+        media: MediaDetails,
     },
+    /// Pack of stickers, downloaded as one zip; see
+    /// `Client::download_sticker_pack`
+    StickerPack {
+        pack_id: String,
+        name: String,
+        publisher: String,
+        description: Option<String>,
+        stickers: Vec<PackSticker>,
+        media: MediaDetails,
+    },
+    /// Media that can be opened once. It is not saved to the chat store
+    /// or downloaded automatically unless configured; see
+    /// `ClientConfig::store_view_once` and
+    /// `MediaAutoDownloadPolicy::with_view_once`
+    ViewOnce(Box<MessageContent>),
     /// Location message
     Location {
         latitude: f64,
@@ -135,10 +259,64 @@ pub enum MessageContent {
         target_id: String,
         emoji: String,
     },
+    /// Product from a business catalog
+    Product {
+        product: Product,
+        /// Business selling the product
+        business_owner: Option<JID>,
+        body: Option<String>,
+        footer: Option<String>,
+    },
+    /// Button pressed on a buttons or template message
+    ButtonResponse {
+        button_id: String,
+        display_text: String,
+        /// ID of the message with the button
+        reply_to: Option<String>,
+    },
+    /// Row picked from a list message
+    ListResponse {
+        row_id: String,
+        title: String,
+        /// ID of the list message
+        reply_to: Option<String>,
+    },
+    /// Poll to vote on
+    Poll {
+        name: String,
+        options: Vec<String>,
+        /// How many options a voter may pick, 0 for any number
+        selectable_count: u32,
+    },
+    /// Vote on a poll, replacing the voter's previous vote
+    PollVote {
+        /// ID of the poll message
+        poll_id: String,
+        /// SHA-256 hashes of the picked option names; see
+        /// `protocol::poll_option_hash`
+        selected_options: Vec<[u8; 32]>,
+    },
+    /// Response to an event invitation
+    EventResponse {
+        /// ID of the event message
+        event_id: String,
+        response: EventResponseType,
+        /// Guests the responder brings along
+        extra_guests: u32,
+    },
     /// Unknown/unsupported message type
     Unknown,
 }
 
+/// Answer to an event invitation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventResponseType {
+    Going,
+    NotGoing,
+    Maybe,
+    Unknown,
+}
+
 /// Receipt event for message delivery/read status
 #[derive(Debug, Clone)]
 pub struct Receipt {
@@ -165,6 +343,49 @@ pub enum ReceiptType {
     Played,
     /// Server received the message
     Server,
+    /// Message we sent from another of our devices was delivered to this
+    /// one
+    Sender,
+    /// Recipient could not decrypt the message and asks for it again
+    Retry,
+    /// We read the message on another of our devices
+    ReadSelf,
+    /// We played the media on another of our devices
+    PlayedSelf,
+    /// Message was delivered to a device that has not been online lately
+    Inactive,
+    /// Peer message between our own devices was delivered
+    PeerMsg,
+    /// Receipt type this library does not know
+    Other(String),
+}
+
+impl ReceiptType {
+    /// Parse the `type` attribute of a receipt; no attribute means
+    /// delivered.
+    pub fn from_attr(attr: Option<&str>) -> Self {
+        match attr {
+            None | Some("") => ReceiptType::Delivered,
+            Some("read") => ReceiptType::Read,
+            Some("played") => ReceiptType::Played,
+            Some("sender") => ReceiptType::Sender,
+            Some("retry") => ReceiptType::Retry,
+            Some("read-self") => ReceiptType::ReadSelf,
+            Some("played-self") => ReceiptType::PlayedSelf,
+            Some("inactive") => ReceiptType::Inactive,
+            Some("peer_msg") => ReceiptType::PeerMsg,
+            Some(other) => ReceiptType::Other(other.to_string()),
+        }
+    }
+
+    /// Whether the receipt comes from one of our own devices rather than
+    /// the recipient.
+    pub fn is_self(&self) -> bool {
+        matches!(
+            self,
+            ReceiptType::Sender | ReceiptType::ReadSelf | ReceiptType::PlayedSelf | ReceiptType::PeerMsg
+        )
+    }
 }
 
 /// Presence event
@@ -205,8 +426,16 @@ pub enum ChatStateType {
 pub struct HistorySync {
     /// Type of history sync
     pub sync_type: HistorySyncType,
-    /// Data (for download)
+    /// Data sent inline, if any
     pub data: Vec<u8>,
+    /// Where to download the data when it is not inline
+    pub media: MediaDetails,
+    /// Position of this chunk in the sync
+    pub chunk_order: u32,
+    /// How far along the sync is, in percent, if the phone said
+    pub progress: Option<u32>,
+    /// ID returned by `Client::request_history`, for on-demand syncs
+    pub request_id: Option<String>,
 }
 
 /// History sync type
@@ -216,19 +445,191 @@ pub enum HistorySyncType {
     Recent,
     Push,
     Full,
+    /// Older messages requested with `Client::request_history`
+    OnDemand,
+    /// A type this client does not know
+    Other(i32),
+}
+
+/// The contact store was filled from the initial sync
+#[derive(Debug, Clone)]
+pub struct ContactsSynced {
+    /// Number of contacts in the store
+    pub count: usize,
+}
+
+/// A scheduled message was sent
+#[derive(Debug, Clone)]
+pub struct ScheduledMessageSent {
+    /// Schedule ID, which is also the message ID
+    pub id: String,
+    /// Recipient of the message
+    pub to: JID,
+    /// Unix timestamp the message was scheduled for
+    pub scheduled_for: i64,
+    /// Unix timestamp it was sent at
+    pub sent_at: i64,
+}
+
+/// A pre-send interceptor blocked an outgoing message
+#[derive(Debug, Clone)]
+pub struct MessageBlocked {
+    /// ID the message would have been sent with
+    pub id: String,
+    /// Recipient of the message
+    pub to: JID,
+    /// Why the interceptor blocked it
+    pub reason: String,
+}
+
+/// A sender exceeded the limit of a `FloodGuard`; their events are dropped
+/// until they slow down
+#[derive(Debug, Clone)]
+pub struct FloodDetected {
+    /// Sender of the events, without a device
+    pub sender: JID,
+}
+
+/// Banned event is emitted when the server refuses us for a while, either
+/// for breaking the terms of service or for connecting or sending too often.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Banned {
+    /// Stream error code: 402 for a temporary ban, 429 for rate limiting
+    pub code: u16,
+    /// When the ban ends, if the server said
+    pub expires: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl Banned {
+    /// Whether we were only rate limited rather than banned.
+    pub fn is_rate_limit(&self) -> bool {
+        self.code == 429
+    }
+}
+
+/// The unread count of a chat changed, from new messages or from reading
+/// the chat here or on another device.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnreadCountChanged {
+    pub chat: JID,
+    pub unread_count: u32,
+    /// ID of the last message marked read, if known
+    pub last_read: Option<String>,
+}
+
+/// Devices were linked to or removed from an account. For our own account
+/// these are our linked devices, so an unexpected one may mean someone
+/// else has access.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LinkedDevicesChanged {
+    /// Account the devices belong to
+    pub user: JID,
+    pub added: Vec<JID>,
+    pub removed: Vec<JID>,
+    /// Hash of the device list after the change, if the server sent it
+    pub device_hash: Option<String>,
+}
+
+/// A message that arrived but could not be decrypted.
+///
+/// A retry receipt asking the sender to send it again has been sent, so
+/// apps can show a placeholder until the message arrives as a `Message`
+/// with the same ID.
+#[derive(Debug, Clone)]
+pub struct UndecryptableMessage {
+    pub info: MessageInfo,
+}
+
+/// The media of a received message was downloaded automatically. The
+/// message is delivered again with the saved file's path in its
+/// `MediaDetails::local_path`.
+#[derive(Debug, Clone)]
+pub struct MediaDownloaded {
+    pub message: Message,
+}
+
+/// A message in a disappearing chat was kept, so it no longer disappears,
+/// or unkept.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeptMessage {
+    pub chat: JID,
+    /// Who kept or unkept the message
+    pub sender: JID,
+    /// ID of the kept message
+    pub message_id: String,
+    /// Whether the message is now kept
+    pub kept: bool,
+    /// Unix timestamp of the change
+    pub timestamp: i64,
+}
+
+impl MessageContent {
+    /// Whether the message is view-once media.
+    pub fn is_view_once(&self) -> bool {
+        matches!(self, MessageContent::ViewOnce(_))
+    }
+
+    /// The JPEG preview sent with a media message, to show while the
+    /// full file downloads.
+    pub fn jpeg_thumbnail(&self) -> Option<&[u8]> {
+        match self {
+            MessageContent::Image { jpeg_thumbnail, .. }
+            | MessageContent::Video { jpeg_thumbnail, .. }
+            | MessageContent::Document { jpeg_thumbnail, .. } => jpeg_thumbnail.as_deref(),
+            _ => None,
+        }
+    }
 }
 
 /// All possible events that can be received
 #[derive(Debug, Clone)]
+#[allow(clippy::large_enum_variant)]
 pub enum Event {
     Connected(Connected),
     Disconnected(Disconnected),
+    StaleConnectionDetected(StaleConnectionDetected),
     LoggedOut(LoggedOut),
     QRCode(QRCode),
     PairingCode(PairingCode),
+    PairSuccess(PairSuccess),
+    PairError(PairError),
+    ConnectFailure(ConnectFailure),
+    /// The server refused to log in because this client version is too
+    /// old to be supported
+    ClientOutdated,
+    /// The server refused a chat state because we have not announced that
+    /// we are online. Call `send_presence(true)`, or set
+    /// `ClientConfig::send_presence_on_connect`.
+    PresenceRequired,
     Message(Message),
     Receipt(Receipt),
     Presence(Presence),
     ChatState(ChatState),
     HistorySync(HistorySync),
+    GroupParticipants(GroupParticipantsUpdate),
+    GroupJoinRequest(GroupJoinRequest),
+    GroupSettingChanged(GroupSettingChanged),
+    GroupDescriptionChanged(GroupDescriptionChanged),
+    GroupPhotoChanged(GroupPhotoChanged),
+    CallOffer(CallOffer),
+    CallTerminate(CallTerminate),
+    ScheduledMessageSent(ScheduledMessageSent),
+    MessageBlocked(MessageBlocked),
+    FloodDetected(FloodDetected),
+    ContactsSynced(ContactsSynced),
+    NewsletterReaction(NewsletterReaction),
+    NewsletterViews(NewsletterViews),
+    Banned(Banned),
+    UnreadCountChanged(UnreadCountChanged),
+    LinkedDevicesChanged(LinkedDevicesChanged),
+    UndecryptableMessage(UndecryptableMessage),
+    MediaDownloaded(MediaDownloaded),
+    KeptMessage(KeptMessage),
+    LabelEdit(LabelEdit),
+    LabelAssociation(LabelAssociation),
+    /// A received node the client has no handling for, passed through raw
+    UnhandledNode(Node),
+    /// A node `ClientConfig::read_only` kept from being sent, as it would
+    /// have been sent
+    WouldHaveSent(Node),
 }
@@ -0,0 +1,259 @@
+//! Group types.
+
+use std::time::Duration;
+
+use crate::types::JID;
+
+/// Metadata of a group.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct GroupInfo {
+    /// Group JID
+    pub jid: JID,
+    /// Group name
+    pub name: String,
+    /// Group description
+    pub topic: Option<String>,
+    /// ID of the current description, which changing it refers to
+    pub topic_id: Option<String>,
+    /// Creator of the group
+    pub owner: Option<JID>,
+    /// Creation timestamp
+    pub created: i64,
+    /// Whether this is a plain group, a community or one of its groups
+    pub kind: GroupKind,
+    /// Only admins can send messages
+    pub is_announce: bool,
+    /// Only admins can edit the group info
+    pub is_locked: bool,
+    /// Default disappearing message timer
+    pub ephemeral: Option<Duration>,
+    /// Current members
+    pub participants: Vec<GroupParticipant>,
+    /// Number of members, which large groups report without listing
+    /// them all
+    pub size: Option<u32>,
+}
+
+impl GroupInfo {
+    /// Whether more members remain to be fetched than are listed.
+    pub fn is_truncated(&self) -> bool {
+        self.size.is_some_and(|size| self.participants.len() < size as usize)
+    }
+}
+
+/// Place of a group in a community structure.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum GroupKind {
+    /// Standalone group
+    #[default]
+    Group,
+    /// Community parent group
+    Community,
+    /// Group linked to a community
+    Subgroup {
+        /// The community the group belongs to
+        parent: JID,
+        /// Whether this is the community's announcement group
+        is_default: bool,
+    },
+}
+
+/// One page of the members of a large group.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ParticipantsPage {
+    pub participants: Vec<GroupParticipant>,
+    /// Cursor of the next page, `None` on the last one
+    pub next: Option<String>,
+}
+
+/// Text and mentioned JIDs that mention a group's members.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Mentions {
+    /// `@<user>` for each member, separated by spaces
+    pub text: String,
+    /// The mentioned members, in the order of `text`
+    pub jids: Vec<JID>,
+}
+
+/// Group linked to a community.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SubGroup {
+    pub jid: JID,
+    pub name: String,
+    /// Whether this is the community's announcement group
+    pub is_default: bool,
+}
+
+/// Member of a group.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GroupParticipant {
+    pub jid: JID,
+    pub is_admin: bool,
+    pub is_super_admin: bool,
+}
+
+/// Kind of participant change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParticipantAction {
+    /// Participants were added by an admin
+    Add,
+    /// Participants were removed by an admin
+    Remove,
+    /// Participants left on their own
+    Leave,
+    /// Participants were made admins
+    Promote,
+    /// Participants were demoted from admin
+    Demote,
+}
+
+impl ParticipantAction {
+    /// Parse the tag of a group notification child.
+    pub fn from_tag(tag: &str) -> Option<Self> {
+        match tag {
+            "add" => Some(Self::Add),
+            "remove" => Some(Self::Remove),
+            "leave" => Some(Self::Leave),
+            "promote" => Some(Self::Promote),
+            "demote" => Some(Self::Demote),
+            _ => None,
+        }
+    }
+
+    /// Whether the participants are no longer members.
+    pub fn is_departure(&self) -> bool {
+        matches!(self, Self::Remove | Self::Leave)
+    }
+}
+
+/// Event for participants joining, leaving or changing role in a group.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GroupParticipantsUpdate {
+    /// The group JID
+    pub group: JID,
+    /// Who made the change, if not the participants themselves
+    pub author: Option<JID>,
+    /// What happened
+    pub action: ParticipantAction,
+    /// Affected participants
+    pub participants: Vec<JID>,
+    /// Timestamp of the change
+    pub timestamp: i64,
+}
+
+/// Pending request to join a group that needs admin approval.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JoinRequest {
+    /// User asking to join
+    pub jid: JID,
+    /// When the request was made
+    pub requested_at: i64,
+}
+
+/// Outcome of a group action for one participant.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParticipantResult {
+    pub jid: JID,
+    /// Error code if the action failed for this participant
+    pub error: Option<u16>,
+}
+
+/// Event for a user asking to join a group with approval mode on.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GroupJoinRequest {
+    /// The group JID
+    pub group: JID,
+    /// User asking to join
+    pub requester: JID,
+    /// How the user found the group, e.g. "invite_link"
+    pub method: Option<String>,
+    /// Timestamp of the request
+    pub timestamp: i64,
+}
+
+/// Admin-only group setting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GroupSetting {
+    /// Only admins can send messages
+    Announce(bool),
+    /// Only admins can edit the group info
+    Locked(bool),
+    /// Default disappearing message timer, `None` when turned off
+    Ephemeral(Option<Duration>),
+}
+
+impl GroupSetting {
+    /// Parse a group notification or metadata child.
+    pub fn from_node(node: &crate::binary::Node) -> Option<Self> {
+        match &*node.tag {
+            "announcement" => Some(Self::Announce(true)),
+            "not_announcement" => Some(Self::Announce(false)),
+            "locked" => Some(Self::Locked(true)),
+            "unlocked" => Some(Self::Locked(false)),
+            "ephemeral" => {
+                let seconds = node.get_attr_str("expiration").and_then(|e| e.parse().ok()).unwrap_or(0);
+                Some(Self::Ephemeral((seconds > 0).then(|| Duration::from_secs(seconds))))
+            }
+            "not_ephemeral" => Some(Self::Ephemeral(None)),
+            _ => None,
+        }
+    }
+
+    /// Build the node that applies this setting.
+    pub fn to_node(&self) -> crate::binary::Node {
+        use crate::binary::Node;
+        match self {
+            Self::Announce(true) => Node::new("announcement"),
+            Self::Announce(false) => Node::new("not_announcement"),
+            Self::Locked(true) => Node::new("locked"),
+            Self::Locked(false) => Node::new("unlocked"),
+            Self::Ephemeral(Some(timer)) => {
+                let mut node = Node::new("ephemeral");
+                node.set_attr("expiration", timer.as_secs().to_string());
+                node
+            }
+            Self::Ephemeral(None) => Node::new("not_ephemeral"),
+        }
+    }
+}
+
+/// Event for an admin changing a group setting.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GroupSettingChanged {
+    /// The group JID
+    pub group: JID,
+    /// Admin who made the change
+    pub author: Option<JID>,
+    /// The new value
+    pub setting: GroupSetting,
+    /// Timestamp of the change
+    pub timestamp: i64,
+}
+
+/// Event for a group description being changed or removed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GroupDescriptionChanged {
+    /// The group JID
+    pub group: JID,
+    /// Member who made the change
+    pub author: Option<JID>,
+    /// The new description, `None` if it was removed
+    pub description: Option<String>,
+    /// ID of the new description
+    pub description_id: Option<String>,
+    /// Timestamp of the change
+    pub timestamp: i64,
+}
+
+/// Event for a group photo being changed or removed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GroupPhotoChanged {
+    /// The group JID
+    pub group: JID,
+    /// Member who made the change
+    pub author: Option<JID>,
+    /// ID of the new photo, `None` if it was removed
+    pub picture_id: Option<String>,
+    /// Timestamp of the change
+    pub timestamp: i64,
+}
@@ -0,0 +1,38 @@
+//! Interactive message types: buttons and lists.
+
+/// Quick-reply button.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Button {
+    /// ID returned when the button is pressed
+    pub id: String,
+    pub text: String,
+}
+
+/// Section of a list message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListSection {
+    pub title: String,
+    pub rows: Vec<ListRow>,
+}
+
+/// Row of a list message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListRow {
+    /// ID returned when the row is picked
+    pub id: String,
+    pub title: String,
+    pub description: Option<String>,
+}
+
+impl ListRow {
+    /// Row without a description.
+    pub fn new(id: impl Into<String>, title: impl Into<String>) -> Self {
+        Self { id: id.into(), title: title.into(), description: None }
+    }
+
+    /// Add a description shown under the title.
+    pub fn with_description(mut self, description: impl Into<String>) -> Self {
+        self.description = Some(description.into());
+        self
+    }
+}
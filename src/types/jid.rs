@@ -18,6 +18,12 @@ pub mod servers {
     pub const HOSTED: &str = "hosted";
     pub const HOSTED_LID: &str = "hosted.lid";
     pub const BOT: &str = "bot";
+
+    /// Every known server, as accepted by `JID::parse_strict`
+    pub const ALL: &[&str] = &[
+        DEFAULT_USER, GROUP, LEGACY_USER, BROADCAST, HIDDEN_USER, MESSENGER, INTEROP, NEWSLETTER,
+        HOSTED, HOSTED_LID, BOT,
+    ];
 }
 
 /// Domain type constants
@@ -75,6 +81,59 @@ impl JID {
         }
     }
 
+    /// Parses a JID, rejecting unknown servers and malformed users.
+    ///
+    /// Unlike `FromStr`, the server must be one of `servers::ALL`, phone
+    /// number and group users must be numeric, and only user and group
+    /// JIDs may be bare servers.
+    pub fn parse_strict(s: &str) -> Result<Self, ParseJIDError> {
+        let jid: JID = s.parse()?;
+        if !servers::ALL.contains(&jid.server.as_str()) {
+            return Err(ParseJIDError(format!("unknown server {:?}", jid.server)));
+        }
+
+        let numeric = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+        let valid_user = match jid.server.as_str() {
+            _ if jid.user.is_empty() => true,
+            servers::DEFAULT_USER | servers::LEGACY_USER | servers::HIDDEN_USER
+            | servers::HOSTED | servers::HOSTED_LID => numeric(&jid.user),
+            // Legacy groups are "creator-timestamp"
+            servers::GROUP => jid.user.split('-').all(numeric) && jid.user.split('-').count() <= 2,
+            _ => true,
+        };
+        if !valid_user {
+            return Err(ParseJIDError(format!("invalid user {:?} for server {}", jid.user, jid.server)));
+        }
+        Ok(jid)
+    }
+
+    /// Creates a user JID from a phone number in international format.
+    ///
+    /// A leading `+` and common separators (spaces, dashes, dots and
+    /// parentheses) are stripped; see `normalize_phone_number`.
+    pub fn from_phone_number(phone: &str) -> Result<Self, ParseJIDError> {
+        Ok(JID::new(normalize_phone_number(phone)?, servers::DEFAULT_USER))
+    }
+
+    /// Returns true if this JID identifies a user account.
+    pub fn is_user(&self) -> bool {
+        !self.user.is_empty() && matches!(
+            self.server.as_str(),
+            servers::DEFAULT_USER | servers::LEGACY_USER | servers::HIDDEN_USER
+                | servers::HOSTED | servers::HOSTED_LID
+        )
+    }
+
+    /// Returns true if this JID identifies a group.
+    pub fn is_group(&self) -> bool {
+        !self.user.is_empty() && self.server == servers::GROUP
+    }
+
+    /// Returns true if this JID identifies a newsletter (channel).
+    pub fn is_newsletter(&self) -> bool {
+        !self.user.is_empty() && self.server == servers::NEWSLETTER
+    }
+
     /// Returns the actual agent/domain type.
     pub fn actual_agent(&self) -> u8 {
         match self.server.as_str() {
@@ -174,12 +233,40 @@ impl fmt::Display for ParseJIDError {
 
 impl std::error::Error for ParseJIDError {}
 
+/// Normalizes a phone number in international format to its digits.
+///
+/// Strips a leading `+` and spaces, dashes, dots and parentheses. The
+/// result must be 5 to 15 digits (the E.164 maximum) without a leading 0.
+pub fn normalize_phone_number(phone: &str) -> Result<String, ParseJIDError> {
+    let trimmed = phone.trim();
+    let trimmed = trimmed.strip_prefix('+').unwrap_or(trimmed);
+    let mut digits = String::with_capacity(trimmed.len());
+    for c in trimmed.chars() {
+        match c {
+            '0'..='9' => digits.push(c),
+            ' ' | '-' | '.' | '(' | ')' => {}
+            _ => return Err(ParseJIDError(format!("invalid character {:?} in phone number", c))),
+        }
+    }
+
+    if !(5..=15).contains(&digits.len()) {
+        return Err(ParseJIDError(format!("phone number has {} digits", digits.len())));
+    }
+    if digits.starts_with('0') {
+        return Err(ParseJIDError("phone number must include the country code".to_string()));
+    }
+    Ok(digits)
+}
+
 impl FromStr for JID {
     type Err = ParseJIDError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let parts: Vec<&str> = s.split('@').collect();
-        
+        if parts.len() > 2 {
+            return Err(ParseJIDError("unexpected number of @ in JID".to_string()));
+        }
+
         if parts.len() == 1 {
             return Ok(JID::new("", parts[0]));
         }
@@ -286,4 +373,43 @@ mod tests {
         assert_eq!(jid.user, "123456789-1234567890");
         assert_eq!(jid.server, servers::GROUP);
     }
+
+    #[test]
+    fn test_parse_strict() {
+        assert!(JID::parse_strict("1234567890@s.whatsapp.net").is_ok());
+        assert!(JID::parse_strict("1234567890:3@s.whatsapp.net").is_ok());
+        assert!(JID::parse_strict("123456789-1234567890@g.us").is_ok());
+        assert!(JID::parse_strict("120363012345678901@newsletter").is_ok());
+        assert!(JID::parse_strict("s.whatsapp.net").is_ok());
+
+        assert!(JID::parse_strict("1234567890@example.com").is_err());
+        assert!(JID::parse_strict("+1 234@s.whatsapp.net").is_err());
+        assert!(JID::parse_strict("abc@g.us").is_err());
+        assert!(JID::parse_strict("1@2@s.whatsapp.net").is_err());
+    }
+
+    #[test]
+    fn test_from_phone_number() {
+        let jid = JID::from_phone_number("+1 (555) 123-4567").unwrap();
+        assert_eq!(jid.to_string(), "15551234567@s.whatsapp.net");
+        assert!(jid.is_user());
+
+        assert!(JID::from_phone_number("0555 123 4567").is_err());
+        assert!(JID::from_phone_number("+1 555 CALL NOW").is_err());
+        assert!(JID::from_phone_number("+1234").is_err());
+    }
+
+    #[test]
+    fn test_jid_kind_predicates() {
+        let group: JID = "123456789-1234567890@g.us".parse().unwrap();
+        assert!(group.is_group());
+        assert!(!group.is_user());
+
+        let newsletter: JID = "120363012345678901@newsletter".parse().unwrap();
+        assert!(newsletter.is_newsletter());
+        assert!(!newsletter.is_group());
+
+        assert!(!SERVER_JID.is_user());
+        assert!(!GROUP_SERVER_JID.is_group());
+    }
 }
@@ -0,0 +1,37 @@
+//! Business label types.
+
+use crate::types::JID;
+
+/// A label a business account sorts chats and messages with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Label {
+    pub id: String,
+    pub name: String,
+    /// Index into the app's palette of label colors
+    pub color: i32,
+    /// ID of the built-in label this is, such as "New customer"
+    pub predefined_id: Option<i32>,
+    pub deleted: bool,
+}
+
+/// What a label is put on.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum LabelTarget {
+    Chat(JID),
+    Message { chat: JID, id: String },
+}
+
+/// A label was created, renamed or deleted.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LabelEdit {
+    pub label: Label,
+}
+
+/// A label was put on or taken off a chat or message.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LabelAssociation {
+    pub label_id: String,
+    pub target: LabelTarget,
+    /// Whether the label is now on the target
+    pub labeled: bool,
+}
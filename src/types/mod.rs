@@ -5,6 +5,20 @@
 
 mod jid;
 mod events;
+mod group;
+mod call;
+mod business;
+mod interactive;
+mod newsletter;
+mod label;
+mod redact;
 
 pub use jid::*;
 pub use events::*;
+pub use group::*;
+pub use call::*;
+pub use business::*;
+pub use interactive::*;
+pub use newsletter::*;
+pub use label::*;
+pub use redact::{Redact, Redacted};
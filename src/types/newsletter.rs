@@ -0,0 +1,22 @@
+//! Newsletter (channel) types.
+
+use crate::types::{MessageServerID, JID};
+
+/// Reaction counts on a newsletter message changed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NewsletterReaction {
+    pub newsletter: JID,
+    /// Server ID of the message
+    pub server_id: MessageServerID,
+    /// Each emoji with its number of reactions
+    pub reactions: Vec<(String, u64)>,
+}
+
+/// View count of a newsletter message changed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NewsletterViews {
+    pub newsletter: JID,
+    /// Server ID of the message
+    pub server_id: MessageServerID,
+    pub count: u64,
+}
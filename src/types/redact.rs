@@ -0,0 +1,111 @@
+//! Log redaction.
+//!
+//! Phone numbers and message text must not end up in logs of deployments
+//! that enable `ClientConfig::redact_logs`. `Redacted` wraps a value for
+//! display and hides it when redaction is on.
+
+use std::fmt;
+
+use crate::types::JID;
+
+/// Number of trailing user digits kept in a redacted JID.
+const KEPT_DIGITS: usize = 3;
+
+/// Values that have a redacted display form.
+pub trait Redact {
+    /// Write the value with personal data hidden.
+    fn fmt_redacted(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result;
+}
+
+impl Redact for JID {
+    /// Keeps only the last digits of the user, e.g. `***567@s.whatsapp.net`.
+    fn fmt_redacted(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.user.is_empty() {
+            return write!(f, "{}", self);
+        }
+        let kept = self.user.char_indices()
+            .rev()
+            .nth(KEPT_DIGITS - 1)
+            .filter(|_| self.user.chars().count() > KEPT_DIGITS)
+            .map(|(i, _)| &self.user[i..])
+            .unwrap_or("");
+        let jid = JID { user: format!("***{}", kept), ..self.clone() };
+        write!(f, "{}", jid)
+    }
+}
+
+impl Redact for str {
+    /// Shows only the length of the text.
+    fn fmt_redacted(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<{} chars>", self.chars().count())
+    }
+}
+
+impl Redact for String {
+    fn fmt_redacted(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.as_str().fmt_redacted(f)
+    }
+}
+
+/// Display wrapper that hides personal data when redaction is on.
+pub struct Redacted<'a, T: ?Sized> {
+    value: &'a T,
+    redact: bool,
+}
+
+impl<'a, T: ?Sized> Redacted<'a, T> {
+    /// Wrap a value, hiding it if `redact` is true.
+    pub fn new(value: &'a T, redact: bool) -> Self {
+        Self { value, redact }
+    }
+}
+
+impl<T: Redact + fmt::Display + ?Sized> fmt::Display for Redacted<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.redact {
+            self.value.fmt_redacted(f)
+        } else {
+            self.value.fmt(f)
+        }
+    }
+}
+
+impl<T: Redact + fmt::Display + ?Sized> fmt::Debug for Redacted<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl JID {
+    /// Display form keeping only the last digits of the user.
+    pub fn redacted(&self) -> Redacted<'_, JID> {
+        Redacted::new(self, true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redacted_jid() {
+        let jid = JID::new("15551234567", "s.whatsapp.net");
+        assert_eq!(jid.redacted().to_string(), "***567@s.whatsapp.net");
+
+        let device: JID = "15551234567:2@s.whatsapp.net".parse().unwrap();
+        assert_eq!(device.redacted().to_string(), "***567:2@s.whatsapp.net");
+
+        assert_eq!(JID::new("12", "s.whatsapp.net").redacted().to_string(), "***@s.whatsapp.net");
+        assert_eq!(JID::new("", "g.us").redacted().to_string(), "g.us");
+    }
+
+    #[test]
+    fn test_redaction_toggle() {
+        let text = "meet me at 5";
+        assert_eq!(Redacted::new(text, true).to_string(), "<12 chars>");
+        assert_eq!(Redacted::new(text, false).to_string(), text);
+
+        let jid = JID::new("15551234567", "s.whatsapp.net");
+        assert_eq!(Redacted::new(&jid, false).to_string(), "15551234567@s.whatsapp.net");
+    }
+}
@@ -0,0 +1,54 @@
+//! Replays of recorded sessions.
+//!
+//! The logs under `tests/captures/` hold decrypted frames as recorded by
+//! `FrameRecorder`. Replaying them through the client must keep producing
+//! the same events, whatever happens to the parsers in between.
+
+use whatsmeow_rust::protocol::{Client, FrameLog};
+use whatsmeow_rust::types::{Event, GroupSetting, MessageContent, ParticipantAction, ReceiptType, JID};
+
+fn replay(capture: &str) -> Vec<Event> {
+    let log = FrameLog::parse(capture).unwrap();
+    Client::new().replay(&log)
+}
+
+#[test]
+fn group_session() {
+    let events = replay(include_str!("captures/group_session.jsonl"));
+    let group = JID::new("120363000000000000", "g.us");
+    match &events[..] {
+        [
+            Event::Presence(presence),
+            Event::GroupParticipants(update),
+            Event::GroupSettingChanged(change),
+            Event::Message(message),
+            Event::Receipt(receipt),
+        ] => {
+            assert_eq!(presence.from, JID::new("15550001111", "s.whatsapp.net"));
+            assert!(!presence.available);
+            assert_eq!(presence.last_seen, Some(1700000000));
+
+            assert_eq!(update.group, group);
+            assert_eq!(update.action, ParticipantAction::Add);
+            assert_eq!(update.participants, [JID::new("15550002222", "s.whatsapp.net")]);
+            assert_eq!(update.timestamp, 1700000100);
+
+            assert_eq!(change.group, group);
+            assert_eq!(change.setting, GroupSetting::Announce(true));
+
+            assert_eq!(message.info.id, "3EB0C0FFEE");
+            assert_eq!(message.info.chat, group);
+            assert_eq!(message.info.sender, JID::new("15550002222", "s.whatsapp.net"));
+            assert!(message.info.is_group);
+            assert_eq!(message.info.timestamp, 1700000200);
+            assert_eq!(message.info.push_name.as_deref(), Some("Bob"));
+            assert!(matches!(&message.content, MessageContent::Text(text) if text == "hello group"));
+
+            assert_eq!(receipt.message_ids, ["3EB0AAAA01"]);
+            assert_eq!(receipt.chat, group);
+            assert_eq!(receipt.sender, JID::new("15550001111", "s.whatsapp.net"));
+            assert_eq!(receipt.receipt_type, ReceiptType::Read);
+        }
+        other => panic!("unexpected events {:?}", other),
+    }
+}
@@ -0,0 +1,121 @@
+//! Property tests for the binary codec and JID strings.
+//!
+//! Generated nodes mix every attribute type with byte and child content,
+//! and must survive encoding and decoding. The wire has no type for ints,
+//! bools or UTF-8 bytes, which all come back as strings, so nodes are
+//! compared with what the wire can carry.
+
+use std::borrow::Cow;
+
+use proptest::prelude::*;
+use whatsmeow_rust::binary::{decode, encode, AttrValue, Node, NodeContent};
+use whatsmeow_rust::types::{servers, JID};
+
+/// Users as they appear in JIDs: phone numbers, group IDs and names.
+fn user() -> impl Strategy<Value = String> {
+    prop_oneof![
+        "[1-9][0-9]{5,14}",
+        "[1-9][0-9]{9}-[1-9][0-9]{9}",
+        "[a-z][a-z0-9_]{0,10}",
+    ]
+}
+
+/// JIDs whatsmeow writes without loss: devices only on user servers, and
+/// no raw agent, which the wire replaces with the server's agent.
+fn wire_jid() -> impl Strategy<Value = JID> {
+    prop_oneof![
+        (user(), prop::sample::select(vec![servers::DEFAULT_USER, servers::HIDDEN_USER]), 0..=255u16)
+            .prop_map(|(user, server, device)| JID { device, ..JID::new(user, server) }),
+        (user(), prop::sample::select(vec![servers::GROUP, servers::BROADCAST, servers::NEWSLETTER]))
+            .prop_map(|(user, server)| JID::new(user, server)),
+        prop::sample::select(servers::ALL.to_vec()).prop_map(|server| JID::new("", server)),
+    ]
+}
+
+/// Any JID that has a string form.
+fn any_jid() -> impl Strategy<Value = JID> {
+    (user(), 0..=255u8, any::<u16>(), prop::sample::select(servers::ALL.to_vec()))
+        .prop_map(|(user, raw_agent, device, server)| JID { raw_agent, device, ..JID::new(user, server) })
+}
+
+/// Strings that hit tokens, packed digits, packed hex and raw text.
+fn text() -> impl Strategy<Value = String> {
+    prop_oneof![
+        prop::sample::select(vec!["", "id", "type", "message", "read-self", "reject", "1961"])
+            .prop_map(str::to_string),
+        "[0-9.-]{0,140}",
+        "[0-9A-F]{1,140}",
+        ".{0,40}",
+    ]
+}
+
+fn attr_value() -> impl Strategy<Value = AttrValue> {
+    prop_oneof![
+        Just(AttrValue::None),
+        text().prop_map(AttrValue::from),
+        prop::collection::vec(any::<u8>(), 0..300).prop_map(AttrValue::Bytes),
+        any::<i64>().prop_map(AttrValue::Int),
+        any::<bool>().prop_map(AttrValue::Bool),
+        wire_jid().prop_map(AttrValue::JID),
+    ]
+}
+
+fn node() -> impl Strategy<Value = Node> {
+    let leaf = (
+        text(),
+        prop::collection::hash_map(text().prop_map(Cow::Owned), attr_value(), 0..4),
+        prop_oneof![
+            Just(NodeContent::None),
+            prop::collection::vec(any::<u8>(), 0..400).prop_map(NodeContent::Bytes),
+        ],
+    )
+        .prop_map(|(tag, attrs, content)| Node { tag: tag.into(), attrs, content });
+    leaf.prop_recursive(3, 24, 4, |inner| {
+        (inner.clone(), prop::collection::vec(inner, 0..4)).prop_map(|(mut node, children)| {
+            node.content = NodeContent::Children(children);
+            node
+        })
+    })
+}
+
+/// The node as it reads back from the wire.
+fn on_the_wire(node: &Node) -> Node {
+    let attrs = node.attrs.iter().map(|(key, value)| {
+        let value = match value {
+            AttrValue::Int(n) => AttrValue::from(n.to_string()),
+            AttrValue::Bool(b) => AttrValue::from(b.to_string()),
+            AttrValue::Bytes(bytes) => match String::from_utf8(bytes.clone()) {
+                Ok(s) => AttrValue::from(s),
+                Err(_) => value.clone(),
+            },
+            _ => value.clone(),
+        };
+        (key.clone(), value)
+    });
+    let content = match &node.content {
+        NodeContent::Children(children) => NodeContent::Children(children.iter().map(on_the_wire).collect()),
+        content => content.clone(),
+    };
+    Node { tag: node.tag.clone(), attrs: attrs.collect(), content }
+}
+
+proptest! {
+    #[test]
+    fn node_roundtrip(node in node()) {
+        let decoded = decode(&encode(&node)).unwrap();
+        prop_assert_eq!(decoded, on_the_wire(&node));
+    }
+
+    #[test]
+    fn jid_string_roundtrip(jid in any_jid()) {
+        prop_assert_eq!(jid.to_string().parse::<JID>().unwrap(), jid);
+    }
+
+    #[test]
+    fn jid_wire_roundtrip(jid in wire_jid()) {
+        let mut node = Node::new("message");
+        node.set_attr("to", jid.clone());
+        let decoded = decode(&encode(&node)).unwrap();
+        prop_assert_eq!(decoded.get_attr_jid("to"), Some(&jid));
+    }
+}
@@ -0,0 +1,244 @@
+//! Golden vectors shared with whatsmeow.
+//!
+//! The files under `tests/vectors/` hold inputs and the outputs the Go
+//! implementation produces for them: JID parsing, the token dictionary,
+//! binary node encoding and the Noise key derivation. Encoded nodes are
+//! the marshalled bytes without the leading frame flags byte.
+
+use std::collections::HashMap;
+
+use serde::Deserialize;
+use whatsmeow_rust::binary::{self, AttrValue, Node, NodeContent};
+use whatsmeow_rust::crypto::{derive_noise_keys, Hkdf, KeyPair, NoiseHandshake, NOISE_PROTOCOL_NAME};
+use whatsmeow_rust::types::JID;
+
+fn unhex(s: &str) -> Vec<u8> {
+    hex::decode(s).unwrap_or_else(|err| panic!("bad hex {s:?}: {err}"))
+}
+
+fn key(s: &str) -> [u8; 32] {
+    unhex(s).try_into().expect("32-byte key")
+}
+
+#[derive(Deserialize)]
+struct JidVectors {
+    valid: Vec<JidCase>,
+    invalid: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct JidCase {
+    input: String,
+    user: String,
+    agent: u8,
+    device: u16,
+    server: String,
+    string: String,
+}
+
+#[test]
+fn jid_vectors() {
+    let vectors: JidVectors = serde_json::from_str(include_str!("vectors/jid.json")).unwrap();
+    for case in &vectors.valid {
+        let jid: JID = case.input.parse().unwrap_or_else(|err| panic!("{}: {err}", case.input));
+        assert_eq!(
+            (jid.user.as_str(), jid.raw_agent, jid.device, jid.server.as_str()),
+            (case.user.as_str(), case.agent, case.device, case.server.as_str()),
+            "{}",
+            case.input,
+        );
+        assert_eq!(jid.to_string(), case.string, "{}", case.input);
+        assert_eq!(case.string.parse::<JID>().unwrap(), jid, "{}", case.input);
+    }
+    for input in &vectors.invalid {
+        assert!(input.parse::<JID>().is_err(), "{input} should not parse");
+    }
+}
+
+#[derive(Deserialize)]
+struct TokenVectors {
+    version: u8,
+    single: Vec<(u8, String)>,
+    double: Vec<(u8, u8, String)>,
+}
+
+#[test]
+fn token_vectors() {
+    let vectors: TokenVectors = serde_json::from_str(include_str!("vectors/tokens.json")).unwrap();
+    let dict = binary::dictionary(vectors.version).expect("dictionary version");
+    for (index, token) in &vectors.single {
+        assert_eq!(dict.token(*index), Some(token.as_str()), "single token {index}");
+        assert_eq!(dict.lookup(token), Some(binary::Token::Single(*index)), "{token}");
+    }
+    for (d, index, token) in &vectors.double {
+        assert_eq!(dict.double_token(*d, *index), Some(token.as_str()), "double token {d}/{index}");
+        assert_eq!(dict.lookup(token), Some(binary::Token::Double { dict: *d, index: *index }), "{token}");
+    }
+}
+
+#[derive(Deserialize)]
+struct BinaryCase {
+    name: String,
+    node: NodeSpec,
+    hex: String,
+}
+
+#[derive(Deserialize)]
+struct NodeSpec {
+    tag: String,
+    #[serde(default)]
+    attrs: HashMap<String, AttrSpec>,
+    content: Option<ContentSpec>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum AttrSpec {
+    String(String),
+    Jid(String),
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ContentSpec {
+    Bytes(String),
+    Children(Vec<NodeSpec>),
+}
+
+impl NodeSpec {
+    fn to_node(&self) -> Node {
+        let mut node = Node::new(self.tag.clone());
+        for (key, value) in &self.attrs {
+            let value = match value {
+                AttrSpec::String(s) => AttrValue::from(s.clone()),
+                AttrSpec::Jid(s) => AttrValue::JID(s.parse().unwrap()),
+            };
+            node.set_attr(key.clone(), value);
+        }
+        node.content = match &self.content {
+            None => NodeContent::None,
+            Some(ContentSpec::Bytes(hex)) => NodeContent::Bytes(unhex(hex)),
+            Some(ContentSpec::Children(children)) => {
+                NodeContent::Children(children.iter().map(NodeSpec::to_node).collect())
+            }
+        };
+        node
+    }
+}
+
+fn assert_same(decoded: &Node, expected: &Node, name: &str) {
+    assert_eq!(decoded.tag, expected.tag, "{name}: tag");
+    assert_eq!(decoded.attrs, expected.attrs, "{name}: attrs");
+    match (&decoded.content, &expected.content) {
+        (NodeContent::None, NodeContent::None) => {}
+        (NodeContent::Bytes(a), NodeContent::Bytes(b)) => assert_eq!(a, b, "{name}: bytes"),
+        (NodeContent::Children(a), NodeContent::Children(b)) => {
+            assert_eq!(a.len(), b.len(), "{name}: children");
+            for (a, b) in a.iter().zip(b) {
+                assert_same(a, b, name);
+            }
+        }
+        (a, b) => panic!("{name}: content {a:?} != {b:?}"),
+    }
+}
+
+#[test]
+fn binary_vectors() {
+    let cases: Vec<BinaryCase> = serde_json::from_str(include_str!("vectors/binary.json")).unwrap();
+    for case in &cases {
+        let node = case.node.to_node();
+        assert_eq!(hex::encode(binary::encode(&node)), case.hex, "{}: encode", case.name);
+        let decoded = binary::decode(&unhex(&case.hex)).unwrap_or_else(|err| panic!("{}: {err}", case.name));
+        assert_same(&decoded, &node, &case.name);
+    }
+}
+
+#[derive(Deserialize)]
+struct CryptoVectors {
+    hkdf_sha256: Vec<HkdfCase>,
+    x25519: X25519Case,
+    noise: NoiseCase,
+}
+
+#[derive(Deserialize)]
+struct HkdfCase {
+    name: String,
+    salt: Option<String>,
+    ikm: String,
+    info: String,
+    okm: String,
+}
+
+#[derive(Deserialize)]
+struct X25519Case {
+    alice_private: String,
+    alice_public: String,
+    bob_private: String,
+    bob_public: String,
+    shared: String,
+}
+
+#[derive(Deserialize)]
+struct NoiseCase {
+    pattern: String,
+    mix_key: MixKeyCase,
+    split: SplitCase,
+}
+
+#[derive(Deserialize)]
+struct MixKeyCase {
+    salt: String,
+    shared: String,
+    chaining_key: String,
+    key: String,
+}
+
+#[derive(Deserialize)]
+struct SplitCase {
+    chaining_key: String,
+    write_key: String,
+    read_key: String,
+}
+
+fn crypto_vectors() -> CryptoVectors {
+    serde_json::from_str(include_str!("vectors/crypto.json")).unwrap()
+}
+
+#[test]
+fn hkdf_vectors() {
+    for case in crypto_vectors().hkdf_sha256 {
+        let okm_len = case.okm.len() / 2;
+        let salt = case.salt.as_deref().map(unhex);
+        let okm = Hkdf::derive(salt.as_deref(), &unhex(&case.ikm), &unhex(&case.info), okm_len);
+        assert_eq!(hex::encode(okm), case.okm, "{}", case.name);
+    }
+}
+
+#[test]
+fn x25519_vectors() {
+    let case = crypto_vectors().x25519;
+    let alice = KeyPair::from_private_key(key(&case.alice_private));
+    let bob = KeyPair::from_private_key(key(&case.bob_private));
+    assert_eq!(hex::encode(alice.public_key()), case.alice_public);
+    assert_eq!(hex::encode(bob.public_key()), case.bob_public);
+    assert_eq!(hex::encode(alice.dh(bob.public_key())), case.shared);
+    assert_eq!(hex::encode(bob.dh(alice.public_key())), case.shared);
+}
+
+#[test]
+fn noise_vectors() {
+    let case = crypto_vectors().noise;
+    assert_eq!(hex::encode(NOISE_PROTOCOL_NAME), case.pattern);
+    // The 32-byte pattern is the initial hash as is
+    let handshake = NoiseHandshake::new_initiator(KeyPair::generate());
+    assert_eq!(hex::encode(handshake.handshake_hash()), case.pattern);
+
+    let mix = &case.mix_key;
+    let (chaining_key, key) = derive_noise_keys(&unhex(&mix.shared), &unhex(&mix.salt));
+    assert_eq!((hex::encode(chaining_key), hex::encode(key)), (mix.chaining_key.clone(), mix.key.clone()));
+
+    let split = &case.split;
+    let keys = Hkdf::derive(Some(&unhex(&split.chaining_key)), &[], b"", 64);
+    assert_eq!(hex::encode(&keys[..32]), split.write_key);
+    assert_eq!(hex::encode(&keys[32..]), split.read_key);
+}